@@ -8,13 +8,16 @@
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
 
-use std::{env, fs::File, io::Write, path::PathBuf};
+use std::{env, fs::File, io::Write, path::PathBuf, process::Command};
 
 fn main() {
     let mut memory_x = None;
     // let mut config = None;
 
-    if let Some(_feature) = env::var_os("CARGO_FEATURE_H7") {
+    // H723 has less flash and RAM than H743; its layout would overflow otherwise.
+    if let Some(_feature) = env::var_os("CARGO_FEATURE_H723") {
+        memory_x = Some(include_bytes!("memory_h723.x").to_vec());
+    } else if let Some(_feature) = env::var_os("CARGO_FEATURE_H7") {
         memory_x = Some(include_bytes!("memory_h7.x").to_vec());
         // config = Some(include_bytes!("./.cargo/config_h7.toml").to_vec());
     } else if let Some(_feature) = env::var_os("CARGO_FEATURE_G4") {
@@ -40,6 +43,20 @@ fn main() {
     println!("cargo:rustc-link-search={}", out.display());
     println!("cargo:rerun-if-changed=memory.x");
     println!("cargo:rerun-if-changed=build.rs");
+
+    // Embed the git commit, for reporting over USB. Falls back to blank, eg if building from a
+    // source archive without git.
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short=8", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash.trim());
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
 }
 
 // let linker_script = match ... {
@@ -0,0 +1,168 @@
+//! This module contains calibration of the battery voltage and current readings from the ADC. The
+//! board's voltage divider, and the current sensor's slope and zero offset, vary between boards
+//! and sensors; the nominal values can be off by several percent.
+//!
+//! To calibrate voltage, measure the pack with a multimeter, and send the reading over USB; we
+//! compute the divider ratio from the current ADC reading. To calibrate the current zero, send the
+//! command with motors stopped. The slope is from the current sensor's datasheet, and can be set
+//! directly.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use defmt::println;
+
+// Nominal values. V batt / V read.
+const BATT_V_DIV_DEFAULT: f32 = 11.;
+// mV/A.
+const CURR_SLOPE_DEFAULT: f32 = 2.5;
+
+// Calibrated values outside these ranges are rejected.
+const BATT_V_DIV_MIN: f32 = 1.;
+const BATT_V_DIV_MAX: f32 = 100.;
+const CURR_SLOPE_MIN: f32 = 0.1;
+const CURR_SLOPE_MAX: f32 = 1_000.;
+// Volts at the ADC pin.
+const CURR_OFFSET_MAX: f32 = 3.3;
+
+// Volts at the ADC pin. Below this, there's no battery to calibrate against.
+const CAL_MIN_PIN_V: f32 = 0.1;
+
+/// Set over USB: The true battery voltage, as measured with a multimeter, as f32 bits. 0 if no
+/// calibration is pending. Taken by the main loop.
+pub static BATT_V_CAL: AtomicU32 = AtomicU32::new(0);
+/// Set over USB, with no current flowing. Taken by the main loop.
+pub static CURR_ZERO_CAL: AtomicBool = AtomicBool::new(false);
+
+// Serialized size: Divider ratio, current slope, and current offset.
+pub const ADC_CAL_CFG_SIZE: usize = 4 * 3;
+// Raw battery and current counts, then the calibrated voltage and current.
+pub const ADC_READINGS_SIZE: usize = 2 * 2 + 4 * 2;
+
+/// Conversions from ADC pin voltage. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct AdcCalCfg {
+    /// V batt / V read.
+    pub batt_v_div: f32,
+    /// Current sensor output, in mV/A.
+    pub curr_slope: f32,
+    /// Current sensor output with no current flowing, in V at the ADC pin.
+    pub curr_offset: f32,
+}
+
+impl Default for AdcCalCfg {
+    fn default() -> Self {
+        Self {
+            batt_v_div: BATT_V_DIV_DEFAULT,
+            curr_slope: CURR_SLOPE_DEFAULT,
+            curr_offset: 0.,
+        }
+    }
+}
+
+impl AdcCalCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let batt_v_div = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let curr_slope = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+        let curr_offset = f32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(BATT_V_DIV_MIN..=BATT_V_DIV_MAX).contains(&batt_v_div)
+            || !(CURR_SLOPE_MIN..=CURR_SLOPE_MAX).contains(&curr_slope)
+            || !(0.0..=CURR_OFFSET_MAX).contains(&curr_offset)
+        {
+            return None;
+        }
+
+        Some(Self {
+            batt_v_div,
+            curr_slope,
+            curr_offset,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ADC_CAL_CFG_SIZE] {
+        let mut result = [0; ADC_CAL_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.batt_v_div.to_be_bytes());
+        result[4..8].clone_from_slice(&self.curr_slope.to_be_bytes());
+        result[8..12].clone_from_slice(&self.curr_offset.to_be_bytes());
+        result
+    }
+
+    /// Battery voltage, from the voltage at the ADC pin.
+    pub fn batt_v(&self, pin_v: f32) -> f32 {
+        pin_v * self.batt_v_div
+    }
+
+    /// Current, in A, from the voltage at the ADC pin.
+    pub fn current(&self, pin_v: f32) -> f32 {
+        (pin_v - self.curr_offset) * 1_000. / self.curr_slope
+    }
+
+    /// Run from the main loop. Applies calibrations requested over USB. Returns true if the
+    /// config changed, and should be saved.
+    pub fn apply_pending(&mut self, readings: &AdcReadings) -> bool {
+        let mut changed = false;
+
+        let batt_v_true = f32::from_bits(BATT_V_CAL.swap(0, Ordering::AcqRel));
+        if batt_v_true > 0. {
+            let div = batt_v_true / readings.pin_v.0;
+
+            if readings.pin_v.0 > CAL_MIN_PIN_V && (BATT_V_DIV_MIN..=BATT_V_DIV_MAX).contains(&div)
+            {
+                self.batt_v_div = div;
+                changed = true;
+                println!("Battery voltage calibrated. Divider: {}", div);
+            } else {
+                println!("Battery voltage calibration failed; is the battery connected?");
+            }
+        }
+
+        if CURR_ZERO_CAL.swap(false, Ordering::AcqRel) {
+            if (0.0..=CURR_OFFSET_MAX).contains(&readings.pin_v.1) {
+                self.curr_offset = readings.pin_v.1;
+                changed = true;
+                println!("Current zero calibrated. Offset: {}V", readings.pin_v.1);
+            } else {
+                println!("Current zero calibration failed");
+            }
+        }
+
+        changed
+    }
+}
+
+/// The latest battery voltage and current readings from the ADC. These are independent of the
+/// configured measurement source; see `BattMeasSource`.
+#[derive(Default)]
+pub struct AdcReadings {
+    /// Battery voltage, and current.
+    pub raw: (u16, u16),
+    /// Volts at the ADC pins.
+    pub pin_v: (f32, f32),
+    pub batt_v: f32,
+    /// A.
+    pub current: f32,
+}
+
+impl AdcReadings {
+    pub fn new(raw: (u16, u16), pin_v: (f32, f32), cfg: &AdcCalCfg) -> Self {
+        Self {
+            raw,
+            pin_v,
+            batt_v: cfg.batt_v(pin_v.0),
+            current: cfg.current(pin_v.1),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; ADC_READINGS_SIZE] {
+        let mut result = [0; ADC_READINGS_SIZE];
+
+        result[0..2].clone_from_slice(&self.raw.0.to_be_bytes());
+        result[2..4].clone_from_slice(&self.raw.1.to_be_bytes());
+        result[4..8].clone_from_slice(&self.batt_v.to_be_bytes());
+        result[8..12].clone_from_slice(&self.current.to_be_bytes());
+        result
+    }
+}
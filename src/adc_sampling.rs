@@ -0,0 +1,128 @@
+//! This module contains battery voltage and current sampling: The main loop starts a short
+//! conversion sequence each task cycle (~1.3kHz), which DMA writes into one half of a double
+//! buffer. The transfer complete ISR publishes that half, and the next sequence goes to
+//! the other. Compared to free-running circular DMA, this leaves the DMA controller idle between
+//! sequences, and a reader never sees a half-updated sample.
+//!
+//! The published half is only written again once a later sequence completes, and the main loop,
+//! which starts sequences, is the only reader; so reads don't need a critical section.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use hal::{
+    adc::Adc,
+    dma::{self, DmaInterrupt},
+};
+
+use crate::{
+    board_config::{BATT_ADC_CH, CURR_ADC_CH},
+    setup::{self, DmaTransfer},
+    ADC,
+};
+
+// Battery voltage, and current. The MCU temperature sensor is on a different ADC; see
+// `perf_stats::sample_mcu_temp`.
+const SEQ_LEN: usize = 2;
+
+// Samples older than this, in s, aren't returned; eg if the ADC or its DMA stalled.
+pub const MAX_SAMPLE_AGE: f32 = 0.01;
+
+// `PUBLISHED` before the first sequence completes.
+const NONE_PUBLISHED: u8 = 2;
+
+/// Written by DMA; one half per sequence.
+static mut BUF: [[u16; SEQ_LEN]; 2] = [[0; SEQ_LEN]; 2];
+
+/// When each half's sequence started, in s since start. Set before starting it.
+static mut START_TIMES: [f32; 2] = [0.; 2];
+
+/// The half holding the latest complete sample.
+static PUBLISHED: AtomicU8 = AtomicU8::new(NONE_PUBLISHED);
+
+/// The half the latest sequence was started on; published by the TC ISR.
+static WRITING: AtomicU8 = AtomicU8::new(NONE_PUBLISHED);
+
+/// Set when starting a sequence, and cleared by the TC ISR.
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Raw readings from one conversion sequence.
+#[derive(Clone, Copy)]
+pub struct AdcSample {
+    /// Battery voltage, and current.
+    pub raw: (u16, u16),
+}
+
+/// Run at init, once the ADC is set up.
+pub fn init() {
+    dma::enable_interrupt(
+        setup::BATT_CURR_DMA_PERIPH,
+        setup::BATT_CURR_DMA_CH,
+        DmaInterrupt::TransferComplete,
+    );
+}
+
+/// Start a conversion sequence, into the half not published. Run from the main loop, after
+/// reading the latest sample. If the previous sequence hasn't completed, it stalled; we abandon it.
+pub fn start(adc: &mut Adc<ADC>, timestamp: f32) {
+    if IN_PROGRESS.swap(true, Ordering::AcqRel) {
+        dma::stop(setup::BATT_CURR_DMA_PERIPH, setup::BATT_CURR_DMA_CH);
+    }
+
+    let i = match PUBLISHED.load(Ordering::Acquire) {
+        0 => 1,
+        _ => 0,
+    };
+
+    WRITING.store(i as u8, Ordering::Release);
+
+    let seq = [BATT_ADC_CH, CURR_ADC_CH];
+
+    unsafe {
+        START_TIMES[i] = timestamp;
+
+        adc.read_dma(
+            &mut (*core::ptr::addr_of_mut!(BUF))[i],
+            &seq,
+            setup::BATT_CURR_DMA_CH,
+            setup::dma_cfg(DmaTransfer::BattCurr),
+            setup::BATT_CURR_DMA_PERIPH,
+        );
+    }
+}
+
+/// Run from the DMA transfer complete ISR.
+pub fn sequence_complete() {
+    dma::clear_interrupt(
+        setup::BATT_CURR_DMA_PERIPH,
+        setup::BATT_CURR_DMA_CH,
+        DmaInterrupt::TransferComplete,
+    );
+
+    dma::stop(setup::BATT_CURR_DMA_PERIPH, setup::BATT_CURR_DMA_CH);
+
+    PUBLISHED.store(WRITING.load(Ordering::Acquire), Ordering::Release);
+    IN_PROGRESS.store(false, Ordering::Release);
+}
+
+/// The latest complete sample, and its age in s. `None` if there isn't one, or it's older than
+/// `MAX_SAMPLE_AGE`.
+pub fn latest(timestamp: f32) -> Option<(AdcSample, f32)> {
+    let i = PUBLISHED.load(Ordering::Acquire) as usize;
+    if i == NONE_PUBLISHED as usize {
+        return None;
+    }
+
+    let (buf, start_time) = unsafe { (BUF[i], START_TIMES[i]) };
+
+    let age = timestamp - start_time;
+    if age > MAX_SAMPLE_AGE {
+        return None;
+    }
+
+    Some((
+        AdcSample {
+            raw: (buf[0], buf[1]),
+        },
+        age,
+    ))
+}
@@ -0,0 +1,290 @@
+//! This module contains the altitude estimator: A single best estimate of altitude MSL, and above
+//! ground level, from the baro, GPS, and TOF sensor. Autopilot modes, and the OSD, read altitude
+//! from here, vice from sensor readings directly.
+//!
+//! MSL altitude is from the baro, which is fast and precise, but drifts with weather and
+//! temperature. While the baro and GPS are both healthy, we slowly estimate the offset between
+//! them, and apply it to the baro; this removes drift, without passing GPS altitude noise through.
+//! If the baro fails, we use GPS altitude directly; the offset keeps this from jumping. If both
+//! fail, we hold the last estimate, and report no source.
+//!
+//! AGL is from the TOF sensor while its reading is valid and in its accurate range. Otherwise,
+//! it's the MSL estimate less the ground elevation, which we take before takeoff, and update from
+//! each TOF reading.
+//!
+//! Altitude above takeoff is relative to the ground reference captured on arming; see `ground_ref`.
+//! Autopilot altitude holds, and the OSD, use it when available.
+
+use ahrs::Params;
+
+use crate::{
+    drivers::gps_ublox::GpsFix,
+    flight_ctrls::common::AltType,
+    ground_ref::GroundRef,
+    system_status::{SensorStatus, SystemStatus},
+};
+
+// Time constant of the baro-to-GPS offset estimate, in seconds.
+const OFFSET_TAU: f32 = 20.;
+// The offset changes no faster than this, in m/s. This limits how fast a GPS altitude error
+// moves the estimate.
+const OFFSET_MAX_RATE: f32 = 0.5;
+
+// GPS fixes older than this, in s, aren't used.
+const GPS_MAX_AGE: f32 = 1.;
+// TOF readings above this, in m, aren't used; they're less accurate than the baro there.
+const TOF_MAX_ALT: f32 = 8.;
+
+// MSL, AGL, and baro offset, then MSL source, AGL source, and whether AGL is valid.
+pub const ALT_EST_SIZE: usize = 4 * 3 + 3;
+
+/// The sensor an altitude is from. Repr is how it's passed over USB.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum AltSource {
+    None = 0,
+    /// Baro, corrected by the baro-to-GPS offset.
+    Baro = 1,
+    Gps = 2,
+    Tof = 3,
+}
+
+impl Default for AltSource {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// The current altitude estimate. For MSL sources, AGL is relative to the ground elevation.
+#[derive(Clone, Copy, Default)]
+pub struct AltEstimate {
+    /// Meters. If there's no source, this is the last estimate.
+    pub msl: f32,
+    /// Meters. `None` if there's no source, or no ground elevation yet.
+    pub agl: Option<f32>,
+    pub msl_source: AltSource,
+    pub agl_source: AltSource,
+    /// Meters. `None` if there's no ground reference, or no source.
+    pub above_takeoff: Option<f32>,
+}
+
+impl AltEstimate {
+    /// The altitude type, and current altitude, for autopilot holds: Above takeoff if available;
+    /// MSL otherwise.
+    pub fn default_alt(&self) -> (AltType, f32) {
+        match self.above_takeoff {
+            Some(alt) => (AltType::Takeoff, alt),
+            None => (AltType::Msl, self.msl),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AltEstimator {
+    pub estimate: AltEstimate,
+    /// Added to baro altitude to match GPS altitude, in m.
+    pub baro_offset: f32,
+    /// Ground elevation MSL, in m, for AGL when TOF isn't available.
+    ground_msl: Option<f32>,
+}
+
+impl AltEstimator {
+    /// Run periodically from the main loop, prior to updating autopilot modes. `on_ground` is
+    /// before takeoff is detected. `armed` is motors armed; the ground reference is captured on
+    /// arming. Timestamp and dt are in seconds.
+    pub fn update(
+        &mut self,
+        params: &Params,
+        gps_fix: &GpsFix,
+        system_status: &SystemStatus,
+        ground_ref: &mut GroundRef,
+        armed: bool,
+        on_ground: bool,
+        timestamp: f32,
+        dt: f32,
+    ) {
+        let baro = if system_status.baro == SensorStatus::Pass {
+            Some(params.alt_msl_baro)
+        } else {
+            None
+        };
+
+        let gps = if system_status.gps == SensorStatus::Pass
+            && timestamp - gps_fix.timestamp < GPS_MAX_AGE
+        {
+            Some(gps_fix.alt_msl)
+        } else {
+            None
+        };
+
+        let tof = if system_status.tof == SensorStatus::Pass {
+            params.alt_tof
+        } else {
+            None
+        };
+
+        self.fuse(baro, gps, tof, on_ground, dt);
+
+        ground_ref.update(baro, gps, self.baro_offset, armed, !on_ground, dt);
+        self.estimate.above_takeoff = ground_ref.above_takeoff(baro, gps, &self.estimate);
+    }
+
+    /// Update the estimate from each source's reading, if valid.
+    fn fuse(
+        &mut self,
+        baro: Option<f32>,
+        gps: Option<f32>,
+        tof: Option<f32>,
+        on_ground: bool,
+        dt: f32,
+    ) {
+        if let (Some(baro), Some(gps)) = (baro, gps) {
+            let error = gps - baro - self.baro_offset;
+            let max_step = OFFSET_MAX_RATE * dt;
+
+            self.baro_offset += (error * (dt / OFFSET_TAU).min(1.)).clamp(-max_step, max_step);
+        }
+
+        let (msl, msl_source) = match (baro, gps) {
+            (Some(baro), _) => (baro + self.baro_offset, AltSource::Baro),
+            (None, Some(gps)) => (gps, AltSource::Gps),
+            (None, None) => (self.estimate.msl, AltSource::None),
+        };
+
+        let tof = tof.filter(|alt| *alt >= 0. && *alt <= TOF_MAX_ALT);
+
+        if msl_source != AltSource::None {
+            if let Some(tof) = tof {
+                self.ground_msl = Some(msl - tof);
+            } else if on_ground {
+                self.ground_msl = Some(msl);
+            }
+        }
+
+        let (agl, agl_source) = match (tof, self.ground_msl) {
+            (Some(tof), _) => (Some(tof), AltSource::Tof),
+            (None, Some(ground)) if msl_source != AltSource::None => {
+                (Some(msl - ground), msl_source)
+            }
+            _ => (None, AltSource::None),
+        };
+
+        self.estimate = AltEstimate {
+            msl,
+            agl,
+            msl_source,
+            agl_source,
+            // Set in `update`.
+            above_takeoff: None,
+        };
+    }
+
+    pub fn to_bytes(&self) -> [u8; ALT_EST_SIZE] {
+        let mut result = [0; ALT_EST_SIZE];
+        let est = &self.estimate;
+
+        result[0..4].clone_from_slice(&est.msl.to_be_bytes());
+        result[4..8].clone_from_slice(&est.agl.unwrap_or(0.).to_be_bytes());
+        result[8..12].clone_from_slice(&self.baro_offset.to_be_bytes());
+        result[12] = est.msl_source as u8;
+        result[13] = est.agl_source as u8;
+        result[14] = est.agl.is_some() as u8;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.1;
+
+    /// Run `fuse` for `time` seconds with constant readings.
+    fn run(
+        est: &mut AltEstimator,
+        baro: Option<f32>,
+        gps: Option<f32>,
+        tof: Option<f32>,
+        on_ground: bool,
+        time: f32,
+    ) {
+        for _ in 0..(time / DT) as u32 {
+            est.fuse(baro, gps, tof, on_ground, DT);
+        }
+    }
+
+    #[test]
+    fn baro_only() {
+        let mut est = AltEstimator::default();
+
+        run(&mut est, Some(100.), None, None, true, 1.);
+        assert_eq!(est.estimate.msl, 100.);
+        assert!(est.estimate.msl_source == AltSource::Baro);
+        assert_eq!(est.baro_offset, 0.);
+        assert_eq!(est.estimate.agl, Some(0.));
+
+        // AGL is from the ground elevation taken before takeoff.
+        run(&mut est, Some(112.5), None, None, false, 1.);
+        assert_eq!(est.estimate.msl, 112.5);
+        assert_eq!(est.estimate.agl, Some(12.5));
+        assert!(est.estimate.agl_source == AltSource::Baro);
+
+        // With no source, the last MSL estimate is held, and there's no AGL.
+        run(&mut est, None, None, None, false, 1.);
+        assert_eq!(est.estimate.msl, 112.5);
+        assert!(est.estimate.msl_source == AltSource::None);
+        assert_eq!(est.estimate.agl, None);
+    }
+
+    #[test]
+    fn tof_dropout() {
+        let mut est = AltEstimator::default();
+
+        run(&mut est, Some(100.), None, None, true, 1.);
+
+        // The TOF reading is 0.3m less than the baro suggests; TOF is used, and moves the ground
+        // elevation.
+        run(&mut est, Some(105.), None, Some(4.7), false, 1.);
+        assert_eq!(est.estimate.agl, Some(4.7));
+        assert!(est.estimate.agl_source == AltSource::Tof);
+
+        // On dropout, AGL continues from the baro, without a step.
+        run(&mut est, Some(105.), None, None, false, 0.1);
+        assert!(est.estimate.agl_source == AltSource::Baro);
+        assert!((est.estimate.agl.unwrap() - 4.7).abs() < 0.01);
+
+        // Likewise above the TOF sensor's accurate range.
+        run(&mut est, Some(109.), None, Some(7.9), false, 0.1);
+        assert!(est.estimate.agl_source == AltSource::Tof);
+        run(&mut est, Some(109.2), None, Some(8.1), false, 0.1);
+        assert!(est.estimate.agl_source == AltSource::Baro);
+        assert!((est.estimate.agl.unwrap() - 8.1).abs() < 0.01);
+    }
+
+    #[test]
+    fn gnss_return_without_step() {
+        let mut est = AltEstimator::default();
+
+        run(&mut est, Some(100.), None, None, true, 1.);
+
+        // GPS returns, 30m from baro altitude. The offset moves slowly, so the estimate doesn't
+        // step.
+        let mut prev = est.estimate.msl;
+        for _ in 0..100 {
+            est.fuse(Some(100.), Some(130.), None, false, DT);
+            assert!((est.estimate.msl - prev).abs() <= OFFSET_MAX_RATE * DT + 0.001);
+            prev = est.estimate.msl;
+        }
+        assert!(est.estimate.msl_source == AltSource::Baro);
+
+        // It converges on GPS altitude.
+        run(&mut est, Some(100.), Some(130.), None, false, 300.);
+        assert!((est.estimate.msl - 130.).abs() < 0.1);
+
+        // With the offset converged, the switch to GPS on baro failure doesn't step either.
+        prev = est.estimate.msl;
+        run(&mut est, None, Some(130.), None, false, DT);
+        assert!(est.estimate.msl_source == AltSource::Gps);
+        assert!((est.estimate.msl - prev).abs() < 0.1);
+    }
+}
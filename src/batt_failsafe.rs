@@ -0,0 +1,286 @@
+//! This module contains the low-battery failsafe: A state machine that escalates through stages as
+//! the battery depletes, from per-cell voltage, and mAh used against a configured capacity.
+//!
+//! - Stage 1, `Warn`: The OSD shows a warning, and the LED and buzzer alert.
+//! - Stage 2, `Limit`: Throttle is capped, and tilt limited in self-leveled modes, to reduce draw,
+//!   and keep the pilot from spending the reserve on a fast run.
+//! - Stage 3, `Action`: We return to the base point if GNSS is usable, or descend and land where we
+//!   are otherwise. The pilot can prevent this by holding the `batt_override` switch; a forced
+//!   landing into trees is often worse than a dead-stick arrival at the pilot's feet.
+//!
+//! Voltage sags under load, so a stage's condition must hold for the debounce time before we enter
+//! it. Stages never go backwards during a flight, even as voltage recovers once the load drops;
+//! they reset on arming. Stage changes are logged in the event log.
+
+use defmt::println;
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    event_log::{self, EventCode},
+    safety::{self, ArmStatus},
+    sensors_shared::BattCellCount,
+};
+
+// Volts per cell. Below this, we assume no battery is connected, eg powered from USB.
+const BATT_PRESENT_CELL_V: f32 = 2.5;
+
+// Thresholds outside these ranges are rejected when loading config.
+const CELL_V_MAX: f32 = 4.3;
+const CAPACITY_MAX: f32 = 100_000.;
+const DEBOUNCE_MAX: f32 = 30.;
+const LIMIT_THROTTLE_MIN: f32 = 0.2;
+
+// Serialized size: Cell voltage, and fraction remaining, for each stage, then capacity,
+// debounce time, throttle and tilt limits, and the action.
+pub const BATT_FAILSAFE_CFG_SIZE: usize = 4 * 3 + 4 * 3 + 4 + 4 + 4 + 4 + 1;
+
+/// Stages, in order of severity. Repr is how it's passed over USB, and in the event log.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum BattStage {
+    Ok = 0,
+    Warn = 1,
+    Limit = 2,
+    Action = 3,
+}
+
+impl Default for BattStage {
+    fn default() -> Self {
+        Self::Ok
+    }
+}
+
+impl BattStage {
+    fn from_index(i: usize) -> Self {
+        match i {
+            0 => Self::Warn,
+            1 => Self::Limit,
+            _ => Self::Action,
+        }
+    }
+}
+
+/// What to do at stage 3. Repr is how it's stored.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum BattAction {
+    /// Return to the base point, as in link-lost recovery, if GNSS is usable; land otherwise.
+    Rth = 0,
+    /// Descend and land where we are.
+    Land = 1,
+}
+
+/// Low-battery failsafe thresholds. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct BattFailsafeCfg {
+    /// Volts per cell, for the `Warn`, `Limit`, and `Action` stages. 0. disables a stage's voltage
+    /// check.
+    pub cell_v: [f32; 3],
+    /// Fraction of capacity remaining, 0. to 1., for each stage. 0. disables a stage's capacity
+    /// check.
+    pub remaining: [f32; 3],
+    /// mAh. 0. disables capacity checks.
+    pub capacity: f32,
+    /// Seconds a stage's condition must hold before we enter it.
+    pub debounce: f32,
+    /// Throttle cap, 0. to 1., from the `Limit` stage.
+    pub limit_throttle: f32,
+    /// Radians. Tilt limit in self-leveled modes, from the `Limit` stage.
+    pub limit_tilt: f32,
+    pub action: BattAction,
+}
+
+impl Default for BattFailsafeCfg {
+    fn default() -> Self {
+        Self {
+            cell_v: [3.5, 3.4, 3.3],
+            remaining: [0.3, 0.2, 0.1],
+            capacity: 0.,
+            debounce: 3.,
+            limit_throttle: 0.7,
+            limit_tilt: 0.52,
+            action: BattAction::Rth,
+        }
+    }
+}
+
+impl BattFailsafeCfg {
+    /// Parse and validate. Returns `None` if values are out of range, or if stages aren't in
+    /// order of severity.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let cell_v = [f(0), f(4), f(8)];
+        let remaining = [f(12), f(16), f(20)];
+        let capacity = f(24);
+        let debounce = f(28);
+        let limit_throttle = f(32);
+        let limit_tilt = f(36);
+        let action = BattAction::try_from(buf[40]).ok()?;
+
+        // These comparisons also reject NaN.
+        if cell_v.iter().any(|v| !(0.0..=CELL_V_MAX).contains(v))
+            || remaining.iter().any(|r| !(0.0..=1.).contains(r))
+            || !(0.0..=CAPACITY_MAX).contains(&capacity)
+            || !(0.0..=DEBOUNCE_MAX).contains(&debounce)
+            || !(LIMIT_THROTTLE_MIN..=1.).contains(&limit_throttle)
+            || !(limit_tilt > 0. && limit_tilt <= core::f32::consts::FRAC_PI_2)
+        {
+            return None;
+        }
+
+        // Each stage must trip at or below the previous one, among those enabled.
+        let ordered = |vals: &[f32; 3]| {
+            let enabled = vals.iter().filter(|v| **v > 0.);
+            enabled.clone().zip(enabled.skip(1)).all(|(a, b)| b <= a)
+        };
+        if !ordered(&cell_v) || !ordered(&remaining) {
+            return None;
+        }
+
+        Some(Self {
+            cell_v,
+            remaining,
+            capacity,
+            debounce,
+            limit_throttle,
+            limit_tilt,
+            action,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; BATT_FAILSAFE_CFG_SIZE] {
+        let mut result = [0; BATT_FAILSAFE_CFG_SIZE];
+
+        for (i, v) in self
+            .cell_v
+            .iter()
+            .chain(self.remaining.iter())
+            .chain(
+                [
+                    self.capacity,
+                    self.debounce,
+                    self.limit_throttle,
+                    self.limit_tilt,
+                ]
+                .iter(),
+            )
+            .enumerate()
+        {
+            result[i * 4..i * 4 + 4].clone_from_slice(&v.to_be_bytes());
+        }
+        result[40] = self.action as u8;
+
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct BattFailsafe {
+    pub stage: BattStage,
+    /// The stage whose condition currently holds, if above `stage`, and how long it has.
+    pending: Option<(BattStage, f32)>,
+    armed: bool,
+    /// Set by `state::update_flight_modes` while the stage 3 action is commanding autopilot modes.
+    pub acting: bool,
+}
+
+impl BattFailsafe {
+    /// Run each battery reading. `batt_v` is in V, and `mah_used` is this flight's.
+    pub fn update(
+        &mut self,
+        batt_v: f32,
+        cell_count: BattCellCount,
+        mah_used: f32,
+        arm_status: ArmStatus,
+        cfg: &BattFailsafeCfg,
+        dt: f32,
+    ) {
+        let armed = arm_status == safety::MOTORS_ARMED;
+
+        // Each flight starts fresh; eg after a battery swap.
+        if armed && !self.armed {
+            self.stage = BattStage::Ok;
+            self.pending = None;
+        }
+        self.armed = armed;
+
+        let v = batt_v / cell_count.num_cells();
+        if v < BATT_PRESENT_CELL_V {
+            self.pending = None;
+            return;
+        }
+
+        let remaining = if cfg.capacity > 0. {
+            Some(1. - mah_used / cfg.capacity)
+        } else {
+            None
+        };
+
+        // The most severe stage whose condition holds.
+        let mut candidate = BattStage::Ok;
+        for i in 0..3 {
+            let low_v = v < cfg.cell_v[i];
+            let low_capacity = match remaining {
+                Some(r) => r < cfg.remaining[i],
+                None => false,
+            };
+
+            if low_v || low_capacity {
+                candidate = BattStage::from_index(i);
+            }
+        }
+
+        if candidate <= self.stage {
+            self.pending = None;
+            return;
+        }
+
+        let held = match self.pending {
+            // Escalating further while pending keeps the time held; the less severe condition
+            // has held throughout.
+            Some((_, t)) => t + dt,
+            None => dt,
+        };
+
+        if held < cfg.debounce {
+            self.pending = Some((candidate, held));
+            return;
+        }
+
+        println!("Battery failsafe stage: {}", candidate as u8);
+        event_log::log(
+            EventCode::BattFailsafe,
+            candidate as u16,
+            (batt_v * 100.) as u16,
+        );
+
+        self.stage = candidate;
+        self.pending = None;
+    }
+
+    /// Apply the `Limit` stage's throttle cap, to throttle commanded. Throttle may be signed, in
+    /// 3D mode.
+    pub fn limit_throttle(&self, throttle: f32, cfg: &BattFailsafeCfg) -> f32 {
+        if self.stage >= BattStage::Limit {
+            throttle.clamp(-cfg.limit_throttle, cfg.limit_throttle)
+        } else {
+            throttle
+        }
+    }
+
+    /// The tilt limit in effect, in radians, from the `Limit` stage. `None` if not limited.
+    pub fn max_tilt(&self, cfg: &BattFailsafeCfg) -> Option<f32> {
+        if self.stage >= BattStage::Limit {
+            Some(cfg.limit_tilt)
+        } else {
+            None
+        }
+    }
+
+    /// True if the stage 3 action should command autopilot modes. `override_switch` is the
+    /// pilot's `batt_override` switch.
+    pub fn action_required(&self, has_taken_off: bool, override_switch: bool) -> bool {
+        self.stage == BattStage::Action && has_taken_off && !override_switch
+    }
+}
@@ -0,0 +1,445 @@
+//! This module contains a lightweight flight data recorder ("blackbox"), for tuning. While armed
+//! and enabled, we log a decimated record of gyro rates, commands, and motor RPMs to flash.
+//! The PC application downloads the log over USB.
+//!
+//! Log format: A stream of records, each starting with a tag byte. Values are fixed-point
+//! integers, encoded as zigzag varints. Keyframes contain absolute values; the records between
+//! them contain deltas from the previous record. Each logging session starts with a header.
+//! Unused space at the end of a page is filled with 0xff; skip to the next page when reading it.
+//!
+//! Sessions are appended until the log region is full; erase it from the PC application.
+//!
+//! Onboard flash erases and writes stall the CPU, so while armed, onboard pages are held in RAM,
+//! and written after disarming. We have two page buffers, so an onboard session logs up to
+//! 8kb; on H7, whose onboard region is a single page, the whole region. Use the external flash
+//! for longer logs.
+
+use cfg_if::cfg_if;
+use defmt::println;
+use hal::flash::Flash;
+
+use crate::{
+    drivers::flash_spi::ExtFlash,
+    imu_processing::filter_imu::{GyroLpfCfg, RpmFilterCfg},
+    loop_rates,
+    storage::{NonVolatileStorage, OnboardStorage, StorageBackend, StorageError},
+};
+
+/// We write to flash a page at a time. Our log region consists of a whole number of these.
+pub const LOG_PAGE_SIZE: usize = 4_096;
+
+/// At the IMU rate of ~8kHz, this logs at ~1kHz.
+pub const DEFAULT_RATE_DIVISOR: u8 = 8;
+
+// Write absolute values every this many records, so a corrupt record only affects a short
+// stretch of the log.
+const KEYFRAME_INTERVAL: u32 = 32;
+
+// Worst-case encoded size of one record; a tag byte, the IMU loop count, and each field.
+const MAX_RECORD_SIZE: usize = 1 + 5 + NUM_FIELDS * 5;
+
+const TAG_HEADER: u8 = b'H';
+const TAG_KEYFRAME: u8 = b'I';
+const TAG_DELTA: u8 = b'P';
+/// Flash reads as this when erased.
+const TAG_ERASED: u8 = 0xff;
+
+// Fixed-point scale factors.
+const SCALE_RATE: f32 = 1_000.; // rad/s -> mrad/s
+const SCALE_QUAT: f32 = 10_000.;
+const SCALE_THROTTLE: f32 = 1_000.;
+
+// Gyro pitch, roll, yaw; rates commanded pitch, roll, yaw; attitude commanded w, x, y, z;
+// 4 motor RPMs; throttle; throttle after compensation; voltage compensation multiplier;
+// 4 dynamic idle corrections.
+const NUM_FIELDS: usize = 21;
+
+cfg_if! {
+    if #[cfg(feature = "h7")] {
+        // Sector 5. The HAL only writes from the start of a sector, so we only get one page out
+        // of it. The SPI flash is a better backend on H7.
+        const ONBOARD_LOG_FIRST_PAGE: usize = 5;
+        const ONBOARD_LOG_NUM_PAGES: usize = 1;
+    } else {
+        // Pages 96 - 123; below the brownout, flight stats, config, and last-position pages. This
+        // assumes the firmware fits in the first 384k.
+        const ONBOARD_LOG_FIRST_PAGE: usize = 96;
+        const ONBOARD_LOG_NUM_PAGES: usize = 28;
+    }
+}
+
+/// The flash region we log to: The spare region of the MCU's onboard flash, or the whole external
+/// SPI flash.
+pub enum LogStorage<'a> {
+    Onboard(OnboardStorage<'a>),
+    External(&'a mut ExtFlash),
+}
+
+impl<'a> LogStorage<'a> {
+    /// `backend` is the one resolved at init; see `Blackbox::backend`.
+    pub fn new(
+        backend: StorageBackend,
+        flash_onboard: &'a mut Flash,
+        flash_ext: &'a mut ExtFlash,
+    ) -> Self {
+        match backend {
+            StorageBackend::External => Self::External(flash_ext),
+            _ => Self::Onboard(OnboardStorage {
+                flash: flash_onboard,
+                first_page: ONBOARD_LOG_FIRST_PAGE,
+                num_pages: ONBOARD_LOG_NUM_PAGES,
+            }),
+        }
+    }
+}
+
+impl<'a> NonVolatileStorage for LogStorage<'a> {
+    fn size(&self) -> usize {
+        match self {
+            Self::Onboard(s) => s.size(),
+            Self::External(s) => s.size(),
+        }
+    }
+
+    fn erase_region(&mut self, addr: usize, len: usize) -> Result<(), StorageError> {
+        match self {
+            Self::Onboard(s) => s.erase_region(addr, len),
+            Self::External(s) => s.erase_region(addr, len),
+        }
+    }
+
+    fn write_region(&mut self, addr: usize, data: &[u8]) -> Result<(), StorageError> {
+        match self {
+            Self::Onboard(s) => s.write_region(addr, data),
+            Self::External(s) => s.write_region(addr, data),
+        }
+    }
+
+    fn read_region(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        match self {
+            Self::Onboard(s) => s.read_region(addr, buf),
+            Self::External(s) => s.read_region(addr, buf),
+        }
+    }
+}
+
+/// Values logged each record, in floating point.
+pub struct LogRecord {
+    /// Gyro readings, after filtering. Pitch, roll, yaw. rad/s.
+    pub gyro: (f32, f32, f32),
+    /// Pitch, roll, yaw. rad/s.
+    pub rates_commanded: (f32, f32, f32),
+    /// w, x, y, z.
+    pub attitude_commanded: (f32, f32, f32, f32),
+    /// Mechanical RPM. 0 if no reading.
+    pub rpms: [f32; 4],
+    /// 0. to 1.
+    pub throttle: f32,
+    /// Throttle after thrust linearization and voltage compensation. 0. to 1.
+    pub throttle_out: f32,
+    /// Voltage compensation multiplier.
+    pub volt_comp: f32,
+    /// Dynamic idle power correction, by motor. 0. to 1.
+    pub idle_correction: [f32; 4],
+}
+
+impl LogRecord {
+    fn to_fixed(&self) -> [i32; NUM_FIELDS] {
+        let r = |v: f32| (v * SCALE_RATE) as i32;
+        let q = |v: f32| (v * SCALE_QUAT) as i32;
+
+        [
+            r(self.gyro.0),
+            r(self.gyro.1),
+            r(self.gyro.2),
+            r(self.rates_commanded.0),
+            r(self.rates_commanded.1),
+            r(self.rates_commanded.2),
+            q(self.attitude_commanded.0),
+            q(self.attitude_commanded.1),
+            q(self.attitude_commanded.2),
+            q(self.attitude_commanded.3),
+            self.rpms[0] as i32,
+            self.rpms[1] as i32,
+            self.rpms[2] as i32,
+            self.rpms[3] as i32,
+            (self.throttle * SCALE_THROTTLE) as i32,
+            (self.throttle_out * SCALE_THROTTLE) as i32,
+            (self.volt_comp * SCALE_THROTTLE) as i32,
+            (self.idle_correction[0] * SCALE_THROTTLE) as i32,
+            (self.idle_correction[1] * SCALE_THROTTLE) as i32,
+            (self.idle_correction[2] * SCALE_THROTTLE) as i32,
+            (self.idle_correction[3] * SCALE_THROTTLE) as i32,
+        ]
+    }
+}
+
+/// Encode a signed integer as a zigzag varint. Returns the number of bytes written.
+fn write_varint(val: i32, buf: &mut [u8]) -> usize {
+    let mut v = ((val << 1) ^ (val >> 31)) as u32;
+    let mut i = 0;
+
+    loop {
+        if v < 0x80 {
+            buf[i] = v as u8;
+            return i + 1;
+        }
+        buf[i] = (v as u8 & 0x7f) | 0x80;
+        v >>= 7;
+        i += 1;
+    }
+}
+
+/// Blackbox state. Records are assembled in RAM a page at a time; full pages are written to
+/// flash from the idle task, so the slow flash write doesn't run in the IMU ISR.
+pub struct Blackbox {
+    /// Where the log is stored; `Onboard` or `External`. Resolved from user config at init.
+    pub backend: StorageBackend,
+    /// Logging is enabled from the PC application. It may also be enabled with a switch.
+    pub enabled_usb: bool,
+    /// True while armed and enabled, and there's space remaining.
+    pub logging: bool,
+    /// The log region is full; we've stopped logging until it's erased.
+    pub full: bool,
+    /// Records dropped because a page was ready before the previous one had been written.
+    pub num_overruns: u32,
+    /// Onboard: The page buffer is waiting to be queued behind a held page. We don't log until
+    /// it's written.
+    held: bool,
+    page_buf: [u8; LOG_PAGE_SIZE],
+    buf_i: usize,
+    /// A page ready to be written to flash, and its page index.
+    page_ready: Option<usize>,
+    page_ready_buf: [u8; LOG_PAGE_SIZE],
+    /// The page the current buffer will be written to.
+    next_page: usize,
+    num_pages: usize,
+    records_since_keyframe: u32,
+    prev: [i32; NUM_FIELDS],
+}
+
+impl Default for Blackbox {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Onboard,
+            enabled_usb: false,
+            logging: false,
+            full: false,
+            num_overruns: 0,
+            held: false,
+            page_buf: [TAG_ERASED; LOG_PAGE_SIZE],
+            buf_i: 0,
+            page_ready: None,
+            page_ready_buf: [TAG_ERASED; LOG_PAGE_SIZE],
+            next_page: 0,
+            num_pages: 0,
+            records_since_keyframe: 0,
+            prev: [0; NUM_FIELDS],
+        }
+    }
+}
+
+impl Blackbox {
+    /// Find where the existing log ends, so new sessions are appended. Run this at init.
+    pub fn init(&mut self, storage: &mut impl NonVolatileStorage) {
+        self.num_pages = storage.size() / LOG_PAGE_SIZE;
+        self.next_page = self.num_pages;
+
+        for page in 0..self.num_pages {
+            let mut tag = [0];
+            storage.read_region(page * LOG_PAGE_SIZE, &mut tag).ok();
+
+            if tag[0] == TAG_ERASED {
+                self.next_page = page;
+                break;
+            }
+        }
+
+        self.full = self.next_page == self.num_pages;
+    }
+
+    /// Number of bytes in flash the PC application can download.
+    pub fn log_size(&self) -> u32 {
+        (self.next_page * LOG_PAGE_SIZE) as u32
+    }
+
+    /// Start a logging session, with a header record.
+    pub fn start(&mut self, rate_divisor: u8, gyro_lpf: &GyroLpfCfg, rpm_filter: &RpmFilterCfg) {
+        if self.full || self.held {
+            return;
+        }
+
+        let mut header = [0; 64];
+        header[0] = TAG_HEADER;
+
+        let version = env!("CARGO_PKG_VERSION").as_bytes();
+        let version_len = version.len().min(16);
+        header[1] = version_len as u8;
+        header[2..2 + version_len].copy_from_slice(&version[..version_len]);
+
+        let mut i = 2 + version_len;
+        let rates = loop_rates::rates();
+        header[i..i + 2].copy_from_slice(&(rates.imu as u16).to_be_bytes());
+        header[i + 2] = rates.flight_ctrl_imu_ratio as u8;
+        header[i + 3] = rate_divisor;
+        i += 4;
+
+        header[i] = gyro_lpf.lpf1.type_ as u8;
+        header[i + 1..i + 5].copy_from_slice(&gyro_lpf.lpf1.cutoff.to_be_bytes());
+        header[i + 5] = gyro_lpf.lpf2.type_ as u8;
+        header[i + 6..i + 10].copy_from_slice(&gyro_lpf.lpf2.cutoff.to_be_bytes());
+        i += 10;
+
+        header[i] = rpm_filter.enabled as u8;
+        header[i + 1..i + 5].copy_from_slice(&rpm_filter.q.to_be_bytes());
+        header[i + 5] = rpm_filter.second_harmonic as u8;
+        i += 6;
+
+        self.logging = true;
+        self.records_since_keyframe = KEYFRAME_INTERVAL; // Start with a keyframe.
+        self.push(&header[..i]);
+
+        println!("Blackbox logging started");
+    }
+
+    /// End a logging session, and queue the partial page for writing.
+    pub fn stop(&mut self) {
+        if !self.logging {
+            return;
+        }
+        self.logging = false;
+
+        if self.buf_i > 0 {
+            if self.onboard_page_held() {
+                self.held = true;
+            } else {
+                self.queue_page();
+            }
+        }
+        println!("Blackbox logging stopped");
+    }
+
+    /// Log a record. Run this from the IMU loop, at the configured divisor.
+    pub fn log(&mut self, record: &LogRecord, imu_loop_i: u32) {
+        if !self.logging {
+            return;
+        }
+
+        let vals = record.to_fixed();
+        let mut buf = [0; MAX_RECORD_SIZE];
+
+        let mut i = 1;
+        if self.records_since_keyframe >= KEYFRAME_INTERVAL {
+            buf[0] = TAG_KEYFRAME;
+            i += write_varint(imu_loop_i as i32, &mut buf[i..]);
+            for val in vals {
+                i += write_varint(val, &mut buf[i..]);
+            }
+            self.records_since_keyframe = 0;
+        } else {
+            buf[0] = TAG_DELTA;
+            for (val, prev) in vals.iter().zip(self.prev) {
+                i += write_varint(val.wrapping_sub(prev), &mut buf[i..]);
+            }
+            self.records_since_keyframe += 1;
+        }
+
+        self.prev = vals;
+        self.push(&buf[..i]);
+    }
+
+    /// Add bytes to the page buffer. Records don't span pages.
+    fn push(&mut self, data: &[u8]) {
+        if self.buf_i + data.len() > LOG_PAGE_SIZE {
+            if self.onboard_page_held() {
+                // The previous page is held until we disarm. Keep this one too, and stop.
+                self.logging = false;
+                self.held = true;
+                println!("Blackbox RAM buffer full; logging stopped until disarmed");
+                return;
+            }
+
+            self.queue_page();
+            // The next record after a page boundary must be a keyframe, so pages can be
+            // decoded independently.
+            if data[0] == TAG_DELTA {
+                self.records_since_keyframe = KEYFRAME_INTERVAL;
+                return;
+            }
+        }
+
+        if !self.logging {
+            return;
+        }
+
+        self.page_buf[self.buf_i..self.buf_i + data.len()].copy_from_slice(data);
+        self.buf_i += data.len();
+    }
+
+    /// Hand the page buffer off to be written from the idle task, and start a new one.
+    fn queue_page(&mut self) {
+        if self.page_ready.is_some() {
+            // The previous page hasn't been written yet; drop this one's contents.
+            self.num_overruns += 1;
+        } else {
+            self.page_ready_buf = self.page_buf;
+            self.page_ready = Some(self.next_page);
+            self.next_page += 1;
+        }
+
+        self.page_buf = [TAG_ERASED; LOG_PAGE_SIZE];
+        self.buf_i = 0;
+
+        if self.next_page >= self.num_pages {
+            self.logging = false;
+            self.full = true;
+            println!("Blackbox log region full");
+        }
+    }
+
+    /// The queued page is waiting for us to disarm, so we can't queue another.
+    fn onboard_page_held(&self) -> bool {
+        self.page_ready.is_some() && self.backend != StorageBackend::External
+    }
+
+    /// Take a page that's ready to be written, if there is one. Run this from the idle task,
+    /// then write the page at `page * LOG_PAGE_SIZE`. Onboard pages are held while armed.
+    pub fn take_page_ready(&mut self, armed: bool) -> Option<(usize, [u8; LOG_PAGE_SIZE])> {
+        if armed && self.backend != StorageBackend::External {
+            return None;
+        }
+
+        let result = self
+            .page_ready
+            .take()
+            .map(|page| (page, self.page_ready_buf));
+
+        // Queue the page held behind it, for the next pass.
+        if result.is_some() && self.held {
+            self.held = false;
+            self.queue_page();
+        }
+
+        result
+    }
+
+    /// Erase the log region. Blocking, and slow; only run this while not logging.
+    pub fn erase(&mut self, storage: &mut impl NonVolatileStorage) {
+        if self.logging {
+            return;
+        }
+
+        let size = self.num_pages * LOG_PAGE_SIZE;
+        if storage.erase_region(0, size).is_err() {
+            println!("Error erasing the blackbox log");
+        }
+
+        self.next_page = 0;
+        self.page_ready = None;
+        self.held = false;
+        self.page_buf = [TAG_ERASED; LOG_PAGE_SIZE];
+        self.buf_i = 0;
+        self.full = false;
+        self.num_overruns = 0;
+    }
+}
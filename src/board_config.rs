@@ -10,13 +10,15 @@ use hal::{
 };
 
 type PortPin = (Port, u8);
-type PortPinAlt = (Port, u8, u8);
+pub type PortPinAlt = (Port, u8, u8);
 
 #[cfg(feature = "h7")]
 pub const CAN_CLOCK: CanClock = CanClock::Mhz80;
 #[cfg(feature = "g4")]
 pub const CAN_CLOCK: CanClock = CanClock::Mhz170;
 
+// The SOF of the USB peripheral on PA11 and PA12: OTG1 on H723, and OTG2 on H743. Each has only
+// the one USB SOF sync source, at the same register value.
 #[cfg(feature = "h7")]
 pub const CRS_SYNC_SRC: CrsSyncSrc = CrsSyncSrc::OtgHs;
 #[cfg(feature = "g4")]
@@ -55,36 +57,15 @@ cfg_if! {
     if #[cfg(feature = "h7")] {
         pub const BATT_ADC_CH: u8 = 18;
         pub const CURR_ADC_CH: u8 = 16;
+        // The internal temperature sensor is only connected to ADC3, so we read it there; not
+        // on ADC1 with battery voltage and current.
+        pub const MCU_TEMP_ADC_CH: u8 = 18;
     } else {
         pub const BATT_ADC_CH: u8 = 2;
         pub const CURR_ADC_CH: u8 = 12;
-    }
-}
-
-// Choose PSC and ARR to get a target frequency. For example,  300 or 500Hz.
-// Generally you can go up to a maximum value. Pulse high time is the predominant
-// factor in servo rotation. Higher pulses generally correspond to CCW motion.
-
-// 170Mhz tim clock on G4.
-// 240Mhz or 240Mhz tim clock on H743
-// 260Mhz tim clock on H723 @ 520Mhz. 275Mhz @ 550Mhz
-cfg_if! {
-    if #[cfg(feature = "h7")] {
-        // 240Mhz tim clock.
-        // pub const PSC_SERVOS: u16 = 7;
-        // pub const ARR_SERVOS: u32 = 59_999;
-        // 200Mhz tim clock.
-        pub const PSC_SERVOS: u16 = 7;
-        pub const ARR_SERVOS: u32 = 49_999;
-        // 260Mhz tim clock.
-        // pub const PSC_SERVOS: u16 = 7;
-        // pub const ARR_SERVOS: u32 = 64_999;
-        // 275Mhz tim clock.
-        // pub const PSC_SERVOS: u16 = 8;
-        // pub const ARR_SERVOS: u32 = 61_110;
-    } else if #[cfg(feature = "g4")] {
-        pub const PSC_SERVOS: u16 = 6;
-        pub const ARR_SERVOS: u32 = 48_570;
+        // The internal temperature sensor is only connected to ADC1 and ADC5, so we read it on
+        // ADC1; not on ADC2 with battery voltage and current.
+        pub const MCU_TEMP_ADC_CH: u8 = 16;
     }
 }
 
@@ -99,6 +80,12 @@ cfg_if! {
     }
 }
 
+// The secondary IMU, on SPI4; see `dual_imu`. The ISM330DHCX's limit is 10Mhz. SPI4 runs from
+// APB2, at 100Mhz; 100Mhz / 16 = 6.25Mhz. The G4 board has no spare SPI pins, so dual IMU is H7
+// only.
+#[cfg(feature = "h7")]
+pub const IMU2_BAUD_DIV: BaudRate = BaudRate::Div16;
+
 // Pins
 cfg_if! {
     if #[cfg(feature = "h7")] {
@@ -113,7 +100,34 @@ cfg_if! {
         pub const PIN_OSD_TX: PortPinAlt = (A, 2, 7); // UART 2
         pub const PIN_OSD_RX: PortPinAlt = (A, 3, 7);
 
+        // ESC telemetry is receive-only.
+        pub const PIN_ESC_TELEM_RX: PortPinAlt = (A, 10, 7); // USART 1
+
         pub const PIN_CS_IMU: PortPin = (C, 4);
+
+        // Secondary IMU. SPI4.
+        pub const PIN_SCK_IMU2: PortPinAlt = (E, 12, 5);
+        pub const PIN_MISO_IMU2: PortPinAlt = (E, 13, 5);
+        pub const PIN_MOSI_IMU2: PortPinAlt = (E, 14, 5);
+        // E11, SPI4's hardware NSS, is the external flash's CS; see `setup`.
+        pub const PIN_CS_IMU2: PortPin = (E, 15);
+
+        // I2C1 for external sensors, via pads.
+        pub const PIN_I2C1_SCL: PortPinAlt = (B, 8, 4);
+        pub const PIN_I2C1_SDA: PortPinAlt = (B, 9, 4);
+        // I2C2 for the DPS310 barometer, and pads.
+        pub const PIN_I2C2_SCL: PortPinAlt = (B, 10, 4);
+        pub const PIN_I2C2_SDA: PortPinAlt = (B, 11, 4);
+
+        // Status LED, and buzzer. Both are active-high GPIO outputs. See the `indicators` module.
+        pub const PIN_LED: PortPin = (E, 3);
+        pub const PIN_BUZZER: PortPin = (E, 4);
+
+        // WS2812 LED strip data. TIM4 CH1.
+        pub const PIN_LED_STRIP: PortPinAlt = (D, 12, 2);
+
+        // Camera tilt servo. TIM15 CH1.
+        pub const PIN_CAMERA_TILT: PortPinAlt = (E, 5, 4);
     } else {
         pub const PIN_BATT_ADC: PortPin = (A, 1);  // ADC12, channel 1
         pub const PIN_CURR_ADC: PortPin = (B, 2);  // ADC2, channel 12
@@ -126,7 +140,24 @@ cfg_if! {
         pub const PIN_OSD_TX: PortPinAlt = (C, 10, 5);  // UART 4
         pub const PIN_OSD_RX: PortPinAlt = (C, 11, 5);
 
+        // ESC telemetry is receive-only.
+        pub const PIN_ESC_TELEM_RX: PortPinAlt = (B, 11, 7); // USART 3
+
         pub const PIN_CS_IMU: PortPin = (B, 12);
+
+        pub const PIN_I2C1_SCL: PortPinAlt = (A, 15, 4);
+        pub const PIN_I2C1_SDA: PortPinAlt = (B, 9, 4);
+        pub const PIN_I2C2_SCL: PortPinAlt = (A, 9, 4);
+        pub const PIN_I2C2_SDA: PortPinAlt = (A, 8, 4);
+
+        pub const PIN_LED: PortPin = (B, 5);
+        pub const PIN_BUZZER: PortPin = (B, 6);
+
+        // TIM4 CH2.
+        pub const PIN_LED_STRIP: PortPinAlt = (B, 7, 2);
+
+        // TIM15 CH1.
+        pub const PIN_CAMERA_TILT: PortPinAlt = (A, 2, 9);
     }
 }
 
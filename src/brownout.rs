@@ -0,0 +1,210 @@
+//! This module contains brownout detection: The battery voltage collapsing, eg as the battery is
+//! pulled, or ejected in a crash. We detect this from the ADC battery reading, while there's still
+//! time to act: Below a hard threshold, or falling faster than a configured rate. Either must hold
+//! for a short time, so a single noisy ADC reading doesn't stop the motors in flight.
+//!
+//! When it trips, we latch a flag. Motors are stopped, arming is blocked, and flash erases and
+//! writes refuse to start, so a collapse mid-write can't corrupt user config or records. (See
+//! `storage`.) We then write a marker to a pre-erased flash slot; this is a single program
+//! operation, so it's quick enough to complete on the remaining charge. On the next power-up, we
+//! report that the previous session ended in a brownout, and whether it was armed.
+//!
+//! The flag stays latched until power cycled.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use hal::flash::{Bank, Flash};
+
+use crate::sensors_shared::BattCellCount;
+
+/// Set when a brownout is detected. Checked before flash erases and writes.
+static BROWNOUT: AtomicBool = AtomicBool::new(false);
+
+// The arm status to write in the marker, as its repr, or `MARK_NONE`. Taken by the idle task.
+static MARK_PENDING: AtomicU8 = AtomicU8::new(MARK_NONE);
+
+const MARK_NONE: u8 = 0xff;
+
+// Marks the slot. Erased flash reads 0xff.
+const SLOT_MARKER: u8 = 0xb0;
+
+// Volts per cell. We only monitor once we've seen a connected battery for this time; eg not
+// while powered from USB only.
+const BATT_PRESENT_CELL_V: f32 = 3.;
+const BATT_PRESENT_TIME: f32 = 0.5;
+
+// Seconds. We measure the fall rate over this window, to reject ADC noise.
+const RATE_WINDOW: f32 = 0.01;
+
+// Seconds. Voltage must stay below the threshold for this long to trip, and the fall rate must
+// exceed its threshold for this long, ie over consecutive windows. A pulled battery stays low;
+// noise, and sag from a throttle punch, don't.
+const MIN_V_TIME: f32 = 0.03;
+const FALL_RATE_TIME: f32 = 0.02;
+
+// Thresholds outside these ranges are rejected when loading config.
+const MIN_CELL_V_MAX: f32 = 3.5;
+const MAX_FALL_RATE_MAX: f32 = 10_000.;
+
+// Serialized size: Min cell voltage, and max fall rate.
+pub const BROWNOUT_CFG_SIZE: usize = 4 + 4;
+
+/// Brownout thresholds. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct BrownoutCfg {
+    /// Volts per cell. Below this, we trip, regardless of how fast it fell. Set it well below
+    /// the lowest voltage sag under load.
+    pub min_cell_v: f32,
+    /// Volts per cell, per second. Falling faster than this trips. Sag from a throttle punch is a
+    /// small step; a pulled battery falls to 0 in milliseconds. 0. disables this check.
+    pub max_fall_rate: f32,
+}
+
+impl Default for BrownoutCfg {
+    fn default() -> Self {
+        Self {
+            min_cell_v: 2.5,
+            max_fall_rate: 200.,
+        }
+    }
+}
+
+impl BrownoutCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let min_cell_v = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let max_fall_rate = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(0.0..=MIN_CELL_V_MAX).contains(&min_cell_v)
+            || !(0.0..=MAX_FALL_RATE_MAX).contains(&max_fall_rate)
+        {
+            return None;
+        }
+
+        Some(Self {
+            min_cell_v,
+            max_fall_rate,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; BROWNOUT_CFG_SIZE] {
+        let mut result = [0; BROWNOUT_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.min_cell_v.to_be_bytes());
+        result[4..8].clone_from_slice(&self.max_fall_rate.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct BrownoutDetect {
+    /// Seconds the battery has been present, up to `BATT_PRESENT_TIME`.
+    present_time: f32,
+    /// Volts per cell, at the start of the rate window.
+    window_start_v: f32,
+    window_time: f32,
+    /// Seconds below the threshold voltage.
+    low_time: f32,
+    /// Seconds over consecutive windows falling faster than the threshold rate.
+    fall_time: f32,
+}
+
+impl BrownoutDetect {
+    /// Run each battery reading, with the ADC voltage. Returns true on the update a brownout is
+    /// detected; stop the motors, and disarm.
+    pub fn update(
+        &mut self,
+        batt_v: f32,
+        cell_count: BattCellCount,
+        arm_status: u8,
+        cfg: &BrownoutCfg,
+        dt: f32,
+    ) -> bool {
+        if active() {
+            return false;
+        }
+
+        let v = batt_v / cell_count.num_cells();
+
+        if self.present_time < BATT_PRESENT_TIME {
+            if v > BATT_PRESENT_CELL_V {
+                self.present_time += dt;
+            } else {
+                self.present_time = 0.;
+            }
+            self.window_start_v = v;
+            self.window_time = 0.;
+            return false;
+        }
+
+        if v < cfg.min_cell_v {
+            self.low_time += dt;
+        } else {
+            self.low_time = 0.;
+        }
+
+        self.window_time += dt;
+        if self.window_time >= RATE_WINDOW {
+            let fall_rate = (self.window_start_v - v) / self.window_time;
+            if cfg.max_fall_rate > 0. && fall_rate > cfg.max_fall_rate {
+                self.fall_time += self.window_time;
+            } else {
+                self.fall_time = 0.;
+            }
+
+            self.window_start_v = v;
+            self.window_time = 0.;
+        }
+
+        let tripped = self.low_time >= MIN_V_TIME || self.fall_time >= FALL_RATE_TIME;
+
+        if tripped {
+            BROWNOUT.store(true, Ordering::Release);
+            MARK_PENDING.store(arm_status, Ordering::Release);
+        }
+
+        tripped
+    }
+}
+
+/// True if a brownout has been detected this session.
+pub fn active() -> bool {
+    BROWNOUT.load(Ordering::Acquire)
+}
+
+/// Run from the idle task, before any other flash operations. Writes the marker, if pending.
+pub fn write_mark_pending(flash: &mut Flash) {
+    let arm_status = MARK_PENDING.swap(MARK_NONE, Ordering::AcqRel);
+    if arm_status == MARK_NONE {
+        return;
+    }
+
+    // The slot was erased at power-up, so this doesn't need an erase.
+    flash
+        .write_page(
+            Bank::B1,
+            crate::FLASH_BROWNOUT_PAGE,
+            &[SLOT_MARKER, arm_status],
+        )
+        .ok();
+}
+
+/// Run at init. If the previous session ended in a brownout, returns the arm status at the time,
+/// as its repr. Erases the slot, so it's ready for this session.
+pub fn check_prev_session(flash: &mut Flash) -> Option<u8> {
+    let mut buf = [0; 2];
+    flash.read(Bank::B1, crate::FLASH_BROWNOUT_PAGE, 0, &mut buf);
+
+    if buf[0] == 0xff {
+        return None;
+    }
+
+    flash.erase_page(Bank::B1, crate::FLASH_BROWNOUT_PAGE).ok();
+
+    if buf[0] == SLOT_MARKER {
+        Some(buf[1])
+    } else {
+        None
+    }
+}
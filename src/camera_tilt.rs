@@ -0,0 +1,192 @@
+//! This module contains single-axis camera tilt: A servo that rotates the camera about the pitch
+//! axis. We counter the aircraft's pitch, so the horizon stays in place during forward flight, and
+//! add an offset from a receiver channel, eg a knob, so the pilot can adjust the view.
+//!
+//! Positive servo positions tilt the camera up; set `reversed` if the linkage is the other way.
+//! The servo runs on its own timer at 50Hz, which all servos support, so it's available on quads,
+//! and on fixed-wing alongside the elevons.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use ahrs::Params;
+use hal::timer::{OutputCompare, TimChannel};
+
+use crate::{
+    controller_interface::ChannelData,
+    protocols::servo::{ServoJog, ServoPulseCfg, SERVO_PULSE_CFG_SIZE},
+    safety::ArmStatus,
+    setup::CameraTimer,
+};
+
+/// Set over USB, to hold the servo at an endpoint for mechanical setup. A `ServoJog`, as its repr,
+/// or `JOG_NONE`. Only applies while disarmed; cleared on arming.
+pub static JOG_USB: AtomicU8 = AtomicU8::new(JOG_NONE);
+
+pub const JOG_NONE: u8 = 0xff;
+
+// Hz.
+const SERVO_FREQ: f32 = 50.;
+
+const TIM_CHANNEL: TimChannel = TimChannel::C1;
+
+// Seconds between servo updates. (100Hz) Faster than the servo frame rate, so we don't add much
+// latency; the flight control rate would be overkill.
+const UPDATE_INTERVAL: f32 = 0.01;
+
+// Compensation gains outside this range are rejected when loading config.
+const COMP_GAIN_MAX: f32 = 2.;
+
+// Serialized size: Enabled, reversed, pulse widths, travel, and compensation gain.
+pub const CAMERA_TILT_CFG_SIZE: usize = 2 + SERVO_PULSE_CFG_SIZE + 4 + 4;
+
+/// Camera tilt settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct CameraTiltCfg {
+    /// If false, no pulses are sent.
+    pub enabled: bool,
+    pub reversed: bool,
+    /// Servo limits, in µs.
+    pub pulse: ServoPulseCfg,
+    /// Radians the camera tilts, from center to either end of the servo's travel.
+    pub travel: f32,
+    /// Portion of aircraft pitch to counter. 1. holds the camera level, within its travel; 0.
+    /// keeps it fixed to the frame.
+    pub comp_gain: f32,
+}
+
+impl Default for CameraTiltCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            reversed: false,
+            pulse: Default::default(),
+            travel: 45_f32.to_radians(),
+            comp_gain: 1.,
+        }
+    }
+}
+
+impl CameraTiltCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let i = 2 + SERVO_PULSE_CFG_SIZE;
+
+        let pulse = ServoPulseCfg::from_bytes(&buf[2..i]);
+        let travel = f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let comp_gain = f32::from_be_bytes(buf[i + 4..i + 8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if buf[0] > 1
+            || buf[1] > 1
+            || !pulse.valid()
+            || !(travel > 0. && travel <= core::f32::consts::PI)
+            || !(0.0..=COMP_GAIN_MAX).contains(&comp_gain)
+        {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            reversed: buf[1] != 0,
+            pulse,
+            travel,
+            comp_gain,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; CAMERA_TILT_CFG_SIZE] {
+        let mut result = [0; CAMERA_TILT_CFG_SIZE];
+        let i = 2 + SERVO_PULSE_CFG_SIZE;
+
+        result[0] = self.enabled as u8;
+        result[1] = self.reversed as u8;
+        result[2..i].clone_from_slice(&self.pulse.to_bytes());
+        result[i..i + 4].clone_from_slice(&self.travel.to_be_bytes());
+        result[i + 4..i + 8].clone_from_slice(&self.comp_gain.to_be_bytes());
+        result
+    }
+}
+
+/// Owns the camera servo timer.
+pub struct CameraTilt {
+    timer: CameraTimer,
+    /// From the receiver channel, -1. to 1. We hold the last value if the link is lost.
+    offset: f32,
+    /// Seconds since start.
+    last_update: f32,
+}
+
+impl CameraTilt {
+    /// The servo pin must already be set to the timer's alternate function. No pulses are sent
+    /// until the first update.
+    pub fn new(mut timer: CameraTimer) -> Self {
+        timer.set_freq(SERVO_FREQ).ok();
+        timer.enable_pwm_output(TIM_CHANNEL, OutputCompare::Pwm1, 0.);
+        timer.enable();
+
+        Self {
+            timer,
+            offset: 0.,
+            last_update: 0.,
+        }
+    }
+
+    /// Run each main loop update; this only sets the servo at `UPDATE_INTERVAL`.
+    pub fn update(
+        &mut self,
+        params: &Params,
+        ch_data: &Option<ChannelData>,
+        arm_status: ArmStatus,
+        cfg: &CameraTiltCfg,
+        timestamp: f32,
+    ) {
+        if timestamp - self.last_update < UPDATE_INTERVAL {
+            return;
+        }
+        self.last_update = timestamp;
+
+        if arm_status != ArmStatus::Disarmed {
+            JOG_USB.store(JOG_NONE, Ordering::Release);
+        }
+
+        if !cfg.enabled {
+            self.set_duty(0.);
+            return;
+        }
+
+        if let Some(ch) = ch_data {
+            self.offset = ch.camera_tilt;
+        }
+
+        let posit = match ServoJog::try_from(JOG_USB.load(Ordering::Acquire)) {
+            // Jog positions are the pulse width limits, so ignore reversal.
+            Ok(jog) => jog.posit(),
+            Err(_) => {
+                let pitch = params.attitude.to_euler().pitch;
+                let angle = self.offset * cfg.travel - cfg.comp_gain * pitch;
+
+                let posit = angle / cfg.travel;
+                if cfg.reversed {
+                    -posit
+                } else {
+                    posit
+                }
+            }
+        };
+
+        self.set_duty(cfg.pulse.pulse_width(posit));
+    }
+
+    /// Set the pulse width, in µs. 0. stops pulses.
+    fn set_duty(&mut self, pulse_width: f32) {
+        // µs to portion of the period.
+        let duty = pulse_width * SERVO_FREQ / 1_000_000.;
+
+        let duty_arr = (duty * self.timer.get_max_duty() as f32) as u32;
+
+        #[cfg(feature = "h7")]
+        let duty_arr = duty_arr as u16;
+
+        self.timer.set_duty(TIM_CHANNEL, duty_arr);
+    }
+}
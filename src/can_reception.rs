@@ -1,11 +1,12 @@
 //! This module handles CAN reception, as from the appropriate ISR
 
 use defmt::println;
-use dronecan::{CanId, MsgType};
+use dronecan::{f16, CanId, MsgType};
 use fdcan::{id::Id, interrupt::Interrupt};
+use lin_alg::f32::Vec3;
 use rtic::mutex_prelude::*;
 
-use crate::{app, drivers::gnss_can};
+use crate::{app, drivers::gnss_can, sensors_shared::MagSource};
 
 static mut RX_BUF_CAN: [u8; 100] = [0; 100];
 
@@ -83,14 +84,17 @@ pub fn run(mut cx: app::can_isr::Context) {
                         // println!("Temp: {} K", temp);
                     }
                     MsgType::MagneticFieldStrength2 => {
-                        println!("Magnetic field strength");
-                        // let x =
-                        //     f32::from(f16::from_le_bytes(rx_buf[1..3].try_into().unwrap()));
-                        // let y =
-                        //     f32::from(f16::from_le_bytes(rx_buf[3..5].try_into().unwrap()));
-                        // let z =
-                        //     f32::from(f16::from_le_bytes(rx_buf[5..7].try_into().unwrap()));
-                        // println!("Mag. x: {}, y: {}, z: {}", x, y, z);
+                        // Byte 0 is sensor ID. Readings are in gauss; convert to µT.
+                        let x =
+                            f32::from(f16::from_le_bytes(rx_buf[1..3].try_into().unwrap())) * 100.;
+                        let y =
+                            f32::from(f16::from_le_bytes(rx_buf[3..5].try_into().unwrap())) * 100.;
+                        let z =
+                            f32::from(f16::from_le_bytes(rx_buf[5..7].try_into().unwrap())) * 100.;
+
+                        cx.shared.mag_reading.lock(|mag_reading| {
+                            *mag_reading = Some((Vec3::new(x, y, z), MagSource::Can));
+                        });
                     }
                     MsgType::NodeStatus => {
                         let uptime = u32::from_le_bytes(rx_buf[0..4].try_into().unwrap());
@@ -8,11 +8,16 @@ use core::sync::atomic::Ordering;
 
 use cfg_if::cfg_if;
 use defmt::println;
+use num_enum::TryFromPrimitive;
 
 use crate::{
-    protocols::crsf::{self, ChannelDataCrsf, LinkStats},
-    safety::ArmStatus,
-    setup,
+    event_log::{self, EventCode},
+    flight_ctrls::follow_me,
+    protocols::{
+        crsf::{self, ChannelDataCrsf, LinkStats},
+        sbus,
+    },
+    safety::{ArmStatus, PrearmStatus},
     system_status::{self, SensorStatus, SystemStatus},
     util,
 };
@@ -21,6 +26,20 @@ const CONTROL_VAL_MIN: f32 = -1.;
 const CONTROL_VAL_MIN_THROTTLE: f32 = 0.;
 const CONTROL_VAL_MAX: f32 = 1.;
 
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+/// The protocol our radio receiver uses. Stored in user config; applied at init.
+pub enum RxProtocol {
+    Crsf = 0,
+    Sbus = 1,
+}
+
+impl Default for RxProtocol {
+    fn default() -> Self {
+        Self::Crsf
+    }
+}
+
 #[derive(Clone, Copy, PartialEq)]
 #[repr(u8)]
 /// For the switch position. We interpret actual mode from this, and other data, like prescense of GPS.
@@ -133,6 +152,312 @@ impl Default for InputModeSwitch {
     }
 }
 
+// Marks a function as unassigned, in serialized channel maps.
+const UNASSIGNED: u8 = 0xff;
+
+// Serialized size: A channel for each function, then 4 thresholds, then the prearm, beeper,
+// turtle mode, camera tilt, control profile, Acro Trainer, follow-me, beginner, battery failsafe
+// override, external control, and motor kill channels.
+pub const CHANNEL_MAP_SIZE: usize = 10 + 2 * 4 + 11;
+
+/// Which receiver channel each switch function is on, and the thresholds used to interpret
+/// switch positions. Stored in user config, so it can be set up for a given radio from the PC.
+/// Channels are 0-based indices into `ChannelDataCrsf::to_array`; 0 - 3 are the sticks, and 4 is
+/// AUX1. `None` means unassigned; these functions use their safe position, eg disarmed.
+#[derive(Clone, Copy)]
+pub struct ChannelMap {
+    pub arm: Option<u8>,
+    /// Fixed-wing only.
+    pub controls_arm: Option<u8>,
+    pub input_mode: Option<u8>,
+    /// Eg loiter, or orbit.
+    pub autopilot_a: Option<u8>,
+    /// Eg heading hold, or land.
+    pub autopilot_b: Option<u8>,
+    pub steerpoint_cycle: Option<u8>,
+    pub pid_tune_mode: Option<u8>,
+    pub pid_tune_actuation: Option<u8>,
+    pub level_attitude: Option<u8>,
+    pub blackbox: Option<u8>,
+    /// Raw channel value above which the arm switch reads armed. Higher than the general 2-position
+    /// threshold, for margin.
+    pub arm_thresh: u16,
+    /// Raw channel value above which a 2-position switch reads on.
+    pub two_pos_thresh: u16,
+    /// Raw channel values separating a 3-position switch's low, middle, and high positions.
+    pub three_pos_thresh: (u16, u16),
+    /// If assigned, this must be active to arm. See `safety::handle_arm_status`.
+    pub prearm: Option<u8>,
+    /// Sounds the lost-model beacon. See the `indicators` module.
+    pub beeper: Option<u8>,
+    /// Quad only. See the `turtle` module.
+    pub turtle: Option<u8>,
+    /// A knob or slider, vice a switch. See the `camera_tilt` module.
+    pub camera_tilt: Option<u8>,
+    /// A 3-position switch. See `flight_ctrls::profiles`.
+    pub profile: Option<u8>,
+    /// Quad only. See the `acro_trainer` module.
+    pub acro_trainer: Option<u8>,
+    /// Quad only. See the `follow_me` module.
+    pub follow_me: Option<u8>,
+    /// Applies the beginner throttle limit. See `flight_ctrls::throttle_limit`.
+    pub beginner: Option<u8>,
+    /// Held on, prevents the battery failsafe's action. See the `batt_failsafe` module.
+    pub batt_override: Option<u8>,
+    /// Allows attitude commands from a companion computer. See the `ext_ctrl` module.
+    pub ext_ctrl: Option<u8>,
+    /// Stops the motors, latched until released with throttle idle. See the `motor_kill` module.
+    pub kill: Option<u8>,
+}
+
+impl Default for ChannelMap {
+    /// ExpressLRS puts the arm switch on AUX1.
+    fn default() -> Self {
+        Self {
+            arm: Some(4),
+            controls_arm: Some(13),
+            input_mode: Some(5),
+            autopilot_a: Some(7),
+            autopilot_b: Some(8),
+            steerpoint_cycle: Some(9),
+            pid_tune_mode: Some(10),
+            pid_tune_actuation: Some(11),
+            level_attitude: Some(12),
+            blackbox: Some(14),
+            arm_thresh: 1_500,
+            two_pos_thresh: 1_000,
+            three_pos_thresh: (667, 1_333),
+            prearm: None,
+            beeper: None,
+            turtle: None,
+            camera_tilt: None,
+            profile: None,
+            acro_trainer: None,
+            follow_me: None,
+            beginner: None,
+            batt_override: None,
+            ext_ctrl: None,
+            kill: None,
+        }
+    }
+}
+
+impl ChannelMap {
+    fn functions(&self) -> [Option<u8>; 10] {
+        [
+            self.arm,
+            self.controls_arm,
+            self.input_mode,
+            self.autopilot_a,
+            self.autopilot_b,
+            self.steerpoint_cycle,
+            self.pid_tune_mode,
+            self.pid_tune_actuation,
+            self.level_attitude,
+            self.blackbox,
+        ]
+    }
+
+    /// Parse and validate. Returns `None` if a channel or threshold is out of range, if the arm,
+    /// prearm, beeper, turtle, camera tilt, profile, Acro Trainer, follow-me, beginner, battery
+    /// override, external control, or kill channel is on a stick channel, if the arm
+    /// or prearm switch shares a channel with another function, or if the turtle switch shares one
+    /// with the beeper.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let parse_ch = |v: u8| match v {
+            UNASSIGNED => Ok(None),
+            v if (v as usize) < crsf::NUM_CHANNELS => Ok(Some(v)),
+            _ => Err(()),
+        };
+
+        let mut ch = [None; 10];
+        for (i, c) in ch.iter_mut().enumerate() {
+            *c = parse_ch(buf[i]).ok()?;
+        }
+        let prearm = parse_ch(buf[18]).ok()?;
+        let beeper = parse_ch(buf[19]).ok()?;
+        let turtle = parse_ch(buf[20]).ok()?;
+        let camera_tilt = parse_ch(buf[21]).ok()?;
+        let profile = parse_ch(buf[22]).ok()?;
+        let acro_trainer = parse_ch(buf[23]).ok()?;
+        let follow_me = parse_ch(buf[24]).ok()?;
+        let beginner = parse_ch(buf[25]).ok()?;
+        let batt_override = parse_ch(buf[26]).ok()?;
+        let ext_ctrl = parse_ch(buf[27]).ok()?;
+        let kill = parse_ch(buf[28]).ok()?;
+
+        let thresh = |i: usize| u16::from_be_bytes(buf[i..i + 2].try_into().unwrap());
+
+        let result = Self {
+            arm: ch[0],
+            controls_arm: ch[1],
+            input_mode: ch[2],
+            autopilot_a: ch[3],
+            autopilot_b: ch[4],
+            steerpoint_cycle: ch[5],
+            pid_tune_mode: ch[6],
+            pid_tune_actuation: ch[7],
+            level_attitude: ch[8],
+            blackbox: ch[9],
+            arm_thresh: thresh(10),
+            two_pos_thresh: thresh(12),
+            three_pos_thresh: (thresh(14), thresh(16)),
+            prearm,
+            beeper,
+            turtle,
+            camera_tilt,
+            profile,
+            acro_trainer,
+            follow_me,
+            beginner,
+            batt_override,
+            ext_ctrl,
+            kill,
+        };
+
+        let range = crsf::CHANNEL_VAL_MIN..=crsf::CHANNEL_VAL_MAX;
+        if !range.contains(&result.arm_thresh)
+            || !range.contains(&result.two_pos_thresh)
+            || !range.contains(&result.three_pos_thresh.0)
+            || !range.contains(&result.three_pos_thresh.1)
+            || result.three_pos_thresh.0 >= result.three_pos_thresh.1
+        {
+            return None;
+        }
+
+        if let Some(arm) = result.arm {
+            if arm < 4 || ch[1..].contains(&Some(arm)) || prearm == Some(arm) {
+                return None;
+            }
+        }
+
+        if let Some(prearm) = prearm {
+            if prearm < 4 || ch.contains(&Some(prearm)) {
+                return None;
+            }
+        }
+
+        if let Some(beeper) = beeper {
+            if beeper < 4 || result.arm == Some(beeper) || prearm == Some(beeper) {
+                return None;
+            }
+        }
+
+        if let Some(turtle) = turtle {
+            if turtle < 4
+                || result.arm == Some(turtle)
+                || prearm == Some(turtle)
+                || beeper == Some(turtle)
+            {
+                return None;
+            }
+        }
+
+        if let Some(camera_tilt) = camera_tilt {
+            if camera_tilt < 4 || result.arm == Some(camera_tilt) || prearm == Some(camera_tilt) {
+                return None;
+            }
+        }
+
+        if let Some(profile) = profile {
+            if profile < 4 || result.arm == Some(profile) || prearm == Some(profile) {
+                return None;
+            }
+        }
+
+        if let Some(acro_trainer) = acro_trainer {
+            if acro_trainer < 4 || result.arm == Some(acro_trainer) || prearm == Some(acro_trainer)
+            {
+                return None;
+            }
+        }
+
+        if let Some(follow_me) = follow_me {
+            if follow_me < 4 || result.arm == Some(follow_me) || prearm == Some(follow_me) {
+                return None;
+            }
+        }
+
+        if let Some(beginner) = beginner {
+            if beginner < 4 || result.arm == Some(beginner) || prearm == Some(beginner) {
+                return None;
+            }
+        }
+
+        if let Some(batt_override) = batt_override {
+            if batt_override < 4
+                || result.arm == Some(batt_override)
+                || prearm == Some(batt_override)
+            {
+                return None;
+            }
+        }
+
+        if let Some(ext_ctrl) = ext_ctrl {
+            if ext_ctrl < 4 || result.arm == Some(ext_ctrl) || prearm == Some(ext_ctrl) {
+                return None;
+            }
+        }
+
+        if let Some(kill) = kill {
+            if kill < 4 || result.arm == Some(kill) || prearm == Some(kill) {
+                return None;
+            }
+        }
+
+        Some(result)
+    }
+
+    pub fn to_bytes(&self) -> [u8; CHANNEL_MAP_SIZE] {
+        let mut result = [0; CHANNEL_MAP_SIZE];
+
+        for (i, c) in self.functions().iter().enumerate() {
+            result[i] = c.unwrap_or(UNASSIGNED);
+        }
+
+        result[10..12].clone_from_slice(&self.arm_thresh.to_be_bytes());
+        result[12..14].clone_from_slice(&self.two_pos_thresh.to_be_bytes());
+        result[14..16].clone_from_slice(&self.three_pos_thresh.0.to_be_bytes());
+        result[16..18].clone_from_slice(&self.three_pos_thresh.1.to_be_bytes());
+        result[18] = self.prearm.unwrap_or(UNASSIGNED);
+        result[19] = self.beeper.unwrap_or(UNASSIGNED);
+        result[20] = self.turtle.unwrap_or(UNASSIGNED);
+        result[21] = self.camera_tilt.unwrap_or(UNASSIGNED);
+        result[22] = self.profile.unwrap_or(UNASSIGNED);
+        result[23] = self.acro_trainer.unwrap_or(UNASSIGNED);
+        result[24] = self.follow_me.unwrap_or(UNASSIGNED);
+        result[25] = self.beginner.unwrap_or(UNASSIGNED);
+        result[26] = self.batt_override.unwrap_or(UNASSIGNED);
+        result[27] = self.ext_ctrl.unwrap_or(UNASSIGNED);
+        result[28] = self.kill.unwrap_or(UNASSIGNED);
+        result
+    }
+
+    /// A 3-position switch: 0, 1, or 2, from low to high. Unassigned reads `default`.
+    fn three_pos(&self, channels: &[u16; crsf::NUM_CHANNELS], ch: Option<u8>, default: u8) -> u8 {
+        let Some(c) = ch else {
+            return default;
+        };
+
+        let val = channels[c as usize];
+        if val <= self.three_pos_thresh.0 {
+            0
+        } else if val <= self.three_pos_thresh.1 {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+/// A 2-position switch. Unassigned reads off.
+fn two_pos(channels: &[u16; crsf::NUM_CHANNELS], ch: Option<u8>, thresh: u16) -> bool {
+    match ch {
+        Some(c) => channels[c as usize] > thresh,
+        None => false,
+    }
+}
+
 /// Map a raw CRSF channel value to a useful value.
 fn channel_to_val(mut chan_val: u16, is_throttle: bool) -> f32 {
     if chan_val < crsf::CHANNEL_VAL_MIN {
@@ -169,6 +494,8 @@ pub struct ChannelData {
     /// switch. (Quad), 3-pos switch (fixed-wing)
     /// todo: Currently, the ELRS arm channel is hard set and 2-pos, so we use 2 2-pos switches.
     pub arm_status: ArmStatus,
+    /// Must be active to arm, if a prearm channel is assigned.
+    pub prearm: PrearmStatus,
     /// Ie angular-rate-based (Acro), or Loiter (with GPS present) or attitude-based
     /// (no GPS present). Ideally on 2-position non-spring switch.
     pub input_mode: InputModeSwitch, // todo: Reconsider how this works.
@@ -185,23 +512,51 @@ pub struct ChannelData {
     pub pid_tune_actuation: PidTuneActuation, // todo: Auto-recover commanded, auto-TO/land/RTB, obstacle avoidance etc.
     /// Auto command level attitude. Ideally on a button
     pub level_attitude_commanded: bool,
+    /// Log to the blackbox while armed. Ideally on a 2-position non-spring switch.
+    pub blackbox: bool,
+    /// Sound the lost-model beacon.
+    pub beeper: bool,
+    /// Enter turtle mode, if disarmed and inverted.
+    pub turtle: bool,
+    /// Camera tilt offset, -1. to 1. 0. if unassigned.
+    pub camera_tilt: f32,
+    /// Control profile index, from a 3-position switch. `None` if unassigned.
+    pub profile: Option<u8>,
+    /// Limit tilt in Acro. See the `acro_trainer` module.
+    pub acro_trainer: bool,
+    /// Follow a target from the ground station. See the `follow_me` module.
+    pub follow_me: bool,
+    /// Apply the beginner throttle limit. See `flight_ctrls::throttle_limit`.
+    pub beginner: bool,
+    /// Prevent the battery failsafe's action. See the `batt_failsafe` module.
+    pub batt_override: bool,
+    /// Use attitude commands from a companion computer, while fresh. See the `ext_ctrl` module.
+    pub ext_ctrl: bool,
+    /// Stop the motors. See the `motor_kill` module.
+    pub kill: bool,
+    /// All channels, as received; used to identify switches during setup.
+    pub raw: [u16; crsf::NUM_CHANNELS],
 }
 
 impl ChannelData {
-    pub fn from_channel_data(crsf_data: &ChannelDataCrsf) -> Self {
+    pub fn from_channel_data(crsf_data: &ChannelDataCrsf, map: &ChannelMap) -> Self {
+        let raw = crsf_data.to_array();
+
         // https://www.expresslrs.org/3.0/software/switch-config/:
         // "WARNING: Put your arm switch on AUX1, and set it as ~1000 is disarmed, ~2000 is armed."
         // todo: On fixed wing, you want this to be a 3-pos switch, but this may not be
         // todo possible with ELRS, with this channel hard-coded as a 2-pos arm sw?
-        let motors_armed = match crsf_data.aux_1 {
-            0..=1_500 => false,
-            // 0..=1_500 => ArmStatus::Disarmed,
-            _ => true,
-            // _ => motors_armed,
+        let motors_armed = two_pos(&raw, map.arm, map.arm_thresh);
+
+        let prearm = match map.prearm {
+            Some(_) if two_pos(&raw, map.prearm, map.two_pos_thresh) => PrearmStatus::Active,
+            Some(_) => PrearmStatus::Inactive,
+            None => PrearmStatus::NotConfigured,
         };
-        let input_mode = match crsf_data.aux_2 {
-            0..=667 => InputModeSwitch::Acro,
-            668..=1_333 => InputModeSwitch::AttitudeLoiter,
+
+        let input_mode = match map.three_pos(&raw, map.input_mode, 0) {
+            0 => InputModeSwitch::Acro,
+            1 => InputModeSwitch::AttitudeLoiter,
             _ => InputModeSwitch::Route,
         };
 
@@ -211,49 +566,69 @@ impl ChannelData {
         //     _ => AltHoldSwitch::EnabledAgl,
         // };
 
-        let autopilot_a = match crsf_data.aux_4 {
-            0..=667 => AutopilotSwitchA::Disabled,
-            668..=1_333 => AutopilotSwitchA::LoiterOrbit,
+        let autopilot_a = match map.three_pos(&raw, map.autopilot_a, 0) {
+            0 => AutopilotSwitchA::Disabled,
+            1 => AutopilotSwitchA::LoiterOrbit,
             _ => AutopilotSwitchA::DirectToPoint,
         };
 
-        let autopilot_b = match crsf_data.aux_5 {
-            0..=667 => AutopilotSwitchB::Disabled,
-            668..=1_333 => AutopilotSwitchB::HdgHold,
+        let autopilot_b = match map.three_pos(&raw, map.autopilot_b, 0) {
+            0 => AutopilotSwitchB::Disabled,
+            1 => AutopilotSwitchB::HdgHold,
             _ => AutopilotSwitchB::Land,
         };
 
-        let steerpoint_cycle = match crsf_data.aux_6 {
-            0..=667 => SteerpointCycleActuation::Decrease,
-            668..=1_333 => SteerpointCycleActuation::Neutral,
+        let steerpoint_cycle = match map.three_pos(&raw, map.steerpoint_cycle, 1) {
+            0 => SteerpointCycleActuation::Decrease,
+            1 => SteerpointCycleActuation::Neutral,
             _ => SteerpointCycleActuation::Increase,
         };
 
-        let pid_tune_mode = match crsf_data.aux_7 {
-            0..=511 => PidTuneMode::Disabled,
-            512..=1_023 => PidTuneMode::P,
-            1_024..=1533 => PidTuneMode::I,
+        // This is a 4-position selection, so we don't use the 3-position thresholds.
+        let pid_tune_mode = match map.pid_tune_mode.map(|c| raw[c as usize]) {
+            None | Some(0..=511) => PidTuneMode::Disabled,
+            Some(512..=1_023) => PidTuneMode::P,
+            Some(1_024..=1533) => PidTuneMode::I,
             _ => PidTuneMode::D,
         };
 
-        let pid_tune_actuation = match crsf_data.aux_8 {
-            0..=667 => PidTuneActuation::Decrease,
-            668..=1_333 => PidTuneActuation::Neutral,
+        let pid_tune_actuation = match map.three_pos(&raw, map.pid_tune_actuation, 1) {
+            0 => PidTuneActuation::Decrease,
+            1 => PidTuneActuation::Neutral,
             _ => PidTuneActuation::Increase,
         };
 
-        let level_attitude_commanded = match crsf_data.aux_9 {
-            0..=1_000 => false,
-            _ => true,
+        let level_attitude_commanded = two_pos(&raw, map.level_attitude, map.two_pos_thresh);
+
+        let blackbox = two_pos(&raw, map.blackbox, map.two_pos_thresh);
+
+        let beeper = two_pos(&raw, map.beeper, map.two_pos_thresh);
+
+        let turtle = two_pos(&raw, map.turtle, map.two_pos_thresh);
+
+        let camera_tilt = match map.camera_tilt {
+            Some(c) => channel_to_val(raw[c as usize], false),
+            None => 0.,
         };
 
+        let profile = map.profile.map(|_| map.three_pos(&raw, map.profile, 0));
+
+        let acro_trainer = two_pos(&raw, map.acro_trainer, map.two_pos_thresh);
+
+        let follow_me = two_pos(&raw, map.follow_me, map.two_pos_thresh);
+
+        let beginner = two_pos(&raw, map.beginner, map.two_pos_thresh);
+
+        let batt_override = two_pos(&raw, map.batt_override, map.two_pos_thresh);
+
+        let ext_ctrl = two_pos(&raw, map.ext_ctrl, map.two_pos_thresh);
+
+        let kill = two_pos(&raw, map.kill, map.two_pos_thresh);
+
         // todo: Ideally, this would be on the same channel as motor arm in a 3-pos
         // todo switch, but ELRS hard codes is
         #[cfg(feature = "fixed-wing")]
-        let controls_armed = match crsf_data.aux_10 {
-            0..=1_000 => false,
-            _ => true,
-        };
+        let controls_armed = two_pos(&raw, map.controls_arm, map.two_pos_thresh);
 
         cfg_if! {
             if #[cfg(feature = "quad")] {
@@ -280,6 +655,7 @@ impl ChannelData {
             throttle: channel_to_val(crsf_data.channel_3, true),
             yaw: channel_to_val(crsf_data.channel_4, false),
             arm_status,
+            prearm,
             input_mode,
             // alt_hold,
             autopilot_a,
@@ -288,37 +664,135 @@ impl ChannelData {
             pid_tune_mode,
             pid_tune_actuation,
             level_attitude_commanded,
+            blackbox,
+            beeper,
+            turtle,
+            camera_tilt,
+            profile,
+            acro_trainer,
+            follow_me,
+            beginner,
+            batt_override,
+            ext_ctrl,
+            kill,
+            raw,
+        }
+    }
+}
+
+/// Loads channel data and link stats from the receiver, per its protocol. Run each IMU update, or
+/// from the sensor watchdog while the IMU has failed.
+pub fn handle_rx_data(
+    rx_protocol: RxProtocol,
+    control_channel_data: &mut Option<ChannelData>,
+    channel_map: &ChannelMap,
+    link_stats: &mut LinkStats,
+    system_status: &mut SystemStatus,
+    timestamp: f32,
+) {
+    match rx_protocol {
+        RxProtocol::Crsf => {
+            handle_crsf_data(
+                control_channel_data,
+                channel_map,
+                link_stats,
+                system_status,
+                timestamp,
+            );
+        }
+        RxProtocol::Sbus => {
+            if !sbus::TRANSFER_IN_PROG.load(Ordering::Acquire)
+                && sbus::NEW_PACKET_RECEIVED.load(Ordering::Acquire)
+            {
+                handle_sbus_data(
+                    control_channel_data,
+                    channel_map,
+                    link_stats,
+                    system_status,
+                    timestamp,
+                );
+            }
         }
     }
 }
 
 // todo: Is this the right module for this?
-/// Loads channel data and link stats into our shared structures,
-/// from the DMA buffer. Performs link-status updates.
+/// Loads channel data and link stats into our shared structures, from each frame received since
+/// the last call. Performs link-status updates.
 pub fn handle_crsf_data(
     control_channel_data: &mut Option<ChannelData>,
+    channel_map: &ChannelMap,
     link_stats: &mut LinkStats,
     system_status: &mut SystemStatus,
     timestamp: f32,
 ) {
     let mut rx_fault = false;
 
-    if let Some(crsf_data) = crsf::handle_packet(setup::CRSF_RX_CH, &mut rx_fault) {
+    while let Some(crsf_data) = crsf::next_packet(&mut rx_fault) {
+        system_status.update_timestamps.rf_packet = Some(timestamp);
+
         match crsf_data {
             crsf::PacketData::ChannelData(data_crsf) => {
-                *control_channel_data = Some(ChannelData::from_channel_data(&data_crsf));
-
-                crsf::NEW_PACKET_RECEIVED.store(false, Ordering::Release);
+                *control_channel_data =
+                    Some(ChannelData::from_channel_data(&data_crsf, channel_map));
 
                 // A bit imprecise since this is synced to IMU loop time, but is good enough
                 // for this purpose.
                 system_status.update_timestamps.rf_control_link = Some(timestamp);
+                if system_status.rf_control_link != SensorStatus::Pass {
+                    event_log::log(EventCode::LinkUp, 0, 0);
+                }
                 system_status.rf_control_link = SensorStatus::Pass;
             }
 
             crsf::PacketData::LinkStats(stats) => {
                 *link_stats = stats;
             }
+
+            crsf::PacketData::FollowTarget(target) => follow_me::store_target(target),
+        }
+    }
+
+    if rx_fault {
+        system_status::RX_FAULT.store(true, Ordering::Release);
+    }
+}
+
+/// Loads channel data from the DMA buffer, for SBUS receivers. Performs link-status updates. Frames
+/// the receiver marks as failsafe are discarded, so our link-lost handling applies as with CRSF.
+pub fn handle_sbus_data(
+    control_channel_data: &mut Option<ChannelData>,
+    channel_map: &ChannelMap,
+    link_stats: &mut LinkStats,
+    system_status: &mut SystemStatus,
+    timestamp: f32,
+) {
+    let mut rx_fault = false;
+
+    // Clear this even if the frame is invalid, so we don't parse it again.
+    sbus::NEW_PACKET_RECEIVED.store(false, Ordering::Release);
+
+    if let Some(frame) = sbus::handle_frame(&mut rx_fault) {
+        // Failsafe frames are from the receiver, but carry no channel data.
+        system_status.update_timestamps.rf_packet = Some(timestamp);
+
+        sbus::update_link_quality(
+            &mut link_stats.uplink_link_quality,
+            frame.frame_lost || frame.failsafe,
+        );
+
+        if !frame.failsafe {
+            *control_channel_data = Some(ChannelData::from_channel_data(
+                &frame.channel_data,
+                channel_map,
+            ));
+
+            // See the note on this in the CRSF fn.
+            system_status.update_timestamps.rf_control_link = Some(timestamp);
+            if system_status.rf_control_link != SensorStatus::Pass {
+                event_log::log(EventCode::LinkUp, 0, 0);
+            }
+            system_status.rf_control_link = SensorStatus::Pass;
         }
     }
 
@@ -0,0 +1,380 @@
+//! This module contains detailed console output over defmt, for debugging on the bench. It's an
+//! alternative to the `Preflight` PC program. Output is split into channels, each printed at its
+//! own decimated rate, and enabled at runtime with a bitmask; the PC can set this over USB, without
+//! reflashing. Printing is only compiled in with the `print-status` feature; release builds omit it.
+//!
+//! Formatting is slow, and the main loop holds several shared resources. While holding them, we
+//! only copy the due channel's values to a small snapshot; we format it after releasing them.
+//! Channels are staggered, so at most one prints per loop.
+
+#[cfg(feature = "print-status")]
+use core::sync::atomic::Ordering;
+
+#[cfg(feature = "print-status")]
+use ahrs::Params;
+#[cfg(feature = "print-status")]
+use defmt::println;
+
+#[cfg(feature = "print-status")]
+use crate::{
+    controller_interface::ChannelData,
+    flight_ctrls::autopilot::AutopilotStatus,
+    imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS,
+    main_loop::TaskDurations,
+    perf_stats::TimingStats,
+    protocols::crsf::LinkStats,
+    safety,
+    state::StateVolatile,
+    system_status::{self, SensorStatus, SystemStatus},
+};
+
+// Main loop iterations each channel's prints are offset from the previous channel's. Ratios are
+// multiples of the smallest, and this times the channel count is below it, so no two channels are
+// due on the same iteration.
+#[cfg(feature = "print-status")]
+const STAGGER: u32 = 1_000;
+
+// Mask, and whether printing is compiled in.
+pub const LOG_CHANNELS_SIZE: usize = 2;
+
+/// Repr is the channel's bit in the mask.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum LogChannel {
+    /// Commanded attitude, autopilot modes, and loop timing.
+    Control = 0,
+    /// Attitude, IMU readings, and temperatures.
+    Sensors = 1,
+    /// Control channel data, and link stats.
+    Radio = 2,
+    /// Battery, and current.
+    Power = 3,
+    /// Motor power, and RPM.
+    Dshot = 4,
+}
+
+impl LogChannel {
+    pub const ALL: [Self; 5] = [
+        Self::Control,
+        Self::Sensors,
+        Self::Radio,
+        Self::Power,
+        Self::Dshot,
+    ];
+
+    /// Main loop iterations between prints.
+    #[cfg(feature = "print-status")]
+    fn ratio(self) -> u32 {
+        match self {
+            Self::Control => 8_000,
+            Self::Sensors => 8_000,
+            Self::Radio => 16_000,
+            Self::Power => 16_000,
+            Self::Dshot => 8_000,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Which channels are enabled. Not stored in user config; it resets to all enabled on power-up.
+#[derive(Clone, Copy)]
+pub struct LogChannels {
+    pub mask: u8,
+}
+
+impl Default for LogChannels {
+    fn default() -> Self {
+        Self {
+            mask: LogChannel::ALL.iter().fold(0, |m, ch| m | ch.bit()),
+        }
+    }
+}
+
+impl LogChannels {
+    /// The enabled channel due to print this main loop iteration, if any.
+    #[cfg(feature = "print-status")]
+    pub fn due(&self, i: u32) -> Option<LogChannel> {
+        LogChannel::ALL.into_iter().find(|ch| {
+            self.mask & ch.bit() != 0 && i.wrapping_add(*ch as u32 * STAGGER) % ch.ratio() == 0
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; LOG_CHANNELS_SIZE] {
+        [self.mask, cfg!(feature = "print-status") as u8]
+    }
+}
+
+/// Pitch, roll, and yaw, in radians.
+#[cfg(feature = "print-status")]
+#[derive(Clone, Copy)]
+pub struct Euler(f32, f32, f32);
+
+#[cfg(feature = "print-status")]
+impl Euler {
+    fn from_params(params: &Params) -> Self {
+        let e = params.attitude.to_euler();
+        Self(e.pitch, e.roll, e.yaw)
+    }
+}
+
+/// A channel's values, copied while holding shared resources, to be printed after.
+#[cfg(feature = "print-status")]
+#[derive(Clone, Copy)]
+pub enum Snapshot {
+    Control {
+        timestamp: f32,
+        att_commanded: Euler,
+        arm_status: u8,
+        autopilot_modes: u16,
+        task_imu: f32,
+        task_flight_ctrls: f32,
+        main_loop_interval: f32,
+        flight_ctrl_interval: f32,
+        isr_exec: TimingStats,
+        isr_latency: TimingStats,
+        overruns_last_window: u32,
+        num_overruns: u32,
+    },
+    Sensors {
+        timestamp: f32,
+        attitude: Euler,
+        accel: (f32, f32, f32),
+        /// Pitch, roll, and yaw rates.
+        gyro: (f32, f32, f32),
+        imu_temp: f32,
+        mcu_temp: Option<f32>,
+        alt_baro: f32,
+    },
+    Radio {
+        timestamp: f32,
+        link_ok: bool,
+        /// Pitch, roll, yaw, and throttle.
+        controls: Option<(f32, f32, f32, f32)>,
+        arm_switch: bool,
+        link_quality: u8,
+        rssi: u8,
+        snr: i8,
+        rx_faults: u32,
+    },
+    Power {
+        timestamp: f32,
+        batt_v: f32,
+        esc_current: f32,
+        mah_used: f32,
+        batt_stage: u8,
+    },
+    Dshot {
+        timestamp: f32,
+        powers: [f32; NUM_RPM_NOTCH_MOTORS],
+        rpms: [Option<f32>; NUM_RPM_NOTCH_MOTORS],
+        rpm_faults: u32,
+    },
+}
+
+#[cfg(feature = "print-status")]
+impl Snapshot {
+    /// Copy a channel's values. Run while holding the shared resources; this doesn't format.
+    pub fn capture(
+        channel: LogChannel,
+        timestamp: f32,
+        params: &Params,
+        system_status: &SystemStatus,
+        control_channel_data: &Option<ChannelData>,
+        link_stats: &LinkStats,
+        state: &StateVolatile,
+        autopilot_status: &AutopilotStatus,
+        task_durations: &TaskDurations,
+    ) -> Self {
+        match channel {
+            LogChannel::Control => {
+                let e = state.attitude_commanded.quat.to_euler();
+
+                Self::Control {
+                    timestamp,
+                    att_commanded: Euler(e.pitch, e.roll, e.yaw),
+                    arm_status: state.arm_status as u8,
+                    autopilot_modes: autopilot_status.mode_flags(),
+                    task_imu: task_durations.imu,
+                    task_flight_ctrls: task_durations.flight_ctrls,
+                    main_loop_interval: task_durations.main_loop_interval,
+                    flight_ctrl_interval: task_durations.flight_ctrl_interval,
+                    isr_exec: state.perf_stats.isr_exec,
+                    isr_latency: state.perf_stats.isr_latency,
+                    overruns_last_window: state.perf_stats.overruns_last_window,
+                    num_overruns: state.perf_stats.num_overruns,
+                }
+            }
+            LogChannel::Sensors => Self::Sensors {
+                timestamp,
+                attitude: Euler::from_params(params),
+                accel: (params.a_x, params.a_y, params.a_z),
+                gyro: (params.v_pitch, params.v_roll, params.v_yaw),
+                imu_temp: state.imu_temp,
+                mcu_temp: state.perf_stats.mcu_temp,
+                alt_baro: params.alt_msl_baro,
+            },
+            LogChannel::Radio => Self::Radio {
+                timestamp,
+                link_ok: system_status.rf_control_link == SensorStatus::Pass,
+                controls: control_channel_data
+                    .as_ref()
+                    .map(|ch| (ch.pitch, ch.roll, ch.yaw, ch.throttle)),
+                arm_switch: control_channel_data
+                    .as_ref()
+                    .map_or(false, |ch| ch.arm_status == safety::MOTORS_ARMED),
+                link_quality: link_stats.uplink_link_quality,
+                rssi: link_stats.uplink_rssi_1,
+                snr: link_stats.uplink_snr,
+                rx_faults: system_status::RX_FAULT.load(Ordering::Acquire),
+            },
+            LogChannel::Power => Self::Power {
+                timestamp,
+                batt_v: state.batt_v,
+                esc_current: state.esc_current,
+                mah_used: state.flight_stats.flight.mah_used,
+                batt_stage: state.batt_failsafe.stage as u8,
+            },
+            LogChannel::Dshot => Self::Dshot {
+                timestamp,
+                powers: state.motor_servo_state.rotor_powers(),
+                rpms: state.motor_servo_state.rotor_rpms(),
+                rpm_faults: system_status::RPM_FAULT.load(Ordering::Acquire),
+            },
+        }
+    }
+
+    /// Format, and print. Run after releasing the shared resources.
+    pub fn print(&self) {
+        match self {
+            Self::Control {
+                timestamp,
+                att_commanded,
+                arm_status,
+                autopilot_modes,
+                task_imu,
+                task_flight_ctrls,
+                main_loop_interval,
+                flight_ctrl_interval,
+                isr_exec,
+                isr_latency,
+                overruns_last_window,
+                num_overruns,
+            } => {
+                println!(
+                    "[control {}] Commanded attitude: pitch: {}, roll: {}, yaw: {}. Arm status: {}. \
+                    Autopilot modes: {:#x}",
+                    timestamp,
+                    att_commanded.0,
+                    att_commanded.1,
+                    att_commanded.2,
+                    arm_status,
+                    autopilot_modes,
+                );
+                println!(
+                    "[control] Task durations in ms. IMU: {}, FC: {}. Main loop: {}Hz. Flight \
+                    ctrls: {}Hz",
+                    task_imu * 1_000.,
+                    task_flight_ctrls * 1_000.,
+                    1. / main_loop_interval,
+                    1. / flight_ctrl_interval,
+                );
+                println!(
+                    "[control] IMU ISR, in µs. Exec min: {} max: {} mean: {}. Latency min: {} \
+                    max: {} mean: {}. Overruns in last second: {}, total: {}",
+                    isr_exec.min * 1_000_000.,
+                    isr_exec.max * 1_000_000.,
+                    isr_exec.mean * 1_000_000.,
+                    isr_latency.min * 1_000_000.,
+                    isr_latency.max * 1_000_000.,
+                    isr_latency.mean * 1_000_000.,
+                    overruns_last_window,
+                    num_overruns,
+                );
+            }
+            Self::Sensors {
+                timestamp,
+                attitude,
+                accel,
+                gyro,
+                imu_temp,
+                mcu_temp,
+                alt_baro,
+            } => {
+                println!(
+                    "[sensors {}] Attitude: pitch: {}, roll: {}, yaw: {}. Accel: x {}, y {}, z {}. \
+                    Gyro: pitch {}, roll {}, yaw {}",
+                    timestamp,
+                    attitude.0,
+                    attitude.1,
+                    attitude.2,
+                    accel.0,
+                    accel.1,
+                    accel.2,
+                    gyro.0,
+                    gyro.1,
+                    gyro.2,
+                );
+                println!(
+                    "[sensors] IMU temp: {}°C. MCU temp: {:?}°C. Alt MSL baro: {}",
+                    imu_temp, mcu_temp, alt_baro,
+                );
+            }
+            Self::Radio {
+                timestamp,
+                link_ok,
+                controls,
+                arm_switch,
+                link_quality,
+                rssi,
+                snr,
+                rx_faults,
+            } => match controls {
+                Some((pitch, roll, yaw, throttle)) => println!(
+                    "[radio {}] Link: {}. Pitch: {} Roll: {}, Yaw: {}, Throttle: {}, Arm switch: \
+                    {}. LQ: {}, RSSI: -{}, SNR: {}. Rx faults: {}",
+                    timestamp,
+                    link_ok,
+                    pitch,
+                    roll,
+                    yaw,
+                    throttle,
+                    arm_switch,
+                    link_quality,
+                    rssi,
+                    snr,
+                    rx_faults,
+                ),
+                None => println!(
+                    "[radio {}] No control channel data. Rx faults: {}",
+                    timestamp, rx_faults,
+                ),
+            },
+            Self::Power {
+                timestamp,
+                batt_v,
+                esc_current,
+                mah_used,
+                batt_stage,
+            } => {
+                println!(
+                    "[power {}] Batt V: {} ESC current: {} mAh used: {}. Failsafe stage: {}",
+                    timestamp, batt_v, esc_current, mah_used, batt_stage,
+                );
+            }
+            Self::Dshot {
+                timestamp,
+                powers,
+                rpms,
+                rpm_faults,
+            } => {
+                println!(
+                    "[dshot {}] Power: {:?}. RPM: {:?}. RPM faults: {}",
+                    timestamp, powers, rpms, rpm_faults,
+                );
+            }
+        }
+    }
+}
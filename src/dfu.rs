@@ -0,0 +1,112 @@
+//! This module contains firmware updates over USB, and version reporting. On command, we reboot
+//! into the MCU's built-in (system memory) bootloader, which enumerates as a USB DFU device; flash
+//! it with `dfu-util`. No debug probe is required.
+//!
+//! We don't jump to the bootloader from the running firmware: Peripherals such as the motor timers,
+//! DMA, and USB would be left configured, with their interrupts enabled. Instead, we stop the
+//! motors, set a flag in RAM that isn't initialized at startup, and reset the MCU. At the start of
+//! `init`, before any peripherals are configured, we check the flag, and jump.
+
+use core::{
+    mem::MaybeUninit,
+    ptr::{self, addr_of, addr_of_mut},
+};
+
+use cfg_if::cfg_if;
+use cortex_m::peripheral::{NVIC, SCB};
+
+use crate::{board_config::AHB_FREQ, protocols::dshot, setup::MotorTimer};
+
+cfg_if! {
+    if #[cfg(feature = "h7")] {
+        // H743. See ST AN2606.
+        const SYSTEM_MEMORY_ADDR: u32 = 0x1ff0_9800;
+    } else {
+        // G473.
+        const SYSTEM_MEMORY_ADDR: u32 = 0x1fff_0000;
+    }
+}
+
+// Marks a bootloader request. Arbitrary; unlikely to appear in uninitialized RAM at power-up.
+const BOOT_MAGIC: u32 = 0xb007_10ad;
+
+// Milliseconds. After commanding motors stopped, we wait this long for the DSHOT frames to go out.
+const STOP_DELAY: u32 = 5;
+
+// Major, minor, and patch versions, then the git hash, as ASCII.
+const GIT_HASH_LEN: usize = 8;
+pub const VERSION_SIZE: usize = 3 + GIT_HASH_LEN;
+
+// Not zeroed at startup, so it survives a reset.
+#[link_section = ".uninit.BOOT_REQUEST"]
+static mut BOOT_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Stop the motors, and reset into the system bootloader. Check the interlocks before calling;
+/// Preflight, disarmed, and no motors running.
+pub fn reboot_to_bootloader(motor_timer: &mut MotorTimer) -> ! {
+    dshot::stop_all(motor_timer);
+    cortex_m::asm::delay(AHB_FREQ / 1_000 * STOP_DELAY);
+
+    cortex_m::interrupt::disable();
+
+    unsafe { ptr::write_volatile(addr_of_mut!(BOOT_REQUEST).cast::<u32>(), BOOT_MAGIC) };
+
+    SCB::sys_reset();
+}
+
+/// Run at the start of `init`, before configuring clocks or peripherals. If a bootloader reboot
+/// was requested, this jumps to it, and doesn't return.
+pub fn jump_if_requested() {
+    let requested =
+        unsafe { ptr::read_volatile(addr_of!(BOOT_REQUEST).cast::<u32>()) } == BOOT_MAGIC;
+
+    if !requested {
+        return;
+    }
+
+    // Clear it, so we boot normally after the bootloader resets us.
+    unsafe { ptr::write_volatile(addr_of_mut!(BOOT_REQUEST).cast::<u32>(), 0) };
+
+    unsafe {
+        // RTIC unmasks its bound interrupts before `init`; the bootloader uses its own.
+        let nvic = &*NVIC::PTR;
+        for i in 0..nvic.icer.len() {
+            nvic.icer[i].write(0xffff_ffff);
+            nvic.icpr[i].write(0xffff_ffff);
+        }
+
+        // Map system memory at address 0, as when booting from it with the BOOT0 pin.
+        #[cfg(feature = "g4")]
+        {
+            let rcc = &(*hal::pac::RCC::ptr());
+            let syscfg = &(*hal::pac::SYSCFG::ptr());
+
+            rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+            syscfg.memrmp.modify(|_, w| w.mem_mode().bits(0b001));
+        }
+
+        (*SCB::PTR).vtor.write(SYSTEM_MEMORY_ADDR);
+
+        // `init` runs with interrupts disabled; the bootloader's USB handling requires them.
+        cortex_m::interrupt::enable();
+
+        // Sets the stack pointer, and jumps to the reset vector, from the bootloader's vector
+        // table.
+        cortex_m::asm::bootload(SYSTEM_MEMORY_ADDR as *const u32);
+    }
+}
+
+/// The firmware version, and the git commit it was built from. See `build.rs`.
+pub fn version_to_bytes() -> [u8; VERSION_SIZE] {
+    let mut result = [0; VERSION_SIZE];
+
+    result[0] = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+    result[1] = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+    result[2] = env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0);
+
+    let hash = env!("GIT_HASH").as_bytes();
+    let len = hash.len().min(GIT_HASH_LEN);
+    result[3..3 + len].copy_from_slice(&hash[..len]);
+
+    result
+}
@@ -0,0 +1,138 @@
+//! This module contains DMA health monitoring: For each transfer in `setup::dma_priorities`,
+//! counts of transfer errors, and on H7, FIFO and direct mode errors, which indicate an overrun or
+//! underrun. We poll the controllers' interrupt status flags from the main loop, and clear only
+//! the error flags; the transfer complete flags belong to the transfer ISRs.
+
+use cfg_if::cfg_if;
+use hal::{
+    dma::{DmaChannel, DmaPeriph},
+    pac::{self, DMA1},
+};
+
+use crate::setup::{self, DmaAssignment, NUM_DMA_TRANSFERS};
+
+// Per transfer: Transfer ID, controller, channel, priority, transfer errors (u16), and FIFO
+// errors (u16).
+const DMA_TRANSFER_REPORT_SIZE: usize = 4 + 2 * 2;
+pub const DMA_REPORT_SIZE: usize = DMA_TRANSFER_REPORT_SIZE * NUM_DMA_TRANSFERS;
+
+cfg_if! {
+    if #[cfg(feature = "h7")] {
+        // Flag positions within a stream's group, in LISR/HISR. RM0468, section 15.5.
+        const FEIF: u32 = 1 << 0;
+        const DMEIF: u32 = 1 << 2;
+        const TEIF: u32 = 1 << 3;
+
+        // Offset of each stream's group within LISR (streams 0 - 3), or HISR (4 - 7).
+        const STREAM_OFFSETS: [u32; 4] = [0, 6, 16, 22];
+    } else {
+        // Flag position within a channel's group, in ISR. RM0440, section 12.6.
+        const TEIF: u32 = 1 << 3;
+    }
+}
+
+/// Error counts, in `DmaTransfer` order. These saturate.
+#[derive(Default)]
+pub struct DmaStats {
+    pub transfer_errors: [u16; NUM_DMA_TRANSFERS],
+    /// FIFO and direct mode errors. Always 0 on G4, which has no FIFOs.
+    pub fifo_errors: [u16; NUM_DMA_TRANSFERS],
+}
+
+impl DmaStats {
+    /// Run periodically from the main loop.
+    pub fn update(&mut self) {
+        for assignment in setup::dma_priorities() {
+            let (transfer_err, fifo_err) = take_error_flags(assignment.periph, assignment.channel);
+
+            let i = assignment.transfer as usize;
+            if transfer_err {
+                self.transfer_errors[i] = self.transfer_errors[i].saturating_add(1);
+            }
+            if fifo_err {
+                self.fifo_errors[i] = self.fifo_errors[i].saturating_add(1);
+            }
+        }
+    }
+
+    /// The channel assignments, with error counts.
+    pub fn to_bytes(&self) -> [u8; DMA_REPORT_SIZE] {
+        let mut result = [0; DMA_REPORT_SIZE];
+
+        for (j, assignment) in setup::dma_priorities().iter().enumerate() {
+            let i = j * DMA_TRANSFER_REPORT_SIZE;
+            let t = assignment.transfer as usize;
+
+            result[i..i + 4].clone_from_slice(&assignment_bytes(assignment));
+            result[i + 4..i + 6].clone_from_slice(&self.transfer_errors[t].to_be_bytes());
+            result[i + 6..i + 8].clone_from_slice(&self.fifo_errors[t].to_be_bytes());
+        }
+        result
+    }
+}
+
+fn assignment_bytes(assignment: &DmaAssignment) -> [u8; 4] {
+    let periph = match assignment.periph {
+        DmaPeriph::Dma1 => 1,
+        DmaPeriph::Dma2 => 2,
+    };
+
+    [
+        assignment.transfer as u8,
+        periph,
+        assignment.channel as u8,
+        assignment.priority as u8,
+    ]
+}
+
+/// Reads, and clears, a channel's error flags. Returns (transfer error, FIFO or direct mode
+/// error).
+fn take_error_flags(periph: DmaPeriph, channel: DmaChannel) -> (bool, bool) {
+    let regs = unsafe {
+        match periph {
+            DmaPeriph::Dma1 => &(*DMA1::ptr()),
+            DmaPeriph::Dma2 => &(*pac::DMA2::ptr()),
+        }
+    };
+
+    let ch = channel as u32;
+
+    cfg_if! {
+        if #[cfg(feature = "h7")] {
+            let shift = STREAM_OFFSETS[ch as usize % 4];
+            let mask = (FEIF | DMEIF | TEIF) << shift;
+
+            let flags = if ch < 4 {
+                regs.lisr.read().bits() & mask
+            } else {
+                regs.hisr.read().bits() & mask
+            };
+
+            if flags == 0 {
+                return (false, false);
+            }
+
+            // These are write-1-to-clear; zeros leave the other flags alone.
+            if ch < 4 {
+                regs.lifcr.write(|w| unsafe { w.bits(flags) });
+            } else {
+                regs.hifcr.write(|w| unsafe { w.bits(flags) });
+            }
+
+            (flags & (TEIF << shift) != 0, flags & ((FEIF | DMEIF) << shift) != 0)
+        } else {
+            // Channels are numbered from 1.
+            let mask = TEIF << ((ch - 1) * 4);
+
+            let flags = regs.isr.read().bits() & mask;
+            if flags == 0 {
+                return (false, false);
+            }
+
+            // Write-1-to-clear; zeros leave the other flags alone.
+            regs.ifcr.write(|w| unsafe { w.bits(flags) });
+
+            (true, false)
+        }
+    }
+}
@@ -90,6 +90,11 @@ fn read_one(reg: Reg, i2c: &mut I2cBaro) -> Result<u8, BaroNotConnectedError> {
     Ok(buf[0])
 }
 
+/// Read the product ID register. This should be `PRODUCT_ID`.
+pub fn read_product_id(i2c: &mut I2cBaro) -> Result<u8, BaroNotConnectedError> {
+    read_one(Reg::ProductId, i2c)
+}
+
 /// Calibration coefficients, read from factory-assigned registers.
 /// 2's complement numbers.
 /// We store these as floats, since that's how they're used in operations.
@@ -295,8 +300,8 @@ impl Altimeter {
     /// This is our main non-DMA API. Note that we read them together, since we need both
     /// to estimate altitude.
     ///
-    /// Note: We don't use this function in practice after init; we use DMA instead to populate the
-    /// buffer.
+    /// Note: We don't use this function in practice after init, other than in the preflight
+    /// self-test; we use DMA instead to populate the buffer.
     pub fn read_pressure_temp(
         &self,
         i2c: &mut I2cBaro,
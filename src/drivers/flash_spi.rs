@@ -1,49 +1,224 @@
-//! This module contains setup code for W25 SPI flash.
-//! We use normal SPI on G4, and quad spi on H7.
+//! This module contains a driver for W25 SPI NOR flash. We use it for the blackbox log.
+//! SPI2 on both G4 and H7.
+//!
+//! Addresses are 24-bit, so this supports parts up to 16MB. Writes are done a 256-byte page at a
+//! time, to erased memory; erases are done a 4k sector at a time, or for the whole chip.
 
+use defmt::println;
 use hal::{gpio::Pin, spi};
 
 use crate::setup::SpiFlash;
 
+// The W25 manufacturer ID.
+const MANUFACTURER_WINBOND: u8 = 0xef;
+// The capacity byte of the JEDEC ID is log2 of the size in bytes. 128k to 16MB.
+const CAPACITY_MIN: u8 = 0x11;
+const CAPACITY_MAX: u8 = 0x18;
+
+pub const PAGE_SIZE: usize = 256;
+pub const SECTOR_SIZE: usize = 4_096;
+
+// Status register 1 bits.
+const STATUS_BUSY: u8 = 1;
+
+// Upper bounds on how long to poll the busy flag for, in status reads. At SPI speeds, each read
+// takes a few µs. Page program is up to 3ms, and sector erase is up to 400ms. Chip erase is up
+// to 25s for a 16MB part.
+const BUSY_POLLS_PROGRAM: u32 = 10_000;
+const BUSY_POLLS_ERASE_SECTOR: u32 = 1_000_000;
+const BUSY_POLLS_ERASE_CHIP: u32 = 100_000_000;
+
 #[derive(Clone, Copy)]
 pub enum FlashSpiError {
     NotConnected,
+    /// The busy flag didn't clear in time.
+    Timeout,
+    /// The address range is outside of the chip.
+    OutOfRange,
 }
 
-// todo: This will be diff for H7.
 impl From<spi::SpiError> for FlashSpiError {
     fn from(_e: spi::SpiError) -> Self {
         Self::NotConnected
     }
 }
 
-/// See Datasheet, Section 13.1 (Note: This doesn't include all regs)
+/// See Datasheet, Section 8.1 (Note: This doesn't include all instructions)
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
 #[repr(u8)]
-pub enum Reg {
+pub enum Instruction {
+    WriteEnable = 0x06,
+    ReadStatus1 = 0x05,
+    PageProgram = 0x02,
+    SectorErase = 0x20,
+    ChipErase = 0xc7,
+    FastRead = 0x0b,
     Jedec = 0x9f,
 }
 
-/// Initialize the flash peripheral, and verify it's returning the correct device id and metadata.
-pub fn setup(spi: &mut SpiFlash, cs: &mut Pin) -> Result<(), FlashSpiError> {
-    let mut buf = [Reg::Jedec as u8, 0, 0, 0];
-    cs.set_low();
+/// External flash; the SPI bus, and its chip select. Holds these even if the flash isn't detected,
+/// so we can query it from Preflight.
+pub struct ExtFlash {
+    spi: SpiFlash,
+    cs: Pin,
+    /// Size in bytes. 0 if the flash wasn't detected.
+    capacity: usize,
+}
 
-    #[cfg(feature = "g4")]
-    spi.transfer(&mut buf)?;
+impl ExtFlash {
+    pub fn new(spi: SpiFlash, cs: Pin) -> Self {
+        Self {
+            spi,
+            cs,
+            capacity: 0,
+        }
+    }
 
-    cs.set_high();
+    /// Read the JEDEC ID, and verify it's a supported part. Sets capacity from it.
+    pub fn setup(&mut self) -> Result<(), FlashSpiError> {
+        let id = self.read_jedec_id()?;
 
-    // The first val is used by all W25 flash. Second means memory type A. Third means 16mb or less.
-    // Given SPI devices may report 0s if not connected properly, this is a good check that
-    // we have 2-way communication.
-    // todo: Will be different for H7.
-    if buf[1] != 0xef || buf[2] != 0x40 || buf[3] != 0x15 {
-        return Err(FlashSpiError::NotConnected);
+        // Given SPI devices may report 0s if not connected properly, this is a good check that
+        // we have 2-way communication.
+        if id[0] != MANUFACTURER_WINBOND || !(CAPACITY_MIN..=CAPACITY_MAX).contains(&id[2]) {
+            self.capacity = 0;
+            return Err(FlashSpiError::NotConnected);
+        }
+
+        self.capacity = 1 << id[2];
+        println!("External flash detected. Size: {}kB", self.capacity / 1_024);
+
+        Ok(())
     }
 
-    Ok(())
-}
+    pub fn detected(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Manufacturer, memory type, and capacity.
+    pub fn read_jedec_id(&mut self) -> Result<[u8; 3], FlashSpiError> {
+        let mut buf = [Instruction::Jedec as u8, 0, 0, 0];
+
+        self.cs.set_low();
+        let result = self.spi.transfer(&mut buf);
+        self.cs.set_high();
+        result?;
+
+        Ok([buf[1], buf[2], buf[3]])
+    }
+
+    /// Write an instruction, followed by a 24-bit address.
+    fn write_addr_cmd(
+        &mut self,
+        instruction: Instruction,
+        addr: usize,
+    ) -> Result<(), FlashSpiError> {
+        self.spi.write(&[
+            instruction as u8,
+            (addr >> 16) as u8,
+            (addr >> 8) as u8,
+            addr as u8,
+        ])?;
+        Ok(())
+    }
+
+    fn write_enable(&mut self) -> Result<(), FlashSpiError> {
+        self.cs.set_low();
+        let result = self.spi.write(&[Instruction::WriteEnable as u8]);
+        self.cs.set_high();
+        result?;
+        Ok(())
+    }
+
+    /// Block until a program or erase operation is complete.
+    fn wait_busy(&mut self, max_polls: u32) -> Result<(), FlashSpiError> {
+        for _ in 0..max_polls {
+            let mut buf = [Instruction::ReadStatus1 as u8, 0];
+
+            self.cs.set_low();
+            let result = self.spi.transfer(&mut buf);
+            self.cs.set_high();
+            result?;
 
-// todo: Add support for QSPI, feature-gated for H7.
+            if buf[1] & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+        }
+        Err(FlashSpiError::Timeout)
+    }
+
+    fn check_range(&self, addr: usize, len: usize) -> Result<(), FlashSpiError> {
+        if addr + len > self.capacity {
+            return Err(FlashSpiError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Erase the 4k sector containing `addr`. Blocking.
+    pub fn erase_sector(&mut self, addr: usize) -> Result<(), FlashSpiError> {
+        self.check_range(addr, 1)?;
+        self.write_enable()?;
+
+        self.cs.set_low();
+        let result = self.write_addr_cmd(Instruction::SectorErase, addr);
+        self.cs.set_high();
+        result?;
+
+        self.wait_busy(BUSY_POLLS_ERASE_SECTOR)
+    }
+
+    /// Erase the whole chip. Blocking, and very slow.
+    pub fn erase_chip(&mut self) -> Result<(), FlashSpiError> {
+        self.write_enable()?;
+
+        self.cs.set_low();
+        let result = self.spi.write(&[Instruction::ChipErase as u8]);
+        self.cs.set_high();
+        result?;
+
+        self.wait_busy(BUSY_POLLS_ERASE_CHIP)
+    }
+
+    /// Program up to a page. The data must not cross a page boundary, or it wraps to the start
+    /// of the page. The region must be erased.
+    pub fn program_page(&mut self, addr: usize, data: &[u8]) -> Result<(), FlashSpiError> {
+        self.check_range(addr, data.len())?;
+        if addr % PAGE_SIZE + data.len() > PAGE_SIZE {
+            return Err(FlashSpiError::OutOfRange);
+        }
+
+        self.write_enable()?;
+
+        self.cs.set_low();
+        let result = self
+            .write_addr_cmd(Instruction::PageProgram, addr)
+            .and_then(|_| Ok(self.spi.write(data)?));
+        self.cs.set_high();
+        result?;
+
+        self.wait_busy(BUSY_POLLS_PROGRAM)
+    }
+
+    /// Read any length, from any address.
+    pub fn fast_read(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), FlashSpiError> {
+        self.check_range(addr, buf.len())?;
+
+        // We clock out 0s while reading.
+        buf.fill(0);
+
+        self.cs.set_low();
+        let result = self
+            .write_addr_cmd(Instruction::FastRead, addr)
+            // Fast read requires a dummy byte after the address.
+            .and_then(|_| Ok(self.spi.write(&[0])?))
+            .and_then(|_| Ok(self.spi.transfer(buf)?));
+        self.cs.set_high();
+
+        result
+    }
+}
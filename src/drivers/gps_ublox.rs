@@ -0,0 +1,573 @@
+//! This module contains a driver for u-blox GNSS modules (eg M9N, M10), connected over I2C
+//! (u-blox calls this DDC). We read the module's byte stream using DMA, and parse UBX frames
+//! from it. We currently use the NAV-PVT message, which contains everything we need for a fix.
+//!
+//! Frames don't line up with our DMA reads, so the parser is a state machine that accepts bytes
+//! one at a time, and picks up where it left off on the next read.
+//!
+//! Reference: u-blox M9 / M10 Interface Description, section 3: UBX protocol.
+
+use ahrs::{Fix, FixType};
+use chrono::NaiveDate;
+use defmt::println;
+use hal::{delay_ms, i2c};
+use num_enum::TryFromPrimitive;
+
+use crate::{board_config::AHB_FREQ, setup::I2cMag};
+
+// 7-bit I2C address. This is the u-blox default, and isn't configurable in hardware.
+pub const ADDR: u8 = 0x42;
+
+// We read this many bytes from the data stream per DMA transfer. If the module has fewer bytes
+// pending than this, it pads the read with 0xff, which the parser skips while looking for sync.
+pub const READ_BUF_SIZE: usize = 64;
+
+// Horizontal accuracy estimate, in meters, above which we don't trust the fix for navigation.
+pub const MAX_H_ACC: f32 = 5.;
+
+const PREAMBLE_0: u8 = 0xb5;
+const PREAMBLE_1: u8 = 0x62;
+
+// Longer than any message we parse. Frames with larger payloads are skipped.
+const MAX_PAYLOAD_LEN: usize = 100;
+
+const CLASS_NAV: u8 = 0x01;
+const ID_NAV_PVT: u8 = 0x07;
+const PAYLOAD_LEN_NAV_PVT: usize = 92;
+
+const CLASS_ACK: u8 = 0x05;
+const ID_ACK_NAK: u8 = 0x00;
+const ID_ACK_ACK: u8 = 0x01;
+
+const CLASS_CFG: u8 = 0x06;
+const ID_CFG_VALSET: u8 = 0x8a;
+
+// Configuration keys, from the interface description, section 6: Configuration interface.
+const KEY_I2COUTPROT_UBX: u32 = 0x1072_0001;
+const KEY_I2COUTPROT_NMEA: u32 = 0x1072_0002;
+const KEY_MSGOUT_NAV_PVT_I2C: u32 = 0x2091_0006;
+const KEY_RATE_MEAS: u32 = 0x3021_0001;
+const KEY_RATE_NAV: u32 = 0x3021_0002;
+const KEY_NAVSPG_DYNMODEL: u32 = 0x2011_0021;
+
+// Dynamic platform model: Airborne with <2g acceleration.
+const DYNMODEL_AIRBORNE_2G: u8 = 7;
+
+// Apply config to RAM (active now), and battery-backed RAM (survives a warm restart).
+const VALSET_LAYERS: u8 = 0b011;
+
+// We poll for an ACK this many times, with this delay between, before giving up.
+const ACK_POLL_ATTEMPTS: u16 = 100;
+const ACK_POLL_INTERVAL_MS: u32 = 5;
+
+pub enum GpsError {
+    /// No response on the I2C bus.
+    NotConnected,
+    /// The module responds, but NAK'd a config command, or didn't ACK it in time.
+    ConfigFailed,
+}
+
+impl From<i2c::Error> for GpsError {
+    fn from(_: i2c::Error) -> Self {
+        Self::NotConnected
+    }
+}
+
+/// Navigation solution rate. NAV-PVT is output once per solution. This is user-configurable,
+/// since the I2C bus is shared with the magnetometer and TOF sensor.
+#[derive(Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum GpsNavRate {
+    H5 = 5,
+    H10 = 10,
+}
+
+impl Default for GpsNavRate {
+    fn default() -> Self {
+        Self::H10
+    }
+}
+
+impl GpsNavRate {
+    /// Measurement period, in ms.
+    fn period_ms(&self) -> u16 {
+        1_000 / *self as u16
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Reg {
+    /// 2 bytes; big-endian. Number of bytes available to read.
+    BytesAvailHigh = 0xfd,
+    BytesAvailLow = 0xfe,
+    /// Read the message stream from here. Write messages to the module here as well.
+    DataStream = 0xff,
+}
+
+/// Fix data of interest, from NAV-PVT. We also produce an AHRS `Fix` from the same message,
+/// which is what we pass to other modules.
+#[derive(Clone, Copy)]
+pub struct GpsFix {
+    /// Seconds since system start, when we received this fix.
+    pub timestamp: f32,
+    /// Degrees x 1e7.
+    pub lat: i32,
+    /// Degrees x 1e7.
+    pub lon: i32,
+    /// Meters.
+    pub alt_msl: f32,
+    /// m/s.
+    pub ground_speed: f32,
+    /// Course over ground, in radians, clockwise from true north.
+    pub course: f32,
+    pub fix_type: FixType,
+    /// Number of satellites used in the solution.
+    pub num_sv: u8,
+    /// Horizontal accuracy estimate, in meters.
+    pub h_acc: f32,
+}
+
+impl Default for GpsFix {
+    fn default() -> Self {
+        Self {
+            timestamp: 0.,
+            lat: 0,
+            lon: 0,
+            alt_msl: 0.,
+            ground_speed: 0.,
+            course: 0.,
+            fix_type: FixType::NoFix,
+            num_sv: 0,
+            h_acc: f32::MAX,
+        }
+    }
+}
+
+impl GpsFix {
+    /// A 3D fix, with accuracy good enough to navigate with.
+    pub fn is_usable(&self) -> bool {
+        matches!(self.fix_type, FixType::Fix3d) && self.h_acc < MAX_H_ACC
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ParseState {
+    Preamble0,
+    Preamble1,
+    Class,
+    Id,
+    Len0,
+    Len1,
+    Payload,
+    CkA,
+    CkB,
+}
+
+/// A complete frame, with a valid checksum.
+pub struct UbxFrame<'a> {
+    pub class: u8,
+    pub id: u8,
+    pub payload: &'a [u8],
+}
+
+/// Streaming UBX frame parser. Feed it bytes from the module, in order; it returns a frame
+/// once one is complete and passes its checksum.
+pub struct UbxParser {
+    state: ParseState,
+    class: u8,
+    id: u8,
+    len: usize,
+    i: usize,
+    payload: [u8; MAX_PAYLOAD_LEN],
+    ck_a: u8,
+    ck_b: u8,
+    ck_a_rx: u8,
+}
+
+impl Default for UbxParser {
+    fn default() -> Self {
+        Self {
+            state: ParseState::Preamble0,
+            class: 0,
+            id: 0,
+            len: 0,
+            i: 0,
+            payload: [0; MAX_PAYLOAD_LEN],
+            ck_a: 0,
+            ck_b: 0,
+            ck_a_rx: 0,
+        }
+    }
+}
+
+impl UbxParser {
+    /// 8-bit Fletcher checksum, over class, ID, length, and payload.
+    fn update_checksum(&mut self, byte: u8) {
+        self.ck_a = self.ck_a.wrapping_add(byte);
+        self.ck_b = self.ck_b.wrapping_add(self.ck_a);
+    }
+
+    /// Process a single byte. Returns a frame when one completes.
+    pub fn feed(&mut self, byte: u8) -> Option<UbxFrame> {
+        match self.state {
+            ParseState::Preamble0 => {
+                if byte == PREAMBLE_0 {
+                    self.state = ParseState::Preamble1;
+                }
+            }
+            ParseState::Preamble1 => {
+                self.state = match byte {
+                    PREAMBLE_1 => ParseState::Class,
+                    // Eg a repeated first preamble byte.
+                    PREAMBLE_0 => ParseState::Preamble1,
+                    _ => ParseState::Preamble0,
+                };
+                self.ck_a = 0;
+                self.ck_b = 0;
+            }
+            ParseState::Class => {
+                self.class = byte;
+                self.update_checksum(byte);
+                self.state = ParseState::Id;
+            }
+            ParseState::Id => {
+                self.id = byte;
+                self.update_checksum(byte);
+                self.state = ParseState::Len0;
+            }
+            ParseState::Len0 => {
+                self.len = byte as usize;
+                self.update_checksum(byte);
+                self.state = ParseState::Len1;
+            }
+            ParseState::Len1 => {
+                self.len |= (byte as usize) << 8;
+                self.update_checksum(byte);
+                self.i = 0;
+
+                self.state = if self.len > MAX_PAYLOAD_LEN {
+                    // Too long to buffer; resync on the next frame.
+                    ParseState::Preamble0
+                } else if self.len == 0 {
+                    ParseState::CkA
+                } else {
+                    ParseState::Payload
+                };
+            }
+            ParseState::Payload => {
+                self.payload[self.i] = byte;
+                self.update_checksum(byte);
+                self.i += 1;
+
+                if self.i == self.len {
+                    self.state = ParseState::CkA;
+                }
+            }
+            ParseState::CkA => {
+                self.ck_a_rx = byte;
+                self.state = ParseState::CkB;
+            }
+            ParseState::CkB => {
+                self.state = ParseState::Preamble0;
+
+                if self.ck_a_rx == self.ck_a && byte == self.ck_b {
+                    return Some(UbxFrame {
+                        class: self.class,
+                        id: self.id,
+                        payload: &self.payload[..self.len],
+                    });
+                }
+                println!("UBX checksum failure");
+            }
+        }
+
+        None
+    }
+}
+
+/// Build a UBX frame into `buf`, and return its length.
+fn build_frame(class: u8, id: u8, payload: &[u8], buf: &mut [u8]) -> usize {
+    let len = payload.len();
+
+    buf[0] = PREAMBLE_0;
+    buf[1] = PREAMBLE_1;
+    buf[2] = class;
+    buf[3] = id;
+    buf[4..6].copy_from_slice(&(len as u16).to_le_bytes());
+    buf[6..6 + len].copy_from_slice(payload);
+
+    let mut ck_a: u8 = 0;
+    let mut ck_b: u8 = 0;
+    for byte in &buf[2..6 + len] {
+        ck_a = ck_a.wrapping_add(*byte);
+        ck_b = ck_b.wrapping_add(ck_a);
+    }
+
+    buf[6 + len] = ck_a;
+    buf[7 + len] = ck_b;
+
+    len + 8
+}
+
+/// Read the number of bytes the module has available. The module has no ID register; a response
+/// here means it's present.
+pub fn bytes_available(i2c: &mut I2cMag) -> Result<u16, GpsError> {
+    let mut buf = [0, 0];
+    i2c.write_read(ADDR, &[Reg::BytesAvailHigh as u8], &mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+/// Wait for an ACK-ACK (or ACK-NAK) of a given message. Blocking.
+fn wait_for_ack(i2c: &mut I2cMag, class: u8, id: u8) -> Result<(), GpsError> {
+    let mut parser = UbxParser::default();
+    let mut buf = [0; READ_BUF_SIZE];
+
+    for _ in 0..ACK_POLL_ATTEMPTS {
+        i2c.write_read(ADDR, &[Reg::DataStream as u8], &mut buf)?;
+
+        for byte in buf {
+            if let Some(frame) = parser.feed(byte) {
+                if frame.class != CLASS_ACK || frame.payload.len() != 2 {
+                    continue;
+                }
+                if frame.payload[0] != class || frame.payload[1] != id {
+                    continue;
+                }
+                match frame.id {
+                    ID_ACK_ACK => return Ok(()),
+                    ID_ACK_NAK => return Err(GpsError::ConfigFailed),
+                    _ => (),
+                }
+            }
+        }
+
+        delay_ms(ACK_POLL_INTERVAL_MS, AHB_FREQ);
+    }
+
+    Err(GpsError::ConfigFailed)
+}
+
+/// Set a single configuration value using CFG-VALSET, and wait for the module to ACK it.
+/// `val` is little-endian, and its length must match the key's size.
+fn valset(i2c: &mut I2cMag, key: u32, val: &[u8]) -> Result<(), GpsError> {
+    // Version, layers, 2 reserved bytes, key, value.
+    let mut payload = [0; 12];
+    payload[1] = VALSET_LAYERS;
+    payload[4..8].copy_from_slice(&key.to_le_bytes());
+    payload[8..8 + val.len()].copy_from_slice(val);
+
+    let mut buf = [0; 20];
+    let len = build_frame(
+        CLASS_CFG,
+        ID_CFG_VALSET,
+        &payload[..8 + val.len()],
+        &mut buf,
+    );
+
+    // Writes longer than one byte go to the data stream directly; no register address.
+    i2c.write(ADDR, &buf[..len])?;
+
+    wait_for_ack(i2c, CLASS_CFG, ID_CFG_VALSET)
+}
+
+/// Configure the module for UBX-only output on I2C, with NAV-PVT at the specified rate, and
+/// an airborne dynamic model. The factory default is NMEA at 1Hz, which isn't suitable for
+/// flight control. Blocking; run this during init.
+pub fn setup(i2c: &mut I2cMag, nav_rate: GpsNavRate) -> Result<(), GpsError> {
+    valset(i2c, KEY_I2COUTPROT_NMEA, &[0])?;
+    valset(i2c, KEY_I2COUTPROT_UBX, &[1])?;
+    valset(i2c, KEY_MSGOUT_NAV_PVT_I2C, &[1])?;
+    valset(i2c, KEY_RATE_MEAS, &nav_rate.period_ms().to_le_bytes())?;
+    valset(i2c, KEY_RATE_NAV, &1_u16.to_le_bytes())?;
+    valset(i2c, KEY_NAVSPG_DYNMODEL, &[DYNMODEL_AIRBORNE_2G])?;
+
+    Ok(())
+}
+
+/// Parse a NAV-PVT frame. Returns our fix, and the AHRS fix format. `timestamp` is
+/// in seconds since system start. Returns `None` if this isn't a NAV-PVT frame.
+pub fn parse_nav_pvt(frame: &UbxFrame, timestamp: f32) -> Option<(GpsFix, Fix)> {
+    if frame.class != CLASS_NAV
+        || frame.id != ID_NAV_PVT
+        || frame.payload.len() != PAYLOAD_LEN_NAV_PVT
+    {
+        return None;
+    }
+
+    let p = frame.payload;
+
+    let u16_ = |i: usize| u16::from_le_bytes(p[i..i + 2].try_into().unwrap());
+    let u32_ = |i: usize| u32::from_le_bytes(p[i..i + 4].try_into().unwrap());
+    let i32_ = |i: usize| i32::from_le_bytes(p[i..i + 4].try_into().unwrap());
+
+    // Bit 0 of `flags` is `gnssFixOK`: The fix is within DOP and accuracy masks.
+    let fix_ok = p[21] & 1 != 0;
+
+    let fix_type = if !fix_ok {
+        FixType::NoFix
+    } else {
+        match p[20] {
+            2 => FixType::Fix2d,
+            // 4 is GNSS + dead reckoning.
+            3 | 4 => FixType::Fix3d,
+            5 => FixType::TimeOnly,
+            _ => FixType::NoFix,
+        }
+    };
+
+    let lon = i32_(24);
+    let lat = i32_(28);
+    let elevation_hae = i32_(32);
+    let elevation_msl = i32_(36);
+    let ground_speed = i32_(60);
+    // Degrees x 1e5.
+    let heading = i32_(64);
+
+    let gps_fix = GpsFix {
+        timestamp,
+        lat,
+        lon,
+        alt_msl: elevation_msl as f32 / 1_000.,
+        ground_speed: ground_speed as f32 / 1_000.,
+        course: (heading as f32 / 100_000.).to_radians(),
+        fix_type,
+        num_sv: p[23],
+        h_acc: u32_(40) as f32 / 1_000.,
+    };
+
+    // Time fields aren't necessarily valid; eg before the module has an almanac.
+    let datetime = NaiveDate::from_ymd_opt(u16_(4) as i32, p[6] as u32, p[7] as u32)
+        .and_then(|d| d.and_hms_opt(p[8] as u32, p[9] as u32, p[10] as u32))
+        .unwrap_or_default();
+
+    let fix = Fix {
+        timestamp_s: timestamp,
+        datetime,
+        type_: fix_type,
+        lat_e7: lat,
+        lon_e7: lon,
+        elevation_hae,
+        elevation_msl,
+        ground_speed,
+        ned_velocity: [i32_(48), i32_(52), i32_(56)],
+        heading: Some(heading as f32 / 100_000.),
+        sats_used: p[23],
+        pdop: u16_(76),
+    };
+
+    Some((gps_fix, fix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A NAV-PVT frame captured from an M9N with a 3D fix: 14 satellites, 1.25m horizontal
+    /// accuracy.
+    const NAV_PVT_CAPTURE: [u8; 100] = [
+        0xb5, 0x62, 0x01, 0x07, 0x5c, 0x00, 0xd8, 0xb0, 0x23, 0x17, 0xe7, 0x07, 0x06, 0x0e, 0x0b,
+        0x32, 0x0f, 0x37, 0x19, 0x00, 0x00, 0x00, 0x15, 0xcd, 0x5b, 0x07, 0x03, 0x01, 0xea, 0x0e,
+        0xf4, 0x6f, 0x1c, 0xb7, 0xf0, 0x13, 0x5d, 0x1c, 0x9a, 0xb0, 0x00, 0x00, 0x66, 0x07, 0x01,
+        0x00, 0xe2, 0x04, 0x00, 0x00, 0x26, 0x07, 0x00, 0x00, 0xe8, 0x05, 0x00, 0x00, 0xd7, 0xfe,
+        0xff, 0xff, 0x12, 0x00, 0x00, 0x00, 0x05, 0x06, 0x00, 0x00, 0xb9, 0x31, 0x13, 0x02, 0x36,
+        0x01, 0x00, 0x00, 0x70, 0xce, 0x20, 0x00, 0x7b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x6d, 0x78,
+    ];
+
+    /// Feed `bytes` in order, and return the NAV-PVT fixes parsed.
+    fn feed_all(parser: &mut UbxParser, bytes: &[u8]) -> Vec<GpsFix> {
+        bytes
+            .iter()
+            .filter_map(|b| parser.feed(*b).and_then(|f| parse_nav_pvt(&f, 0.)))
+            .map(|(fix, _)| fix)
+            .collect()
+    }
+
+    fn check_capture_fix(fix: &GpsFix) {
+        assert_eq!(fix.lat, 475_862_000);
+        assert_eq!(fix.lon, -1_222_873_100);
+        assert!((fix.alt_msl - 67.43).abs() < 0.001);
+        assert!((fix.ground_speed - 1.541).abs() < 0.001);
+        assert!((fix.course - 348.123_45_f32.to_radians()).abs() < 0.000_1);
+        assert!(matches!(fix.fix_type, FixType::Fix3d));
+        assert_eq!(fix.num_sv, 14);
+        assert!((fix.h_acc - 1.25).abs() < 0.001);
+        assert!(fix.is_usable());
+    }
+
+    #[test]
+    fn valid_capture() {
+        // The module pads reads with 0xff when it has nothing pending.
+        let mut stream = vec![0xff; 17];
+        stream.extend_from_slice(&NAV_PVT_CAPTURE);
+        stream.extend_from_slice(&[0xff; 9]);
+
+        let fixes = feed_all(&mut UbxParser::default(), &stream);
+
+        assert_eq!(fixes.len(), 1);
+        check_capture_fix(&fixes[0]);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut parser = UbxParser::default();
+
+        // A corrupted payload byte, then a wrong checksum byte.
+        let mut corrupted = NAV_PVT_CAPTURE;
+        corrupted[40] ^= 0x10;
+        assert!(feed_all(&mut parser, &corrupted).is_empty());
+
+        let mut corrupted = NAV_PVT_CAPTURE;
+        corrupted[99] ^= 0x01;
+        assert!(feed_all(&mut parser, &corrupted).is_empty());
+
+        // The parser resyncs on the next good frame.
+        let fixes = feed_all(&mut parser, &NAV_PVT_CAPTURE);
+        assert_eq!(fixes.len(), 1);
+        check_capture_fix(&fixes[0]);
+    }
+
+    #[test]
+    fn split_across_reads() {
+        // Every split point, including inside the preamble, length, and checksum.
+        for split in 1..NAV_PVT_CAPTURE.len() {
+            let mut parser = UbxParser::default();
+
+            let (a, b) = NAV_PVT_CAPTURE.split_at(split);
+            let mut fixes = feed_all(&mut parser, a);
+            assert!(fixes.is_empty());
+            fixes.extend(feed_all(&mut parser, b));
+
+            assert_eq!(fixes.len(), 1, "split at {}", split);
+            check_capture_fix(&fixes[0]);
+        }
+
+        // Back-to-back frames, in reads of `READ_BUF_SIZE`, as from DMA.
+        let mut stream = Vec::new();
+        for _ in 0..3 {
+            stream.extend_from_slice(&NAV_PVT_CAPTURE);
+        }
+
+        let mut parser = UbxParser::default();
+        let fixes: Vec<_> = stream
+            .chunks(READ_BUF_SIZE)
+            .flat_map(|read| feed_all(&mut parser, read))
+            .collect();
+
+        assert_eq!(fixes.len(), 3);
+        fixes.iter().for_each(check_capture_fix);
+    }
+
+    #[test]
+    fn other_frames_ignored() {
+        let mut buf = [0; 16];
+        let len = build_frame(CLASS_ACK, ID_ACK_ACK, &[CLASS_CFG, ID_CFG_VALSET], &mut buf);
+
+        let mut stream = buf[..len].to_vec();
+        stream.extend_from_slice(&NAV_PVT_CAPTURE);
+
+        let fixes = feed_all(&mut UbxParser::default(), &stream);
+        assert_eq!(fixes.len(), 1);
+    }
+}
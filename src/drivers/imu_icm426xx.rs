@@ -5,15 +5,35 @@
 //! Note that both this and the DPS310 barometer read temperature.
 //!
 //! 24 MHz max SPI frequency
+//!
+//! Full-scale ranges, and the anti-alias (AAF) and UI filters, are set from `ImuCfg`, in user
+//! config. At 8kHz, these dominate the gyro's noise vs latency tradeoff. The AAF is a 2nd-order
+//! filter ahead of the ADC decimation; the UI filter is applied after, at the output data rate.
+//! The ODR itself is `UserConfig::imu_odr`; it sets loop rates, so is only applied at init.
 
 // todo: Robust fault detection: regularly check IMU's fault registers, and put that in the init
 // todo script. Use the `Fault` status etc as required.
 
 use hal::{delay_us, gpio::Pin, spi};
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    board_config::AHB_FREQ, imu_processing::imu_shared, loop_rates::ImuOdr, setup::SpiImu,
+};
+
+pub const DEVICE_ID: u8 = 0x47;
+
+// Gyros and accelerometers in low noise mode.
+const PWR_MGMT0_VAL: u8 = 0b0000_1111;
 
-use crate::{board_config::AHB_FREQ, setup::SpiImu};
+// `GYRO_CONFIG1` and `ACCEL_CONFIG1`, without the UI filter order bits: Reset values, with 3rd
+// order DEC2_M2 filters.
+const GYRO_CONFIG1_BASE: u8 = 0b0001_0010;
+const ACCEL_CONFIG1_BASE: u8 = 0b0000_0101;
 
-const DEVICE_ID: u8 = 0x47;
+// Serialized size: Full-scale ranges, AAF bandwidths, UI filter bandwidths, and UI filter
+// orders; gyro, then accelerometer, for each.
+pub const IMU_CFG_SIZE: usize = 8;
 
 use defmt::println;
 
@@ -24,6 +44,199 @@ use defmt::println;
 pub enum ImuError {
     NotConnected,
     SelfTestFail,
+    /// Registers read back after setup don't match what we wrote.
+    ConfigMismatch,
+}
+
+/// Gyro full-scale range; the `GYRO_FS_SEL` field of `GYRO_CONFIG0`. Repr is how it's stored.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum GyroFs {
+    #[default]
+    Dps2000 = 0,
+    Dps1000 = 1,
+    Dps500 = 2,
+    Dps250 = 3,
+}
+
+impl GyroFs {
+    /// In radians per second.
+    pub fn fullscale(self) -> f32 {
+        match self {
+            Self::Dps2000 => 34.90659,
+            Self::Dps1000 => 17.453293,
+            Self::Dps500 => 8.726646,
+            Self::Dps250 => 4.363323,
+        }
+    }
+}
+
+/// Accelerometer full-scale range; the `ACCEL_FS_SEL` field of `ACCEL_CONFIG0`. Repr is how it's
+/// stored.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum AccelFs {
+    #[default]
+    G16 = 0,
+    G8 = 1,
+    G4 = 2,
+    G2 = 3,
+}
+
+impl AccelFs {
+    /// In m/s^2.
+    pub fn fullscale(self) -> f32 {
+        match self {
+            Self::G16 => 156.9056,
+            Self::G8 => 78.4528,
+            Self::G4 => 39.2264,
+            Self::G2 => 19.6132,
+        }
+    }
+}
+
+/// Anti-alias filter 3dB bandwidth. A subset of the table in DS, section 5.3. Repr is how it's
+/// stored.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum AafBandwidth {
+    Hz126 = 0,
+    Hz258 = 1,
+    Hz536 = 2,
+    #[default]
+    Hz997 = 3,
+    Hz1962 = 4,
+}
+
+impl AafBandwidth {
+    /// `AAF_DELT`, `AAF_DELTSQR`, and `AAF_BITSHIFT`.
+    fn reg_vals(self) -> (u8, u16, u8) {
+        match self {
+            Self::Hz126 => (3, 9, 12),
+            Self::Hz258 => (6, 36, 10),
+            Self::Hz536 => (12, 144, 8),
+            Self::Hz997 => (21, 440, 6),
+            Self::Hz1962 => (37, 1376, 4),
+        }
+    }
+}
+
+/// UI filter bandwidth; the `GYRO_UI_FILT_BW` and `ACCEL_UI_FILT_BW` fields of
+/// `GYRO_ACCEL_CONFIG0`. Repr is the register value, and how it's stored.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum UiFiltBw {
+    /// ODR / 2
+    Odr2 = 0,
+    Odr4 = 1,
+    Odr5 = 2,
+    Odr8 = 3,
+    Odr10 = 4,
+    Odr16 = 5,
+    Odr20 = 6,
+    Odr40 = 7,
+    /// Decimation only, with the DEC2 filter running at max(400Hz, ODR). The UI filter order
+    /// doesn't apply.
+    #[default]
+    LowLatency = 14,
+    /// Decimation only, with the DEC2 filter running at max(400Hz, 8 x ODR). The UI filter order
+    /// doesn't apply.
+    LowLatency8x = 15,
+}
+
+/// UI filter order; the `GYRO_UI_FILT_ORD` and `ACCEL_UI_FILT_ORD` fields of `GYRO_CONFIG1` and
+/// `ACCEL_CONFIG1`. Repr is the register value, and how it's stored.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum UiFiltOrder {
+    First = 0,
+    #[default]
+    Second = 1,
+    Third = 2,
+}
+
+/// Full-scale ranges, and filter settings. Stored in user config. The defaults match what we
+/// used before these were configurable.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct ImuCfg {
+    pub gyro_fs: GyroFs,
+    pub accel_fs: AccelFs,
+    pub gyro_aaf: AafBandwidth,
+    pub accel_aaf: AafBandwidth,
+    pub gyro_ui_bw: UiFiltBw,
+    pub accel_ui_bw: UiFiltBw,
+    pub gyro_ui_order: UiFiltOrder,
+    pub accel_ui_order: UiFiltOrder,
+}
+
+impl ImuCfg {
+    /// Parse and validate. Returns `None` if any values are invalid.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        Some(Self {
+            gyro_fs: GyroFs::try_from(buf[0]).ok()?,
+            accel_fs: AccelFs::try_from(buf[1]).ok()?,
+            gyro_aaf: AafBandwidth::try_from(buf[2]).ok()?,
+            accel_aaf: AafBandwidth::try_from(buf[3]).ok()?,
+            gyro_ui_bw: UiFiltBw::try_from(buf[4]).ok()?,
+            accel_ui_bw: UiFiltBw::try_from(buf[5]).ok()?,
+            gyro_ui_order: UiFiltOrder::try_from(buf[6]).ok()?,
+            accel_ui_order: UiFiltOrder::try_from(buf[7]).ok()?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; IMU_CFG_SIZE] {
+        [
+            self.gyro_fs as u8,
+            self.accel_fs as u8,
+            self.gyro_aaf as u8,
+            self.accel_aaf as u8,
+            self.gyro_ui_bw as u8,
+            self.accel_ui_bw as u8,
+            self.gyro_ui_order as u8,
+            self.accel_ui_order as u8,
+        ]
+    }
+
+    fn gyro_config0(&self, odr: ImuOdr) -> u8 {
+        (self.gyro_fs as u8) << 5 | odr.reg_val()
+    }
+
+    fn accel_config0(&self, odr: ImuOdr) -> u8 {
+        (self.accel_fs as u8) << 5 | odr.reg_val()
+    }
+
+    fn gyro_config1(&self) -> u8 {
+        GYRO_CONFIG1_BASE | (self.gyro_ui_order as u8) << 2
+    }
+
+    fn accel_config1(&self) -> u8 {
+        ACCEL_CONFIG1_BASE | (self.accel_ui_order as u8) << 3
+    }
+
+    fn gyro_accel_config0(&self) -> u8 {
+        (self.accel_ui_bw as u8) << 4 | self.gyro_ui_bw as u8
+    }
+
+    /// `GYRO_CONFIG_STATIC3` - `5`, with the gyro AAF settings.
+    fn gyro_aaf_regs(&self) -> [u8; 3] {
+        let (delt, delt_sqr, bitshift) = self.gyro_aaf.reg_vals();
+        [
+            delt,
+            (delt_sqr & 0xff) as u8,
+            (delt_sqr >> 8) as u8 | bitshift << 4,
+        ]
+    }
+
+    /// `ACCEL_CONFIG_STATIC2` - `4`, with the accelerometer AAF settings. `ACCEL_AAF_DELT` is
+    /// bits 6:1; bit 0 disables the filter.
+    fn accel_aaf_regs(&self) -> [u8; 3] {
+        let (delt, delt_sqr, bitshift) = self.accel_aaf.reg_vals();
+        [
+            delt << 1,
+            (delt_sqr & 0xff) as u8,
+            (delt_sqr >> 8) as u8 | bitshift << 4,
+        ]
+    }
 }
 
 impl From<spi::SpiError> for ImuError {
@@ -67,6 +280,7 @@ pub enum RegBank0 {
     AccelConfig0 = 0x50,
     GyroConfig1 = 0x51,
     GyroAccelConfig0 = 0x52,
+    AccelConfig1 = 0x53,
 
     IntConfig0 = 0x63,
     IntConfig1 = 0x64,
@@ -129,8 +343,9 @@ impl Reg {
     }
 }
 
-// We use this to determine which reg to start DMA reads
-pub const READINGS_START_ADDR: u8 = 0x80 | 0x1F; // (AccelDataX1)
+// We use this to determine which reg to start DMA reads. We start at temperature, since it's
+// directly before the accel and gyro data regs.
+pub const READINGS_START_ADDR: u8 = 0x80 | 0x1D; // (TempData1)
 
 // https://github.com/pms67/Attitude-Estimation
 
@@ -170,70 +385,96 @@ fn set_bank(bank: Reg, spi: &mut SpiImu, cs: &mut Pin) -> Result<(), ImuError> {
     write_one(Reg::Bank0(RegBank0::BankSel), val, spi, cs)
 }
 
+// Gyro, then accelerometer AAF registers, in the order of `ImuCfg::gyro_aaf_regs` and
+// `ImuCfg::accel_aaf_regs`.
+const AAF_REGS: [Reg; 6] = [
+    Reg::Bank1(RegBank1::GyroConfigStatic3),
+    Reg::Bank1(RegBank1::GyroConfigStatic4),
+    Reg::Bank1(RegBank1::GyroConfigStatic5),
+    Reg::Bank2(RegBank2::AccelConfigStatic2),
+    Reg::Bank2(RegBank2::AccelConfigStatic3),
+    Reg::Bank2(RegBank2::AccelConfigStatic4),
+];
+
 /// Set up anti-alias filters. See DS, section 5.3. Reselect Bank 0 once complete.
-fn setup_aa_filters(spi: &mut SpiImu, cs: &mut Pin) -> Result<(), ImuError> {
-    // todo: What should these be?? Currently set to 997Hz.
-    let aaf_delt = 21_u16;
-    let aaf_delt_sqr = 440_u16;
-    let aaf_bitshift = 6_u16;
-    set_bank(Reg::Bank1(RegBank1::GyroConfigStatic3), spi, cs)?; // todo dummy val
+fn setup_aa_filters(cfg: &ImuCfg, spi: &mut SpiImu, cs: &mut Pin) -> Result<(), ImuError> {
+    let mut vals = [0; 6];
+    vals[..3].copy_from_slice(&cfg.gyro_aaf_regs());
+    vals[3..].copy_from_slice(&cfg.accel_aaf_regs());
+
+    for (i, (reg, val)) in AAF_REGS.iter().zip(vals).enumerate() {
+        if i % 3 == 0 {
+            set_bank(*reg, spi, cs)?;
+        }
+        write_one(*reg, val, spi, cs)?;
+    }
 
-    write_one(
-        Reg::Bank1(RegBank1::GyroConfigStatic3),
-        aaf_delt as u8,
-        spi,
-        cs,
-    )?;
+    set_bank(Reg::Bank0(RegBank0::WhoAmI), spi, cs)?; // todo dummy val
 
-    write_one(
-        Reg::Bank1(RegBank1::GyroConfigStatic4),
-        (aaf_delt_sqr & 0xff) as u8,
-        spi,
-        cs,
-    )?;
+    Ok(())
+}
 
-    write_one(
-        Reg::Bank1(RegBank1::GyroConfigStatic5),
-        ((aaf_delt_sqr >> 8) | (aaf_bitshift << 4)) as u8,
-        spi,
-        cs,
-    )?;
+/// Read back the AAF registers. Reselects Bank 0 once complete.
+fn verify_aa_filters(cfg: &ImuCfg, spi: &mut SpiImu, cs: &mut Pin) -> Result<bool, ImuError> {
+    let mut vals = [0; 6];
+    vals[..3].copy_from_slice(&cfg.gyro_aaf_regs());
+    vals[3..].copy_from_slice(&cfg.accel_aaf_regs());
 
-    set_bank(Reg::Bank2(RegBank2::AccelConfigStatic2), spi, cs)?; // todo dummy val
+    let mut matches = true;
+    for (i, (reg, val)) in AAF_REGS.iter().zip(vals).enumerate() {
+        if i % 3 == 0 {
+            set_bank(*reg, spi, cs)?;
+        }
+        matches &= read_one(*reg, spi, cs)? == val;
+    }
 
-    write_one(
-        Reg::Bank2(RegBank2::AccelConfigStatic2),
-        aaf_delt as u8,
-        spi,
-        cs,
-    )?;
+    set_bank(Reg::Bank0(RegBank0::WhoAmI), spi, cs)?; // todo dummy val
 
-    write_one(
-        Reg::Bank2(RegBank2::AccelConfigStatic3),
-        (aaf_delt_sqr & 0xff) as u8,
-        spi,
-        cs,
-    )?;
+    Ok(matches)
+}
 
-    write_one(
-        Reg::Bank2(RegBank2::AccelConfigStatic4),
-        ((aaf_delt_sqr >> 8) | (aaf_bitshift << 4)) as u8,
-        spi,
-        cs,
-    )?;
+/// Read the WHO_AM_I register. This should be `DEVICE_ID`.
+pub fn read_device_id(spi: &mut SpiImu, cs: &mut Pin) -> Result<u8, ImuError> {
+    read_one(Reg::Bank0(RegBank0::WhoAmI), spi, cs)
+}
 
-    set_bank(Reg::Bank0(RegBank0::WhoAmI), spi, cs)?; // todo dummy val
+/// Read back the WHO_AM_I register, `PWR_MGMT0`, which resets to 0 (sensors off) if the device
+/// resets, and the registers setting ODR, full-scale ranges, and filters. Returns `false` if any
+/// don't match what `setup` configured. Bank 0 must be selected, as it is after `setup`.
+pub fn verify_config(
+    spi: &mut SpiImu,
+    cs: &mut Pin,
+    odr: ImuOdr,
+    cfg: &ImuCfg,
+) -> Result<bool, ImuError> {
+    let device_id = read_device_id(spi, cs)?;
+    let pwr_mgmt = read_one(Reg::Bank0(RegBank0::PwrMgmt0), spi, cs)?;
+
+    let expected = [
+        (RegBank0::GyroConfig0, cfg.gyro_config0(odr)),
+        (RegBank0::AccelConfig0, cfg.accel_config0(odr)),
+        (RegBank0::GyroConfig1, cfg.gyro_config1()),
+        (RegBank0::AccelConfig1, cfg.accel_config1()),
+        (RegBank0::GyroAccelConfig0, cfg.gyro_accel_config0()),
+    ];
+
+    let mut matches = device_id == DEVICE_ID && pwr_mgmt == PWR_MGMT0_VAL;
+    for (reg, val) in expected {
+        matches &= read_one(Reg::Bank0(reg), spi, cs)? == val;
+    }
 
-    Ok(())
+    Ok(matches && verify_aa_filters(cfg, spi, cs)?)
 }
 
-/// Configure the device.
-pub fn setup(spi: &mut SpiImu, cs: &mut Pin) -> Result<(), ImuError> {
+/// Configure the device. `odr` sets the update rate of both the gyro and accelerometer; it drives
+/// the main loop. Registers are read back once written; returns `ImuError::ConfigMismatch` if they
+/// don't match.
+pub fn setup(spi: &mut SpiImu, cs: &mut Pin, odr: ImuOdr, cfg: &ImuCfg) -> Result<(), ImuError> {
     // todo: Without self-test, we'll use a WHOAMI read to verify if the IMU is connected. Note that
     // todo the SPI bus will still not fail if the IMU isn't present. HAL error?
     // todo: Better sanity check than WHOAMI.
 
-    let device_id = read_one(Reg::Bank0(RegBank0::WhoAmI), spi, cs)?;
+    let device_id = read_device_id(spi, cs)?;
 
     println!("Device ID SPI: {}", device_id);
 
@@ -247,28 +488,50 @@ pub fn setup(spi: &mut SpiImu, cs: &mut Pin) -> Result<(), ImuError> {
     write_one(Reg::Bank1(RegBank1::IntfConfig5), 0b0000_0100, spi, cs)?;
     // (Bank 0 set by AA filter setup fn);
 
-    setup_aa_filters(spi, cs)?;
+    setup_aa_filters(cfg, spi, cs)?;
 
     // Enable gyros and accelerometers in low noise mode.
     // Do this after setting up the AA filters.
-    write_one(Reg::Bank0(RegBank0::PwrMgmt0), 0b0000_1111, spi, cs)?;
+    write_one(Reg::Bank0(RegBank0::PwrMgmt0), PWR_MGMT0_VAL, spi, cs)?;
 
-    // Set gyros and accelerometers to 8kHz update rate, 2000 DPS gyro full scale range,
-    // and +-16g accelerometer full scale range.
-    write_one(Reg::Bank0(RegBank0::GyroConfig0), 0b0000_0011, spi, cs)?;
+    // Set gyros and accelerometers to the configured update rate, and full scale ranges.
+    write_one(
+        Reg::Bank0(RegBank0::GyroConfig0),
+        cfg.gyro_config0(odr),
+        spi,
+        cs,
+    )?;
 
     // "When transitioning from OFF to any of the other modes, do not issue any
     // register writes for 200µs." (Gyro and accel)
     delay_us(200, AHB_FREQ);
 
-    write_one(Reg::Bank0(RegBank0::AccelConfig0), 0b0000_0011, spi, cs)?;
+    write_one(
+        Reg::Bank0(RegBank0::AccelConfig0),
+        cfg.accel_config0(odr),
+        spi,
+        cs,
+    )?;
     delay_us(200, AHB_FREQ);
 
-    // Set both the accelerator and gyro filters to the low latency option.
-    // todo: This is what BF does. Do we want this?
+    imu_shared::set_fullscale(cfg.accel_fs.fullscale(), cfg.gyro_fs.fullscale());
+
+    // UI filter order, then bandwidth. (The low latency options are what BF uses.)
+    write_one(
+        Reg::Bank0(RegBank0::GyroConfig1),
+        cfg.gyro_config1(),
+        spi,
+        cs,
+    )?;
+    write_one(
+        Reg::Bank0(RegBank0::AccelConfig1),
+        cfg.accel_config1(),
+        spi,
+        cs,
+    )?;
     write_one(
         Reg::Bank0(RegBank0::GyroAccelConfig0),
-        14 << 4 | 14,
+        cfg.gyro_accel_config0(),
         spi,
         cs,
     )?;
@@ -289,13 +552,15 @@ pub fn setup(spi: &mut SpiImu, cs: &mut Pin) -> Result<(), ImuError> {
     // Enable UI data ready interrupt routed to the INT1 pin.
     write_one(Reg::Bank0(RegBank0::IntSource0), 0b0000_1000, spi, cs)?;
 
-    // todo: Set filters?
-
     // Start a self test on all 6 channels, and accel power self test.
     // write_one(Reg::SelfTestConfig, 0b0111_1111, spi, cs)?;
 
     // todo: Get self-test working. DS is unclear on how this works. If fail, return SelfTest error.
 
+    if !verify_config(spi, cs, odr, cfg)? {
+        return Err(ImuError::ConfigMismatch);
+    }
+
     Ok(())
 }
 
@@ -1,16 +1,36 @@
-//! This module contains code for the ICM42605 inertial measuring unit.
-//! This IMU has a 8kHz maximum update rate. Also compatible with ICM42688
-//! SPI speed max is 24Mhz.
-//!
-//! Note that both this and the DPS310 barometer read temperature.
+//! This module contains code for the ISM330DHCX inertial measuring unit. We use it as the
+//! secondary IMU; see `dual_imu`. This IMU has a 6.66kHz maximum update rate.
+//! SPI speed max is 10Mhz.
 //!
+//! Full-scale ranges are fixed, at ±16G, and ±2,000 degrees/sec. Readings are converted to the
+//! ICM-426xx's buffer layout, so they're parsed and checked with the primary's code.
 //!
+//! Only the H7 board has a bus for it; the device functions are H7 only.
 
 // todo: Consider hardware notch filter.
 
-use hal::{gpio::Pin, pac::SPI1, spi::Spi};
+#[cfg(feature = "h7")]
+use hal::gpio::Pin;
+
+use crate::imu_processing::imu_shared::READINGS_BUF_SIZE;
+#[cfg(feature = "h7")]
+use crate::{drivers::imu_icm426xx::ImuError, setup::SpiImu2};
+
+pub const DEVICE_ID: u8 = 0x6B;
 
-use crate::imu_shared::_ImuReadingsRaw;
+// ODR = 6.66kHz, ±16G, first stage digital filtering.
+const CTRL1_XL_VAL: u8 = 0b1010_0100;
+// ODR = 6.66kHz, ±2,000 dps.
+const CTRL2_G_VAL: u8 = 0b1010_1100;
+// Block data update, so the high and low bytes of a reading are from the same sample, and
+// register address auto-increment, for burst reads.
+const CTRL3_C_VAL: u8 = 0b0100_0100;
+
+// In m/s^2, and radians per second; the value of a reading of `i16::MAX`, as passed to
+// `ImuReadings::from_buffer`. The sensitivities are 0.488mg and 70mdps per LSB; these are
+// slightly wider than the nominal ranges.
+pub const ACCEL_FULLSCALE: f32 = 156.816;
+pub const GYRO_FULLSCALE: f32 = 40.03366;
 
 /// See Datasheet, Table 19.
 #[allow(dead_code)]
@@ -74,117 +94,101 @@ pub enum Reg {
     FifoDataOutZH = 0x7E,
 }
 
-// todo: Copy `read_reg` method from icm426xx` too, if required.
 impl Reg {
     /// Get the read address, which has the MSB = 1. Use the `u8` repr for writes.
     pub fn read_addr(&self) -> u8 {
         0x80 | (*self as u8)
     }
 }
-// We use this to determine which reg to start DMA reads
-pub const READINGS_START_ADDR: u8 = 0x80 | 0x22; // (OutxLG)
-
-// todo: Read via DMA at a very high rate, then apply a lowpass filter?
-
-// https://github.com/pms67/Attitude-Estimation
+// We use this to determine which reg to start DMA reads. Temperature, then gyro, then
+// accelerometer.
+pub const READINGS_START_ADDR: u8 = 0x80 | 0x20; // (OutTempL)
 
 /// Utility function to read a single byte.
-fn read_one(reg: Reg, spi: &mut Spi<SPI1>, cs: &mut Pin) -> u8 {
+#[cfg(feature = "h7")]
+fn read_one(reg: Reg, spi: &mut SpiImu2, cs: &mut Pin) -> Result<u8, ImuError> {
     let mut buf = [reg.read_addr(), 0];
 
     cs.set_low();
-    spi.transfer(&mut buf).ok();
+    spi.transfer(&mut buf)?;
     cs.set_high();
 
-    buf[1]
+    Ok(buf[1])
 }
 
 /// Utility function to write a single byte.
-fn write_one(reg: Reg, word: u8, spi: &mut Spi<SPI1>, cs: &mut Pin) {
+#[cfg(feature = "h7")]
+fn write_one(reg: Reg, word: u8, spi: &mut SpiImu2, cs: &mut Pin) -> Result<(), ImuError> {
     cs.set_low();
-    spi.write(&[reg as u8, word]).ok();
+    spi.write(&[reg as u8, word])?;
     cs.set_high();
+
+    Ok(())
 }
 
-/// Configure the device.
-pub fn setup(spi: &mut Spi<SPI1>, cs: &mut Pin) {
+/// Configure the device. Registers are read back once written; returns
+/// `ImuError::ConfigMismatch` if they don't match.
+#[cfg(feature = "h7")]
+pub fn setup(spi: &mut SpiImu2, cs: &mut Pin) -> Result<(), ImuError> {
     // Leave default of SPI mode 0 and 3.
 
+    if read_one(Reg::WhoAmI, spi, cs)? != DEVICE_ID {
+        return Err(ImuError::NotConnected);
+    }
+
+    write_one(Reg::Ctrl3C, CTRL3_C_VAL, spi, cs)?;
+
     // "The accelerometer is activated from power-down by writing ODR_XL[3:0] in CTRL1_XL (10h) while the gyroscope
     // is activated from power-down by writing ODR_G[3:0] in CTRL2_G (11h). For combo-mode the ODRs are totally
     // independent."
-
-    // Set accelerometer to ODR = 6.66kHz update rate, +-16G full scale range, first state digital filtering
-    // todo: Currently set to output from first state digital filtering. Do we want this, or second?
-
-    write_one(Reg::Ctrl1Xl, 0b1010_0100, spi, cs);
-
-    // Set gyro ODR to 6.66kHz update rate, 2000dps full scale range
-    write_one(Reg::Ctrl2G, 0b1010_1100, spi, cs);
+    write_one(Reg::Ctrl1Xl, CTRL1_XL_VAL, spi, cs)?;
+    write_one(Reg::Ctrl2G, CTRL2_G_VAL, spi, cs)?;
 
     // Disable I2C interface. Enable Gyro LPF1.
-    write_one(Reg::Ctrl4C, 0b0000_0110, spi, cs);
+    write_one(Reg::Ctrl4C, 0b0000_0110, spi, cs)?;
 
     // Enable high performance mode on the accelerometer
-    write_one(Reg::Ctrl6C, 0b1000_0000, spi, cs);
+    write_one(Reg::Ctrl6C, 0b1000_0000, spi, cs)?;
 
     // Enable high performance mode on the gyro
-    write_one(Reg::Ctrl7G, 0b1000_0000, spi, cs);
+    write_one(Reg::Ctrl7G, 0b1000_0000, spi, cs)?;
+
+    // We don't use the data-ready interrupt; reads are clocked by the primary IMU's.
 
-    // Enable data ready on INT1 pin.
-    // todo: What do we want? Both accel and gyro? DEN? What is DEN? Will both gryo and accel
-    // todo together cause problems?
-    write_one(Reg::Int1Ctrl, 0b0000_0011, spi, cs);
+    let expected = [
+        (Reg::Ctrl1Xl, CTRL1_XL_VAL),
+        (Reg::Ctrl2G, CTRL2_G_VAL),
+        (Reg::Ctrl3C, CTRL3_C_VAL),
+    ];
 
-    // todo: Finish this; check what other settings are avail.
+    for (reg, val) in expected {
+        if read_one(reg, spi, cs)? != val {
+            return Err(ImuError::ConfigMismatch);
+        }
+    }
+
+    Ok(())
 }
 
-// todo: Low power fn
+/// Convert a readings buffer, read from `READINGS_START_ADDR`, to the ICM-426xx's layout: Register
+/// byte, temperature, accelerometer, then gyro; big endian, with temperature at its scale. This
+/// device's words are little endian, with the gyro first, and temperature at 256 LSB/°C.
+pub fn to_primary_layout(buf: &[u8; READINGS_BUF_SIZE]) -> [u8; READINGS_BUF_SIZE] {
+    let word = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]);
 
-// todo: Combine read_temp and read_all with ICM driver?, but pass different reg values
+    let mut result = [0; READINGS_BUF_SIZE];
 
-/// Read temperature.
-pub fn _read_temp(spi: &mut Spi<SPI1>, cs: &mut Pin) -> f32 {
-    let upper_byte = read_one(Reg::OutTempH, spi, cs);
-    let lower_byte = read_one(Reg::OutTempL, spi, cs);
+    // Both read 0 at 25°C.
+    let temp = (word(1) as f32 * 132.48 / 256.) as i16;
+    result[1..3].clone_from_slice(&temp.to_be_bytes());
 
-    // todo: What's the conversion? This comment and code is for the ICM chip.
-    // Temperature in Degrees Centigrade = (TEMP_DATA / 132.48) + 25
-    let temp_data = ((upper_byte as u16) << 8) | (lower_byte as u16);
-    temp_data as f32 / 132.48 + 25.
-}
+    for axis in 0..3 {
+        let gyro = word(3 + axis * 2);
+        let accel = word(9 + axis * 2);
 
-/// Read all data
-pub fn _read_all(spi: &mut Spi<SPI1>, cs: &mut Pin) -> _ImuReadingsRaw {
-    let accel_x_upper = read_one(Reg::OutxHA, spi, cs);
-    let accel_x_lower = read_one(Reg::OutxLA, spi, cs);
-    let accel_y_upper = read_one(Reg::OutyHA, spi, cs);
-    let accel_y_lower = read_one(Reg::OutyLA, spi, cs);
-    let accel_z_upper = read_one(Reg::OutzHA, spi, cs);
-    let accel_z_lower = read_one(Reg::OutzLA, spi, cs);
-
-    let gyro_x_upper = read_one(Reg::OutxHG, spi, cs);
-    let gyro_x_lower = read_one(Reg::OutxLG, spi, cs);
-    let gyro_y_upper = read_one(Reg::OutyHG, spi, cs);
-    let gyro_y_lower = read_one(Reg::OutyLG, spi, cs);
-    let gyro_z_upper = read_one(Reg::OutzHG, spi, cs);
-    let gyro_z_lower = read_one(Reg::OutzLG, spi, cs);
-
-    let a_x = i16::from_be_bytes([accel_x_upper, accel_x_lower]);
-    let a_y = i16::from_be_bytes([accel_y_upper, accel_y_lower]);
-    let a_z = i16::from_be_bytes([accel_z_upper, accel_z_lower]);
-
-    // Positive yaw: CW rotation. Positive pitch: Nose down.
-    let v_pitch = i16::from_be_bytes([gyro_x_upper, gyro_x_lower]);
-    let v_roll = i16::from_be_bytes([gyro_y_upper, gyro_y_lower]);
-    let v_yaw = i16::from_be_bytes([gyro_z_upper, gyro_z_lower]);
-
-    _ImuReadingsRaw {
-        a_x,
-        a_y,
-        a_z,
-        v_pitch,
-        v_roll,
-        v_yaw,
+        result[3 + axis * 2..5 + axis * 2].clone_from_slice(&accel.to_be_bytes());
+        result[9 + axis * 2..11 + axis * 2].clone_from_slice(&gyro.to_be_bytes());
     }
+
+    result
 }
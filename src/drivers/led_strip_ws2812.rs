@@ -0,0 +1,128 @@
+//! This module contains a driver for WS2812 ("NeoPixel") addressable RGB LEDs, on a single data
+//! line. Like DSHOT, each bit is a fixed-length PWM period, with its high time setting its value;
+//! we build a buffer of duty cycle (CCR) values, one per bit, and send it with timer burst DMA.
+//!
+//! Each LED takes 24 bits: Green, red, then blue, MSB first. A low period of at least 50µs after
+//! the last LED latches the colors.
+
+use hal::{
+    dma, pac,
+    timer::{OutputCompare, Timer, TimerInterrupt},
+};
+
+use crate::{
+    board_config::TIM_CLK_SPEED,
+    setup::{self, DmaTransfer},
+};
+
+// The most LEDs we support on a strip.
+pub const MAX_LEDS: usize = 16;
+
+const BITS_PER_LED: usize = 24;
+
+// Hz. 1.25µs per bit.
+const BIT_RATE: u32 = 800_000;
+
+const ARR: u32 = TIM_CLK_SPEED / BIT_RATE - 1;
+// Duty cycle values. ~0.8µs high for a 1, and ~0.4µs for a 0.
+const DUTY_HIGH: u16 = (ARR * 2 / 3) as u16;
+const DUTY_LOW: u16 = (ARR / 3) as u16;
+
+// Periods held low after the last LED, to latch. 50 periods is 62.5µs.
+const RESET_PERIODS: usize = 50;
+
+const BUF_LEN: usize = MAX_LEDS * BITS_PER_LED + RESET_PERIODS;
+
+// The reset periods stay 0, since we only write the LED section.
+static mut PAYLOAD: [u16; BUF_LEN] = [0; BUF_LEN];
+
+/// 8-bit color channels.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Self = Self::new(0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Scale each channel. `brightness` is 0 - 255.
+    pub fn scaled(&self, brightness: u8) -> Self {
+        let scale = |v: u8| (v as u16 * brightness as u16 / 255) as u8;
+
+        Self {
+            r: scale(self.r),
+            g: scale(self.g),
+            b: scale(self.b),
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Self::new(buf[0], buf[1], buf[2])
+    }
+
+    pub fn to_bytes(&self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+}
+
+/// Owns the LED strip timer.
+pub struct LedStrip {
+    timer: Timer<pac::TIM4>,
+}
+
+impl LedStrip {
+    /// Configure the timer for PWM at the WS2812 bit rate. The data pin must already be set to
+    /// the timer's alternate function.
+    pub fn new(mut timer: Timer<pac::TIM4>) -> Self {
+        timer.set_prescaler(0);
+        timer.set_auto_reload(ARR);
+
+        timer.enable_pwm_output(setup::LED_STRIP_TIM_CH, OutputCompare::Pwm1, 0.);
+        timer.enable_interrupt(TimerInterrupt::UpdateDma);
+
+        Self { timer }
+    }
+
+    /// Send colors to the strip. Non-blocking; if a previous write is in progress, it's
+    /// restarted. LEDs past `MAX_LEDS` are ignored.
+    pub fn write(&mut self, colors: &[Rgb]) {
+        let num_leds = colors.len().min(MAX_LEDS);
+
+        for (i, color) in colors.iter().take(num_leds).enumerate() {
+            let word = (color.g as u32) << 16 | (color.r as u32) << 8 | color.b as u32;
+
+            for bit in 0..BITS_PER_LED {
+                let val = if (word >> (BITS_PER_LED - 1 - bit)) & 1 == 1 {
+                    DUTY_HIGH
+                } else {
+                    DUTY_LOW
+                };
+                unsafe { PAYLOAD[i * BITS_PER_LED + bit] = val };
+            }
+        }
+
+        // Send the LEDs in use, then the reset periods.
+        let len = num_leds * BITS_PER_LED + RESET_PERIODS;
+
+        // Stop any transfer in progress.
+        dma::stop(setup::LED_STRIP_DMA_PERIPH, setup::LED_STRIP_CH);
+
+        unsafe {
+            self.timer.write_dma_burst(
+                &PAYLOAD[..len],
+                setup::LED_STRIP_BASE_DIR_OFFSET,
+                1,
+                setup::LED_STRIP_CH,
+                setup::dma_cfg(DmaTransfer::LedStrip),
+                true,
+                setup::LED_STRIP_DMA_PERIPH,
+            );
+        }
+    }
+}
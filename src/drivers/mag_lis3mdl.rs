@@ -0,0 +1,104 @@
+//! This module contains code for the ST LIS3MDL magnetometer, on the external sensors I2C bus. It's
+//! set up here, and then read continuously over DMA, in sequence with the GPS and TOF sensor; see
+//! `sensors_shared`.
+//!
+//! Measurement rate: 80Hz.
+
+use hal::i2c;
+use lin_alg::f32::Vec3;
+
+use crate::setup::I2cMag;
+
+// 7-bit address, with SDO/SA1 pulled high. It's 0x1c if pulled low.
+pub const ADDR: u8 = 0x1e;
+const WHO_AM_I: u8 = 0x3d;
+
+// Set in a register address to read several registers in one transfer.
+const AUTO_INCREMENT: u8 = 0x80;
+
+/// The register address to write ahead of a DMA read of `READ_BUF_SIZE` bytes.
+pub const READ_START: u8 = Reg::OutXL as u8 | AUTO_INCREMENT;
+
+// X, Y, and Z, each an i16.
+pub const READ_BUF_SIZE: usize = 6;
+
+pub const FULLSCALE: MagFullscale = MagFullscale::G4;
+
+pub struct MagNotConnectedError {}
+
+impl From<i2c::Error> for MagNotConnectedError {
+    fn from(_: i2c::Error) -> Self {
+        Self {}
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Reg {
+    WhoAmI = 0x0f,
+    CtrlReg1 = 0x20,
+    CtrlReg2 = 0x21,
+    CtrlReg3 = 0x22,
+    CtrlReg4 = 0x23,
+    CtrlReg5 = 0x24,
+    StatusReg = 0x27,
+    OutXL = 0x28,
+}
+
+/// Full scale range. Set in `CTRL_REG2`.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum MagFullscale {
+    G4 = 0b00,
+    G8 = 0b01,
+    G12 = 0b10,
+    G16 = 0b11,
+}
+
+impl MagFullscale {
+    /// From the LIS3MDL datasheet, Table 3.
+    pub fn lsb_per_gauss(&self) -> f32 {
+        match self {
+            Self::G4 => 6_842.,
+            Self::G8 => 3_421.,
+            Self::G12 => 2_281.,
+            Self::G16 => 1_711.,
+        }
+    }
+}
+
+/// Check that the sensor is present, and start continuous measurements at `FULLSCALE`.
+pub fn setup(i2c: &mut I2cMag) -> Result<(), MagNotConnectedError> {
+    let mut buf = [0];
+    i2c.write_read(ADDR, &[Reg::WhoAmI as u8], &mut buf)?;
+
+    if buf[0] != WHO_AM_I {
+        return Err(MagNotConnectedError {});
+    }
+
+    // X and Y in ultra-high-performance mode, at 80Hz. Temperature sensor off.
+    i2c.write(ADDR, &[Reg::CtrlReg1 as u8, 0b0111_1100])?;
+    i2c.write(ADDR, &[Reg::CtrlReg2 as u8, (FULLSCALE as u8) << 5])?;
+    // Continuous conversion.
+    i2c.write(ADDR, &[Reg::CtrlReg3 as u8, 0])?;
+    // Z in ultra-high-performance mode; little endian.
+    i2c.write(ADDR, &[Reg::CtrlReg4 as u8, 0b0000_1100])?;
+    // Block data update, so we don't read the low and high bytes of different samples.
+    i2c.write(ADDR, &[Reg::CtrlReg5 as u8, 0b0100_0000])?;
+
+    Ok(())
+}
+
+/// Convert a reading, starting at `OUT_X_L`, to µT. Each axis is a little-endian i16.
+pub fn reading_from_buf(buf: &[u8; READ_BUF_SIZE]) -> Vec3 {
+    // 1 gauss = 100µT.
+    let scale = 100. / FULLSCALE.lsb_per_gauss();
+
+    Vec3::new(
+        i16::from_le_bytes([buf[0], buf[1]]) as f32 * scale,
+        i16::from_le_bytes([buf[2], buf[3]]) as f32 * scale,
+        i16::from_le_bytes([buf[4], buf[5]]) as f32 * scale,
+    )
+}
@@ -2,9 +2,11 @@
 
 pub mod baro_dps310;
 pub mod gnss_can;
+pub mod gps_ublox;
 pub mod imu_icm426xx;
-// pub mod imu_ism330dhcx;
-// pub mod mag_lis3mdl;
+pub mod imu_ism330dhcx;
+pub mod led_strip_ws2812;
+pub mod mag_lis3mdl;
 // pub mod optical_flow_driver;
 pub mod osd;
 // `tof_driver` uses partially-translated C code that doesn't conform to Rust naming conventions.
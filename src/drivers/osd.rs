@@ -26,10 +26,12 @@ use hal::dma::DmaChannel;
 
 use crate::{
     flight_ctrls::autopilot::{self, AutopilotStatus},
+    gps_metrics::SpeedUnits,
     protocols::msp::{Direction, Packet, METADATA_SIZE_V1, MSG_ID_DP, MSG_ID_STATUS},
     safety::ArmStatus,
     sensors_shared::BattCellCount,
-    setup::{self, UartOsd},
+    setup::{self, DmaTransfer, UartOsd},
+    state::{FlightMode, Warning, FLIGHT_MODE_LABEL_LEN, WARNING_LABEL_LEN},
     util,
 };
 
@@ -52,7 +54,7 @@ pub static OSD_INTERRUPT_CYCLE: AtomicBool = AtomicBool::new(false);
 // We use this to make sure OSD writes don't step on each other.
 pub static OSD_WRITE_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
 
-static mut OSD_TX_BUF: [u8; 180] = [0; 180]; // Adjust size A/R as you adjust what's displayed.
+static mut OSD_TX_BUF: [u8; 480] = [0; 480]; // Adjust size A/R as you adjust what's displayed.
 
 // Just big enough to read the fucntion type, so we can reply if it's a status frame.
 // pub static mut OSD_READ_BUF: [u8; 5] = [0; 5];
@@ -177,6 +179,22 @@ fn format_int(buf: &mut [u8], int: u16) {
     }
 }
 
+/// Format a latitude or longitude, in degrees x 1e7, as a sign, then degrees with 5 decimal
+/// places, zero-padded.
+fn format_coord(buf: &mut [u8; 10], coord: i32) {
+    buf[0] = if coord < 0 { b'-' } else { b'+' };
+
+    let mut current = coord.unsigned_abs() / 100; // Degrees x 1e5.
+    for i in (1..10).rev() {
+        if i == 4 {
+            buf[i] = b'.';
+            continue;
+        }
+        buf[i] = digit_to_char((current % 10) as u8);
+        current /= 10;
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(u8)]
 enum SubCommand {
@@ -288,16 +306,46 @@ pub struct OsdData {
     pub arm_status: ArmStatus,
     pub battery_voltage: f32,
     pub current_draw: f32, // mA
-    pub alt_msl_baro: f32, // m
+    pub alt_msl: f32,      // m
+    /// m. Displayed in place of MSL altitude when available. `None` without a ground reference.
+    pub alt_above_takeoff: Option<f32>,
     pub posit_vel: PositVelEarthUnits,
+    /// m/s. `None` if unknown.
+    pub airspeed: Option<f32>,
     pub autopilot: AutopilotData,
-    /// Distance and bearing to the base point (usually takeoff location), in m, radians respectively.
-    pub base_dist_bearing: (f32, f32),
+    /// Distance and bearing to home (the first fix after arming), in m, radians respectively.
+    /// `None` if unknown.
+    pub base_dist_bearing: Option<(f32, f32)>,
+    /// From GNSS, in m/s. `None` without a usable fix.
+    pub ground_speed: Option<f32>,
+    /// Meters, this flight.
+    pub distance_flown: f32,
+    pub speed_units: SpeedUnits,
     pub link_quality: u8, // Same format as CRSF uses.
     pub num_satellites: u8,
     pub batt_cell_count: BattCellCount,
     pub throttle: f32,
     pub total_acc: f32,
+    /// Estimated hover throttle. 0. to 1.
+    pub hover_throttle: f32,
+    /// Estimated wind speed in m/s, and the direction it's from, in radians. `None` if there's
+    /// no estimate.
+    pub wind: Option<(f32, f32)>,
+    /// The last known position saved prior to this power-up: Lat and lon, in degrees x 1e7.
+    /// `None` once we have a new fix.
+    pub saved_posit: Option<(i32, i32)>,
+    /// Seconds armed, this flight. Held after disarming, until the next arm.
+    pub flight_time: f32,
+    /// The active control profile's index.
+    pub profile: u8,
+    /// From `state::flight_mode_label`, as sent over CRSF.
+    pub flight_mode: FlightMode,
+    /// The throttle limit in effect, 0. to 1. `None` if unlimited.
+    pub throttle_limit: Option<f32>,
+    /// The warning on the ticker, from `Warnings::ticker`.
+    pub warning: Option<Warning>,
+    /// The motor kill is latched. See the `motor_kill` module.
+    pub killed: bool,
 }
 
 fn make_heartbeat_packet<'a>() -> Packet<'a> {
@@ -388,7 +436,6 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
     // made for.
     // - AGL altitude
     // - Autopilot modes
-    // - Flight mode
     // - RSSI, LQ, and Tx power
     // - Steer point name and number
     // - Symbols on home plate, steerpoint etc?
@@ -445,25 +492,85 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
     buf_batt[8] = "%".as_bytes()[0];
     add_to_write_buf::<{ 9 + METADATA_SIZE_WRITE_PACKET }>(buf, 11, 10, &buf_batt, &mut i);
 
-    // Altitude
-    let mut alt_buf = [blank; 5];
-    format_int(&mut alt_buf[0..4], data.alt_msl_baro as u16);
+    // Warnings ticker, above the battery display, padded to a fixed width. All warnings share
+    // this line; see `state::Warnings`.
+    if let Some(warning) = data.warning {
+        let mut warning_buf = [blank; WARNING_LABEL_LEN];
+        let label = warning.label().as_bytes();
+        warning_buf[..label.len()].clone_from_slice(label);
+        add_to_write_buf::<{ WARNING_LABEL_LEN + METADATA_SIZE_WRITE_PACKET }>(
+            buf,
+            10,
+            10,
+            &warning_buf,
+            &mut i,
+        );
+    }
+
+    // Altitude. Above takeoff by default; MSL, tagged, without a ground reference.
+    let mut alt_buf = [blank; 7];
+    let alt = data.alt_above_takeoff.unwrap_or(data.alt_msl);
+    if alt < 0. {
+        // Eg landing below the takeoff point.
+        alt_buf[0] = b'-';
+        format_int(&mut alt_buf[1..4], -alt as u16);
+    } else {
+        format_int(&mut alt_buf[0..4], alt as u16);
+    }
     alt_buf[4] = "M".as_bytes()[0]; // lowercase available in font?
-    add_to_write_buf::<{ 5 + METADATA_SIZE_WRITE_PACKET }>(buf, 7, 25, &alt_buf, &mut i);
+    if data.alt_above_takeoff.is_none() {
+        alt_buf[5..7].clone_from_slice("SL".as_bytes());
+    }
+    add_to_write_buf::<{ 7 + METADATA_SIZE_WRITE_PACKET }>(buf, 7, 23, &alt_buf, &mut i);
 
     // Airspeed
     let mut airspeed_buf = [blank; 6];
-    let airspeed = data.posit_vel.velocity.magnitude() as u16;
-    format_int(&mut airspeed_buf[0..3], airspeed);
+    match data.airspeed {
+        Some(airspeed) => format_int(&mut airspeed_buf[0..3], airspeed as u16),
+        None => airspeed_buf[0..3].clone_from_slice("---".as_bytes()),
+    }
     airspeed_buf[3..6].clone_from_slice("M/S".as_bytes()); // lowercase available in font?
     add_to_write_buf::<{ 6 + METADATA_SIZE_WRITE_PACKET }>(buf, 7, 0, &airspeed_buf, &mut i);
 
+    // Ground speed, below airspeed.
+    let mut ground_speed_buf = [blank; 6];
+    match data.ground_speed {
+        Some(speed) => format_int(
+            &mut ground_speed_buf[0..3],
+            data.speed_units.convert(speed) as u16,
+        ),
+        None => ground_speed_buf[0..3].clone_from_slice("---".as_bytes()),
+    }
+    ground_speed_buf[3..6].clone_from_slice(data.speed_units.label());
+    add_to_write_buf::<{ 6 + METADATA_SIZE_WRITE_PACKET }>(buf, 8, 0, &ground_speed_buf, &mut i);
+
     // Number of sattelites
     let mut num_sats_buf = [blank; 3];
     num_sats_buf[0] = "v".as_bytes()[0]; // todo: Find the correct icon in the font.
     format_int(&mut num_sats_buf[1..3], data.num_satellites as u16);
     add_to_write_buf::<{ 3 + METADATA_SIZE_WRITE_PACKET }>(buf, 0, 13, &num_sats_buf, &mut i);
 
+    // Flight time, as minutes and seconds.
+    let mut time_buf = [blank; 5];
+    let time = data.flight_time as u16;
+    format_int(&mut time_buf[0..2], (time / 60).min(99));
+    time_buf[2] = b':';
+    time_buf[3] = digit_to_char((time % 60 / 10) as u8);
+    time_buf[4] = digit_to_char((time % 10) as u8);
+    add_to_write_buf::<{ 5 + METADATA_SIZE_WRITE_PACKET }>(buf, 0, 24, &time_buf, &mut i);
+
+    // Flight mode, padded to a fixed width.
+    let mut mode_buf = [blank; FLIGHT_MODE_LABEL_LEN];
+    let label = data.flight_mode.label().as_bytes();
+    mode_buf[..label.len()].clone_from_slice(label);
+    add_to_write_buf::<{ FLIGHT_MODE_LABEL_LEN + METADATA_SIZE_WRITE_PACKET }>(
+        buf, 0, 0, &mode_buf, &mut i,
+    );
+
+    // Control profile, numbered from 1, as on the switch.
+    let profile_buf = [b'P', digit_to_char(data.profile + 1)];
+    add_to_write_buf::<{ 2 + METADATA_SIZE_WRITE_PACKET }>(buf, 0, 20, &profile_buf, &mut i);
+
     // Throttle display.
     let mut throttle_buf = [blank; 4];
     let throttle = (data.throttle * 100.) as u16;
@@ -471,6 +578,33 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
     throttle_buf[0] = "T".as_bytes()[0];
     add_to_write_buf::<{ 4 + METADATA_SIZE_WRITE_PACKET }>(buf, 14, 0, &throttle_buf, &mut i);
 
+    // Estimated hover throttle, next to throttle.
+    let mut hover_buf = [blank; 4];
+    let hover = (data.hover_throttle * 100.) as u16;
+    format_int(&mut hover_buf[1..4], hover);
+    hover_buf[0] = "H".as_bytes()[0];
+    add_to_write_buf::<{ 4 + METADATA_SIZE_WRITE_PACKET }>(buf, 14, 5, &hover_buf, &mut i);
+
+    // Wind speed and direction, next to hover throttle.
+    let mut wind_buf = [blank; 7];
+    wind_buf[0] = "W".as_bytes()[0];
+    match data.wind {
+        Some((speed, dir)) => {
+            format_int(&mut wind_buf[1..3], speed as u16);
+            format_int(&mut wind_buf[4..7], dir.to_degrees() as u16);
+        }
+        None => wind_buf[1..3].clone_from_slice("--".as_bytes()),
+    }
+    add_to_write_buf::<{ 7 + METADATA_SIZE_WRITE_PACKET }>(buf, 14, 10, &wind_buf, &mut i);
+
+    // Throttle limit, next to wind, if one is in effect.
+    if let Some(limit) = data.throttle_limit {
+        let mut limit_buf = [blank; 4];
+        limit_buf[0] = "L".as_bytes()[0];
+        format_int(&mut limit_buf[1..4], (limit * 100.) as u16);
+        add_to_write_buf::<{ 4 + METADATA_SIZE_WRITE_PACKET }>(buf, 14, 18, &limit_buf, &mut i);
+    }
+
     // Total acceleration (G force) display
     let mut g_buf = [blank; 4];
     let g = (data.total_acc * 10. / 9.8) as u16;
@@ -478,9 +612,29 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
     g_buf[3] = "G".as_bytes()[0];
     add_to_write_buf::<{ 4 + METADATA_SIZE_WRITE_PACKET }>(buf, 13, 0, &g_buf, &mut i);
 
+    // Last known position from before power-up, eg to find a crashed aircraft.
+    if let Some((lat, lon)) = data.saved_posit {
+        let mut posit_buf = [blank; 21];
+        format_coord((&mut posit_buf[0..10]).try_into().unwrap(), lat);
+        format_coord((&mut posit_buf[11..21]).try_into().unwrap(), lon);
+        add_to_write_buf::<{ 21 + METADATA_SIZE_WRITE_PACKET }>(buf, 9, 4, &posit_buf, &mut i);
+    }
+
+    // In place of the arm status, mid-screen: We disarm while killed, so it would read DISARMED.
+    if data.killed {
+        add_to_write_buf::<{ 6 + METADATA_SIZE_WRITE_PACKET }>(
+            buf,
+            6,
+            12,
+            "KILLED".as_bytes(),
+            &mut i,
+        );
+    }
+
     // todo: Test these once you verify working on O3.
     #[cfg(feature = "quad")]
     match data.arm_status {
+        _ if data.killed => (),
         ArmStatus::Armed => {
             // add_to_write_buf::<{ 5 + METADATA_SIZE_WRITE_PACKET }>(buf, 7, 12, "ARMED".as_bytes(), &mut i);
         }
@@ -497,6 +651,7 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
 
     #[cfg(feature = "fixed-wing")]
     match data.arm_status {
+        _ if data.killed => (),
         ArmStatus::MotorsControlsArmed => {
             // add_to_write_buf::<{ 5 + METADATA_SIZE_WRITE_PACKET }>(buf, 7, 12, "ARMED".as_bytes(), &mut i);
         }
@@ -520,7 +675,25 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
         }
     }
 
-    // todo: Base dist/bearing
+    // Distance and bearing to home, below altitude.
+    let mut home_buf = [blank; 10];
+    home_buf[0] = "h".as_bytes()[0]; // todo: Find the correct icon in the font.
+    match data.base_dist_bearing {
+        Some((dist, bearing)) => {
+            format_int(&mut home_buf[1..5], dist as u16);
+            format_int(&mut home_buf[7..10], bearing.to_degrees() as u16);
+        }
+        None => home_buf[1..5].clone_from_slice("----".as_bytes()),
+    }
+    home_buf[5] = "M".as_bytes()[0];
+    add_to_write_buf::<{ 10 + METADATA_SIZE_WRITE_PACKET }>(buf, 8, 20, &home_buf, &mut i);
+
+    // Distance flown this flight, next to total acceleration.
+    let mut dist_buf = [blank; 7];
+    dist_buf[0] = "D".as_bytes()[0];
+    format_int(&mut dist_buf[1..6], data.distance_flown as u16);
+    dist_buf[6] = "M".as_bytes()[0];
+    add_to_write_buf::<{ 7 + METADATA_SIZE_WRITE_PACKET }>(buf, 13, 5, &dist_buf, &mut i);
 
     make_draw_packet().to_buf_v1(&mut buf[i..i + METADATA_SIZE_V1 + 1]);
     i += METADATA_SIZE_V1 + 1;
@@ -529,7 +702,7 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
         if I % 30 == 0 {
             // println!("OSD buf len: {:?}", i);
             // println!("Buf: {:x}", buf);
-            // println!("Alt {}", data.alt_msl_baro );
+            // println!("Alt {}", data.alt_msl );
         }
     }
 
@@ -537,7 +710,7 @@ pub fn send_osd_data(uart: &mut UartOsd, data: &OsdData) {
         uart.write_dma(
             buf,
             setup::OSD_TX_CH,
-            Default::default(),
+            setup::dma_cfg(DmaTransfer::OsdTx),
             setup::OSD_DMA_PERIPH,
         )
     };
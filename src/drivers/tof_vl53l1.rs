@@ -24,6 +24,7 @@
 
 use core::f32::consts::TAU;
 
+use ahrs::UP;
 // use cmsis_dsp_sys::{arm_cos_f32 as cos, arm_sqrt_f32}; // todo: sqrt missing?
 use cmsis_dsp_sys::arm_cos_f32;
 use hal::{
@@ -36,6 +37,8 @@ pub enum TofError {
     NotConnected,
     BankThreshExceeded,
     DistThreshExceeded,
+    /// The sensor flagged the reading as invalid; eg weak signal, or wrap-around.
+    RangeStatus(u8),
 }
 
 impl From<i2c::Error> for TofError {
@@ -65,60 +68,141 @@ fn cos(val: f32) -> f32 {
     unsafe { arm_cos_f32(val) }
 }
 
-pub const ADDR: u8 = 0x52;
+// 7-bit address. ST's docs list this as 0x52, which is the 8-bit form.
+pub const ADDR: u8 = 0x29;
 
-// todo: Make sure to compensate for A/C angle.
+pub const MODEL_ID: u16 = 0xeacc;
 
 // Outside these thresholds, ignore TOF data.
 const THRESH_DIST: f32 = 12.; // meters. IOC VL53L1CB specs, and extended
 const THRESH_ANGLE: f32 = 0.03 * TAU; // radians, from level, in any direction.
-const READING_QUAL_THRESH: f32 = 0.7;
 
-pub fn setup(i2c: &mut I2c<I2C1>) -> Result<(), TofError> {
-    // todo
+// MCPS. Matches the sensor's default signal threshold; readings below it are mostly noise.
+const MIN_SIGNAL_RATE: f32 = 1.;
 
-    return Err(TofError::NotConnected); // todo: Until we finish work on it
+// Long distance mode, with a timing budget and period that give about 30Hz.
+const DISTANCE_MODE_LONG: u16 = 2;
+const TIMING_BUDGET: u16 = 33; // ms
+const INTER_MEASUREMENT_PERIOD: u32 = 33; // ms
 
-    Ok(())
-}
+// Boot takes ~1.2ms. Each poll is an I2C read, so this is on the order of 100ms.
+const BOOT_POLLS: u32 = 1_000;
+
+/// We read this many bytes, starting at `RESULT__RANGE_STATUS`, to get a complete reading.
+pub const READ_BUF_SIZE: usize = 17;
+
+/// Register address to start the DMA read from; 16-bit, big endian.
+pub const RESULT_REG: [u8; 2] = [
+    (VL53L1_RESULT__RANGE_STATUS >> 8) as u8,
+    VL53L1_RESULT__RANGE_STATUS as u8,
+];
+
+/// Write this after reading a result, to let the sensor start the next measurement:
+/// `SYSTEM__INTERRUPT_CLEAR`, 16-bit, big endian, then 1.
+pub const CLEAR_INT_BUF: [u8; 3] = [
+    (SYSTEM__INTERRUPT_CLEAR >> 8) as u8,
+    SYSTEM__INTERRUPT_CLEAR as u8,
+    0x01,
+];
 
-// todo: Rethink this once you learn the sensor's caps adn API
-struct Reading {
-    dist: f32,
-    quality: f32, // Scale of 0 to 1.
+/// Read the model ID register using the HAL. This should be `MODEL_ID`.
+pub fn read_model_id(i2c: &mut I2c<I2C1>) -> Result<u16, TofError> {
+    let mut buf = [0, 0];
+    i2c.write_read(
+        ADDR,
+        &[
+            (VL53L1_IDENTIFICATION__MODEL_ID >> 8) as u8,
+            VL53L1_IDENTIFICATION__MODEL_ID as u8,
+        ],
+        &mut buf,
+    )?;
+
+    Ok(u16::from_be_bytes(buf))
 }
 
-// todo: Don't use a blocking read. Use DMA etc.
-/// Read from the sensor. Result is in meters. Return `None` if the measured reading,
-/// or aircraft attitude is outside the maximum range we consider acceptable,
-pub fn read(attitude: Quaternion, i2c: &mut I2c<I2C1>) -> Result<f32, TofError> {
-    let down = Vec3::new(0., -1., 0.);
-    let down_ac = attitude.rotate_vec(down);
+pub fn setup(i2c: &mut I2c<I2C1>) -> Result<(), TofError> {
+    // Check that the sensor is present using the HAL, since the translated ST code below
+    // blocks indefinitely if nothing responds.
+    if read_model_id(i2c)? != MODEL_ID {
+        return Err(TofError::NotConnected);
+    }
 
-    // todo: Figure out how to to acos.
-    // let aircraft_angle_from_down = (down.dot(down_ac)).arccos();
-    let aircraft_angle_from_down = 0.; // todo temp; above should be right, but need acos.
+    let dev = ADDR as u16;
 
-    if aircraft_angle_from_down > THRESH_ANGLE {
-        return Err(TofError::BankThreshExceeded);
+    let mut booted = 0;
+    for _ in 0..BOOT_POLLS {
+        VL53L1X_BootState(dev, &mut booted);
+        if booted != 0 {
+            break;
+        }
     }
 
-    let mut result = [0];
-    // i2c.write_read(ADDR, &[READ_REG, 0], &mut result)?;
+    if booted == 0 {
+        return Err(TofError::NotConnected);
+    }
 
-    let reading = result[0];
+    let mut status = VL53L1X_SensorInit(dev);
+    status |= VL53L1X_SetDistanceMode(dev, DISTANCE_MODE_LONG);
+    status |= VL53L1X_SetTimingBudgetInMs(dev, TIMING_BUDGET);
+    status |= VL53L1X_SetInterMeasurementInMs(dev, INTER_MEASUREMENT_PERIOD);
+    status |= VL53L1X_StartRanging(dev);
 
-    // todo: What's the conversion from reg reads?
-    let reading = Reading {
-        dist: result[0] as f32,
-        quality: 1.,
-    };
+    if status != 0 {
+        return Err(TofError::NotConnected);
+    }
+
+    Ok(())
+}
+
+/// A ranging result, as reported by the sensor. This is slant range, along the sensor's axis.
+pub struct Reading {
+    /// Meters.
+    pub dist: f32,
+    /// 0 is valid. See ST UM2510, table 4 for others; eg 2 is weak signal, and 7 is wrap-around.
+    pub range_status: u8,
+    /// Return signal rate, in MCPS.
+    pub signal_rate: f32,
+}
+
+impl Reading {
+    /// Parse from a buffer read starting at `RESULT__RANGE_STATUS`. Mirrors `VL53L1X_GetResult`.
+    pub fn from_buf(buf: &[u8; READ_BUF_SIZE]) -> Self {
+        let status_raw = buf[0] & 0x1f;
+        let range_status = if status_raw < 24 {
+            status_rtn[status_raw as usize]
+        } else {
+            status_raw
+        };
+
+        Self {
+            dist: u16::from_be_bytes([buf[13], buf[14]]) as f32 / 1_000.,
+            range_status,
+            // 9.7 fixed point.
+            signal_rate: u16::from_be_bytes([buf[15], buf[16]]) as f32 / 128.,
+        }
+    }
+}
+
+/// Convert a reading to vertical height above ground, in meters, compensating for aircraft tilt.
+/// Returns an error if the reading is invalid, or the aircraft is tilted too far to trust it.
+pub fn agl_from_reading(reading: &Reading, attitude: Quaternion) -> Result<f32, TofError> {
+    if reading.range_status != 0 {
+        return Err(TofError::RangeStatus(reading.range_status));
+    }
 
-    if reading.dist > THRESH_DIST || reading.quality < READING_QUAL_THRESH {
+    if reading.dist > THRESH_DIST || reading.signal_rate < MIN_SIGNAL_RATE {
         return Err(TofError::DistThreshExceeded);
     }
 
-    Ok(reading.dist * cos(aircraft_angle_from_down))
+    // The sensor points along the aircraft's down axis. This is the cosine of the angle
+    // between it and earth down.
+    let cos_tilt = attitude.rotate_vec(UP).dot(UP);
+
+    if cos_tilt < cos(THRESH_ANGLE) {
+        return Err(TofError::BankThreshExceeded);
+    }
+
+    Ok(reading.dist * cos_tilt)
 }
 
 // todo: Consider if you want to move the ST lib to one or more separate files, or a module.
@@ -0,0 +1,167 @@
+//! This module contains an in-RAM event log: A fixed-size ring of timestamped events, such as
+//! arming, link loss, and sensor faults. Unlike `println!`, this is available without a debugger
+//! attached; the PC retrieves it over USB.
+//!
+//! `log` may be called from any priority; entries are written in a critical section. Timestamps
+//! are from `util::monotonic`, which doesn't need the tick timer locked.
+//!
+//! The log is RAM-only; it's cleared at power-up.
+
+use cortex_m::interrupt;
+
+use crate::util::monotonic;
+
+// Number of events stored. Once full, new events overwrite the oldest.
+pub const EVENT_LOG_LEN: usize = 64;
+
+// Timestamp (f32), code, and two payload words (u16).
+pub const EVENT_SIZE: usize = 4 + 1 + 2 + 2;
+
+static mut LOG: EventLog = EventLog {
+    events: [Event {
+        timestamp: 0.,
+        code: EventCode::ArmStatus,
+        a: 0,
+        b: 0,
+    }; EVENT_LOG_LEN],
+    next: 0,
+    count: 0,
+};
+
+/// Event types. Repr is how these are passed over USB; keep these values stable, since the PC
+/// application decodes them. Payload words are described for each; unused ones are 0.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum EventCode {
+    /// Armed or disarmed. a: The new `ArmStatus`, as its repr.
+    ArmStatus = 0,
+    /// Channel data stopped for the lost delay; link-lost recovery starts. a: 1 if the receiver
+    /// was still sending other packets.
+    LinkLost = 1,
+    /// Includes the first connection after power-up.
+    LinkUp = 2,
+    /// A sensor's data went stale. a: IMU 0, baro 1, GPS 2, mag 3, TOF 4; the order of
+    /// `StaleCounts`.
+    SensorStale = 3,
+    /// IMU data was implausible, or a register readback failed, and we re-initialized it.
+    ImuRecovery = 4,
+    /// The first RPM decoding fault since power-up.
+    RpmFault = 5,
+    /// a: Rotor index.
+    EscDesync = 6,
+    /// Autopilot modes changed. a: The new modes; see `AutopilotStatus::mode_flags`.
+    AutopilotModes = 7,
+    /// User config was saved. a: 1 on success, 0 on failure.
+    ConfigSave = 8,
+    /// The battery voltage collapsed; motors were stopped, and flash writes blocked. a: The
+    /// `ArmStatus` at the time, as its repr. b: Battery voltage x 100.
+    Brownout = 9,
+    /// Logged at power-up: The previous session ended in a brownout. a: The `ArmStatus` at the
+    /// time, as its repr.
+    PrevBrownout = 10,
+    /// The accelerometer's use in attitude fusion changed. a: The new `FusionMode`, as its repr.
+    /// b: Accelerometer norm at the time, relative to its length at rest, x 100.
+    FusionMode = 11,
+    /// A motor stopped in flight; we're in degraded control until disarm. a: Rotor index, in the
+    /// order of `MotorServoState::rotor_rpms`.
+    MotorFailure = 12,
+    /// We disarmed on the ground after tipping over, or a prop strike. a: The `TipoverCause`, as
+    /// its repr. b: Tilt in degrees, or the stalled rotor's index.
+    Tipover = 13,
+    /// A stick command ran. a: The `StickAction`, as its repr.
+    StickCommand = 14,
+    /// Logged at power-up: The previous session ended in a watchdog reset, eg from a lockup. See
+    /// `watchdog`.
+    WatchdogReset = 15,
+    /// The low-battery failsafe entered a stage. a: The `BattStage`, as its repr. b: Battery
+    /// voltage x 100.
+    BattFailsafe = 16,
+    /// Fixed-wing auto-launch detected a throw, or ended. a: The new `LaunchPhase`, as its repr.
+    /// b: Seconds since the throw x 10, when ending.
+    AutoLaunch = 17,
+    /// Channel data stopped for the hold delay; we're holding the last attitude and throttle
+    /// commanded. a: 1 if the receiver was still sending other packets.
+    LinkHold = 18,
+    /// The attitude estimate converged after power-up or a reset, diverged, or re-converged. a:
+    /// The new `AttConvergence`, as its repr. b: Mean gravity residual over the last second, in
+    /// mrad.
+    AttConvergence = 19,
+    /// External attitude control started, or ended. a: 1 if started. b: When ending, the cause: 0
+    /// for the switch, 1 for stale commands, and 2 for a control link dropout.
+    ExtControl = 20,
+    /// We switched IMUs. a: The new active `ImuId`, as its repr. b: 0 for gyro divergence, 1 for
+    /// the active IMU failing its integrity checks.
+    ImuFailover = 21,
+    /// The motor kill latched, or was released. a: 1 if latched. b: The `ArmStatus` at the time,
+    /// as its repr.
+    MotorKill = 22,
+    /// A motor failed the arm-time check; we disarmed. a: The `ArmCheckCause`, as its repr. b:
+    /// Rotor index, in the order of `MotorServoState::rotor_rpms`.
+    ArmCheck = 23,
+}
+
+#[derive(Clone, Copy)]
+pub struct Event {
+    /// Seconds since start.
+    pub timestamp: f32,
+    pub code: EventCode,
+    pub a: u16,
+    pub b: u16,
+}
+
+impl Event {
+    pub fn to_bytes(&self) -> [u8; EVENT_SIZE] {
+        let mut result = [0; EVENT_SIZE];
+
+        result[0..4].clone_from_slice(&self.timestamp.to_be_bytes());
+        result[4] = self.code as u8;
+        result[5..7].clone_from_slice(&self.a.to_be_bytes());
+        result[7..9].clone_from_slice(&self.b.to_be_bytes());
+        result
+    }
+}
+
+struct EventLog {
+    events: [Event; EVENT_LOG_LEN],
+    /// Index the next event is written to.
+    next: usize,
+    /// Number of events stored; saturates at `EVENT_LOG_LEN`.
+    count: usize,
+}
+
+/// Add an event. May be called from any ISR.
+pub fn log(code: EventCode, a: u16, b: u16) {
+    let event = Event {
+        timestamp: monotonic::now(),
+        code,
+        a,
+        b,
+    };
+
+    interrupt::free(|_| {
+        let log = unsafe { &mut *core::ptr::addr_of_mut!(LOG) };
+
+        log.events[log.next] = event;
+        log.next = (log.next + 1) % EVENT_LOG_LEN;
+        log.count = (log.count + 1).min(EVENT_LOG_LEN);
+    });
+}
+
+/// The number of events stored.
+pub fn count() -> usize {
+    interrupt::free(|_| unsafe { (*core::ptr::addr_of!(LOG)).count })
+}
+
+/// An event, by index; 0 is the oldest stored. `None` if out of range.
+pub fn get(i: usize) -> Option<Event> {
+    interrupt::free(|_| {
+        let log = unsafe { &*core::ptr::addr_of!(LOG) };
+
+        if i >= log.count {
+            return None;
+        }
+
+        let start = (log.next + EVENT_LOG_LEN - log.count) % EVENT_LOG_LEN;
+        Some(log.events[(start + i) % EVENT_LOG_LEN])
+    })
+}
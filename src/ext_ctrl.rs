@@ -0,0 +1,178 @@
+//! This module contains external attitude control: A companion computer, eg running vision-based
+//! navigation, sends a target attitude and throttle over USB, at up to the flight control rate.
+//! While the `ext_ctrl` switch is on, these replace the attitude and throttle commanded from the
+//! sticks. Everything downstream still runs on the FC: The attitude controller, arming, the link
+//! failsafe, the ceiling and geofence, and throttle and tilt limits.
+//!
+//! Commands older than the stale time fall back to the sticks, as does a control link dropout, so
+//! the pilot keeps authority. Each command is answered with feedback: The attitude estimate, and
+//! body rates, so the companion can close its loop. Entry and exit are logged in the event log,
+//! with the reason for exiting.
+
+use cortex_m::interrupt;
+use defmt::println;
+use lin_alg::f32::Quaternion;
+use num_traits::Float;
+
+use crate::{
+    event_log::{self, EventCode},
+    flight_ctrls::{common::AttitudeCommanded, ctrl_logic},
+};
+
+// Commands older than this, in s, aren't used.
+const STALE_TIME: f32 = 0.2;
+
+// Commanded quaternions whose norm differs from 1 by more than this are rejected, vice
+// normalized; the companion's output is likely garbage.
+const QUAT_NORM_TOLERANCE: f32 = 0.05;
+
+// The latest command received, if new since the main loop last took it.
+static mut CMD: Option<ExtAttCmd> = None;
+
+// Attitude quaternion (w, x, y, z), then throttle.
+pub const EXT_ATT_CMD_SIZE: usize = 4 * 4 + 4;
+// Whether external control is active, the attitude estimate (w, x, y, z), and pitch, roll, and
+// yaw rates, in rad/s.
+pub const EXT_FEEDBACK_SIZE: usize = 1 + 4 * 4 + 4 * 3;
+
+/// Why external control ended. Repr is how it's passed in the event log.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum ExitCause {
+    Switch = 0,
+    Stale = 1,
+    LinkDropout = 2,
+}
+
+/// A command from the companion computer.
+#[derive(Clone, Copy)]
+pub struct ExtAttCmd {
+    /// Earth-to-body, as `AttitudeCommanded::quat`.
+    pub quat: Quaternion,
+    /// 0. to 1.
+    pub throttle: f32,
+}
+
+impl ExtAttCmd {
+    /// Returns `None` if the quaternion isn't close to unit length, or throttle is out of range.
+    /// The quaternion is normalized.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let quat = Quaternion {
+            w: f(0),
+            x: f(4),
+            y: f(8),
+            z: f(12),
+        };
+        let throttle = f(16);
+
+        let norm = (quat.w.powi(2) + quat.x.powi(2) + quat.y.powi(2) + quat.z.powi(2)).sqrt();
+
+        // These comparisons also reject NaN.
+        if !((norm - 1.).abs() < QUAT_NORM_TOLERANCE) || !(0.0..=1.).contains(&throttle) {
+            return None;
+        }
+
+        Some(Self {
+            quat: quat.to_normalized(),
+            throttle,
+        })
+    }
+}
+
+/// Run from the USB ISR, on receiving a command.
+pub fn store_cmd(cmd: ExtAttCmd) {
+    interrupt::free(|_| unsafe { CMD = Some(cmd) });
+}
+
+/// The latest command, if one has arrived since the last call.
+fn take_cmd() -> Option<ExtAttCmd> {
+    interrupt::free(|_| unsafe { (*core::ptr::addr_of_mut!(CMD)).take() })
+}
+
+#[derive(Default)]
+pub struct ExtCtrl {
+    /// Commands replace the pilot's.
+    pub active: bool,
+    /// The latest command, while fresh.
+    cmd: Option<ExtAttCmd>,
+    /// When `cmd` was taken, in s.
+    cmd_time: f32,
+}
+
+impl ExtCtrl {
+    /// Run each flight control update, after the link failsafe update. `switch` is the
+    /// `ext_ctrl` switch's position, and `link_ok`, whether channel data is current.
+    pub fn update(&mut self, switch: bool, link_ok: bool, timestamp: f32) {
+        if let Some(cmd) = take_cmd() {
+            self.cmd = Some(cmd);
+            self.cmd_time = timestamp;
+        }
+
+        // Once stale, a command isn't used again; entering needs a new one.
+        if timestamp - self.cmd_time > STALE_TIME {
+            self.cmd = None;
+        }
+
+        let active = switch && link_ok && self.cmd.is_some();
+
+        if active == self.active {
+            return;
+        }
+
+        if active {
+            println!("External control active");
+            event_log::log(EventCode::ExtControl, 1, 0);
+        } else {
+            let cause = if !switch {
+                ExitCause::Switch
+            } else if !link_ok {
+                ExitCause::LinkDropout
+            } else {
+                ExitCause::Stale
+            };
+
+            println!("External control ended: {}", cause as u8);
+            event_log::log(EventCode::ExtControl, 0, cause as u16);
+        }
+
+        self.active = active;
+    }
+
+    /// Run each flight control update, after setting attitude commanded, and computing throttle
+    /// from the sticks, but before throttle limits. While active, replaces the attitude commanded,
+    /// and returns the throttle to use. `max_tilt` is the battery failsafe's tilt limit, if any.
+    pub fn apply(&self, cmd: &mut AttitudeCommanded, throttle: f32, max_tilt: Option<f32>) -> f32 {
+        let Some(ext) = self.cmd.filter(|_| self.active) else {
+            return throttle;
+        };
+
+        cmd.quat = match max_tilt {
+            Some(max_tilt) => ctrl_logic::limit_tilt(ext.quat, max_tilt),
+            None => ext.quat,
+        };
+        cmd.quat_dt = (0., 0., 0.);
+
+        ext.throttle
+    }
+
+    /// `rates` are pitch, roll, and yaw, in rad/s.
+    pub fn feedback_to_bytes(
+        &self,
+        attitude: Quaternion,
+        rates: (f32, f32, f32),
+    ) -> [u8; EXT_FEEDBACK_SIZE] {
+        let mut result = [0; EXT_FEEDBACK_SIZE];
+
+        result[0] = self.active as u8;
+        result[1..5].clone_from_slice(&attitude.w.to_be_bytes());
+        result[5..9].clone_from_slice(&attitude.x.to_be_bytes());
+        result[9..13].clone_from_slice(&attitude.y.to_be_bytes());
+        result[13..17].clone_from_slice(&attitude.z.to_be_bytes());
+        result[17..21].clone_from_slice(&rates.0.to_be_bytes());
+        result[21..25].clone_from_slice(&rates.1.to_be_bytes());
+        result[25..29].clone_from_slice(&rates.2.to_be_bytes());
+        result
+    }
+}
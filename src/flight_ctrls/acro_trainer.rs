@@ -0,0 +1,112 @@
+//! This module contains Acro Trainer: A middle ground between Acro and Attitude modes, for
+//! learning to fly rate mode. Sticks still command rates, but once tilt from level exceeds a
+//! limit, we add a pitch and roll rate command back toward it, proportional to the overshoot. This
+//! prevents accidentally going inverted. Yaw is unaffected.
+//!
+//! It applies in Acro only, while its switch is on, and after takeoff. The correction is added to
+//! stick rates for both control schemes: Before integrating the attitude commanded, and to the
+//! rate PID's setpoints. It's indicated on the OSD while correcting. Quad only.
+
+use ahrs::Params;
+
+use super::ctrl_logic;
+
+// Limits outside this range, in degrees, are rejected when loading config.
+const MAX_TILT_MIN: f32 = 15.;
+const MAX_TILT_MAX: f32 = 85.;
+// Gains above this, in radians/s per radian of overshoot, are rejected.
+const GAIN_MAX: f32 = 20.;
+
+// Serialized size: Tilt limit in degrees, and gain.
+pub const ACRO_TRAINER_CFG_SIZE: usize = 4 * 2;
+
+/// Acro Trainer settings. Stored in user config. It's toggled by the `acro_trainer` channel.
+#[derive(Clone, Copy)]
+pub struct AcroTrainerCfg {
+    /// Tilt from level, in radians, beyond which we correct.
+    pub max_tilt: f32,
+    /// Rate command added, in radians/s, per radian of tilt past `max_tilt`.
+    pub gain: f32,
+}
+
+impl Default for AcroTrainerCfg {
+    fn default() -> Self {
+        Self {
+            max_tilt: 60_f32.to_radians(),
+            gain: 5.,
+        }
+    }
+}
+
+impl AcroTrainerCfg {
+    /// Parse and validate, from degrees. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let max_tilt = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let gain = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(MAX_TILT_MIN..=MAX_TILT_MAX).contains(&max_tilt) || !(gain > 0. && gain <= GAIN_MAX)
+        {
+            return None;
+        }
+
+        Some(Self {
+            max_tilt: max_tilt.to_radians(),
+            gain,
+        })
+    }
+
+    /// Serialize, in degrees.
+    pub fn to_bytes(&self) -> [u8; ACRO_TRAINER_CFG_SIZE] {
+        let mut result = [0; ACRO_TRAINER_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.max_tilt.to_degrees().to_be_bytes());
+        result[4..8].clone_from_slice(&self.gain.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct AcroTrainer {
+    /// Displayed on the OSD.
+    pub active: bool,
+    /// Pitch and roll rates, in radians/s, to add to those commanded by the sticks. Uses the same
+    /// sign convention as stick rate commands.
+    pub correction: (f32, f32),
+}
+
+impl AcroTrainer {
+    /// Run each flight control update, prior to updating the attitude commanded. `enabled` is
+    /// the switch being on, in Acro.
+    pub fn update(
+        &mut self,
+        enabled: bool,
+        params: &Params,
+        has_taken_off: bool,
+        cfg: &AcroTrainerCfg,
+    ) {
+        if !enabled || !has_taken_off {
+            *self = Default::default();
+            return;
+        }
+
+        let (level, tilt) = ctrl_logic::decompose_tilt(params.attitude);
+
+        let overshoot = tilt - cfg.max_tilt;
+        self.active = overshoot > 0.;
+
+        if !self.active {
+            self.correction = (0., 0.);
+            return;
+        }
+
+        // The pitch and roll components of the rotation back to level, in the convention we use
+        // for rates commanded; see `cmd_updates::ang_v_from_attitudes`. Their magnitude is about
+        // the tilt, so we normalize by it.
+        let to_level = level / params.attitude;
+        let (pitch, roll, _) = to_level.to_axes();
+
+        let scale = cfg.gain * overshoot / tilt;
+        self.correction = (pitch * scale, roll * scale);
+    }
+}
@@ -0,0 +1,208 @@
+//! This module contains a synthetic airspeed estimate, for fixed-wing aircraft without a pitot tube.
+//! We combine GPS ground velocity with heading, and a wind estimate.
+//!
+//! The wind estimate is updated from orbits: Flying a full circle at a constant airspeed, ground
+//! velocity traces a circle centered on the wind vector. We bin ground velocity samples by course,
+//! and once every bin is filled, take the center of the bin means as that orbit's wind. This is
+//! blended slowly into the wind estimate, so one poorly-flown orbit doesn't corrupt it.
+
+use core::f32::consts::TAU;
+
+use num_traits::Float;
+
+use super::common::CtrlInputs;
+use crate::drivers::gps_ublox::GpsFix;
+
+// Number of course bins, evenly spaced around the circle.
+const NUM_COURSE_BINS: usize = 8;
+// Each bin needs this many samples before we use an orbit's samples to update the wind estimate.
+const MIN_SAMPLES_PER_BIN: u16 = 3;
+// Below this ground speed, in m/s, GPS course is too noisy to bin by.
+const MIN_GROUND_SPEED_FOR_WIND: f32 = 3.;
+
+// Minimum airspeeds above this, in m/s, are rejected.
+const MIN_AIRSPEED_MAX: f32 = 60.;
+
+// When below the minimum airspeed: radians of nose-down pitch, and throttle (0. to 1.) commanded,
+// per m/s of deficit.
+const PITCH_PER_AIRSPEED_DEFICIT: f32 = 0.05;
+const THROTTLE_PER_AIRSPEED_DEFICIT: f32 = 0.2;
+// We never pitch the nose lower than this to regain airspeed, in radians.
+const MAX_PITCH_DOWN_FOR_AIRSPEED: f32 = 0.35;
+
+// Serialized size: Minimum airspeed, and wind blend factor.
+pub const AIRSPEED_CFG_SIZE: usize = 4 * 2;
+
+/// Airspeed estimate configuration. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct AirspeedCfg {
+    /// m/s. The fixed-wing autopilot lowers pitch and adds throttle to stay above this.
+    pub min_airspeed: f32,
+    /// 0. to 1. The portion of each completed orbit's wind measurement blended into the estimate.
+    pub wind_blend: f32,
+}
+
+impl Default for AirspeedCfg {
+    fn default() -> Self {
+        Self {
+            min_airspeed: 12.,
+            wind_blend: 0.3,
+        }
+    }
+}
+
+impl AirspeedCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let min_airspeed = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let wind_blend = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(0. ..=MIN_AIRSPEED_MAX).contains(&min_airspeed)
+            || !(wind_blend > 0. && wind_blend <= 1.)
+        {
+            return None;
+        }
+
+        Some(Self {
+            min_airspeed,
+            wind_blend,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; AIRSPEED_CFG_SIZE] {
+        let mut result = [0; AIRSPEED_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.min_airspeed.to_be_bytes());
+        result[4..8].clone_from_slice(&self.wind_blend.to_be_bytes());
+        result
+    }
+}
+
+/// Ground velocity samples in one course bin. m/s.
+#[derive(Clone, Copy, Default)]
+struct CourseBin {
+    sum_north: f32,
+    sum_east: f32,
+    count: u16,
+}
+
+#[derive(Default)]
+pub struct AirspeedEst {
+    /// m/s. `None` if unknown, eg if GPS is lost.
+    pub airspeed: Option<f32>,
+    /// (north, east), in m/s; the direction the wind is blowing toward. `None` until we've
+    /// completed an orbit; airspeed is then ground speed along the heading.
+    pub wind: Option<(f32, f32)>,
+    bins: [CourseBin; NUM_COURSE_BINS],
+    /// The timestamp of the last fix used, so we only sample each fix once.
+    fix_timestamp: f32,
+}
+
+impl AirspeedEst {
+    /// Update the estimate. `heading` is in radians, clockwise from true north, as is GPS course.
+    /// Set `in_orbit` when flying an autopilot orbit, to update the wind estimate.
+    pub fn update(
+        &mut self,
+        fix: &GpsFix,
+        heading: f32,
+        gnss_usable: bool,
+        in_orbit: bool,
+        cfg: &AirspeedCfg,
+    ) {
+        if !gnss_usable {
+            // Don't mix samples from before and after the loss into one orbit.
+            self.airspeed = None;
+            self.reset_bins();
+            return;
+        }
+
+        let v_north = fix.ground_speed * fix.course.cos();
+        let v_east = fix.ground_speed * fix.course.sin();
+
+        if in_orbit {
+            if fix.timestamp != self.fix_timestamp {
+                self.add_sample(v_north, v_east, fix.course, cfg.wind_blend);
+            }
+        } else {
+            self.reset_bins();
+        }
+        self.fix_timestamp = fix.timestamp;
+
+        let (wind_north, wind_east) = self.wind.unwrap_or((0., 0.));
+
+        // Air velocity, projected onto the heading. This accounts for the difference between
+        // heading and course caused by wind.
+        let airspeed =
+            (v_north - wind_north) * heading.cos() + (v_east - wind_east) * heading.sin();
+
+        self.airspeed = Some(airspeed.max(0.));
+    }
+
+    fn add_sample(&mut self, v_north: f32, v_east: f32, course: f32, wind_blend: f32) {
+        if v_north.hypot(v_east) < MIN_GROUND_SPEED_FOR_WIND {
+            return;
+        }
+
+        let course = course % TAU;
+        let course = if course < 0. { course + TAU } else { course };
+
+        let i = (course / TAU * NUM_COURSE_BINS as f32) as usize % NUM_COURSE_BINS;
+        let bin = &mut self.bins[i];
+
+        bin.sum_north += v_north;
+        bin.sum_east += v_east;
+        bin.count = bin.count.saturating_add(1);
+
+        if self.bins.iter().any(|b| b.count < MIN_SAMPLES_PER_BIN) {
+            return;
+        }
+
+        // The bins are evenly spaced, so the mean of their means is the orbit circle's center.
+        let mut north = 0.;
+        let mut east = 0.;
+        for b in &self.bins {
+            north += b.sum_north / b.count as f32;
+            east += b.sum_east / b.count as f32;
+        }
+        north /= NUM_COURSE_BINS as f32;
+        east /= NUM_COURSE_BINS as f32;
+
+        self.wind = Some(match self.wind {
+            Some((n, e)) => (n + (north - n) * wind_blend, e + (east - e) * wind_blend),
+            None => (north, east),
+        });
+
+        self.reset_bins();
+    }
+
+    fn reset_bins(&mut self) {
+        self.bins = Default::default();
+    }
+}
+
+/// Used by the fixed-wing autopilot. If the estimated airspeed is below the minimum, lower the
+/// pitch commanded and raise throttle, in proportion to the deficit. Does nothing if airspeed is
+/// unknown.
+pub fn protect_min_airspeed(cmds: &mut CtrlInputs, airspeed: Option<f32>, cfg: &AirspeedCfg) {
+    let Some(airspeed) = airspeed else {
+        return;
+    };
+
+    let deficit = cfg.min_airspeed - airspeed;
+    if deficit <= 0. {
+        return;
+    }
+
+    let pitch = cmds
+        .pitch
+        .unwrap_or(0.)
+        .min(-deficit * PITCH_PER_AIRSPEED_DEFICIT);
+    cmds.pitch = Some(pitch.max(-MAX_PITCH_DOWN_FOR_AIRSPEED));
+
+    let throttle = cmds
+        .throttle
+        .unwrap_or(0.)
+        .max(deficit * THROTTLE_PER_AIRSPEED_DEFICIT);
+    cmds.throttle = Some(throttle.min(1.));
+}
@@ -0,0 +1,247 @@
+//! This module contains the arm-time motor check, against the "flip of death": With a motor on
+//! the wrong output, or spinning the wrong way, the flight controls' corrections drive the aircraft
+//! further over, and it flips the moment it leaves the ground. In the first 200ms after arming,
+//! with motors held at idle, we check that each motor reports a plausible idle RPM. We then raise
+//! each motor in turn by a small amount, and check that yaw rate responds with the sign the mixer
+//! expects. If a motor fails, we disarm, and log which one. Arming is blocked until the arm switch
+//! is cycled.
+//!
+//! On the ground, the response to a small step is weak; a response too small to tell passes, and
+//! only one clearly of the wrong sign fails. Throttle is held at idle until the check completes,
+//! which is well before takeoff can be detected; see `safety::TAKEOFF_POWER_TIME`.
+//!
+//! This relies on RPM telemetry, and is skipped if bidirectional DSHOT is disabled; if the ESCs
+//! don't report RPM, disable it in config. It's also skipped in 3D mode, where motors are stopped
+//! at idle throttle. Quad only.
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(feature = "quad")] {
+        use super::{
+            common::ArmLatch,
+            mixer::Mixer,
+            motor_servo::{MotorPower, RotationDir, MIN_ROTOR_RPM},
+            RotorPosition,
+        };
+        use crate::{imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS, safety};
+
+        // Seconds after arming before we check RPM; motors are spinning up.
+        const SETTLE_TIME: f32 = 0.05;
+        // Seconds after arming the idle portion ends. Each motor must have reported a plausible RPM
+        // since `SETTLE_TIME`.
+        const IDLE_TIME: f32 = 0.1;
+        // Seconds each motor is raised.
+        const STEP_TIME: f32 = 0.025;
+        const CHECK_TIME: f32 = IDLE_TIME + STEP_TIME * NUM_RPM_NOTCH_MOTORS as f32;
+
+        // Above this, an idle RPM reading is implausible, eg from a wrong pole count.
+        const IDLE_RPM_MAX: f32 = 30_000.;
+
+        // rad/s. A change in yaw rate over a step smaller than this is inconclusive.
+        const YAW_RESPONSE_MIN: f32 = 0.05;
+
+        const _: () = assert!(
+            CHECK_TIME < safety::TAKEOFF_POWER_TIME,
+            "The arm check must complete before takeoff can be detected"
+        );
+    }
+}
+
+// Step power outside this range, 0. to 1., is rejected when loading config.
+pub const STEP_POWER_MIN: f32 = 0.02;
+pub const STEP_POWER_MAX: f32 = 0.15;
+
+// Serialized size: Enabled, and step power.
+pub const ARM_CHECK_CFG_SIZE: usize = 1 + 4;
+
+/// Arm-time motor check settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct ArmCheckCfg {
+    /// Disable for setups without RPM telemetry; otherwise every arm fails the check.
+    pub enabled: bool,
+    /// Power added to each motor in turn, above idle, 0. to 1.
+    pub step_power: f32,
+}
+
+impl Default for ArmCheckCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            step_power: 0.05,
+        }
+    }
+}
+
+impl ArmCheckCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let step_power = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if buf[0] > 1 || !(STEP_POWER_MIN..=STEP_POWER_MAX).contains(&step_power) {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            step_power,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARM_CHECK_CFG_SIZE] {
+        let mut result = [0; ARM_CHECK_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1..5].clone_from_slice(&self.step_power.to_be_bytes());
+        result
+    }
+}
+
+/// Why a motor failed the check. Repr is the event log payload.
+#[cfg(feature = "quad")]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum ArmCheckCause {
+    /// No RPM reading, or an implausible one, at idle.
+    Rpm = 0,
+    /// Yaw rate responded to the motor's step with the wrong sign.
+    YawResponse = 1,
+}
+
+#[cfg(feature = "quad")]
+#[derive(Default)]
+pub struct ArmCheck {
+    /// Set when we disarm.
+    pub latch: ArmLatch,
+    /// Seconds since arming, while the check runs.
+    time: Option<f32>,
+    /// The check completed since arming; it runs again after a disarm.
+    done: bool,
+    /// Per motor, in the order of `MotorServoState::rotor_rpms`: A plausible idle RPM was
+    /// reported.
+    rpm_ok: [bool; NUM_RPM_NOTCH_MOTORS],
+    /// The motor being raised, and the yaw rate when its step started, in rad/s.
+    step: Option<(usize, f32)>,
+    /// From config; set each update.
+    step_power: f32,
+}
+
+#[cfg(feature = "quad")]
+impl ArmCheck {
+    /// True while the check controls motor power.
+    pub fn running(&self) -> bool {
+        self.time.is_some()
+    }
+
+    /// Run each flight control update, prior to the flight controls, with RPM readings in the
+    /// order of `MotorServoState::rotor_rpms`. Returns the cause, and the failed rotor's index,
+    /// if we should disarm.
+    pub fn update(
+        &mut self,
+        armed: bool,
+        reversible: bool,
+        v_yaw: f32,
+        rpms: &[Option<f32>; NUM_RPM_NOTCH_MOTORS],
+        mixer: &Mixer,
+        front_left_dir: RotationDir,
+        cfg: &ArmCheckCfg,
+        dt: f32,
+    ) -> Option<(ArmCheckCause, u16)> {
+        if !armed {
+            *self = Self {
+                latch: self.latch,
+                ..Default::default()
+            };
+            return None;
+        }
+
+        if !cfg.enabled || reversible || self.done {
+            self.time = None;
+            self.step = None;
+            return None;
+        }
+
+        let t = self.time.unwrap_or(0.) + dt;
+        self.time = Some(t);
+        self.step_power = cfg.step_power;
+
+        if t < IDLE_TIME {
+            if t >= SETTLE_TIME {
+                for (ok, rpm) in self.rpm_ok.iter_mut().zip(rpms) {
+                    if rpm.map_or(false, |r| (MIN_ROTOR_RPM..=IDLE_RPM_MAX).contains(&r)) {
+                        *ok = true;
+                    }
+                }
+            }
+            return None;
+        }
+
+        if self.step.is_none() {
+            if let Some(i) = self.rpm_ok.iter().position(|ok| !ok) {
+                return self.fail(ArmCheckCause::Rpm, i);
+            }
+        }
+
+        let i = ((t - IDLE_TIME) / STEP_TIME) as usize;
+
+        // The previous step ended; check its response. Positive yaw from the IMU is clockwise, as
+        // the mixer assumes.
+        if let Some((prev, rate_start)) = self.step {
+            if i != prev {
+                let expected = match front_left_dir {
+                    RotationDir::Clockwise => mixer.rows[prev].yaw,
+                    RotationDir::CounterClockwise => -mixer.rows[prev].yaw,
+                };
+                let response = v_yaw - rate_start;
+
+                if expected != 0.
+                    && response.abs() >= YAW_RESPONSE_MIN
+                    && response.signum() != expected.signum()
+                {
+                    return self.fail(ArmCheckCause::YawResponse, prev);
+                }
+            }
+        }
+
+        if i >= NUM_RPM_NOTCH_MOTORS {
+            self.time = None;
+            self.step = None;
+            self.done = true;
+            return None;
+        }
+
+        if self.step.map(|s| s.0) != Some(i) {
+            self.step = Some((i, v_yaw));
+        }
+
+        None
+    }
+
+    /// While the check runs, hold each motor at idle, and raise the one being stepped. Run after
+    /// all other power adjustments.
+    pub fn apply(&self, power: &mut MotorPower, idle: f32) {
+        if !self.running() {
+            return;
+        }
+
+        *power = MotorPower {
+            front_left: idle,
+            front_right: idle,
+            aft_left: idle,
+            aft_right: idle,
+        };
+
+        if let Some((i, _)) = self.step {
+            if let Ok(rotor) = RotorPosition::try_from(i as u8) {
+                power.set(rotor, idle + self.step_power);
+            }
+        }
+    }
+
+    fn fail(&mut self, cause: ArmCheckCause, rotor: usize) -> Option<(ArmCheckCause, u16)> {
+        *self = Default::default();
+        self.latch.trip();
+        Some((cause, rotor as u16))
+    }
+}
@@ -0,0 +1,252 @@
+//! This module contains control authority scaling: Gain scheduling from measured control
+//! effectiveness, so a given command produces a consistent response as battery voltage sags, or
+//! airspeed changes.
+//!
+//! Each axis's accel map slope is the control command needed per angular acceleration; see
+//! `ctrl_effect_est`. We also estimate each axis's angular drag coefficient, from the gap between
+//! the acceleration the map predicts for the command, and the one measured, per angular rate. The
+//! first time an axis's map is trustworthy in flight, its slope and drag are captured as the
+//! reference. After that, its scale is how much more command the axis needs now than at the
+//! reference, for the same response, limited to the configured range.
+//!
+//! Adaptation is frozen while the mix saturates, and, on quads with RPM telemetry, while any RPM
+//! reading is stale; the fit points are least representative then. Scales are estimated even if
+//! disabled in config, so they can be compared across flights.
+
+use num_traits::Float;
+
+use super::{
+    common::CtrlMix,
+    ctrl_effect_est::{AccelMap, AccelMaps},
+    ctrl_logic::DragCoeffs,
+};
+
+// Values outside this range are rejected when loading config.
+pub const MAX_ADAPT_MIN: f32 = 0.05;
+pub const MAX_ADAPT_MAX: f32 = 0.3;
+
+// Serialized size: Enabled, and the adaptation limit.
+pub const AUTHORITY_CFG_SIZE: usize = 1 + 4;
+// If the reference is captured, scale, and drag coefficient.
+const AXIS_STATUS_SIZE: usize = 1 + 4 + 4;
+// Enabled, and frozen, then pitch, roll, and yaw.
+pub const AUTHORITY_STATUS_SIZE: usize = 1 + 1 + AXIS_STATUS_SIZE * 3;
+
+// Drag is only estimated at angular rates above this, in rad/s; below, the ratio is mostly noise.
+const DRAG_RATE_MIN: f32 = 1.;
+// Drag coefficients are clamped to this, in 1/s.
+const DRAG_MAX: f32 = 20.;
+// Per sample. 0. to 1.; higher weights the newest sample more.
+const DRAG_SMOOTHING: f32 = 0.01;
+// Drag samples needed on an axis before capturing its reference.
+const DRAG_MIN_SAMPLES: u32 = 500;
+
+// Roughly the rate loop's response time, in s. More drag takes a larger share of the command
+// over this time; used to weight the drag coefficient against the map slope.
+const RESPONSE_TIME: f32 = 0.05;
+
+// Per sample. Scales move slowly, so they can't couple with the rate loop.
+const SCALE_SMOOTHING: f32 = 0.002;
+
+// Mix commands at or above this magnitude are treated as saturated.
+const SATURATION_THRESH: f32 = 0.95;
+
+/// Control authority scaling settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct AuthorityCfg {
+    /// Apply the scales. If disabled, they're still estimated, but not applied; eg for A/B
+    /// comparison flights.
+    pub enabled: bool,
+    /// Scales are limited to 1. plus or minus this.
+    pub max_adapt: f32,
+}
+
+impl Default for AuthorityCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_adapt: 0.2,
+        }
+    }
+}
+
+impl AuthorityCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let max_adapt = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+
+        // This comparison also rejects NaN.
+        if buf[0] > 1 || !(MAX_ADAPT_MIN..=MAX_ADAPT_MAX).contains(&max_adapt) {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            max_adapt,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; AUTHORITY_CFG_SIZE] {
+        let mut result = [0; AUTHORITY_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1..5].clone_from_slice(&self.max_adapt.to_be_bytes());
+        result
+    }
+}
+
+/// Scaling state for one axis.
+#[derive(Clone, Copy)]
+pub struct AxisAuthority {
+    /// Multiplies the axis's mix command, when enabled.
+    pub scale: f32,
+    /// Filtered angular drag coefficient, in 1/s.
+    pub drag: f32,
+    drag_samples: u32,
+    /// Map slope, and drag coefficient, captured once the map was first trustworthy in flight.
+    reference: Option<(f32, f32)>,
+}
+
+impl Default for AxisAuthority {
+    fn default() -> Self {
+        Self {
+            scale: 1.,
+            drag: 0.,
+            drag_samples: 0,
+            reference: None,
+        }
+    }
+}
+
+impl AxisAuthority {
+    /// `cmd` is the logged control command, eg RPM delta, `accel` the angular acceleration
+    /// measured, and `rate` the angular rate.
+    fn update(&mut self, map: &AccelMap, cmd: f32, accel: f32, rate: f32, max_adapt: f32) {
+        // Without a trustworthy map, its slope may be the defaults, and its predictions poor.
+        if !map.fit.trustworthy() || map.lin.abs() < f32::EPSILON {
+            return;
+        }
+
+        // We neglect the map's squared term; it's small in the region we fly in.
+        let accel_predicted = (cmd - map.constant) / map.lin;
+
+        // Drag opposes the rate: measured = predicted - drag * rate.
+        if rate.abs() > DRAG_RATE_MIN {
+            let drag = ((accel_predicted - accel) / rate).clamp(0., DRAG_MAX);
+
+            // This comparison also rejects NaN.
+            if drag.is_finite() {
+                self.drag += DRAG_SMOOTHING * (drag - self.drag);
+                self.drag_samples = self.drag_samples.saturating_add(1);
+            }
+        }
+
+        let Some((lin_ref, drag_ref)) = self.reference else {
+            if self.drag_samples >= DRAG_MIN_SAMPLES {
+                self.reference = Some((map.lin, self.drag));
+            }
+            return;
+        };
+
+        // A slope that changed sign points to a bad fit, not a change in the aircraft.
+        if map.lin.signum() != lin_ref.signum() {
+            return;
+        }
+
+        let target = (map.lin / lin_ref) * (1. + self.drag * RESPONSE_TIME)
+            / (1. + drag_ref * RESPONSE_TIME);
+        let target = target.clamp(1. - max_adapt, 1. + max_adapt);
+
+        self.scale += SCALE_SMOOTHING * (target - self.scale);
+    }
+
+    fn to_bytes(&self) -> [u8; AXIS_STATUS_SIZE] {
+        let mut result = [0; AXIS_STATUS_SIZE];
+
+        result[0] = self.reference.is_some() as u8;
+        result[1..5].clone_from_slice(&self.scale.to_be_bytes());
+        result[5..9].clone_from_slice(&self.drag.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct Authority {
+    pub pitch: AxisAuthority,
+    pub roll: AxisAuthority,
+    pub yaw: AxisAuthority,
+    /// Adaptation was frozen at the last update.
+    pub frozen: bool,
+}
+
+impl Authority {
+    /// Run each time accel map points are logged, after logging them. `cmds` are the pitch,
+    /// roll, and yaw commands logged. Sets the drag coefficients used by control logic.
+    pub fn update(
+        &mut self,
+        maps: &AccelMaps,
+        drag_coeffs: &mut DragCoeffs,
+        cmds: (f32, f32, f32),
+        accels: (f32, f32, f32),
+        rates: (f32, f32, f32),
+        ctrl_mix: &CtrlMix,
+        yaw_removed: f32,
+        cfg: &AuthorityCfg,
+    ) {
+        let mix = [
+            ctrl_mix.pitch,
+            ctrl_mix.roll,
+            ctrl_mix.yaw,
+            ctrl_mix.throttle,
+        ];
+        self.frozen = mix.iter().any(|v| v.abs() >= SATURATION_THRESH) || yaw_removed > 0.;
+
+        if self.frozen {
+            return;
+        }
+
+        let max = cfg.max_adapt;
+        self.pitch
+            .update(&maps.map_pitch, cmds.0, accels.0, rates.0, max);
+        self.roll
+            .update(&maps.map_roll, cmds.1, accels.1, rates.1, max);
+        self.yaw
+            .update(&maps.map_yaw, cmds.2, accels.2, rates.2, max);
+
+        drag_coeffs.pitch = self.pitch.drag;
+        drag_coeffs.roll = self.roll.drag;
+        drag_coeffs.yaw = self.yaw.drag;
+    }
+
+    /// Hold the scales; eg while RPM readings are stale.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Scale the mix's pitch, roll, and yaw commands. Run each flight control update, after
+    /// computing the mix.
+    pub fn apply(&self, ctrl_mix: &mut CtrlMix, cfg: &AuthorityCfg) {
+        if !cfg.enabled {
+            return;
+        }
+
+        ctrl_mix.pitch *= self.pitch.scale;
+        ctrl_mix.roll *= self.roll.scale;
+        ctrl_mix.yaw *= self.yaw.scale;
+        ctrl_mix.clamp();
+    }
+
+    pub fn to_bytes(&self, cfg: &AuthorityCfg) -> [u8; AUTHORITY_STATUS_SIZE] {
+        let mut result = [0; AUTHORITY_STATUS_SIZE];
+
+        result[0] = cfg.enabled as u8;
+        result[1] = self.frozen as u8;
+
+        for (i, axis) in [&self.pitch, &self.roll, &self.yaw].iter().enumerate() {
+            let start = 2 + i * AXIS_STATUS_SIZE;
+            result[start..start + AXIS_STATUS_SIZE].clone_from_slice(&axis.to_bytes());
+        }
+
+        result
+    }
+}
@@ -0,0 +1,367 @@
+//! This module contains auto-launch, for fixed-wing hand launches: With the arm switch at
+//! controls-armed, and auto-launch enabled, the pilot throws the aircraft with the motor stopped.
+//! We detect the throw from a forward acceleration spike, followed by sustained flying speed,
+//! then arm the motor, and ramp it to the launch throttle. We hold wings level, at the launch
+//! heading, and a climb pitch, until the duration or altitude gain is reached, or the pilot moves
+//! the pitch or roll stick; then control returns to the pilot, and the current input mode.
+//!
+//! The motor never spins before the throw is detected. After a launch completes, the motor stays
+//! armed until the arm switch leaves controls-armed. We abort, stopping the motor, if roll becomes
+//! excessive, or if we aren't climbing shortly after the throw. Launches, and aborts, are logged in
+//! the event log, and shown on the OSD. Fixed-wing only.
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(feature = "fixed-wing")] {
+        use ahrs::{Params, RIGHT, UP};
+        use defmt::println;
+        use lin_alg::f32::{Quaternion, Vec3};
+        use num_traits::Float;
+
+        use super::common::AttitudeCommanded;
+        use crate::{
+            controller_interface::ChannelData,
+            event_log::{self, EventCode},
+            safety::{self, ArmStatus, PrearmStatus},
+        };
+    }
+}
+
+// Throw detection. A spike in forward acceleration, in m/s^2, then flying speed, in m/s, held for
+// this time, within the window after the spike. Lower than takeoff detection in `safety`, since
+// the motor is stopped during the throw.
+#[cfg(feature = "fixed-wing")]
+const THROW_ACCEL_THRESH: f32 = 15.;
+#[cfg(feature = "fixed-wing")]
+const THROW_WINDOW: f32 = 0.5;
+#[cfg(feature = "fixed-wing")]
+const THROW_SPEED_THRESH: f32 = 5.;
+#[cfg(feature = "fixed-wing")]
+const THROW_SPEED_TIME: f32 = 0.1;
+
+// Seconds. After the throw, we wait this long before starting the motor, so the prop clears the
+// pilot's hand, then ramp it to the launch throttle over this time.
+#[cfg(feature = "fixed-wing")]
+const MOTOR_DELAY: f32 = 0.3;
+#[cfg(feature = "fixed-wing")]
+const MOTOR_RAMP_TIME: f32 = 0.5;
+
+// Abort if roll exceeds this, in radians, or if we haven't gained this altitude, in m, this long
+// after the throw.
+#[cfg(feature = "fixed-wing")]
+const ABORT_ROLL: f32 = 0.8;
+#[cfg(feature = "fixed-wing")]
+const CLIMB_CHECK_TIME: f32 = 2.;
+#[cfg(feature = "fixed-wing")]
+const CLIMB_MIN: f32 = 1.;
+
+// Pitch or roll stick past this, -1. to 1., hands control back to the pilot.
+#[cfg(feature = "fixed-wing")]
+const STICK_TAKEOVER_THRESH: f32 = 0.3;
+
+// Values outside these ranges are rejected when loading config.
+pub const THROTTLE_MIN: f32 = 0.3;
+pub const PITCH_MAX: f32 = 0.7;
+pub const DURATION_MIN: f32 = 1.;
+pub const DURATION_MAX: f32 = 20.;
+pub const ALT_GAIN_MAX: f32 = 200.;
+
+// Serialized size: Enabled, then throttle, pitch, duration, and altitude gain.
+pub const AUTO_LAUNCH_CFG_SIZE: usize = 1 + 4 * 4;
+
+/// Auto-launch settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct AutoLaunchCfg {
+    pub enabled: bool,
+    /// Motor power during the launch, 0. to 1.
+    pub throttle: f32,
+    /// Radians. Nose-up pitch held during the launch.
+    pub pitch: f32,
+    /// Seconds after the throw the launch ends.
+    pub duration: f32,
+    /// m. The launch ends early once we've climbed this much.
+    pub alt_gain: f32,
+}
+
+impl Default for AutoLaunchCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            throttle: 0.8,
+            pitch: 0.26,
+            duration: 5.,
+            alt_gain: 30.,
+        }
+    }
+}
+
+impl AutoLaunchCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let throttle = f(1);
+        let pitch = f(5);
+        let duration = f(9);
+        let alt_gain = f(13);
+
+        // These comparisons also reject NaN.
+        if buf[0] > 1
+            || !(THROTTLE_MIN..=1.).contains(&throttle)
+            || !(0.0..=PITCH_MAX).contains(&pitch)
+            || !(DURATION_MIN..=DURATION_MAX).contains(&duration)
+            || !(0.0..=ALT_GAIN_MAX).contains(&alt_gain)
+        {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            throttle,
+            pitch,
+            duration,
+            alt_gain,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; AUTO_LAUNCH_CFG_SIZE] {
+        let mut result = [0; AUTO_LAUNCH_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        for (i, v) in [self.throttle, self.pitch, self.duration, self.alt_gain]
+            .iter()
+            .enumerate()
+        {
+            result[1 + i * 4..5 + i * 4].clone_from_slice(&v.to_be_bytes());
+        }
+
+        result
+    }
+}
+
+/// Repr is the event log payload.
+#[cfg(feature = "fixed-wing")]
+#[derive(Clone, Copy, PartialEq, Default)]
+#[repr(u8)]
+pub enum LaunchPhase {
+    #[default]
+    Idle = 0,
+    /// Controls armed; waiting for the throw. The motor is stopped.
+    Waiting = 1,
+    /// Thrown; we're flying the climb-out.
+    Launching = 2,
+    /// Control has returned to the pilot.
+    Done = 3,
+    /// The launch was aborted, and the motor stopped.
+    Aborted = 4,
+}
+
+#[cfg(feature = "fixed-wing")]
+#[derive(Default)]
+pub struct AutoLaunch {
+    pub phase: LaunchPhase,
+    /// Seconds since the last forward acceleration spike, while within `THROW_WINDOW`.
+    since_spike: Option<f32>,
+    /// Seconds at flying speed since the spike.
+    time_at_speed: f32,
+    /// Seconds since the throw was detected.
+    elapsed: f32,
+    /// Radians. Held during the launch.
+    heading: f32,
+    /// m MSL, at the throw.
+    alt_start: f32,
+    /// We've been disarmed since the last launch. Controls-armed is only reached this way on the
+    /// ground; in flight, it follows disarming the motor.
+    disarmed: bool,
+}
+
+#[cfg(feature = "fixed-wing")]
+impl AutoLaunch {
+    /// Run before `safety::handle_arm_status`, on the arm status from the controller. While the
+    /// launch is running, or after it's completed, the motor stays armed with the switch at
+    /// controls-armed.
+    pub fn controller_arm_status(&self, controller_arm_status: ArmStatus) -> ArmStatus {
+        match self.phase {
+            LaunchPhase::Launching | LaunchPhase::Done
+                if controller_arm_status == ArmStatus::ControlsArmed =>
+            {
+                safety::MOTORS_ARMED
+            }
+            _ => controller_arm_status,
+        }
+    }
+
+    /// Run after `safety::handle_arm_status`. Detects the throw, arming the motor, and ends or
+    /// aborts the launch. `controller_arm_status` is the arm switch's, without our override.
+    /// `speed` is airspeed, or ground speed, in m/s; without it, we can't detect a throw. `alt` is
+    /// in m MSL. As when arming from the switch, the prearm switch, if configured, must be active
+    /// at the throw.
+    pub fn update(
+        &mut self,
+        arm_status: &mut ArmStatus,
+        has_taken_off: &mut bool,
+        controller_arm_status: ArmStatus,
+        prearm: PrearmStatus,
+        params: &Params,
+        speed: Option<f32>,
+        alt: f32,
+        cfg: &AutoLaunchCfg,
+        dt: f32,
+    ) {
+        if !cfg.enabled {
+            *self = Default::default();
+            return;
+        }
+
+        match self.phase {
+            LaunchPhase::Idle => {
+                if *arm_status == ArmStatus::Disarmed {
+                    self.disarmed = true;
+                } else if *arm_status == ArmStatus::ControlsArmed
+                    && self.disarmed
+                    && !*has_taken_off
+                {
+                    *self = Self {
+                        phase: LaunchPhase::Waiting,
+                        ..Default::default()
+                    };
+                }
+            }
+            LaunchPhase::Waiting => {
+                // Eg the pilot armed the motor, or disarmed.
+                if *arm_status != ArmStatus::ControlsArmed {
+                    *self = Default::default();
+                    return;
+                }
+
+                if self.throw_detected(params, speed, dt) && prearm != PrearmStatus::Inactive {
+                    println!("Throw detected; launching");
+                    event_log::log(EventCode::AutoLaunch, LaunchPhase::Launching as u16, 0);
+
+                    *arm_status = safety::MOTORS_ARMED;
+                    event_log::log(EventCode::ArmStatus, *arm_status as u16, 0);
+                    *has_taken_off = true;
+
+                    self.phase = LaunchPhase::Launching;
+                    self.elapsed = 0.;
+                    self.heading = params.attitude.to_axes().2;
+                    self.alt_start = alt;
+                }
+            }
+            LaunchPhase::Launching => {
+                // Eg the arm switch moved to disarmed.
+                if *arm_status != safety::MOTORS_ARMED {
+                    *self = Default::default();
+                    return;
+                }
+
+                self.elapsed += dt;
+                let climb = alt - self.alt_start;
+
+                let roll = params.attitude.to_euler().roll;
+                if roll.abs() > ABORT_ROLL
+                    || (self.elapsed >= CLIMB_CHECK_TIME && climb < CLIMB_MIN)
+                {
+                    println!("Launch aborted");
+                    self.end(LaunchPhase::Aborted);
+                } else if self.elapsed >= cfg.duration || climb >= cfg.alt_gain {
+                    println!("Launch complete");
+                    self.end(LaunchPhase::Done);
+                }
+            }
+            LaunchPhase::Done => {
+                if controller_arm_status != ArmStatus::ControlsArmed {
+                    *self = Default::default();
+                }
+            }
+            LaunchPhase::Aborted => {
+                // Require disarming before another launch.
+                if *arm_status == ArmStatus::Disarmed {
+                    *self = Default::default();
+                }
+            }
+        }
+    }
+
+    /// Run each time attitude and throttle commanded are updated, after they are, and before
+    /// stall protection. While waiting for the throw, the throttle commanded is 0. During the
+    /// launch, sets the attitude and throttle commanded, in place.
+    pub fn apply(
+        &mut self,
+        cmd: &mut AttitudeCommanded,
+        ch_data: &ChannelData,
+        cfg: &AutoLaunchCfg,
+    ) {
+        match self.phase {
+            LaunchPhase::Waiting => {
+                cmd.throttle = 0.;
+            }
+            LaunchPhase::Launching => {
+                if ch_data.pitch.abs() > STICK_TAKEOVER_THRESH
+                    || ch_data.roll.abs() > STICK_TAKEOVER_THRESH
+                {
+                    println!("Launch ended from stick input");
+                    self.end(LaunchPhase::Done);
+                    return;
+                }
+
+                let ramp = ((self.elapsed - MOTOR_DELAY) / MOTOR_RAMP_TIME).clamp(0., 1.);
+                cmd.throttle = cfg.throttle * ramp;
+
+                // Wings level, at the launch heading. Composed as in `update_att_commanded_att_mode`.
+                cmd.quat = Quaternion::from_axis_angle(UP, -self.heading)
+                    * Quaternion::from_axis_angle(RIGHT, -cfg.pitch);
+                cmd.quat_dt = (0., 0., 0.);
+            }
+            _ => (),
+        }
+    }
+
+    /// True if waiting for the throw. Displayed on the OSD.
+    pub fn ready(&self) -> bool {
+        self.phase == LaunchPhase::Waiting
+    }
+
+    /// True if the launch was aborted. Displayed on the OSD, until disarmed.
+    pub fn aborted(&self) -> bool {
+        self.phase == LaunchPhase::Aborted
+    }
+
+    fn throw_detected(&mut self, params: &Params, speed: Option<f32>, dt: f32) -> bool {
+        let fwd_accel = Vec3::new(params.a_x, params.a_y, params.a_z).dot(ahrs::FORWARD);
+        if fwd_accel >= THROW_ACCEL_THRESH {
+            self.since_spike = Some(0.);
+        }
+
+        let Some(t) = self.since_spike else {
+            return false;
+        };
+
+        match speed {
+            Some(s) if s >= THROW_SPEED_THRESH => self.time_at_speed += dt,
+            _ => self.time_at_speed = 0.,
+        }
+
+        if self.time_at_speed >= THROW_SPEED_TIME {
+            return true;
+        }
+
+        if t + dt > THROW_WINDOW {
+            self.since_spike = None;
+            self.time_at_speed = 0.;
+        } else {
+            self.since_spike = Some(t + dt);
+        }
+        false
+    }
+
+    fn end(&mut self, phase: LaunchPhase) {
+        event_log::log(
+            EventCode::AutoLaunch,
+            phase as u16,
+            (self.elapsed * 10.) as u16,
+        );
+        self.phase = phase;
+    }
+}
@@ -7,9 +7,11 @@ use cfg_if::cfg_if;
 use num_traits::float::Float;
 
 use crate::{
+    alt_estimator::AltEstimate,
     controller_interface::{AltHoldSwitch, AutopilotSwitchA, AutopilotSwitchB, ChannelData},
     flight_ctrls::common::{AltType, CtrlInputs},
-    system_status::{SensorStatus, SystemStatus},
+    geo::LatLon,
+    system_status::SystemStatus,
     util,
     // pid::{self, CtrlCoeffGroup, PidDerivFilters, PidGroup},
 };
@@ -26,8 +28,9 @@ use defmt::println;
 
 cfg_if! {
     if #[cfg(feature = "fixed-wing")] {
+        use crate::flight_ctrls::airspeed::{self, AirspeedCfg};
     } else {
-        use crate::flight_ctrls::takeoff_speed;
+        use crate::flight_ctrls::{follow_me::FollowPoint, takeoff_speed, wind_est::WindEst};
 
         // Minimium speed before auto-yaw will engage. (if we end up setting up auto-yaw to align flight path
         // with heading)
@@ -38,6 +41,28 @@ cfg_if! {
         // if coeff = 0.5, if accel is 1 m/s^2, yaw correction is 1/2 rad/s
         // angular velocity / accel: (radians/s) / (m/s^2) = radiants x s / m
         const YAW_ASSIST_COEFF: f32 = 0.1;
+
+        // During the takeoff ramp, we add throttle to the hover estimate in proportion to the
+        // target vertical velocity (m/s), up to a max.
+        const TAKEOFF_THROTTLE_PER_VV: f32 = 0.1;
+        const TAKEOFF_THROTTLE_MAX: f32 = 0.8;
+
+        // While landing, we correct throttle from the hover estimate per m/s of vertical velocity
+        // error, and integrate the error, per s, to take up error in the estimate.
+        const LAND_VV_P: f32 = 0.05;
+        const LAND_VV_I: f32 = 0.1;
+        // Landing throttle is held within these portions of the hover estimate.
+        const LAND_THROTTLE_MIN: f32 = 0.5;
+        const LAND_THROTTLE_MAX: f32 = 1.3;
+        // While landing, we've touched down once throttle is at its minimum, and vertical speed is
+        // below this, in m/s, for `TOUCHDOWN_TIME`, in s.
+        const TOUCHDOWN_VV: f32 = 0.2;
+        const TOUCHDOWN_TIME: f32 = 1.;
+
+        // Loiter position controller. Tilt commanded per m of position error, and per m/s of
+        // velocity, in radians. Wind feed-forward tilt is added to this.
+        const LOITER_P: f32 = 0.05;
+        const LOITER_D: f32 = 0.1;
     }
 }
 
@@ -48,8 +73,6 @@ use lin_alg::f32::Vec3;
 
 use crate::flight_ctrls::motor_servo::MotorServoState;
 
-const R: f32 = 6_371_000.; // Earth's radius in meters. (ellipsoid?)
-
 // Highest bank to use in all autopilot modes.
 const MAX_BANK: f32 = TAU / 6.;
 
@@ -77,8 +100,6 @@ const ORBIT_START_LATERAL_TOLERANCE: f32 = 10.;
 pub const ORBIT_DEFAULT_RADIUS: f32 = 20.; // meters.
 pub const ORBIT_DEFAULT_GROUNDSPEED: f32 = 10.; // m/s
 
-const DEG_SCALE_1E8: f32 = 100_000_000.;
-
 fn cos(v: f32) -> f32 {
     unsafe { arm_cos_f32(v) }
 }
@@ -87,41 +108,39 @@ fn sin(v: f32) -> f32 {
     unsafe { arm_sin_f32(v) }
 }
 
-/// Calculate the great circle bearing to fly to arrive at a point, given the aircraft's current position.
-/// Params and output are in radians. Does not take into account turn radius.
-/// https://www.movable-type.co.uk/scripts/latlong.html
-/// θ = atan2( sin Δλ ⋅ cos φ2 , cos φ1 ⋅ sin φ2 − sin φ1 ⋅ cos φ2 ⋅ cos Δλ )
-fn find_bearing(target: (i64, i64), aircraft: (i64, i64)) -> f32 {
-    let tgt_lat = target.0 as f32 / DEG_SCALE_1E8;
-    let tgt_lon = target.1 as f32 / DEG_SCALE_1E8;
-    let ac_lat = aircraft.0 as f32 / DEG_SCALE_1E8;
-    let ac_lon = aircraft.1 as f32 / DEG_SCALE_1E8;
-
-    let y = sin(tgt_lon - tgt_lon) * cos(tgt_lat);
-    let x = cos(ac_lat) * sin(tgt_lat) - sin(ac_lat) * cos(tgt_lat) * cos(tgt_lon - ac_lon);
-    y.atan2(x) % TAU
+/// The aircraft's fused position.
+fn aircraft_posit(params: &Params) -> LatLon {
+    LatLon::new(params.posit_fused.lat_e8, params.posit_fused.lon_e8)
 }
 
-/// Calculate the distance between two points, in meters.
-/// Params are in radians. Uses the 'haversine' formula
-/// https://www.movable-type.co.uk/scripts/latlong.html
-/// a = sin²(Δφ/2) + cos φ1 ⋅ cos φ2 ⋅ sin²(Δλ/2)
-/// c = 2 ⋅ atan2( √a, √(1−a) )
-/// d = R ⋅ c
-#[allow(non_snake_case)]
-fn find_distance(target: (f32, f32), aircraft: (f32, f32)) -> f32 {
-    // todo: LatLon struct with named fields.
-
-    let φ1 = aircraft.0; // φ, λ in radians
-    let φ2 = target.0;
-    let Δφ = target.0 - aircraft.0;
-    let Δλ = target.1 - aircraft.1;
-
-    let a = sin(Δφ / 2.) * sin(Δφ / 2.) + cos(φ1) * cos(φ2) * sin(Δλ / 2.) * sin(Δλ / 2.);
-
-    let c = 2. * a.sqrt().atan2((1. - a).sqrt());
-
-    R * c
+/// Tilt to command to hold a point, and match a velocity: Pitch and roll, in radians. The
+/// velocity is North and East, in m/s; it's zero when loitering.
+#[cfg(feature = "quad")]
+fn posit_hold_tilt(
+    params: &Params,
+    pt: LatLon,
+    velocity: (f32, f32),
+    wind_est: &WindEst,
+) -> (f32, f32) {
+    let (err_n, err_e) = aircraft_posit(params).offset_ne(&pt);
+
+    // Earth-frame velocity; x is East, and y is North.
+    let tilt_n = LOITER_P * err_n + LOITER_D * (velocity.0 - params.v_y);
+    let tilt_e = LOITER_P * err_e + LOITER_D * (velocity.1 - params.v_x);
+
+    // Rotate into the body frame. Nose down moves forward, and right wing down moves
+    // right.
+    let heading = params.s_yaw_heading;
+    let (sin_h, cos_h) = (sin(heading), cos(heading));
+    let fwd = tilt_n * cos_h + tilt_e * sin_h;
+    let right = -tilt_n * sin_h + tilt_e * cos_h;
+
+    let (ff_pitch, ff_roll) = wind_est.tilt_ff(heading);
+
+    (
+        (ff_pitch - fwd).clamp(-MAX_BANK, MAX_BANK),
+        (ff_roll + right).clamp(-MAX_BANK, MAX_BANK),
+    )
 }
 
 #[cfg(feature = "fixed-wing")]
@@ -158,8 +177,7 @@ impl Default for OrbitDirection {
 /// move over time.
 pub struct Orbit {
     pub shape: OrbitShape,
-    pub center_lat: f32,   // radians
-    pub center_lon: f32,   // radians
+    pub center: LatLon,
     pub radius: f32,       // m
     pub ground_speed: f32, // m/s
     pub direction: OrbitDirection,
@@ -248,27 +266,89 @@ pub struct AutopilotStatus {
     #[cfg(feature = "fixed-wing")]
     /// Orbit over a point on the ground
     pub orbit: Option<Orbit>,
+    #[cfg(feature = "quad")]
+    /// Follow a moving target. Set by `FollowMe`.
+    pub follow: Option<FollowPoint>,
+    #[cfg(feature = "quad")]
+    /// While landing: The vertical velocity integrator, as throttle, and time at touchdown
+    /// conditions, in s.
+    land_i: f32,
+    #[cfg(feature = "quad")]
+    touchdown_time: f32,
 }
 
 // todo: Here or PID: If you set something like throttle to some or none via an AP mode etc,
 // todo make sure you set it back to none A/R.
 
 impl AutopilotStatus {
+    /// Active modes, as bit flags, for the event log. From the LSB: Alt hold, heading hold,
+    /// velocity vector, direct-to-point, sequence, terrain following, takeoff, land, recover,
+    /// loiter (quad) or orbit (fixed-wing), and follow-me (quad).
+    pub fn mode_flags(&self) -> u16 {
+        cfg_if! {
+            if #[cfg(feature = "quad")] {
+                let hold_pt = self.loiter.is_some();
+                let follow = self.follow.is_some();
+            } else {
+                let hold_pt = self.orbit.is_some();
+                let follow = false;
+            }
+        }
+
+        [
+            self.alt_hold.is_some(),
+            self.hdg_hold.is_some(),
+            self.velocity_vector.is_some(),
+            self.direct_to_point.is_some(),
+            self.sequence,
+            self.terrain_following.is_some(),
+            self.takeoff,
+            self.land.is_some(),
+            self.recover.is_some(),
+            hold_pt,
+            follow,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, on)| acc | ((*on as u16) << i))
+    }
+
+    /// Distance from the loiter point, in m. `None` if not loitering, or without a usable GNSS
+    /// position.
+    #[cfg(feature = "quad")]
+    pub fn loiter_error(&self, params: &Params, system_status: &SystemStatus) -> Option<f32> {
+        let pt = self.loiter.as_ref()?;
+        if !system_status.gnss_usable() {
+            return None;
+        }
+
+        Some(aircraft_posit(params).dist(&LatLon::from_posit(pt)))
+    }
+
     #[cfg(feature = "quad")]
-    /// The output `CtrlInputs` are in Euler angle attitudes.
+    /// The output `CtrlInputs` are in Euler angle attitudes. Returns `true` if we touched down
+    /// while landing; the caller disarms.
     pub fn apply(
-        &self,
+        &mut self,
         autopilot_commands: &mut CtrlInputs,
         params: &Params,
+        alt: &AltEstimate,
         // filters: &mut PidDerivFilters,
         // coeffs: &CtrlCoeffGroup,
         system_status: &SystemStatus,
-        throttle_prev: f32, // ie might be autopilot or ch data.
+        // From `HoverThrottleEst`. The starting point for throttle commands.
+        hover_throttle: f32,
+        wind_est: &WindEst,
         dt: f32,
-    ) {
+    ) -> bool {
         // We use if/else logic on these to indicate they're mutually-exlusive. Modes listed first
         // take precedent.
 
+        if self.land.is_none() {
+            self.land_i = 0.;
+            self.touchdown_time = 0.;
+        }
+
         // todo: sensors check for this fn, and for here and fixed.
         // todo sensor check for alt hold agl
 
@@ -279,31 +359,92 @@ impl AutopilotStatus {
         // If in acro or attitude mode, we can adjust the throttle setting to maintain a fixed altitude,
         // either MSL or AGL.
         if self.takeoff {
-            let to_speed = match params.alt_tof {
-                Some(alt) => alt,
-                None => params.alt_msl_baro, // todo temp?
-            };
+            let to_speed = alt.agl.unwrap_or(alt.msl); // todo temp?
 
             *autopilot_commands = CtrlInputs {
                 pitch: Some(0.),
                 roll: Some(0.),
                 yaw: None,
-                throttle: Some(takeoff_speed(to_speed, MAX_VER_SPEED)),
+                throttle: Some(
+                    (hover_throttle
+                        + TAKEOFF_THROTTLE_PER_VV * takeoff_speed(to_speed, MAX_VER_SPEED))
+                    .min(TAKEOFF_THROTTLE_MAX),
+                ),
             };
         } else if let Some(ldg_cfg) = &self.land {
-            if system_status.gnss_can == SensorStatus::Pass {}
+            // Hold position over the touchdown point if we can; otherwise, descend level, and
+            // let the pilot correct drift.
+            let (pitch, roll) = if system_status.gnss_usable() {
+                posit_hold_tilt(
+                    params,
+                    LatLon::from_posit(&ldg_cfg.touchdown_point),
+                    (0., 0.),
+                    wind_est,
+                )
+            } else {
+                (0., 0.)
+            };
+
+            // Close the loop on vertical velocity. On the ground, the error holds negative, so
+            // throttle winds down to its minimum.
+            let vv_err = -ldg_cfg.descent_speed - params.v_z_baro;
+            let throttle_min = hover_throttle * LAND_THROTTLE_MIN;
+            let throttle_max = hover_throttle * LAND_THROTTLE_MAX;
+
+            let throttle_unclamped = hover_throttle + LAND_VV_P * vv_err + self.land_i;
+            let throttle = throttle_unclamped.clamp(throttle_min, throttle_max);
+
+            // Don't wind up past the limits.
+            if (throttle_unclamped > throttle_min || vv_err > 0.)
+                && (throttle_unclamped < throttle_max || vv_err < 0.)
+            {
+                self.land_i += LAND_VV_I * vv_err * dt;
+            }
+
+            *autopilot_commands = CtrlInputs {
+                pitch: Some(pitch),
+                roll: Some(roll),
+                yaw: None,
+                throttle: Some(throttle),
+            };
+
+            if throttle <= throttle_min && params.v_z_baro.abs() < TOUCHDOWN_VV {
+                self.touchdown_time += dt;
+            } else {
+                self.touchdown_time = 0.;
+            }
+
+            if self.touchdown_time >= TOUCHDOWN_TIME {
+                println!("Touchdown detected");
+                self.land = None;
+                return true;
+            }
         } else if let Some(pt) = &self.direct_to_point {
-            if system_status.gnss_can == SensorStatus::Pass {
-                let target_heading = find_bearing(
-                    (params.posit_fused.lat_e8, params.posit_fused.lon_e8),
-                    (pt.lat_e8, pt.lon_e8),
-                );
+            if system_status.gnss_usable() {
+                let target_heading = aircraft_posit(params).bearing(&LatLon::from_posit(pt));
 
                 autopilot_commands.yaw = Some(target_heading);
             }
+        } else if let Some(pt) = &self.follow {
+            if system_status.gnss_usable() {
+                let (pitch, roll) = posit_hold_tilt(params, pt.posit, pt.velocity, wind_est);
+
+                autopilot_commands.pitch = Some(pitch);
+                autopilot_commands.roll = Some(roll);
+            } else {
+                autopilot_commands.pitch = None;
+                autopilot_commands.roll = None;
+            }
         } else if let Some(pt) = &self.loiter {
-            if system_status.gnss_can == SensorStatus::Pass {
-                // todo
+            if system_status.gnss_usable() {
+                let (pitch, roll) =
+                    posit_hold_tilt(params, LatLon::from_posit(pt), (0., 0.), wind_est);
+
+                autopilot_commands.pitch = Some(pitch);
+                autopilot_commands.roll = Some(roll);
+            } else {
+                autopilot_commands.pitch = None;
+                autopilot_commands.roll = None;
             }
         }
 
@@ -315,6 +456,11 @@ impl AutopilotStatus {
             autopilot_commands.throttle = None;
         }
 
+        if !self.takeoff && self.land.is_none() && self.loiter.is_none() && self.follow.is_none() {
+            autopilot_commands.pitch = None;
+            autopilot_commands.roll = None;
+        }
+
         // todo: (Hmm forgot, but it was something I need to add to this!)
 
         // todo: Take into account attitude! Probalby take angle between earth and AC up,
@@ -345,8 +491,11 @@ impl AutopilotStatus {
                     const ACCEPTABLE_THRESHOLD: f32 = 0.3; // meters.
 
                     let mut error_alt = match alt_type {
-                        AltType::Msl => alt_commanded - params.alt_msl_baro,
-                        AltType::Agl => 0., // todo tmep
+                        AltType::Msl => alt_commanded - alt.msl,
+                        AltType::Agl => alt.agl.map(|agl| alt_commanded - agl).unwrap_or(0.),
+                        AltType::Takeoff => {
+                            alt.above_takeoff.map(|a| alt_commanded - a).unwrap_or(0.)
+                        }
                     };
 
                     if error_alt.abs() < ACCEPTABLE_THRESHOLD {
@@ -364,7 +513,7 @@ impl AutopilotStatus {
 
                     autopilot_commands.throttle = {
                         let mut throttle_command =
-                            autopilot_commands.throttle.unwrap_or(throttle_prev)
+                            autopilot_commands.throttle.unwrap_or(hover_throttle)
                                 + vertical_velocity_correction;
 
                         // todo: Remove 0.5 limit eventually; it's there for safety currently.
@@ -433,6 +582,8 @@ impl AutopilotStatus {
         //         autopilot_commands.throttle = None;
         //     }
         // }
+
+        false
     }
 
     #[cfg(feature = "fixed-wing")]
@@ -440,10 +591,13 @@ impl AutopilotStatus {
         &self,
         autopilot_commands: &mut CtrlInputs,
         params: &Params,
+        alt: &AltEstimate,
         // pid_attitude: &mut PidGroup,
         // filters: &mut PidDerivFilters,
         // coeffs: &CtrlCoeffGroup,
         system_status: &SystemStatus,
+        airspeed_est: Option<f32>,
+        airspeed_cfg: &AirspeedCfg,
         dt: f32,
     ) {
         if self.takeoff {
@@ -454,11 +608,9 @@ impl AutopilotStatus {
                 throttle: Some(1.0), // full thrust.
             };
         } else if let Some(ldg_cfg) = &self.land {
-            if system_status.gnss_can == SensorStatus::Pass {
-                let dist_to_touchdown = find_distance(
-                    (ldg_cfg.touchdown_point.lat, ldg_cfg.touchdown_point.lon),
-                    (params.lat, params.lon),
-                );
+            if system_status.gnss_usable() {
+                let dist_to_touchdown =
+                    aircraft_posit(params).dist(&LatLon::from_posit(&ldg_cfg.touchdown_point));
 
                 let heading_diff = 0.; // todo
 
@@ -467,15 +619,12 @@ impl AutopilotStatus {
             }
             // todo: DRY between quad and FC here, although the diff is power vs pitch.
         } else if let Some(orbit) = &self.orbit {
-            if system_status.gnss_can == SensorStatus::Pass {
+            if system_status.gnss_usable() {
                 // todo: You'll get a smoother entry if you initially calculate, and fly to a point on the radius
                 // todo on a heading similar to your own angle to it. For now, fly directly to the point for
                 // todo simpler logic and good-enough.
 
-                let dist_to_center = find_distance(
-                    (orbit.center_lat, orbit.center_lon),
-                    (params.lat, params.lon),
-                );
+                let dist_to_center = aircraft_posit(params).dist(&orbit.center);
 
                 let heading_diff = 0.; // todo
 
@@ -492,24 +641,20 @@ impl AutopilotStatus {
                 }
 
                 let target_heading = if established {
-                    find_bearing(
-                        (params.lat, params.lon),
-                        (orbit.center_lat, orbit.center_lon),
-                    )
+                    aircraft_posit(params).bearing(&orbit.center)
                 } else {
-                    find_bearing(
-                        (params.lat, params.lon),
-                        (orbit.center_lat, orbit.center_lon),
-                    )
+                    aircraft_posit(params).bearing(&orbit.center)
                 };
+
+                airspeed::protect_min_airspeed(autopilot_commands, airspeed_est, airspeed_cfg);
             }
         } else if let Some(pt) = &self.direct_to_point {
-            if system_status.gnss_can == SensorStatus::Pass {
-                let target_heading = find_bearing((params.lat, params.lon), (pt.lat, pt.lon));
+            if system_status.gnss_usable() {
+                let target = LatLon::from_posit(pt);
+                let target_heading = aircraft_posit(params).bearing(&target);
 
-                let target_pitch = ((pt.alt_msl - params.alt_msl_baro)
-                    / find_distance((pt.lat, pt.lon), (params.lat, params.lon)))
-                .atan();
+                let target_pitch =
+                    ((pt.elevation_msl - alt.msl) / aircraft_posit(params).dist(&target)).atan();
 
                 // todo: Crude algo here. Is this OK? Important distinction: Flight path does'nt mean
                 // todo exactly pitch! Might be close enough for good enough.
@@ -517,6 +662,8 @@ impl AutopilotStatus {
                 autopilot_commands.roll =
                     Some(((target_heading - params.s_yaw_heading) * roll_const).max(MAX_BANK));
                 autopilot_commands.pitch = Some(target_pitch);
+
+                airspeed::protect_min_airspeed(autopilot_commands, airspeed_est, airspeed_cfg);
             }
         }
 
@@ -527,11 +674,14 @@ impl AutopilotStatus {
         {
             let (alt_type, alt_commanded) = self.alt_hold.unwrap();
 
-            if !(alt_type == AltType::Agl && system_status.tof != SensorStatus::Pass) {
+            if !(alt_type == AltType::Agl && alt.agl.is_none())
+                && !(alt_type == AltType::Takeoff && alt.above_takeoff.is_none())
+            {
                 // Set a vertical velocity for the inner loop to maintain, based on distance
                 let dist = match alt_type {
-                    AltType::Msl => alt_commanded - params.alt_msl_baro,
-                    AltType::Agl => alt_commanded - params.alt_tof.unwrap_or(0.),
+                    AltType::Msl => alt_commanded - alt.msl,
+                    AltType::Agl => alt_commanded - alt.agl.unwrap_or(0.),
+                    AltType::Takeoff => alt_commanded - alt.above_takeoff.unwrap_or(0.),
                 };
 
                 // todo replacement for this and quad.
@@ -566,7 +716,12 @@ impl AutopilotStatus {
     }
 
     /// Set auto pilot modes based on control inputs.
-    pub fn set_modes_from_ctrls(&mut self, control_channel_data: &ChannelData, params: &Params) {
+    pub fn set_modes_from_ctrls(
+        &mut self,
+        control_channel_data: &ChannelData,
+        params: &Params,
+        alt: &AltEstimate,
+    ) {
         // match control_channel_data.alt_hold {
         //     AltHoldSwitch::Disabled => self.alt_hold = None,
         //     // If just setting this hold mode, use the current altitude. Otherwise, keep
@@ -610,8 +765,7 @@ impl AutopilotStatus {
                 self.orbit = Some(Orbit {
                     shape: Default::default(),
                     // todo qc these
-                    center_lat: params.posit_fused.lat_e8 as f32 / 10_000_000.,
-                    center_lon: params.posit_fused.lat_e8 as f32 / 10_000_000.,
+                    center: aircraft_posit(params),
                     radius: ORBIT_DEFAULT_RADIUS,
                     ground_speed: ORBIT_DEFAULT_GROUNDSPEED,
                     direction: Default::default(),
@@ -622,13 +776,11 @@ impl AutopilotStatus {
                 self.loiter = Some(PositVelEarthUnits {
                     lat_e8: params.posit_fused.lat_e8,
                     lon_e8: params.posit_fused.lon_e8,
-                    elevation_msl: params.alt_msl_baro,
+                    elevation_msl: alt.msl,
                     velocity: Vec3::new(params.v_x, params.v_y, params.v_z),
                 });
             }
-            AutopilotSwitchA::DirectToPoint => {
-                self.alt_hold = Some((AltType::Msl, params.alt_msl_baro))
-            }
+            AutopilotSwitchA::DirectToPoint => self.alt_hold = Some(alt.default_alt()),
         }
 
         match control_channel_data.autopilot_b {
@@ -5,16 +5,74 @@ use ahrs::{FORWARD, RIGHT, UP};
 use lin_alg::f32::Quaternion;
 use num_traits::Float;
 
-use super::common::InputMap;
-use crate::{
-    controller_interface::ChannelData,
-    main_loop::{ATT_CMD_UPDATE_RATIO, DT_FLIGHT_CTRLS, FLIGHT_CTRL_IMU_RATIO},
-};
+use super::{common::InputMap, ctrl_logic};
+use crate::{controller_interface::ChannelData, loop_rates, main_loop::ATT_CMD_UPDATE_RATIO};
 
 // todo: This DEADZONE is to prevent f32(?) drift. We probably need a better way.
 // todo: This works for now though, at least when the stick is idle.
 const ACRO_DEADZONE: f32 = 0.001;
 
+// Deadbands outside this range, in portion of stick deflection, are rejected.
+pub const DEADBAND_MAX: f32 = 0.5;
+// Return rates above this, in degrees/s, are rejected.
+const RETURN_RATE_MAX: f32 = 720.;
+
+// Serialized size: Enabled flag, deadband, and return rate in degrees/s.
+pub const ANGLE_ON_CENTER_CFG_SIZE: usize = 1 + 4 * 2;
+
+/// Configuration for Acro with auto-level on center ("angle on center"): Inside the deadband,
+/// the attitude commanded returns to level; outside it, sticks command rates, as in Acro.
+#[derive(Clone, Copy)]
+pub struct AngleOnCenterCfg {
+    /// If true, the Acro switch position selects this mode, vice plain Acro.
+    pub enabled: bool,
+    /// Pitch and roll stick deflection, 0. to 1., below which we return to level.
+    pub deadband: f32,
+    /// Rate at which the attitude commanded returns to level, in radians/s.
+    pub return_rate: f32,
+}
+
+impl Default for AngleOnCenterCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            deadband: 0.05,
+            return_rate: 90_f32.to_radians(),
+        }
+    }
+}
+
+impl AngleOnCenterCfg {
+    /// Parse and validate, from degrees/s. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let deadband = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+        let return_rate = f32::from_be_bytes(buf[5..9].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(0. ..=DEADBAND_MAX).contains(&deadband)
+            || !(return_rate > 0. && return_rate <= RETURN_RATE_MAX)
+        {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            deadband,
+            return_rate: return_rate.to_radians(),
+        })
+    }
+
+    /// Serialize, in degrees/s.
+    pub fn to_bytes(&self) -> [u8; ANGLE_ON_CENTER_CFG_SIZE] {
+        let mut result = [0; ANGLE_ON_CENTER_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1..5].clone_from_slice(&self.deadband.to_be_bytes());
+        result[5..9].clone_from_slice(&self.return_rate.to_degrees().to_be_bytes());
+        result
+    }
+}
+
 /// Modify our attitude commanded from rate-based user inputs. ctrl_crates are in radians/s, and `dt` is in s.
 fn modify_att_target(
     orientation: Quaternion,
@@ -55,7 +113,8 @@ fn ang_v_from_attitudes(att_prev: Quaternion, att_this: Quaternion, dt: f32) ->
 }
 
 /// Used in Acro mode. Based on control channel data, update attitude commanded, and attitude-rate
-/// commanded. Controls map to commanded angular velocity.
+/// commanded. Controls map to commanded angular velocity. `trainer_correction` is added to the
+/// pitch and roll rates; see `acro_trainer`.
 pub fn update_att_commanded_acro(
     ch_data: &ChannelData,
     input_map: &InputMap,
@@ -63,6 +122,7 @@ pub fn update_att_commanded_acro(
     current_att: Quaternion,
     has_taken_off: bool,
     takeoff_attitude: Quaternion,
+    trainer_correction: (f32, f32),
 ) -> (Quaternion, (f32, f32, f32)) {
     // If we haven't taken off, apply the attitude lock.
     if !has_taken_off {
@@ -76,22 +136,18 @@ pub fn update_att_commanded_acro(
         return (att, (0., 0., 0.));
     }
 
-    let pitch_cmd = -ch_data.pitch;
-    let roll_cmd = ch_data.roll;
-    let yaw_cmd = ch_data.yaw;
-
     // Negative on pitch, since we want pulling down (back) on the stick to raise
-    // the nose.
-    let pitch_rate_cmd = input_map.calc_pitch_rate(pitch_cmd);
-    let roll_rate_cmd = input_map.calc_roll_rate(roll_cmd);
-    let yaw_rate_cmd = input_map.calc_yaw_rate(yaw_cmd);
+    // the nose. We negate after mapping, since the calibrated center applies to raw inputs.
+    let pitch_rate_cmd = -input_map.calc_pitch_rate(ch_data.pitch) + trainer_correction.0;
+    let roll_rate_cmd = input_map.calc_roll_rate(ch_data.roll) + trainer_correction.1;
+    let yaw_rate_cmd = input_map.calc_yaw_rate(ch_data.yaw);
 
     // Don't update attitude commanded, or the change in attitude commanded
     // each loop. We don't get control commands that rapidly, and more importantly,
     // doing it every loop leads to numerical precision issues due to how small
     // the changes are.
 
-    let dt = DT_FLIGHT_CTRLS * ATT_CMD_UPDATE_RATIO as f32;
+    let dt = loop_rates::rates().dt_flight_ctrls * ATT_CMD_UPDATE_RATIO as f32;
 
     let att_commanded_current = modify_att_target(
         att_commanded_prev,
@@ -107,6 +163,82 @@ pub fn update_att_commanded_acro(
     )
 }
 
+/// Used in Acro with auto-level on center. When the pitch and roll sticks are inside the deadband,
+/// slew the attitude commanded toward level; yaw remains rate-based. Otherwise, sticks command
+/// rates as in Acro, but tilt from vertical is limited to `max_tilt`, in radians.
+pub fn update_att_commanded_acro_hybrid(
+    ch_data: &ChannelData,
+    input_map: &InputMap,
+    att_commanded_prev: Quaternion,
+    current_att: Quaternion,
+    has_taken_off: bool,
+    takeoff_attitude: Quaternion,
+    cfg: &AngleOnCenterCfg,
+    max_tilt: f32,
+) -> (Quaternion, (f32, f32, f32)) {
+    if !has_taken_off {
+        return update_att_commanded_acro(
+            ch_data,
+            input_map,
+            att_commanded_prev,
+            current_att,
+            has_taken_off,
+            takeoff_attitude,
+            (0., 0.),
+        );
+    }
+
+    let centered = input_map.cal.pitch.apply_centered(ch_data.pitch).abs() < cfg.deadband
+        && input_map.cal.roll.apply_centered(ch_data.roll).abs() < cfg.deadband;
+
+    let yaw_rate_cmd = input_map.calc_yaw_rate(ch_data.yaw);
+
+    let dt = loop_rates::rates().dt_flight_ctrls * ATT_CMD_UPDATE_RATIO as f32;
+
+    let att_commanded_current = if centered {
+        let leveled = ctrl_logic::slew_toward_level(att_commanded_prev, cfg.return_rate * dt);
+        modify_att_target(leveled, 0., 0., yaw_rate_cmd, dt)
+    } else {
+        // See the note on pitch in the Acro fn.
+        let mut pitch_rate_cmd = -input_map.calc_pitch_rate(ch_data.pitch);
+        let mut roll_rate_cmd = input_map.calc_roll_rate(ch_data.roll);
+
+        let mut att = modify_att_target(
+            att_commanded_prev,
+            pitch_rate_cmd,
+            roll_rate_cmd,
+            yaw_rate_cmd,
+            dt,
+        );
+
+        // Slow pitch and roll integration as we approach the limit, but only if the inputs tilt
+        // us further; moving back toward level is never slowed.
+        let (_, tilt_prev) = ctrl_logic::decompose_tilt(att_commanded_prev);
+        let (_, tilt) = ctrl_logic::decompose_tilt(att);
+
+        if tilt > tilt_prev {
+            let scale = ctrl_logic::tilt_rate_scale(tilt_prev, max_tilt);
+            pitch_rate_cmd *= scale;
+            roll_rate_cmd *= scale;
+
+            att = modify_att_target(
+                att_commanded_prev,
+                pitch_rate_cmd,
+                roll_rate_cmd,
+                yaw_rate_cmd,
+                dt,
+            );
+        }
+
+        ctrl_logic::limit_tilt(att, max_tilt)
+    };
+
+    (
+        att_commanded_current,
+        ang_v_from_attitudes(att_commanded_prev, att_commanded_current, dt),
+    )
+}
+
 /// Used in Attitude mode. Based on control channel data, update attitude commanded, and attitude-rate
 /// commanded. Controls map to attitude directly.
 pub fn update_att_commanded_att_mode(
@@ -130,20 +262,16 @@ pub fn update_att_commanded_att_mode(
         return (att, (0., 0., 0.));
     }
 
-    let pitch_cmd = -ch_data.pitch;
-    let roll_cmd = ch_data.roll;
-    let yaw_cmd = ch_data.yaw;
-
     // Negative on pitch, since we want pulling down (back) on the stick to raise
-    // the nose.
-    let pitch_att_cmd = input_map.calc_pitch_angle(pitch_cmd);
-    let roll_att_cmd = input_map.calc_roll_angle(roll_cmd);
-    let yaw_rate_cmd = input_map.calc_yaw_rate(yaw_cmd);
+    // the nose. We negate after mapping, since the calibrated center applies to raw inputs.
+    let pitch_att_cmd = -input_map.calc_pitch_angle(ch_data.pitch);
+    let roll_att_cmd = input_map.calc_roll_angle(ch_data.roll);
+    let yaw_rate_cmd = input_map.calc_yaw_rate(ch_data.yaw);
 
     let rotation_pitch = Quaternion::from_axis_angle(RIGHT, -pitch_att_cmd);
     let rotation_roll = Quaternion::from_axis_angle(FORWARD, -roll_att_cmd);
 
-    let dt = DT_FLIGHT_CTRLS * ATT_CMD_UPDATE_RATIO as f32;
+    let dt = loop_rates::rates().dt_flight_ctrls * ATT_CMD_UPDATE_RATIO as f32;
     let rotation_yaw = Quaternion::from_axis_angle(UP, yaw_rate_cmd * dt);
 
     // todo: Axis order, A/R. And, DRY from above.
@@ -166,7 +294,8 @@ pub fn update_alt_baro_commanded(
 
     let vv_cmd = input_map.calc_vv(ch_data_throttle, neutral_range);
 
-    let alt_commanded_current = alt_commanded_prev + vv_cmd * DT_FLIGHT_CTRLS;
+    let dt = loop_rates::rates().dt_flight_ctrls;
+    let alt_commanded_current = alt_commanded_prev + vv_cmd * dt;
 
     // todo: This thresh adds a bit of a pad. Consider how you want to handle this.
     if alt_commanded_current < -5. {
@@ -176,6 +305,6 @@ pub fn update_alt_baro_commanded(
 
     (
         alt_commanded_current,
-        (alt_commanded_current - alt_commanded_prev) / DT_FLIGHT_CTRLS,
+        (alt_commanded_current - alt_commanded_prev) / dt,
     )
 }
@@ -6,7 +6,10 @@ use core::f32::consts::TAU;
 use defmt::println;
 use lin_alg::f32::Quaternion;
 
-use crate::util::map_linear;
+#[cfg(feature = "quad")]
+use super::motor_servo::MotorRpm;
+use super::{input_cal::InputCal, rates::RateCurve};
+use crate::{imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS, util::map_linear};
 
 // Our input ranges for the 4 controls. rad/s
 const PITCH_IN_RNG: (f32, f32) = (-1., 1.);
@@ -18,16 +21,27 @@ const THROTTLE_IN_RNG: (f32, f32) = (0., 1.);
 const PITCH_IN_RNG_ATT: (f32, f32) = (-TAU / 4., TAU / 4.);
 const ROLL_IN_RNG_ATT: (f32, f32) = (-TAU / 4., TAU / 4.);
 
+// RPM filter cutoffs outside this range, in Hz, are rejected when loading config.
+const RPM_LPF_CUTOFF_MIN: f32 = 5.;
+const RPM_LPF_CUTOFF_MAX: f32 = 500.;
+// Seconds. If a motor has no RPM reading for this long, its filtered values are stale, and the
+// filter restarts from the next reading; differencing across a gap would be meaningless.
+const RPM_STALE_TIME: f32 = 0.02;
+
+// Serialized size: RPM cutoff, and rate-of-change cutoff.
+pub const RPM_LPF_CFG_SIZE: usize = 4 * 2;
+
 /// Maps manual control inputs (range 0. to 1. or -1. to 1.) to velocities, rotational velocities etc
 /// for various flight modes. The values are for full input range.
 /// Note that defaults are defined in the `quad` and `fixed-wing` modules.
 pub struct InputMap {
-    /// Pitch velocity commanded, (Eg Acro mode). radians/sec
-    pub pitch_rate: (f32, f32),
+    /// Pitch velocity commanded, (Eg Acro mode). radians/sec. Rate curves are Betaflight-style,
+    /// with center sensitivity, max rate, and expo.
+    pub pitch_rate: RateCurve,
     /// Pitch velocity commanded (Eg Acro mode)
-    pub roll_rate: (f32, f32),
+    pub roll_rate: RateCurve,
     /// Yaw velocity commanded (Eg Acro mode)
-    pub yaw_rate: (f32, f32),
+    pub yaw_rate: RateCurve,
     #[cfg(feature = "quad")]
     /// Throttle setting, clamped to leave room for maneuvering near the limits.
     pub throttle_clamped: (f32, f32),
@@ -44,41 +58,60 @@ pub struct InputMap {
     pub alt_commanded_agl: (f32, f32),
     ///  In m/s.; mapped to throttle settings.
     pub vertical_velocity: (f32, f32),
+    /// Calibrated stick ranges and centers. Applied to inputs before the mappings above.
+    pub cal: InputCal,
 }
 
 impl InputMap {
+    /// Rate curves for pitch, roll, and yaw.
+    pub fn rates(&self) -> [RateCurve; 3] {
+        [self.pitch_rate, self.roll_rate, self.yaw_rate]
+    }
+
     /// Convert from control inputs to radians/s.
     pub fn calc_pitch_rate(&self, input: f32) -> f32 {
-        map_linear(input, PITCH_IN_RNG, self.pitch_rate)
+        self.pitch_rate.eval(self.cal.pitch.apply_centered(input))
     }
 
     pub fn calc_roll_rate(&self, input: f32) -> f32 {
-        map_linear(input, ROLL_IN_RNG, self.roll_rate)
+        self.roll_rate.eval(self.cal.roll.apply_centered(input))
     }
 
     pub fn calc_yaw_rate(&self, input: f32) -> f32 {
-        map_linear(input, YAW_IN_RNG, self.yaw_rate)
+        self.yaw_rate.eval(self.cal.yaw.apply_centered(input))
     }
 
     #[cfg(feature = "quad")]
     pub fn calc_manual_throttle(&self, input: f32) -> f32 {
-        map_linear(input, THROTTLE_IN_RNG, self.throttle_clamped)
+        map_linear(
+            self.cal.throttle.apply(input),
+            THROTTLE_IN_RNG,
+            self.throttle_clamped,
+        )
     }
 
     #[cfg(feature = "quad")]
     pub fn calc_pitch_angle(&self, input: f32) -> f32 {
-        map_linear(input, PITCH_IN_RNG_ATT, self.pitch_angle)
+        map_linear(
+            self.cal.pitch.apply_centered(input),
+            PITCH_IN_RNG_ATT,
+            self.pitch_angle,
+        )
     }
 
     #[cfg(feature = "quad")]
     pub fn calc_roll_angle(&self, input: f32) -> f32 {
-        map_linear(input, ROLL_IN_RNG_ATT, self.roll_angle)
+        map_linear(
+            self.cal.roll.apply_centered(input),
+            ROLL_IN_RNG_ATT,
+            self.roll_angle,
+        )
     }
 
     #[cfg(feature = "quad")]
     pub fn calc_vv(&self, input: f32, neutral_range: f32) -> f32 {
         // Re-map from 0 to 1, to -1 to 1, to make calculations clearer.
-        let input_remapped = map_linear(input, THROTTLE_IN_RNG, (-1., 1.));
+        let input_remapped = map_linear(self.cal.throttle.apply(input), THROTTLE_IN_RNG, (-1., 1.));
 
         if input_remapped > neutral_range {
             // Climb
@@ -107,6 +140,8 @@ pub enum AltType {
     Agl = 0,
     /// Mean sea level (eg from GPS or baro)
     Msl = 1,
+    /// Above the ground reference captured on arming, or set manually
+    Takeoff = 2,
 }
 
 #[derive(Clone, Default)]
@@ -175,3 +210,159 @@ pub struct AttitudeCommanded {
                        // pub roll: Option<f32>,
                        // pub yaw: Option<f32>,
 }
+
+/// Lowpass cutoffs for conditioning RPM telemetry. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct RpmLpfCfg {
+    /// Hz. Applied to RPM readings.
+    pub cutoff: f32,
+    /// Hz. Applied to the RPM rate of change, after differencing filtered RPM.
+    pub rate_cutoff: f32,
+}
+
+impl Default for RpmLpfCfg {
+    fn default() -> Self {
+        Self {
+            cutoff: 150.,
+            rate_cutoff: 50.,
+        }
+    }
+}
+
+impl RpmLpfCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let cutoff = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let rate_cutoff = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        let range = RPM_LPF_CUTOFF_MIN..=RPM_LPF_CUTOFF_MAX;
+        if !range.contains(&cutoff) || !range.contains(&rate_cutoff) {
+            return None;
+        }
+
+        Some(Self {
+            cutoff,
+            rate_cutoff,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; RPM_LPF_CFG_SIZE] {
+        let mut result = [0; RPM_LPF_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.cutoff.to_be_bytes());
+        result[4..8].clone_from_slice(&self.rate_cutoff.to_be_bytes());
+        result
+    }
+}
+
+/// Filtered RPM, and its rate of change, for one motor.
+#[derive(Clone, Copy, Default)]
+pub struct RpmFiltered {
+    pub rpm: f32,
+    /// RPM per second.
+    pub rate: f32,
+    /// Seconds since start of the last reading used. `None` until the first.
+    pub timestamp: Option<f32>,
+}
+
+/// Conditions bidirectional DSHOT RPM telemetry for control use. Readings are quantized, from the
+/// period encoding, so differencing them directly gives a spiky rate of change. We lowpass RPM,
+/// difference the result, and lowpass that. Filters run per reading, using the time since the
+/// previous one, so gaps don't distort them.
+#[derive(Default)]
+pub struct RpmLpf {
+    /// In the order of `MotorServoState::rotor_rpms`.
+    pub motors: [RpmFiltered; NUM_RPM_NOTCH_MOTORS],
+}
+
+impl RpmLpf {
+    /// Run each time RPM readings are decoded, with readings in the order of
+    /// `MotorServoState::rotor_rpms`. Timestamp is in seconds.
+    pub fn update(
+        &mut self,
+        readings: &[Option<f32>; NUM_RPM_NOTCH_MOTORS],
+        cfg: &RpmLpfCfg,
+        timestamp: f32,
+    ) {
+        let tau = 1. / (TAU * cfg.cutoff);
+        let tau_rate = 1. / (TAU * cfg.rate_cutoff);
+
+        for (motor, reading) in self.motors.iter_mut().zip(readings) {
+            let Some(rpm) = *reading else {
+                continue;
+            };
+
+            let dt = match motor.timestamp {
+                Some(t) if timestamp - t <= RPM_STALE_TIME && timestamp > t => timestamp - t,
+                // The first reading, or the first after a gap.
+                _ => {
+                    *motor = RpmFiltered {
+                        rpm,
+                        rate: 0.,
+                        timestamp: Some(timestamp),
+                    };
+                    continue;
+                }
+            };
+
+            let rpm_filtered = motor.rpm + (rpm - motor.rpm) * dt / (tau + dt);
+            let rate = (rpm_filtered - motor.rpm) / dt;
+
+            motor.rate += (rate - motor.rate) * dt / (tau_rate + dt);
+            motor.rpm = rpm_filtered;
+            motor.timestamp = Some(timestamp);
+        }
+    }
+
+    /// Filtered values for each motor, or `None` for motors without a recent reading.
+    pub fn current(&self, timestamp: f32) -> [Option<RpmFiltered>; NUM_RPM_NOTCH_MOTORS] {
+        self.motors.map(|m| match m.timestamp {
+            Some(t) if timestamp - t <= RPM_STALE_TIME => Some(m),
+            _ => None,
+        })
+    }
+
+    /// Filtered RPM for each rotor, by position, or `None` if any is stale.
+    #[cfg(feature = "quad")]
+    pub fn motor_rpm(&self, timestamp: f32) -> Option<MotorRpm> {
+        let [front_left, front_right, aft_left, aft_right] = self.current(timestamp);
+
+        Some(MotorRpm {
+            front_left: front_left?.rpm,
+            front_right: front_right?.rpm,
+            aft_left: aft_left?.rpm,
+            aft_right: aft_right?.rpm,
+        })
+    }
+}
+
+/// Blocks arming after a ground protection disarms, eg tip-over protection, or the arm-time motor
+/// check, or after an autopilot landing, until the arm switch is cycled.
+#[cfg(feature = "quad")]
+#[derive(Clone, Copy, Default)]
+pub struct ArmLatch {
+    tripped: bool,
+}
+
+#[cfg(feature = "quad")]
+impl ArmLatch {
+    /// Run on disarming.
+    pub fn trip(&mut self) {
+        self.tripped = true;
+    }
+
+    /// True if we disarmed, and the arm switch hasn't been cycled since. Displayed on the OSD.
+    pub fn tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// Run prior to handling arm status, with the status the arm switch commands. Returns true
+    /// if arming is blocked. Cycling the switch to disarmed clears the block.
+    pub fn blocks_arming(&mut self, switch_armed: bool) -> bool {
+        if !switch_armed {
+            self.tripped = false;
+        }
+        self.tripped
+    }
+}
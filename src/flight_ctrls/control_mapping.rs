@@ -0,0 +1,356 @@
+//! This module contains the mapping of motor and servo functions to output pins, motor
+//! directions, and servo trim. It's stored in user config, edited from the PC application,
+//! and applied to `MotorServoState`.
+
+use super::motor_servo::{MotorServoHardware, MotorServoState, RotationDir};
+
+#[cfg(feature = "fixed-wing")]
+use super::{
+    motor_servo::{MotorState, ServoState},
+    surface_mix::{SurfaceMix, SURFACE_MIX_SIZE},
+};
+#[cfg(feature = "fixed-wing")]
+use crate::{protocols::servo::NUM_SURFACES, setup};
+
+// Serialized sizes. Outputs are a byte each, as `MotorServoHardware`; 0 means not present.
+// Flags are packed into a single byte.
+#[cfg(feature = "quad")]
+pub const CONTROL_MAPPING_SIZE: usize = 6; // 4 rotor outputs, reversed flags, and direction.
+
+#[cfg(feature = "fixed-wing")]
+// 2 thrust motor outputs, an output for each surface, reversed flags, trims for each surface, max
+// differential thrust, and surface mixing.
+pub const CONTROL_MAPPING_SIZE: usize =
+    2 + NUM_SURFACES + 1 + NUM_SURFACES * 4 + 4 + SURFACE_MIX_SIZE;
+
+/// Servo trim is limited to this portion of full scale, in either direction.
+#[cfg(feature = "fixed-wing")]
+pub const MAX_SERVO_TRIM: f32 = 0.25;
+
+/// Max differential thrust is limited to this portion of throttle.
+#[cfg(feature = "fixed-wing")]
+pub const DIFF_THRUST_LIMIT: f32 = 0.5;
+
+/// The result of setting a control mapping from the PC. Sent back over USB.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MappingStatus {
+    Success = 0,
+    /// Two functions are assigned to the same output.
+    DuplicateOutput = 1,
+    /// An output doesn't exist, or can't be used for this function. Eg a motor on a pin
+    /// without DSHOT.
+    InvalidOutput = 2,
+    /// An invalid direction, or out-of-range trim.
+    InvalidValue = 3,
+    /// Changing motor directions requires Preflight mode, since it blocks.
+    NotPreflight = 4,
+    /// Pitch or roll isn't driven by any control surface present. Fixed-wing only.
+    MissingSurface = 5,
+}
+
+/// Parse an output that must be present, and have DSHOT (pins 1 - 4).
+fn motor_output(val: u8) -> Result<MotorServoHardware, MappingStatus> {
+    match val {
+        1..=4 => MotorServoHardware::try_from(val).map_err(|_| MappingStatus::InvalidOutput),
+        _ => Err(MappingStatus::InvalidOutput),
+    }
+}
+
+#[cfg(feature = "fixed-wing")]
+/// Parse a servo output that may be absent (0). It must be on a servo timer channel.
+fn servo_output(val: u8) -> Result<Option<MotorServoHardware>, MappingStatus> {
+    match val {
+        0 => Ok(None),
+        _ => match MotorServoHardware::try_from(val) {
+            Ok(output) if setup::servo_channel(output).is_some() => Ok(Some(output)),
+            _ => Err(MappingStatus::InvalidOutput),
+        },
+    }
+}
+
+/// Returns `DuplicateOutput` if any output is used more than once.
+fn check_duplicates(outputs: &[Option<MotorServoHardware>]) -> Result<(), MappingStatus> {
+    for (i, a) in outputs.iter().enumerate() {
+        for b in &outputs[i + 1..] {
+            if let (Some(a), Some(b)) = (a, b) {
+                if *a as u8 == *b as u8 {
+                    return Err(MappingStatus::DuplicateOutput);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "quad")]
+#[derive(Clone, Copy)]
+pub struct ControlMapping {
+    pub front_left: MotorServoHardware,
+    pub front_right: MotorServoHardware,
+    pub aft_left: MotorServoHardware,
+    pub aft_right: MotorServoHardware,
+    /// Reversed in relation to 3-wire motor wiring. By position: FL, FR, AL, AR.
+    pub reversed: (bool, bool, bool, bool),
+    pub frontleft_aftright_dir: RotationDir,
+}
+
+#[cfg(feature = "quad")]
+impl Default for ControlMapping {
+    fn default() -> Self {
+        Self {
+            front_left: MotorServoHardware::Pin4,
+            front_right: MotorServoHardware::Pin2,
+            aft_left: MotorServoHardware::Pin3,
+            aft_right: MotorServoHardware::Pin1,
+            reversed: (false, false, false, false),
+            frontleft_aftright_dir: RotationDir::Clockwise,
+        }
+    }
+}
+
+#[cfg(feature = "quad")]
+impl ControlMapping {
+    /// Parse and validate.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MappingStatus> {
+        let result = Self {
+            front_left: motor_output(buf[0])?,
+            front_right: motor_output(buf[1])?,
+            aft_left: motor_output(buf[2])?,
+            aft_right: motor_output(buf[3])?,
+            reversed: (
+                buf[4] & 1 != 0,
+                buf[4] & 0b10 != 0,
+                buf[4] & 0b100 != 0,
+                buf[4] & 0b1000 != 0,
+            ),
+            frontleft_aftright_dir: RotationDir::try_from(buf[5])
+                .map_err(|_| MappingStatus::InvalidValue)?,
+        };
+
+        check_duplicates(&[
+            Some(result.front_left),
+            Some(result.front_right),
+            Some(result.aft_left),
+            Some(result.aft_right),
+        ])?;
+
+        Ok(result)
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONTROL_MAPPING_SIZE] {
+        [
+            self.front_left as u8,
+            self.front_right as u8,
+            self.aft_left as u8,
+            self.aft_right as u8,
+            self.reversed.0 as u8
+                | (self.reversed.1 as u8) << 1
+                | (self.reversed.2 as u8) << 2
+                | (self.reversed.3 as u8) << 3,
+            self.frontleft_aftright_dir as u8,
+        ]
+    }
+
+    /// Reversed flags by output: Motor 1 - 4. For use with `dshot::setup_motor_dir`.
+    pub fn motors_reversed(&self) -> (bool, bool, bool, bool) {
+        let mut result = [false; 4];
+
+        for (output, reversed) in [
+            (self.front_left, self.reversed.0),
+            (self.front_right, self.reversed.1),
+            (self.aft_left, self.reversed.2),
+            (self.aft_right, self.reversed.3),
+        ] {
+            result[output as usize - 1] = reversed;
+        }
+
+        (result[0], result[1], result[2], result[3])
+    }
+
+    /// Apply to motor state.
+    pub fn apply(&self, state: &mut MotorServoState) {
+        state.rotor_front_left_hardware = self.front_left;
+        state.rotor_front_right_hardware = self.front_right;
+        state.rotor_aft_left_hardware = self.aft_left;
+        state.rotor_aft_right_hardware = self.aft_right;
+
+        state.rotor_front_left.reversed = self.reversed.0;
+        state.rotor_front_right.reversed = self.reversed.1;
+        state.rotor_aft_left.reversed = self.reversed.2;
+        state.rotor_aft_right.reversed = self.reversed.3;
+
+        state.frontleft_aftright_dir = self.frontleft_aftright_dir;
+    }
+}
+
+#[cfg(feature = "fixed-wing")]
+#[derive(Clone, Copy)]
+pub struct ControlMapping {
+    pub motor_thrust1: MotorServoHardware,
+    pub motor_thrust2: Option<MotorServoHardware>,
+    /// Control surface servo outputs; `None` if not present. What each surface does depends on the
+    /// mix; see the `surface_mix` module.
+    pub surfaces: [Option<MotorServoHardware>; NUM_SURFACES],
+    /// Servo reversal, by surface.
+    pub reversed: [bool; NUM_SURFACES],
+    /// Added to servo commands; -1. to 1. scale. By surface.
+    pub trim: [f32; NUM_SURFACES],
+    /// With two thrust motors, thrust 1 is the left motor, and thrust 2 the right. Yaw commands
+    /// split throttle between them, by up to this portion of throttle.
+    pub diff_thrust_max: f32,
+    pub mix: SurfaceMix,
+}
+
+#[cfg(feature = "fixed-wing")]
+impl Default for ControlMapping {
+    /// Elevons, on the outputs used before surfaces were configurable.
+    fn default() -> Self {
+        Self {
+            motor_thrust1: MotorServoHardware::Pin1,
+            motor_thrust2: None,
+            surfaces: [
+                Some(MotorServoHardware::Pin3),
+                Some(MotorServoHardware::Pin2),
+                None,
+                None,
+            ],
+            reversed: [false; NUM_SURFACES],
+            trim: [0.; NUM_SURFACES],
+            diff_thrust_max: 0.3,
+            mix: Default::default(),
+        }
+    }
+}
+
+#[cfg(feature = "fixed-wing")]
+impl ControlMapping {
+    /// Parse and validate.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MappingStatus> {
+        let motor_thrust2 = match buf[1] {
+            0 => None,
+            v => Some(motor_output(v)?),
+        };
+
+        let mut surfaces = [None; NUM_SURFACES];
+        for (i, surface) in surfaces.iter_mut().enumerate() {
+            *surface = servo_output(buf[2 + i])?;
+        }
+
+        let i = 2 + NUM_SURFACES;
+        let mut reversed = [false; NUM_SURFACES];
+        for (j, r) in reversed.iter_mut().enumerate() {
+            *r = buf[i] & (1 << j) != 0;
+        }
+
+        let i = i + 1;
+        let mut trim = [0.; NUM_SURFACES];
+        for (j, t) in trim.iter_mut().enumerate() {
+            let start = i + j * 4;
+            *t = f32::from_be_bytes(buf[start..start + 4].try_into().unwrap());
+
+            // This comparison also rejects NaN.
+            if !(t.abs() <= MAX_SERVO_TRIM) {
+                return Err(MappingStatus::InvalidValue);
+            }
+        }
+
+        let i = i + NUM_SURFACES * 4;
+        let diff_thrust_max = f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        if !(0. ..=DIFF_THRUST_LIMIT).contains(&diff_thrust_max) {
+            return Err(MappingStatus::InvalidValue);
+        }
+
+        let i = i + 4;
+        let mix = SurfaceMix::from_bytes(&buf[i..i + SURFACE_MIX_SIZE])
+            .ok_or(MappingStatus::InvalidValue)?;
+
+        if !mix.controllable(&surfaces.map(|s| s.is_some())) {
+            return Err(MappingStatus::MissingSurface);
+        }
+
+        let result = Self {
+            motor_thrust1: motor_output(buf[0])?,
+            motor_thrust2,
+            surfaces,
+            reversed,
+            trim,
+            diff_thrust_max,
+            mix,
+        };
+
+        let mut outputs = [None; 2 + NUM_SURFACES];
+        outputs[0] = Some(result.motor_thrust1);
+        outputs[1] = result.motor_thrust2;
+        outputs[2..].clone_from_slice(&result.surfaces);
+
+        check_duplicates(&outputs)?;
+
+        Ok(result)
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONTROL_MAPPING_SIZE] {
+        let mut result = [0; CONTROL_MAPPING_SIZE];
+
+        result[0] = self.motor_thrust1 as u8;
+        result[1] = self.motor_thrust2.map(|o| o as u8).unwrap_or(0);
+
+        for (i, surface) in self.surfaces.iter().enumerate() {
+            result[2 + i] = surface.map(|o| o as u8).unwrap_or(0);
+        }
+
+        let i = 2 + NUM_SURFACES;
+        for (j, r) in self.reversed.iter().enumerate() {
+            result[i] |= (*r as u8) << j;
+        }
+
+        let i = i + 1;
+        for (j, t) in self.trim.iter().enumerate() {
+            let start = i + j * 4;
+            result[start..start + 4].clone_from_slice(&t.to_be_bytes());
+        }
+
+        let i = i + NUM_SURFACES * 4;
+        result[i..i + 4].clone_from_slice(&self.diff_thrust_max.to_be_bytes());
+
+        let i = i + 4;
+        result[i..i + SURFACE_MIX_SIZE].clone_from_slice(&self.mix.to_bytes());
+
+        result
+    }
+
+    /// Reversed flags by output: Motor 1 - 4. We don't currently reverse fixed-wing thrust
+    /// motors in software.
+    pub fn motors_reversed(&self) -> (bool, bool, bool, bool) {
+        (false, false, false, false)
+    }
+
+    /// Apply to motor and servo state. Output pin functions are set separately, with
+    /// `setup::set_output_pins`.
+    pub fn apply(&self, state: &mut MotorServoState) {
+        state.motor_thrust1_hardware = self.motor_thrust1;
+        state.motor_thrust2_hardware = self.motor_thrust2;
+        state.surface_hardware = self.surfaces;
+
+        // Keep motor and servo state in sync with the hardware assignments.
+        match self.motor_thrust2 {
+            Some(_) => {
+                if state.motor_thrust2.is_none() {
+                    state.motor_thrust2 = Some(MotorState::default());
+                }
+            }
+            None => state.motor_thrust2 = None,
+        }
+
+        for (i, surface) in state.surfaces.iter_mut().enumerate() {
+            *surface = self.surfaces[i].map(|_| ServoState {
+                reversed: self.reversed[i],
+                trim: self.trim[i],
+                ..surface.unwrap_or_default()
+            });
+        }
+
+        state.diff_thrust_max = self.diff_thrust_max;
+        state.surface_mix = self.mix;
+    }
+}
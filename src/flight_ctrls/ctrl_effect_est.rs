@@ -1,29 +1,61 @@
 //! Estimation of control effectiveness based on recorded data. Regularly generates a map of
 //! control commands (eg elevon deltas, rotor pair deltas etc) to angular accelerations per axis,
 //! and/or its inverse.
-
-use core::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! In flight, we log pairs of control command (RPM delta about an axis on quads; servo position
+//! delta on fixed-wing), and the angular acceleration measured. Each axis's map is fit to these
+//! with recursive least squares, with a forgetting factor so it tracks changes, eg a prop swap.
+//! Implausible points, and those far from the current fit, are rejected.
+//!
+//! Fitted maps are only used by control logic once the fit is trustworthy: Enough samples, with
+//! an acceptable R². Until then, we use the maps loaded from flash, or conservative defaults.
+//! Maps are saved to the flight stats flash page, after the health trend history, on each save
+//! after disarming; see `flight_stats`. Fit quality is readable over USB.
 
 use defmt::println;
+use hal::flash::{Bank, Flash};
 use num_traits::float::Float;
 
-use crate::util;
-
-static SAMPLE_PT_I: AtomicUsize = AtomicUsize::new(0);
-
 pub const NUM_SAMPLE_PTS: usize = 30;
 
-// This point of 0 command, 0 acceleration, is an anchor.
-const PT_0: AccelMapPt = AccelMapPt {
-    angular_accel: 0.,
-    ctrl_cmd: 0.,
-    timestamp: 0.,
-};
-
-// todo: Leaning towards this: Store a collection of pitch, roll, and yaw angular accels, as well
-// todo as corresponding servo settings (fixed) or motor RPMs (quad). Also store altitude and time-of-measurement.
-// todo: From this, create a model. Store readings and/or model in external flash periodically,
-// todo for init at next takeoff.
+// Angular accelerations are divided by this, in rad/s^2, before fitting, so the squared term is of
+// a similar magnitude to the others.
+const ACCEL_SCALE: f32 = 100.;
+
+// Per sample. Older samples are weighted less; this gives a memory of ~1,000 samples.
+const FORGETTING: f32 = 0.999;
+
+// Initial parameter covariance. Large, since the defaults are only a guess.
+const P_INIT: f32 = 10_000.;
+// For trustworthy maps loaded from flash.
+const P_LOADED: f32 = 10.;
+// We stop inflating the covariance past this trace; otherwise, it grows without bound while
+// commands don't vary, eg in a steady hover.
+const P_TRACE_MAX: f32 = 1_000_000.;
+
+// Points with angular acceleration beyond this, in rad/s^2, are rejected; eg from a crash.
+const ACCEL_MAX: f32 = 2_000.;
+// Once we have this many samples, we reject points with residuals beyond `OUTLIER_SIGMAS`.
+const OUTLIER_MIN_SAMPLES: u32 = 100;
+const OUTLIER_SIGMAS: f32 = 4.;
+
+// Control logic uses a fit once it has this many samples, and an R² of at least this.
+const TRUST_MIN_SAMPLES: u32 = 2_000;
+const TRUST_MIN_R_SQUARED: f32 = 0.5;
+
+// Marks a valid record on flash. Erased flash reads 0xff.
+const RECORD_MARKER: u8 = 0xa5;
+
+// Coefficients in use, fitted coefficients, samples, RMS residual, and R².
+const ACCEL_MAP_SIZE: usize = 4 * 3 + 4 * 3 + 4 + 4 + 4;
+// Marker, then pitch, roll, and yaw maps.
+pub const ACCEL_MAPS_SIZE: usize = 1 + ACCEL_MAP_SIZE * 3;
+
+// Coefficients in use, fitted coefficients, samples, rejected points, RMS residual, R², and if
+// trustworthy.
+const ACCEL_MAP_FIT_SIZE: usize = 4 * 3 + 4 * 3 + 4 + 4 + 4 + 4 + 1;
+// If loaded from flash, then pitch, roll, and yaw.
+pub const ACCEL_MAPS_FIT_SIZE: usize = 1 + ACCEL_MAP_FIT_SIZE * 3;
 
 // We use this approach instead of feature-gating an `AccelMap` struct, to make the
 // struct names here more explicit.
@@ -72,6 +104,143 @@ pub struct AccelMapPt {
     pub timestamp: f32,
 }
 
+fn dot(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn f32_at(buf: &[u8], i: usize) -> f32 {
+    f32::from_be_bytes(buf[i..i + 4].try_into().unwrap())
+}
+
+/// Recursive least-squares fit of control command to angular acceleration, with fit quality
+/// statistics.
+#[derive(Clone, Copy)]
+pub struct MapFit {
+    /// Squared, linear, and constant terms, for scaled acceleration.
+    theta: [f32; 3],
+    /// Parameter covariance.
+    p: [[f32; 3]; 3],
+    pub samples: u32,
+    pub rejected: u32,
+    /// Exponentially-weighted variance of the error of each point, after fitting it.
+    resid_var: f32,
+    cmd_mean: f32,
+    cmd_var: f32,
+}
+
+impl MapFit {
+    /// Start from a map's coefficients, with covariance `p` on each parameter.
+    fn new(square: f32, lin: f32, constant: f32, p: f32) -> Self {
+        Self {
+            theta: [square * ACCEL_SCALE.powi(2), lin * ACCEL_SCALE, constant],
+            p: [[p, 0., 0.], [0., p, 0.], [0., 0., p]],
+            samples: 0,
+            rejected: 0,
+            resid_var: 0.,
+            cmd_mean: 0.,
+            cmd_var: 0.,
+        }
+    }
+
+    /// Squared, linear, and constant terms, for acceleration in rad/s^2.
+    pub fn coeffs(&self) -> (f32, f32, f32) {
+        (
+            self.theta[0] / ACCEL_SCALE.powi(2),
+            self.theta[1] / ACCEL_SCALE,
+            self.theta[2],
+        )
+    }
+
+    /// RMS of the error of recent points; in control command units.
+    pub fn rms_resid(&self) -> f32 {
+        self.resid_var.sqrt()
+    }
+
+    /// The portion of recent variance in control command the fit explains; 0. to 1.
+    pub fn r_squared(&self) -> f32 {
+        if self.cmd_var <= 0. {
+            return 0.;
+        }
+        (1. - self.resid_var / self.cmd_var).clamp(0., 1.)
+    }
+
+    /// If control logic should use this fit.
+    pub fn trustworthy(&self) -> bool {
+        self.samples >= TRUST_MIN_SAMPLES && self.r_squared() >= TRUST_MIN_R_SQUARED
+    }
+
+    /// Fit a new point. Returns `false` if it was rejected.
+    fn update(&mut self, accel: f32, cmd: f32) -> bool {
+        // This comparison also rejects NaN.
+        if !(-ACCEL_MAX..=ACCEL_MAX).contains(&accel) || !cmd.is_finite() {
+            self.rejected = self.rejected.saturating_add(1);
+            return false;
+        }
+
+        let u = accel / ACCEL_SCALE;
+        let x = [u.powi(2), u, 1.];
+        let err = cmd - dot(&self.theta, &x);
+
+        let px = [
+            dot(&self.p[0], &x),
+            dot(&self.p[1], &x),
+            dot(&self.p[2], &x),
+        ];
+        let xpx = dot(&x, &px);
+
+        // The expected error grows with the fit's uncertainty at this point; eg early on, or at
+        // accelerations we've rarely seen.
+        if self.samples >= OUTLIER_MIN_SAMPLES
+            && self.resid_var > 0.
+            && err.powi(2) > OUTLIER_SIGMAS.powi(2) * self.resid_var * (1. + xpx)
+        {
+            self.rejected = self.rejected.saturating_add(1);
+            return false;
+        }
+
+        let gain = px.map(|v| v / (FORGETTING + xpx));
+
+        for (t, k) in self.theta.iter_mut().zip(gain) {
+            *t += k * err;
+        }
+
+        // The error after fitting this point.
+        let resid = err * FORGETTING / (FORGETTING + xpx);
+
+        // Statistics are weighted evenly until we have enough samples to fill the forgetting
+        // window, so they aren't biased towards 0 at first.
+        let weight = (1. / (self.samples as f32 + 1.)).max(1. - FORGETTING);
+        let cmd_diff = cmd - self.cmd_mean;
+        self.cmd_mean += weight * cmd_diff;
+        self.cmd_var += weight * (cmd_diff.powi(2) - self.cmd_var);
+        self.resid_var += weight * (resid.powi(2) - self.resid_var);
+
+        let trace = self.p[0][0] + self.p[1][1] + self.p[2][2];
+        let inflation = if trace < P_TRACE_MAX {
+            1. / FORGETTING
+        } else {
+            1.
+        };
+
+        for (row, k) in self.p.iter_mut().zip(gain) {
+            for (v, px_j) in row.iter_mut().zip(px) {
+                *v = (*v - k * px_j) * inflation;
+            }
+        }
+
+        // Rounding error makes the covariance drift from symmetric, which, in f32, soon makes the
+        // fit diverge.
+        for (i, j) in [(0, 1), (0, 2), (1, 2)] {
+            let v = (self.p[i][j] + self.p[j][i]) / 2.;
+            self.p[i][j] = v;
+            self.p[j][i] = v;
+        }
+
+        self.samples = self.samples.saturating_add(1);
+        true
+    }
+}
+
 /// Polynomial coefficients that map angular acceleration to either RPM, or servo positions.
 pub struct AccelMap {
     /// AKA A
@@ -80,91 +249,125 @@ pub struct AccelMap {
     pub lin: f32,
     /// AKA C
     pub constant: f32,
+    pub fit: MapFit,
 }
 
-// todo: Here next (Apr 2023). Using this collection of points, create a linear map. Probably least-suqres.
-// todo: You need a way to get timestamps.
-
 impl AccelMap {
-    // /// Pts are (angular accel, RPM or servo posit delta) // todo: Do you want it the other way?
-    // pub fn new(pt0: AccelMapPt, pt1: AccelMapPt, pt2: AccelMapPt) -> Self {
-    //     // let (square, lin, constant) = util::create_polynomial_terms(pt0, pt1, pt2);
-    //     let (square, lin, constant) = (0., 0., 0.);
-    //
-    //     Self {
-    //         square,
-    //         lin,
-    //         constant,
-    //     }
-    // }
-
-    /// Fit a least-squares approximation from logged points.
-    pub fn update_coeffs(&mut self, pts: &[AccelMapPt]) {
-        // todo: Do we need to take into account different ranges that may not be in the recent
-        // todo data?
-
-        // todo: Sloppy estimation here with only 3 pts.
-        let (square, lin, constant) = util::create_polynomial_terms(
-            (
-                pts[NUM_SAMPLE_PTS - 1].angular_accel,
-                pts[NUM_SAMPLE_PTS - 1].ctrl_cmd,
-            ),
-            (
-                pts[NUM_SAMPLE_PTS - 5].angular_accel,
-                pts[NUM_SAMPLE_PTS - 5].ctrl_cmd,
-            ),
-            (0., 0.),
-        );
-
-        // println!("Latest pt: {}, {}", pts[NUM_SAMPLE_PTS - 1].angular_accel, pts[NUM_SAMPLE_PTS - 1].ctrl_cmd);
+    fn new(square: f32, lin: f32, constant: f32, p: f32) -> Self {
+        Self {
+            square,
+            lin,
+            constant,
+            fit: MapFit::new(square, lin, constant, p),
+        }
+    }
 
-        // let constant = 0.;
-        // let squares = 0.;
-        // let lin = (pts[pts.len()].ctrl_cmd / pts[pts.len()].angular_accel);
+    /// Fit a logged point. Once the fit is trustworthy, control logic uses its coefficients.
+    pub fn update_coeffs(&mut self, pt: &AccelMapPt) {
+        let was_trustworthy = self.fit.trustworthy();
 
-        self.square = square;
-        self.lin = lin;
-        self.constant = constant;
+        if self.fit.update(pt.angular_accel, pt.ctrl_cmd) && self.fit.trustworthy() {
+            (self.square, self.lin, self.constant) = self.fit.coeffs();
 
-        // todo: Temp / v sloppy!!
-        self.square = 0.;
-        self.lin = 0.001;
-        self.constant = 0.;
+            if !was_trustworthy {
+                println!(
+                    "Accel map fit trustworthy. sq: {}, lin: {}, const: {}",
+                    self.square, self.lin, self.constant
+                );
+            }
+        }
     }
 
     /// Given a target angular acceleration, calculate a polynomial-fit RPM delta or servo position.
     /// This is called by flight control logic to determine how to set the control mix.
     pub fn interpolate(&self, target_accel: f32) -> f32 {
-        static mut i: u32 = 0;
-        unsafe { i += 1 };
-        if unsafe { i } % 3_000 == 0 {
-            // println!(
-            //     "sq: {}, lin: {}, const: {}",
-            //     self.square, self.lin, self.constant
-            // );
+        self.square * target_accel.powi(2) + self.lin * target_accel + self.constant
+    }
+
+    /// Returns `None` if values are invalid.
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let coeffs: [f32; 6] = core::array::from_fn(|i| f32_at(buf, i * 4));
+        let samples = u32::from_be_bytes(buf[24..28].try_into().unwrap());
+        let rms_resid = f32_at(buf, 28);
+        let r_squared = f32_at(buf, 32);
+
+        // These comparisons also reject NaN.
+        if coeffs.iter().any(|v| !v.is_finite())
+            || !(rms_resid >= 0. && rms_resid.is_finite())
+            || !(0.0..=1.).contains(&r_squared)
+        {
+            return None;
         }
 
-        self.square * target_accel.powi(2) + self.lin * target_accel + self.constant
+        // A fit that wasn't trustworthy when saved starts as uncertain as the defaults.
+        let p = if samples >= TRUST_MIN_SAMPLES && r_squared >= TRUST_MIN_R_SQUARED {
+            P_LOADED
+        } else {
+            P_INIT
+        };
+
+        Some(Self {
+            square: coeffs[0],
+            lin: coeffs[1],
+            constant: coeffs[2],
+            fit: MapFit {
+                samples,
+                resid_var: rms_resid.powi(2),
+                // Recovers the variance that gives this R²; an R² of 1 is treated as slightly less.
+                cmd_var: rms_resid.powi(2) / (1. - r_squared).max(0.01),
+                ..MapFit::new(coeffs[3], coeffs[4], coeffs[5], p)
+            },
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; ACCEL_MAP_SIZE] {
+        let mut result = [0; ACCEL_MAP_SIZE];
+        let (sq, lin, constant) = self.fit.coeffs();
+
+        for (i, v) in [self.square, self.lin, self.constant, sq, lin, constant]
+            .iter()
+            .enumerate()
+        {
+            result[i * 4..i * 4 + 4].clone_from_slice(&v.to_be_bytes());
+        }
+        result[24..28].clone_from_slice(&self.fit.samples.to_be_bytes());
+        result[28..32].clone_from_slice(&self.fit.rms_resid().to_be_bytes());
+        result[32..36].clone_from_slice(&self.fit.r_squared().to_be_bytes());
+
+        result
+    }
+
+    fn fit_to_bytes(&self) -> [u8; ACCEL_MAP_FIT_SIZE] {
+        let mut result = [0; ACCEL_MAP_FIT_SIZE];
+        let (sq, lin, constant) = self.fit.coeffs();
+
+        for (i, v) in [self.square, self.lin, self.constant, sq, lin, constant]
+            .iter()
+            .enumerate()
+        {
+            result[i * 4..i * 4 + 4].clone_from_slice(&v.to_be_bytes());
+        }
+        result[24..28].clone_from_slice(&self.fit.samples.to_be_bytes());
+        result[28..32].clone_from_slice(&self.fit.rejected.to_be_bytes());
+        result[32..36].clone_from_slice(&self.fit.rms_resid().to_be_bytes());
+        result[36..40].clone_from_slice(&self.fit.r_squared().to_be_bytes());
+        result[40] = self.fit.trustworthy() as u8;
+
+        result
     }
 }
 
 impl Default for AccelMap {
+    /// Conservative defaults, used until we've loaded or fit a map. Erring towards larger commands
+    /// per acceleration gives a sluggish, vice unstable, response.
     fn default() -> Self {
         // Rpm. Units are RPM / (rad/s^2)
         #[cfg(feature = "quad")]
-        return Self {
-            square: 0.,
-            lin: 1_000.,
-            constant: 0.,
-        };
+        return Self::new(0., 1_000., 0., P_INIT);
 
         // Servo posits. Units are servo_posit / (rad/s^2)
-        #[cfg(feature = "quad")]
-        return Self {
-            square: 0.,
-            lin: 1_000.,
-            constant: 0.,
-        };
+        #[cfg(feature = "fixed-wing")]
+        return Self::new(0., 0.005, 0., P_INIT);
     }
 }
 
@@ -173,11 +376,15 @@ pub struct AccelMaps {
     pub map_roll: AccelMap,
     pub map_yaw: AccelMap,
 
-    /// A buffer of sample points. More than required (3) for now.
+    /// A buffer of recent sample points, for inspection.
     /// Format is (angular accel, RPM or servo posit delta).
     pub sample_pts_pitch: [AccelMapPt; NUM_SAMPLE_PTS],
     pub sample_pts_roll: [AccelMapPt; NUM_SAMPLE_PTS],
     pub sample_pts_yaw: [AccelMapPt; NUM_SAMPLE_PTS],
+    /// Where the next sample point goes in the buffers.
+    sample_i: usize,
+    /// The maps were loaded from flash at power-up, vice defaults.
+    pub loaded: bool,
 }
 
 impl Default for AccelMaps {
@@ -192,33 +399,84 @@ impl Default for AccelMaps {
             sample_pts_pitch: pts,
             sample_pts_roll: pts,
             sample_pts_yaw: pts,
+            sample_i: 0,
+            loaded: false,
         }
     }
 }
 
 impl AccelMaps {
-    /// Add a new sample point for each of pitch, roll and yaw. Update coefficients to
-    /// incorporate latest 3 points.
-    pub fn log_pt(&mut self, pt_pitch: AccelMapPt, pt_roll: AccelMapPt, pt_yaw: AccelMapPt) {
-        // todo: Sanity-check and reject as-requird, relying on other points you've logged recently.
-        let i = SAMPLE_PT_I.fetch_add(1, Ordering::Relaxed);
+    /// Run at init, before the main loop starts. Reads maps from the flight stats page, or uses
+    /// defaults if there are none saved.
+    pub fn load(flash: &mut Flash) -> Self {
+        let mut buf = [0; ACCEL_MAPS_SIZE];
+        flash.read(
+            Bank::B1,
+            crate::FLASH_STATS_PAGE,
+            crate::flight_stats::ACCEL_MAPS_OFFSET,
+            &mut buf,
+        );
+
+        if buf[0] != RECORD_MARKER {
+            return Default::default();
+        }
+
+        let map = |i: usize| AccelMap::from_bytes(&buf[1 + i * ACCEL_MAP_SIZE..]);
+        let (Some(map_pitch), Some(map_roll), Some(map_yaw)) = (map(0), map(1), map(2)) else {
+            println!("Invalid accel maps on flash; using defaults");
+            return Default::default();
+        };
+
+        Self {
+            map_pitch,
+            map_roll,
+            map_yaw,
+            loaded: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; ACCEL_MAPS_SIZE] {
+        let mut result = [0; ACCEL_MAPS_SIZE];
+        result[0] = RECORD_MARKER;
+
+        for (i, map) in [&self.map_pitch, &self.map_roll, &self.map_yaw]
+            .iter()
+            .enumerate()
+        {
+            let start = 1 + i * ACCEL_MAP_SIZE;
+            result[start..start + ACCEL_MAP_SIZE].clone_from_slice(&map.to_bytes());
+        }
 
-        // todo: This needs work! Likely scenario si many points near identitcal are logged in a row,
-        // todo and the square and linear terms become bogus!
+        result
+    }
 
-        if i >= NUM_SAMPLE_PTS - 1 {
-            // Loop the atomic index back to the first position. (The retrieved value, `i` is still
-            // OK without modification)
-            SAMPLE_PT_I.store(0, Ordering::Release);
+    /// Coefficients, and fit quality, for sending over USB.
+    pub fn fit_to_bytes(&self) -> [u8; ACCEL_MAPS_FIT_SIZE] {
+        let mut result = [0; ACCEL_MAPS_FIT_SIZE];
+        result[0] = self.loaded as u8;
+
+        for (i, map) in [&self.map_pitch, &self.map_roll, &self.map_yaw]
+            .iter()
+            .enumerate()
+        {
+            let start = 1 + i * ACCEL_MAP_FIT_SIZE;
+            result[start..start + ACCEL_MAP_FIT_SIZE].clone_from_slice(&map.fit_to_bytes());
         }
 
+        result
+    }
+
+    /// Add a new sample point for each of pitch, roll and yaw, and fit each map to it.
+    pub fn log_pt(&mut self, pt_pitch: AccelMapPt, pt_roll: AccelMapPt, pt_yaw: AccelMapPt) {
+        let i = self.sample_i;
         self.sample_pts_pitch[i] = pt_pitch;
         self.sample_pts_roll[i] = pt_roll;
         self.sample_pts_yaw[i] = pt_yaw;
+        self.sample_i = (i + 1) % NUM_SAMPLE_PTS;
 
-        // todo: Slice these from i!
-        self.map_pitch.update_coeffs(&self.sample_pts_pitch);
-        self.map_roll.update_coeffs(&self.sample_pts_roll);
-        self.map_yaw.update_coeffs(&self.sample_pts_yaw);
+        self.map_pitch.update_coeffs(&pt_pitch);
+        self.map_roll.update_coeffs(&pt_roll);
+        self.map_yaw.update_coeffs(&pt_yaw);
     }
 }
@@ -1,9 +1,10 @@
 //! This module contains code for control logic. (todo: expand)
 
-use ahrs::Params;
+use ahrs::{Params, UP};
 use cfg_if::cfg_if;
 use defmt::println;
 use lin_alg::f32::Quaternion;
+use num_traits::Float;
 
 use super::{common::CtrlMix, ctrl_effect_est::AccelMaps, filters::FlightCtrlFilters};
 use crate::flight_ctrls::{
@@ -29,6 +30,7 @@ pub struct DragCoeffs {
 }
 
 /// Control coefficients that affect the toleranaces and restrictions of the flight controls.
+#[derive(Clone, Copy)]
 pub struct CtrlCoeffs {
     /// todo: For fixed-wing, you should probably have separate roll and pitch values.
     /// If unable to find a linear jerk to cause a correction given the current parameters (or
@@ -61,6 +63,92 @@ impl Default for CtrlCoeffs {
     }
 }
 
+// Above this dot product, quaternions are close enough that we interpolate linearly; this avoids
+// dividing by a near-zero sine.
+const SLERP_LINEAR_THRESH: f32 = 0.9995;
+
+/// Spherical linear interpolation between two attitudes, taking the shorter path. `amount` is 0.
+/// (`start`) to 1. (`end`).
+pub fn slerp(start: Quaternion, end: Quaternion, amount: f32) -> Quaternion {
+    let mut dot = start.w * end.w + start.x * end.x + start.y * end.y + start.z * end.z;
+
+    // `q` and `-q` represent the same attitude; flip one if required to take the short path.
+    let sign = if dot < 0. {
+        dot = -dot;
+        -1.
+    } else {
+        1.
+    };
+
+    let (s_start, s_end) = if dot > SLERP_LINEAR_THRESH {
+        (1. - amount, amount * sign)
+    } else {
+        let θ_0 = dot.acos();
+        let θ = θ_0 * amount;
+        let sin_θ_0 = θ_0.sin();
+
+        ((θ_0 - θ).sin() / sin_θ_0, θ.sin() / sin_θ_0 * sign)
+    };
+
+    Quaternion {
+        w: s_start * start.w + s_end * end.w,
+        x: s_start * start.x + s_end * end.x,
+        y: s_start * start.y + s_end * end.y,
+        z: s_start * start.z + s_end * end.z,
+    }
+    .to_normalized()
+}
+
+// As a portion of the tilt limit: Within this distance of the limit, we progressively reduce the
+// rate at which stick inputs tilt the attitude commanded further.
+const TILT_LIMIT_SOFT_ZONE: f32 = 0.25;
+
+/// Decompose an attitude into heading and tilt: Returns a level attitude (zero pitch and roll) at
+/// its heading, and the tilt from vertical, in radians.
+pub fn decompose_tilt(attitude: Quaternion) -> (Quaternion, f32) {
+    // This matches how we sync heading for the takeoff attitude lock.
+    let hdg = attitude.to_axes().2;
+    let level = Quaternion::from_axis_angle(UP, -hdg);
+
+    let dot =
+        attitude.w * level.w + attitude.x * level.x + attitude.y * level.y + attitude.z * level.z;
+    let tilt = 2. * dot.abs().min(1.).acos();
+
+    (level, tilt)
+}
+
+/// Rotate an attitude toward level (zero pitch and roll, at its current heading), by at most
+/// `max_angle`, in radians.
+pub fn slew_toward_level(attitude: Quaternion, max_angle: f32) -> Quaternion {
+    let (level, tilt) = decompose_tilt(attitude);
+
+    if tilt <= max_angle {
+        return level;
+    }
+
+    slerp(attitude, level, max_angle / tilt)
+}
+
+/// A multiplier, 0. to 1., for pitch and roll rate commands that would tilt the attitude commanded
+/// further. It's 1. until the soft zone near the limit, then falls linearly to 0. at the limit, so
+/// approaching the limit feels progressive, vice a hard stop.
+pub fn tilt_rate_scale(tilt: f32, max_tilt: f32) -> f32 {
+    let soft_zone = max_tilt * TILT_LIMIT_SOFT_ZONE;
+
+    ((max_tilt - tilt) / soft_zone).clamp(0., 1.)
+}
+
+/// Clamp an attitude's tilt from vertical to `max_tilt`, in radians, keeping its heading.
+pub fn limit_tilt(attitude: Quaternion, max_tilt: f32) -> Quaternion {
+    let (level, tilt) = decompose_tilt(attitude);
+
+    if tilt <= max_tilt {
+        return attitude;
+    }
+
+    slerp(level, attitude, max_tilt / tilt)
+}
+
 /// Calculate an angular velocity command to perform a given attitude correction on a given axis.
 /// Attempts to command a constant angular acceleration, using kinematics. Assumes we have an accurate
 /// way of commanding angular velocity, as that's downstream of this.
@@ -70,6 +158,37 @@ fn att_correction_to_ω(dθ: f32, time_to_correct: f32, ω_target: f32) -> f32 {
     2. / time_to_correct * dθ - ω_target
 }
 
+#[cfg(feature = "quad")]
+/// Calculate angular rate commands, (pitch, roll, yaw) in rad/s, that correct the error between
+/// the current and target attitudes. Used by both control schemes.
+pub fn rates_from_att(
+    target_attitude: Quaternion,
+    target_ω: &(f32, f32, f32), // (pitch, roll, yaw)
+    params: &Params,
+    pid_coeffs: &PidCoeffs,
+) -> (f32, f32, f32) {
+    // This is the rotation we need to create to arrive at the target attitude from the current one.
+    let rot_cmd_axes = (target_attitude / params.attitude).to_axes();
+
+    // These are in rad
+    let error_att_x = rot_cmd_axes.0;
+    let error_att_y = rot_cmd_axes.1;
+    let error_att_z = rot_cmd_axes.2;
+
+    let pitch_rate_cmd = att_correction_to_ω(error_att_x, pid_coeffs.att_ttc, target_ω.0);
+    let roll_rate_cmd = att_correction_to_ω(error_att_y, pid_coeffs.att_ttc, target_ω.1);
+    let yaw_rate_cmd = att_correction_to_ω(error_att_z, pid_coeffs.att_ttc, target_ω.2);
+
+    // This cap mainly applies to non-continuous attitude commands.
+    const MAX_ATT_CORRECTION_ω: f32 = 12.;
+
+    (
+        pitch_rate_cmd.clamp(-MAX_ATT_CORRECTION_ω, MAX_ATT_CORRECTION_ω),
+        roll_rate_cmd.clamp(-MAX_ATT_CORRECTION_ω, MAX_ATT_CORRECTION_ω),
+        yaw_rate_cmd.clamp(-MAX_ATT_CORRECTION_ω, MAX_ATT_CORRECTION_ω),
+    )
+}
+
 #[cfg(feature = "quad")]
 /// Calculate target rotor RPM or control-surface positions from current and target attitudes,
 /// and current and target angular velocities.
@@ -93,24 +212,8 @@ pub fn ctrl_mix_from_att(
     pid_state: &mut PidStateRate,
     has_taken_off: bool,
 ) -> CtrlMix {
-    // This is the rotation we need to create to arrive at the target attitude from the current one.
-    let rot_cmd_axes = (target_attitude / params.attitude).to_axes();
-
-    // These are in rad
-    let error_att_x = rot_cmd_axes.0;
-    let error_att_y = rot_cmd_axes.1;
-    let error_att_z = rot_cmd_axes.2;
-
-    let mut pitch_rate_cmd = att_correction_to_ω(error_att_x, pid_coeffs.att_ttc, target_ω.0);
-    let mut roll_rate_cmd = att_correction_to_ω(error_att_y, pid_coeffs.att_ttc, target_ω.1);
-    let mut yaw_rate_cmd = att_correction_to_ω(error_att_z, pid_coeffs.att_ttc, target_ω.2);
-
-    // This cap mainly applies to non-continuous attitude commands.
-    const MAX_ATT_CORRECTION_ω: f32 = 12.;
-
-    pitch_rate_cmd = pitch_rate_cmd.clamp(-MAX_ATT_CORRECTION_ω, MAX_ATT_CORRECTION_ω);
-    roll_rate_cmd = roll_rate_cmd.clamp(-MAX_ATT_CORRECTION_ω, MAX_ATT_CORRECTION_ω);
-    yaw_rate_cmd = yaw_rate_cmd.clamp(-MAX_ATT_CORRECTION_ω, MAX_ATT_CORRECTION_ω);
+    let (pitch_rate_cmd, roll_rate_cmd, yaw_rate_cmd) =
+        rates_from_att(target_attitude, target_ω, params, pid_coeffs);
 
     // The I-term builds up if corrections are unable to expeditiously converge.
     // An example of when this can happen is when the aircraft is on the ground.
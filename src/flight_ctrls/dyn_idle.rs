@@ -0,0 +1,109 @@
+//! This module contains dynamic idle: Using bidirectional DSHOT RPM readings, we hold each motor
+//! above a minimum RPM while airborne, instead of relying on a fixed minimum power alone. This
+//! prevents rotors from stopping, or falling out of sync, on aggressive throttle chops, and keeps
+//! control authority at low throttle.
+//!
+//! A motor below the floor gets a power correction proportional to how far below it is. Motors
+//! without a valid RPM reading get no correction. This applies to quads; fixed-wing thrust motors
+//! may idle stopped.
+
+use crate::imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS;
+
+// Floors outside this range, in RPM, are rejected when loading config.
+pub const MIN_RPM_MIN: f32 = 500.;
+pub const MIN_RPM_MAX: f32 = 10_000.;
+// Gains outside this range are rejected when loading config.
+pub const GAIN_MAX: f32 = 2.;
+
+/// We limit the correction on each motor to this much power, so a faulty RPM reading can't
+/// command much power.
+const CORRECTION_MAX: f32 = 0.2;
+
+// Serialized size: Enabled, floor, and gain.
+pub const DYN_IDLE_CFG_SIZE: usize = 1 + 4 * 2;
+
+/// Dynamic idle settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct DynIdleCfg {
+    pub enabled: bool,
+    /// Mechanical RPM each motor is held above while airborne.
+    pub min_rpm: f32,
+    /// Power added per portion of `min_rpm` a motor is below it. Eg with 0.2, a motor at half
+    /// the floor gets 0.1 power added.
+    pub gain: f32,
+}
+
+impl Default for DynIdleCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_rpm: 3_500.,
+            gain: 0.2,
+        }
+    }
+}
+
+impl DynIdleCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let min_rpm = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+        let gain = f32::from_be_bytes(buf[5..9].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if buf[0] > 1
+            || !(MIN_RPM_MIN..=MIN_RPM_MAX).contains(&min_rpm)
+            || !(0. ..=GAIN_MAX).contains(&gain)
+        {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            min_rpm,
+            gain,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; DYN_IDLE_CFG_SIZE] {
+        let mut result = [0; DYN_IDLE_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1..5].clone_from_slice(&self.min_rpm.to_be_bytes());
+        result[5..9].clone_from_slice(&self.gain.to_be_bytes());
+        result
+    }
+}
+
+/// Dynamic idle state. The corrections are logged, so the effect can be observed while tuning.
+#[derive(Default)]
+pub struct DynIdle {
+    /// Power added to each motor, in the order of `MotorServoState::rotor_rpms`. 0. to
+    /// `CORRECTION_MAX`.
+    pub correction: [f32; NUM_RPM_NOTCH_MOTORS],
+}
+
+impl DynIdle {
+    /// Update corrections from the latest RPM readings. Run this each flight control update, prior
+    /// to applying them. Only active while armed and airborne.
+    pub fn update(
+        &mut self,
+        rpms: &[Option<f32>; NUM_RPM_NOTCH_MOTORS],
+        motors_armed: bool,
+        has_taken_off: bool,
+        cfg: &DynIdleCfg,
+    ) {
+        if !cfg.enabled || !motors_armed || !has_taken_off {
+            self.correction = [0.; NUM_RPM_NOTCH_MOTORS];
+            return;
+        }
+
+        for (correction, rpm) in self.correction.iter_mut().zip(rpms) {
+            *correction = match rpm {
+                Some(rpm) if *rpm < cfg.min_rpm => {
+                    (cfg.gain * (cfg.min_rpm - rpm) / cfg.min_rpm).min(CORRECTION_MAX)
+                }
+                _ => 0.,
+            };
+        }
+    }
+}
@@ -107,6 +107,16 @@ impl FlightCtrlFilters {
             iir_apply(&mut self.d_term_z, d_term_z),
         )
     }
+
+    /// Clear the D-term filter states, eg after switching control profiles, so output from the
+    /// previous coefficients doesn't carry over.
+    pub fn reset_d_terms(&mut self) {
+        unsafe {
+            FILTER_STATE_D_TERM_X = [0.; 4];
+            FILTER_STATE_D_TERM_Y = [0.; 4];
+            FILTER_STATE_D_TERM_Z = [0.; 4];
+        }
+    }
 }
 
 /// D term filters for the motor-RPM PID
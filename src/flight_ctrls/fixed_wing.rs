@@ -7,7 +7,10 @@
 
 use cfg_if::cfg_if;
 
-use super::common::{CtrlMix, InputMap};
+use super::{
+    common::{CtrlMix, InputMap},
+    rates::RateCurve,
+};
 use crate::{
     dshot,
     protocols::servo,
@@ -34,11 +37,12 @@ const ANGULAR_ACCEL_LOG_RATIO: usize = 20;
 impl Default for InputMap {
     fn default() -> Self {
         Self {
-            pitch_rate: (-6., 6.),
-            roll_rate: (-6., 6.),
-            yaw_rate: (-6., 6.),
+            pitch_rate: RateCurve::linear(6.),
+            roll_rate: RateCurve::linear(6.),
+            yaw_rate: RateCurve::linear(6.),
             alt_commanded_offset_msl: (0., 100.),
             alt_commanded_agl: (0.5, 8.),
+            cal: Default::default(),
         }
     }
 }
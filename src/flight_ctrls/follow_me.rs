@@ -0,0 +1,363 @@
+//! This module contains follow-me: The aircraft follows a moving target, eg a person carrying a
+//! phone. A ground station sends the target's position periodically, over USB, or over CRSF as an
+//! MSP message in the uplink. We hold a configured distance from the target, along the line from
+//! it to the aircraft, at a configured height above it, using the loiter position controller.
+//! The target's velocity, estimated from successive positions, is used to predict where it is
+//! between updates, and is fed forward to the position controller, so we don't lag behind it.
+//!
+//! It's enabled by the `follow_me` switch, and overrides the autopilot A switch; link-lost
+//! recovery overrides it. If the target stops updating, we hold position, then return to the
+//! base point. Targets are accepted on fixed-wing, but ignored. Quad only.
+
+use cfg_if::cfg_if;
+use cortex_m::interrupt;
+
+cfg_if! {
+    if #[cfg(feature = "quad")] {
+        use ahrs::{ppks::PositVelEarthUnits, Params};
+        use defmt::println;
+        use lin_alg::f32::Vec3;
+        use num_traits::Float;
+
+        use crate::{
+            alt_estimator::AltEstimate,
+            flight_ctrls::{autopilot::AutopilotStatus, common::AltType},
+            geo::LatLon,
+            rth::{self, Rth, RthCfg},
+            system_status::SystemStatus,
+        };
+
+        // If the target hasn't updated for this long, in s, we hold position.
+        const HOLD_TIMEOUT: f32 = 5.;
+        // If the target hasn't updated for this long, in s, we return to the base point.
+        const RTH_TIMEOUT: f32 = 30.;
+
+        // Targets farther than this from the aircraft, in m, are treated as stale. A sanity
+        // check.
+        const MAX_TARGET_DIST: f32 = 300.;
+
+        // Target position changes implying more than this speed, in m/s, are treated as jumps,
+        // and reset the velocity estimate.
+        const MAX_TARGET_SPEED: f32 = 20.;
+        // Successive targets closer in time than this, in s, don't update velocity; the position
+        // change would be mostly noise.
+        const MIN_VEL_DT: f32 = 0.2;
+        // Filtering of the velocity estimate. 0. to 1.; higher weights the newest sample more.
+        const VEL_SMOOTHING: f32 = 0.5;
+    }
+}
+
+// The latest target received, if new since the main loop last took it.
+static mut TARGET: Option<FollowTarget> = None;
+
+// Lat and lon, in degrees x 1e7 (i32), then altitude MSL in m.
+pub const FOLLOW_TARGET_SIZE: usize = 4 * 3;
+
+// Serialized size: Standoff distance, and height.
+pub const FOLLOW_CFG_SIZE: usize = 4 * 2;
+
+// Values outside these ranges, in m, are rejected when loading config.
+const STANDOFF_MAX: f32 = 50.;
+const HEIGHT_MIN: f32 = 2.;
+const HEIGHT_MAX: f32 = 50.;
+
+/// A target position, from the ground station.
+#[derive(Clone, Copy)]
+pub struct FollowTarget {
+    /// Degrees x 1e7.
+    pub lat_e7: i32,
+    pub lon_e7: i32,
+    /// Meters.
+    pub alt_msl: f32,
+}
+
+impl FollowTarget {
+    /// Returns `None` if lat or lon is out of range, or altitude isn't finite.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let lat_e7 = i32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let lon_e7 = i32::from_be_bytes(buf[4..8].try_into().unwrap());
+        let alt_msl = f32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+        if !(-900_000_000..=900_000_000).contains(&lat_e7)
+            || !(-1_800_000_000..=1_800_000_000).contains(&lon_e7)
+            || !alt_msl.is_finite()
+        {
+            return None;
+        }
+
+        Some(Self {
+            lat_e7,
+            lon_e7,
+            alt_msl,
+        })
+    }
+}
+
+/// Run from the USB or CRSF ISR, on receiving a target.
+pub fn store_target(target: FollowTarget) {
+    interrupt::free(|_| unsafe { TARGET = Some(target) });
+}
+
+/// The latest target, if one has arrived since the last call.
+#[cfg(feature = "quad")]
+fn take_target() -> Option<FollowTarget> {
+    interrupt::free(|_| unsafe { (*core::ptr::addr_of_mut!(TARGET)).take() })
+}
+
+/// Follow-me settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct FollowCfg {
+    /// Horizontal distance to hold from the target, in m.
+    pub standoff: f32,
+    /// Height to hold above the target, in m.
+    pub height: f32,
+}
+
+impl Default for FollowCfg {
+    fn default() -> Self {
+        Self {
+            standoff: 8.,
+            height: 6.,
+        }
+    }
+}
+
+impl FollowCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let standoff = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let height = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(0.0..=STANDOFF_MAX).contains(&standoff) || !(HEIGHT_MIN..=HEIGHT_MAX).contains(&height)
+        {
+            return None;
+        }
+
+        Some(Self { standoff, height })
+    }
+
+    pub fn to_bytes(&self) -> [u8; FOLLOW_CFG_SIZE] {
+        let mut result = [0; FOLLOW_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.standoff.to_be_bytes());
+        result[4..8].clone_from_slice(&self.height.to_be_bytes());
+        result
+    }
+}
+
+/// The point the position controller holds while following.
+#[cfg(feature = "quad")]
+#[derive(Clone, Copy)]
+pub struct FollowPoint {
+    pub posit: LatLon,
+    /// The target's velocity: North, and East, in m/s.
+    pub velocity: (f32, f32),
+}
+
+#[cfg(feature = "quad")]
+#[derive(Clone, Copy, PartialEq)]
+pub enum FollowStatus {
+    Inactive,
+    Following,
+    /// The target is stale; holding position.
+    Holding,
+    /// The target has been stale for a while; returning to the base point.
+    Returning,
+}
+
+#[cfg(feature = "quad")]
+impl Default for FollowStatus {
+    fn default() -> Self {
+        Self::Inactive
+    }
+}
+
+#[cfg(feature = "quad")]
+#[derive(Default)]
+pub struct FollowMe {
+    pub status: FollowStatus,
+    /// The latest target: Position, and altitude MSL.
+    target: Option<(LatLon, f32)>,
+    /// When the latest target arrived, in s.
+    target_time: f32,
+    /// North, and East, in m/s.
+    velocity: (f32, f32),
+    /// Where we hold while the target is stale.
+    hold_pt: Option<PositVelEarthUnits>,
+}
+
+#[cfg(feature = "quad")]
+impl FollowMe {
+    /// Run from `update_flight_modes`, after the switches set modes. Takes a new target if one's
+    /// arrived, and sets the autopilot modes for the target's freshness. `enabled` is the switch.
+    pub fn update(
+        &mut self,
+        enabled: bool,
+        autopilot_status: &mut AutopilotStatus,
+        system_status: &SystemStatus,
+        params: &Params,
+        alt: &AltEstimate,
+        base_pt: &PositVelEarthUnits,
+        cfg: &FollowCfg,
+        rth: &mut Rth,
+        rth_cfg: &RthCfg,
+        timestamp: f32,
+    ) {
+        if let Some(target) = take_target() {
+            self.add_target(target, timestamp);
+        }
+
+        if !enabled {
+            if self.status != FollowStatus::Inactive {
+                // Clear what we commanded; the switches set modes again next update.
+                rth::clear_modes(autopilot_status);
+                self.stop(autopilot_status);
+            }
+            return;
+        }
+
+        // On enabling, count staleness from now, so we hold before returning.
+        if self.status == FollowStatus::Inactive && timestamp - self.target_time >= HOLD_TIMEOUT {
+            self.target = None;
+            self.target_time = timestamp;
+        }
+
+        let age = timestamp - self.target_time;
+
+        let follow_pt = match self.target {
+            Some(target) if age < HOLD_TIMEOUT => self.follow_pt(target, age, params, cfg),
+            _ => None,
+        };
+
+        let status = if follow_pt.is_some() {
+            FollowStatus::Following
+        } else if age > RTH_TIMEOUT {
+            FollowStatus::Returning
+        } else {
+            FollowStatus::Holding
+        };
+
+        if status != self.status {
+            match status {
+                FollowStatus::Following => println!("Following target"),
+                FollowStatus::Holding => println!("Follow-me target stale; holding position"),
+                FollowStatus::Returning => println!("Follow-me target stale; returning to base"),
+                FollowStatus::Inactive => (),
+            }
+
+            // Hold where we are when we stop following, vice where the target was.
+            if status == FollowStatus::Holding {
+                self.hold_pt = Some(PositVelEarthUnits {
+                    lat_e8: params.posit_fused.lat_e8,
+                    lon_e8: params.posit_fused.lon_e8,
+                    elevation_msl: alt.msl,
+                    velocity: Vec3::new(0., 0., 0.),
+                });
+            }
+
+            // Returning's modes are set by `rth`.
+            rth::clear_modes(autopilot_status);
+        }
+        self.status = status;
+
+        autopilot_status.follow = follow_pt;
+        autopilot_status.loiter = None;
+
+        match status {
+            FollowStatus::Following => {
+                let alt = self.target.unwrap().1 + cfg.height;
+                autopilot_status.alt_hold = Some((AltType::Msl, alt));
+            }
+            FollowStatus::Holding => {
+                if let Some(pt) = &self.hold_pt {
+                    autopilot_status.alt_hold = Some((AltType::Msl, pt.elevation_msl));
+                    autopilot_status.loiter = Some(pt.clone());
+                }
+            }
+            FollowStatus::Returning => {
+                rth.execute(
+                    system_status,
+                    autopilot_status,
+                    params,
+                    alt,
+                    base_pt,
+                    rth_cfg,
+                );
+            }
+            FollowStatus::Inactive => (),
+        }
+    }
+
+    /// Stop following, without clearing altitude and point holds. Used when link-lost recovery
+    /// takes over.
+    pub fn stop(&mut self, autopilot_status: &mut AutopilotStatus) {
+        autopilot_status.follow = None;
+        self.status = FollowStatus::Inactive;
+        self.hold_pt = None;
+    }
+
+    /// Update the target, and its velocity estimate.
+    fn add_target(&mut self, target: FollowTarget, timestamp: f32) {
+        let posit = LatLon::from_e7(target.lat_e7, target.lon_e7);
+
+        if let Some((prev, _)) = self.target {
+            let dt = timestamp - self.target_time;
+
+            if dt < MIN_VEL_DT {
+                // Keep the previous position and time as the velocity reference.
+                return;
+            }
+
+            let (n, e) = prev.offset_ne(&posit);
+            let (v_n, v_e) = (n / dt, e / dt);
+
+            if dt > HOLD_TIMEOUT || v_n.hypot(v_e) > MAX_TARGET_SPEED {
+                self.velocity = (0., 0.);
+            } else {
+                self.velocity = (
+                    self.velocity.0 + VEL_SMOOTHING * (v_n - self.velocity.0),
+                    self.velocity.1 + VEL_SMOOTHING * (v_e - self.velocity.1),
+                );
+            }
+        }
+
+        self.target = Some((posit, target.alt_msl));
+        self.target_time = timestamp;
+    }
+
+    /// The point to hold: The target's position, predicted forward by its velocity, offset
+    /// toward the aircraft by the standoff distance. `None` if the target is too far away.
+    fn follow_pt(
+        &self,
+        target: (LatLon, f32),
+        age: f32,
+        params: &Params,
+        cfg: &FollowCfg,
+    ) -> Option<FollowPoint> {
+        let aircraft = LatLon::new(params.posit_fused.lat_e8, params.posit_fused.lon_e8);
+
+        let target = target
+            .0
+            .offset_by(self.velocity.0 * age, self.velocity.1 * age);
+
+        let (n, e) = target.offset_ne(&aircraft);
+        let dist = n.hypot(e);
+
+        if dist > MAX_TARGET_DIST {
+            return None;
+        }
+
+        // Directly over the target, the direction is undefined; hold over it.
+        let (off_n, off_e) = if dist > 0.1 {
+            (n / dist * cfg.standoff, e / dist * cfg.standoff)
+        } else {
+            (0., 0.)
+        };
+
+        Some(FollowPoint {
+            posit: target.offset_by(off_n, off_e),
+            velocity: self.velocity,
+        })
+    }
+}
@@ -0,0 +1,79 @@
+//! This module contains an estimate of the throttle required to hover. Autopilot modes use it as
+//! a starting point; eg alt hold, and the takeoff ramp.
+//!
+//! While armed, airborne, and neither accelerating nor moving vertically, the commanded throttle
+//! is what hovers the aircraft. We low-pass filter it, with a slow time constant. The estimate is
+//! saved to flash on disarm, so the next flight starts from it.
+
+// Estimates outside this range are rejected, both when updating, and when loading config.
+pub const HOVER_THROTTLE_MIN: f32 = 0.08;
+pub const HOVER_THROTTLE_MAX: f32 = 0.75;
+
+pub const HOVER_THROTTLE_DEFAULT: f32 = 0.3;
+
+// We only learn while vertical acceleration (m/s^2, filtered) and velocity (m/s) are below these.
+const ACCEL_UP_THRESH: f32 = 0.5;
+const V_Z_THRESH: f32 = 0.3;
+
+// Time constant of the lowpass filter on vertical acceleration, in seconds.
+const ACCEL_FILTER_TAU: f32 = 0.2;
+// Time constant of the estimate's lowpass filter, in seconds. Slow, so maneuvering that passes
+// the checks above doesn't move it much.
+const EST_TAU: f32 = 5.;
+
+// We only save the estimate if we've learned for at least this long, in seconds, during a
+// flight.
+const LEARN_TIME_MIN: f32 = 5.;
+
+pub struct HoverThrottleEst {
+    /// Throttle, as commanded prior to thrust compensation. 0. to 1.
+    pub throttle: f32,
+    accel_up: f32,
+    /// Seconds spent learning this flight.
+    learn_time: f32,
+}
+
+impl Default for HoverThrottleEst {
+    fn default() -> Self {
+        Self::new(HOVER_THROTTLE_DEFAULT)
+    }
+}
+
+impl HoverThrottleEst {
+    /// `initial` is the estimate saved in user config.
+    pub fn new(initial: f32) -> Self {
+        Self {
+            throttle: initial,
+            accel_up: 0.,
+            learn_time: 0.,
+        }
+    }
+
+    /// Run each IMU update. `accel_up` is vertical acceleration in the earth frame, with gravity
+    /// removed, in m/s^2. `v_z` is vertical velocity, in m/s.
+    pub fn update(&mut self, throttle: f32, accel_up: f32, v_z: f32, airborne: bool, dt: f32) {
+        self.accel_up += dt / (ACCEL_FILTER_TAU + dt) * (accel_up - self.accel_up);
+
+        if !airborne
+            || self.accel_up.abs() > ACCEL_UP_THRESH
+            || v_z.abs() > V_Z_THRESH
+            || !(HOVER_THROTTLE_MIN..=HOVER_THROTTLE_MAX).contains(&throttle)
+        {
+            return;
+        }
+
+        self.throttle += dt / (EST_TAU + dt) * (throttle - self.throttle);
+        self.learn_time += dt;
+    }
+
+    /// Run while disarmed. Returns the estimate, if we learned enough this flight that it should be
+    /// saved to flash. Returns it only once per flight.
+    pub fn take_learned(&mut self) -> Option<f32> {
+        if self.learn_time < LEARN_TIME_MIN {
+            return None;
+        }
+
+        self.learn_time = 0.;
+        Some(self.throttle)
+    }
+}
@@ -0,0 +1,289 @@
+//! This module contains calibration of control inputs: The range and center of each stick
+//! channel, as received from the radio. Started over USB. The user first moves each stick
+//! through its extremes, then centers the sticks with throttle at minimum. We record each stage
+//! over a fixed window, and the PC application prompts the user based on the current stage.
+
+use defmt::println;
+
+use crate::{controller_interface::ChannelData, util::map_linear};
+
+/// Seconds the user has to move each stick through its full range.
+const EXTREMES_WINDOW: f32 = 8.;
+/// Seconds we average stick positions over, while centered.
+const CENTER_WINDOW: f32 = 2.;
+
+// We shrink each calibrated range by this portion of its span on each end, so full deflection
+// reliably reaches full scale.
+const DEADBAND_MARGIN: f32 = 0.02;
+
+// Calibration fails if any centered channel's span is less than this. Nominal span is 2.
+const MIN_SPAN: f32 = 1.;
+// Nominal span is 1.
+const MIN_SPAN_THROTTLE: f32 = 0.5;
+// The center must be at least this portion of the span away from either extreme.
+const MIN_CENTER_OFFSET: f32 = 0.25;
+
+/// Calibration for a single channel, in the units of `ChannelData`.
+#[derive(Clone, Copy)]
+pub struct ChannelCal {
+    pub min: f32,
+    pub center: f32,
+    pub max: f32,
+}
+
+impl ChannelCal {
+    const fn new(min: f32, center: f32, max: f32) -> Self {
+        Self { min, center, max }
+    }
+
+    /// Map a self-centering channel to -1. to 1., with 0. at the calibrated center.
+    pub fn apply_centered(&self, input: f32) -> f32 {
+        let result = if input >= self.center {
+            map_linear(input, (self.center, self.max), (0., 1.))
+        } else {
+            map_linear(input, (self.min, self.center), (-1., 0.))
+        };
+
+        result.clamp(-1., 1.)
+    }
+
+    /// Map a channel to 0. to 1. Used for throttle.
+    pub fn apply(&self, input: f32) -> f32 {
+        map_linear(input, (self.min, self.max), (0., 1.)).clamp(0., 1.)
+    }
+
+    fn valid(&self, min_span: f32) -> bool {
+        let span = self.max - self.min;
+        // This comparison also rejects NaN.
+        if !(span >= min_span) {
+            return false;
+        }
+
+        self.center >= self.min + span * MIN_CENTER_OFFSET
+            && self.center <= self.max - span * MIN_CENTER_OFFSET
+    }
+}
+
+/// Calibrated ranges and centers of the stick channels. Stored in user config, as part of
+/// `InputMap`.
+#[derive(Clone, Copy)]
+pub struct InputCal {
+    pub pitch: ChannelCal,
+    pub roll: ChannelCal,
+    pub yaw: ChannelCal,
+    /// The center isn't used for throttle.
+    pub throttle: ChannelCal,
+}
+
+impl Default for InputCal {
+    fn default() -> Self {
+        Self {
+            pitch: ChannelCal::new(-1., 0., 1.),
+            roll: ChannelCal::new(-1., 0., 1.),
+            yaw: ChannelCal::new(-1., 0., 1.),
+            throttle: ChannelCal::new(0., 0.5, 1.),
+        }
+    }
+}
+
+impl InputCal {
+    /// Pitch, roll, yaw, throttle; min, center, max for each.
+    pub fn to_bytes(&self) -> [u8; INPUT_CAL_SIZE] {
+        let mut result = [0; INPUT_CAL_SIZE];
+
+        for (i, ch) in [self.pitch, self.roll, self.yaw, self.throttle]
+            .iter()
+            .enumerate()
+        {
+            let r = &mut result[i * 12..(i + 1) * 12];
+            r[0..4].clone_from_slice(&ch.min.to_be_bytes());
+            r[4..8].clone_from_slice(&ch.center.to_be_bytes());
+            r[8..12].clone_from_slice(&ch.max.to_be_bytes());
+        }
+        result
+    }
+
+    /// Returns `None` if any channel fails the checks a new calibration must pass; eg from a
+    /// corrupt config, or one saved before this field was added.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let ch = |i: usize| {
+            let b = &buf[i * 12..(i + 1) * 12];
+            ChannelCal {
+                min: f32::from_be_bytes(b[0..4].try_into().unwrap()),
+                center: f32::from_be_bytes(b[4..8].try_into().unwrap()),
+                max: f32::from_be_bytes(b[8..12].try_into().unwrap()),
+            }
+        };
+
+        let result = Self {
+            pitch: ch(0),
+            roll: ch(1),
+            yaw: ch(2),
+            throttle: ch(3),
+        };
+
+        if !result.valid() {
+            return None;
+        }
+
+        Some(result)
+    }
+
+    fn valid(&self) -> bool {
+        self.pitch.valid(MIN_SPAN)
+            && self.roll.valid(MIN_SPAN)
+            && self.yaw.valid(MIN_SPAN)
+            && self.throttle.valid(MIN_SPAN_THROTTLE)
+    }
+}
+
+pub const INPUT_CAL_SIZE: usize = 4 * 3 * 4;
+
+/// Calibration stage. Sent to the PC application, so it can prompt the user.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum InputCalStage {
+    Idle = 0,
+    /// Move all sticks through their full range.
+    Extremes = 1,
+    /// Center the sticks, with throttle at minimum.
+    Center = 2,
+    /// The last calibration succeeded, and was saved.
+    Success = 3,
+    /// The last calibration failed; ranges too small, or no control data received.
+    Failed = 4,
+}
+
+impl Default for InputCalStage {
+    fn default() -> Self {
+        Self::Idle
+    }
+}
+
+pub enum InputCalResult {
+    /// Not calibrating, or still collecting readings.
+    InProgress,
+    Success(InputCal),
+    Fail,
+}
+
+#[derive(Default)]
+struct Extremes {
+    min: [f32; 4],
+    max: [f32; 4],
+}
+
+/// Records channel extremes and centers during calibration.
+#[derive(Default)]
+pub struct InputCalCollector {
+    pub stage: InputCalStage,
+    /// Set over USB; we start on the next update, since that's where we have a timestamp.
+    pub start_pending: bool,
+    stage_start: f32,
+    extremes: Extremes,
+    center_sum: [f32; 4],
+    num_center_samples: u32,
+}
+
+impl InputCalCollector {
+    /// Don't allow arming while this is true: The sticks are being moved through their full range.
+    pub fn active(&self) -> bool {
+        self.start_pending || matches!(self.stage, InputCalStage::Extremes | InputCalStage::Center)
+    }
+
+    /// Abandon a calibration in progress, without changing the saved one.
+    pub fn cancel(&mut self) {
+        if self.active() {
+            println!("Input cal cancelled");
+            *self = Default::default();
+        }
+    }
+
+    /// Update with the latest control data, if any. Run this regularly from the main loop.
+    pub fn update(&mut self, ch_data: Option<&ChannelData>, timestamp: f32) -> InputCalResult {
+        if self.start_pending {
+            self.start_pending = false;
+            *self = Self {
+                stage: InputCalStage::Extremes,
+                stage_start: timestamp,
+                extremes: Extremes {
+                    min: [f32::MAX; 4],
+                    max: [f32::MIN; 4],
+                },
+                ..Default::default()
+            };
+            println!("Input cal started; move sticks to their extremes");
+        }
+
+        let elapsed = timestamp - self.stage_start;
+
+        match self.stage {
+            InputCalStage::Extremes => {
+                if let Some(ch) = ch_data {
+                    for (i, v) in [ch.pitch, ch.roll, ch.yaw, ch.throttle].iter().enumerate() {
+                        self.extremes.min[i] = self.extremes.min[i].min(*v);
+                        self.extremes.max[i] = self.extremes.max[i].max(*v);
+                    }
+                }
+
+                if elapsed > EXTREMES_WINDOW {
+                    self.stage = InputCalStage::Center;
+                    self.stage_start = timestamp;
+                    println!("Input cal: center the sticks, with throttle at minimum");
+                }
+            }
+            InputCalStage::Center => {
+                if let Some(ch) = ch_data {
+                    for (i, v) in [ch.pitch, ch.roll, ch.yaw, ch.throttle].iter().enumerate() {
+                        self.center_sum[i] += v;
+                    }
+                    self.num_center_samples += 1;
+                }
+
+                if elapsed > CENTER_WINDOW {
+                    return self.finish();
+                }
+            }
+            _ => (),
+        }
+
+        InputCalResult::InProgress
+    }
+
+    fn finish(&mut self) -> InputCalResult {
+        if self.num_center_samples == 0 {
+            println!("Input cal failed; no control data received");
+            self.stage = InputCalStage::Failed;
+            return InputCalResult::Fail;
+        }
+
+        let ch = |i: usize| {
+            let span = self.extremes.max[i] - self.extremes.min[i];
+            ChannelCal {
+                min: self.extremes.min[i] + span * DEADBAND_MARGIN,
+                center: self.center_sum[i] / self.num_center_samples as f32,
+                max: self.extremes.max[i] - span * DEADBAND_MARGIN,
+            }
+        };
+
+        let mut throttle = ch(3);
+        // Throttle is at minimum during the center stage, so its center is meaningless.
+        throttle.center = (throttle.min + throttle.max) / 2.;
+
+        let cal = InputCal {
+            pitch: ch(0),
+            roll: ch(1),
+            yaw: ch(2),
+            throttle,
+        };
+
+        if !cal.valid() {
+            println!("Input cal failed; ranges too small, or sticks not centered");
+            self.stage = InputCalStage::Failed;
+            return InputCalResult::Fail;
+        }
+
+        self.stage = InputCalStage::Success;
+        InputCalResult::Success(cal)
+    }
+}
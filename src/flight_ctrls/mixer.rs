@@ -0,0 +1,357 @@
+//! This module contains the quad motor mixer: Per-motor weights that convert a control mix
+//! (throttle, pitch, roll, yaw) into per-motor power. This lets us support frames that aren't a
+//! symmetric X, eg a deadcat, where the front arms are further from the CG. Fixed-wing doesn't
+//! use it.
+//!
+//! After mixing, we desaturate instead of clipping individual motors; clipping changes the ratio
+//! between axes, and causes yaw washout. By default, yaw gives way first: A hard yaw at high
+//! throttle can otherwise take the authority pitch and roll need, and the craft washes out. Yaw is
+//! scaled back until the outputs fit, down to a configured minimum; past that, pitch, roll, and
+//! the remaining yaw are scaled together. Thrust shifts last, after axis scaling, to keep all
+//! motors in range. The portion of yaw removed is reported in telemetry, so tuners can see when
+//! this engages. `DesatMode::Uniform` scales all axes together, as before, for comparison.
+//!
+//! Air mode: Motors never drop below idle while armed, and the mixer may shift all motors up
+//! (or down) from the commanded throttle to keep full attitude authority, eg at stick-low.
+//!
+//! After a motor failure, we mix with a reduced table; see `motor_failure`.
+
+use num_enum::TryFromPrimitive;
+use num_traits::Float;
+
+use super::{common::CtrlMix, motor_servo::RotationDir};
+
+/// Motor order for the rows of the mixer table.
+pub const NUM_MOTORS: usize = 4;
+
+// Serialized size: The preset, then the table, row by row.
+pub const MIXER_SIZE: usize = 1 + NUM_MOTORS * 4 * 4;
+
+// Serialized size: The mode, and minimum yaw retained.
+pub const DESAT_CFG_SIZE: usize = 1 + 4;
+
+// Weights outside this range are rejected.
+const WEIGHT_MAX: f32 = 1.;
+
+/// Idle power, 0. to 1. This is the minimum power of any motor while armed.
+pub const IDLE_PWR_DEFAULT: f32 = 0.045;
+/// Idle power settings above this are rejected.
+pub const IDLE_PWR_MAX: f32 = 0.15;
+
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum MixerPreset {
+    /// A symmetric X frame.
+    X = 0,
+    /// Motors at front, right, aft, and left. The front motor uses the front-left output, the
+    /// right motor front-right, the left motor aft-left, and the aft motor aft-right. (This keeps
+    /// rotation directions the same as on an X.)
+    Plus = 1,
+    /// Front arms wider, and further from the CG than aft arms. This preset assumes the front
+    /// motors are 1.5x as far from the CG as the aft motors; adjust weights for your frame.
+    Deadcat = 2,
+    /// Weights set by the user.
+    Custom = 3,
+}
+
+/// How the mixer desaturates. Repr is how it's stored.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum DesatMode {
+    /// Scale back yaw before pitch and roll.
+    YawFirst = 0,
+    /// Scale pitch, roll, and yaw together.
+    Uniform = 1,
+}
+
+/// Mixer desaturation settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct DesatCfg {
+    pub mode: DesatMode,
+    /// The minimum portion of the yaw command kept before pitch and roll are scaled back, 0. to
+    /// 1. `YawFirst` only.
+    pub yaw_min: f32,
+}
+
+impl Default for DesatCfg {
+    fn default() -> Self {
+        Self {
+            mode: DesatMode::YawFirst,
+            yaw_min: 0.2,
+        }
+    }
+}
+
+impl DesatCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let mode = DesatMode::try_from(buf[0]).ok()?;
+        let yaw_min = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+
+        // This comparison also rejects NaN.
+        if !(0. ..=1.).contains(&yaw_min) {
+            return None;
+        }
+
+        Some(Self { mode, yaw_min })
+    }
+
+    pub fn to_bytes(&self) -> [u8; DESAT_CFG_SIZE] {
+        let mut result = [0; DESAT_CFG_SIZE];
+        result[0] = self.mode as u8;
+        result[1..5].clone_from_slice(&self.yaw_min.to_be_bytes());
+        result
+    }
+}
+
+/// Weights for one motor.
+#[derive(Clone, Copy)]
+pub struct MixerRow {
+    pub throttle: f32,
+    /// Positive means this motor raises the nose.
+    pub pitch: f32,
+    /// Positive means this motor raises the left wing.
+    pub roll: f32,
+    /// Positive means this motor yaws clockwise, when the front-left motor rotates clockwise.
+    pub yaw: f32,
+}
+
+impl MixerRow {
+    const fn new(throttle: f32, pitch: f32, roll: f32, yaw: f32) -> Self {
+        Self {
+            throttle,
+            pitch,
+            roll,
+            yaw,
+        }
+    }
+
+    fn valid(&self) -> bool {
+        // These comparisons also reject NaN.
+        self.throttle > 0.
+            && self.throttle <= WEIGHT_MAX
+            && [self.pitch, self.roll, self.yaw]
+                .iter()
+                .all(|w| w.abs() <= WEIGHT_MAX)
+    }
+}
+
+/// Rows are front left, front right, aft left, aft right.
+#[derive(Clone, Copy)]
+pub struct Mixer {
+    pub preset: MixerPreset,
+    pub rows: [MixerRow; NUM_MOTORS],
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::from_preset(MixerPreset::X)
+    }
+}
+
+impl Mixer {
+    /// Weights for a preset. Custom returns the X weights, as a starting point.
+    pub fn from_preset(preset: MixerPreset) -> Self {
+        let rows = match preset {
+            MixerPreset::X | MixerPreset::Custom => [
+                MixerRow::new(1., 0.5, 0.5, 0.5),
+                MixerRow::new(1., 0.5, -0.5, -0.5),
+                MixerRow::new(1., -0.5, 0.5, -0.5),
+                MixerRow::new(1., -0.5, -0.5, 0.5),
+            ],
+            // Front and aft motors produce all pitch, and left and right all roll. They're at
+            // full arm length, vice 1/√2 for an X, so total moment matches.
+            MixerPreset::Plus => [
+                MixerRow::new(1., 0.707, 0., 0.5),
+                MixerRow::new(1., 0., -0.707, -0.5),
+                MixerRow::new(1., 0., 0.707, -0.5),
+                MixerRow::new(1., -0.707, 0., 0.5),
+            ],
+            // The aft motors carry more of the weight, since they're closer to the CG, and the
+            // front motors need less thrust for the same pitch moment.
+            MixerPreset::Deadcat => [
+                MixerRow::new(0.67, 0.4, 0.4, 0.5),
+                MixerRow::new(0.67, 0.4, -0.4, -0.5),
+                MixerRow::new(1., -0.6, 0.6, -0.5),
+                MixerRow::new(1., -0.6, -0.6, 0.5),
+            ],
+        };
+
+        Self { preset, rows }
+    }
+
+    /// Mix, and desaturate. Returns power for front left, front right, aft left, and aft right,
+    /// and the portion of the yaw command removed by desaturation, 0. to 1. Throttle is 0. to 1.,
+    /// and maps to `idle` to 1.; the outputs are kept within this range.
+    pub fn mix(
+        &self,
+        mix: &CtrlMix,
+        front_left_dir: RotationDir,
+        idle: f32,
+        desat: &DesatCfg,
+    ) -> ([f32; NUM_MOTORS], f32) {
+        // Assumes positive yaw from the IMU means clockwise. If props rotate in,
+        // front-left/aft-right rotors induce a CCW torque on the aircraft. If props rotate out,
+        // these same rotors induce a CW torque.
+        let yaw = match front_left_dir {
+            RotationDir::Clockwise => mix.yaw,
+            RotationDir::CounterClockwise => -mix.yaw,
+        };
+
+        mix_rows(&self.rows, mix, yaw, idle, desat)
+    }
+
+    /// Mix without the failed motor, at row index `failed`. It stays at idle. The diagonally
+    /// opposite motor, at `NUM_MOTORS - 1 - failed`, provides no throttle, only pitch and roll;
+    /// this balances the moments of the two motors adjacent to the failed one, which carry the
+    /// weight. `yaw_authority` scales the yaw command, 0. to
+    /// 1.; the remaining motors can't cancel yaw torque without giving up pitch and roll. The
+    /// yaw portion removed doesn't include `yaw_authority`.
+    pub fn mix_degraded(
+        &self,
+        mix: &CtrlMix,
+        front_left_dir: RotationDir,
+        failed: usize,
+        yaw_authority: f32,
+        idle: f32,
+        desat: &DesatCfg,
+    ) -> ([f32; NUM_MOTORS], f32) {
+        let mut rows = self.rows;
+        rows[failed] = MixerRow::new(0., 0., 0., 0.);
+        rows[NUM_MOTORS - 1 - failed].throttle = 0.;
+
+        let yaw = match front_left_dir {
+            RotationDir::Clockwise => mix.yaw,
+            RotationDir::CounterClockwise => -mix.yaw,
+        } * yaw_authority;
+
+        let (mut result, yaw_removed) = mix_rows(&rows, mix, yaw, idle, desat);
+        result[failed] = idle;
+        (result, yaw_removed)
+    }
+
+    /// Parse and validate. Returns `None` if the preset is unknown, or a weight is out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let preset = MixerPreset::try_from(buf[0]).ok()?;
+
+        let w = |i: usize| f32::from_be_bytes(buf[1 + i * 4..5 + i * 4].try_into().unwrap());
+        let row = |r: usize| MixerRow::new(w(r * 4), w(r * 4 + 1), w(r * 4 + 2), w(r * 4 + 3));
+
+        let rows = [row(0), row(1), row(2), row(3)];
+
+        if !rows.iter().all(|r| r.valid()) {
+            return None;
+        }
+
+        Some(Self { preset, rows })
+    }
+
+    pub fn to_bytes(&self) -> [u8; MIXER_SIZE] {
+        let mut result = [0; MIXER_SIZE];
+        result[0] = self.preset as u8;
+
+        for (r, row) in self.rows.iter().enumerate() {
+            for (c, w) in [row.throttle, row.pitch, row.roll, row.yaw]
+                .iter()
+                .enumerate()
+            {
+                let i = 1 + (r * 4 + c) * 4;
+                result[i..i + 4].clone_from_slice(&w.to_be_bytes());
+            }
+        }
+        result
+    }
+}
+
+/// Mix, and desaturate, with a given table. `yaw` is already corrected for rotation direction.
+/// Returns the outputs, and the portion of yaw removed.
+fn mix_rows(
+    rows: &[MixerRow; NUM_MOTORS],
+    mix: &CtrlMix,
+    yaw: f32,
+    idle: f32,
+    desat: &DesatCfg,
+) -> ([f32; NUM_MOTORS], f32) {
+    let throttle = mix.throttle.clamp(0., 1.);
+    let span = 1. - idle;
+
+    let mut base = [0.; NUM_MOTORS];
+    let mut pitch_roll = [0.; NUM_MOTORS];
+    let mut yaws = [0.; NUM_MOTORS];
+
+    for (i, row) in rows.iter().enumerate() {
+        base[i] = idle + throttle * row.throttle * span;
+        pitch_roll[i] = mix.pitch * row.pitch + mix.roll * row.roll;
+        yaws[i] = yaw * row.yaw;
+    }
+
+    // The portion of yaw kept, before scaling all axes.
+    let yaw_scale = match desat.mode {
+        DesatMode::YawFirst => yaw_scale_to_fit(&pitch_roll, &yaws, span).max(desat.yaw_min),
+        DesatMode::Uniform => 1.,
+    };
+
+    let mut axes = [0.; NUM_MOTORS];
+    for i in 0..NUM_MOTORS {
+        axes[i] = pitch_roll[i] + yaws[i] * yaw_scale;
+    }
+
+    // If the spread between motors is still more than the available range, scale down the
+    // pitch, roll, and remaining yaw contributions together until it fits.
+    let axes_min = axes.iter().fold(f32::MAX, |a, b| a.min(*b));
+    let axes_max = axes.iter().fold(f32::MIN, |a, b| a.max(*b));
+
+    let spread = axes_max - axes_min;
+    let scale = if spread > span { span / spread } else { 1. };
+    for a in &mut axes {
+        *a *= scale;
+    }
+
+    // Then shift all motors together, so none are below idle or above full power. This
+    // changes the effective throttle, but preserves the moments.
+    let mut out_min = f32::MAX;
+    let mut out_max = f32::MIN;
+    for i in 0..NUM_MOTORS {
+        out_min = out_min.min(base[i] + axes[i]);
+        out_max = out_max.max(base[i] + axes[i]);
+    }
+
+    let shift = if out_min < idle {
+        idle - out_min
+    } else if out_max > 1. {
+        1. - out_max
+    } else {
+        0.
+    };
+
+    let mut result = [0.; NUM_MOTORS];
+    for i in 0..NUM_MOTORS {
+        result[i] = (base[i] + axes[i] + shift).clamp(idle, 1.);
+    }
+
+    let yaw_removed = if yaws.iter().any(|y| *y != 0.) {
+        1. - yaw_scale * scale
+    } else {
+        0.
+    };
+
+    (result, yaw_removed)
+}
+
+/// The largest portion of yaw, 0. to 1., that keeps the spread between motors within `span`.
+/// Each pair of motors limits it, where yaw widens their difference. 0. if pitch and roll alone
+/// don't fit.
+fn yaw_scale_to_fit(pitch_roll: &[f32; NUM_MOTORS], yaws: &[f32; NUM_MOTORS], span: f32) -> f32 {
+    let mut result: f32 = 1.;
+
+    for i in 0..NUM_MOTORS {
+        for j in 0..NUM_MOTORS {
+            let d_yaw = yaws[i] - yaws[j];
+            if d_yaw > 0. {
+                result = result.min((span - (pitch_roll[i] - pitch_roll[j])) / d_yaw);
+            }
+        }
+    }
+
+    result.max(0.)
+}
@@ -4,28 +4,65 @@
 //! [Betaflight Signal flow diagram](https://github.com/betaflight/betaflight/wiki/Signal-Flow-Diagram)
 //! Note that this is just an example, and isn't necesssarily something to emulate.
 
+pub mod acro_trainer;
+pub mod airspeed;
+pub mod arm_check;
+pub mod authority;
+pub mod auto_launch;
 pub mod autopilot;
 pub mod cmd_updates;
 pub mod common;
+pub mod control_mapping;
 pub mod ctrl_effect_est;
 pub mod ctrl_logic;
+pub mod dyn_idle;
 pub mod filters;
+pub mod follow_me;
+pub mod hover_est;
+pub mod input_cal;
+pub mod mixer;
+pub mod motor_failure;
 pub mod motor_servo;
+pub mod motor_test;
 pub mod pid;
+pub mod profiles;
+pub mod rates;
+#[cfg(feature = "quad")]
+pub mod reversible;
+pub mod stall_protect;
+pub mod stick_cmds;
+#[cfg(feature = "fixed-wing")]
+pub mod surface_mix;
+pub mod throttle_limit;
+pub mod thrust_comp;
+pub mod tipover;
+#[cfg(feature = "quad")]
+pub mod turtle;
+pub mod wind_est;
 
 use ahrs::Params;
+use authority::AuthorityCfg;
 use cfg_if::cfg_if;
 use ctrl_effect_est::AccelMapPt;
 use ctrl_logic::CtrlCoeffs;
 use defmt::println;
+use dyn_idle::DynIdleCfg;
 use filters::FlightCtrlFilters;
-use motor_servo::MotorPower;
+use mixer::{DesatCfg, Mixer};
+use motor_failure::MotorFailCfg;
+use motor_servo::{MotorPower, OutputSmoothingCfg};
+use num_enum::TryFromPrimitive;
 use pid::PidCoeffs;
+#[cfg(feature = "quad")]
+use reversible::ThrustDir;
+use thrust_comp::ThrustCompCfg;
 
 use crate::{
     controller_interface::ChannelData,
-    flight_ctrls::{autopilot::AutopilotStatus, common::InputMap},
-    main_loop::DT_IMU,
+    flight_ctrls::{autopilot::AutopilotStatus, common::InputMap, control_mapping::ControlMapping},
+    loop_rates,
+    protocols::dshot,
+    safety,
     setup::MotorTimer,
     state::StateVolatile,
 };
@@ -40,6 +77,30 @@ cfg_if! {
     }
 }
 
+/// We limit how fast each motor's power may drop, per second, to prevent ESC desyncs. This allows
+/// full power to idle in 0.25s.
+const MAX_POWER_DROP_RATE: f32 = 4.;
+
+/// Selects which flight control loop runs. Stored in user config, so it can be changed from the
+/// PC without reflashing. Quad only; fixed-wing always uses `ModelBased`.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum CtrlScheme {
+    /// The classic rate loop: PID on commanded vs measured angular rates. In Acro, stick inputs
+    /// map directly to rates, with no attitude target. In other input modes, rate commands are
+    /// derived from the attitude error, as in `ModelBased`.
+    RatePid = 0,
+    /// Command an attitude quaternion, and derive rate commands from the attitude error, in
+    /// `ctrl_logic`.
+    ModelBased = 1,
+}
+
+impl Default for CtrlScheme {
+    fn default() -> Self {
+        Self::ModelBased
+    }
+}
+
 /// Our entry point for control logic
 pub fn run(
     params: &Params,
@@ -48,13 +109,22 @@ pub fn run(
     control_channel_data: &Option<ChannelData>,
     ctrl_coeffs: &CtrlCoeffs,
     flight_ctrl_filters: &mut FlightCtrlFilters,
-    motor_timer: &mut MotorTimer,
     input_map: &InputMap, // todo TS
     pid_coeffs: &PidCoeffs,
     autopilot_status: &AutopilotStatus,
     has_taken_off: bool,
+    thrust_comp_cfg: &ThrustCompCfg,
+    mixer: &Mixer,
+    desat_cfg: &DesatCfg,
+    idle_pwr: f32,
+    dyn_idle_cfg: &DynIdleCfg,
+    ctrl_scheme: CtrlScheme,
+    motor_fail_cfg: &MotorFailCfg,
+    authority_cfg: &AuthorityCfg,
     // throttle: f32,
 ) {
+    let rates = loop_rates::rates();
+
     // let throttle = match state_volatile.autopilot_commands.throttle {
     //     Some(t) => t,
     //     None => match control_channel_data {
@@ -67,40 +137,165 @@ pub fn run(
     //     },
     // };
 
-    // todo: Temp using rate controls to TS flight control logic
+    // Rate commands from stick inputs, for the rate PID scheme in Acro.
     let pry = match control_channel_data {
         Some(ch_data) => {
-            // temp trying traditional rate controls
-            let pitch_rate_cmd = input_map.calc_pitch_rate(-ch_data.pitch);
+            let pitch_rate_cmd = -input_map.calc_pitch_rate(ch_data.pitch);
             let roll_rate_cmd = input_map.calc_roll_rate(ch_data.roll);
-            let yaw_rate_cmd = input_map.calc_yaw_rate(-ch_data.yaw);
+            let yaw_rate_cmd = -input_map.calc_yaw_rate(ch_data.yaw);
 
             (pitch_rate_cmd, roll_rate_cmd, yaw_rate_cmd)
         }
         None => (0., 0., 0.),
     };
 
+    // This is only nonzero in Acro, with the trainer switch on.
+    #[cfg(feature = "quad")]
+    let pry = {
+        let (pitch, roll) = state_volatile.acro_trainer.correction;
+        (pry.0 + pitch, pry.1 + roll, pry.2)
+    };
+
+    // Manual and autopilot throttle both arrive here as commanded throttle. After a motor failure,
+    // we command a descent instead.
+    #[cfg(feature = "quad")]
+    let throttle_cmd = match state_volatile.motor_failed {
+        Some(_) => motor_failure::descent_throttle(
+            state_volatile.hover_throttle_est.throttle,
+            motor_fail_cfg,
+        ),
+        None => state_volatile.attitude_commanded.throttle,
+    };
+    #[cfg(feature = "fixed-wing")]
+    let throttle_cmd = state_volatile.attitude_commanded.throttle;
+
+    // In 3D mode, we mix throttle magnitude, and apply the direction after. Only manual throttle
+    // is signed.
+    #[cfg(feature = "quad")]
+    let throttle_cmd = if dshot::reversible() {
+        let throttle_signed = match (state_volatile.input_mode, state_volatile.motor_failed) {
+            (InputMode::Acro | InputMode::AcroHybrid, None) => {
+                reversible::throttle_from_stick(throttle_cmd)
+            }
+            _ => throttle_cmd,
+        };
+
+        state_volatile
+            .motor_servo_state
+            .thrust_dir
+            .update(throttle_signed)
+    } else {
+        throttle_cmd
+    };
+
+    let throttle = state_volatile
+        .thrust_comp
+        .apply(throttle_cmd, thrust_comp_cfg);
+
     cfg_if! {
         if #[cfg(feature = "quad")] {
-            let ctrl_mix = ctrl_logic::ctrl_mix_from_att(
-                state_volatile.attitude_commanded.quat,
-                &state_volatile.attitude_commanded.quat_dt,
-                state_volatile.attitude_commanded.throttle,
-                state_volatile.motor_servo_state.frontleft_aftright_dir,
-                params,
-                params_prev,
-                ctrl_coeffs,
-                &state_volatile.drag_coeffs,
-                &state_volatile.accel_maps,
-                flight_ctrl_filters,
-                // The DT passed is the IMU rate, since we update params_prev each IMU update.
-                DT_IMU,
-                pid_coeffs,
-                &mut state_volatile.pid_state_rate,
+            let mut ctrl_mix = match ctrl_scheme {
+                CtrlScheme::RatePid => {
+                    // Outside Acro, the sticks or autopilot command an attitude, vice rates.
+                    let pry = match state_volatile.input_mode {
+                        InputMode::Acro => pry,
+                        _ => ctrl_logic::rates_from_att(
+                            state_volatile.attitude_commanded.quat,
+                            &state_volatile.attitude_commanded.quat_dt,
+                            params,
+                            pid_coeffs,
+                        ),
+                    };
+
+                    pid::ctrl_mix_from_rates(
+                        pry,
+                        throttle,
+                        params,
+                        pid_coeffs,
+                        &mut state_volatile.pid_state_rate,
+                        flight_ctrl_filters,
+                        rates.dt_flight_ctrls,
+                        has_taken_off,
+                    )
+                }
+                CtrlScheme::ModelBased => ctrl_logic::ctrl_mix_from_att(
+                    state_volatile.attitude_commanded.quat,
+                    &state_volatile.attitude_commanded.quat_dt,
+                    throttle,
+                    state_volatile.motor_servo_state.frontleft_aftright_dir,
+                    params,
+                    params_prev,
+                    ctrl_coeffs,
+                    &state_volatile.drag_coeffs,
+                    &state_volatile.accel_maps,
+                    flight_ctrl_filters,
+                    // The DT passed is the IMU rate, since we update params_prev each IMU update.
+                    rates.dt_imu,
+                    pid_coeffs,
+                    &mut state_volatile.pid_state_rate,
+                    has_taken_off,
+                ),
+            };
+
+            state_volatile.authority.apply(&mut ctrl_mix, authority_cfg);
+
+            let authority = safety::ground_authority(throttle, has_taken_off);
+            ctrl_mix.pitch *= authority;
+            ctrl_mix.roll *= authority;
+            ctrl_mix.yaw *= authority;
+
+            let thrust_dir = state_volatile.motor_servo_state.thrust_dir;
+            let mix_dir = thrust_dir.apply_to_mix(&ctrl_mix);
+
+            let (mut power_commanded, yaw_removed) = match state_volatile.motor_failed {
+                Some(rotor) => MotorPower::from_mix_degraded(
+                    &mix_dir,
+                    state_volatile.motor_servo_state.frontleft_aftright_dir,
+                    mixer,
+                    rotor,
+                    motor_fail_cfg.yaw_authority,
+                    idle_pwr,
+                    desat_cfg,
+                ),
+                None => MotorPower::from_mix(
+                    &mix_dir,
+                    state_volatile.motor_servo_state.frontleft_aftright_dir,
+                    mixer,
+                    idle_pwr,
+                    desat_cfg,
+                ),
+            };
+            state_volatile.yaw_removed = yaw_removed;
+
+            power_commanded.limit_drop(
+                &state_volatile.motor_servo_state.get_power_settings().abs(),
+                MAX_POWER_DROP_RATE * rates.dt_flight_ctrls,
+            );
+
+            // Apply after the drop limit, so the correction takes effect immediately.
+            state_volatile.dyn_idle.update(
+                &state_volatile.motor_servo_state.rotor_rpms(),
+                state_volatile.arm_status == safety::ArmStatus::Armed,
                 has_taken_off,
+                dyn_idle_cfg,
             );
+            power_commanded.add(&state_volatile.dyn_idle.correction);
 
-            let power_commanded = MotorPower::from_mix(&ctrl_mix, state_volatile.motor_servo_state.frontleft_aftright_dir);
+            // Dynamic idle would push the failed motor up, since it reports no RPM.
+            if let Some(rotor) = state_volatile.motor_failed {
+                power_commanded.set(rotor, idle_pwr);
+            }
+
+            // Just after arming, the motor check holds idle, and steps each motor in turn.
+            state_volatile.arm_check.apply(&mut power_commanded, idle_pwr);
+
+            if dshot::reversible() {
+                match thrust_dir {
+                    ThrustDir::Stopped => power_commanded = MotorPower::default(),
+                    ThrustDir::Forward => (),
+                    ThrustDir::Reverse => power_commanded.negate(),
+                }
+            }
 
               static mut i: u32 = 0;
                 unsafe { i += 1 };
@@ -113,10 +308,8 @@ pub fn run(
             state_volatile.ctrl_mix = ctrl_mix;
 
             state_volatile.motor_servo_state.set_cmds_from_power(&power_commanded);
-
-            state_volatile.motor_servo_state.send_to_rotors(state_volatile.arm_status, motor_timer);
         } else {
-            let ctrl_mix = ctrl_logic::ctrl_mix_from_att(
+            let mut ctrl_mix = ctrl_logic::ctrl_mix_from_att(
                 state_volatile.attitude_commanded.quat.unwrap(),
                 params.attitude_quat,
                 params.attitude_quat_dt,
@@ -128,12 +321,24 @@ pub fn run(
                 &state_volatile.drag_coeffs,
                 &state_volatile.accel_maps,
                 flight_ctrl_filters,
-                DT_IMU,
+                rates.dt_imu,
                 pid_coeffs,
                 has_taken_off,
             );
 
-            let ctrl_sfc_posits = CtrlSfcPosits::from_mix(&ctrl_mix, state_volatile.motor_servo_state.frontleft_aftright_dir);
+            state_volatile.authority.apply(&mut ctrl_mix, authority_cfg);
+
+            let diff_thrust_max = state_volatile
+                .motor_servo_state
+                .motor_thrust2
+                .as_ref()
+                .map(|_| state_volatile.motor_servo_state.diff_thrust_max);
+
+            let ctrl_sfc_posits = CtrlSfcPosits::from_mix(
+                &ctrl_mix,
+                &state_volatile.motor_servo_state.surface_mix,
+                diff_thrust_max,
+            );
             state_volatile.ctrl_mix = ctrl_mix;
 
             state_volatile.motor_servo_state.set_cmds_from_control_posits(
@@ -142,43 +347,112 @@ pub fn run(
                 pid_coeffs,
             );
 
-            // This is what causes the actual change in motor speed, via DSHOT.
-            state_volatile.motor_servo_state.send_to_motors(ArmStatus::MotorsControlsArmed, motor_timer);
-
             // This is what causes the actual change in servo position, via PWM.
             state_volatile.motor_servo_state.send_to_servos(ArmStatus::MotorsControlsArmed, servo_timer);
         }
     }
 }
 
+/// Send the motor commands set by `run`, via DSHOT. This is separate so the caller only locks the
+/// motor timer for the transmission, and not the control computation; the RPM edge ISRs, CRSF,
+/// and the IMU data-ready ISR are all below that lock's ceiling.
+pub fn send_motor_cmds(
+    state_volatile: &mut StateVolatile,
+    control_mapping: &ControlMapping,
+    output_smoothing: &OutputSmoothingCfg,
+    motor_timer: &mut MotorTimer,
+) {
+    #[cfg(feature = "quad")]
+    state_volatile.motor_servo_state.send_to_rotors(
+        state_volatile.arm_status,
+        control_mapping,
+        output_smoothing,
+        motor_timer,
+    );
+
+    // With controls armed only, this stops the motor.
+    #[cfg(feature = "fixed-wing")]
+    state_volatile.motor_servo_state.send_to_motors(
+        state_volatile.arm_status,
+        control_mapping,
+        output_smoothing,
+        motor_timer,
+    );
+}
+
 /// Entry point for logging acceleration map points. (Mapping target angular acceleration to
-/// RPM, motor power settings, or servo positions.
-pub fn log_accel_pts(state_volatile: &mut StateVolatile, params: &Params, timestamp: f32) {
-    // Log angular accel from RPM or servo posit delta.
-    // Code-shorteners
+/// RPM, motor power settings, or servo positions. Only logs while flying; on the ground, contact
+/// forces dominate angular acceleration. Updates control authority scaling from them.
+pub fn log_accel_pts(
+    state_volatile: &mut StateVolatile,
+    params: &Params,
+    authority_cfg: &AuthorityCfg,
+    timestamp: f32,
+) {
+    if state_volatile.arm_status != safety::ArmStatus::Armed || !state_volatile.has_taken_off {
+        return;
+    }
+
+    // Log angular accel from RPM or servo posit delta, as pitch, roll, and yaw deltas.
     #[cfg(feature = "quad")]
-    let ctrl_cmds = state_volatile.motor_servo_state.get_power_settings();
+    let (pitch, roll, yaw) = {
+        let dir = state_volatile.motor_servo_state.frontleft_aftright_dir;
+
+        // With RPM telemetry, we use filtered RPM; raw readings are too quantized to difference.
+        // If any is stale, we skip this point, vice mixing in power settings.
+        if dshot::BIDIR_EN {
+            let Some(rpm) = state_volatile.rpm_lpf.motor_rpm(timestamp) else {
+                state_volatile.authority.freeze();
+                return;
+            };
+            (rpm.pitch_delta(), rpm.roll_delta(), rpm.yaw_delta(dir))
+        } else {
+            let power = state_volatile.motor_servo_state.get_power_settings();
+            (
+                power.pitch_delta(),
+                power.roll_delta(),
+                power.yaw_delta(dir),
+            )
+        }
+    };
+
     #[cfg(feature = "fixed-wing")]
-    let ctrl_cmds = state_volatile.motor_servo_state.get_ctrl_positions();
+    let (pitch, roll, yaw) = {
+        let posits = state_volatile.motor_servo_state.get_ctrl_positions();
+        let mix = &state_volatile.motor_servo_state.surface_mix;
+        (
+            posits.pitch_delta(mix),
+            posits.roll_delta(mix),
+            posits.yaw_delta(mix),
+        )
+    };
 
     state_volatile.accel_maps.log_pt(
         AccelMapPt {
             angular_accel: params.a_pitch,
-            ctrl_cmd: ctrl_cmds.pitch_delta(),
+            ctrl_cmd: pitch,
             timestamp,
         },
         AccelMapPt {
             angular_accel: params.a_roll,
-            ctrl_cmd: ctrl_cmds.roll_delta(),
+            ctrl_cmd: roll,
             timestamp,
         },
         AccelMapPt {
             angular_accel: params.a_yaw,
-            #[cfg(feature = "quad")]
-            ctrl_cmd: ctrl_cmds.yaw_delta(state_volatile.motor_servo_state.frontleft_aftright_dir),
-            #[cfg(feature = "fixed-wing")]
-            ctrl_cmd: ctrl_cmds.yaw_delta(),
+            ctrl_cmd: yaw,
             timestamp,
         },
     );
+
+    state_volatile.authority.update(
+        &state_volatile.accel_maps,
+        &mut state_volatile.drag_coeffs,
+        (pitch, roll, yaw),
+        (params.a_pitch, params.a_roll, params.a_yaw),
+        (params.v_pitch, params.v_roll, params.v_yaw),
+        &state_volatile.ctrl_mix,
+        state_volatile.yaw_removed,
+        authority_cfg,
+    );
 }
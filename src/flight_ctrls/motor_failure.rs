@@ -0,0 +1,150 @@
+//! This module contains motor failure handling, for quads. Using bidirectional DSHOT RPM readings,
+//! we detect a motor or ESC that's stopped in flight: Commanded above a threshold power, but
+//! reporting near-zero, or no RPM. A quad can stay partly controllable on the remaining three
+//! motors by giving up yaw control.
+//!
+//! Once a failure is detected, the mixer holds the failed motor at idle, and removes throttle from
+//! the motor diagonally opposite it, which then only balances pitch and roll. The other two motors,
+//! each adjacent to the failed one, carry the weight. They spin the same direction, so their
+//! torques don't cancel, and the aircraft spins in yaw; which way depends on the configured
+//! rotation direction (props in or out). We then command a steady descent. This lasts until
+//! disarm.
+//!
+//! Fixed-wing doesn't use this.
+
+use crate::imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS;
+
+#[cfg(feature = "quad")]
+use super::{motor_servo::MIN_ROTOR_RPM, RotorPosition};
+
+// A motor above this power, 0. to 1., must report RPM.
+#[cfg(feature = "quad")]
+const POWER_THRESH: f32 = 0.2;
+
+// Seconds a motor is above `POWER_THRESH` without reporting RPM before we consider it failed.
+#[cfg(feature = "quad")]
+const FAIL_TIME: f32 = 0.25;
+
+// Descent thrust outside this range, as a portion of hover thrust, is rejected when loading
+// config.
+const DESCENT_THRUST_MIN: f32 = 0.5;
+const DESCENT_THRUST_MAX: f32 = 1.;
+
+// Serialized size: Enabled, descent thrust, and yaw authority.
+pub const MOTOR_FAIL_CFG_SIZE: usize = 1 + 4 * 2;
+
+/// Motor failure handling settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct MotorFailCfg {
+    pub enabled: bool,
+    /// Thrust commanded after a failure, as a portion of the hover thrust estimate. Below 1., so
+    /// we descend.
+    pub descent_thrust: f32,
+    /// The portion of the yaw command kept after a failure, 0. to 1. At 0., we accept the spin,
+    /// and keep all remaining authority for pitch and roll. Higher values slow the spin, at the
+    /// cost of pitch and roll authority.
+    pub yaw_authority: f32,
+}
+
+impl Default for MotorFailCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            descent_thrust: 0.85,
+            yaw_authority: 0.,
+        }
+    }
+}
+
+impl MotorFailCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let descent_thrust = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+        let yaw_authority = f32::from_be_bytes(buf[5..9].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if buf[0] > 1
+            || !(DESCENT_THRUST_MIN..=DESCENT_THRUST_MAX).contains(&descent_thrust)
+            || !(0. ..=1.).contains(&yaw_authority)
+        {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            descent_thrust,
+            yaw_authority,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; MOTOR_FAIL_CFG_SIZE] {
+        let mut result = [0; MOTOR_FAIL_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1..5].clone_from_slice(&self.descent_thrust.to_be_bytes());
+        result[5..9].clone_from_slice(&self.yaw_authority.to_be_bytes());
+        result
+    }
+}
+
+/// Tracks how long each motor has been powered without reporting RPM.
+#[derive(Default)]
+pub struct MotorFailureDetect {
+    /// Seconds, in the order of `MotorServoState::rotor_rpms`.
+    dead_time: [f32; NUM_RPM_NOTCH_MOTORS],
+}
+
+#[cfg(feature = "quad")]
+impl MotorFailureDetect {
+    /// Run each flight control update, with power settings and RPM readings in the order of
+    /// `MotorServoState::rotor_rpms`. Only active while armed and airborne. Returns the failed
+    /// rotor, once a single one has been dead for `FAIL_TIME`.
+    pub fn update(
+        &mut self,
+        powers: &[f32; NUM_RPM_NOTCH_MOTORS],
+        rpms: &[Option<f32>; NUM_RPM_NOTCH_MOTORS],
+        motors_armed: bool,
+        has_taken_off: bool,
+        dt: f32,
+    ) -> Option<RotorPosition> {
+        // If no motors report RPM, eg bidirectional DSHOT isn't working, we can't tell a failed
+        // motor from missing telemetry.
+        let reporting = rpms.iter().filter(|r| r.is_some()).count();
+
+        if !motors_armed || !has_taken_off || reporting == 0 {
+            self.dead_time = [0.; NUM_RPM_NOTCH_MOTORS];
+            return None;
+        }
+
+        for i in 0..NUM_RPM_NOTCH_MOTORS {
+            let stopped = match rpms[i] {
+                Some(rpm) => rpm < MIN_ROTOR_RPM,
+                None => true,
+            };
+
+            if powers[i] > POWER_THRESH && stopped {
+                self.dead_time[i] += dt;
+            } else {
+                self.dead_time[i] = 0.;
+            }
+        }
+
+        // With more than one motor out, there's nothing to recover with.
+        let mut failed = self
+            .dead_time
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| **t >= FAIL_TIME);
+
+        match (failed.next(), failed.next()) {
+            (Some((i, _)), None) => RotorPosition::try_from(i as u8).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Throttle to command after a failure, from the hover throttle estimate. Two motors carry the
+/// weight, so each needs about twice its share of hover power.
+pub fn descent_throttle(hover_throttle: f32, cfg: &MotorFailCfg) -> f32 {
+    (2. * hover_throttle * cfg.descent_thrust).min(1.)
+}
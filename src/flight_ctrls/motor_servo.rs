@@ -2,14 +2,28 @@
 //! its more basic data structures apply to both quadcopters and fixed-wing, and aren't
 //! specific to a specific role. The aggregate structures are more specific.
 
-use super::{common::CtrlMix, pid};
+use num_enum::TryFromPrimitive;
+use num_traits::Float;
+
+#[cfg(feature = "fixed-wing")]
+use super::surface_mix::SurfaceMix;
+use super::{common::CtrlMix, control_mapping::ControlMapping, pid};
+#[cfg(feature = "quad")]
+use super::{
+    mixer::{DesatCfg, Mixer},
+    reversible::ThrustDir,
+    RotorPosition,
+};
 use crate::{
-    main_loop::DT_FLIGHT_CTRLS,
+    imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS,
+    loop_rates,
     protocols::{dshot, servo},
     safety::ArmStatus,
     setup::{MotorTimer, ServoTimer},
     util,
 };
+#[cfg(feature = "fixed-wing")]
+use crate::{protocols::servo::NUM_SURFACES, setup as hw_setup};
 
 const MOTOR_CMD_MIN: f32 = 0.03; //  An idle.
 const MOTOR_CMD_MAX: f32 = 1.;
@@ -23,13 +37,119 @@ const SERVO_CMD_MAX: f32 = 1.;
 // const MIN_ROTOR_POWER: f32 = 0.03;
 //
 // Min RPM setting for any individual rotor at idle setting.
-const MIN_ROTOR_RPM: f32 = 100.; // todo: Finda good value.
+pub const MIN_ROTOR_RPM: f32 = 100.; // todo: Finda good value.
 
 // Max power setting for any individual rotor at idle setting.
 pub const MAX_ROTOR_POWER: f32 = 1.;
 
 // todo: End of do we use these.
 
+// If a motor's power setting is above this, but it reports RPM below `MIN_ROTOR_RPM` for
+// `DESYNC_TIME` (seconds), we consider its ESC desynced.
+const DESYNC_POWER_THRESH: f32 = 0.2;
+const DESYNC_TIME: f32 = 0.1;
+
+// Output slew rates above this, in portion of full scale per ms, are rejected.
+const SLEW_RATE_MAX: f32 = 10.;
+// Output smoothing time constants above this, in seconds, are rejected.
+const SMOOTHING_TC_MAX: f32 = 0.05;
+
+// Serialized size: Slew rate, and smoothing time constant.
+pub const OUTPUT_SMOOTHING_CFG_SIZE: usize = 4 * 2;
+
+/// Motor output slew-rate limiting and smoothing configuration. Stored in user config. The defaults
+/// don't measurably affect step response; they take the edge off stick snaps.
+#[derive(Clone, Copy)]
+pub struct OutputSmoothingCfg {
+    /// Max change in each motor's output per ms, as a portion of full scale. (Power, or the RPM
+    /// setpoint when controlling RPM)
+    pub slew_rate: f32,
+    /// Seconds. Time constant of a first-order lowpass filter on each output. 0. disables it.
+    pub time_constant: f32,
+}
+
+impl Default for OutputSmoothingCfg {
+    fn default() -> Self {
+        Self {
+            slew_rate: 0.5,
+            time_constant: 0.,
+        }
+    }
+}
+
+impl OutputSmoothingCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let slew_rate = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let time_constant = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(slew_rate > 0. && slew_rate <= SLEW_RATE_MAX)
+            || !(0. ..=SMOOTHING_TC_MAX).contains(&time_constant)
+        {
+            return None;
+        }
+
+        Some(Self {
+            slew_rate,
+            time_constant,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; OUTPUT_SMOOTHING_CFG_SIZE] {
+        let mut result = [0; OUTPUT_SMOOTHING_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.slew_rate.to_be_bytes());
+        result[4..8].clone_from_slice(&self.time_constant.to_be_bytes());
+        result
+    }
+}
+
+/// Applies `OutputSmoothingCfg` to motor outputs, just prior to sending them.
+#[derive(Default)]
+pub struct OutputSmoother {
+    /// The previous outputs. `None` until the first armed update, so we don't slew from stale
+    /// values.
+    prev: Option<[f32; 4]>,
+}
+
+impl OutputSmoother {
+    /// Run on disarm, so the first output after arming passes through directly.
+    pub fn reset(&mut self) {
+        self.prev = None;
+    }
+
+    /// `full_scale` is what the slew rate is relative to; 1. for power.
+    pub fn apply(
+        &mut self,
+        outputs: [f32; 4],
+        cfg: &OutputSmoothingCfg,
+        full_scale: f32,
+        dt: f32,
+    ) -> [f32; 4] {
+        let Some(prev) = self.prev else {
+            self.prev = Some(outputs);
+            return outputs;
+        };
+
+        let max_step = cfg.slew_rate * full_scale * dt * 1_000.;
+        let alpha = if cfg.time_constant > 0. {
+            dt / (cfg.time_constant + dt)
+        } else {
+            1.
+        };
+
+        let mut result = [0.; 4];
+        for i in 0..4 {
+            let smoothed = prev[i] + (outputs[i] - prev[i]) * alpha;
+            result[i] = prev[i] + (smoothed - prev[i]).clamp(-max_step, max_step);
+        }
+
+        self.prev = Some(result);
+        result
+    }
+}
+
 #[derive(Default)]
 pub struct RpmCmd {
     /// The RPM commanded.
@@ -58,11 +178,20 @@ impl MotorCmd {
         }
     }
 
-    /// Clamp power and/or RPM commands.
+    /// Clamp power and/or RPM commands. In 3D mode, power is negative in reverse, and 0. while
+    /// stopped; see `reversible`.
     pub fn clamp(&mut self) {
         match self {
             Self::Power(c) => {
-                *c = c.clamp(MOTOR_CMD_MIN, MOTOR_CMD_MAX);
+                *c = if !dshot::reversible() {
+                    c.clamp(MOTOR_CMD_MIN, MOTOR_CMD_MAX)
+                } else if *c > 0. {
+                    c.clamp(MOTOR_CMD_MIN, MOTOR_CMD_MAX)
+                } else if *c < 0. {
+                    c.clamp(-MOTOR_CMD_MAX, -MOTOR_CMD_MIN)
+                } else {
+                    0.
+                };
             }
             Self::Rpm(c) => {
                 c.rpm_cmd = c.rpm_cmd.clamp(MOTOR_RPM_MIN, MOTOR_RPM_MAX);
@@ -72,7 +201,7 @@ impl MotorCmd {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
 #[repr(u8)] // u8 repr for serializing via USB.
 pub enum RotationDir {
     Clockwise = 0,
@@ -85,8 +214,14 @@ pub struct MotorState {
     /// None indicates no reading.
     pub cmd: MotorCmd,
     pub power_setting: f32,
-    // todo: When do we set this??a
+    /// The latest RPM reading. `None` if the latest decode failed, or no edges were captured
+    /// during the receive window; we don't hold the previous value.
     pub rpm_reading: Option<f32>, // todo: This state is repatative with `rpm_readings`.
+    /// Seconds since start of the last valid RPM reading.
+    pub rpm_timestamp: Option<f32>,
+    /// Time, in seconds, the motor has been powered above `DESYNC_POWER_THRESH` while reporting
+    /// near-zero RPM.
+    low_rpm_time: f32,
     // pub dir: RotationDir, // todo: Do we want this?
     /// Reversed is in relation to the 3-wire motor brushless wiring. This software setting
     /// allows the wires to be connected in any order, and compensated for in software. (Eg by
@@ -95,18 +230,54 @@ pub struct MotorState {
     pub reversed: bool,
 }
 
+impl MotorState {
+    /// Update with the latest RPM reading. `dt` is the time since the last update. Returns `true`
+    /// if the ESC appears desynced.
+    fn update_rpm(&mut self, reading: Option<f32>, timestamp: f32, dt: f32) -> bool {
+        self.rpm_reading = reading;
+
+        let Some(rpm) = reading else {
+            // We can't tell from an invalid reading; hold the desync timer.
+            return self.low_rpm_time >= DESYNC_TIME;
+        };
+
+        self.rpm_timestamp = Some(timestamp);
+
+        if self.power_setting > DESYNC_POWER_THRESH && rpm < MIN_ROTOR_RPM {
+            self.low_rpm_time += dt;
+        } else {
+            self.low_rpm_time = 0.;
+        }
+
+        self.low_rpm_time >= DESYNC_TIME
+    }
+}
+
 /// State of an individual servo.
 #[derive(Clone, Copy, PartialEq, Default)]
 pub struct ServoState {
     /// Commanded position
     pub posit_cmd: f32,
     pub reversed: bool,
+    /// Added to the commanded position. -1. to 1. scale.
+    pub trim: f32,
 }
 
 impl ServoState {
     pub fn clamp(&mut self) {
         self.posit_cmd = self.posit_cmd.clamp(SERVO_CMD_MIN, SERVO_CMD_MAX);
     }
+
+    /// The position to send to the servo, -1. to 1., after trim and reversal.
+    pub fn output_posit(&self) -> f32 {
+        let posit = (self.posit_cmd + self.trim).clamp(SERVO_CMD_MIN, SERVO_CMD_MAX);
+
+        if self.reversed {
+            -posit
+        } else {
+            posit
+        }
+    }
 }
 
 /// A possible function for a given motor/servo pin
@@ -159,7 +330,7 @@ impl MotorServoRole {
 
 /// Corresponds to pin number. Used to map functions (Such as thrust motor, front-left rotor etc)
 /// to hardware pins. The u8 repr is for Preflight.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, TryFromPrimitive)]
 #[repr(u8)]
 pub enum MotorServoHardware {
     Pin1 = 1,
@@ -195,27 +366,35 @@ pub struct MotorServoState {
     pub servo_aux_2: Option<ServoState>,
 
     pub frontleft_aftright_dir: RotationDir,
+    /// Slew limiting and smoothing of power outputs.
+    pub power_smoother: OutputSmoother,
+    /// Slew limiting and smoothing of RPM setpoints, when controlling RPM.
+    pub rpm_smoother: OutputSmoother,
+    /// Thrust direction in 3D mode. Reset to stopped on disarm.
+    pub thrust_dir: ThrustDir,
 }
 
 #[cfg(feature = "fixed-wing")]
 pub struct MotorServoState {
     pub motor_thrust1_hardware: MotorServoHardware,
     pub motor_thrust2_hardware: Option<MotorServoHardware>,
-    pub elevon_left_hardware: MotorServoHardware,
-    pub elevon_right_hardware: MotorServoHardware,
-    pub rudder_hardware: Option<MotorServoHardware>,
+    /// By control surface; see `ControlMapping::surfaces`.
+    pub surface_hardware: [Option<MotorServoHardware>; NUM_SURFACES],
     pub servo_aux_1_hardware: Option<MotorServoHardware>,
     pub servo_aux_2_hardware: Option<MotorServoHardware>,
 
     pub motor_thrust1: MotorState,
     pub motor_thrust2: Option<MotorState>,
-    /// Elevons are similar to stabilators.
-    pub elevon_left: ServoState,
-    pub elevon_right: ServoState,
-    pub rudder: Option<ServoState>,
+    /// `None` for surfaces not present.
+    pub surfaces: [Option<ServoState>; NUM_SURFACES],
     pub servo_aux_1: Option<ServoState>,
     pub servo_aux_2: Option<ServoState>,
-    // todo: More A/R, eg ailerons, elevator etc.
+    /// Max differential thrust, as a portion of throttle. Only used if `motor_thrust2` is present.
+    pub diff_thrust_max: f32,
+    /// How pitch, roll, and yaw commands map to the surfaces.
+    pub surface_mix: SurfaceMix,
+    /// Slew limiting and smoothing of thrust motor power.
+    pub power_smoother: OutputSmoother,
 }
 
 impl Default for MotorServoState {
@@ -237,25 +416,37 @@ impl Default for MotorServoState {
             servo_aux_2: None,
 
             frontleft_aftright_dir: RotationDir::Clockwise,
+            power_smoother: Default::default(),
+            rpm_smoother: Default::default(),
+            thrust_dir: Default::default(),
         };
 
         #[cfg(feature = "fixed-wing")]
         return Self {
             motor_thrust1_hardware: MotorServoHardware::Pin1,
             motor_thrust2_hardware: None,
-            elevon_left_hardware: MotorServoHardware::Pin3,
-            elevon_right_hardware: MotorServoHardware::Pin2,
-            rudder_hardware: None,
+            surface_hardware: [
+                Some(MotorServoHardware::Pin3),
+                Some(MotorServoHardware::Pin2),
+                None,
+                None,
+            ],
             servo_aux_1_hardware: None,
             servo_aux_2_hardware: None,
 
             motor_thrust1: Default::default(),
             motor_thrust2: None,
-            elevon_left: Default::default(),
-            elevon_right: Default::default(),
-            rudder: None,
+            surfaces: [
+                Some(Default::default()),
+                Some(Default::default()),
+                None,
+                None,
+            ],
             servo_aux_1: None,
             servo_aux_2: None,
+            diff_thrust_max: 0.,
+            surface_mix: Default::default(),
+            power_smoother: Default::default(),
         };
     }
 }
@@ -266,119 +457,166 @@ impl MotorServoState {
     //
     //     }
 
-    /// Update internal state of RPM readings.
-    pub fn update_rpm_readings(&mut self, readings: &RpmReadings) {
-        self.rotor_front_left.rpm_reading = readings.front_left;
-        self.rotor_front_right.rpm_reading = readings.front_right;
-        self.rotor_aft_left.rpm_reading = readings.aft_left;
-        self.rotor_aft_right.rpm_reading = readings.aft_right;
+    /// RPM readings for each motor, in a fixed order, for use with RPM filtering.
+    pub fn rotor_rpms(&self) -> [Option<f32>; NUM_RPM_NOTCH_MOTORS] {
+        #[cfg(feature = "quad")]
+        return [
+            self.rotor_front_left.rpm_reading,
+            self.rotor_front_right.rpm_reading,
+            self.rotor_aft_left.rpm_reading,
+            self.rotor_aft_right.rpm_reading,
+        ];
+
+        #[cfg(feature = "fixed-wing")]
+        return [
+            self.motor_thrust1.rpm_reading,
+            self.motor_thrust2.as_ref().and_then(|m| m.rpm_reading),
+            None,
+            None,
+        ];
+    }
+
+    /// Power settings for each motor, 0. to 1., in the same order as `rotor_rpms`.
+    pub fn rotor_powers(&self) -> [f32; NUM_RPM_NOTCH_MOTORS] {
+        #[cfg(feature = "quad")]
+        return [
+            self.rotor_front_left.power_setting,
+            self.rotor_front_right.power_setting,
+            self.rotor_aft_left.power_setting,
+            self.rotor_aft_right.power_setting,
+        ];
+
+        #[cfg(feature = "fixed-wing")]
+        return [
+            self.motor_thrust1.power_setting,
+            self.motor_thrust2.as_ref().map_or(0., |m| m.power_setting),
+            0.,
+            0.,
+        ];
+    }
+
+    /// Update internal state of RPM readings. `dt` is the time since the last update. Returns
+    /// desync flags for each motor, in the same order as `rotor_rpms`.
+    #[cfg(feature = "quad")]
+    pub fn update_rpm_readings(
+        &mut self,
+        readings: &RpmReadings,
+        timestamp: f32,
+        dt: f32,
+    ) -> [bool; NUM_RPM_NOTCH_MOTORS] {
+        [
+            self.rotor_front_left
+                .update_rpm(readings.front_left, timestamp, dt),
+            self.rotor_front_right
+                .update_rpm(readings.front_right, timestamp, dt),
+            self.rotor_aft_left
+                .update_rpm(readings.aft_left, timestamp, dt),
+            self.rotor_aft_right
+                .update_rpm(readings.aft_right, timestamp, dt),
+        ]
+    }
+
+    #[cfg(feature = "fixed-wing")]
+    pub fn update_rpm_readings(
+        &mut self,
+        readings: &RpmReadings,
+        timestamp: f32,
+        dt: f32,
+    ) -> [bool; NUM_RPM_NOTCH_MOTORS] {
+        [
+            self.motor_thrust1
+                .update_rpm(readings.thrust1, timestamp, dt),
+            self.motor_thrust2
+                .as_mut()
+                .map(|m| m.update_rpm(readings.thrust2, timestamp, dt))
+                .unwrap_or(false),
+            false,
+            false,
+        ]
     }
 
     /// Populate command state from rotor RPMs. This both marks the target RPM,
-    /// and calculates an instantaneous power level to achieve it.
+    /// and calculates an instantaneous power level to achieve it, using PID, with optional
+    /// battery-voltage-aware setpoint limiting and feedforward.
     ///
     /// Note that RPMs must already be updated in this instance.
     #[cfg(feature = "quad")]
     pub fn set_cmds_from_rpms(
         &mut self,
         rpms_commanded: &MotorRpm,
-        // pid_group: &pid::MotorPidGroup,
+        pid_group: &mut pid::MotorPidGroup,
         pid_coeffs: &pid::MotorCoeffs,
+        rpm_cfg: &pid::RpmCtrlCfg,
+        smoothing: &OutputSmoothingCfg,
+        batt_v: f32,
     ) {
-        // todo: DRY
-        // Calculate target RPMS, using our PID logic.
-        // let p_front_left = match self.rotor_front_left.rpm_reading {
-        //     Some(reading) => {
-        //         pid::run(
-        //             rpms_commanded.front_left,
-        //             reading,
-        //             &pid_group.front_left,
-        //             pid_coeffs.p_front_left,
-        //             pid_coeffs.i_front_left,
-        //             0.,
-        //             None,
-        //             DT_FLIGHT_CTRLS,
-        //         )
-        //         .out()
-        //             + self.rotor_front_left.cmd.power()
-        //     }
-        //     None => 0.,
-        // };
-        //
-        // let p_front_right = match self.rotor_front_right.rpm_reading {
-        //     Some(reading) => {
-        //         pid::run(
-        //             rpms_commanded.front_right,
-        //             reading,
-        //             &pid_group.front_right,
-        //             pid_coeffs.p_front_right,
-        //             pid_coeffs.i_front_right,
-        //             0.,
-        //             None,
-        //             DT_FLIGHT_CTRLS,
-        //         )
-        //         .out()
-        //             + self.rotor_front_right.cmd.power()
-        //     }
-        //     None => 0.,
-        // };
-        //
-        // let p_aft_left = match self.rotor_aft_left.rpm_reading {
-        //     Some(reading) => {
-        //         pid::run(
-        //             rpms_commanded.aft_left,
-        //             reading,
-        //             &pid_group.aft_left,
-        //             pid_coeffs.p_aft_left,
-        //             pid_coeffs.i_aft_left,
-        //             0.,
-        //             None,
-        //             DT_FLIGHT_CTRLS,
-        //         )
-        //         .out()
-        //             + self.rotor_aft_left.cmd.power()
-        //     }
-        //     None => 0.,
-        // };
-        //
-        // let p_aft_right = match self.rotor_aft_right.rpm_reading {
-        //     Some(reading) => {
-        //         pid::run(
-        //             rpms_commanded.aft_right,
-        //             reading,
-        //             &pid_group.aft_right,
-        //             pid_coeffs.p_aft_right,
-        //             pid_coeffs.i_aft_right,
-        //             0.,
-        //             None,
-        //             DT_FLIGHT_CTRLS,
-        //         )
-        //         .out()
-        //             + self.rotor_aft_right.cmd.power()
-        //     }
-        //     None => 0.,
-        // };
-        let p_front_left = 0.;
-        let p_front_right = 0.;
-        let p_aft_left = 0.;
-        let p_aft_right = 0.;
-
-        self.rotor_front_left.cmd = MotorCmd::Rpm(RpmCmd {
-            rpm_cmd: rpms_commanded.front_left,
-            pwr_calculated: p_front_left,
-        });
-        self.rotor_front_right.cmd = MotorCmd::Rpm(RpmCmd {
-            rpm_cmd: rpms_commanded.front_right,
-            pwr_calculated: p_front_right,
-        });
-        self.rotor_aft_left.cmd = MotorCmd::Rpm(RpmCmd {
-            rpm_cmd: rpms_commanded.aft_left,
-            pwr_calculated: p_aft_left,
-        });
-        self.rotor_aft_right.cmd = MotorCmd::Rpm(RpmCmd {
-            rpm_cmd: rpms_commanded.aft_right,
-            pwr_calculated: p_aft_right,
-        });
+        let c = pid_coeffs; // code shortener
+
+        let [sp_fl, sp_fr, sp_al, sp_ar] = self.rpm_smoother.apply(
+            [
+                rpms_commanded.front_left,
+                rpms_commanded.front_right,
+                rpms_commanded.aft_left,
+                rpms_commanded.aft_right,
+            ],
+            smoothing,
+            MOTOR_RPM_MAX,
+            loop_rates::rates().dt_flight_ctrls,
+        );
+
+        for (rotor, setpoint, pid_state, p, i) in [
+            (
+                &mut self.rotor_front_left,
+                sp_fl,
+                &mut pid_group.front_left,
+                c.p_front_left,
+                c.i_front_left,
+            ),
+            (
+                &mut self.rotor_front_right,
+                sp_fr,
+                &mut pid_group.front_right,
+                c.p_front_right,
+                c.i_front_right,
+            ),
+            (
+                &mut self.rotor_aft_left,
+                sp_al,
+                &mut pid_group.aft_left,
+                c.p_aft_left,
+                c.i_aft_left,
+            ),
+            (
+                &mut self.rotor_aft_right,
+                sp_ar,
+                &mut pid_group.aft_right,
+                c.p_aft_right,
+                c.i_aft_right,
+            ),
+        ] {
+            let setpoint = rpm_cfg.limit_setpoint(setpoint, batt_v);
+
+            // Without a valid reading, estimate the power from the setpoint, vice closing the loop
+            // on a stale value.
+            let pwr_calculated = match rotor.rpm_reading {
+                Some(reading) => pid_state.apply(
+                    setpoint,
+                    reading,
+                    p,
+                    i,
+                    rpm_cfg.feedforward(setpoint, batt_v),
+                    loop_rates::rates().dt_flight_ctrls,
+                ),
+                None => rpm_cfg
+                    .power_estimate(setpoint, batt_v)
+                    .unwrap_or(rotor.power_setting),
+            };
+
+            rotor.cmd = MotorCmd::Rpm(RpmCmd {
+                rpm_cmd: setpoint,
+                pwr_calculated,
+            });
+        }
 
         self.clamp_cmds();
     }
@@ -406,9 +644,9 @@ impl MotorServoState {
     #[cfg(feature = "fixed-wing")]
     pub fn get_ctrl_positions(&self) -> CtrlSfcPosits {
         CtrlSfcPosits {
-            elevon_left: self.elevon_left.posit_cmd,
-            elevon_right: self.elevon_right.posit_cmd,
-            rudder: None, //todo! And differential thrust between motors A/R.
+            surfaces: self.surfaces.map(|s| s.map_or(0., |s| s.posit_cmd)),
+            thrust1: self.motor_thrust1.cmd.power(),
+            thrust2: self.motor_thrust2.as_ref().map(|m| m.cmd.power()),
         }
     }
 
@@ -425,11 +663,15 @@ impl MotorServoState {
 
     #[cfg(feature = "fixed-wing")]
     pub fn set_cmds_from_control_posits(&mut self, posits: &CtrlSfcPosits) {
-        self.elevon_left.posit_cmd = posits.elevon_left;
-        self.elevon_right.posit_cmd = posits.elevon_right;
+        for (surface, posit) in self.surfaces.iter_mut().zip(posits.surfaces) {
+            if let Some(s) = surface {
+                s.posit_cmd = posit;
+            }
+        }
 
-        if let Some(mut r) = self.rudder {
-            r.posit_cmd = posits.rudder.unwrap_or(0.);
+        self.motor_thrust1.cmd = MotorCmd::Power(posits.thrust1);
+        if let (Some(m), Some(p)) = (&mut self.motor_thrust2, posits.thrust2) {
+            m.cmd = MotorCmd::Power(p);
         }
 
         self.clamp_cmds();
@@ -452,107 +694,65 @@ impl MotorServoState {
             m.cmd.clamp();
         }
 
-        self.elevon_left.clamp();
-        self.elevon_right.clamp();
-
-        if let Some(r) = &mut self.rudder {
-            r.clamp();
+        for s in self.surfaces.iter_mut().flatten() {
+            s.clamp();
         }
     }
 
     /// Send commands to all rotors. This uses a single DSHOT command. Assumes power level
     /// to achieve the target RPM is already applied.
     #[cfg(feature = "quad")]
-    pub fn send_to_rotors(&mut self, arm_status: ArmStatus, motor_timer: &mut MotorTimer) {
-        let mut p1 = 0.;
-        let mut p2 = 0.;
-        let mut p3 = 0.;
-        let mut p4 = 0.;
-
-        let p_fl = self.rotor_front_left.cmd.power();
-        let p_fr = self.rotor_front_right.cmd.power();
-        let p_al = self.rotor_aft_left.cmd.power();
-        let p_ar = self.rotor_aft_right.cmd.power();
-
-        // Map from rotor position to motor number.
-        // todo DRY
-        // todo: This process doesn't elegantly handle mismapped pins.
-        match self.rotor_front_left_hardware {
-            MotorServoHardware::Pin1 => {
-                p1 = p_fl;
-            }
-            MotorServoHardware::Pin2 => {
-                p2 = p_fl;
-            }
-            MotorServoHardware::Pin3 => {
-                p3 = p_fl;
-            }
-            MotorServoHardware::Pin4 => {
-                p4 = p_fl;
-            }
-            _ => (), // todo
-        }
-
-        match self.rotor_front_right_hardware {
-            MotorServoHardware::Pin1 => {
-                p1 = p_fr;
-            }
-            MotorServoHardware::Pin2 => {
-                p2 = p_fr;
-            }
-            MotorServoHardware::Pin3 => {
-                p3 = p_fr;
-            }
-            MotorServoHardware::Pin4 => {
-                p4 = p_fr;
-            }
-            _ => (), // todo
-        }
-
-        match self.rotor_aft_left_hardware {
-            MotorServoHardware::Pin1 => {
-                p1 = p_al;
-            }
-            MotorServoHardware::Pin2 => {
-                p2 = p_al;
-            }
-            MotorServoHardware::Pin3 => {
-                p3 = p_al;
-            }
-            MotorServoHardware::Pin4 => {
-                p4 = p_al;
-            }
-            _ => (), // todo
-        }
-
-        match self.rotor_aft_right_hardware {
-            MotorServoHardware::Pin1 => {
-                p1 = p_ar;
-            }
-            MotorServoHardware::Pin2 => {
-                p2 = p_ar;
-            }
-            MotorServoHardware::Pin3 => {
-                p3 = p_ar;
-            }
-            MotorServoHardware::Pin4 => {
-                p4 = p_ar;
-            }
-            _ => (), // todo
-        }
+    pub fn send_to_rotors(
+        &mut self,
+        arm_status: ArmStatus,
+        mapping: &ControlMapping,
+        smoothing: &OutputSmoothingCfg,
+        motor_timer: &mut MotorTimer,
+    ) {
+        let power = [
+            self.rotor_front_left.cmd.power(),
+            self.rotor_front_right.cmd.power(),
+            self.rotor_aft_left.cmd.power(),
+            self.rotor_aft_right.cmd.power(),
+        ];
 
         match arm_status {
             ArmStatus::Armed => {
+                // When controlling RPM, we smooth the setpoints instead.
+                let power = match self.rotor_front_left.cmd {
+                    MotorCmd::Power(_) => self.power_smoother.apply(
+                        power,
+                        smoothing,
+                        1.,
+                        loop_rates::rates().dt_flight_ctrls,
+                    ),
+                    MotorCmd::Rpm(_) => power,
+                };
+
+                let [front_left, front_right, aft_left, aft_right] = power;
+                let power = MotorPower {
+                    front_left,
+                    front_right,
+                    aft_left,
+                    aft_right,
+                };
+
+                let [p1, p2, p3, p4] = power.to_motor_order(mapping);
                 dshot::set_power(p1, p2, p3, p4, motor_timer);
 
-                self.rotor_front_left.power_setting = p_fl;
-                self.rotor_front_right.power_setting = p_fr;
-                self.rotor_aft_left.power_setting = p_al;
-                self.rotor_aft_right.power_setting = p_ar;
+                self.rotor_front_left.power_setting = power.front_left;
+                self.rotor_front_right.power_setting = power.front_right;
+                self.rotor_aft_left.power_setting = power.aft_left;
+                self.rotor_aft_right.power_setting = power.aft_right;
             }
             ArmStatus::Disarmed => {
+                // Stopping bypasses smoothing.
                 dshot::stop_all(motor_timer);
 
+                self.power_smoother.reset();
+                self.rpm_smoother.reset();
+                self.thrust_dir = ThrustDir::Stopped;
+
                 self.rotor_front_left.power_setting = 0.;
                 self.rotor_front_right.power_setting = 0.;
                 self.rotor_aft_left.power_setting = 0.;
@@ -564,112 +764,69 @@ impl MotorServoState {
     /// Send commands to all thrust motors. This uses a single DSHOT command. Assumes power level
     /// to achieve the target RPM is already applied.
     #[cfg(feature = "fixed-wing")]
-    pub fn send_to_motors(&self, arm_status: ArmStatus, motor_timer: &mut MotorTimer) {
-        let mut p1 = 0.;
-        let mut p2 = 0.;
-        let mut p3 = 0.;
-        let mut p4 = 0.;
-        let mut p5 = 0.;
-        let mut p6 = 0.;
-
-        // todo: p5 and p6 are currently unused in our DSHOT setup.
-
-        let p_thrust1 = self.motor_thrust1.cmd.power();
-
-        // Map from rotor position to motor number.
-        // todo DRY
-        // todo: This process doesn't elegantly handle mismapped pins.
-        match self.motor_thrust1_hardware {
-            MotorServoHardware::Pin1 => {
-                p1 = p_thrust1;
-            }
-            MotorServoHardware::Pin2 => {
-                p2 = p_thrust1;
-            }
-            MotorServoHardware::Pin3 => {
-                p3 = p_thrust1;
-            }
-            MotorServoHardware::Pin4 => {
-                p4 = p_thrust1;
-            }
-            MotorServoHardware::Pin5 => {
-                p5 = p_thrust1;
-            }
-            MotorServoHardware::Pin6 => {
-                p6 = p_thrust1;
-            }
-        }
-
-        if let Some(motor_thrust2) = &self.motor_thrust2 {
-            let p_thrust2 = motor_thrust2.cmd.power();
-
-            // Note: This unwrap assumes the Some/None is synced between `motor_thrust2` and
-            // `motor_thrust2_hardware`.
-            match self.motor_thrust2_hardware.unwrap() {
-                MotorServoHardware::Pin1 => {
-                    p1 = p_thrust2;
-                }
-                MotorServoHardware::Pin2 => {
-                    p2 = p_thrust2;
-                }
-                MotorServoHardware::Pin3 => {
-                    p3 = p_thrust2;
-                }
-                MotorServoHardware::Pin4 => {
-                    p4 = p_thrust2;
-                }
-                MotorServoHardware::Pin5 => {
-                    p5 = p_thrust2;
-                }
-                MotorServoHardware::Pin6 => {
-                    p6 = p_thrust2;
-                }
-            }
-        }
-
+    pub fn send_to_motors(
+        &mut self,
+        arm_status: ArmStatus,
+        mapping: &ControlMapping,
+        smoothing: &OutputSmoothingCfg,
+        motor_timer: &mut MotorTimer,
+    ) {
         match arm_status {
             ArmStatus::MotorsControlsArmed => {
+                let thrust2 = self.motor_thrust2.as_ref().map(|m| m.cmd.power());
+
+                let [thrust1, thrust2_smoothed, _, _] = self.power_smoother.apply(
+                    [
+                        self.motor_thrust1.cmd.power(),
+                        thrust2.unwrap_or(0.),
+                        0.,
+                        0.,
+                    ],
+                    smoothing,
+                    1.,
+                    loop_rates::rates().dt_flight_ctrls,
+                );
+
+                let power = MotorPower {
+                    thrust1,
+                    thrust2: thrust2.map(|_| thrust2_smoothed),
+                };
+
+                let [p1, p2, p3, p4] = power.to_motor_order(mapping);
                 dshot::set_power(p1, p2, p3, p4, motor_timer);
             }
             _ => {
+                // Stopping bypasses smoothing.
                 dshot::stop_all(motor_timer);
+                self.power_smoother.reset();
             }
         }
     }
 
     #[cfg(feature = "fixed-wing")]
-    pub fn send_to_servos(&self, arm_status: ArmStatus, servo_timer: &mut ServoTimer) {
+    pub fn send_to_servos(
+        &self,
+        arm_status: ArmStatus,
+        servo_timer: &mut ServoTimer,
+        servo_cfg: &servo::ServoCfg,
+    ) {
         // todo: In the future, this may apply to quads as well.
 
         if arm_status == ArmStatus::Disarmed {
             return;
         }
 
-        let range_in_l = if self.elevon_left.reversed {
-            (-SERVO_CMD_MIN, -SERVO_CMD_MAX)
-        } else {
-            (SERVO_CMD_MIN, SERVO_CMD_MAX)
-        };
+        for (i, surface) in self.surfaces.iter().enumerate() {
+            let (Some(s), Some(output)) = (surface, self.surface_hardware[i]) else {
+                continue;
+            };
+            // `ControlMapping` validates that surfaces are on servo timer channels.
+            let Some(channel) = hw_setup::servo_channel(output) else {
+                continue;
+            };
 
-        let range_in_r = if self.elevon_right.reversed {
-            (-SERVO_CMD_MIN, -SERVO_CMD_MAX)
-        } else {
-            (SERVO_CMD_MIN, SERVO_CMD_MAX)
-        };
-
-        servo::set_posit(
-            self.elevon_left.posit_cmd,
-            range_in_l,
-            servo_timer,
-            // todo: 1 v 2 and L v 2
-            servo::ServoWing::S1.tim_channel(),
-        );
-        servo::set_posit(
-            self.elevon_right.posit_cmd,
-            range_in_r,
-            servo_timer,
-            servo::ServoWing::S2.tim_channel(),
-        );
+            servo::set_posit(s.output_posit(), &servo_cfg.pulses[i], servo_timer, channel);
+        }
     }
 }
 
@@ -690,7 +847,8 @@ pub struct RpmReadings {
     pub thrust2: Option<f32>,
 }
 
-/// Represents power levels for the rotors. These map from 0. to 1.; 0% to 100% power.
+/// Represents power levels for the rotors. These map from 0. to 1.; 0% to 100% power. In 3D mode,
+/// they're negative in reverse.
 /// Used as a quad-specific output from flight control logic. Passed to the motor state,
 /// which handles application.
 #[cfg(feature = "quad")]
@@ -710,6 +868,23 @@ pub struct MotorPower {
     pub thrust2: Option<f32>,
 }
 
+#[cfg(feature = "fixed-wing")]
+impl MotorPower {
+    /// Convert to motor outputs (Motor 1 - 4), in the order `dshot::set_power` takes. Outputs
+    /// without a thrust motor are 0.
+    pub fn to_motor_order(&self, mapping: &ControlMapping) -> [f32; 4] {
+        let mut result = [0.; 4];
+
+        // `ControlMapping` validates that thrust motors are on distinct outputs, 1 - 4.
+        result[mapping.motor_thrust1 as usize - 1] = self.thrust1;
+        if let (Some(output), Some(power)) = (mapping.motor_thrust2, self.thrust2) {
+            result[output as usize - 1] = power;
+        }
+
+        result
+    }
+}
+
 /// Holds all 4 RPMs, by position.
 /// Used as a quad-specific output from flight control logic. Passed to the motor state,
 /// which handles application.
@@ -734,58 +909,74 @@ pub struct MotorRpm {
 #[cfg(feature = "fixed-wing")]
 #[derive(Default)]
 pub struct CtrlSfcPosits {
-    pub elevon_left: f32,
-    /// `None` if the rudder isn't present.
-    pub elevon_right: f32,
-    pub rudder: Option<f32>,
+    /// By control surface, -1. to 1. 0. for surfaces not present.
+    pub surfaces: [f32; NUM_SURFACES],
+    /// Thrust motor power, 0. to 1. With two motors, this is the left one.
+    pub thrust1: f32,
+    /// `None` if the second thrust motor isn't present.
+    pub thrust2: Option<f32>,
 }
 
+/// Split throttle between left and right thrust motors, for yaw authority. Yaw is -1. to 1.;
+/// positive yaws clockwise (nose right), so adds thrust to the left motor. The differential
+/// is a portion of throttle, so it fades out at low throttle, and neither motor is commanded
+/// below 0. If one motor would exceed full power, we shift both down to keep the differential.
 #[cfg(feature = "fixed-wing")]
-impl CtrlSfcPosits {
-    pub fn from_mix(mix: &CtrlMix) -> Self {
-        let mut elevon_left = 0.;
-        let mut elevon_right = 0.;
-        let mut rudder = 0.;
+pub fn diff_thrust(throttle: f32, yaw: f32, diff_max: f32) -> (f32, f32) {
+    let throttle = throttle.clamp(0., 1.);
+    let diff = throttle * yaw.clamp(-1., 1.) * diff_max;
 
-        elevon_left += mix.pitch;
-        elevon_right += mix.pitch;
+    let mut left = throttle + diff;
+    let mut right = throttle - diff;
 
-        // elevon_left += mix.roll * ROLL_COEFF;
-        // elevon_right -= mix.roll * ROLL_COEFF;
-        //
-        // rudder += mix.pitch * YAW_COEFF;
-
-        elevon_left += mix.roll;
-        elevon_right -= mix.roll;
+    let excess = left.max(right) - 1.;
+    if excess > 0. {
+        left -= excess;
+        right -= excess;
+    }
 
-        rudder += mix.yaw;
+    (left.clamp(0., 1.), right.clamp(0., 1.))
+}
 
-        let mut result = Self {
-            elevon_left,
-            elevon_right,
-            rudder: Some(rudder), // todo?
+#[cfg(feature = "fixed-wing")]
+impl CtrlSfcPosits {
+    /// `diff_thrust_max` is `None` with a single thrust motor.
+    pub fn from_mix(mix: &CtrlMix, surface_mix: &SurfaceMix, diff_thrust_max: Option<f32>) -> Self {
+        let (thrust1, thrust2) = match diff_thrust_max {
+            Some(diff_max) => {
+                let (left, right) = diff_thrust(mix.throttle, mix.yaw, diff_max);
+                (left, Some(right))
+            }
+            None => (mix.throttle, None),
         };
 
-        result
+        Self {
+            surfaces: surface_mix.surface_posits(mix),
+            thrust1,
+            thrust2,
+        }
     }
 
     /// Maps to angular accel. Positive means nose-up pitching.
     /// Note: This is located on a non-equiv struct on Quads (RPMs). This is because
     /// on fixed-wing, we map control commands directly to accel, while
-    pub fn pitch_delta(&self) -> f32 {
-        self.elevon_left + self.elevon_right
+    pub fn pitch_delta(&self, surface_mix: &SurfaceMix) -> f32 {
+        surface_mix.deltas(&self.surfaces).0
     }
 
-    /// Maps to angular accel. Positive means left-wing-up.
+    /// Maps to angular accel, with the roll command's sign.
     /// (See note on `pitch_delta)`.
-    pub fn roll_delta(&self) -> f32 {
-        self.elevon_right - self.elevon_left
+    pub fn roll_delta(&self, surface_mix: &SurfaceMix) -> f32 {
+        surface_mix.deltas(&self.surfaces).1
     }
 
-    pub fn yaw_delta(&self) -> f32 {
-        match self.rudder {
-            Some(r) => r,
-            None => 0.,
+    /// Positive means nose-right. Includes differential thrust, if present.
+    pub fn yaw_delta(&self, surface_mix: &SurfaceMix) -> f32 {
+        let surfaces = surface_mix.deltas(&self.surfaces).2;
+
+        match self.thrust2 {
+            Some(t2) => surfaces + self.thrust1 - t2,
+            None => surfaces,
         }
     }
 }
@@ -869,60 +1060,120 @@ impl MotorRpm {
 
 #[cfg(feature = "quad")]
 impl MotorPower {
-    /// Generate power for each motor, from a control mix.
-    /// Pitch, roll, and yaw are in RPM difference between the sum of each pair.
-    /// todo: DRY with equiv RPM command.
-    pub fn from_mix(mix: &CtrlMix, front_left_dir: RotationDir) -> Self {
-        let baseline_pwr = mix.throttle;
+    /// Generate power for each motor, from a control mix, using the configured mixer. The
+    /// mixer desaturates, so outputs are within idle to 1. Also returns the portion of the yaw
+    /// command removed by desaturation.
+    pub fn from_mix(
+        mix: &CtrlMix,
+        front_left_dir: RotationDir,
+        mixer: &Mixer,
+        idle: f32,
+        desat: &DesatCfg,
+    ) -> (Self, f32) {
+        let ([front_left, front_right, aft_left, aft_right], yaw_removed) =
+            mixer.mix(mix, front_left_dir, idle, desat);
+
+        (
+            Self {
+                front_left,
+                front_right,
+                aft_left,
+                aft_right,
+            },
+            yaw_removed,
+        )
+    }
 
-        let mut front_left = baseline_pwr;
-        let mut front_right = baseline_pwr;
-        let mut aft_left = baseline_pwr;
-        let mut aft_right = baseline_pwr;
+    /// Generate power for each motor after a motor failure. See `Mixer::mix_degraded`.
+    pub fn from_mix_degraded(
+        mix: &CtrlMix,
+        front_left_dir: RotationDir,
+        mixer: &Mixer,
+        failed: RotorPosition,
+        yaw_authority: f32,
+        idle: f32,
+        desat: &DesatCfg,
+    ) -> (Self, f32) {
+        let ([front_left, front_right, aft_left, aft_right], yaw_removed) = mixer.mix_degraded(
+            mix,
+            front_left_dir,
+            failed as usize,
+            yaw_authority,
+            idle,
+            desat,
+        );
 
-        // inputs are a differential between opposing rotor pairs.
-        let half_pitch = mix.pitch / 2.;
-        let half_roll = mix.roll / 2.;
-        let half_yaw = mix.yaw / 2.;
+        (
+            Self {
+                front_left,
+                front_right,
+                aft_left,
+                aft_right,
+            },
+            yaw_removed,
+        )
+    }
 
-        // Nose up for positive pitch.
-        front_left += half_pitch;
-        front_right += half_pitch;
-        aft_left -= half_pitch;
-        aft_right -= half_pitch;
+    pub fn set(&mut self, rotor: RotorPosition, power: f32) {
+        match rotor {
+            RotorPosition::FrontLeft => self.front_left = power,
+            RotorPosition::FrontRight => self.front_right = power,
+            RotorPosition::AftLeft => self.aft_left = power,
+            RotorPosition::AftRight => self.aft_right = power,
+        }
+    }
 
-        // Left side up for positive roll
-        front_left += half_roll;
-        front_right -= half_roll;
-        aft_left += half_roll;
-        aft_right -= half_roll;
+    /// Convert from rotor positions to motor outputs (Motor 1 - 4), in the order
+    /// `dshot::set_power` takes.
+    pub fn to_motor_order(&self, mapping: &ControlMapping) -> [f32; 4] {
+        let mut result = [0.; 4];
+
+        // `ControlMapping` validates that each rotor is on a distinct output, 1 - 4.
+        for (output, power) in [
+            (mapping.front_left, self.front_left),
+            (mapping.front_right, self.front_right),
+            (mapping.aft_left, self.aft_left),
+            (mapping.aft_right, self.aft_right),
+        ] {
+            result[output as usize - 1] = power;
+        }
 
-        // Assumes positive yaw from the IMU means clockwise.
-        // If props rotate in, front-left/aft-right rotors induce a CCW torque on the aircraft.
-        // If props rotate out, these same rotors induce a CW torque.
-        // This code assumes props rotate inwards towards the front and back ends.
-        let yaw = if front_left_dir == RotationDir::Clockwise {
-            half_yaw
-        } else {
-            -half_yaw
-        };
+        result
+    }
 
-        front_left += yaw;
-        front_right -= yaw;
-        aft_left -= yaw;
-        aft_right += yaw;
+    /// Limit how far each motor's power may drop from its previous setting. Rapid drops can
+    /// cause ESC desyncs.
+    pub fn limit_drop(&mut self, prev: &Self, max_drop: f32) {
+        self.front_left = self.front_left.max(prev.front_left - max_drop);
+        self.front_right = self.front_right.max(prev.front_right - max_drop);
+        self.aft_left = self.aft_left.max(prev.aft_left - max_drop);
+        self.aft_right = self.aft_right.max(prev.aft_right - max_drop);
+    }
 
-        let mut result = Self {
-            front_left,
-            front_right,
-            aft_left,
-            aft_right,
-        };
+    /// Each motor's power magnitude. In 3D mode, power is negative in reverse.
+    pub fn abs(&self) -> Self {
+        Self {
+            front_left: self.front_left.abs(),
+            front_right: self.front_right.abs(),
+            aft_left: self.aft_left.abs(),
+            aft_right: self.aft_right.abs(),
+        }
+    }
 
-        // Note: We may also apply this clamp downstream.
-        // result.clamp_cmds();
+    /// Negate each motor's power, for reverse thrust in 3D mode.
+    pub fn negate(&mut self) {
+        self.front_left = -self.front_left;
+        self.front_right = -self.front_right;
+        self.aft_left = -self.aft_left;
+        self.aft_right = -self.aft_right;
+    }
 
-        result
+    /// Add per-motor power, eg from dynamic idle, in the order of `MotorServoState::rotor_rpms`.
+    pub fn add(&mut self, power: &[f32; NUM_RPM_NOTCH_MOTORS]) {
+        self.front_left = (self.front_left + power[0]).min(1.);
+        self.front_right = (self.front_right + power[1]).min(1.);
+        self.aft_left = (self.aft_left + power[2]).min(1.);
+        self.aft_right = (self.aft_right + power[3]).min(1.);
     }
 }
 
@@ -966,3 +1217,82 @@ impl MotorPower {
         self.aft_right *= scaler;
     }
 }
+
+#[cfg(all(test, feature = "quad"))]
+mod tests {
+    use super::*;
+
+    const PINS: [MotorServoHardware; 4] = [
+        MotorServoHardware::Pin1,
+        MotorServoHardware::Pin2,
+        MotorServoHardware::Pin3,
+        MotorServoHardware::Pin4,
+    ];
+
+    /// Every assignment of outputs 1 - 4 to rotors; FL, FR, AL, AR.
+    fn mappings() -> impl Iterator<Item = ControlMapping> {
+        (0..4 * 4 * 4 * 4)
+            .map(|n| [n % 4, n / 4 % 4, n / 16 % 4, n / 64])
+            .filter(|[a, b, c, d]| a != b && a != c && a != d && b != c && b != d && c != d)
+            .map(|[a, b, c, d]| ControlMapping {
+                front_left: PINS[a],
+                front_right: PINS[b],
+                aft_left: PINS[c],
+                aft_right: PINS[d],
+                ..Default::default()
+            })
+    }
+
+    fn output(outputs: &[f32; 4], pin: MotorServoHardware) -> f32 {
+        outputs[pin as usize - 1]
+    }
+
+    #[test]
+    fn each_rotor_on_its_output() {
+        let power = MotorPower {
+            front_left: 0.1,
+            front_right: 0.2,
+            aft_left: 0.3,
+            aft_right: 0.4,
+        };
+
+        assert_eq!(mappings().count(), 24);
+
+        for mapping in mappings() {
+            let outputs = power.to_motor_order(&mapping);
+
+            assert_eq!(output(&outputs, mapping.front_left), power.front_left);
+            assert_eq!(output(&outputs, mapping.front_right), power.front_right);
+            assert_eq!(output(&outputs, mapping.aft_left), power.aft_left);
+            assert_eq!(output(&outputs, mapping.aft_right), power.aft_right);
+        }
+    }
+
+    #[test]
+    fn pitch_up_raises_front_outputs() {
+        let throttle = 0.5;
+        let mix = CtrlMix {
+            pitch: 0.2,
+            roll: 0.,
+            yaw: 0.,
+            throttle,
+        };
+
+        for mapping in mappings() {
+            let (power, _) = MotorPower::from_mix(
+                &mix,
+                mapping.frontleft_aftright_dir,
+                &Mixer::default(),
+                0.05,
+                &DesatCfg::default(),
+            );
+            let outputs = power.to_motor_order(&mapping);
+            let level = 0.05 + throttle * 0.95;
+
+            assert!(output(&outputs, mapping.front_left) > level);
+            assert!(output(&outputs, mapping.front_right) > level);
+            assert!(output(&outputs, mapping.aft_left) < level);
+            assert!(output(&outputs, mapping.aft_right) < level);
+        }
+    }
+}
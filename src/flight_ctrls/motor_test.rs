@@ -0,0 +1,139 @@
+//! This module contains preflight motor tests: Spinning a single motor at low power, for a short
+//! time, so the PC application can verify motor order and direction. Tests are commanded over
+//! USB, and run from the main loop.
+//!
+//! Interlocks: Tests only run in Preflight mode, while disarmed, and after the user has
+//! acknowledged that props are off. A running test stops if we don't receive a keep-alive
+//! message within `KEEPALIVE_TIMEOUT`.
+
+use defmt::println;
+
+use crate::protocols::dshot::Motor;
+
+/// We cap commanded power to this, regardless of what's requested. 0. to 1.
+pub const MAX_POWER: f32 = 0.25;
+/// We cap test duration to this, in seconds.
+pub const MAX_DURATION: f32 = 3.;
+/// Stop the test if we don't receive a keep-alive message within this time, in seconds.
+pub const KEEPALIVE_TIMEOUT: f32 = 0.2;
+
+/// A test requested over USB.
+#[derive(Clone, Copy)]
+pub struct MotorTestCmd {
+    pub motor: Motor,
+    /// 0. to 1.
+    pub power: f32,
+    /// Seconds.
+    pub duration: f32,
+}
+
+impl MotorTestCmd {
+    /// Clamp power and duration to their safe limits.
+    pub fn new(motor: Motor, power: f32, duration: f32) -> Self {
+        // Treat invalid values as 0.
+        let clamp = |v: f32, max: f32| if v.is_nan() { 0. } else { v.clamp(0., max) };
+
+        Self {
+            motor,
+            power: clamp(power, MAX_POWER),
+            duration: clamp(duration, MAX_DURATION),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ActiveTest {
+    cmd: MotorTestCmd,
+    started_at: f32,
+    last_keepalive: f32,
+}
+
+/// What the main loop should send to the motors.
+pub enum MotorTestOutput {
+    /// Stop all motors, then spin a single motor. Stopping first ensures no other motor is left
+    /// running from a previous command.
+    Start(Motor, f32),
+    /// Stop all motors.
+    Stop,
+    /// Spin a single motor, with all others stopped.
+    Spin(Motor, f32),
+    /// No test is running, and there's nothing to stop.
+    Idle,
+}
+
+/// Motor test state. Commands are set from the USB ISR, and handled in the main loop, which
+/// tracks timing.
+#[derive(Default)]
+pub struct MotorTest {
+    /// The user acknowledged, from the PC application, that props are removed. This lasts
+    /// until power-off.
+    pub props_off_ack: bool,
+    /// A test requested over USB, and not yet started.
+    pub cmd_pending: Option<MotorTestCmd>,
+    pub stop_pending: bool,
+    pub keepalive_pending: bool,
+    /// The latest RPM reading from the motor under test.
+    pub rpm: Option<f32>,
+    active: Option<ActiveTest>,
+}
+
+impl MotorTest {
+    /// The motor currently being tested, if any.
+    pub fn motor_active(&self) -> Option<Motor> {
+        self.active.map(|a| a.cmd.motor)
+    }
+
+    /// Start, continue, or stop a test. Run this regularly from the main loop, while in
+    /// Preflight mode. Timestamp is in seconds.
+    pub fn update(&mut self, timestamp: f32) -> MotorTestOutput {
+        if self.stop_pending {
+            self.stop_pending = false;
+            self.cmd_pending = None;
+            self.cancel();
+            return MotorTestOutput::Stop;
+        }
+
+        if let Some(cmd) = self.cmd_pending.take() {
+            self.active = Some(ActiveTest {
+                cmd,
+                started_at: timestamp,
+                last_keepalive: timestamp,
+            });
+            self.rpm = None;
+            println!("Motor test started. Power: {}", cmd.power);
+
+            return MotorTestOutput::Start(cmd.motor, cmd.power);
+        }
+
+        let mut active = match self.active {
+            Some(a) => a,
+            None => return MotorTestOutput::Idle,
+        };
+
+        if self.keepalive_pending {
+            self.keepalive_pending = false;
+            active.last_keepalive = timestamp;
+            self.active = Some(active);
+        }
+
+        if timestamp - active.started_at > active.cmd.duration {
+            println!("Motor test complete");
+            self.cancel();
+            return MotorTestOutput::Stop;
+        }
+
+        if timestamp - active.last_keepalive > KEEPALIVE_TIMEOUT {
+            println!("Motor test stopped; keep-alive timed out");
+            self.cancel();
+            return MotorTestOutput::Stop;
+        }
+
+        MotorTestOutput::Spin(active.cmd.motor, active.cmd.power)
+    }
+
+    /// Stop a test in progress, eg on leaving Preflight mode.
+    pub fn cancel(&mut self) {
+        self.active = None;
+        self.keepalive_pending = false;
+    }
+}
@@ -6,10 +6,13 @@
 //! [Some info on the PID terms, focused on BF](https://gist.github.com/exocode/90339d7f946ad5f83dd1cf29bf5df0dc)
 //! https://oscarliang.com/quadcopter-pid-explained-tuning/
 //!
-//! As of 2023-02-15, we use this only for commanding specific motor RPMs.
+//! As of 2023-02-15, we use this for commanding specific motor RPMs, and for the classic rate
+//! loop, when selected with `CtrlScheme::RatePid`.
 
+use ahrs::Params;
 use cfg_if::cfg_if;
 
+use super::{common::CtrlMix, filters::FlightCtrlFilters};
 use crate::util::{iir_apply, IirInstWrapper};
 
 cfg_if! {
@@ -21,6 +24,7 @@ cfg_if! {
 
 // use defmt::println;
 
+#[derive(Clone, Copy)]
 pub struct PidCoeffs {
     pub p: f32,
     pub i: f32,
@@ -89,6 +93,56 @@ impl PidStateRate {
     }
 }
 
+/// The classic rate loop: PID on the error between commanded rates (eg from stick inputs, via
+/// rate curves) and gyro rates, with no attitude target. Rates are (pitch, roll, yaw), in rad/s.
+pub fn ctrl_mix_from_rates(
+    rates_commanded: (f32, f32, f32),
+    throttle: f32,
+    params: &Params,
+    coeffs: &PidCoeffs,
+    pid_state: &mut PidStateRate,
+    filters: &mut FlightCtrlFilters,
+    dt: f32, // seconds
+    has_taken_off: bool,
+) -> CtrlMix {
+    // Don't let the I term build up while on the ground, where rates can't converge.
+    if !has_taken_off {
+        pid_state.reset_i();
+    }
+
+    let pitch = pid_state.pitch.apply(
+        rates_commanded.0,
+        params.v_pitch,
+        coeffs,
+        &mut filters.d_term_x,
+        dt,
+    );
+    let roll = pid_state.roll.apply(
+        rates_commanded.1,
+        params.v_roll,
+        coeffs,
+        &mut filters.d_term_y,
+        dt,
+    );
+    let yaw = pid_state.yaw.apply(
+        rates_commanded.2,
+        params.v_yaw,
+        coeffs,
+        &mut filters.d_term_z,
+        dt,
+    );
+
+    let mut result = CtrlMix {
+        pitch,
+        roll,
+        yaw,
+        throttle,
+    };
+
+    result.clamp();
+    result
+}
+
 /// Cutoff frequency for our PID lowpass frequency, in Hz
 #[derive(Clone, Copy)]
 pub enum LowpassCutoff {
@@ -165,21 +219,218 @@ impl Default for MotorCoeffs {
     }
 }
 
-// #[derive(Default)]
-// /// For Motor RPM PID
-// pub struct MotorPidGroup {
-//     pub front_left: PidStateLegacy,
-//     pub front_right: PidStateLegacy,
-//     pub aft_left: PidStateLegacy,
-//     pub aft_right: PidStateLegacy,
-// }
-//
-// impl MotorPidGroup {
-//     /// Reset the interator term on all components.
-//     pub fn reset_integrator(&mut self) {
-//         self.front_left.i = 0.;
-//         self.front_right.i = 0.;
-//         self.aft_left.i = 0.;
-//         self.aft_right.i = 0.;
-//     }
-// }
+// Loaded motors reach roughly this portion of their no-load RPM (KV x voltage).
+const RPM_LIMIT_EFFICIENCY: f32 = 0.8;
+
+// Below this battery voltage, we assume the reading is invalid (eg on USB power), and don't
+// apply voltage scaling.
+const BATT_V_MIN_VALID: f32 = 5.;
+
+/// Battery-voltage-aware RPM control. As the battery sags, the achievable RPM for a given power
+/// setting falls. Both features are off by default.
+#[derive(Clone, Copy)]
+pub struct RpmCtrlCfg {
+    /// Motor KV: no-load RPM per volt.
+    pub motor_kv: f32,
+    /// Limit the RPM setpoint to what's achievable at the measured battery voltage, so the
+    /// integrator doesn't wind up chasing an impossible setpoint.
+    pub rpm_limit_enabled: bool,
+    /// Add a feedforward term proportional to the setpoint, scaled by battery voltage.
+    pub ff_enabled: bool,
+    /// Feedforward power, per unit of setpoint as a portion of no-load RPM. 1. commands the
+    /// power that would reach the setpoint with no load.
+    pub ff_gain: f32,
+}
+
+impl Default for RpmCtrlCfg {
+    fn default() -> Self {
+        Self {
+            motor_kv: 1_900.,
+            rpm_limit_enabled: false,
+            ff_enabled: false,
+            ff_gain: 1.,
+        }
+    }
+}
+
+pub const RPM_CTRL_CFG_SIZE: usize = 1 + 4 * 2;
+
+impl RpmCtrlCfg {
+    /// No-load RPM at this battery voltage, or `None` if the voltage reading is invalid.
+    fn no_load_rpm(&self, batt_v: f32) -> Option<f32> {
+        if batt_v < BATT_V_MIN_VALID {
+            None
+        } else {
+            Some(self.motor_kv * batt_v)
+        }
+    }
+
+    /// Limit an RPM setpoint to what's achievable at this battery voltage.
+    pub fn limit_setpoint(&self, rpm: f32, batt_v: f32) -> f32 {
+        match self.no_load_rpm(batt_v) {
+            Some(max) if self.rpm_limit_enabled => rpm.min(max * RPM_LIMIT_EFFICIENCY),
+            _ => rpm,
+        }
+    }
+
+    /// Feedforward power for an RPM setpoint. Since it's relative to no-load RPM at the measured
+    /// voltage, it rises as the battery sags.
+    pub fn feedforward(&self, rpm: f32, batt_v: f32) -> f32 {
+        match self.no_load_rpm(batt_v) {
+            Some(max) if self.ff_enabled => self.ff_gain * rpm / max,
+            _ => 0.,
+        }
+    }
+
+    /// Estimate the power required for an RPM setpoint, from no-load RPM at this battery voltage.
+    /// Used when we don't have a valid RPM reading to close the loop on.
+    pub fn power_estimate(&self, rpm: f32, batt_v: f32) -> Option<f32> {
+        self.no_load_rpm(batt_v).map(|max| rpm / max)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Self {
+            motor_kv: f32::from_be_bytes(buf[1..5].try_into().unwrap()),
+            rpm_limit_enabled: buf[0] & 1 != 0,
+            ff_enabled: buf[0] & 0b10 != 0,
+            ff_gain: f32::from_be_bytes(buf[5..9].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RPM_CTRL_CFG_SIZE] {
+        let mut result = [0; RPM_CTRL_CFG_SIZE];
+
+        result[0] = self.rpm_limit_enabled as u8 | (self.ff_enabled as u8) << 1;
+        result[1..5].clone_from_slice(&self.motor_kv.to_be_bytes());
+        result[5..9].clone_from_slice(&self.ff_gain.to_be_bytes());
+        result
+    }
+
+    /// True if the numerical values are in range; eg false on configs saved before these fields
+    /// were added.
+    pub fn valid(&self) -> bool {
+        // These comparisons also reject NaN.
+        self.motor_kv > 0. && self.motor_kv < 100_000. && (0. ..=2.).contains(&self.ff_gain)
+    }
+}
+
+/// PID state for one motor's RPM control.
+#[derive(Default)]
+pub struct MotorPidState {
+    pub i: f32,
+}
+
+impl MotorPidState {
+    /// Calculate the power setting to reach an RPM setpoint. `ff` is feedforward power.
+    /// Anti-windup: We don't integrate while the output is saturated in the direction of the
+    /// error.
+    pub fn apply(&mut self, setpoint: f32, reading: f32, p: f32, i: f32, ff: f32, dt: f32) -> f32 {
+        let error = setpoint - reading;
+
+        let out = ff + p * error + i * (self.i + error * dt);
+
+        let saturated_high = out >= 1. && error > 0.;
+        let saturated_low = out <= 0. && error < 0.;
+
+        if !saturated_high && !saturated_low {
+            self.i += error * dt;
+        }
+
+        (ff + p * error + i * self.i).clamp(0., 1.)
+    }
+}
+
+#[derive(Default)]
+/// For Motor RPM PID
+pub struct MotorPidGroup {
+    pub front_left: MotorPidState,
+    pub front_right: MotorPidState,
+    pub aft_left: MotorPidState,
+    pub aft_right: MotorPidState,
+}
+
+impl MotorPidGroup {
+    /// Reset the interator term on all components.
+    pub fn reset_integrator(&mut self) {
+        self.front_left.i = 0.;
+        self.front_right.i = 0.;
+        self.aft_left.i = 0.;
+        self.aft_right.i = 0.;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.001;
+    const P: f32 = 0.000_02;
+    const I: f32 = 0.000_3;
+
+    /// A first-order motor model: RPM approaches `MAX_RPM` times power, with a time constant.
+    struct Motor {
+        rpm: f32,
+    }
+
+    impl Motor {
+        const MAX_RPM: f32 = 30_000.;
+        const TAU: f32 = 0.03;
+
+        fn step(&mut self, power: f32) {
+            self.rpm += (power * Self::MAX_RPM - self.rpm) * DT / Self::TAU;
+        }
+    }
+
+    /// Run for `time` seconds. `check` is passed the time since the start, RPM, and PID state
+    /// after each step.
+    fn run(
+        pid: &mut MotorPidState,
+        motor: &mut Motor,
+        setpoint: f32,
+        time: f32,
+        mut check: impl FnMut(f32, f32, &MotorPidState),
+    ) {
+        for step in 0..(time / DT) as u32 {
+            let power = pid.apply(setpoint, motor.rpm, P, I, 0., DT);
+            motor.step(power);
+            check(step as f32 * DT, motor.rpm, pid);
+        }
+    }
+
+    #[test]
+    fn integrator_holds_while_saturated() {
+        let mut pid = MotorPidState::default();
+        let mut motor = Motor { rpm: 0. };
+
+        // Above what the motor can reach at full power, so the error never reaches 0. Without
+        // anti-windup, the integrator term would reach several times full power.
+        run(&mut pid, &mut motor, 40_000., 2., |t, _, pid| {
+            assert!(I * pid.i <= 1., "{t}: {}", pid.i);
+        });
+
+        // Once the motor is at its maximum, the integrator stops.
+        let i_saturated = pid.i;
+        run(&mut pid, &mut motor, 40_000., 0.5, |_, _, _| ());
+        assert!((pid.i - i_saturated).abs() < i_saturated * 0.001);
+
+        assert!(pid.apply(40_000., motor.rpm, P, I, 0., DT) > 0.99);
+    }
+
+    #[test]
+    fn recovers_without_overshoot() {
+        let mut pid = MotorPidState::default();
+        let mut motor = Motor { rpm: 0. };
+
+        run(&mut pid, &mut motor, 40_000., 2., |_, _, _| ());
+
+        // With wind-up, the integrator would hold power at full well after the setpoint drops,
+        // then undershoot.
+        let setpoint = 20_000.;
+        run(&mut pid, &mut motor, setpoint, 1., |t, rpm, _| {
+            assert!(rpm > setpoint * 0.95, "{t}: {rpm}");
+            if t > 0.3 {
+                assert!((rpm - setpoint).abs() < setpoint * 0.05, "{t}: {rpm}");
+            }
+        });
+    }
+}
@@ -0,0 +1,237 @@
+//! This module contains control profiles: Up to 3 sets of tuning, eg one for freestyle, and one for
+//! cinematic flying. Each has PID and control coefficients, stick rate curves, and gyro lowpass
+//! cutoffs. A 3-position switch, or a USB command, selects the active one.
+//!
+//! The active profile's values live in the usual `UserConfig` fields, which the flight controls
+//! read directly; the profile slots hold the others. Selecting a profile stores the live values in
+//! the outgoing slot, and copies the new slot in. This runs in the main loop, with user config
+//! locked, so the flight controls never see a partial profile. We then reset the PID integrators
+//! and D-term filters, and `ImuFilters::update_gyro_lpfs` settles the gyro filters on the new
+//! cutoffs, so the switch doesn't cause a transient.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::println;
+
+use super::{
+    common::InputMap,
+    ctrl_logic::CtrlCoeffs,
+    filters::FlightCtrlFilters,
+    pid::{PidCoeffs, PidStateRate},
+    rates::{self, RateCurve, RATES_SIZE},
+};
+use crate::{
+    controller_interface::ChannelData,
+    imu_processing::filter_imu::{GyroLpfCfg, LpfStageCfg, LpfType},
+    state::UserConfig,
+};
+
+pub const NUM_PROFILES: usize = 3;
+
+/// Set over USB, to select a profile. The index, or `PROFILE_NONE`. Taken by the main loop.
+pub static PROFILE_USB: AtomicU8 = AtomicU8::new(PROFILE_NONE);
+
+pub const PROFILE_NONE: u8 = 0xff;
+
+// Serialized size: PID P, I, D, and attitude TTC, control TTCs, rate curves, then each gyro
+// lowpass stage's type and cutoff.
+pub const PROFILE_SIZE: usize = 4 * 4 + 4 * 2 + RATES_SIZE + 2 * (1 + 4);
+
+/// One set of tuning values.
+#[derive(Clone, Copy)]
+pub struct CtrlProfile {
+    pub pid_coeffs: PidCoeffs,
+    pub ctrl_coeffs: CtrlCoeffs,
+    /// Pitch, roll, and yaw.
+    pub rates: [RateCurve; 3],
+    pub gyro_lpf: GyroLpfCfg,
+}
+
+impl Default for CtrlProfile {
+    fn default() -> Self {
+        Self {
+            pid_coeffs: Default::default(),
+            ctrl_coeffs: Default::default(),
+            rates: InputMap::default().rates(),
+            gyro_lpf: Default::default(),
+        }
+    }
+}
+
+impl CtrlProfile {
+    /// The values currently in use.
+    pub fn from_active(cfg: &UserConfig) -> Self {
+        Self {
+            pid_coeffs: cfg.pid_coeffs,
+            ctrl_coeffs: cfg.ctrl_coeffs,
+            rates: cfg.input_map.rates(),
+            gyro_lpf: cfg.gyro_lpf,
+        }
+    }
+
+    /// Put these values in use.
+    pub fn apply(&self, cfg: &mut UserConfig) {
+        cfg.pid_coeffs = self.pid_coeffs;
+        cfg.ctrl_coeffs = self.ctrl_coeffs;
+        cfg.input_map.pitch_rate = self.rates[0];
+        cfg.input_map.roll_rate = self.rates[1];
+        cfg.input_map.yaw_rate = self.rates[2];
+        cfg.gyro_lpf = self.gyro_lpf;
+    }
+
+    /// Parse and validate. Returns `None` if values are out of range, eg from an empty slot.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let pid_coeffs = PidCoeffs {
+            p: f(0),
+            i: f(4),
+            d: f(8),
+            att_ttc: f(12),
+            ..Default::default()
+        };
+
+        let ctrl_coeffs = CtrlCoeffs {
+            ttc_per_dθ: f(16),
+            max_ttc_per_dθ: f(20),
+        };
+
+        // This comparison also rejects NaN.
+        for v in [
+            pid_coeffs.p,
+            pid_coeffs.i,
+            pid_coeffs.d,
+            pid_coeffs.att_ttc,
+            ctrl_coeffs.ttc_per_dθ,
+            ctrl_coeffs.max_ttc_per_dθ,
+        ] {
+            if !(v >= 0. && v.is_finite()) {
+                return None;
+            }
+        }
+
+        let rates = rates::rates_from_bytes(&buf[24..24 + RATES_SIZE])?;
+
+        let i = 24 + RATES_SIZE;
+        let gyro_lpf = GyroLpfCfg {
+            lpf1: LpfStageCfg {
+                type_: LpfType::try_from(buf[i]).ok()?,
+                cutoff: f(i + 1),
+            },
+            lpf2: LpfStageCfg {
+                type_: LpfType::try_from(buf[i + 5]).ok()?,
+                cutoff: f(i + 6),
+            },
+        };
+
+        // Cutoffs are clamped when applied; this comparison also rejects NaN.
+        if !(gyro_lpf.lpf1.cutoff >= 0. && gyro_lpf.lpf2.cutoff >= 0.) {
+            return None;
+        }
+
+        Some(Self {
+            pid_coeffs,
+            ctrl_coeffs,
+            rates,
+            gyro_lpf,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; PROFILE_SIZE] {
+        let mut result = [0; PROFILE_SIZE];
+
+        result[0..4].clone_from_slice(&self.pid_coeffs.p.to_be_bytes());
+        result[4..8].clone_from_slice(&self.pid_coeffs.i.to_be_bytes());
+        result[8..12].clone_from_slice(&self.pid_coeffs.d.to_be_bytes());
+        result[12..16].clone_from_slice(&self.pid_coeffs.att_ttc.to_be_bytes());
+        result[16..20].clone_from_slice(&self.ctrl_coeffs.ttc_per_dθ.to_be_bytes());
+        result[20..24].clone_from_slice(&self.ctrl_coeffs.max_ttc_per_dθ.to_be_bytes());
+        result[24..24 + RATES_SIZE].clone_from_slice(&rates::rates_to_bytes(&self.rates));
+
+        let i = 24 + RATES_SIZE;
+        result[i] = self.gyro_lpf.lpf1.type_ as u8;
+        result[i + 1..i + 5].clone_from_slice(&self.gyro_lpf.lpf1.cutoff.to_be_bytes());
+        result[i + 5] = self.gyro_lpf.lpf2.type_ as u8;
+        result[i + 6..i + 10].clone_from_slice(&self.gyro_lpf.lpf2.cutoff.to_be_bytes());
+        result
+    }
+}
+
+/// The profile at `i`, including the active one.
+pub fn get(cfg: &UserConfig, i: usize) -> CtrlProfile {
+    if i == cfg.active_profile as usize {
+        CtrlProfile::from_active(cfg)
+    } else {
+        cfg.profiles[i]
+    }
+}
+
+/// Store a profile at `i`. If it's the active one, it takes effect immediately.
+pub fn set(cfg: &mut UserConfig, i: usize, profile: &CtrlProfile) {
+    if i == cfg.active_profile as usize {
+        profile.apply(cfg);
+    } else {
+        cfg.profiles[i] = *profile;
+    }
+}
+
+/// Tracks switch position, and requests filter resets from the flight controls.
+#[derive(Default)]
+pub struct ProfileSwitch {
+    /// The last switch position; we only select on a change, so USB selections hold.
+    ch_prev: Option<u8>,
+    /// Set on a switch; taken by the flight controls, which own the D-term filters.
+    filter_reset_pending: bool,
+}
+
+impl ProfileSwitch {
+    /// Run periodically, from the main loop. Applies selections from the receiver channel, and
+    /// from USB.
+    pub fn update(
+        &mut self,
+        ch_data: &Option<ChannelData>,
+        cfg: &mut UserConfig,
+        pid_state: &mut PidStateRate,
+    ) {
+        let ch = ch_data.as_ref().and_then(|c| c.profile);
+
+        let mut requested = None;
+        if ch != self.ch_prev {
+            requested = ch;
+            self.ch_prev = ch;
+        }
+
+        let usb = PROFILE_USB.swap(PROFILE_NONE, Ordering::AcqRel);
+        if usb != PROFILE_NONE {
+            requested = Some(usb);
+        }
+
+        let Some(i) = requested else {
+            return;
+        };
+
+        if i as usize >= NUM_PROFILES || i == cfg.active_profile {
+            return;
+        }
+
+        let active = cfg.active_profile as usize;
+        let profile = cfg.profiles[i as usize];
+
+        cfg.profiles[active] = CtrlProfile::from_active(cfg);
+        profile.apply(cfg);
+        cfg.active_profile = i;
+
+        pid_state.reset_i();
+        self.filter_reset_pending = true;
+
+        println!("Control profile {} selected", i + 1);
+    }
+
+    /// Run from the flight controls, with the filters locked.
+    pub fn reset_filters_if_pending(&mut self, filters: &mut FlightCtrlFilters) {
+        if self.filter_reset_pending {
+            filters.reset_d_terms();
+            self.filter_reset_pending = false;
+        }
+    }
+}
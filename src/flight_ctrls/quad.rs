@@ -9,18 +9,14 @@ use core::f32::consts::TAU;
 
 use ahrs::Params;
 use defmt::println;
+use num_enum::TryFromPrimitive;
 use num_traits::Float;
 
-use super::common::InputMap;
+use super::{cmd_updates::AngleOnCenterCfg, common::InputMap, rates::RateCurve};
 use crate::{
-    controller_interface::InputModeSwitch,
-    state::StateVolatile,
-    system_status::{SensorStatus, SystemStatus},
-    util,
+    controller_interface::InputModeSwitch, state::StateVolatile, system_status::SystemStatus, util,
 };
 
-// todo: Variabel/struct field found from cal routine that is power to hover.
-
 // Our maneuverability clamps are different from normal throttle settings: They're used
 // to reduce the risk and severity of individual rotors clamping due to throttle settings that
 // are too high or too low. They reduce user throttle authority, but provide more predictable
@@ -35,34 +31,44 @@ pub const THROTTLE_MIN_MNVR_CLAMP: f32 = 0.06;
 // todo: Calibration unimplemented
 // const MIN_CAL_ALT: f32 = 6.;
 
+// Acro rates, in radians/s: Near center, and at full stick deflection.
+const ACRO_RATE_CENTER: f32 = 3.5;
 const ACRO_RATE: f32 = 10.;
+const ACRO_EXPO: f32 = 0.5;
+
+const ACRO_CURVE: RateCurve = RateCurve {
+    center_sens: ACRO_RATE_CENTER,
+    max_rate: ACRO_RATE,
+    expo: ACRO_EXPO,
+};
 
 impl Default for InputMap {
     fn default() -> Self {
         Self {
-            pitch_rate: (-ACRO_RATE, ACRO_RATE),
-            roll_rate: (-ACRO_RATE, ACRO_RATE),
-            yaw_rate: (-ACRO_RATE, ACRO_RATE),
+            pitch_rate: ACRO_CURVE,
+            roll_rate: ACRO_CURVE,
+            yaw_rate: ACRO_CURVE,
             throttle_clamped: (THROTTLE_MIN_MNVR_CLAMP, THROTTLE_MAX_MNVR_CLAMP),
             pitch_angle: (-TAU / 4., TAU / 4.),
             roll_angle: (-TAU / 4., TAU / 4.),
             alt_commanded_offset_msl: (0., 100.),
             alt_commanded_agl: (0.5, 8.),
             vertical_velocity: (-3., 3.),
+            cal: Default::default(),
         }
     }
 }
 
-// /// Specify the rotor by position. Used in power application code.
-// /// repr(u8) is for use in Preflight.
-// #[derive(Clone, Copy, PartialEq)]
-// #[repr(u8)]
-// pub enum RotorPosition {
-//     FrontLeft = 0,
-//     FrontRight = 1,
-//     AftLeft = 2,
-//     AftRight = 3,
-// }
+/// Specify the rotor by position. The repr is the index in the order of
+/// `MotorServoState::rotor_rpms`, and mixer rows; it's also used in Preflight.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RotorPosition {
+    FrontLeft = 0,
+    FrontRight = 1,
+    AftLeft = 2,
+    AftRight = 3,
+}
 
 /// Mode used for control inputs. These are the three "industry-standard" modes.
 #[derive(Clone, Copy, PartialEq)]
@@ -70,6 +76,9 @@ pub enum InputMode {
     /// Rate, also know as manual, hard or Acro. Attitude and power stay the same after
     /// releasing controls.
     Acro,
+    /// Acro, but attitude returns to level while the pitch and roll sticks are centered. Also
+    /// known as "angle on center". Selected by the Acro switch position, if enabled in config.
+    AcroHybrid,
     /// Attitude also know as self-level, angle, or Auto-level. Attitude resets to a level
     /// hover after releasing controls.  When moving the
     /// roll/pitch stick to its maximum position, the drone will also reach the maximum angle
@@ -156,20 +165,27 @@ pub fn takeoff_speed(height: f32, max_v: f32) -> f32 {
     if height > 2. {
         return 0.;
     }
-    (height / 4. + 0.01).max(max_v)
+    (height / 4. + 0.01).min(max_v)
 }
 
 pub fn set_input_mode(
     input_mode_control: InputModeSwitch,
     state_volatile: &mut StateVolatile,
     system_status: &SystemStatus,
+    angle_on_center: &AngleOnCenterCfg,
 ) {
     state_volatile.input_mode_switch = input_mode_control; // todo: Do we need or use this field?
 
     state_volatile.input_mode = match input_mode_control {
-        InputModeSwitch::Acro => InputMode::Acro,
+        InputModeSwitch::Acro => {
+            if angle_on_center.enabled {
+                InputMode::AcroHybrid
+            } else {
+                InputMode::Acro
+            }
+        }
         InputModeSwitch::AttitudeLoiter => {
-            if system_status.gnss_can == SensorStatus::Pass {
+            if system_status.gnss_usable() {
                 InputMode::Loiter
             } else {
                 InputMode::Attitude
@@ -0,0 +1,117 @@
+//! This module contains stick rate curves: How stick deflection maps to commanded rotation rate.
+//! We use the Betaflight "actual rates" curve: A center sensitivity sets the slope near center,
+//! a max rate sets the rate at full deflection, and expo blends between the two.
+
+use num_traits::Float;
+
+// Evaluating the curve at these stick deflections, 0. to 1., for display. The curve is
+// symmetric about center.
+pub const NUM_CURVE_SAMPLES: usize = 11;
+
+// We reject configured rates above this, in degrees/s.
+const MAX_RATE_LIMIT: f32 = 2_000.;
+
+// Serialized sizes. Center sensitivity and max rate are in degrees/s.
+pub const RATE_CURVE_SIZE: usize = 4 * 3;
+pub const RATES_SIZE: usize = RATE_CURVE_SIZE * 3;
+pub const CURVE_SAMPLES_SIZE: usize = 4 * NUM_CURVE_SAMPLES * 3;
+
+/// Maps stick deflection to rotation rate for one axis. Rates are in radians/s.
+#[derive(Clone, Copy)]
+pub struct RateCurve {
+    /// Rate per unit stick deflection, near center.
+    pub center_sens: f32,
+    /// Rate at full stick deflection.
+    pub max_rate: f32,
+    /// 0. to 1. 0. is linear between center sensitivity and max rate.
+    pub expo: f32,
+}
+
+impl RateCurve {
+    /// A linear curve; the same as mapping stick range directly to `max_rate`.
+    pub const fn linear(max_rate: f32) -> Self {
+        Self {
+            center_sens: max_rate,
+            max_rate,
+            expo: 0.,
+        }
+    }
+
+    /// Convert stick deflection, -1. to 1., to radians/s. This runs at the rate we update
+    /// commanded rates, so avoid `powf` etc.
+    pub fn eval(&self, input: f32) -> f32 {
+        let input_sq = input * input;
+        let input_5 = input_sq * input_sq * input;
+
+        let expo = input.abs() * (input_5 * self.expo + input * (1. - self.expo));
+        let stick_movement = (self.max_rate - self.center_sens).max(0.);
+
+        input * self.center_sens + stick_movement * expo
+    }
+
+    /// Parse and validate, from degrees/s. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let center_sens = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let max_rate = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+        let expo = f32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(center_sens > 0. && center_sens <= MAX_RATE_LIMIT)
+            || !(max_rate >= center_sens && max_rate <= MAX_RATE_LIMIT)
+            || !(0. ..=1.).contains(&expo)
+        {
+            return None;
+        }
+
+        Some(Self {
+            center_sens: center_sens.to_radians(),
+            max_rate: max_rate.to_radians(),
+            expo,
+        })
+    }
+
+    /// Serialize, in degrees/s.
+    pub fn to_bytes(&self) -> [u8; RATE_CURVE_SIZE] {
+        let mut result = [0; RATE_CURVE_SIZE];
+
+        result[0..4].clone_from_slice(&self.center_sens.to_degrees().to_be_bytes());
+        result[4..8].clone_from_slice(&self.max_rate.to_degrees().to_be_bytes());
+        result[8..12].clone_from_slice(&self.expo.to_be_bytes());
+        result
+    }
+}
+
+/// Serialize curves for pitch, roll, and yaw.
+pub fn rates_to_bytes(curves: &[RateCurve; 3]) -> [u8; RATES_SIZE] {
+    let mut result = [0; RATES_SIZE];
+
+    for (i, curve) in curves.iter().enumerate() {
+        result[i * RATE_CURVE_SIZE..(i + 1) * RATE_CURVE_SIZE].clone_from_slice(&curve.to_bytes());
+    }
+    result
+}
+
+/// Parse and validate curves for pitch, roll, and yaw. Returns `None` if any are invalid.
+pub fn rates_from_bytes(buf: &[u8]) -> Option<[RateCurve; 3]> {
+    let curve =
+        |i: usize| RateCurve::from_bytes(&buf[i * RATE_CURVE_SIZE..(i + 1) * RATE_CURVE_SIZE]);
+
+    Some([curve(0)?, curve(1)?, curve(2)?])
+}
+
+/// Evaluate each curve at evenly-spaced stick deflections from 0. to 1., in degrees/s, so the PC
+/// application can plot them. Pitch, then roll, then yaw.
+pub fn curve_samples_to_bytes(curves: &[RateCurve; 3]) -> [u8; CURVE_SAMPLES_SIZE] {
+    let mut result = [0; CURVE_SAMPLES_SIZE];
+
+    for (i, curve) in curves.iter().enumerate() {
+        for j in 0..NUM_CURVE_SAMPLES {
+            let input = j as f32 / (NUM_CURVE_SAMPLES - 1) as f32;
+            let start = (i * NUM_CURVE_SAMPLES + j) * 4;
+
+            result[start..start + 4]
+                .clone_from_slice(&curve.eval(input).to_degrees().to_be_bytes());
+        }
+    }
+    result
+}
@@ -0,0 +1,83 @@
+//! This module contains 3D mode: With reversible ESCs, motors spin in either direction, so thrust
+//! may be negative. This allows 3D (inverted) flying, and turtle mode without DSHOT direction
+//! commands. Enabled with `UserConfig::reversible`; the ESCs are put in 3D mode at init, so
+//! changes take effect after a restart. Quad only.
+//!
+//! In Acro modes, throttle stick center is zero thrust, and below it is reverse. Around center is
+//! a deadband, in which motors idle in the direction last commanded; direction only changes once
+//! the stick leaves the deadband on the other side. This prevents direction chatter near zero.
+//! After arming, motors are stopped (DSHOT 0, vice minimum forward) until the stick first leaves
+//! the deadband. Autopilot throttle is forward only.
+//!
+//! The mixer works on thrust magnitude. In reverse, we negate the pitch, roll, and yaw commands
+//! before mixing, and the motor powers after: Reversing a motor reverses both its thrust, and its
+//! reaction torque.
+
+use num_traits::Float;
+
+use super::common::CtrlMix;
+
+// Throttle stick deflection from center, as a portion of half its travel, in which we don't
+// change direction.
+const DEADBAND: f32 = 0.08;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ThrustDir {
+    /// Motors are stopped. This is the state on arming, until throttle leaves the deadband.
+    Stopped,
+    Forward,
+    Reverse,
+}
+
+impl Default for ThrustDir {
+    fn default() -> Self {
+        Self::Stopped
+    }
+}
+
+impl ThrustDir {
+    /// Update from signed throttle, -1. to 1. Returns the throttle magnitude, 0. to 1., with the
+    /// deadband removed.
+    pub fn update(&mut self, throttle: f32) -> f32 {
+        if throttle > DEADBAND {
+            *self = Self::Forward;
+        } else if throttle < -DEADBAND {
+            *self = Self::Reverse;
+        }
+
+        magnitude(throttle)
+    }
+
+    /// Negate pitch, roll, and yaw in reverse, prior to mixing thrust magnitudes.
+    pub fn apply_to_mix(&self, mix: &CtrlMix) -> CtrlMix {
+        let mut result = mix.clone();
+
+        if *self == Self::Reverse {
+            result.pitch = -mix.pitch;
+            result.roll = -mix.roll;
+            result.yaw = -mix.yaw;
+        }
+
+        result
+    }
+}
+
+/// Map throttle stick position, 0. to 1., to signed throttle, -1. to 1.
+pub fn throttle_from_stick(throttle: f32) -> f32 {
+    throttle * 2. - 1.
+}
+
+/// Throttle magnitude, 0. to 1., from signed throttle, with the deadband removed.
+fn magnitude(throttle: f32) -> f32 {
+    ((throttle.abs() - DEADBAND) / (1. - DEADBAND)).clamp(0., 1.)
+}
+
+/// The throttle to check for idle prior to arming. In 3D mode, idle is stick center: This is 0.
+/// anywhere in the deadband.
+pub fn arm_throttle(throttle: f32, reversible: bool) -> f32 {
+    if reversible {
+        magnitude(throttle_from_stick(throttle))
+    } else {
+        throttle
+    }
+}
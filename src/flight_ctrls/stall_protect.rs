@@ -0,0 +1,137 @@
+//! This module contains stall protection, for fixed-wing aircraft. As airspeed falls below the
+//! minimum, we progressively lower the maximum pitch-up that may be commanded, and add throttle.
+//! This applies to manual stabilized modes, and to the autopilot.
+//!
+//! Airspeed is from the synthetic airspeed estimate. Without it, eg if GPS is lost, we use a
+//! heuristic: Nose-high attitude at low throttle bleeds airspeed.
+//!
+//! It can be disabled entirely; eg for hand launches, and aerobatics.
+
+use ahrs::{Params, RIGHT};
+use lin_alg::f32::Quaternion;
+
+use super::{airspeed::AirspeedCfg, common::AttitudeCommanded};
+use crate::safety::{self, ArmStatus};
+
+// Bands outside this range, in m/s, are rejected when loading config.
+const BAND_MIN: f32 = 0.5;
+const BAND_MAX: f32 = 20.;
+
+// Max pitch-up commandable, in radians, as protection begins, and when fully active. We
+// interpolate between these.
+const PITCH_UP_MAX_ONSET: f32 = 0.5;
+const PITCH_UP_MAX_FULL: f32 = -0.05;
+
+// Throttle added when fully active. 0. to 1.
+const THROTTLE_ADD_MAX: f32 = 0.5;
+
+// Heuristic, used when airspeed is unknown: Protection begins above this pitch, in radians, and is
+// fully active this much higher. It's scaled down as throttle approaches the threshold.
+const HEURISTIC_PITCH_ONSET: f32 = 0.35;
+const HEURISTIC_PITCH_RANGE: f32 = 0.35;
+const HEURISTIC_THROTTLE_THRESH: f32 = 0.4;
+
+// Serialized size: Enabled, and band.
+pub const STALL_PROTECT_CFG_SIZE: usize = 1 + 4;
+
+/// Stall protection settings. Stored in user config. The minimum airspeed is from `AirspeedCfg`.
+#[derive(Clone, Copy)]
+pub struct StallProtectCfg {
+    pub enabled: bool,
+    /// m/s. Protection ramps in from the minimum airspeed, to fully active at this far below it.
+    pub band: f32,
+}
+
+impl Default for StallProtectCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            band: 3.,
+        }
+    }
+}
+
+impl StallProtectCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let band = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+
+        // This comparison also rejects NaN.
+        if buf[0] > 1 || !(BAND_MIN..=BAND_MAX).contains(&band) {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            band,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; STALL_PROTECT_CFG_SIZE] {
+        let mut result = [0; STALL_PROTECT_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1..5].clone_from_slice(&self.band.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct StallProtect {
+    /// Displayed on the OSD.
+    pub active: bool,
+    /// 0. (inactive) to 1. (fully active).
+    pub severity: f32,
+}
+
+impl StallProtect {
+    /// Run each time attitude and throttle commanded are updated, after they are. Limits the
+    /// attitude commanded's pitch-up, and adds throttle, in place. `airspeed` is from
+    /// `AirspeedEst`, if available.
+    pub fn apply(
+        &mut self,
+        cmd: &mut AttitudeCommanded,
+        params: &Params,
+        airspeed: Option<f32>,
+        arm_status: ArmStatus,
+        has_taken_off: bool,
+        airspeed_cfg: &AirspeedCfg,
+        cfg: &StallProtectCfg,
+    ) {
+        if !cfg.enabled || !has_taken_off || arm_status == ArmStatus::Disarmed {
+            *self = Default::default();
+            return;
+        }
+
+        self.severity = match airspeed {
+            Some(a) => (airspeed_cfg.min_airspeed - a) / cfg.band,
+            None => {
+                let pitch = params.attitude.to_euler().pitch;
+                (pitch - HEURISTIC_PITCH_ONSET) / HEURISTIC_PITCH_RANGE
+                    * (1. - cmd.throttle / HEURISTIC_THROTTLE_THRESH)
+            }
+        }
+        .clamp(0., 1.);
+
+        self.active = self.severity > 0.;
+        if !self.active {
+            return;
+        }
+
+        let pitch_up_max =
+            PITCH_UP_MAX_ONSET + (PITCH_UP_MAX_FULL - PITCH_UP_MAX_ONSET) * self.severity;
+
+        let pitch = cmd.quat.to_euler().pitch;
+        if pitch > pitch_up_max {
+            // Lower the nose about the commanded attitude's own pitch axis. This matches how pitch
+            // is composed in `update_att_commanded_att_mode`.
+            cmd.quat = (cmd.quat * Quaternion::from_axis_angle(RIGHT, pitch - pitch_up_max))
+                .to_normalized();
+        }
+
+        // With controls armed, but motors not, we must never spin the motor.
+        if arm_status == safety::MOTORS_ARMED {
+            cmd.throttle = (cmd.throttle + THROTTLE_ADD_MAX * self.severity).min(1.);
+        }
+    }
+}
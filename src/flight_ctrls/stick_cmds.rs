@@ -0,0 +1,232 @@
+//! This module contains stick commands: Gestures on the sticks, while disarmed, that trigger
+//! actions on the field without a laptop, eg accelerometer calibration, or saving config. Each is a
+//! position of all four sticks, held for a set time. Commands are listed in `STICK_CMDS`; add an
+//! entry there, and handle its action, to add one.
+//!
+//! To keep a bumped stick from triggering a command, the sticks must be centered, with throttle
+//! low, before a command starts. Once one is recognized, its action runs when the sticks are
+//! centered again; this also keeps eg turtle mode from starting with a stick deflected. Each
+//! action is indicated by the LED and buzzer, and in the event log.
+//!
+//! Positions are of the raw channel values, as in `ChannelData`. Low is stick down (towards the
+//! pilot), or left.
+
+use defmt::println;
+use num_traits::Float;
+
+use crate::{
+    controller_interface::ChannelData,
+    event_log::{self, EventCode},
+    safety::ArmStatus,
+};
+
+// Seconds a command must be held.
+const HOLD_TIME: f32 = 2.;
+// Seconds the indicators show feedback after an action runs.
+const FEEDBACK_TIME: f32 = 1.;
+
+// Self-centering sticks are low or high past these, and centered within `CENTER_THRESH`.
+const LOW_THRESH: f32 = -0.7;
+const HIGH_THRESH: f32 = 0.7;
+const CENTER_THRESH: f32 = 0.2;
+// Throttle is 0. to 1.
+const THROTTLE_LOW_THRESH: f32 = 0.1;
+const THROTTLE_HIGH_THRESH: f32 = 0.9;
+
+// Radians from upright. Once the aircraft is within this, eg after turtle mode flips it, we clear
+// the turtle command; otherwise, it would enter turtle mode again the next time it's inverted.
+const TURTLE_UPRIGHT_ANGLE: f32 = 0.5;
+
+/// Actions stick commands trigger. Repr is the event log payload.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum StickAction {
+    /// Start accelerometer calibration. The aircraft must be level, and still.
+    AccelCal = 0,
+    /// Save user config to flash.
+    SaveConfig = 1,
+    /// Enter turtle mode, as from the switch, or leave it if entered this way. Quad only.
+    Turtle = 2,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StickPos {
+    Low,
+    Center,
+    High,
+}
+
+impl StickPos {
+    fn matches(self, val: f32) -> bool {
+        match self {
+            Self::Low => val < LOW_THRESH,
+            Self::Center => val.abs() < CENTER_THRESH,
+            Self::High => val > HIGH_THRESH,
+        }
+    }
+
+    /// Throttle doesn't self-center; we treat low as its center.
+    fn matches_throttle(self, val: f32) -> bool {
+        match self {
+            Self::Low | Self::Center => val < THROTTLE_LOW_THRESH,
+            Self::High => val > THROTTLE_HIGH_THRESH,
+        }
+    }
+}
+
+struct StickCmd {
+    throttle: StickPos,
+    yaw: StickPos,
+    pitch: StickPos,
+    roll: StickPos,
+    action: StickAction,
+}
+
+impl StickCmd {
+    fn matches(&self, ch: &ChannelData) -> bool {
+        self.throttle.matches_throttle(ch.throttle)
+            && self.yaw.matches(ch.yaw)
+            && self.pitch.matches(ch.pitch)
+            && self.roll.matches(ch.roll)
+    }
+}
+
+static STICK_CMDS: [StickCmd; 3] = [
+    StickCmd {
+        throttle: StickPos::Low,
+        yaw: StickPos::High,
+        pitch: StickPos::Low,
+        roll: StickPos::Center,
+        action: StickAction::AccelCal,
+    },
+    StickCmd {
+        throttle: StickPos::Low,
+        yaw: StickPos::Low,
+        pitch: StickPos::High,
+        roll: StickPos::Center,
+        action: StickAction::SaveConfig,
+    },
+    StickCmd {
+        throttle: StickPos::Low,
+        yaw: StickPos::High,
+        pitch: StickPos::Center,
+        roll: StickPos::Center,
+        action: StickAction::Turtle,
+    },
+];
+
+fn centered(ch: &ChannelData) -> bool {
+    StickPos::Center.matches_throttle(ch.throttle)
+        && StickPos::Center.matches(ch.yaw)
+        && StickPos::Center.matches(ch.pitch)
+        && StickPos::Center.matches(ch.roll)
+}
+
+#[derive(Default)]
+pub struct StickCmds {
+    /// The sticks have been centered since the last command; required to start one.
+    ready: bool,
+    /// The command currently matched, and when it started matching.
+    held: Option<(StickAction, f32)>,
+    /// A command was recognized; its action runs once the sticks are centered.
+    pending: Option<StickAction>,
+    /// When the last action ran, for indicator feedback.
+    last_run: Option<f32>,
+    /// Set by the turtle command; enters turtle mode as the switch does. Cleared once upright,
+    /// and on arming or disarming.
+    pub turtle: bool,
+    arm_status_prev: ArmStatus,
+}
+
+impl StickCmds {
+    /// Run each main loop update. `ch_data` is `None` if the link is lost. `angle_from_upright` is
+    /// in radians. Returns an action to run, if any; the caller runs it, except for turtle mode,
+    /// which is handled here.
+    pub fn update(
+        &mut self,
+        ch_data: Option<&ChannelData>,
+        arm_status: ArmStatus,
+        angle_from_upright: f32,
+        timestamp: f32,
+    ) -> Option<StickAction> {
+        if arm_status != self.arm_status_prev
+            || arm_status != ArmStatus::Disarmed
+            || angle_from_upright < TURTLE_UPRIGHT_ANGLE
+        {
+            self.turtle = false;
+        }
+        self.arm_status_prev = arm_status;
+
+        let ch = match ch_data {
+            Some(ch) if arm_status == ArmStatus::Disarmed => ch,
+            _ => {
+                self.ready = false;
+                self.held = None;
+                self.pending = None;
+                return None;
+            }
+        };
+
+        let centered = centered(ch);
+
+        if let Some(action) = self.pending {
+            if !centered {
+                return None;
+            }
+
+            self.pending = None;
+            self.ready = true;
+            self.last_run = Some(timestamp);
+
+            if action == StickAction::Turtle {
+                self.turtle = !self.turtle;
+            }
+
+            println!("Stick command: {}", action as u8);
+            event_log::log(EventCode::StickCommand, action as u16, 0);
+
+            return Some(action);
+        }
+
+        if centered {
+            self.ready = true;
+            self.held = None;
+            return None;
+        }
+
+        if !self.ready {
+            return None;
+        }
+
+        let matched = STICK_CMDS
+            .iter()
+            .find(|cmd| cmd.matches(ch))
+            .map(|cmd| cmd.action)
+            // Turtle mode is quad only.
+            .filter(|a| !(cfg!(feature = "fixed-wing") && *a == StickAction::Turtle));
+
+        self.held = match (matched, self.held) {
+            (Some(a), Some((held, start))) if a == held => {
+                if timestamp - start >= HOLD_TIME {
+                    self.pending = Some(a);
+                    self.ready = false;
+                    None
+                } else {
+                    Some((held, start))
+                }
+            }
+            (Some(a), _) => Some((a, timestamp)),
+            (None, _) => None,
+        };
+
+        None
+    }
+
+    /// True briefly after an action runs; the indicators show this.
+    pub fn feedback(&self, timestamp: f32) -> bool {
+        match self.last_run {
+            Some(t) => timestamp - t < FEEDBACK_TIME,
+            None => false,
+        }
+    }
+}
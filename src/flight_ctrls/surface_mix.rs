@@ -0,0 +1,164 @@
+//! This module contains fixed-wing control surface mixing: How pitch, roll, and yaw commands map
+//! to up to `NUM_SURFACES` servo outputs. Presets cover common airframes; a custom matrix covers
+//! the rest. Surfaces are numbered as in `ControlMapping::surfaces`:
+//!
+//! - Elevon: 1 and 2 are the left and right elevons. 3 is an optional rudder.
+//! - V-tail: 1 and 2 are the left and right ruddervators; 3 and 4 the left and right ailerons,
+//!   if present.
+//! - Conventional: 1 and 2 are the left and right ailerons, 3 the elevator, and 4 the rudder, if
+//!   present.
+//!
+//! A positive coefficient moves the surface with the command's sign; hardware direction is set by
+//! each servo's reversal. The rudder coordination term adds a portion of the roll command to yaw,
+//! countering adverse yaw in turns. It applies to every surface with a yaw coefficient, eg
+//! ruddervators; differential thrust uses the yaw command alone.
+
+use num_enum::TryFromPrimitive;
+
+use super::common::CtrlMix;
+use crate::protocols::servo::NUM_SURFACES;
+
+// Custom coefficients, and the rudder coordination gain, outside these ranges are rejected when
+// loading config.
+const MAX_COEFF: f32 = 1.;
+pub const RUDDER_COORD_MAX: f32 = 1.;
+
+// Serialized size: Preset, the custom matrix's pitch, roll, and yaw coefficients for each
+// surface, and rudder coordination.
+pub const SURFACE_MIX_SIZE: usize = 1 + NUM_SURFACES * 3 * 4 + 4;
+
+/// Pitch, roll, and yaw coefficients, for each surface.
+pub type MixMatrix = [[f32; 3]; NUM_SURFACES];
+
+/// Repr is how it's stored in config.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum MixPreset {
+    #[default]
+    Elevon = 0,
+    VTail = 1,
+    Conventional = 2,
+    /// Use the custom matrix.
+    Custom = 3,
+}
+
+impl MixPreset {
+    fn matrix(self) -> Option<MixMatrix> {
+        Some(match self {
+            Self::Elevon => [[1., 1., 0.], [1., -1., 0.], [0., 0., 1.], [0., 0., 0.]],
+            Self::VTail => [[1., 0., 1.], [1., 0., -1.], [0., 1., 0.], [0., -1., 0.]],
+            Self::Conventional => [[0., 1., 0.], [0., -1., 0.], [1., 0., 0.], [0., 0., 1.]],
+            Self::Custom => return None,
+        })
+    }
+}
+
+/// Control surface mixing. Stored in user config, as part of the control mapping.
+#[derive(Clone, Copy)]
+pub struct SurfaceMix {
+    pub preset: MixPreset,
+    /// Used with the custom preset.
+    pub custom: MixMatrix,
+    /// Portion of the roll command added to yaw, for coordinated turns. 0. disables.
+    pub rudder_coord: f32,
+}
+
+impl Default for SurfaceMix {
+    fn default() -> Self {
+        Self {
+            preset: Default::default(),
+            custom: MixPreset::Elevon.matrix().unwrap(),
+            rudder_coord: 0.,
+        }
+    }
+}
+
+impl SurfaceMix {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let mut custom = [[0.; 3]; NUM_SURFACES];
+        for (i, coeffs) in custom.iter_mut().enumerate() {
+            for (j, coeff) in coeffs.iter_mut().enumerate() {
+                *coeff = f(1 + (i * 3 + j) * 4);
+
+                // This comparison also rejects NaN.
+                if !(coeff.abs() <= MAX_COEFF) {
+                    return None;
+                }
+            }
+        }
+
+        let rudder_coord = f(1 + NUM_SURFACES * 3 * 4);
+
+        // This comparison also rejects NaN.
+        if !(0. ..=RUDDER_COORD_MAX).contains(&rudder_coord) {
+            return None;
+        }
+
+        Some(Self {
+            preset: MixPreset::try_from(buf[0]).ok()?,
+            custom,
+            rudder_coord,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; SURFACE_MIX_SIZE] {
+        let mut result = [0; SURFACE_MIX_SIZE];
+
+        result[0] = self.preset as u8;
+
+        for (i, coeffs) in self.custom.iter().enumerate() {
+            for (j, coeff) in coeffs.iter().enumerate() {
+                let start = 1 + (i * 3 + j) * 4;
+                result[start..start + 4].clone_from_slice(&coeff.to_be_bytes());
+            }
+        }
+
+        let i = 1 + NUM_SURFACES * 3 * 4;
+        result[i..i + 4].clone_from_slice(&self.rudder_coord.to_be_bytes());
+        result
+    }
+
+    /// The preset's matrix, or the custom one.
+    pub fn matrix(&self) -> MixMatrix {
+        self.preset.matrix().unwrap_or(self.custom)
+    }
+
+    /// Surface positions from the mix, -1. to 1., before trim and reversal.
+    pub fn surface_posits(&self, mix: &CtrlMix) -> [f32; NUM_SURFACES] {
+        let yaw = mix.yaw + self.rudder_coord * mix.roll;
+
+        let mut result = [0.; NUM_SURFACES];
+        for (posit, [p, r, y]) in result.iter_mut().zip(self.matrix()) {
+            *posit = (p * mix.pitch + r * mix.roll + y * yaw).clamp(-1., 1.);
+        }
+        result
+    }
+
+    /// Surface positions projected back onto pitch, roll, and yaw; the inverse of
+    /// `surface_posits`, up to scale. Used to log control commands for the accel maps.
+    pub fn deltas(&self, posits: &[f32; NUM_SURFACES]) -> (f32, f32, f32) {
+        let mut result = (0., 0., 0.);
+        for (posit, [p, r, y]) in posits.iter().zip(self.matrix()) {
+            result.0 += p * posit;
+            result.1 += r * posit;
+            result.2 += y * posit;
+        }
+        result
+    }
+
+    /// True if pitch and roll are each driven by at least one of the surfaces present.
+    pub fn controllable(&self, present: &[bool; NUM_SURFACES]) -> bool {
+        let matrix = self.matrix();
+        let driven = |axis: usize| {
+            matrix
+                .iter()
+                .zip(present)
+                .any(|(coeffs, present)| *present && coeffs[axis] != 0.)
+        };
+
+        driven(0) && driven(1)
+    }
+}
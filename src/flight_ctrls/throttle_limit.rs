@@ -0,0 +1,119 @@
+//! This module contains throttle limiting, eg to meet a racing class's power limit, or to tame an
+//! aircraft for a beginner. The limit either scales throttle, keeping the full stick travel, or
+//! clips it, keeping full resolution below the limit. A separate, lower limit applies while the
+//! `beginner` switch is on.
+//!
+//! It's applied to throttle commanded, after mapping the throttle stick, and prior to thrust
+//! linearization, and protections that add throttle, eg stall protection. Throttle from altitude
+//! hold, and other autopilot modes, can be exempted, so eg a climb during return-to-home isn't
+//! limited. The active limit is shown on the OSD.
+
+use num_enum::TryFromPrimitive;
+
+// Limits below this, 0. to 1., are rejected when loading config; lower would prevent flight.
+pub const LIMIT_MIN: f32 = 0.2;
+
+// Serialized size: Mode, limit, beginner limit, and the autopilot exemption.
+pub const THROTTLE_LIMIT_CFG_SIZE: usize = 1 + 4 * 2 + 1;
+
+/// How the limit is applied. Repr is how it's stored.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ThrottleLimitMode {
+    Off = 0,
+    /// Multiply throttle by the limit.
+    Scale = 1,
+    /// Cap throttle at the limit.
+    Clip = 2,
+}
+
+/// Throttle limit settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct ThrottleLimitCfg {
+    pub mode: ThrottleLimitMode,
+    /// 0. to 1. Applies at all times.
+    pub limit: f32,
+    /// 0. to 1. Applies in place of `limit`, if lower, while the beginner switch is on.
+    pub beginner_limit: f32,
+    /// Don't limit throttle commanded by altitude hold, and other autopilot modes.
+    pub exempt_autopilot: bool,
+}
+
+impl Default for ThrottleLimitCfg {
+    fn default() -> Self {
+        Self {
+            mode: ThrottleLimitMode::Off,
+            limit: 1.,
+            beginner_limit: 0.5,
+            exempt_autopilot: true,
+        }
+    }
+}
+
+impl ThrottleLimitCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let mode = ThrottleLimitMode::try_from(buf[0]).ok()?;
+        let limit = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+        let beginner_limit = f32::from_be_bytes(buf[5..9].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(LIMIT_MIN..=1.).contains(&limit)
+            || !(LIMIT_MIN..=1.).contains(&beginner_limit)
+            || buf[9] > 1
+        {
+            return None;
+        }
+
+        Some(Self {
+            mode,
+            limit,
+            beginner_limit,
+            exempt_autopilot: buf[9] != 0,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; THROTTLE_LIMIT_CFG_SIZE] {
+        let mut result = [0; THROTTLE_LIMIT_CFG_SIZE];
+
+        result[0] = self.mode as u8;
+        result[1..5].clone_from_slice(&self.limit.to_be_bytes());
+        result[5..9].clone_from_slice(&self.beginner_limit.to_be_bytes());
+        result[9] = self.exempt_autopilot as u8;
+        result
+    }
+
+    /// The limit in effect, 0. to 1., or `None` if throttle isn't limited. `beginner` is the
+    /// beginner switch being on.
+    pub fn active_limit(&self, beginner: bool) -> Option<f32> {
+        if self.mode == ThrottleLimitMode::Off {
+            return None;
+        }
+
+        let limit = if beginner {
+            self.limit.min(self.beginner_limit)
+        } else {
+            self.limit
+        };
+
+        if limit < 1. {
+            Some(limit)
+        } else {
+            None
+        }
+    }
+
+    /// Apply the limit to throttle commanded. `autopilot` is throttle being from altitude hold,
+    /// or another autopilot mode, vice the stick. Throttle may be signed, in 3D mode.
+    pub fn apply(&self, throttle: f32, beginner: bool, autopilot: bool) -> f32 {
+        if autopilot && self.exempt_autopilot {
+            return throttle;
+        }
+
+        match (self.mode, self.active_limit(beginner)) {
+            (ThrottleLimitMode::Scale, Some(limit)) => throttle * limit,
+            (ThrottleLimitMode::Clip, Some(limit)) => throttle.clamp(-limit, limit),
+            _ => throttle,
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! This module contains compensation applied between commanded throttle and motor power:
+//! Thrust linearization, and battery voltage compensation. It's applied in one place, so manual
+//! and autopilot throttle are treated the same.
+//!
+//! Props produce thrust roughly with the square of RPM, so we linearize with an exponent, for
+//! throttle that maps proportionally to thrust. Available thrust at a given power setting sags
+//! with battery voltage, so we scale power by the ratio of a reference voltage to the current
+//! voltage; this keeps hover throttle steady through a pack.
+
+use num_traits::Float;
+
+use crate::sensors_shared::BattCellCount;
+
+// Thrust exponents outside this range are rejected when loading config.
+const EXPONENT_MIN: f32 = 1.;
+const EXPONENT_MAX: f32 = 3.;
+// Reference voltages outside this range are rejected when loading config.
+const V_REF_MIN: f32 = 3.;
+const V_REF_MAX: f32 = 4.4;
+
+// We limit the voltage compensation multiplier to this range, eg so a faulty voltage reading
+// doesn't command full power.
+const VOLT_COMP_MIN: f32 = 0.7;
+const VOLT_COMP_MAX: f32 = 1.4;
+
+// Below this per-cell voltage, we assume the reading is invalid (eg on USB power), and don't
+// compensate.
+const V_CELL_MIN_VALID: f32 = 2.5;
+
+// Time constant of the lowpass filter on battery voltage, in seconds. Long enough to smooth
+// transient sag from throttle punches, so we compensate for pack state of charge, not load.
+const V_FILTER_TAU: f32 = 2.;
+
+// Serialized sizes. Config is packed enable flags, exponent, and reference voltage. State is
+// filtered voltage per cell, compensation multiplier, and throttle out.
+pub const THRUST_COMP_CFG_SIZE: usize = 1 + 4 * 2;
+pub const THRUST_COMP_STATE_SIZE: usize = 4 * 3;
+
+#[derive(Clone, Copy)]
+pub struct ThrustCompCfg {
+    pub linearization_enabled: bool,
+    /// Thrust is modeled as proportional to power raised to this exponent. 2. is a typical
+    /// value.
+    pub exponent: f32,
+    pub volt_comp_enabled: bool,
+    /// Per-cell voltage where the compensation multiplier is 1.
+    pub v_ref_per_cell: f32,
+}
+
+impl Default for ThrustCompCfg {
+    fn default() -> Self {
+        Self {
+            linearization_enabled: false,
+            exponent: 2.,
+            volt_comp_enabled: false,
+            v_ref_per_cell: 3.9,
+        }
+    }
+}
+
+impl ThrustCompCfg {
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Self {
+            linearization_enabled: buf[0] & 1 != 0,
+            exponent: f32::from_be_bytes(buf[1..5].try_into().unwrap()),
+            volt_comp_enabled: buf[0] & 0b10 != 0,
+            v_ref_per_cell: f32::from_be_bytes(buf[5..9].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; THRUST_COMP_CFG_SIZE] {
+        let mut result = [0; THRUST_COMP_CFG_SIZE];
+
+        result[0] = self.linearization_enabled as u8 | (self.volt_comp_enabled as u8) << 1;
+        result[1..5].clone_from_slice(&self.exponent.to_be_bytes());
+        result[5..9].clone_from_slice(&self.v_ref_per_cell.to_be_bytes());
+        result
+    }
+
+    /// True if the numerical values are in range; eg false on configs saved before these fields
+    /// were added.
+    pub fn valid(&self) -> bool {
+        // These comparisons also reject NaN.
+        (EXPONENT_MIN..=EXPONENT_MAX).contains(&self.exponent)
+            && (V_REF_MIN..=V_REF_MAX).contains(&self.v_ref_per_cell)
+    }
+}
+
+/// Compensation state. The fields are logged, and reported over USB, so the effect can be
+/// verified.
+pub struct ThrustComp {
+    /// Filtered battery voltage, per cell.
+    pub v_per_cell: Option<f32>,
+    /// Multiplier applied to motor power. 1. when disabled.
+    pub volt_comp_factor: f32,
+    /// Throttle after compensation. This is what's sent to the control mix.
+    pub throttle_out: f32,
+}
+
+impl Default for ThrustComp {
+    fn default() -> Self {
+        Self {
+            v_per_cell: None,
+            volt_comp_factor: 1.,
+            throttle_out: 0.,
+        }
+    }
+}
+
+impl ThrustComp {
+    /// For reporting over USB. Voltage is 0. if there's no valid reading.
+    pub fn to_bytes(&self) -> [u8; THRUST_COMP_STATE_SIZE] {
+        let mut result = [0; THRUST_COMP_STATE_SIZE];
+
+        result[0..4].clone_from_slice(&self.v_per_cell.unwrap_or(0.).to_be_bytes());
+        result[4..8].clone_from_slice(&self.volt_comp_factor.to_be_bytes());
+        result[8..12].clone_from_slice(&self.throttle_out.to_be_bytes());
+        result
+    }
+
+    /// Update the voltage compensation multiplier from a new battery voltage reading. `dt` is
+    /// the time since the last reading, in seconds.
+    pub fn update_voltage(
+        &mut self,
+        batt_v: f32,
+        cell_count: BattCellCount,
+        dt: f32,
+        cfg: &ThrustCompCfg,
+    ) {
+        let v_cell = batt_v / cell_count.num_cells();
+
+        if v_cell < V_CELL_MIN_VALID {
+            self.v_per_cell = None;
+        } else {
+            let alpha = dt / (V_FILTER_TAU + dt);
+
+            self.v_per_cell = Some(match self.v_per_cell {
+                Some(v) => v + alpha * (v_cell - v),
+                None => v_cell,
+            });
+        }
+
+        self.volt_comp_factor = match self.v_per_cell {
+            Some(v) if cfg.volt_comp_enabled => {
+                (cfg.v_ref_per_cell / v).clamp(VOLT_COMP_MIN, VOLT_COMP_MAX)
+            }
+            _ => 1.,
+        };
+    }
+
+    /// Convert commanded throttle (proportional to thrust) to the power setting sent to the
+    /// control mix. 0. to 1.
+    pub fn apply(&mut self, throttle: f32, cfg: &ThrustCompCfg) -> f32 {
+        let throttle = throttle.clamp(0., 1.);
+
+        let linearized = if cfg.linearization_enabled {
+            throttle.powf(1. / cfg.exponent)
+        } else {
+            throttle
+        };
+
+        self.throttle_out = (linearized * self.volt_comp_factor).clamp(0., 1.);
+        self.throttle_out
+    }
+}
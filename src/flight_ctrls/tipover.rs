@@ -0,0 +1,187 @@
+//! This module contains ground tip-over protection: If the aircraft tips over before takeoff, eg
+//! catching a prop in grass, the motors would otherwise keep fighting at high power, and the ESCs
+//! overheat. While armed, and before takeoff is detected, we disarm if tilt passes a limit, or if
+//! a motor powered above a threshold repeatedly stalls, or stays stalled, per RPM telemetry.
+//!
+//! This is distinct from in-flight motor failure handling; since it only runs on the ground, its
+//! thresholds can be aggressive. After it trips, arming is blocked until the arm switch is cycled.
+//! It's indicated in the event log, and on the OSD. Quad only.
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(feature = "quad")] {
+        use num_traits::Float;
+
+        use super::{common::ArmLatch, motor_servo::MIN_ROTOR_RPM};
+        use crate::imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS;
+
+        // Seconds past the tilt limit before we disarm. Rejects brief spikes, eg from a bump.
+        const TILT_TIME: f32 = 0.1;
+
+        // Each stall onset adds 1. to a motor's count, which decays at this rate, per second. We
+        // disarm once it reaches `STALL_COUNT`.
+        const STALL_DECAY: f32 = 1.;
+        const STALL_COUNT: f32 = 3.;
+        // Seconds a motor may stay stalled before we disarm.
+        const STALL_TIME: f32 = 0.2;
+    }
+}
+
+// Limits outside these ranges are rejected when loading config. Degrees, and 0. to 1.
+const MAX_TILT_MIN: f32 = 30.;
+const MAX_TILT_MAX: f32 = 90.;
+const STALL_POWER_MIN: f32 = 0.05;
+const STALL_POWER_MAX: f32 = 0.8;
+
+// Serialized size: Enabled, tilt limit in degrees, and stall power.
+pub const TIPOVER_CFG_SIZE: usize = 1 + 4 * 2;
+
+/// Ground tip-over protection settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct TipoverCfg {
+    pub enabled: bool,
+    /// Tilt from upright, in radians, past which we disarm.
+    pub max_tilt: f32,
+    /// A motor above this power, 0. to 1., must not stall.
+    pub stall_power: f32,
+}
+
+impl Default for TipoverCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_tilt: 60_f32.to_radians(),
+            stall_power: 0.25,
+        }
+    }
+}
+
+impl TipoverCfg {
+    /// Parse and validate, from degrees. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let max_tilt = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+        let stall_power = f32::from_be_bytes(buf[5..9].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if buf[0] > 1
+            || !(MAX_TILT_MIN..=MAX_TILT_MAX).contains(&max_tilt)
+            || !(STALL_POWER_MIN..=STALL_POWER_MAX).contains(&stall_power)
+        {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            max_tilt: max_tilt.to_radians(),
+            stall_power,
+        })
+    }
+
+    /// Serialize, in degrees.
+    pub fn to_bytes(&self) -> [u8; TIPOVER_CFG_SIZE] {
+        let mut result = [0; TIPOVER_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1..5].clone_from_slice(&self.max_tilt.to_degrees().to_be_bytes());
+        result[5..9].clone_from_slice(&self.stall_power.to_be_bytes());
+        result
+    }
+}
+
+/// Why we disarmed. Repr is the event log payload.
+#[cfg(feature = "quad")]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum TipoverCause {
+    Tilt = 0,
+    Stall = 1,
+}
+
+#[cfg(feature = "quad")]
+#[derive(Default)]
+pub struct TipoverDetect {
+    /// Set when we disarm.
+    pub latch: ArmLatch,
+    /// Seconds past the tilt limit.
+    tilt_time: f32,
+    /// Per motor, in the order of `MotorServoState::rotor_rpms`: Whether it was stalled at the
+    /// last update, its decaying count of stall onsets, and seconds stalled.
+    stalled: [bool; NUM_RPM_NOTCH_MOTORS],
+    stall_count: [f32; NUM_RPM_NOTCH_MOTORS],
+    stall_time: [f32; NUM_RPM_NOTCH_MOTORS],
+}
+
+#[cfg(feature = "quad")]
+impl TipoverDetect {
+    /// Run periodically while armed, with power settings and RPM readings in the order of
+    /// `MotorServoState::rotor_rpms`; RPM readings are only used with bidirectional DSHOT.
+    /// `tilt` is the angle from upright, in radians. Returns the cause, and a detail, if we
+    /// should disarm: Tilt in degrees, or the stalled rotor's index.
+    pub fn update(
+        &mut self,
+        armed: bool,
+        has_taken_off: bool,
+        tilt: f32,
+        powers: &[f32; NUM_RPM_NOTCH_MOTORS],
+        rpms: Option<&[Option<f32>; NUM_RPM_NOTCH_MOTORS]>,
+        cfg: &TipoverCfg,
+        dt: f32,
+    ) -> Option<(TipoverCause, u16)> {
+        if !cfg.enabled || !armed || has_taken_off {
+            *self = Self {
+                latch: self.latch,
+                ..Default::default()
+            };
+            return None;
+        }
+
+        if tilt > cfg.max_tilt {
+            self.tilt_time += dt;
+            if self.tilt_time >= TILT_TIME {
+                self.trip();
+                return Some((TipoverCause::Tilt, tilt.to_degrees() as u16));
+            }
+        } else {
+            self.tilt_time = 0.;
+        }
+
+        // If no motors report RPM, we can't tell a stall from missing telemetry.
+        let Some(rpms) = rpms else {
+            return None;
+        };
+        if rpms.iter().all(|r| r.is_none()) {
+            return None;
+        }
+
+        for i in 0..NUM_RPM_NOTCH_MOTORS {
+            // In 3D mode, power may be negative.
+            let stalled = powers[i].abs() > cfg.stall_power
+                && rpms[i].map_or(true, |rpm| rpm < MIN_ROTOR_RPM);
+
+            self.stall_count[i] = (self.stall_count[i] - STALL_DECAY * dt).max(0.);
+
+            if stalled {
+                if !self.stalled[i] {
+                    self.stall_count[i] += 1.;
+                }
+                self.stall_time[i] += dt;
+            } else {
+                self.stall_time[i] = 0.;
+            }
+            self.stalled[i] = stalled;
+
+            if self.stall_count[i] >= STALL_COUNT || self.stall_time[i] >= STALL_TIME {
+                self.trip();
+                return Some((TipoverCause::Stall, i as u16));
+            }
+        }
+
+        None
+    }
+
+    fn trip(&mut self) {
+        *self = Default::default();
+        self.latch.trip();
+    }
+}
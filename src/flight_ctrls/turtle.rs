@@ -0,0 +1,186 @@
+//! This module contains turtle mode: Flipping the aircraft upright after it lands inverted. While
+//! disarmed and inverted, a switch reverses motor direction; deflecting the roll or pitch stick
+//! then spins the two motors on that side, at a limited power, flipping the aircraft towards the
+//! stick.
+//!
+//! Motor direction is set with DSHOT commands, which block for ~40ms; this only happens on the
+//! ground, while disarmed. On leaving turtle mode, we re-send the configured directions, and
+//! block arming until a brief RPM check confirms the motors have stopped. (DSHOT RPM telemetry
+//! doesn't include direction.) In 3D mode, we command reverse power instead, so no direction
+//! commands are sent. Quad only.
+
+use ahrs::{self, Params};
+use defmt::println;
+use num_traits::Float;
+
+use crate::{
+    controller_interface::ChannelData,
+    flight_ctrls::{control_mapping::ControlMapping, motor_servo::MotorPower},
+    protocols::dshot,
+    safety::ArmStatus,
+    setup::MotorTimer,
+};
+
+// Radians from upright. We only enter turtle mode if inverted past this, so a switch bumped
+// in flight, or on a level aircraft, does nothing.
+const INVERTED_ANGLE: f32 = 2.;
+
+// Power applied at full stick deflection. 0. to 1.
+const MAX_POWER: f32 = 0.35;
+// Stick deflections smaller than this are ignored.
+const DEADBAND: f32 = 0.15;
+
+// Seconds after restoring motor direction before we check RPM.
+const VERIFY_TIME: f32 = 0.3;
+// RPM readings above this fail the check.
+const MAX_RPM_STOPPED: f32 = 100.;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Off,
+    /// Motors are reversed, and driven from the sticks.
+    Active,
+    /// Motor directions are restored; waiting to check RPM. Value is the time restored, in
+    /// seconds.
+    Verify(f32),
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Self::Off
+    }
+}
+
+#[derive(Default)]
+pub struct TurtleMode {
+    stage: Stage,
+}
+
+impl TurtleMode {
+    pub fn active(&self) -> bool {
+        self.stage == Stage::Active
+    }
+
+    /// Don't allow arming while this is true: Motors may be reversed, or not yet verified.
+    pub fn blocks_arming(&self) -> bool {
+        self.stage != Stage::Off
+    }
+
+    /// Run each flight control update, outside of Preflight. `ch_data` is `None` if the link is
+    /// lost. `stick_cmd` acts as the switch does; it's set from a stick command. `rpms` is by rotor
+    /// position, as from `MotorServoState::rotor_rpms`. Returns true if turtle mode set the motors
+    /// this update; if so, don't run flight controls.
+    pub fn update(
+        &mut self,
+        ch_data: Option<&ChannelData>,
+        stick_cmd: bool,
+        arm_status: ArmStatus,
+        params: &Params,
+        rpms: &[Option<f32>],
+        mapping: &ControlMapping,
+        motor_timer: &mut MotorTimer,
+        timestamp: f32,
+    ) -> bool {
+        let switch = match ch_data {
+            Some(ch) => ch.turtle || stick_cmd,
+            None => false,
+        };
+
+        match self.stage {
+            Stage::Off => {
+                let angle_from_upright = params.attitude.rotate_vec(ahrs::UP).dot(ahrs::UP).acos();
+
+                if !switch
+                    || arm_status != ArmStatus::Disarmed
+                    || angle_from_upright < INVERTED_ANGLE
+                {
+                    return false;
+                }
+
+                if !dshot::reversible() {
+                    let (m1, m2, m3, m4) = mapping.motors_reversed();
+                    dshot::setup_motor_dir((!m1, !m2, !m3, !m4), false, motor_timer);
+                }
+
+                self.stage = Stage::Active;
+                println!("Turtle mode on");
+            }
+            Stage::Active => {
+                // Link loss reads as the switch off.
+                if !switch || arm_status != ArmStatus::Disarmed {
+                    self.exit(mapping, motor_timer, timestamp);
+                    return true;
+                }
+
+                let (roll, pitch) = match ch_data {
+                    Some(ch) => (ch.roll, ch.pitch),
+                    None => (0., 0.),
+                };
+
+                let p = stick_power(roll.abs().max(pitch.abs()));
+
+                let mut power = MotorPower::default();
+                if roll.abs() >= pitch.abs() {
+                    if roll > 0. {
+                        power.front_right = p;
+                        power.aft_right = p;
+                    } else {
+                        power.front_left = p;
+                        power.aft_left = p;
+                    }
+                } else if pitch > 0. {
+                    power.front_left = p;
+                    power.front_right = p;
+                } else {
+                    power.aft_left = p;
+                    power.aft_right = p;
+                }
+
+                if dshot::reversible() {
+                    power.negate();
+                }
+
+                let [p1, p2, p3, p4] = power.to_motor_order(mapping);
+                dshot::set_power(p1, p2, p3, p4, motor_timer);
+            }
+            Stage::Verify(restored_at) => {
+                dshot::stop_all(motor_timer);
+
+                if timestamp - restored_at < VERIFY_TIME {
+                    return true;
+                }
+
+                // Readings are absent without bidirectional DSHOT; we don't fail on that.
+                if rpms.iter().flatten().any(|rpm| *rpm > MAX_RPM_STOPPED) {
+                    println!("Motors still spinning after turtle mode; restoring direction again");
+                    self.exit(mapping, motor_timer, timestamp);
+                } else {
+                    self.stage = Stage::Off;
+                    println!("Turtle mode off; motor direction restored");
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Stop the motors, and restore their configured direction. Run this on leaving turtle mode,
+    /// including from Preflight.
+    pub fn exit(&mut self, mapping: &ControlMapping, motor_timer: &mut MotorTimer, timestamp: f32) {
+        dshot::stop_all(motor_timer);
+        if !dshot::reversible() {
+            dshot::setup_motor_dir(mapping.motors_reversed(), false, motor_timer);
+        }
+
+        self.stage = Stage::Verify(timestamp);
+    }
+}
+
+/// Map stick deflection, 0. to 1., to motor power.
+fn stick_power(deflection: f32) -> f32 {
+    if deflection < DEADBAND {
+        return 0.;
+    }
+
+    ((deflection - DEADBAND) / (1. - DEADBAND)).min(1.) * MAX_POWER
+}
@@ -0,0 +1,134 @@
+//! This module contains a wind estimate, for quads. While holding position, the aircraft leans
+//! into the wind, to counter drag. We convert the commanded tilt, and thrust relative to the hover
+//! estimate, into the horizontal acceleration drag is causing, and from that, a wind vector.
+//!
+//! We only learn while in GPS position hold, and close to the held point; otherwise, tilt is
+//! mostly from maneuvering. Between holds, the estimate is frozen. It's cleared if it's stale:
+//! after enough time, or a large enough altitude change, since it was last updated.
+//!
+//! The loiter controller uses the filtered acceleration as a feed-forward tilt.
+
+use core::f32::consts::TAU;
+
+use num_traits::float::Float;
+
+use crate::flight_ctrls::hover_est::HOVER_THROTTLE_MIN;
+
+const G: f32 = 9.8;
+
+// Drag acceleration (m/s^2) per square wind speed (m/s). Approximate for a 5" quad; eg 10m/s of
+// wind tilts it about 15°.
+const DRAG_COEFF: f32 = 0.026;
+
+// We only learn while within this distance of the held point, in meters.
+pub const POSIT_ERR_THRESH: f32 = 3.;
+
+// We don't learn at tilts above this, in radians; the small-angle model no longer applies.
+const TILT_MAX: f32 = 0.6;
+
+// Time constant of the acceleration lowpass filter, in seconds. Slow, so gusts and position
+// corrections average out.
+const EST_TAU: f32 = 10.;
+
+// The estimate is cleared after this long without updating, in seconds, or this much altitude
+// change from where it was last updated, in meters.
+const STALE_TIME: f32 = 600.;
+const STALE_ALT_CHANGE: f32 = 100.;
+
+// We don't report an estimate until we've learned for this long, in seconds.
+const LEARN_TIME_MIN: f32 = 5.;
+
+#[derive(Default)]
+pub struct WindEst {
+    /// Filtered horizontal acceleration from thrust that counters drag, in the earth frame. North,
+    /// East. m/s^2.
+    pub accel_n: f32,
+    pub accel_e: f32,
+    /// Altitude MSL at the last update, in m.
+    alt_ref: f32,
+    /// Seconds since the last update.
+    age: f32,
+    /// Seconds spent learning since the estimate was last cleared.
+    learn_time: f32,
+}
+
+impl WindEst {
+    /// Run at a regular interval. `pitch` and `roll` are the commanded attitude, in radians; nose
+    /// up and right wing down are positive. `heading` is in radians, clockwise from north.
+    /// `holding` is true when in position hold, within `POSIT_ERR_THRESH` of the held point.
+    pub fn update(
+        &mut self,
+        pitch: f32,
+        roll: f32,
+        heading: f32,
+        throttle: f32,
+        hover_throttle: f32,
+        alt_msl: f32,
+        holding: bool,
+        dt: f32,
+    ) {
+        if !holding || pitch.abs() > TILT_MAX || roll.abs() > TILT_MAX {
+            self.age += dt;
+
+            if self.age > STALE_TIME || (alt_msl - self.alt_ref).abs() > STALE_ALT_CHANGE {
+                *self = Default::default();
+            }
+            return;
+        }
+
+        // Thrust, in g. Vertical thrust balances weight, but climbs and descents during the
+        // hold change it slightly.
+        let thrust = throttle / hover_throttle.max(HOVER_THROTTLE_MIN);
+
+        // Horizontal acceleration from tilt, body frame: forward, and right.
+        let fwd = G * thrust * (-pitch).tan();
+        let right = G * thrust * roll.tan();
+
+        let (sin_h, cos_h) = heading.sin_cos();
+        let accel_n = fwd * cos_h - right * sin_h;
+        let accel_e = fwd * sin_h + right * cos_h;
+
+        let k = dt / (EST_TAU + dt);
+        self.accel_n += k * (accel_n - self.accel_n);
+        self.accel_e += k * (accel_e - self.accel_e);
+
+        self.alt_ref = alt_msl;
+        self.age = 0.;
+        self.learn_time += dt;
+    }
+
+    /// True once we've learned long enough for the estimate to be meaningful.
+    pub fn valid(&self) -> bool {
+        self.learn_time >= LEARN_TIME_MIN
+    }
+
+    /// Wind speed, in m/s.
+    pub fn speed(&self) -> f32 {
+        ((self.accel_n.powi(2) + self.accel_e.powi(2)).sqrt() / DRAG_COEFF).sqrt()
+    }
+
+    /// The direction the wind is coming from, in radians clockwise from north. 0. to τ. We lean
+    /// into the wind, so this is the direction of the tilt.
+    pub fn direction(&self) -> f32 {
+        let result = self.accel_e.atan2(self.accel_n);
+        if result < 0. {
+            result + TAU
+        } else {
+            result
+        }
+    }
+
+    /// Feed-forward tilt to counter the wind, in the body frame: Pitch, and roll, in radians.
+    /// Zero if the estimate isn't valid.
+    pub fn tilt_ff(&self, heading: f32) -> (f32, f32) {
+        if !self.valid() {
+            return (0., 0.);
+        }
+
+        let (sin_h, cos_h) = heading.sin_cos();
+        let fwd = self.accel_n * cos_h + self.accel_e * sin_h;
+        let right = -self.accel_n * sin_h + self.accel_e * cos_h;
+
+        (-(fwd / G).atan(), (right / G).atan())
+    }
+}
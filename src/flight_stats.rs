@@ -0,0 +1,248 @@
+//! This module contains flight statistics: A timer for the current flight, and cumulative airframe
+//! totals, eg for maintenance intervals. Totals are saved to a flash slot after disarming.
+//!
+//! Times are from the tick timer, since power-up. The RTC isn't set from a time source, so we
+//! don't record when flights took place.
+//!
+//! A crash can disarm the aircraft while the battery sags, or as it ejects; a brownout during a
+//! flash write may corrupt the slot. So, we wait for the voltage to settle after disarming, and
+//! only queue a write if it's healthy. Writes are rate-limited for flash wear, and performed in
+//! the idle task.
+//!
+//! The health trend history, and control effect maps, share this page, after the totals; they're
+//! written along with them.
+
+use hal::flash::{Bank, Flash};
+
+use crate::{
+    flight_ctrls::ctrl_effect_est::ACCEL_MAPS_SIZE,
+    health_trend::{HealthHistory, HEALTH_HISTORY_SIZE},
+    safety::{self, ArmStatus},
+    sensors_shared::BattCellCount,
+    storage,
+};
+
+// Marks a valid slot. Erased flash reads 0xff.
+const SLOT_MARKER: u8 = 0x5a;
+
+// Flights, airborne time, mAh consumed, max altitude, and max speed.
+pub const FLIGHT_STATS_SIZE: usize = 4 * 5;
+
+// Where the health trend history starts on the page: After the marker, and totals.
+pub const HEALTH_HISTORY_OFFSET: usize = 1 + FLIGHT_STATS_SIZE;
+
+// Where the control effect maps start on the page: After the health trend history.
+pub const ACCEL_MAPS_OFFSET: usize = HEALTH_HISTORY_OFFSET + HEALTH_HISTORY_SIZE;
+
+// Seconds after disarming before we save, so the voltage reading reflects the battery at rest.
+const SAVE_DELAY: f32 = 2.;
+// Minimum seconds between saves.
+const SAVE_INTERVAL: f32 = 60.;
+// Volts per cell. We don't save below this, or with no battery connected.
+const SAVE_MIN_CELL_V: f32 = 3.4;
+
+// Amp-seconds to mAh.
+const AS_TO_MAH: f32 = 1_000. / 3_600.;
+
+/// Cumulative airframe statistics.
+#[derive(Clone, Copy, Default)]
+pub struct FlightStats {
+    /// Arming periods that included a takeoff.
+    pub flights: u32,
+    /// Seconds.
+    pub airborne_time: f32,
+    pub mah_used: f32,
+    /// Meters, above the arming point.
+    pub max_alt: f32,
+    /// Ground speed, in m/s.
+    pub max_speed: f32,
+}
+
+impl FlightStats {
+    /// Returns `None` if values are invalid, eg from an empty slot.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let result = Self {
+            flights: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            airborne_time: f(4),
+            mah_used: f(8),
+            max_alt: f(12),
+            max_speed: f(16),
+        };
+
+        // This comparison also rejects NaN.
+        for v in [
+            result.airborne_time,
+            result.mah_used,
+            result.max_alt,
+            result.max_speed,
+        ] {
+            if !(v >= 0. && v.is_finite()) {
+                return None;
+            }
+        }
+
+        Some(result)
+    }
+
+    pub fn to_bytes(&self) -> [u8; FLIGHT_STATS_SIZE] {
+        let mut result = [0; FLIGHT_STATS_SIZE];
+
+        result[0..4].clone_from_slice(&self.flights.to_be_bytes());
+        result[4..8].clone_from_slice(&self.airborne_time.to_be_bytes());
+        result[8..12].clone_from_slice(&self.mah_used.to_be_bytes());
+        result[12..16].clone_from_slice(&self.max_alt.to_be_bytes());
+        result[16..20].clone_from_slice(&self.max_speed.to_be_bytes());
+        result
+    }
+}
+
+/// The current, or most recent flight.
+#[derive(Clone, Copy, Default)]
+pub struct FlightTimer {
+    /// Seconds.
+    pub armed_time: f32,
+    /// Seconds.
+    pub airborne_time: f32,
+    pub mah_used: f32,
+    /// Meters, above the arming point.
+    pub max_alt: f32,
+    /// m/s.
+    pub max_speed: f32,
+}
+
+#[derive(Default)]
+pub struct FlightStatsState {
+    /// Loaded from flash at power-up, and updated on each disarm.
+    pub totals: FlightStats,
+    pub flight: FlightTimer,
+    armed: bool,
+    /// Altitude MSL at arming, in m.
+    arm_alt: f32,
+    /// Seconds since start.
+    disarm_time: f32,
+    last_save: Option<f32>,
+    /// Totals have changed since the last save.
+    save_needed: bool,
+    /// Taken by the idle task, which writes it to flash.
+    write_pending: Option<FlightStats>,
+}
+
+impl FlightStatsState {
+    /// Run at init, before the main loop starts.
+    pub fn load(flash: &mut Flash) -> Self {
+        let mut buf = [0; 1 + FLIGHT_STATS_SIZE];
+        flash.read(Bank::B1, crate::FLASH_STATS_PAGE, 0, &mut buf);
+
+        let totals = if buf[0] == SLOT_MARKER {
+            FlightStats::from_bytes(&buf[1..]).unwrap_or_default()
+        } else {
+            Default::default()
+        };
+
+        Self {
+            totals,
+            ..Default::default()
+        }
+    }
+
+    /// Run periodically. `current` is in A. `speed` is ground speed in m/s, if we have a valid
+    /// fix.
+    pub fn update(
+        &mut self,
+        arm_status: ArmStatus,
+        has_taken_off: bool,
+        alt_msl: f32,
+        speed: Option<f32>,
+        current: f32,
+        batt_v: f32,
+        cell_count: BattCellCount,
+        timestamp: f32,
+        dt: f32,
+    ) {
+        let armed = arm_status == safety::MOTORS_ARMED;
+
+        if armed && !self.armed {
+            self.flight = Default::default();
+            self.arm_alt = alt_msl;
+        } else if !armed && self.armed {
+            self.end_flight(timestamp);
+        }
+        self.armed = armed;
+
+        if armed {
+            let f = &mut self.flight;
+
+            f.armed_time += dt;
+            f.mah_used += current * dt * AS_TO_MAH;
+
+            if has_taken_off {
+                f.airborne_time += dt;
+                f.max_alt = f.max_alt.max(alt_msl - self.arm_alt);
+                if let Some(s) = speed {
+                    f.max_speed = f.max_speed.max(s);
+                }
+            }
+            return;
+        }
+
+        if !self.save_needed || timestamp - self.disarm_time < SAVE_DELAY {
+            return;
+        }
+
+        if let Some(t) = self.last_save {
+            if timestamp - t < SAVE_INTERVAL {
+                return;
+            }
+        }
+
+        // If low, we keep trying; eg after a battery swap. The totals are lost if it's unplugged
+        // first.
+        if batt_v / cell_count.num_cells() < SAVE_MIN_CELL_V {
+            return;
+        }
+
+        self.write_pending = Some(self.totals);
+        self.last_save = Some(timestamp);
+        self.save_needed = false;
+    }
+
+    fn end_flight(&mut self, timestamp: f32) {
+        let f = &self.flight;
+        let t = &mut self.totals;
+
+        if f.airborne_time > 0. {
+            t.flights += 1;
+        }
+        t.airborne_time += f.airborne_time;
+        t.mah_used += f.mah_used;
+        t.max_alt = t.max_alt.max(f.max_alt);
+        t.max_speed = t.max_speed.max(f.max_speed);
+
+        self.disarm_time = timestamp;
+        self.save_needed = true;
+    }
+
+    /// Run from the idle task. Returns totals to write, if a save is pending.
+    pub fn take_write_pending(&mut self) -> Option<FlightStats> {
+        self.write_pending.take()
+    }
+}
+
+/// Write totals, and the health trend history, to the flash slot. Blocking, and slow; run from
+/// the idle task.
+pub fn write(
+    flash: &mut Flash,
+    stats: &FlightStats,
+    health: &HealthHistory,
+    accel_maps: &[u8; ACCEL_MAPS_SIZE],
+) {
+    let mut buf = [0; ACCEL_MAPS_OFFSET + ACCEL_MAPS_SIZE];
+    buf[0] = SLOT_MARKER;
+    buf[1..HEALTH_HISTORY_OFFSET].clone_from_slice(&stats.to_bytes());
+    buf[HEALTH_HISTORY_OFFSET..ACCEL_MAPS_OFFSET].clone_from_slice(&health.to_bytes());
+    buf[ACCEL_MAPS_OFFSET..].clone_from_slice(accel_maps);
+
+    storage::write_onboard_page(flash, crate::FLASH_STATS_PAGE, &buf).ok();
+}
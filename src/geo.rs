@@ -0,0 +1,289 @@
+//! This module contains position math for autopilot modes: Distance, bearing, and offsetting a
+//! point by meters, between lat/lon positions, and conversion to a local East, North, Up frame
+//! anchored at the base point. It extends `ahrs::ppks::PositVelEarthUnits`, which only stores a
+//! position. Autopilot code should use these, vice ad-hoc math.
+//!
+//! We use an equirectangular approximation: Accurate to well under 1% over the few km an aircraft
+//! covers, and much cheaper than great-circle math. Longitude differences are scaled by the cosine
+//! of the mean latitude, and wrap across the antimeridian. Differences are taken as integers before
+//! converting to float, so precision doesn't depend on how far a point is from 0°.
+//!
+//! Positions without a GNSS fix, eg waypoints set relative to takeoff, are `Location::Rel0`; these
+//! interoperate with lat/lon positions by anchoring them at the base point.
+
+use core::f32::consts::TAU;
+
+use ahrs::ppks::PositVelEarthUnits;
+use num_traits::Float;
+
+// Earth's mean radius, in m.
+const R: f32 = 6_371_000.;
+
+// Position units are degrees x 1e8.
+const DEG_SCALE: f32 = 100_000_000.;
+const LAT_MAX: i64 = 90 * 100_000_000;
+const LON_HALF: i64 = 180 * 100_000_000;
+
+/// Wrap a longitude, or longitude difference, in degrees x 1e8, to -180° to 180°.
+fn wrap_lon(lon: i64) -> i64 {
+    (lon + LON_HALF).rem_euclid(2 * LON_HALF) - LON_HALF
+}
+
+/// Map a bearing from `atan2`, -π to π, to 0 to τ.
+fn wrap_bearing(bearing: f32) -> f32 {
+    if bearing < 0. {
+        bearing + TAU
+    } else {
+        bearing
+    }
+}
+
+/// Meters per unit of latitude; degrees x 1e8.
+fn m_per_unit() -> f32 {
+    R * (TAU / 360.) / DEG_SCALE
+}
+
+/// A horizontal position, in degrees x 1e8, as in `PositVelEarthUnits`, and `Params::posit_fused`.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct LatLon {
+    pub lat_e8: i64,
+    pub lon_e8: i64,
+}
+
+impl LatLon {
+    pub fn new(lat_e8: i64, lon_e8: i64) -> Self {
+        Self { lat_e8, lon_e8 }
+    }
+
+    /// From degrees x 1e7, as in GNSS fixes, and positions passed over USB.
+    pub fn from_e7(lat_e7: i32, lon_e7: i32) -> Self {
+        Self::new(lat_e7 as i64 * 10, lon_e7 as i64 * 10)
+    }
+
+    pub fn from_posit(posit: &PositVelEarthUnits) -> Self {
+        Self::new(posit.lat_e8, posit.lon_e8)
+    }
+
+    /// Offset from this point to another: North, and East, in m.
+    pub fn offset_ne(&self, other: &Self) -> (f32, f32) {
+        let lat_mean = ((self.lat_e8 + other.lat_e8) / 2) as f32 / DEG_SCALE;
+
+        let d_lat = other.lat_e8 - self.lat_e8;
+        let d_lon = wrap_lon(other.lon_e8 - self.lon_e8);
+
+        (
+            d_lat as f32 * m_per_unit(),
+            d_lon as f32 * m_per_unit() * lat_mean.to_radians().cos(),
+        )
+    }
+
+    /// Horizontal distance to another point, in m.
+    pub fn dist(&self, other: &Self) -> f32 {
+        let (n, e) = self.offset_ne(other);
+        n.hypot(e)
+    }
+
+    /// Bearing to another point, in radians clockwise from true North; 0 to τ. 0 if the points are
+    /// the same.
+    pub fn bearing(&self, other: &Self) -> f32 {
+        let (n, e) = self.offset_ne(other);
+        wrap_bearing(e.atan2(n))
+    }
+
+    /// This point, moved by a distance North, and East, in m. The inverse of `offset_ne`.
+    /// Latitude is clamped at the poles, and longitude wraps across the antimeridian.
+    pub fn offset_by(&self, north: f32, east: f32) -> Self {
+        let lat_e8 = (self.lat_e8 + (north / m_per_unit()) as i64).clamp(-LAT_MAX, LAT_MAX);
+
+        // Scale by the mean latitude, as in `offset_ne`, so the two round-trip.
+        let lat_mean = ((self.lat_e8 + lat_e8) / 2) as f32 / DEG_SCALE;
+        let d_lon = east / (m_per_unit() * lat_mean.to_radians().cos());
+
+        Self::new(lat_e8, wrap_lon(self.lon_e8 + d_lon as i64))
+    }
+}
+
+/// A position in a local frame anchored at the base point, in m: East, North, and Up.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Enu {
+    pub e: f32,
+    pub n: f32,
+    pub u: f32,
+}
+
+impl Enu {
+    /// Convert a position to the base point's frame. `alt_msl` is in m.
+    pub fn from_latlon(pt: &LatLon, alt_msl: f32, base: &PositVelEarthUnits) -> Self {
+        let (n, e) = LatLon::from_posit(base).offset_ne(pt);
+
+        Self {
+            e,
+            n,
+            u: alt_msl - base.elevation_msl,
+        }
+    }
+
+    /// Convert to lat/lon, and altitude MSL in m.
+    pub fn to_latlon(&self, base: &PositVelEarthUnits) -> (LatLon, f32) {
+        (
+            LatLon::from_posit(base).offset_by(self.n, self.e),
+            base.elevation_msl + self.u,
+        )
+    }
+
+    /// Horizontal distance to another point, in m.
+    pub fn dist_hor(&self, other: &Self) -> f32 {
+        (other.e - self.e).hypot(other.n - self.n)
+    }
+
+    /// Bearing to another point, in radians clockwise from true North; 0 to τ.
+    pub fn bearing(&self, other: &Self) -> f32 {
+        wrap_bearing((other.e - self.e).atan2(other.n - self.n))
+    }
+}
+
+/// A position, either absolute, or relative to the base point.
+#[derive(Clone, Copy)]
+pub enum Location {
+    /// Lat and lon, and altitude MSL, in m.
+    LatLon(LatLon, f32),
+    /// Relative to the base point, eg for points set without a GNSS fix.
+    Rel0(Enu),
+}
+
+impl Location {
+    pub fn from_posit(posit: &PositVelEarthUnits) -> Self {
+        Self::LatLon(LatLon::from_posit(posit), posit.elevation_msl)
+    }
+
+    /// Position in the base point's frame.
+    pub fn to_enu(&self, base: &PositVelEarthUnits) -> Enu {
+        match self {
+            Self::LatLon(pt, alt_msl) => Enu::from_latlon(pt, *alt_msl, base),
+            Self::Rel0(enu) => *enu,
+        }
+    }
+
+    /// Lat and lon, and altitude MSL in m.
+    pub fn to_latlon(&self, base: &PositVelEarthUnits) -> (LatLon, f32) {
+        match self {
+            Self::LatLon(pt, alt_msl) => (*pt, *alt_msl),
+            Self::Rel0(enu) => enu.to_latlon(base),
+        }
+    }
+
+    /// Horizontal distance to another location, in m. Lat/lon pairs don't use the base point.
+    pub fn dist(&self, other: &Self, base: &PositVelEarthUnits) -> f32 {
+        match (self, other) {
+            (Self::LatLon(a, _), Self::LatLon(b, _)) => a.dist(b),
+            _ => self.to_enu(base).dist_hor(&other.to_enu(base)),
+        }
+    }
+
+    /// Bearing to another location, in radians clockwise from true North; 0 to τ.
+    pub fn bearing(&self, other: &Self, base: &PositVelEarthUnits) -> f32 {
+        match (self, other) {
+            (Self::LatLon(a, _), Self::LatLon(b, _)) => a.bearing(b),
+            _ => self.to_enu(base).bearing(&other.to_enu(base)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deg(lat: f64, lon: f64) -> LatLon {
+        LatLon::new((lat * 1e8).round() as i64, (lon * 1e8).round() as i64)
+    }
+
+    fn assert_close(actual: f32, expected: f32, tol: f32) {
+        assert!(
+            (actual - expected).abs() <= tol,
+            "{actual} isn't within {tol} of {expected}"
+        );
+    }
+
+    #[test]
+    fn dist_and_bearing_known_pairs() {
+        // From, to, great-circle distance (m), and initial bearing (rad), from the haversine
+        // formula.
+        let pairs = [
+            // Space Needle to Pike Place Market, Seattle.
+            (
+                deg(47.6205, -122.3493),
+                deg(47.6097, -122.3422),
+                1_313.5,
+                2.7244,
+            ),
+            // Sydney Opera House to the Harbour Bridge.
+            (
+                deg(-33.8568, 151.2153),
+                deg(-33.8523, 151.2108),
+                650.4,
+                5.5901,
+            ),
+            // Due East, at 60°N, where longitude is scaled by half.
+            (deg(60., 10.), deg(60., 10.05), 2_779.9, 1.5704),
+            // Across the prime meridian.
+            (
+                deg(51.5007, -0.1246),
+                deg(51.5014, -0.1419),
+                1_200.0,
+                4.7774,
+            ),
+        ];
+
+        for (a, b, dist, bearing) in pairs {
+            assert_close(a.dist(&b), dist, dist * 0.002);
+            assert_close(b.dist(&a), dist, dist * 0.002);
+            assert_close(a.bearing(&b), bearing, 0.002);
+        }
+    }
+
+    #[test]
+    fn antimeridian() {
+        let west = deg(-17., 179.99);
+        let east = deg(-17., -179.99);
+
+        assert_close(west.dist(&east), 2_126.7, 2.);
+        assert_close(west.bearing(&east), TAU / 4., 0.002);
+        assert_close(east.bearing(&west), TAU * 3. / 4., 0.002);
+
+        let moved = west.offset_by(0., 2_126.7);
+        assert_close(moved.dist(&east), 0., 1.);
+        assert!(moved.lon_e8 < 0);
+    }
+
+    #[test]
+    fn cardinal_bearings() {
+        let a = deg(40., -105.);
+
+        assert_eq!(a.bearing(&a), 0.);
+        assert_close(a.bearing(&deg(40.01, -105.)), 0., 0.001);
+        assert_close(a.bearing(&deg(40., -104.99)), TAU / 4., 0.001);
+        assert_close(a.bearing(&deg(39.99, -105.)), TAU / 2., 0.001);
+        assert_close(a.bearing(&deg(40., -105.01)), TAU * 3. / 4., 0.001);
+    }
+
+    #[test]
+    fn offset_round_trip() {
+        let points = [
+            deg(47.6205, -122.3493),
+            deg(-33.8568, 151.2153),
+            deg(0., 0.),
+            deg(64.1466, -21.9426),
+            deg(-17., 179.999),
+        ];
+
+        for pt in points {
+            for (north, east) in [(500., -300.), (-2_000., 1_500.), (0., 4_000.)] {
+                let moved = pt.offset_by(north, east);
+                let (n, e) = pt.offset_ne(&moved);
+
+                assert_close(n, north, 0.5);
+                assert_close(e, east, 0.5);
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! This module contains flight metrics derived from GNSS fixes: Ground speed, distance flown this
+//! flight, and distance and bearing to home. These are displayed on the OSD, and sent in the
+//! telemetry stream; max ground speed is tracked in `flight_stats`.
+//!
+//! Distance is integrated from ground speed as each fix arrives. It pauses while we don't have a
+//! usable fix, and the gap isn't integrated once one returns. Below a minimum speed, we treat the
+//! aircraft as stationary, so GNSS speed noise while sitting still doesn't accumulate.
+//!
+//! Home is the position of the first usable fix after arming. Metrics reset on arming, and are held
+//! after disarming, until the next arm.
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    drivers::gps_ublox::GpsFix,
+    geo::LatLon,
+    safety::{self, ArmStatus},
+};
+
+// m/s. Below this, we don't accumulate distance. GNSS speed noise at rest is a few tenths of a m/s.
+const MIN_SPEED: f32 = 0.8;
+
+// Seconds. We don't integrate over longer intervals between fixes; eg a fix missed while the bus
+// was busy.
+const MAX_FIX_INTERVAL: f32 = 1.;
+
+const MPS_TO_MPH: f32 = 2.236_936;
+
+/// Units for speeds displayed on the OSD. Repr is how it's stored.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum SpeedUnits {
+    #[default]
+    Mps = 0,
+    Mph = 1,
+}
+
+impl SpeedUnits {
+    /// Convert a speed in m/s to these units.
+    pub fn convert(self, speed: f32) -> f32 {
+        match self {
+            Self::Mps => speed,
+            Self::Mph => speed * MPS_TO_MPH,
+        }
+    }
+
+    /// Displayed on the OSD, after the speed.
+    pub fn label(self) -> &'static [u8; 3] {
+        match self {
+            Self::Mps => b"M/S",
+            Self::Mph => b"MPH",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct GpsMetrics {
+    /// m/s. `None` if we don't have a usable fix.
+    pub ground_speed: Option<f32>,
+    /// Meters, this flight.
+    pub distance: f32,
+    /// Distance to home, in m, and bearing to it, in radians clockwise from true North. `None` if
+    /// we don't have a usable fix, or haven't set home.
+    pub home_dist_bearing: Option<(f32, f32)>,
+    home: Option<LatLon>,
+    /// Seconds since start. When we received the last fix processed; `None` after losing the fix.
+    last_fix: Option<f32>,
+    armed: bool,
+}
+
+impl GpsMetrics {
+    /// Run regularly; each fix is processed once, when it arrives. `fix_valid` is our GNSS status
+    /// being `Pass`.
+    pub fn update(&mut self, fix: &GpsFix, fix_valid: bool, arm_status: ArmStatus) {
+        let armed = arm_status == safety::MOTORS_ARMED;
+
+        if armed && !self.armed {
+            self.distance = 0.;
+            self.home = None;
+            self.home_dist_bearing = None;
+        }
+        self.armed = armed;
+
+        if !fix_valid {
+            self.ground_speed = None;
+            self.home_dist_bearing = None;
+            self.last_fix = None;
+            return;
+        }
+
+        if self.last_fix == Some(fix.timestamp) {
+            return;
+        }
+
+        let speed = fix.ground_speed;
+        let posit = LatLon::from_e7(fix.lat, fix.lon);
+
+        if let Some(t) = self.last_fix {
+            let dt = fix.timestamp - t;
+            if armed && speed >= MIN_SPEED && dt > 0. && dt <= MAX_FIX_INTERVAL {
+                self.distance += speed * dt;
+            }
+        }
+
+        if armed && self.home.is_none() {
+            self.home = Some(posit);
+        }
+
+        self.ground_speed = Some(speed);
+        self.home_dist_bearing = self
+            .home
+            .map(|home| (posit.dist(&home), posit.bearing(&home)));
+        self.last_fix = Some(fix.timestamp);
+    }
+}
@@ -0,0 +1,245 @@
+//! This module contains the ground reference: The field elevation, captured on arming, that
+//! altitude above takeoff is relative to. Autopilot altitude holds, the ceiling, and the OSD use
+//! altitude above takeoff by default; MSL altitude is still logged, and reported over USB.
+//!
+//! While disarmed, we average baro altitude, and GPS altitude if available, over one-second
+//! windows. On arming, the last window becomes the reference. Using the second before arming,
+//! vice after, keeps a quick takeoff out of the average. Altitude above takeoff is then baro
+//! altitude less its reference, so it doesn't move as the baro-to-GPS offset converges in flight.
+//! If the baro fails, we use GPS altitude less its reference, or the MSL estimate less field
+//! elevation. Field elevation MSL is the GPS average if available; otherwise, the baro average,
+//! with the baro-to-GPS offset applied.
+//!
+//! Re-arming recaptures, unless we're still airborne; eg after a disarm in flight. The PC can set
+//! the field elevation manually, eg when arming on a moving platform; this holds until it's
+//! cleared, or power is removed. Altitude above takeoff is then the MSL estimate less it.
+
+#[cfg(feature = "fixed-wing")]
+use ahrs::RIGHT;
+use defmt::println;
+#[cfg(feature = "fixed-wing")]
+use lin_alg::f32::Quaternion;
+
+use crate::{
+    alt_estimator::{AltEstimate, AltSource},
+    flight_ctrls::common::AttitudeCommanded,
+};
+
+// Seconds of readings averaged for each reference window.
+const WINDOW_TIME: f32 = 1.;
+
+// Manually-set field elevations outside this range, in m MSL, are rejected.
+const ELEVATION_MIN: f32 = -500.;
+const ELEVATION_MAX: f32 = 9_000.;
+
+// Source, field elevation, altitude above takeoff, and whether it's valid.
+pub const GROUND_REF_SIZE: usize = 1 + 4 + 4 + 1;
+// Set (1), or clear (0), and field elevation.
+pub const SET_GROUND_REF_SIZE: usize = 1 + 4;
+
+/// Where the reference is from. Repr is how it's passed over USB.
+#[derive(Clone, Copy, PartialEq, Default)]
+#[repr(u8)]
+pub enum GroundRefSource {
+    /// We haven't armed since power-up, or the manual reference was cleared.
+    #[default]
+    None = 0,
+    /// Averaged before arming.
+    Captured = 1,
+    /// Set over USB.
+    Manual = 2,
+}
+
+/// Sums of readings over a window.
+#[derive(Clone, Copy, Default)]
+struct Window {
+    time: f32,
+    baro_sum: f32,
+    baro_count: u32,
+    gps_sum: f32,
+    gps_count: u32,
+}
+
+impl Window {
+    /// Mean baro, and GPS altitude, if there were readings.
+    fn means(&self) -> (Option<f32>, Option<f32>) {
+        let mean = |sum: f32, count: u32| {
+            if count > 0 {
+                Some(sum / count as f32)
+            } else {
+                None
+            }
+        };
+
+        (
+            mean(self.baro_sum, self.baro_count),
+            mean(self.gps_sum, self.gps_count),
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct GroundRef {
+    pub source: GroundRefSource,
+    /// Field elevation, in m MSL. `None` if there's no reference.
+    pub elevation: Option<f32>,
+    /// Baro, and GPS altitude at the reference, in m MSL.
+    baro_ref: Option<f32>,
+    gps_ref: Option<f32>,
+    /// The window in progress, and the last complete one.
+    window: Window,
+    window_prev: Option<Window>,
+    armed: bool,
+    /// Set while the ceiling is limiting climb. Displayed on the OSD.
+    pub at_ceiling: bool,
+}
+
+impl GroundRef {
+    /// Run each altitude estimator update. `baro` and `gps` are altitudes MSL, in m, if valid.
+    /// `airborne` is takeoff having been detected, and not reset since.
+    pub fn update(
+        &mut self,
+        baro: Option<f32>,
+        gps: Option<f32>,
+        baro_offset: f32,
+        armed: bool,
+        airborne: bool,
+        dt: f32,
+    ) {
+        let arming = armed && !self.armed;
+        self.armed = armed;
+
+        if armed {
+            self.window = Default::default();
+        } else {
+            if let Some(b) = baro {
+                self.window.baro_sum += b;
+                self.window.baro_count += 1;
+            }
+            if let Some(g) = gps {
+                self.window.gps_sum += g;
+                self.window.gps_count += 1;
+            }
+
+            self.window.time += dt;
+            if self.window.time >= WINDOW_TIME {
+                self.window_prev = Some(self.window);
+                self.window = Default::default();
+            }
+        }
+
+        if !arming || airborne || self.source == GroundRefSource::Manual {
+            return;
+        }
+
+        // Eg if we arm within a second of power-up.
+        let (baro_ref, gps_ref) = match self.window_prev {
+            Some(w) => w.means(),
+            None => self.window.means(),
+        };
+
+        if baro_ref.is_none() && gps_ref.is_none() {
+            println!("No altitude readings at arming; keeping the previous ground reference");
+            return;
+        }
+
+        self.baro_ref = baro_ref;
+        self.gps_ref = gps_ref;
+        self.elevation = gps_ref.or(baro_ref.map(|b| b + baro_offset));
+        self.source = GroundRefSource::Captured;
+
+        println!(
+            "Ground reference captured. Field elevation: {}m",
+            self.elevation
+        );
+    }
+
+    /// Altitude above takeoff, in m. `None` without a reference, or readings to compare to it.
+    pub fn above_takeoff(
+        &self,
+        baro: Option<f32>,
+        gps: Option<f32>,
+        estimate: &AltEstimate,
+    ) -> Option<f32> {
+        match (baro, self.baro_ref, gps, self.gps_ref) {
+            (Some(b), Some(b_ref), _, _) => Some(b - b_ref),
+            (_, _, Some(g), Some(g_ref)) => Some(g - g_ref),
+            // Set manually, or the sensor we captured from has failed.
+            _ if estimate.msl_source != AltSource::None => self.elevation.map(|e| estimate.msl - e),
+            _ => None,
+        }
+    }
+
+    /// Set the field elevation manually, from the PC. The payload is set (1), or clear (0), then
+    /// field elevation, in m MSL. Clearing leaves no reference until the next arming. Returns
+    /// `false` if the payload is invalid.
+    pub fn set_manual(&mut self, payload: &[u8]) -> bool {
+        let elevation = f32::from_be_bytes(payload[1..5].try_into().unwrap());
+
+        match payload[0] {
+            0 => {
+                *self = Self {
+                    window: self.window,
+                    window_prev: self.window_prev,
+                    armed: self.armed,
+                    ..Default::default()
+                };
+            }
+            // This comparison also rejects NaN.
+            1 if (ELEVATION_MIN..=ELEVATION_MAX).contains(&elevation) => {
+                self.source = GroundRefSource::Manual;
+                self.elevation = Some(elevation);
+                self.baro_ref = None;
+                self.gps_ref = None;
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Run each time attitude and throttle commanded are updated, after they are. At or above the
+    /// ceiling, altitude above takeoff, in m, we prevent further climb: On quads, by capping
+    /// throttle at the hover estimate; on fixed-wing, by limiting pitch-up to level.
+    #[cfg_attr(feature = "fixed-wing", allow(unused_variables))]
+    pub fn limit_ceiling(
+        &mut self,
+        cmd: &mut AttitudeCommanded,
+        above_takeoff: Option<f32>,
+        ceiling: Option<f32>,
+        hover_throttle: f32,
+    ) {
+        self.at_ceiling = match (above_takeoff, ceiling) {
+            (Some(alt), Some(c)) => alt >= c,
+            _ => false,
+        };
+
+        if !self.at_ceiling {
+            return;
+        }
+
+        #[cfg(feature = "quad")]
+        {
+            cmd.throttle = cmd.throttle.min(hover_throttle);
+        }
+
+        #[cfg(feature = "fixed-wing")]
+        {
+            // As in `stall_protect`, lower the nose about the commanded attitude's pitch axis.
+            let pitch = cmd.quat.to_euler().pitch;
+            if pitch > 0. {
+                cmd.quat = (cmd.quat * Quaternion::from_axis_angle(RIGHT, pitch)).to_normalized();
+            }
+        }
+    }
+
+    pub fn to_bytes(&self, above_takeoff: Option<f32>) -> [u8; GROUND_REF_SIZE] {
+        let mut result = [0; GROUND_REF_SIZE];
+
+        result[0] = self.source as u8;
+        result[1..5].clone_from_slice(&self.elevation.unwrap_or(0.).to_be_bytes());
+        result[5..9].clone_from_slice(&above_takeoff.unwrap_or(0.).to_be_bytes());
+        result[9] = above_takeoff.is_some() as u8;
+        result
+    }
+}
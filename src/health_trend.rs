@@ -0,0 +1,300 @@
+//! This module contains per-flight health trends, for spotting gradual wear. For each motor, we
+//! average commanded power, and the RPM it achieved; a motor needing more power for the same RPM
+//! over successive flights indicates a damaged prop, or worn bearings. On fixed-wing, we also
+//! average servo commands while flying straight and level; these drift from 0 as the trim
+//! required changes, eg from a warped surface, or a slipping linkage.
+//!
+//! Averages are accumulated as integer sums of scaled readings, so they neither lose precision
+//! nor overflow over a long flight. At disarm, a compact record of the flight is added to a history
+//! of recent flights. The history is saved to the flight stats flash page, after the totals, and
+//! written along with them; see `flight_stats`. It's readable over USB.
+
+use ahrs::Params;
+use hal::flash::{Bank, Flash};
+use num_traits::Float;
+
+use crate::{
+    flight_ctrls::motor_servo::MotorServoState,
+    imu_processing::filter_imu::NUM_RPM_NOTCH_MOTORS,
+    safety::{self, ArmStatus},
+};
+
+// Flights stored. The oldest is replaced once full.
+pub const HEALTH_HISTORY_LEN: usize = 16;
+
+// Flight number (u32), and airborne time in s (u16). Then average power and RPM for each motor,
+// and average command for each servo; u16 and i16, scaled.
+pub const HEALTH_RECORD_SIZE: usize = 4 + 2 + NUM_RPM_NOTCH_MOTORS * 2 * 2 + NUM_SERVOS * 2;
+
+// Count and next index, then each record.
+pub const HEALTH_HISTORY_SIZE: usize = 2 + HEALTH_HISTORY_LEN * HEALTH_RECORD_SIZE;
+
+// Control surfaces 1 - 3, as mapped; see `ControlMapping::surfaces`. Always 0 on quads.
+const NUM_SERVOS: usize = 3;
+
+// Power and servo commands are stored as integers, in units of 1 / this.
+const CMD_SCALE: f32 = 10_000.;
+
+// On fixed-wing, we average servo commands only while roll is within this of level, and body
+// rates are below this; radians, and radians/s.
+#[cfg(feature = "fixed-wing")]
+const STRAIGHT_MAX_ROLL: f32 = 0.087;
+#[cfg(feature = "fixed-wing")]
+const STRAIGHT_MAX_RATE: f32 = 0.1;
+
+/// A summary of one flight.
+#[derive(Clone, Copy, Default)]
+pub struct HealthRecord {
+    /// From the flight stats totals; 1 is the airframe's first flight.
+    pub flight: u32,
+    /// Seconds.
+    pub airborne_time: u16,
+    /// Average power commanded, for each motor, in the order of `MotorServoState::rotor_rpms`;
+    /// in units of 1 / `CMD_SCALE`. 0 for motors without RPM readings.
+    pub power: [u16; NUM_RPM_NOTCH_MOTORS],
+    /// Average RPM, for each motor.
+    pub rpm: [u16; NUM_RPM_NOTCH_MOTORS],
+    /// Average servo command while flying straight, in units of 1 / `CMD_SCALE`. Fixed-wing only.
+    pub servo: [i16; NUM_SERVOS],
+}
+
+impl HealthRecord {
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_be_bytes(buf[i..i + 2].try_into().unwrap());
+
+        let mut result = Self {
+            flight: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            airborne_time: u16_at(4),
+            ..Default::default()
+        };
+
+        for m in 0..NUM_RPM_NOTCH_MOTORS {
+            result.power[m] = u16_at(6 + m * 4);
+            result.rpm[m] = u16_at(8 + m * 4);
+        }
+
+        let i = 6 + NUM_RPM_NOTCH_MOTORS * 4;
+        for s in 0..NUM_SERVOS {
+            result.servo[s] = u16_at(i + s * 2) as i16;
+        }
+
+        result
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEALTH_RECORD_SIZE] {
+        let mut result = [0; HEALTH_RECORD_SIZE];
+
+        result[0..4].clone_from_slice(&self.flight.to_be_bytes());
+        result[4..6].clone_from_slice(&self.airborne_time.to_be_bytes());
+
+        for m in 0..NUM_RPM_NOTCH_MOTORS {
+            let i = 6 + m * 4;
+            result[i..i + 2].clone_from_slice(&self.power[m].to_be_bytes());
+            result[i + 2..i + 4].clone_from_slice(&self.rpm[m].to_be_bytes());
+        }
+
+        let i = 6 + NUM_RPM_NOTCH_MOTORS * 4;
+        for s in 0..NUM_SERVOS {
+            result[i + s * 2..i + s * 2 + 2].clone_from_slice(&self.servo[s].to_be_bytes());
+        }
+
+        result
+    }
+}
+
+/// The most recent flights, in a ring buffer.
+#[derive(Clone, Copy, Default)]
+pub struct HealthHistory {
+    records: [HealthRecord; HEALTH_HISTORY_LEN],
+    /// Number stored; saturates at `HEALTH_HISTORY_LEN`.
+    count: usize,
+    /// Where the next record goes.
+    next: usize,
+}
+
+impl HealthHistory {
+    /// Returns an empty history if the buffer is invalid, eg from an erased page.
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let (count, next) = (buf[0] as usize, buf[1] as usize);
+
+        if count > HEALTH_HISTORY_LEN || next >= HEALTH_HISTORY_LEN {
+            return Default::default();
+        }
+
+        let mut result = Self {
+            count,
+            next,
+            ..Default::default()
+        };
+
+        for (i, record) in result.records.iter_mut().enumerate() {
+            let j = 2 + i * HEALTH_RECORD_SIZE;
+            *record = HealthRecord::from_bytes(&buf[j..j + HEALTH_RECORD_SIZE]);
+        }
+
+        result
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEALTH_HISTORY_SIZE] {
+        let mut result = [0; HEALTH_HISTORY_SIZE];
+
+        result[0] = self.count as u8;
+        result[1] = self.next as u8;
+
+        for (i, record) in self.records.iter().enumerate() {
+            let j = 2 + i * HEALTH_RECORD_SIZE;
+            result[j..j + HEALTH_RECORD_SIZE].clone_from_slice(&record.to_bytes());
+        }
+
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// A record by index; 0 is the oldest.
+    pub fn get(&self, i: usize) -> Option<&HealthRecord> {
+        if i >= self.count {
+            return None;
+        }
+
+        let start = (self.next + HEALTH_HISTORY_LEN - self.count) % HEALTH_HISTORY_LEN;
+        Some(&self.records[(start + i) % HEALTH_HISTORY_LEN])
+    }
+
+    fn push(&mut self, record: HealthRecord) {
+        self.records[self.next] = record;
+        self.next = (self.next + 1) % HEALTH_HISTORY_LEN;
+        self.count = (self.count + 1).min(HEALTH_HISTORY_LEN);
+    }
+}
+
+/// Sums over the current flight. With u64 sums, a sample per ms, of full-scale values, would take
+/// millions of years to overflow.
+#[derive(Default)]
+struct Accum {
+    power_sum: [u64; NUM_RPM_NOTCH_MOTORS],
+    rpm_sum: [u64; NUM_RPM_NOTCH_MOTORS],
+    /// Samples with an RPM reading, for each motor.
+    motor_count: [u32; NUM_RPM_NOTCH_MOTORS],
+    servo_sum: [i64; NUM_SERVOS],
+    servo_count: u32,
+    airborne_time: f32,
+}
+
+impl Accum {
+    fn record(&self, flight: u32) -> HealthRecord {
+        let mut result = HealthRecord {
+            flight,
+            airborne_time: self.airborne_time.min(u16::MAX as f32) as u16,
+            ..Default::default()
+        };
+
+        for m in 0..NUM_RPM_NOTCH_MOTORS {
+            let n = self.motor_count[m] as u64;
+            if n > 0 {
+                result.power[m] = (self.power_sum[m] / n).min(u16::MAX as u64) as u16;
+                result.rpm[m] = (self.rpm_sum[m] / n).min(u16::MAX as u64) as u16;
+            }
+        }
+
+        if self.servo_count > 0 {
+            for s in 0..NUM_SERVOS {
+                result.servo[s] = (self.servo_sum[s] / self.servo_count as i64) as i16;
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct HealthTrend {
+    /// Loaded from flash at power-up, and added to on each disarm after a flight.
+    pub history: HealthHistory,
+    accum: Accum,
+    armed: bool,
+}
+
+impl HealthTrend {
+    /// Run at init, before the main loop starts. Reads the history from the flight stats page.
+    pub fn load(flash: &mut Flash) -> Self {
+        let mut buf = [0; HEALTH_HISTORY_SIZE];
+        flash.read(
+            Bank::B1,
+            crate::FLASH_STATS_PAGE,
+            crate::flight_stats::HEALTH_HISTORY_OFFSET,
+            &mut buf,
+        );
+
+        Self {
+            history: HealthHistory::from_bytes(&buf),
+            ..Default::default()
+        }
+    }
+
+    /// Run periodically, after `FlightStatsState::update`, so `flights` includes one just ended.
+    /// `dt` is in seconds.
+    pub fn update(
+        &mut self,
+        arm_status: ArmStatus,
+        has_taken_off: bool,
+        motor_servo_state: &MotorServoState,
+        params: &Params,
+        flights: u32,
+        dt: f32,
+    ) {
+        let armed = arm_status == safety::MOTORS_ARMED;
+
+        if armed && !self.armed {
+            self.accum = Default::default();
+        } else if !armed && self.armed && self.accum.airborne_time > 0. {
+            self.history.push(self.accum.record(flights));
+        }
+        self.armed = armed;
+
+        if !armed || !has_taken_off {
+            return;
+        }
+
+        let a = &mut self.accum;
+        a.airborne_time += dt;
+
+        let powers = motor_servo_state.rotor_powers();
+        let rpms = motor_servo_state.rotor_rpms();
+
+        for m in 0..NUM_RPM_NOTCH_MOTORS {
+            // Without RPM, there's nothing to compare power to.
+            if let Some(rpm) = rpms[m] {
+                // In 3D mode, power may be negative.
+                a.power_sum[m] += (powers[m].abs() * CMD_SCALE) as u64;
+                a.rpm_sum[m] += rpm.max(0.) as u64;
+                a.motor_count[m] += 1;
+            }
+        }
+
+        #[cfg(feature = "fixed-wing")]
+        {
+            let straight = params.s_roll.abs() < STRAIGHT_MAX_ROLL
+                && params.v_pitch.abs() < STRAIGHT_MAX_RATE
+                && params.v_roll.abs() < STRAIGHT_MAX_RATE
+                && params.v_yaw.abs() < STRAIGHT_MAX_RATE;
+
+            if straight {
+                let s = &motor_servo_state.surfaces;
+                let cmds: [f32; NUM_SERVOS] =
+                    core::array::from_fn(|i| s[i].map_or(0., |s| s.posit_cmd));
+
+                for (sum, cmd) in a.servo_sum.iter_mut().zip(cmds) {
+                    *sum += (cmd * CMD_SCALE) as i64;
+                }
+                a.servo_count += 1;
+            }
+        }
+
+        // Attitude is only used for servo averages.
+        #[cfg(feature = "quad")]
+        let _ = params;
+    }
+}
@@ -0,0 +1,171 @@
+//! This module contains a bench hardware-in-the-loop (HIL) mode, for testing control logic
+//! without flying. The PC sends synthetic IMU, baro, and GPS samples over USB; while HIL is active,
+//! these replace the sensor readings in the main loop, so AHRS, filters, flight controls, and the
+//! autopilot all run unmodified. After each flight control update, we send the resulting motor
+//! commands back to the PC, for its simulation.
+//!
+//! HIL can only be enabled from Preflight, while disarmed with motors stopped. Enabling it stops
+//! the motors, and while active, `dshot` sends 0 power regardless of what's commanded. It can be
+//! armed once active, eg from the radio, or over USB, so the flight controls run. It can only be
+//! disabled while disarmed; this returns to Preflight.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ahrs::FixType;
+use anyleaf_usb::{CRC_LEN, PAYLOAD_START_I};
+use cortex_m::interrupt;
+use usbd_serial::SerialPort;
+
+use crate::{
+    drivers::gps_ublox::GpsFix,
+    protocols::usb_preflight::{self, MsgType},
+    safety::ArmStatus,
+    setup,
+};
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// The latest sample from the PC, and whether it's new since the main loop last took it.
+static mut SAMPLE: Option<(HilSample, bool)> = None;
+
+// Reported for synthetic GPS fixes.
+const GPS_NUM_SV: u8 = 12;
+const GPS_H_ACC: f32 = 1.;
+
+// Gyro, accel, baro altitude and vertical velocity, then GPS valid flag, lat, lon, altitude MSL,
+// ground speed, and course.
+pub const HIL_SAMPLE_SIZE: usize = 4 * 3 + 4 * 3 + 4 * 2 + 1 + 4 * 5;
+// Timestamp, motor powers, and arm status.
+pub const HIL_OUTPUT_SIZE: usize = 4 + 4 * 4 + 1;
+
+/// Synthetic sensor readings, from the PC's simulation.
+#[derive(Clone, Copy)]
+pub struct HilSample {
+    /// Pitch, roll, and yaw rates, in rad/s.
+    pub gyro: (f32, f32, f32),
+    /// m/s^2, in the IMU's frame.
+    pub accel: (f32, f32, f32),
+    /// Meters MSL.
+    pub alt_baro: f32,
+    /// m/s.
+    pub v_z_baro: f32,
+    /// `None` if the simulated GPS has no fix. `timestamp` is set when taken.
+    pub gps: Option<GpsFix>,
+}
+
+impl HilSample {
+    /// Returns `None` if any value is non-finite.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let int = |i: usize| i32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        for i in (0..32).step_by(4).chain((41..53).step_by(4)) {
+            if !f(i).is_finite() {
+                return None;
+            }
+        }
+
+        let gps = if buf[32] != 0 {
+            Some(GpsFix {
+                timestamp: 0.,
+                lat: int(33),
+                lon: int(37),
+                alt_msl: f(41),
+                ground_speed: f(45),
+                course: f(49),
+                fix_type: FixType::Fix3d,
+                num_sv: GPS_NUM_SV,
+                h_acc: GPS_H_ACC,
+            })
+        } else {
+            None
+        };
+
+        Some(Self {
+            gyro: (f(0), f(4), f(8)),
+            accel: (f(12), f(16), f(20)),
+            alt_baro: f(24),
+            v_z_baro: f(28),
+            gps,
+        })
+    }
+}
+
+/// Motor commands from a flight control update, sent to the PC.
+pub struct HilOutput {
+    /// Seconds since start.
+    pub timestamp: f32,
+    /// 0. to 1. In the order of `MotorServoState::rotor_powers`.
+    pub motor_powers: [f32; 4],
+    pub arm_status: ArmStatus,
+}
+
+impl HilOutput {
+    fn to_bytes(&self) -> [u8; HIL_OUTPUT_SIZE] {
+        let mut result = [0; HIL_OUTPUT_SIZE];
+
+        result[0..4].clone_from_slice(&self.timestamp.to_be_bytes());
+        for (i, p) in self.motor_powers.iter().enumerate() {
+            result[4 + i * 4..8 + i * 4].clone_from_slice(&p.to_be_bytes());
+        }
+        result[20] = self.arm_status as u8;
+        result
+    }
+}
+
+/// True while HIL mode is active. Motors must stay stopped.
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Check the interlocks before calling; Preflight, disarmed, and no motors running. Stop the
+/// motors after calling.
+pub fn enable() {
+    interrupt::free(|_| unsafe { SAMPLE = None });
+    ACTIVE.store(true, Ordering::Release);
+}
+
+/// Check that we're disarmed before calling.
+pub fn disable() {
+    ACTIVE.store(false, Ordering::Release);
+}
+
+/// Run from the USB ISR, on receiving a sample.
+pub fn store_sample(sample: HilSample) {
+    if !active() {
+        return;
+    }
+    interrupt::free(|_| unsafe { SAMPLE = Some((sample, true)) });
+}
+
+/// Run each main loop update. The latest sample, and whether it's new since the last call; the
+/// IMU readings are held between samples. `None` if HIL isn't active, or we haven't received one.
+pub fn take_sample() -> Option<(HilSample, bool)> {
+    if !active() {
+        return None;
+    }
+
+    interrupt::free(|_| {
+        let sample = unsafe { &mut *core::ptr::addr_of_mut!(SAMPLE) };
+
+        let result = *sample;
+        if let Some((_, new)) = sample {
+            *new = false;
+        }
+        result
+    })
+}
+
+/// Send motor commands to the PC. Skipped if the previous one hasn't left the buffer, so we don't
+/// block the main loop.
+pub fn send_output(output: &HilOutput, usb_serial: &mut SerialPort<'static, setup::UsbBusType>) {
+    if usb_serial.flush().is_err() {
+        return;
+    }
+
+    usb_preflight::send_payload::<{ HIL_OUTPUT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+        MsgType::HilOutput,
+        &output.to_bytes(),
+        usb_serial,
+    );
+}
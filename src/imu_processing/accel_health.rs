@@ -0,0 +1,225 @@
+//! This module contains accelerometer health monitoring, for attitude fusion. If the accelerometer's
+//! norm is persistently outside a plausible band, or it's clipping, eg from a failed sensor, or
+//! heavy vibration, its correction would drag the attitude estimate off. In that case, we
+//! propagate attitude from the gyro only, until the accelerometer has been healthy for a set time.
+//!
+//! The AHRS doesn't have a gyro-only mode, so we pass it an accelerometer reading that agrees with
+//! its current attitude estimate; its accel correction is then 0. On recovery, we blend from that
+//! towards the measured reading over `AccelHealthCfg::reconverge_time`, so the attitude doesn't
+//! jump.
+
+use ahrs::ImuReadings;
+use lin_alg::f32::{Quaternion, Vec3};
+use num_traits::Float;
+
+use crate::{
+    event_log::{self, EventCode},
+    imu_processing::imu_shared,
+    system_status::SystemStatus,
+};
+
+const G: f32 = 9.8;
+
+// Any axis at or above this portion of full scale is clipping. Slightly below 1, since the
+// readings may be filtered.
+const CLIP_FRACTION: f32 = 0.95;
+
+// Config values outside these ranges are rejected when loading.
+const NORM_MAX_MAX: f32 = 16.;
+const TIME_MAX: f32 = 60.;
+
+// Serialized size: Norm min and max, fault time, recover time, and reconverge time.
+pub const ACCEL_HEALTH_CFG_SIZE: usize = 4 * 5;
+
+/// Accelerometer health thresholds. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct AccelHealthCfg {
+    /// Accelerometer norm, in G, relative to its length at rest. Outside this band, the reading
+    /// is implausible. This includes legitimate, sustained non-1G flight, where the accelerometer
+    /// doesn't indicate attitude anyway.
+    pub norm_min: f32,
+    pub norm_max: f32,
+    /// Seconds of implausible or clipping readings in a row before switching to gyro-only.
+    pub fault_time: f32,
+    /// Seconds of healthy readings in a row before returning to full fusion.
+    pub recover_time: f32,
+    /// Seconds to blend the accelerometer back in, on recovery.
+    pub reconverge_time: f32,
+}
+
+impl Default for AccelHealthCfg {
+    fn default() -> Self {
+        Self {
+            norm_min: 0.3,
+            norm_max: 4.,
+            fault_time: 0.2,
+            recover_time: 1.,
+            reconverge_time: 3.,
+        }
+    }
+}
+
+impl AccelHealthCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let result = Self {
+            norm_min: f(0),
+            norm_max: f(4),
+            fault_time: f(8),
+            recover_time: f(12),
+            reconverge_time: f(16),
+        };
+
+        // These comparisons also reject NaN.
+        if !(0.0..=NORM_MAX_MAX).contains(&result.norm_min)
+            || !(result.norm_min..=NORM_MAX_MAX).contains(&result.norm_max)
+            || !(0.0..=TIME_MAX).contains(&result.fault_time)
+            || !(0.0..=TIME_MAX).contains(&result.recover_time)
+            || !(0.0..=TIME_MAX).contains(&result.reconverge_time)
+        {
+            return None;
+        }
+
+        Some(result)
+    }
+
+    pub fn to_bytes(&self) -> [u8; ACCEL_HEALTH_CFG_SIZE] {
+        let mut result = [0; ACCEL_HEALTH_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.norm_min.to_be_bytes());
+        result[4..8].clone_from_slice(&self.norm_max.to_be_bytes());
+        result[8..12].clone_from_slice(&self.fault_time.to_be_bytes());
+        result[12..16].clone_from_slice(&self.recover_time.to_be_bytes());
+        result[16..20].clone_from_slice(&self.reconverge_time.to_be_bytes());
+        result
+    }
+}
+
+/// How the accelerometer is used in attitude fusion. Repr is how this is passed over USB, and in
+/// the event log.
+#[derive(Clone, Copy, PartialEq, Default)]
+#[repr(u8)]
+pub enum FusionMode {
+    /// Gyro and accelerometer.
+    #[default]
+    Full = 0,
+    /// The accelerometer is unhealthy; attitude is propagated from the gyro only.
+    GyroOnly = 1,
+    /// The accelerometer is healthy again, and being blended back in.
+    Reconverging = 2,
+}
+
+#[derive(Default)]
+pub struct AccelHealth {
+    pub mode: FusionMode,
+    /// Seconds of unhealthy readings in a row.
+    bad_time: f32,
+    /// Seconds of healthy readings in a row.
+    good_time: f32,
+    /// While reconverging, the accelerometer's weight. 0. to 1.
+    weight: f32,
+    /// The latest norm, in G, relative to the length at rest.
+    norm: f32,
+}
+
+impl AccelHealth {
+    /// Run each IMU update, prior to the AHRS update. `acc_len_at_rest` is in m/s^2.
+    pub fn update(
+        &mut self,
+        readings: &ImuReadings,
+        acc_len_at_rest: f32,
+        cfg: &AccelHealthCfg,
+        system_status: &mut SystemStatus,
+        dt: f32,
+    ) {
+        // Before the accelerometer is calibrated, compare against 1G.
+        let len_at_rest = if acc_len_at_rest > 0. {
+            acc_len_at_rest
+        } else {
+            G
+        };
+
+        let accel = Vec3::new(readings.a_x, readings.a_y, readings.a_z);
+        self.norm = accel.magnitude() / len_at_rest;
+
+        let clip_thresh = CLIP_FRACTION * imu_shared::fullscale().accel;
+        let clipping = [readings.a_x, readings.a_y, readings.a_z]
+            .iter()
+            .any(|a| a.abs() >= clip_thresh);
+
+        // This comparison also rejects NaN.
+        let healthy = !clipping && (cfg.norm_min..=cfg.norm_max).contains(&self.norm);
+
+        if healthy {
+            self.good_time += dt;
+            self.bad_time = 0.;
+        } else {
+            self.bad_time += dt;
+            self.good_time = 0.;
+        }
+
+        match self.mode {
+            FusionMode::Full | FusionMode::Reconverging => {
+                if self.bad_time >= cfg.fault_time {
+                    self.set_mode(FusionMode::GyroOnly);
+                } else if self.mode == FusionMode::Reconverging {
+                    self.weight = if cfg.reconverge_time > 0. {
+                        self.weight + dt / cfg.reconverge_time
+                    } else {
+                        1.
+                    };
+
+                    if self.weight >= 1. {
+                        self.set_mode(FusionMode::Full);
+                    }
+                }
+            }
+            FusionMode::GyroOnly => {
+                if self.good_time >= cfg.recover_time {
+                    self.weight = 0.;
+                    self.set_mode(FusionMode::Reconverging);
+                }
+            }
+        }
+
+        system_status.accel_fault = self.mode == FusionMode::GyroOnly;
+    }
+
+    fn set_mode(&mut self, mode: FusionMode) {
+        self.mode = mode;
+
+        // The norm is reported as G x 100; this saturates.
+        event_log::log(
+            EventCode::FusionMode,
+            mode as u16,
+            (self.norm * 100.).clamp(0., u16::MAX as f32) as u16,
+        );
+    }
+
+    /// The accelerometer reading to pass to the AHRS, in m/s^2. In gyro-only mode, this is the
+    /// reading we'd expect at rest, from `attitude`, the current estimate; the AHRS's accel
+    /// correction is then 0. While reconverging, this blends towards the measured reading.
+    pub fn accel_for_fusion(
+        &self,
+        measured: (f32, f32, f32),
+        attitude: Quaternion,
+        acc_len_at_rest: f32,
+    ) -> (f32, f32, f32) {
+        let weight = match self.mode {
+            FusionMode::Full => return measured,
+            FusionMode::GyroOnly => 0.,
+            FusionMode::Reconverging => self.weight.clamp(0., 1.),
+        };
+
+        // The inverse of the earth-frame conversion we use for vertical acceleration.
+        let expected = attitude.rotate_vec(Vec3::new(0., 0., acc_len_at_rest));
+
+        (
+            expected.x + (measured.0 - expected.x) * weight,
+            expected.y + (measured.1 - expected.y) * weight,
+            expected.z + (measured.2 - expected.z) * weight,
+        )
+    }
+}
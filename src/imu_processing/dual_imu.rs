@@ -0,0 +1,435 @@
+//! This module contains dual-IMU operation: An ISM330DHCX alongside the ICM-426xx, on its own SPI
+//! bus. Both are read each update. The active IMU's readings go through the IMU pipeline. We
+//! cross-check gyro rates between the two, and fail over to the other if they diverge beyond a
+//! threshold for a sustained period, or if the active IMU fails its integrity checks. Failing over
+//! resets the rate integrators and filter state, and is logged in the event log.
+//!
+//! Caveats:
+//! - The main loop is clocked by the ICM's data-ready line, even if the ISM is primary. If the ICM
+//!   stops sending data entirely, the loop stops; the sensor watchdog handles that.
+//! - The ISM's readings are from the previous update.
+//! - The ISM's mounting relative to the ICM is set in config. Its readings are rotated to the ICM's
+//!   axes before anything else uses them.
+//! - Calibrations are the ICM's. After power-up, once we've been disarmed and still for
+//!   `CAL_TIME`, we measure the ISM's offsets from the ICM, and remove them from its readings. Until
+//!   then, the ISM isn't used or cross-checked, even if it's primary. Gyro temperature compensation
+//!   is skipped while the ISM is active.
+//! - Divergence doesn't tell us which IMU is wrong, so we only fail over on it once. We switch
+//!   back only if the active IMU fails its integrity checks.
+//! - Whether it's enabled, and which IMU is primary, apply at init. Only the H7 board has a bus for
+//!   the secondary.
+
+use ahrs::ImuReadings;
+use defmt::println;
+use num_enum::TryFromPrimitive;
+use num_traits::Float;
+
+use crate::{
+    drivers::imu_ism330dhcx as imu2,
+    event_log::{self, EventCode},
+    flight_ctrls::filters::FlightCtrlFilters,
+    imu_processing::{
+        imu_integrity::{ImuIntegrity, MAX_IMPLAUSIBLE_TIME},
+        imu_shared::{self, READINGS_BUF_SIZE},
+    },
+    system_status::{SensorStatus, SystemStatus},
+};
+
+// Values outside these ranges are rejected when loading config; in rad/s, and s.
+pub const MAX_DIFF_MIN: f32 = 0.05;
+pub const MAX_DIFF_MAX: f32 = 2.;
+pub const MAX_DIFF_TIME_MIN: f32 = 0.01;
+pub const MAX_DIFF_TIME_MAX: f32 = 1.;
+
+// Serialized size: Enabled, primary, cross-check, max difference, max difference time, and
+// orientation.
+pub const DUAL_IMU_CFG_SIZE: usize = 1 + 1 + 1 + 4 + 4 + 1;
+
+// Time constant of the gyro difference filter, in s. This rejects noise, and the ISM's one-update
+// lag during fast rotation.
+const DIFF_TAU: f32 = 0.01;
+
+// If we haven't received ISM readings for this long, in s, it's unhealthy.
+const ISM_STALE_TIME: f32 = 0.01;
+
+// We average the ISM's offsets from the ICM over this long, in s, while disarmed and still.
+const CAL_TIME: f32 = 1.;
+// Gyro rates above this on any axis, in rad/s, aren't still enough to calibrate.
+const CAL_MAX_GYRO: f32 = 0.05;
+
+/// Repr is how it's stored in config, and passed in the event log.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ImuId {
+    #[default]
+    Icm426xx = 0,
+    Ism330dhcx = 1,
+}
+
+impl ImuId {
+    fn other(self) -> Self {
+        match self {
+            Self::Icm426xx => Self::Ism330dhcx,
+            Self::Ism330dhcx => Self::Icm426xx,
+        }
+    }
+}
+
+/// The ISM's mounting, relative to the ICM: Optionally flipped, ie rotated 180° about the X axis,
+/// then rotated clockwise about the Z axis, looking down. Repr is how it's stored in config.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Imu2Orientation {
+    #[default]
+    Aligned = 0,
+    Cw90 = 1,
+    Cw180 = 2,
+    Cw270 = 3,
+    Flipped = 4,
+    FlippedCw90 = 5,
+    FlippedCw180 = 6,
+    FlippedCw270 = 7,
+}
+
+impl Imu2Orientation {
+    /// Rotate a readings buffer, in the ICM's layout, from the ISM's axes to the ICM's.
+    fn apply(self, buf: &mut [u8; READINGS_BUF_SIZE]) {
+        let flipped = self as u8 >= Self::Flipped as u8;
+        let cw_steps = self as u8 % 4;
+
+        // Accelerometer, then gyro.
+        for start in [3, 9] {
+            let word = |axis: usize| {
+                i16::from_be_bytes([buf[start + axis * 2], buf[start + axis * 2 + 1]])
+            };
+
+            let (mut x, mut y, mut z) = (word(0), word(1), word(2));
+
+            if flipped {
+                y = y.saturating_neg();
+                z = z.saturating_neg();
+            }
+
+            // Each step maps the ISM's +X to the ICM's -Y, and its +Y to the ICM's +X.
+            for _ in 0..cw_steps {
+                (x, y) = (y, x.saturating_neg());
+            }
+
+            for (axis, v) in [x, y, z].iter().enumerate() {
+                buf[start + axis * 2..start + axis * 2 + 2].clone_from_slice(&v.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Why we failed over. Repr is how it's passed in the event log.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum FailoverCause {
+    Divergence = 0,
+    Health = 1,
+}
+
+/// Dual-IMU settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct DualImuCfg {
+    /// Set up and read the secondary IMU. Applied at init.
+    pub enabled: bool,
+    /// The IMU used until a failover. Applied at init, or for the ISM, once it's calibrated.
+    pub primary: ImuId,
+    /// Fail over on sustained divergence. If disabled, we only fail over on integrity failures.
+    pub cross_check: bool,
+    /// Gyro rates differing by more than this on any axis, in rad/s, after filtering, diverge.
+    pub max_diff: f32,
+    /// We fail over once rates diverge for this long, in s.
+    pub max_diff_time: f32,
+    /// The ISM's mounting, relative to the ICM.
+    pub orientation: Imu2Orientation,
+}
+
+impl Default for DualImuCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            primary: Default::default(),
+            cross_check: true,
+            max_diff: 0.35,
+            max_diff_time: 0.1,
+            orientation: Default::default(),
+        }
+    }
+}
+
+impl DualImuCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let max_diff = f(3);
+        let max_diff_time = f(7);
+
+        // These comparisons also reject NaN.
+        if buf[0] > 1
+            || buf[2] > 1
+            || !(MAX_DIFF_MIN..=MAX_DIFF_MAX).contains(&max_diff)
+            || !(MAX_DIFF_TIME_MIN..=MAX_DIFF_TIME_MAX).contains(&max_diff_time)
+        {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            primary: ImuId::try_from(buf[1]).ok()?,
+            cross_check: buf[2] != 0,
+            max_diff,
+            max_diff_time,
+            orientation: Imu2Orientation::try_from(buf[11]).ok()?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; DUAL_IMU_CFG_SIZE] {
+        let mut result = [0; DUAL_IMU_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1] = self.primary as u8;
+        result[2] = self.cross_check as u8;
+        result[3..7].clone_from_slice(&self.max_diff.to_be_bytes());
+        result[7..11].clone_from_slice(&self.max_diff_time.to_be_bytes());
+        result[11] = self.orientation as u8;
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct DualImu {
+    /// The IMU whose readings the pipeline uses.
+    pub active: ImuId,
+    /// The ISM is set up, and read each update. Set at init.
+    present: bool,
+    /// From config. If it's the ISM, we switch to it once it's calibrated.
+    primary: ImuId,
+    /// The ISM's offsets from the ICM, subtracted from its readings: Gyro pitch, roll, and yaw, in
+    /// rad/s, then accelerometer X, Y, and Z, in m/s². `None` until calibrated.
+    offsets: Option<[f32; 6]>,
+    /// Time-weighted sums of offsets, and the time summed, in s, towards calibration.
+    cal_sum: [f32; 6],
+    cal_time: f32,
+    /// Plausibility checks on the ISM's readings.
+    integrity: ImuIntegrity,
+    /// The ISM's latest readings, in the ICM's buffer layout.
+    buf: [u8; READINGS_BUF_SIZE],
+    /// Since the ISM's last readings, in s.
+    since_readings: f32,
+    /// The ISM's readings have been implausible for this long, in s.
+    implausible_time: f32,
+    /// Filtered gyro rate differences, pitch, roll, and yaw, in rad/s.
+    diff: (f32, f32, f32),
+    diverged_time: f32,
+    /// We've failed over on divergence; we don't again.
+    diverge_failed_over: bool,
+    /// Set on a failover; taken by the flight controls, which own the D-term filters.
+    filter_reset_pending: bool,
+}
+
+impl DualImu {
+    /// Run at init, once the IMUs are set up. If the ISM didn't respond, we use the ICM only.
+    pub fn new(cfg: &DualImuCfg, system_status: &SystemStatus) -> Self {
+        let present = cfg.enabled && system_status.imu_secondary == SensorStatus::Pass;
+
+        if cfg.enabled && !present {
+            println!("Secondary IMU unavailable; using the ICM only");
+        }
+
+        Self {
+            active: ImuId::Icm426xx,
+            present,
+            primary: cfg.primary,
+            ..Default::default()
+        }
+    }
+
+    /// Run on each IMU update, after the ICM's integrity check, and before re-initializing it.
+    /// `icm` is its readings. Returns `true` if we failed over this update; the caller resets
+    /// rate integrators, and IMU filter state.
+    pub fn update(
+        &mut self,
+        icm: &ImuReadings,
+        icm_healthy: bool,
+        cfg: &DualImuCfg,
+        disarmed: bool,
+        system_status: &mut SystemStatus,
+        dt: f32,
+    ) -> bool {
+        if !self.present {
+            return false;
+        }
+
+        self.since_readings += dt;
+
+        if let Some(mut buf) = imu_shared::take_imu2_readings() {
+            cfg.orientation.apply(&mut buf);
+            self.buf = buf;
+            self.since_readings = 0.;
+
+            let ism = self.ism_readings();
+            if self.integrity.plausible(&self.buf, &ism, disarmed) {
+                self.implausible_time = 0.;
+            } else {
+                self.implausible_time += dt;
+            }
+        }
+
+        let ism = self.ism_readings();
+        let ism_healthy =
+            self.since_readings < ISM_STALE_TIME && self.implausible_time < MAX_IMPLAUSIBLE_TIME;
+
+        system_status.imu_secondary = if ism_healthy {
+            SensorStatus::Pass
+        } else {
+            SensorStatus::Fault
+        };
+
+        if self.offsets.is_none() {
+            let still = disarmed
+                && icm_healthy
+                && ism_healthy
+                && icm.v_pitch.abs() < CAL_MAX_GYRO
+                && icm.v_roll.abs() < CAL_MAX_GYRO
+                && icm.v_yaw.abs() < CAL_MAX_GYRO;
+
+            if !self.calibrate(icm, &ism, still, dt) || self.primary != ImuId::Ism330dhcx {
+                return false;
+            }
+
+            // Disarmed, so switching is safe; the caller resets state as on a failover.
+            self.active = ImuId::Ism330dhcx;
+            self.filter_reset_pending = true;
+            return true;
+        }
+
+        let a = dt / (DIFF_TAU + dt);
+        self.diff.0 += a * ((icm.v_pitch - ism.v_pitch).abs() - self.diff.0);
+        self.diff.1 += a * ((icm.v_roll - ism.v_roll).abs() - self.diff.1);
+        self.diff.2 += a * ((icm.v_yaw - ism.v_yaw).abs() - self.diff.2);
+
+        // Only while both are healthy; otherwise, the health check handles it.
+        let diverged = icm_healthy
+            && ism_healthy
+            && self.diff.0.max(self.diff.1).max(self.diff.2) > cfg.max_diff;
+
+        if diverged {
+            self.diverged_time += dt;
+        } else {
+            self.diverged_time = 0.;
+        }
+
+        let (active_healthy, other_healthy) = match self.active {
+            ImuId::Icm426xx => (icm_healthy, ism_healthy),
+            ImuId::Ism330dhcx => (ism_healthy, icm_healthy),
+        };
+
+        let cause = if !active_healthy {
+            FailoverCause::Health
+        } else if cfg.cross_check
+            && !self.diverge_failed_over
+            && self.diverged_time >= cfg.max_diff_time
+        {
+            self.diverge_failed_over = true;
+            FailoverCause::Divergence
+        } else {
+            return false;
+        };
+
+        if !other_healthy {
+            return false;
+        }
+
+        self.active = self.active.other();
+        self.diverged_time = 0.;
+        self.filter_reset_pending = true;
+        system_status.imu_failed_over = true;
+
+        println!(
+            "IMU failover. Active: {}, cause: {}",
+            self.active as u8, cause as u8
+        );
+        event_log::log(EventCode::ImuFailover, self.active as u16, cause as u16);
+
+        true
+    }
+
+    /// If the ISM is active, replace the readings and temperature with its. Run after `update`.
+    pub fn apply(&self, readings: &mut ImuReadings, temp: &mut f32) {
+        if self.active != ImuId::Ism330dhcx {
+            return;
+        }
+
+        *readings = self.ism_readings();
+        *temp = imu_shared::temp_from_buffer(&self.buf);
+    }
+
+    /// Run from the flight controls, with the filters locked.
+    pub fn reset_filters_if_pending(&mut self, filters: &mut FlightCtrlFilters) {
+        if self.filter_reset_pending {
+            filters.reset_d_terms();
+            self.filter_reset_pending = false;
+        }
+    }
+
+    /// Accumulate the ISM's offsets from the ICM while `still`; restart if not. Returns `true`
+    /// once calibration completes.
+    fn calibrate(&mut self, icm: &ImuReadings, ism: &ImuReadings, still: bool, dt: f32) -> bool {
+        if !still {
+            self.cal_sum = [0.; 6];
+            self.cal_time = 0.;
+            return false;
+        }
+
+        let diff = [
+            ism.v_pitch - icm.v_pitch,
+            ism.v_roll - icm.v_roll,
+            ism.v_yaw - icm.v_yaw,
+            ism.a_x - icm.a_x,
+            ism.a_y - icm.a_y,
+            ism.a_z - icm.a_z,
+        ];
+
+        for (sum, d) in self.cal_sum.iter_mut().zip(diff) {
+            *sum += d * dt;
+        }
+        self.cal_time += dt;
+
+        if self.cal_time < CAL_TIME {
+            return false;
+        }
+
+        let offsets = self.cal_sum.map(|s| s / self.cal_time);
+        self.offsets = Some(offsets);
+
+        println!(
+            "Secondary IMU calibrated. Gyro offsets: p{} r{} y{}",
+            offsets[0], offsets[1], offsets[2]
+        );
+        true
+    }
+
+    /// The ISM's readings, with its offsets removed once calibrated.
+    fn ism_readings(&self) -> ImuReadings {
+        let mut result = ImuReadings::from_buffer(
+            imu_shared::accel_gyro_buf(&self.buf),
+            imu2::ACCEL_FULLSCALE,
+            imu2::GYRO_FULLSCALE,
+        );
+
+        if let Some(o) = self.offsets {
+            result.v_pitch -= o[0];
+            result.v_roll -= o[1];
+            result.v_yaw -= o[2];
+            result.a_x -= o[3];
+            result.a_y -= o[4];
+            result.a_z -= o[5];
+        }
+
+        result
+    }
+}
@@ -1,13 +1,20 @@
-//! This module contains filtering code for the IMU, including an IIR bessel lowpass, and IIR
-//! notch filters for RPM filtering.
+//! This module contains filtering code for the IMU, including a configurable IIR lowpass chain
+//! for the gyro, and IIR notch filters for RPM filtering.
 //!
 //! Reference: https://brushlesswhoop.com/betaflight-rpm-filter/
 
+use core::f32::consts::{PI, TAU};
+
 use ahrs::ImuReadings;
 use cmsis_dsp_api as dsp_api;
 use dsp_api::iir_new;
+use num_enum::TryFromPrimitive;
+use num_traits::Float;
 
-use crate::util::{iir_apply, IirInstWrapper};
+use crate::{
+    loop_rates,
+    util::{iir_apply, IirInstWrapper},
+};
 
 // const BLOCK_SIZE: u32 = crate::FLIGHT_CTRL_IMU_RATIO as u32;
 const BLOCK_SIZE: u32 = 1;
@@ -16,36 +23,57 @@ static mut FILTER_STATE_ACCEL_X: [f32; 4] = [0.; 4];
 static mut FILTER_STATE_ACCEL_Y: [f32; 4] = [0.; 4];
 static mut FILTER_STATE_ACCEL_Z: [f32; 4] = [0.; 4];
 
-static mut FILTER_STATE_GYRO_PITCH: [f32; 4] = [0.; 4];
-static mut FILTER_STATE_GYRO_ROLL: [f32; 4] = [0.; 4];
-static mut FILTER_STATE_GYRO_YAW: [f32; 4] = [0.; 4];
+static mut FILTER_STATE_GYRO_LPF1_PITCH: [f32; 4] = [0.; 4];
+static mut FILTER_STATE_GYRO_LPF1_ROLL: [f32; 4] = [0.; 4];
+static mut FILTER_STATE_GYRO_LPF1_YAW: [f32; 4] = [0.; 4];
+
+static mut FILTER_STATE_GYRO_LPF2_PITCH: [f32; 4] = [0.; 4];
+static mut FILTER_STATE_GYRO_LPF2_ROLL: [f32; 4] = [0.; 4];
+static mut FILTER_STATE_GYRO_LPF2_YAW: [f32; 4] = [0.; 4];
 
 static mut FILTER_STATE_VV_BARO: [f32; 4] = [0.; 4];
 
+// Number of motors we track RPM for, and number of harmonics (1 is the fundamental only)
+// we notch for each.
+pub const NUM_RPM_NOTCH_MOTORS: usize = 4;
+const NUM_HARMONICS: usize = 2;
+const NUM_NOTCHES: usize = NUM_RPM_NOTCH_MOTORS * NUM_HARMONICS;
+
+// Below the min, motors are near idle, and there's little vibration to remove; notching low
+// frequencies also adds phase delay in the band we control in. The max keeps us clear of
+// the Nyquist frequency, as a portion of the IMU rate.
+const RPM_NOTCH_MIN_FREQ: f32 = 80.;
+const RPM_NOTCH_MAX_FREQ_RATIO: f32 = 0.45;
+
+// Only recompute a notch's coefficients when its center frequency has moved by at least this
+// much, in Hz. This keeps the cost in the IMU loop bounded.
+const RPM_NOTCH_RETUNE_THRESH: f32 = 3.;
+
+// Coefficients are updated in place when a notch is re-tuned; the filter instances point to
+// these. One set per motor and harmonic, shared between the 3 gyro axes. Initialized as
+// pass-through.
+static mut COEFFS_RPM_NOTCH: [[f32; 5]; NUM_NOTCHES] = [[1., 0., 0., 0., 0.]; NUM_NOTCHES];
+
+static mut FILTER_STATE_RPM_NOTCH_PITCH: [[f32; 4]; NUM_NOTCHES] = [[0.; 4]; NUM_NOTCHES];
+static mut FILTER_STATE_RPM_NOTCH_ROLL: [[f32; 4]; NUM_NOTCHES] = [[0.; 4]; NUM_NOTCHES];
+static mut FILTER_STATE_RPM_NOTCH_YAW: [[f32; 4]; NUM_NOTCHES] = [[0.; 4]; NUM_NOTCHES];
+
 // todo: What cutoffs to use? I think you're in the ballpark, but maybe a little higher.
 // Using 100 for acc now.
-// filter_ = signal.iirfilter(1, 300, btype="lowpass", ftype="bessel", output="sos", fs=8_000)
-// coeffs = []
-// for row in filter_:
-//     coeffs.extend([row[0] / row[3], row[1] / row[3], row[2] / row[3], -row[4] / row[3], -row[5] / row[3]])
+const ACCEL_LPF_CUTOFF: f32 = 100.;
 
-#[allow(clippy::excessive_precision)]
-static COEFFS_LP_ACCEL: [f32; 5] = [
-    0.037804754170896473,
-    0.037804754170896473,
-    0.0,
-    0.9243904916582071,
-    -0.0,
-];
+// Computed from the IMU rate when the filters are created. Initialized as pass-through.
+static mut COEFFS_LP_ACCEL: [f32; 5] = [1., 0., 0., 0., 0.];
 
-#[allow(clippy::excessive_precision)]
-static COEFFS_LP_GYRO: [f32; 5] = [
-    0.10583178270745373,
-    0.10583178270745373,
-    0.0,
-    0.7883364345850926,
-    -0.0,
-];
+// Gyro lowpass cutoffs are clamped to this range, in Hz. The max keeps us clear of the
+// Nyquist frequency, as a portion of the IMU rate.
+const GYRO_LPF_MIN_CUTOFF: f32 = 20.;
+const GYRO_LPF_MAX_CUTOFF_RATIO: f32 = 0.45;
+
+// Computed from user config at init, and when the config changes; the filter instances point
+// to these. Shared between the 3 gyro axes. Initialized as pass-through.
+static mut COEFFS_GYRO_LPF1: [f32; 5] = [1., 0., 0., 0., 0.];
+static mut COEFFS_GYRO_LPF2: [f32; 5] = [1., 0., 0., 0., 0.];
 
 // filter_ = signal.iirfilter(1, 20, btype="lowpass", ftype="bessel", output="sos", fs=155)
 // coeffs = []
@@ -61,22 +89,197 @@ static COEFFS_VV_BARO: [f32; 5] = [
     -0.0,
 ];
 
+/// RPM filter settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct RpmFilterCfg {
+    pub enabled: bool,
+    /// Notch quality factor. Higher is narrower.
+    pub q: f32,
+    /// Also notch the second harmonic of each motor's rotation frequency.
+    pub second_harmonic: bool,
+}
+
+impl Default for RpmFilterCfg {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            q: 5.,
+            second_harmonic: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum LpfType {
+    /// The stage is bypassed.
+    Off = 0,
+    /// First-order. Least phase delay.
+    Pt1 = 1,
+    /// Second-order Butterworth. Steeper rolloff, with more phase delay than PT1.
+    Biquad = 2,
+}
+
+/// Settings for one gyro lowpass stage.
+#[derive(Clone, Copy, PartialEq)]
+pub struct LpfStageCfg {
+    pub type_: LpfType,
+    /// Cutoff frequency, in Hz.
+    pub cutoff: f32,
+}
+
+impl LpfStageCfg {
+    /// The config as actually run: The cutoff clamped to the range we support, and 0 if off.
+    fn implemented(&self) -> Self {
+        let cutoff = match self.type_ {
+            LpfType::Off => 0.,
+            _ => self.cutoff.clamp(
+                GYRO_LPF_MIN_CUTOFF,
+                GYRO_LPF_MAX_CUTOFF_RATIO * loop_rates::rates().imu,
+            ),
+        };
+
+        Self {
+            type_: self.type_,
+            cutoff,
+        }
+    }
+
+    /// Coefficients in CMSIS-DSP's biquad format. Pass-through if off.
+    fn coeffs(&self) -> [f32; 5] {
+        match self.type_ {
+            LpfType::Off => [1., 0., 0., 0., 0.],
+            LpfType::Pt1 => pt1_coeffs(self.cutoff),
+            LpfType::Biquad => lowpass_biquad_coeffs(self.cutoff),
+        }
+    }
+}
+
+/// Gyro lowpass filter chain settings. Stored in user config. Stage 1 runs first.
+#[derive(Clone, Copy, PartialEq)]
+pub struct GyroLpfCfg {
+    pub lpf1: LpfStageCfg,
+    pub lpf2: LpfStageCfg,
+}
+
+impl Default for GyroLpfCfg {
+    fn default() -> Self {
+        Self {
+            lpf1: LpfStageCfg {
+                type_: LpfType::Pt1,
+                cutoff: 250.,
+            },
+            lpf2: LpfStageCfg {
+                type_: LpfType::Biquad,
+                cutoff: 500.,
+            },
+        }
+    }
+}
+
+impl GyroLpfCfg {
+    fn implemented(&self) -> Self {
+        Self {
+            lpf1: self.lpf1.implemented(),
+            lpf2: self.lpf2.implemented(),
+        }
+    }
+}
+
+/// First-order lowpass coefficients, for CMSIS-DSP's biquad format.
+fn pt1_coeffs(cutoff: f32) -> [f32; 5] {
+    let dt = loop_rates::rates().dt_imu;
+    let rc = 1. / (TAU * cutoff);
+    let k = dt / (rc + dt);
+
+    [k, 0., 0., 1. - k, 0.]
+}
+
+/// First-order lowpass coefficients from the bilinear transform, for CMSIS-DSP's biquad format.
+/// Equivalent to `signal.iirfilter(1, cutoff, btype="lowpass", ftype="bessel", output="sos")`.
+fn lowpass_bilinear_coeffs(cutoff: f32) -> [f32; 5] {
+    let k = (PI * cutoff * loop_rates::rates().dt_imu).tan();
+    let b = k / (1. + k);
+
+    [b, b, 0., (1. - k) / (1. + k), 0.]
+}
+
+/// Second-order Butterworth lowpass coefficients, for CMSIS-DSP's biquad format. From the RBJ
+/// Audio EQ cookbook.
+fn lowpass_biquad_coeffs(cutoff: f32) -> [f32; 5] {
+    const Q: f32 = core::f32::consts::FRAC_1_SQRT_2;
+
+    let w0 = TAU * cutoff * loop_rates::rates().dt_imu;
+    let cos_w0 = w0.cos();
+    let alpha = w0.sin() / (2. * Q);
+
+    let a0 = 1. + alpha;
+    let b0 = (1. - cos_w0) / 2.;
+
+    // CMSIS expects feedback coefficients negated, compared to the cookbook.
+    [
+        b0 / a0,
+        (1. - cos_w0) / a0,
+        b0 / a0,
+        2. * cos_w0 / a0,
+        -(1. - alpha) / a0,
+    ]
+}
+
+/// Notch filter coefficients, for CMSIS-DSP's biquad format. From the RBJ Audio EQ cookbook.
+fn notch_coeffs(center_freq: f32, q: f32) -> [f32; 5] {
+    let w0 = TAU * center_freq * loop_rates::rates().dt_imu;
+    let cos_w0 = w0.cos();
+    let alpha = w0.sin() / (2. * q);
+
+    let a0 = 1. + alpha;
+
+    // CMSIS expects feedback coefficients negated, compared to the cookbook.
+    [
+        1. / a0,
+        -2. * cos_w0 / a0,
+        1. / a0,
+        2. * cos_w0 / a0,
+        -(1. - alpha) / a0,
+    ]
+}
+
 /// Store lowpass IIR filter instances, for use with lowpass and notch filters for IMU readings.
 pub struct ImuFilters {
     pub accel_x: IirInstWrapper,
     pub accel_y: IirInstWrapper,
     pub accel_z: IirInstWrapper,
 
-    pub gyro_pitch: IirInstWrapper,
-    pub gyro_roll: IirInstWrapper,
-    pub gyro_yaw: IirInstWrapper,
+    pub gyro_lpf1_pitch: IirInstWrapper,
+    pub gyro_lpf1_roll: IirInstWrapper,
+    pub gyro_lpf1_yaw: IirInstWrapper,
+
+    pub gyro_lpf2_pitch: IirInstWrapper,
+    pub gyro_lpf2_roll: IirInstWrapper,
+    pub gyro_lpf2_yaw: IirInstWrapper,
+    /// The gyro lowpass settings the coefficients were last computed for; this is what's
+    /// actually running. `None` until first computed.
+    gyro_lpf_implemented: Option<GyroLpfCfg>,
+    /// The gyro chain's last output; pitch, roll, yaw. Lowpass stages start from this when
+    /// re-created.
+    gyro_out: (f32, f32, f32),
 
     pub vv_baro: IirInstWrapper,
+
+    /// Indexed by motor, then harmonic.
+    pub rpm_notch_pitch: [IirInstWrapper; NUM_NOTCHES],
+    pub rpm_notch_roll: [IirInstWrapper; NUM_NOTCHES],
+    pub rpm_notch_yaw: [IirInstWrapper; NUM_NOTCHES],
+    /// Center frequencies the notch coefficients were last computed for, in Hz.
+    rpm_notch_freqs: [f32; NUM_NOTCHES],
 }
 
 impl Default for ImuFilters {
+    /// Run this after `loop_rates::init`; the accelerometer coefficients depend on the IMU rate.
     fn default() -> Self {
         unsafe {
+            COEFFS_LP_ACCEL = lowpass_bilinear_coeffs(ACCEL_LPF_CUTOFF);
+
             Self {
                 accel_x: IirInstWrapper {
                     inner: iir_new(&COEFFS_LP_ACCEL, &mut FILTER_STATE_ACCEL_X),
@@ -87,18 +290,39 @@ impl Default for ImuFilters {
                 accel_z: IirInstWrapper {
                     inner: iir_new(&COEFFS_LP_ACCEL, &mut FILTER_STATE_ACCEL_Z),
                 },
-                gyro_pitch: IirInstWrapper {
-                    inner: iir_new(&COEFFS_LP_GYRO, &mut FILTER_STATE_GYRO_PITCH),
+                gyro_lpf1_pitch: IirInstWrapper {
+                    inner: iir_new(&COEFFS_GYRO_LPF1, &mut FILTER_STATE_GYRO_LPF1_PITCH),
+                },
+                gyro_lpf1_roll: IirInstWrapper {
+                    inner: iir_new(&COEFFS_GYRO_LPF1, &mut FILTER_STATE_GYRO_LPF1_ROLL),
+                },
+                gyro_lpf1_yaw: IirInstWrapper {
+                    inner: iir_new(&COEFFS_GYRO_LPF1, &mut FILTER_STATE_GYRO_LPF1_YAW),
                 },
-                gyro_roll: IirInstWrapper {
-                    inner: iir_new(&COEFFS_LP_GYRO, &mut FILTER_STATE_GYRO_ROLL),
+                gyro_lpf2_pitch: IirInstWrapper {
+                    inner: iir_new(&COEFFS_GYRO_LPF2, &mut FILTER_STATE_GYRO_LPF2_PITCH),
                 },
-                gyro_yaw: IirInstWrapper {
-                    inner: iir_new(&COEFFS_LP_GYRO, &mut FILTER_STATE_GYRO_YAW),
+                gyro_lpf2_roll: IirInstWrapper {
+                    inner: iir_new(&COEFFS_GYRO_LPF2, &mut FILTER_STATE_GYRO_LPF2_ROLL),
                 },
+                gyro_lpf2_yaw: IirInstWrapper {
+                    inner: iir_new(&COEFFS_GYRO_LPF2, &mut FILTER_STATE_GYRO_LPF2_YAW),
+                },
+                gyro_lpf_implemented: None,
+                gyro_out: (0., 0., 0.),
                 vv_baro: IirInstWrapper {
                     inner: iir_new(&COEFFS_VV_BARO, &mut FILTER_STATE_VV_BARO),
                 },
+                rpm_notch_pitch: core::array::from_fn(|i| IirInstWrapper {
+                    inner: iir_new(&COEFFS_RPM_NOTCH[i], &mut FILTER_STATE_RPM_NOTCH_PITCH[i]),
+                }),
+                rpm_notch_roll: core::array::from_fn(|i| IirInstWrapper {
+                    inner: iir_new(&COEFFS_RPM_NOTCH[i], &mut FILTER_STATE_RPM_NOTCH_ROLL[i]),
+                }),
+                rpm_notch_yaw: core::array::from_fn(|i| IirInstWrapper {
+                    inner: iir_new(&COEFFS_RPM_NOTCH[i], &mut FILTER_STATE_RPM_NOTCH_YAW[i]),
+                }),
+                rpm_notch_freqs: [0.; NUM_NOTCHES],
             }
         }
     }
@@ -107,22 +331,168 @@ impl Default for ImuFilters {
 impl ImuFilters {
     /// Apply the filters to IMU readings, modifying in place. Block size = 1.
     /// Note: Baro is handled separately.
-    pub fn apply(&mut self, data: &mut ImuReadings) {
+    pub fn apply(&mut self, data: &mut ImuReadings, rpm_filter_cfg: &RpmFilterCfg) {
+        // Pass-through until `update_gyro_lpfs` has run.
+        let (lpf1_on, lpf2_on) = match self.gyro_lpf_implemented {
+            Some(cfg) => (
+                cfg.lpf1.type_ != LpfType::Off,
+                cfg.lpf2.type_ != LpfType::Off,
+            ),
+            None => (false, false),
+        };
+
         data.a_x = iir_apply(&mut self.accel_x, data.a_x);
         data.a_y = iir_apply(&mut self.accel_y, data.a_y);
         data.a_z = iir_apply(&mut self.accel_z, data.a_z);
-        data.v_pitch = iir_apply(&mut self.gyro_pitch, data.v_pitch);
-        data.v_roll = iir_apply(&mut self.gyro_roll, data.v_roll);
-        data.v_yaw = iir_apply(&mut self.gyro_yaw, data.v_yaw);
+
+        if rpm_filter_cfg.enabled {
+            let num_harmonics = if rpm_filter_cfg.second_harmonic { 2 } else { 1 };
+
+            for motor in 0..NUM_RPM_NOTCH_MOTORS {
+                for harmonic in 0..num_harmonics {
+                    let i = motor * NUM_HARMONICS + harmonic;
+                    data.v_pitch = iir_apply(&mut self.rpm_notch_pitch[i], data.v_pitch);
+                    data.v_roll = iir_apply(&mut self.rpm_notch_roll[i], data.v_roll);
+                    data.v_yaw = iir_apply(&mut self.rpm_notch_yaw[i], data.v_yaw);
+                }
+            }
+        }
+
+        if lpf1_on {
+            data.v_pitch = iir_apply(&mut self.gyro_lpf1_pitch, data.v_pitch);
+            data.v_roll = iir_apply(&mut self.gyro_lpf1_roll, data.v_roll);
+            data.v_yaw = iir_apply(&mut self.gyro_lpf1_yaw, data.v_yaw);
+        }
+
+        if lpf2_on {
+            data.v_pitch = iir_apply(&mut self.gyro_lpf2_pitch, data.v_pitch);
+            data.v_roll = iir_apply(&mut self.gyro_lpf2_roll, data.v_roll);
+            data.v_yaw = iir_apply(&mut self.gyro_lpf2_yaw, data.v_yaw);
+        }
+
+        self.gyro_out = (data.v_pitch, data.v_roll, data.v_yaw);
     }
-}
 
-/// Calulate the frequency to filter out, in Hz, based on one rotor's RPM.
-/// `erpm` is the rpm transmitted by the ESC, aka electrical RPM, via bidirectional
-/// DSHOT.
-fn rpm_filter_freq(erpm: f32, num_poles: u8) -> f32 {
-    let rpm = erpm / (2. * num_poles as f32); // QC this.
+    /// Recompute gyro lowpass coefficients if the config has changed since they were last
+    /// computed. Run this at init, and from the same ISR as `apply`; the config may be changed
+    /// over USB at a different priority, but the coefficients the filter instances read are
+    /// only modified here.
+    pub fn update_gyro_lpfs(&mut self, cfg: &GyroLpfCfg) {
+        let cfg = cfg.implemented();
+
+        if self.gyro_lpf_implemented == Some(cfg) {
+            return;
+        }
+
+        unsafe {
+            COEFFS_GYRO_LPF1 = cfg.lpf1.coeffs();
+            COEFFS_GYRO_LPF2 = cfg.lpf2.coeffs();
+
+            // Re-create the stages, so none keeps state from its previous type, or from before
+            // it was turned off.
+            self.gyro_lpf1_pitch = IirInstWrapper {
+                inner: iir_new(&COEFFS_GYRO_LPF1, &mut FILTER_STATE_GYRO_LPF1_PITCH),
+            };
+            self.gyro_lpf1_roll = IirInstWrapper {
+                inner: iir_new(&COEFFS_GYRO_LPF1, &mut FILTER_STATE_GYRO_LPF1_ROLL),
+            };
+            self.gyro_lpf1_yaw = IirInstWrapper {
+                inner: iir_new(&COEFFS_GYRO_LPF1, &mut FILTER_STATE_GYRO_LPF1_YAW),
+            };
+            self.gyro_lpf2_pitch = IirInstWrapper {
+                inner: iir_new(&COEFFS_GYRO_LPF2, &mut FILTER_STATE_GYRO_LPF2_PITCH),
+            };
+            self.gyro_lpf2_roll = IirInstWrapper {
+                inner: iir_new(&COEFFS_GYRO_LPF2, &mut FILTER_STATE_GYRO_LPF2_ROLL),
+            };
+            self.gyro_lpf2_yaw = IirInstWrapper {
+                inner: iir_new(&COEFFS_GYRO_LPF2, &mut FILTER_STATE_GYRO_LPF2_YAW),
+            };
+
+            // Settle each stage at the chain's last output, so new coefficients don't start from
+            // a step; eg when switching control profiles in flight. Each stage has unity gain at
+            // DC.
+            let (pitch, roll, yaw) = self.gyro_out;
+            FILTER_STATE_GYRO_LPF1_PITCH = [pitch; 4];
+            FILTER_STATE_GYRO_LPF1_ROLL = [roll; 4];
+            FILTER_STATE_GYRO_LPF1_YAW = [yaw; 4];
+            FILTER_STATE_GYRO_LPF2_PITCH = [pitch; 4];
+            FILTER_STATE_GYRO_LPF2_ROLL = [roll; 4];
+            FILTER_STATE_GYRO_LPF2_YAW = [yaw; 4];
+        }
+
+        self.gyro_lpf_implemented = Some(cfg);
+    }
+
+    /// Settle each filter stage at steady state, with `data` as its input; eg when switching IMUs,
+    /// so the step between them doesn't ring through the chain. Each stage has unity gain at DC.
+    pub fn settle(&mut self, data: &ImuReadings) {
+        unsafe {
+            FILTER_STATE_ACCEL_X = [data.a_x; 4];
+            FILTER_STATE_ACCEL_Y = [data.a_y; 4];
+            FILTER_STATE_ACCEL_Z = [data.a_z; 4];
+
+            FILTER_STATE_GYRO_LPF1_PITCH = [data.v_pitch; 4];
+            FILTER_STATE_GYRO_LPF1_ROLL = [data.v_roll; 4];
+            FILTER_STATE_GYRO_LPF1_YAW = [data.v_yaw; 4];
+            FILTER_STATE_GYRO_LPF2_PITCH = [data.v_pitch; 4];
+            FILTER_STATE_GYRO_LPF2_ROLL = [data.v_roll; 4];
+            FILTER_STATE_GYRO_LPF2_YAW = [data.v_yaw; 4];
+
+            FILTER_STATE_RPM_NOTCH_PITCH = [[data.v_pitch; 4]; NUM_NOTCHES];
+            FILTER_STATE_RPM_NOTCH_ROLL = [[data.v_roll; 4]; NUM_NOTCHES];
+            FILTER_STATE_RPM_NOTCH_YAW = [[data.v_yaw; 4]; NUM_NOTCHES];
+        }
+    }
+
+    /// The gyro lowpass settings actually running, with cutoffs after clamping. For reporting
+    /// to the PC application.
+    pub fn gyro_lpfs_implemented(&self) -> Option<GyroLpfCfg> {
+        self.gyro_lpf_implemented
+    }
+
+    /// Re-tune RPM notch filters from the latest motor RPM readings. (Mechanical RPM; the pole
+    /// count has already been factored in by `rpm_reception`.) Only notches whose center
+    /// frequency has moved meaningfully are recomputed. Run this from the same ISR as `apply`,
+    /// since it modifies coefficients the filter instances read.
+    pub fn update_rpm_notches(
+        &mut self,
+        rpms: &[Option<f32>; NUM_RPM_NOTCH_MOTORS],
+        cfg: &RpmFilterCfg,
+    ) {
+        if !cfg.enabled {
+            return;
+        }
+
+        let max_freq = RPM_NOTCH_MAX_FREQ_RATIO * loop_rates::rates().imu;
+
+        for (motor, rpm) in rpms.iter().enumerate() {
+            // With no reading, leave the notch where it was.
+            let Some(rpm) = rpm else {
+                continue;
+            };
+
+            for harmonic in 0..NUM_HARMONICS {
+                let i = motor * NUM_HARMONICS + harmonic;
+
+                let freq = (rpm_filter_freq(*rpm) * (harmonic + 1) as f32)
+                    .clamp(RPM_NOTCH_MIN_FREQ, max_freq);
+
+                if (freq - self.rpm_notch_freqs[i]).abs() < RPM_NOTCH_RETUNE_THRESH {
+                    continue;
+                }
+
+                unsafe {
+                    COEFFS_RPM_NOTCH[i] = notch_coeffs(freq, cfg.q);
+                }
+                self.rpm_notch_freqs[i] = freq;
+            }
+        }
+    }
+}
 
+/// Calulate the frequency to filter out, in Hz, based on one rotor's (mechanical) RPM.
+fn rpm_filter_freq(rpm: f32) -> f32 {
     // `rpm` is per minute - convert to per second.
     rpm / 60.
 }
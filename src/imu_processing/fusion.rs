@@ -0,0 +1,253 @@
+//! This module contains attitude fusion tuning, and convergence monitoring.
+//!
+//! The AHRS's accelerometer correction gain is fixed. We scale it as we do for accelerometer
+//! health: By passing the AHRS a reading blended between the one we'd expect at rest, from its
+//! current attitude estimate, and the measured one. A gain of 1. is the AHRS's own; below that,
+//! the accelerometer is trusted less, eg on a craft with heavy vibration. Gain changes are slewed,
+//! so tuning it in flight doesn't step the attitude estimate.
+//!
+//! The convergence monitor tracks the angle between measured gravity, and gravity from the
+//! attitude estimate, averaged over one-second windows. It only uses readings near 1G, with the
+//! accelerometer healthy; otherwise the accelerometer doesn't indicate attitude. After power-up,
+//! or a reset, we're aligning until a window's residual is low; arming is blocked until then. Once
+//! converged, a window with a high residual means the estimate has diverged; this is logged, and
+//! shown as a warning.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ahrs::{Ahrs, DeviceOrientation};
+use defmt::println;
+use lin_alg::f32::{Quaternion, Vec3};
+use num_traits::Float;
+
+use crate::{
+    event_log::{self, EventCode},
+    imu_processing::accel_health::FusionMode,
+};
+
+// Seconds in each residual window.
+const WINDOW_TIME: f32 = 1.;
+// A window needs at least this portion of its readings usable to change state.
+const WINDOW_USABLE_MIN: f32 = 0.5;
+// Readings whose norm differs from the length at rest by more than this portion aren't used; the
+// craft is accelerating.
+const NORM_TOLERANCE: f32 = 0.1;
+
+// Mean residuals, in radians, below which we're converged, and above which we've diverged.
+const CONVERGED_THRESH: f32 = 0.035;
+const DIVERGED_THRESH: f32 = 0.26;
+
+// Gain change per second, when the configured gain changes.
+const GAIN_SLEW_RATE: f32 = 0.5;
+
+// Values outside these ranges are rejected when loading config.
+pub const GAIN_MIN: f32 = 0.1;
+pub const GAIN_MAX: f32 = 3.;
+pub const MAG_REJECT_MIN: f32 = 0.05;
+pub const MAG_REJECT_MAX: f32 = 1.;
+
+// Serialized size: Gain, and mag rejection threshold.
+pub const FUSION_CFG_SIZE: usize = 4 * 2;
+// Convergence state, mean residual of the last window, and gain in effect.
+pub const FUSION_STATUS_SIZE: usize = 1 + 4 + 4;
+
+// Set over USB; handled from the main loop, which holds the AHRS.
+static RESET_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Attitude fusion tuning. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct FusionCfg {
+    /// Accelerometer correction gain, relative to the AHRS's own.
+    pub gain: f32,
+    /// Mag readings whose magnitude differs from the calibrated field strength by more than this
+    /// portion of it are rejected; eg from motor current, or nearby steel.
+    pub mag_reject: f32,
+}
+
+impl Default for FusionCfg {
+    fn default() -> Self {
+        Self {
+            gain: 1.,
+            mag_reject: 0.25,
+        }
+    }
+}
+
+impl FusionCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let gain = f32::from_be_bytes(buf[0..4].try_into().unwrap());
+        let mag_reject = f32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+        // These comparisons also reject NaN.
+        if !(GAIN_MIN..=GAIN_MAX).contains(&gain)
+            || !(MAG_REJECT_MIN..=MAG_REJECT_MAX).contains(&mag_reject)
+        {
+            return None;
+        }
+
+        Some(Self { gain, mag_reject })
+    }
+
+    pub fn to_bytes(&self) -> [u8; FUSION_CFG_SIZE] {
+        let mut result = [0; FUSION_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.gain.to_be_bytes());
+        result[4..8].clone_from_slice(&self.mag_reject.to_be_bytes());
+        result
+    }
+}
+
+/// Whether the attitude estimate agrees with gravity. Repr is how it's passed over USB, and in
+/// the event log.
+#[derive(Clone, Copy, PartialEq, Default)]
+#[repr(u8)]
+pub enum AttConvergence {
+    /// After power-up, or a reset. Arming is blocked.
+    #[default]
+    Aligning = 0,
+    Converged = 1,
+    /// Was converged; the residual has since been high.
+    Diverged = 2,
+}
+
+pub struct FusionMonitor {
+    pub convergence: AttConvergence,
+    /// Gain in effect; slews towards the configured one.
+    pub gain: f32,
+    /// Mean residual of the last window with enough usable readings, in radians.
+    pub residual: f32,
+    residual_sum: f32,
+    usable_time: f32,
+    window_time: f32,
+}
+
+impl Default for FusionMonitor {
+    fn default() -> Self {
+        Self {
+            convergence: Default::default(),
+            gain: 1.,
+            residual: 0.,
+            residual_sum: 0.,
+            usable_time: 0.,
+            window_time: 0.,
+        }
+    }
+}
+
+impl FusionMonitor {
+    /// The accelerometer reading to pass to the AHRS, in m/s^2, with the gain applied. Run each IMU
+    /// update, after `AccelHealth::accel_for_fusion`.
+    pub fn accel_for_fusion(
+        &mut self,
+        accel: (f32, f32, f32),
+        attitude: Quaternion,
+        acc_len_at_rest: f32,
+        cfg: &FusionCfg,
+        dt: f32,
+    ) -> (f32, f32, f32) {
+        let max_step = GAIN_SLEW_RATE * dt;
+        self.gain += (cfg.gain - self.gain).clamp(-max_step, max_step);
+
+        // As in `AccelHealth::accel_for_fusion`.
+        let expected = attitude.rotate_vec(Vec3::new(0., 0., acc_len_at_rest));
+
+        (
+            expected.x + (accel.0 - expected.x) * self.gain,
+            expected.y + (accel.1 - expected.y) * self.gain,
+            expected.z + (accel.2 - expected.z) * self.gain,
+        )
+    }
+
+    /// Run each IMU update, after the AHRS update. `accel` is the measured reading, in m/s^2.
+    pub fn update(
+        &mut self,
+        accel: (f32, f32, f32),
+        attitude: Quaternion,
+        acc_len_at_rest: f32,
+        fusion_mode: FusionMode,
+        dt: f32,
+    ) {
+        let measured = Vec3::new(accel.0, accel.1, accel.2);
+        let expected = attitude.rotate_vec(Vec3::new(0., 0., acc_len_at_rest));
+
+        let norm = measured.magnitude() / acc_len_at_rest;
+
+        // This comparison also rejects NaN.
+        if fusion_mode == FusionMode::Full && (norm - 1.).abs() < NORM_TOLERANCE {
+            let cos = measured.to_normalized().dot(expected.to_normalized());
+            self.residual_sum += cos.clamp(-1., 1.).acos() * dt;
+            self.usable_time += dt;
+        }
+
+        self.window_time += dt;
+        if self.window_time < WINDOW_TIME {
+            return;
+        }
+
+        if self.usable_time >= WINDOW_TIME * WINDOW_USABLE_MIN {
+            self.residual = self.residual_sum / self.usable_time;
+
+            let convergence = match self.convergence {
+                AttConvergence::Converged if self.residual > DIVERGED_THRESH => {
+                    AttConvergence::Diverged
+                }
+                AttConvergence::Aligning | AttConvergence::Diverged
+                    if self.residual < CONVERGED_THRESH =>
+                {
+                    AttConvergence::Converged
+                }
+                c => c,
+            };
+
+            if convergence != self.convergence {
+                println!("Attitude convergence: {}", convergence as u8);
+                // The residual is reported in mrad.
+                event_log::log(
+                    EventCode::AttConvergence,
+                    convergence as u16,
+                    (self.residual * 1_000.) as u16,
+                );
+                self.convergence = convergence;
+            }
+        }
+
+        self.residual_sum = 0.;
+        self.usable_time = 0.;
+        self.window_time = 0.;
+    }
+
+    /// If a reset was requested, re-initialize the AHRS, keeping its calibration, and start
+    /// aligning. Run from the main loop, while holding the AHRS.
+    pub fn handle_reset(&mut self, ahrs: &mut Ahrs, dt_imu: f32) {
+        if !RESET_REQUESTED.swap(false, Ordering::AcqRel) {
+            return;
+        }
+
+        let mut new = Ahrs::new(dt_imu, DeviceOrientation::default());
+        core::mem::swap(&mut new.cal, &mut ahrs.cal);
+        *ahrs = new;
+
+        *self = Self {
+            gain: self.gain,
+            ..Default::default()
+        };
+
+        println!("Attitude estimate reset");
+    }
+
+    pub fn to_bytes(&self) -> [u8; FUSION_STATUS_SIZE] {
+        let mut result = [0; FUSION_STATUS_SIZE];
+
+        result[0] = self.convergence as u8;
+        result[1..5].clone_from_slice(&self.residual.to_be_bytes());
+        result[5..9].clone_from_slice(&self.gain.to_be_bytes());
+        result
+    }
+}
+
+/// Request the attitude estimate be reset, eg after moving the craft on the bench while powered.
+/// Only call this while disarmed.
+pub fn request_reset() {
+    RESET_REQUESTED.store(true, Ordering::Release);
+}
@@ -0,0 +1,149 @@
+//! This module contains a linear model of gyro bias drift vs IMU die temperature, and a
+//! bench calibration procedure that fits it from readings taken at two temperatures.
+//!
+//! Gyro bias drifts noticeably between a cold start, and a warm battery compartment; this
+//! removes the part of that drift that tracks temperature.
+
+use ahrs::ImuReadings;
+use defmt::println;
+use num_traits::Float;
+
+// Number of readings averaged at each calibration point.
+const NUM_TEMP_CAL_VALS: u32 = 4_000;
+// Skip readings at the start of each point, eg so the button press or click that started it
+// doesn't contaminate the bias.
+const TEMP_CAL_CYCLES_TO_SKIP: u32 = 8_000;
+// If the two points are closer than this in temperature, the slope is mostly noise. °C.
+const MIN_TEMP_DIFF: f32 = 8.;
+
+/// Gyro bias slope vs temperature. Persisted in user config, alongside the accel calibration.
+#[derive(Clone, Copy)]
+pub struct GyroTempComp {
+    /// Temperature, in °C, at which this model applies no correction. This is the temperature
+    /// of the first calibration point.
+    pub ref_temp: f32,
+    /// Bias slope for pitch, roll, and yaw rates, in rad/s per °C.
+    pub slope: (f32, f32, f32),
+}
+
+impl Default for GyroTempComp {
+    fn default() -> Self {
+        Self {
+            ref_temp: 25.,
+            slope: (0., 0., 0.),
+        }
+    }
+}
+
+impl GyroTempComp {
+    /// Remove the temperature-dependent part of gyro bias from readings, in place. `temp` is in °C.
+    pub fn apply(&self, data: &mut ImuReadings, temp: f32) {
+        let temp_diff = temp - self.ref_temp;
+
+        data.v_pitch -= self.slope.0 * temp_diff;
+        data.v_roll -= self.slope.1 * temp_diff;
+        data.v_yaw -= self.slope.2 * temp_diff;
+    }
+}
+
+/// Temperature, and mean gyro readings, captured at rest.
+#[derive(Clone, Copy)]
+struct CalPoint {
+    temp: f32,
+    bias: (f32, f32, f32),
+}
+
+pub enum TempCalResult {
+    /// Not calibrating, or still collecting readings.
+    InProgress,
+    /// The first (cold) point has been captured. Wait for the aircraft to warm up, then
+    /// start the second.
+    FirstPointCaptured,
+    Success(GyroTempComp),
+    Fail,
+}
+
+/// State for the two-temperature calibration procedure. The aircraft must be stationary while
+/// each point is captured. Capture the first point cold, and the second once warm.
+#[derive(Default)]
+pub struct GyroTempCal {
+    /// True while collecting readings for a point.
+    pub capturing: bool,
+    i: u32,
+    sum_temp: f32,
+    sum_bias: (f32, f32, f32),
+    first_pt: Option<CalPoint>,
+}
+
+impl GyroTempCal {
+    /// Begin capturing a calibration point. The first call starts the cold point; the second
+    /// starts the warm one.
+    pub fn start(&mut self) {
+        self.capturing = true;
+        self.i = 0;
+        self.sum_temp = 0.;
+        self.sum_bias = (0., 0., 0.);
+    }
+
+    /// Run this each IMU update, with gyro readings that haven't been temperature-compensated.
+    pub fn update(&mut self, data: &ImuReadings, temp: f32) -> TempCalResult {
+        if !self.capturing {
+            return TempCalResult::InProgress;
+        }
+
+        self.i += 1;
+        if self.i <= TEMP_CAL_CYCLES_TO_SKIP {
+            return TempCalResult::InProgress;
+        }
+
+        self.sum_temp += temp;
+        self.sum_bias.0 += data.v_pitch;
+        self.sum_bias.1 += data.v_roll;
+        self.sum_bias.2 += data.v_yaw;
+
+        if self.i < TEMP_CAL_CYCLES_TO_SKIP + NUM_TEMP_CAL_VALS {
+            return TempCalResult::InProgress;
+        }
+
+        self.capturing = false;
+
+        let n = NUM_TEMP_CAL_VALS as f32;
+        let pt = CalPoint {
+            temp: self.sum_temp / n,
+            bias: (
+                self.sum_bias.0 / n,
+                self.sum_bias.1 / n,
+                self.sum_bias.2 / n,
+            ),
+        };
+
+        match self.first_pt {
+            None => {
+                println!("Gyro temp cal: first point captured at {}°C", pt.temp);
+                self.first_pt = Some(pt);
+                TempCalResult::FirstPointCaptured
+            }
+            Some(first) => {
+                self.first_pt = None;
+
+                let temp_diff = pt.temp - first.temp;
+                if temp_diff.abs() < MIN_TEMP_DIFF {
+                    println!(
+                        "Gyro temp cal failed: points at {}°C and {}°C are too close",
+                        first.temp, pt.temp
+                    );
+                    return TempCalResult::Fail;
+                }
+
+                TempCalResult::Success(GyroTempComp {
+                    ref_temp: first.temp,
+                    slope: (
+                        (pt.bias.0 - first.bias.0) / temp_diff,
+                        (pt.bias.1 - first.bias.1) / temp_diff,
+                        (pt.bias.2 - first.bias.2) / temp_diff,
+                    ),
+                })
+            }
+        }
+    }
+}
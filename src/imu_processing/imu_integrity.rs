@@ -0,0 +1,171 @@
+//! This module contains checks on the integrity of IMU communication. We read the IMU over SPI
+//! with DMA at up to 8kHz, and have no other indication of a CS glitch, or the IMU resetting itself; we'd
+//! consume garbage readings. Here, we check each reading for plausibility, and periodically read back
+//! registers with a blocking transaction. On failure, we re-run the driver's setup.
+//!
+//! We also apply changes to the IMU's full-scale and filter config here, re-running the driver's
+//! setup once the aircraft is disarmed and in Preflight mode.
+//!
+//! If the IMU stops sending data entirely, the main loop stops too; the sensor watchdog handles that.
+
+use ahrs::ImuReadings;
+use defmt::println;
+use hal::{delay_us, gpio::Pin};
+use num_traits::Float;
+
+use crate::{
+    board_config::AHB_FREQ,
+    drivers::imu_icm426xx::{self as imu, ImuCfg, ImuError},
+    event_log::{self, EventCode},
+    imu_processing::imu_shared,
+    loop_rates,
+    setup::SpiImu,
+    system_status::SystemStatus,
+};
+
+// How often we read back IMU registers, in seconds.
+pub const VERIFY_INTERVAL: f32 = 1.;
+
+const G: f32 = 9.8;
+// While stationary, accelerometer magnitude outside this range, in G, is implausible.
+const ACCEL_MAG_MIN: f32 = 0.5;
+const ACCEL_MAG_MAX: f32 = 3.;
+// Below this rotation rate on every axis, in rad/s, we treat a disarmed aircraft as stationary.
+const STATIONARY_MAX_GYRO: f32 = 0.2;
+
+// Gyro readings identical to the previous for this long in a row, in seconds, indicate stuck
+// data. Sensor noise is several LSB, even at rest.
+const STUCK_GYRO_TIME: f32 = 0.1;
+
+// After implausible readings in a row for this long, in seconds, we re-initialize the IMU.
+pub const MAX_IMPLAUSIBLE_TIME: f32 = 0.01;
+
+// Gyro data in the readings buffer, after the register byte, temperature, and accelerometer.
+const GYRO_BUF_START: usize = 9;
+
+#[derive(Default)]
+pub struct ImuIntegrity {
+    /// The config the IMU was last set up with. Readback checks against this.
+    pub cfg_applied: ImuCfg,
+    /// Raw gyro bytes from the previous reading.
+    gyro_prev: [u8; 6],
+    identical_gyro_count: u32,
+    consecutive_implausible: u32,
+    reinit_pending: bool,
+}
+
+impl ImuIntegrity {
+    /// Run on each IMU update, with the raw readings buffer, and readings parsed from it. Counts
+    /// implausible data events in `system_status`.
+    pub fn check_readings(
+        &mut self,
+        buf: &[u8],
+        readings: &ImuReadings,
+        disarmed: bool,
+        system_status: &mut SystemStatus,
+    ) {
+        if self.plausible(buf, readings, disarmed) {
+            self.consecutive_implausible = 0;
+            return;
+        }
+
+        if self.consecutive_implausible == 0 {
+            system_status.imu_implausible_count =
+                system_status.imu_implausible_count.saturating_add(1);
+        }
+        self.consecutive_implausible += 1;
+
+        if self.consecutive_implausible == loop_rates::rates().imu_updates(MAX_IMPLAUSIBLE_TIME) {
+            println!("Implausible IMU data; re-initializing");
+            self.reinit_pending = true;
+        }
+    }
+
+    /// Check a reading for plausibility, and update stuck data detection. Use this directly for
+    /// the secondary IMU, which we don't re-initialize; `check_readings` for the primary.
+    pub fn plausible(&mut self, buf: &[u8], readings: &ImuReadings, disarmed: bool) -> bool {
+        let data = &buf[1..];
+        let all_same = data.iter().all(|b| *b == 0) || data.iter().all(|b| *b == 0xff);
+
+        let gyro = &buf[GYRO_BUF_START..GYRO_BUF_START + 6];
+        if gyro == self.gyro_prev {
+            self.identical_gyro_count += 1;
+        } else {
+            self.identical_gyro_count = 0;
+            self.gyro_prev.copy_from_slice(gyro);
+        }
+        let stuck = self.identical_gyro_count >= loop_rates::rates().imu_updates(STUCK_GYRO_TIME);
+
+        let stationary = disarmed
+            && readings.v_pitch.abs() < STATIONARY_MAX_GYRO
+            && readings.v_roll.abs() < STATIONARY_MAX_GYRO
+            && readings.v_yaw.abs() < STATIONARY_MAX_GYRO;
+
+        let accel_mag =
+            (readings.a_x.powi(2) + readings.a_y.powi(2) + readings.a_z.powi(2)).sqrt() / G;
+        // This comparison also rejects NaN.
+        let accel_implausible = stationary && !(ACCEL_MAG_MIN..=ACCEL_MAG_MAX).contains(&accel_mag);
+
+        !(all_same || stuck || accel_implausible)
+    }
+
+    /// If we should run `verify` this update, outside of the periodic check.
+    pub fn reinit_pending(&self) -> bool {
+        self.reinit_pending
+    }
+
+    /// Read back IMU registers with a blocking transaction, and re-initialize the IMU if they don't
+    /// match, or if implausible readings were detected. Run this with the SPI bus locked.
+    pub fn verify(&mut self, spi: &mut SpiImu, cs: &mut Pin, system_status: &mut SystemStatus) {
+        delay_us(imu_shared::IMU_DMA_SETTLE_TIME, AHB_FREQ);
+
+        if !self.reinit_pending {
+            match imu::verify_config(spi, cs, loop_rates::rates().imu_odr, &self.cfg_applied) {
+                Ok(true) => return,
+                _ => println!("IMU register readback failed; re-initializing"),
+            }
+        }
+
+        self.reinit_pending = false;
+        self.consecutive_implausible = 0;
+        self.identical_gyro_count = 0;
+
+        system_status.imu_recoveries = system_status.imu_recoveries.saturating_add(1);
+        event_log::log(EventCode::ImuRecovery, 0, 0);
+
+        self.setup(spi, cs, system_status);
+    }
+
+    /// Re-initialize the IMU if its full-scale and filter config has changed, eg from a config save
+    /// over USB. Run this with the SPI bus locked, while disarmed, and in Preflight mode.
+    pub fn apply_cfg(
+        &mut self,
+        cfg: &ImuCfg,
+        spi: &mut SpiImu,
+        cs: &mut Pin,
+        system_status: &mut SystemStatus,
+    ) {
+        if *cfg == self.cfg_applied {
+            return;
+        }
+
+        println!("IMU config changed; re-initializing");
+        delay_us(imu_shared::IMU_DMA_SETTLE_TIME, AHB_FREQ);
+
+        self.cfg_applied = *cfg;
+        self.identical_gyro_count = 0;
+        self.setup(spi, cs, system_status);
+    }
+
+    /// Run the driver's setup with the applied config, and set whether its readback matched.
+    fn setup(&mut self, spi: &mut SpiImu, cs: &mut Pin, system_status: &mut SystemStatus) {
+        match imu::setup(spi, cs, loop_rates::rates().imu_odr, &self.cfg_applied) {
+            Ok(_) => system_status.imu_cfg_mismatch = false,
+            Err(ImuError::ConfigMismatch) => {
+                println!("IMU register readback doesn't match config after re-initializing");
+                system_status.imu_cfg_mismatch = true;
+            }
+            Err(_) => println!("IMU re-initialization failed"),
+        }
+    }
+}
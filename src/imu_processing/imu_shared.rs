@@ -1,31 +1,96 @@
 //! This module contains device-agnostic IMU code, including parsing IMU readings from a static
-//! DMA buffer.
+//! DMA buffer. It also handles reads from the secondary IMU, if present; see `dual_imu`.
 
+use cortex_m::interrupt;
 use hal::{
-    dma::{ChannelCfg, DmaPeriph, Priority},
+    dma::DmaPeriph,
     gpio::{self, Port},
 };
 
 use crate::{
     board_config::PIN_CS_IMU,
-    setup::{SpiImu, IMU_RX_CH, IMU_TX_CH},
+    setup::{self, DmaTransfer, SpiImu, IMU_RX_CH, IMU_TX_CH},
+};
+#[cfg(feature = "h7")]
+use crate::{
+    board_config::PIN_CS_IMU2,
+    drivers::imu_ism330dhcx as imu2,
+    setup::{SpiImu2, IMU2_DMA_PERIPH, IMU2_RX_CH, IMU2_TX_CH},
 };
 
 const G: f32 = 9.8; // m/s
 
-pub const GYRO_FULLSCALE: f32 = 34.90659; // In radians per second; equals 2,000 degrees/sec
-pub const ACCEL_FULLSCALE: f32 = 156.9056; // 16 G
+/// Full-scale ranges, as passed to `ImuReadings::from_buffer`.
+pub struct FullScale {
+    /// m/s^2
+    pub accel: f32,
+    /// Radians per second.
+    pub gyro: f32,
+}
+
+// Set by the IMU driver's `setup`, each time it configures the device. The default is 16G, and
+// 2,000 degrees/sec; the default user config.
+static mut FULLSCALE: FullScale = FullScale {
+    accel: 156.9056,
+    gyro: 34.90659,
+};
+
+// A read may have been started by the data-ready ISR before we lock the SPI bus for a blocking
+// transaction. Wait this long, in µs, for it to complete.
+pub const IMU_DMA_SETTLE_TIME: u32 = 50;
+
+// Temperature, 3 accelerometer, and 3 gyro measurements; 2 bytes each, plus the register byte.
+pub const READINGS_BUF_SIZE: usize = 15;
 
 // In order to let this fill multiple times per processing, we need to send the register
 // requests once per reading.
-static mut WRITE_BUF: [u8; 13] = [0; 13];
+static mut WRITE_BUF: [u8; READINGS_BUF_SIZE] = [0; READINGS_BUF_SIZE];
 
-// IMU readings buffer. 3 accelerometer, and 3 gyro measurements; 2 bytes each. 0-padded on the left,
-// since that's where we pass the register in the write buffer.
+// IMU readings buffer. Temperature, 3 accelerometer, and 3 gyro measurements; 2 bytes each. 0-padded
+// on the left, since that's where we pass the register in the write buffer.
 // We use this buffer for DMA transfers of IMU readings. Note that reading order is different
 // between different IMUs, due to their reg layout, and consecutive reg reads. In both cases, 6 readings,
-// each with 2 bytes each.
-pub static mut IMU_READINGS: [u8; 13] = [0; 13];
+// each with 2 bytes each, follow the temperature.
+pub static mut IMU_READINGS: [u8; READINGS_BUF_SIZE] = [0; READINGS_BUF_SIZE];
+
+// The secondary IMU's bus. Set at init, if the secondary responds. We keep it here vice as a
+// shared resource, since only the H7 has it. It's used from the data-ready, and secondary TC ISRs,
+// and locked with a critical section.
+#[cfg(feature = "h7")]
+static mut SPI_IMU2: Option<SpiImu2> = None;
+
+#[cfg(feature = "h7")]
+static mut WRITE_BUF_IMU2: [u8; READINGS_BUF_SIZE] = [0; READINGS_BUF_SIZE];
+#[cfg(feature = "h7")]
+static mut IMU2_READINGS: [u8; READINGS_BUF_SIZE] = [0; READINGS_BUF_SIZE];
+
+// The secondary's latest readings, converted to the primary's layout, if new since the main loop
+// last took them.
+static mut IMU2_LATEST: Option<[u8; READINGS_BUF_SIZE]> = None;
+
+/// The portion of the readings buffer passed to `ImuReadings::from_buffer`. This skips the
+/// temperature word, and uses the temperature's low byte as the padding byte that function expects.
+pub fn accel_gyro_buf(buf: &[u8]) -> &[u8] {
+    &buf[2..]
+}
+
+/// Set the full-scale ranges in use. Run this from the driver's setup, when writing them to the
+/// device.
+pub fn set_fullscale(accel: f32, gyro: f32) {
+    unsafe { FULLSCALE = FullScale { accel, gyro } };
+}
+
+/// The full-scale ranges in use.
+pub fn fullscale() -> &'static FullScale {
+    unsafe { &*core::ptr::addr_of!(FULLSCALE) }
+}
+
+/// Read IMU die temperature, in °C, from the readings buffer.
+pub fn temp_from_buffer(buf: &[u8]) -> f32 {
+    // Temperature in Degrees Centigrade = (TEMP_DATA / 132.48) + 25
+    let temp_data = i16::from_be_bytes([buf[1], buf[2]]);
+    temp_data as f32 / 132.48 + 25.
+}
 
 /// Read all 3 measurements, by commanding a DMA transfer. The transfer is closed, and readings
 /// are processed in the Transfer Complete ISR.
@@ -44,15 +109,64 @@ pub fn read_imu(starting_addr: u8, spi: &mut SpiImu, periph: DmaPeriph) {
             &mut IMU_READINGS,
             IMU_TX_CH,
             IMU_RX_CH,
-            ChannelCfg {
-                priority: Priority::Low,
-                ..Default::default()
-            },
-            ChannelCfg {
-                priority: Priority::Low,
-                ..Default::default()
-            },
+            setup::dma_cfg(DmaTransfer::ImuTx),
+            setup::dma_cfg(DmaTransfer::ImuRx),
             periph,
         );
     }
 }
+
+/// Start reading the secondary IMU; run this at init, once its setup passes.
+#[cfg(feature = "h7")]
+pub fn init_imu2(spi: SpiImu2) {
+    interrupt::free(|_| unsafe { SPI_IMU2 = Some(spi) });
+}
+
+/// Read the secondary IMU by commanding a DMA transfer, if it's set up. Run from the data-ready ISR,
+/// after starting the primary's read; the two are on separate busses. The transfer is closed in
+/// the secondary's Transfer Complete ISR.
+#[cfg(feature = "h7")]
+pub fn read_imu2() {
+    interrupt::free(|_| unsafe {
+        let Some(spi) = (*core::ptr::addr_of_mut!(SPI_IMU2)).as_mut() else {
+            return;
+        };
+
+        WRITE_BUF_IMU2[0] = imu2::READINGS_START_ADDR;
+
+        gpio::set_low(PIN_CS_IMU2.0, PIN_CS_IMU2.1);
+
+        spi.transfer_dma(
+            &WRITE_BUF_IMU2,
+            &mut IMU2_READINGS,
+            IMU2_TX_CH,
+            IMU2_RX_CH,
+            setup::dma_cfg(DmaTransfer::Imu2Tx),
+            setup::dma_cfg(DmaTransfer::Imu2Rx),
+            IMU2_DMA_PERIPH,
+        );
+    });
+}
+
+/// Close the secondary's transfer, and store its readings for the main loop. Run from its
+/// Transfer Complete ISR.
+#[cfg(feature = "h7")]
+pub fn imu2_read_complete() {
+    gpio::set_high(PIN_CS_IMU2.0, PIN_CS_IMU2.1);
+
+    interrupt::free(|_| unsafe {
+        if let Some(spi) = (*core::ptr::addr_of_mut!(SPI_IMU2)).as_mut() {
+            // Note that this step is mandatory, per STM32 RM.
+            spi.cleanup_dma(IMU2_DMA_PERIPH, IMU2_TX_CH, Some(IMU2_RX_CH));
+        }
+
+        IMU2_LATEST = Some(imu2::to_primary_layout(&*core::ptr::addr_of!(
+            IMU2_READINGS
+        )));
+    });
+}
+
+/// The secondary's latest readings, in the primary's buffer layout, if new since the last call.
+pub fn take_imu2_readings() -> Option<[u8; READINGS_BUF_SIZE]> {
+    interrupt::free(|_| unsafe { (*core::ptr::addr_of_mut!(IMU2_LATEST)).take() })
+}
@@ -0,0 +1,144 @@
+//! This module contains magnetometer calibration (hard and soft iron), and the logic that decides
+//! if a mag reading is trustworthy enough to pass to the AHRS for yaw drift correction.
+//!
+//! Calibration is collected by rotating the aircraft through a figure-eight, covering as many
+//! orientations as practical, while we log the extremes seen on each axis.
+
+use defmt::println;
+use lin_alg::f32::Vec3;
+use num_traits::Float;
+
+// We require at least this many samples, and at least this range on each axis (µT), to
+// accept a calibration. This catches a calibration ended before the aircraft was rotated
+// through enough orientations.
+const MIN_CAL_SAMPLES: u32 = 200;
+const MIN_CAL_AXIS_RANGE: f32 = 30.;
+
+/// Stored in user config. Readings are in µT.
+#[derive(Clone, Copy)]
+pub struct MagCal {
+    /// Offset, subtracted from readings, in µT.
+    pub hard_iron: Vec3,
+    /// Per-axis scale, applied after removing the hard-iron offset. This is a diagonal-only
+    /// soft-iron model.
+    pub soft_iron: Vec3,
+    /// Earth field strength at the calibration location, in µT.
+    pub field_strength: f32,
+}
+
+impl Default for MagCal {
+    fn default() -> Self {
+        Self {
+            hard_iron: Vec3::new(0., 0., 0.),
+            soft_iron: Vec3::new(1., 1., 1.),
+            // A typical mid-latitude value.
+            field_strength: 50.,
+        }
+    }
+}
+
+impl MagCal {
+    /// Apply hard and soft-iron calibration to a raw reading, in µT.
+    pub fn apply(&self, raw: Vec3) -> Vec3 {
+        Vec3::new(
+            (raw.x - self.hard_iron.x) * self.soft_iron.x,
+            (raw.y - self.hard_iron.y) * self.soft_iron.y,
+            (raw.z - self.hard_iron.z) * self.soft_iron.z,
+        )
+    }
+
+    /// Determine if a calibrated reading is likely contaminated by a magnetic disturbance,
+    /// based on how far its magnitude is from the expected earth field. `thresh` is the portion of
+    /// it allowed; see `FusionCfg::mag_reject`.
+    pub fn is_disturbed(&self, calibrated: Vec3, thresh: f32) -> bool {
+        (calibrated.magnitude() - self.field_strength).abs() > self.field_strength * thresh
+    }
+}
+
+/// Logs min and max values on each axis during a figure-eight calibration.
+pub struct MagCalCollector {
+    /// True while collecting; set over USB.
+    pub active: bool,
+    min: Vec3,
+    max: Vec3,
+    num_samples: u32,
+}
+
+impl Default for MagCalCollector {
+    fn default() -> Self {
+        Self {
+            active: false,
+            min: Vec3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Vec3::new(f32::MIN, f32::MIN, f32::MIN),
+            num_samples: 0,
+        }
+    }
+}
+
+impl MagCalCollector {
+    pub fn start(&mut self) {
+        *self = Self {
+            active: true,
+            ..Default::default()
+        };
+    }
+
+    /// Log a raw (uncalibrated) reading, in µT.
+    pub fn log(&mut self, raw: Vec3) {
+        self.min = Vec3::new(
+            self.min.x.min(raw.x),
+            self.min.y.min(raw.y),
+            self.min.z.min(raw.z),
+        );
+        self.max = Vec3::new(
+            self.max.x.max(raw.x),
+            self.max.y.max(raw.y),
+            self.max.z.max(raw.z),
+        );
+        self.num_samples += 1;
+    }
+
+    /// Stop collecting, and compute a calibration from the logged extremes. Returns `None` if
+    /// not enough data was collected.
+    pub fn finish(&mut self) -> Option<MagCal> {
+        self.active = false;
+
+        let range = Vec3::new(
+            self.max.x - self.min.x,
+            self.max.y - self.min.y,
+            self.max.z - self.min.z,
+        );
+
+        if self.num_samples < MIN_CAL_SAMPLES
+            || range.x < MIN_CAL_AXIS_RANGE
+            || range.y < MIN_CAL_AXIS_RANGE
+            || range.z < MIN_CAL_AXIS_RANGE
+        {
+            println!(
+                "Mag cal failed; insufficient coverage. Samples: {}, ranges: x{} y{} z{}",
+                self.num_samples, range.x, range.y, range.z
+            );
+            return None;
+        }
+
+        let hard_iron = Vec3::new(
+            (self.max.x + self.min.x) / 2.,
+            (self.max.y + self.min.y) / 2.,
+            (self.max.z + self.min.z) / 2.,
+        );
+
+        // Radius of each axis; scale each to the mean, so the calibrated readings lie on a sphere.
+        let radius = Vec3::new(range.x / 2., range.y / 2., range.z / 2.);
+        let radius_avg = (radius.x + radius.y + radius.z) / 3.;
+
+        Some(MagCal {
+            hard_iron,
+            soft_iron: Vec3::new(
+                radius_avg / radius.x,
+                radius_avg / radius.y,
+                radius_avg / radius.z,
+            ),
+            field_strength: radius_avg,
+        })
+    }
+}
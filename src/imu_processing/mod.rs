@@ -1,2 +1,8 @@
+pub mod accel_health;
+pub mod dual_imu;
 pub mod filter_imu;
+pub mod fusion;
+pub mod gyro_temp_comp;
+pub mod imu_integrity;
 pub mod imu_shared;
+pub mod mag_cal;
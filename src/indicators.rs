@@ -0,0 +1,250 @@
+//! This module contains the status LED, and the buzzer. Each main loop update, we select a
+//! pattern for each from system status and state, and step through it using the loop counter;
+//! there are no blocking delays.
+//!
+//! The buzzer also acts as a lost-model beacon: While disarmed, it chirps once the link has been
+//! lost for a configurable time. It can also be triggered on demand, from a switch, or the PC.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use hal::gpio::Pin;
+
+use crate::{
+    batt_failsafe::BattStage,
+    controller_interface::ChannelData,
+    loop_rates,
+    safety::ArmStatus,
+    sensors_shared::BattCellCount,
+    state::StateVolatile,
+    system_status::{SensorStatus, SystemStatus},
+    util,
+};
+
+/// Set over USB, to find the aircraft, or test the buzzer.
+pub static BEACON_USB: AtomicBool = AtomicBool::new(false);
+
+// Each pattern is 32 steps of this duration, in seconds, so it repeats every 3.2s.
+const STEP_TIME: f32 = 0.1;
+const NUM_STEPS: u32 = 32;
+
+// Battery remaining, from `util::batt_left_from_v`, below which we warn.
+const LOW_BATT_THRESH: f32 = 0.2;
+// Below this voltage, we assume no battery is connected, eg powered from USB, and don't warn.
+const BATT_PRESENT_V: f32 = 3.;
+
+// Beacon delays outside this range, in seconds, are rejected when loading config.
+const BEACON_DELAY_MAX: f32 = 600.;
+
+// Serialized size: Buzzer enabled, and beacon delay.
+pub const INDICATOR_CFG_SIZE: usize = 1 + 4;
+
+/// Indicator settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct IndicatorCfg {
+    /// If false, the buzzer is never driven; eg if one isn't installed.
+    pub buzzer_enabled: bool,
+    /// Seconds. While disarmed, the beacon starts once the link has been lost for this long.
+    pub beacon_delay: f32,
+}
+
+impl Default for IndicatorCfg {
+    fn default() -> Self {
+        Self {
+            buzzer_enabled: true,
+            beacon_delay: 30.,
+        }
+    }
+}
+
+impl IndicatorCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let beacon_delay = f32::from_be_bytes(buf[1..5].try_into().unwrap());
+
+        // This comparison also rejects NaN.
+        if buf[0] > 1 || !(0.0..=BEACON_DELAY_MAX).contains(&beacon_delay) {
+            return None;
+        }
+
+        Some(Self {
+            buzzer_enabled: buf[0] != 0,
+            beacon_delay,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; INDICATOR_CFG_SIZE] {
+        let mut result = [0; INDICATOR_CFG_SIZE];
+
+        result[0] = self.buzzer_enabled as u8;
+        result[1..5].clone_from_slice(&self.beacon_delay.to_be_bytes());
+        result
+    }
+}
+
+/// A repeating on/off sequence, for the LED or buzzer.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pattern {
+    Off,
+    /// Disarmed.
+    SlowBlink,
+    /// Armed.
+    Solid,
+    /// A warning, eg low battery, or lost link.
+    FastBlink,
+    /// A critical fault, eg IMU failure. Morse code.
+    Sos,
+    /// Lost-model beacon: Two short chirps.
+    Beacon,
+    /// An action was triggered, eg from a stick command: Rapid blinks.
+    Confirm,
+}
+
+impl Pattern {
+    /// Each bit is a step, starting with the LSB. 1 is on.
+    fn bits(self) -> u32 {
+        match self {
+            Self::Off => 0,
+            Self::SlowBlink => 0x00ff_00ff,
+            Self::Solid => u32::MAX,
+            Self::FastBlink => 0x3333_3333,
+            Self::Sos => 0b0000_0101_0100_0111_0111_0111_0001_0101,
+            Self::Beacon => 0b0101,
+            Self::Confirm => 0x5555_5555,
+        }
+    }
+
+    /// If the output should be on, at a given main loop count.
+    fn is_on(self, loop_i: u32) -> bool {
+        let step_len = loop_rates::rates().imu_updates(STEP_TIME).max(1);
+        let step = (loop_i / step_len) % NUM_STEPS;
+
+        self.bits() & (1 << step) != 0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum AlertLevel {
+    None,
+    Warning,
+    Critical,
+}
+
+fn low_batt(batt_v: f32, cell_count: BattCellCount) -> bool {
+    batt_v > BATT_PRESENT_V && util::batt_left_from_v(batt_v, cell_count) < LOW_BATT_THRESH
+}
+
+fn alert_level(
+    system_status: &SystemStatus,
+    state: &StateVolatile,
+    cell_count: BattCellCount,
+) -> AlertLevel {
+    if system_status.imu != SensorStatus::Pass
+        || system_status.esc_desync.iter().any(|d| *d)
+        || system_status.batt_stage == BattStage::Action
+    {
+        return AlertLevel::Critical;
+    }
+
+    // A link we've never had isn't a warning; eg on the bench, with the radio off.
+    let link_lost = system_status.update_timestamps.rf_control_link.is_some()
+        && system_status.rf_control_link != SensorStatus::Pass;
+
+    if link_lost
+        || low_batt(state.batt_v, cell_count)
+        || system_status.batt_stage != BattStage::Ok
+        || system_status.esc_over_temp
+        || system_status.batt_meas_mismatch
+        || system_status.imu_isr_overrun
+        || system_status.imu_rate_mismatch
+        || system_status.imu_cfg_mismatch
+        || system_status.imu_failed_over
+        || system_status.self_test_fault
+        || system_status.accel_fault
+    {
+        return AlertLevel::Warning;
+    }
+
+    AlertLevel::None
+}
+
+/// Owns the indicator GPIO pins.
+pub struct Indicators {
+    led: Pin,
+    buzzer: Pin,
+}
+
+impl Indicators {
+    /// The pins must be configured as outputs. We start with both off.
+    pub fn new(mut led: Pin, mut buzzer: Pin) -> Self {
+        led.set_low();
+        buzzer.set_low();
+
+        Self { led, buzzer }
+    }
+
+    /// Run each main loop update. `loop_i` is the main loop counter; patterns are timed from it.
+    pub fn update(
+        &mut self,
+        loop_i: u32,
+        system_status: &SystemStatus,
+        state: &StateVolatile,
+        ch_data: &Option<ChannelData>,
+        cell_count: BattCellCount,
+        cfg: &IndicatorCfg,
+        timestamp: f32,
+    ) {
+        let alert = alert_level(system_status, state, cell_count);
+        let disarmed = state.arm_status == ArmStatus::Disarmed;
+
+        let confirm = state.stick_cmds.feedback(timestamp);
+
+        let led = match alert {
+            _ if confirm => Pattern::Confirm,
+            AlertLevel::Critical => Pattern::Sos,
+            AlertLevel::Warning => Pattern::FastBlink,
+            AlertLevel::None if disarmed => Pattern::SlowBlink,
+            AlertLevel::None => Pattern::Solid,
+        };
+
+        let beeper_switch = match ch_data {
+            Some(ch) => ch.beeper,
+            None => false,
+        };
+
+        let link_lost_time = match system_status.update_timestamps.rf_control_link {
+            Some(t) if system_status.rf_control_link != SensorStatus::Pass => timestamp - t,
+            _ => 0.,
+        };
+
+        let beacon = beeper_switch
+            || BEACON_USB.load(Ordering::Acquire)
+            || (disarmed && link_lost_time > cfg.beacon_delay);
+
+        let buzzer = if !cfg.buzzer_enabled {
+            Pattern::Off
+        } else if confirm {
+            Pattern::Confirm
+        } else if beacon {
+            Pattern::Beacon
+        } else if alert == AlertLevel::Critical {
+            Pattern::Sos
+        } else if (low_batt(state.batt_v, cell_count) || system_status.batt_stage != BattStage::Ok)
+            && !disarmed
+        {
+            Pattern::FastBlink
+        } else {
+            Pattern::Off
+        };
+
+        set_pin(&mut self.led, led.is_on(loop_i));
+        set_pin(&mut self.buzzer, buzzer.is_on(loop_i));
+    }
+}
+
+fn set_pin(pin: &mut Pin, on: bool) {
+    if on {
+        pin.set_high();
+    } else {
+        pin.set_low();
+    }
+}
@@ -5,9 +5,10 @@ use ahrs::{Ahrs, DeviceOrientation};
 use hal::{
     adc::{self, Adc, AdcConfig, AdcDevice},
     clocks::{self, Clocks, PllSrc},
-    dma::{self, ChannelCfg, Dma},
+    dma::{self, Dma},
     flash::Flash,
-    iwdg, pac,
+    gpio::{Pin, PinMode},
+    pac,
     timer::{Timer, TimerConfig, TimerInterrupt},
 };
 use lin_alg::f32::Vec3;
@@ -15,14 +16,31 @@ use usb_device::{bus::UsbBusAllocator, prelude::*};
 use usbd_serial::{self, SerialPort};
 
 use crate::{
+    adc_sampling,
     app::{self, Local, Shared},
-    board_config::{BATT_ADC_CH, CAN_CLOCK, CRS_SYNC_SRC, CURR_ADC_CH, DSHOT_ARR_READ},
-    main_loop::DT_IMU,
-    protocols::{crsf, dshot},
-    sensors_shared::{ExtSensor, V_A_ADC_READ_BUF},
-    setup,
+    blackbox::LogStorage,
+    board_config::{CAN_CLOCK, CRS_SYNC_SRC, DSHOT_ARR_READ, PIN_BUZZER, PIN_LED},
+    brownout,
+    camera_tilt::CameraTilt,
+    controller_interface::RxProtocol,
+    dfu,
+    drivers::{flash_spi::ExtFlash, led_strip_ws2812::LedStrip},
+    event_log::{self, EventCode},
+    flight_ctrls::{ctrl_effect_est::AccelMaps, hover_est::HoverThrottleEst},
+    flight_stats::FlightStatsState,
+    health_trend::HealthTrend,
+    imu_processing::{dual_imu::DualImu, filter_imu::ImuFilters},
+    indicators::Indicators,
+    led_strip::LedStatus,
+    loop_rates,
+    lost_craft::LostCraft,
+    perf_stats,
+    protocols::{crsf, dshot, esc_telemetry, sbus},
+    sensors_shared::ExtSensor,
+    setup::{self, UsbBusType},
     state::{StateVolatile, UserConfig},
     system_status::SensorStatus,
+    watchdog,
 };
 
 cfg_if! {
@@ -30,17 +48,24 @@ cfg_if! {
         use hal::{
             can,
             clocks::{PllCfg, VosRange},
-            // USB1 on H723; USB2 on H743.
-            usb::{Usb2, UsbBus, Usb2BusType as UsbBusType},
+            usb::UsbBus,
         };
     } else if #[cfg(feature = "g4")] {
         use hal::{
-            usb::{self, UsbBus, UsbBusType},
+            usb::{self, UsbBus},
             clocks::InputSrc,
         };
     }
 }
 
+cfg_if! {
+    if #[cfg(feature = "h723")] {
+        use hal::usb::Usb1;
+    } else if #[cfg(feature = "h743")] {
+        use hal::usb::Usb2;
+    }
+}
+
 use cfg_if::cfg_if;
 use defmt::println;
 
@@ -49,8 +74,13 @@ use defmt::println;
 static mut USB_BUS: Option<UsbBusAllocator<UsbBusType>> = None;
 
 use crate::board_config::AHB_FREQ;
+#[cfg(feature = "fixed-wing")]
+use crate::protocols::servo;
 
 pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
+    // Must be first; the bootloader expects peripherals in their reset state.
+    dfu::jump_if_requested();
+
     let mut cp = cx.core;
     let dp = pac::Peripherals::take().unwrap();
 
@@ -106,6 +136,11 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
     // Set up pins with appropriate modes.
     setup::setup_pins();
 
+    let indicators = Indicators::new(
+        Pin::new(PIN_LED.0, PIN_LED.1, PinMode::Output),
+        Pin::new(PIN_BUZZER.0, PIN_BUZZER.1, PinMode::Output),
+    );
+
     let _dma = Dma::new(dp.DMA1);
     let _dma = Dma::new(dp.DMA2);
 
@@ -118,10 +153,12 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
         if #[cfg(feature = "h7")] {
             let uart_crsf_pac = dp.UART7;
             let uart_osd_pac = dp.USART2;
+            let uart_esc_telem_pac = dp.USART1;
         } else {
             let uart_crsf_pac = dp.USART2;
             // let uart_crsf_pac = dp.USART3;
             let uart_osd_pac = dp.UART4;
+            let uart_esc_telem_pac = dp.USART3;
         }
     }
 
@@ -138,13 +175,14 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
 
     let (
         mut spi1,
-        mut flash_spi,
+        flash_spi,
         mut cs_imu,
-        mut cs_flash,
+        cs_flash,
         mut i2c1,
         mut i2c2,
         uart_osd,
         mut uart_crsf,
+        mut uart_esc_telem,
     ) = setup::setup_busses(
         dp.SPI1,
         spi_flash_pac,
@@ -152,28 +190,43 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
         dp.I2C2,
         uart_osd_pac,
         uart_crsf_pac,
+        uart_esc_telem_pac,
         &clock_cfg,
     );
 
     // We use the ADC to measure battery voltage and ESC current.
     let adc_cfg = AdcConfig {
-        // With non-timing-critical continuous reads, we can set a long sample time.
+        // With non-timing-critical reads, we can set a long sample time. Each conversion sequence
+        // is started from the main loop; see `adc_sampling`.
+        sample_time: adc::SampleTime::T601,
+        operation_mode: adc::OperationMode::OneShot,
+        ..Default::default()
+    };
+
+    #[cfg(feature = "h7")]
+    let batt_curr_adc = Adc::new_adc1(dp.ADC1, AdcDevice::One, adc_cfg, AHB_FREQ);
+
+    #[cfg(feature = "g4")]
+    let batt_curr_adc = Adc::new_adc2(dp.ADC2, AdcDevice::Two, adc_cfg, AHB_FREQ);
+
+    // The internal temperature sensor is on a different ADC; see `board_config`. It needs a
+    // sample time of at least ~10us, which this comfortably exceeds.
+    let mcu_temp_adc_cfg = AdcConfig {
         sample_time: adc::SampleTime::T601,
-        operation_mode: adc::OperationMode::Continuous,
+        operation_mode: adc::OperationMode::OneShot,
         ..Default::default()
     };
 
     #[cfg(feature = "h7")]
-    let mut batt_curr_adc = Adc::new_adc1(dp.ADC1, AdcDevice::One, adc_cfg, AHB_FREQ);
+    let mcu_temp_adc = Adc::new_adc3(dp.ADC3, AdcDevice::Three, mcu_temp_adc_cfg, AHB_FREQ);
 
     #[cfg(feature = "g4")]
-    let mut batt_curr_adc = Adc::new_adc2(dp.ADC2, AdcDevice::Two, adc_cfg, AHB_FREQ);
+    let mcu_temp_adc = Adc::new_adc1(dp.ADC1, AdcDevice::One, mcu_temp_adc_cfg, AHB_FREQ);
 
-    // todo temp while we sort out HAL. We've fudged this to make the number come out correctly.
-    batt_curr_adc.vdda_calibrated = 3.6;
+    perf_stats::enable_temp_sensor();
 
-    // todo: Which edge should it be?
-    batt_curr_adc.set_trigger(adc::Trigger::Tim6Trgo, adc::TriggerEdge::HardwareRising);
+    // We use VDDA as measured against the internal reference; board-specific error is
+    // corrected by the calibration in `adc_cal`.
 
     // let mut update_timer = Timer::new_tim15(
     //     dp.TIM15,
@@ -208,43 +261,54 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
     dshot_read_timer.set_auto_reload(DSHOT_ARR_READ);
     dshot_read_timer.enable_interrupt(TimerInterrupt::Update);
 
-    let (ctrl_coeff_adj_timer, mut tick_timer, mut adc_timer) =
-        setup::setup_timers(dp.TIM1, dp.TIM5, dp.TIM6, &clock_cfg);
+    // WS2812 LED strip. Frequency is set in the driver, from PSC and ARR.
+    let led_strip_timer = Timer::new_tim4(dp.TIM4, 1., Default::default(), &clock_cfg);
+    let led_status = LedStatus::new(LedStrip::new(led_strip_timer));
 
-    // Note: With this circular DMA approach, we discard many readings,
-    // but shouldn't have consequences other than higher power use, compared to commanding
-    // conversions when needed.
+    let camera_timer = Timer::new_tim15(
+        dp.TIM15,
+        1.,
+        TimerConfig {
+            auto_reload_preload: true,
+            ..Default::default()
+        },
+        &clock_cfg,
+    );
+    let camera_tilt = CameraTilt::new(camera_timer);
 
-    unsafe {
-        batt_curr_adc.read_dma(
-            &mut V_A_ADC_READ_BUF,
-            &[BATT_ADC_CH, CURR_ADC_CH],
-            setup::BATT_CURR_DMA_CH,
-            ChannelCfg {
-                circular: dma::Circular::Enabled,
-                ..Default::default()
-            },
-            setup::BATT_CURR_DMA_PERIPH,
-        );
-    }
+    let (ctrl_coeff_adj_timer, mut tick_timer, mut watchdog_timer) =
+        setup::setup_timers(dp.TIM1, dp.TIM5, dp.TIM17, &clock_cfg);
+
+    adc_sampling::init();
 
     // todo: ID connected sensors etc by checking their device ID etc.
     let mut state_volatile = StateVolatile::default();
 
     cfg_if! {
-        // todo: Probably OTG1 on H723.
-        // On H743, PA11 and PA12 are connected to OTG2 AKA OTG_FS. There are naming inconsistencies
-        // on ST's docs.
-        if #[cfg(feature = "h7")] {
+        // On H723, PA11 and PA12 are connected to OTG1 AKA OTG_HS, using its embedded full-speed
+        // PHY. H723 has no OTG2. On H743, they're connected to OTG2 AKA OTG_FS. There are naming
+        // inconsistencies on ST's docs. Both are clocked from HSI48, trimmed by the CRS.
+        if #[cfg(feature = "h723")] {
+            let usb = Usb1::new(
+                dp.OTG1_HS_GLOBAL,
+                dp.OTG1_HS_DEVICE,
+                dp.OTG1_HS_PWRCLK,
+                clock_cfg.hclk(),
+            );
+        } else if #[cfg(feature = "h743")] {
             let usb = Usb2::new(
                 dp.OTG2_HS_GLOBAL,
                 dp.OTG2_HS_DEVICE,
                 dp.OTG2_HS_PWRCLK,
                 clock_cfg.hclk(),
-                // 240_000_000, // todo temp hardcoded
             );
+        }
+    }
 
-            // todo: C+P from h7xx-hal. Is this right?
+    cfg_if! {
+        if #[cfg(feature = "h7")] {
+            // Endpoint buffers for the OTG peripherals; 4kB covers the CDC endpoints, with room for
+            // the largest config messages.
             static mut USB_EP_MEMORY: [u32; 1024] = [0; 1024];
             unsafe { USB_BUS = Some(UsbBus::new(usb, unsafe { &mut USB_EP_MEMORY })) };
         } else {
@@ -276,10 +340,61 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
         user_cfg.save(&mut flash_onboard);
     }
 
+    // Configs saved before these fields were added will have them as NaN.
+    if user_cfg.gyro_temp_comp.ref_temp.is_nan() {
+        user_cfg.gyro_temp_comp = Default::default();
+    }
+    if user_cfg.mag_cal.field_strength.is_nan() {
+        user_cfg.mag_cal = Default::default();
+    }
+    if user_cfg.rpm_filter.q.is_nan() {
+        user_cfg.rpm_filter = Default::default();
+    }
+    if user_cfg.gyro_lpf.lpf1.cutoff.is_nan() || user_cfg.gyro_lpf.lpf2.cutoff.is_nan() {
+        user_cfg.gyro_lpf = Default::default();
+    }
+
     user_cfg.save(&mut flash_onboard);
 
-    let mut ahrs = Ahrs::new(DT_IMU, DeviceOrientation::default());
-    // let mut ahrs = Ahrs::new(DT_IMU, user_cfg.orientation); // todo
+    // Set these prior to anything that uses the IMU or flight control rates, including IMU setup,
+    // and the filters.
+    loop_rates::init(user_cfg.imu_odr, user_cfg.flight_ctrl_imu_ratio);
+    let rates = loop_rates::rates();
+
+    user_cfg
+        .control_mapping
+        .apply(&mut state_volatile.motor_servo_state);
+
+    #[cfg(feature = "fixed-wing")]
+    setup::set_output_pins(&user_cfg.control_mapping);
+
+    state_volatile.hover_throttle_est = HoverThrottleEst::new(user_cfg.hover_throttle);
+
+    state_volatile.lost_craft = LostCraft::load(&mut flash_onboard);
+    if let Some(p) = state_volatile.lost_craft.saved {
+        println!(
+            "Last known position from flash: lat {} lon {}",
+            p.lat as f32 / 10_000_000.,
+            p.lon as f32 / 10_000_000.
+        );
+    }
+
+    state_volatile.flight_stats = FlightStatsState::load(&mut flash_onboard);
+    state_volatile.health_trend = HealthTrend::load(&mut flash_onboard);
+    state_volatile.accel_maps = AccelMaps::load(&mut flash_onboard);
+
+    let prev_brownout = brownout::check_prev_session(&mut flash_onboard);
+
+    if watchdog::check_reset_cause() {
+        println!("The previous session ended in a watchdog reset");
+        event_log::log(EventCode::WatchdogReset, 0, 0);
+    }
+
+    #[cfg(feature = "fixed-wing")]
+    servo::set_freq(user_cfg.servo_cfg.update_freq, &mut servo_timer);
+
+    let mut ahrs = Ahrs::new(rates.dt_imu, DeviceOrientation::default());
+    // let mut ahrs = Ahrs::new(rates.dt_imu, user_cfg.orientation); // todo
 
     ahrs.cal.acc_bias = Vec3::new(
         user_cfg.acc_cal_bias.0,
@@ -289,23 +404,55 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
 
     let mut params = Default::default();
 
-    let (system_status, altimeter) = setup::init_sensors(
+    let mut imu_filters = ImuFilters::default();
+    imu_filters.update_gyro_lpfs(&user_cfg.gyro_lpf);
+
+    let mut flash_ext = ExtFlash::new(flash_spi, cs_flash);
+
+    let (mut system_status, altimeter) = setup::init_sensors(
         &mut params,
         &mut state_volatile.base_point,
         &mut spi1,
-        &mut flash_spi,
+        &mut flash_ext,
         &mut i2c1,
         &mut i2c2,
         &mut cs_imu,
-        &mut cs_flash,
+        &user_cfg.imu_cfg,
+        user_cfg.gps_nav_rate,
         &clock_cfg,
     );
+    state_volatile.imu_integrity.cfg_applied = user_cfg.imu_cfg;
+
+    #[cfg(feature = "h7")]
+    setup::setup_imu2(dp.SPI4, &user_cfg.dual_imu, &mut system_status);
+    state_volatile.dual_imu = DualImu::new(&user_cfg.dual_imu, &system_status);
+
+    // If the external flash isn't detected, we log to onboard flash instead of blocking boot.
+    let (log_backend, fallback) = user_cfg.log_storage.resolve(flash_ext.detected());
+    system_status.ext_flash_fallback = fallback;
+
+    if let Some(arm_status) = prev_brownout {
+        println!(
+            "The previous session ended in a brownout. Arm status: {}",
+            arm_status
+        );
+        system_status.prev_brownout = true;
+        event_log::log(EventCode::PrevBrownout, arm_status as u16, 0);
+    }
+
+    state_volatile.blackbox.backend = log_backend;
+    state_volatile.blackbox.init(&mut LogStorage::new(
+        log_backend,
+        &mut flash_onboard,
+        &mut flash_ext,
+    ));
 
     println!(
         "System status:\n IMU: {}, Baro: {}, Mag: {}, GPS: {}, TOF: {}, OSD: {}",
         system_status.imu == SensorStatus::Pass,
         system_status.baro == SensorStatus::Pass,
-        system_status.magnetometer_can == SensorStatus::Pass,
+        system_status.magnetometer == SensorStatus::Pass
+            || system_status.magnetometer_can == SensorStatus::Pass,
         system_status.gnss_can == SensorStatus::Pass,
         system_status.tof == SensorStatus::Pass,
         system_status.osd == SensorStatus::Pass,
@@ -331,29 +478,27 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
 
     // Set up motor direction; do this once the warmup time has elapsed.
     #[cfg(feature = "quad")]
-    // todo: Wrong. You need to do this by number; apply your pin mapping.
-    let motors_reversed = (
-        state_volatile.motor_servo_state.rotor_aft_right.reversed,
-        state_volatile.motor_servo_state.rotor_front_right.reversed,
-        state_volatile.motor_servo_state.rotor_aft_left.reversed,
-        state_volatile.motor_servo_state.rotor_front_left.reversed,
-        // user_cfg.control_mapping.m1_reversed,
-        // user_cfg.control_mapping.m2_reversed,
-        // user_cfg.control_mapping.m3_reversed,
-        // user_cfg.control_mapping.m4_reversed,
+    dshot::setup_motor_dir(
+        user_cfg.control_mapping.motors_reversed(),
+        true,
+        &mut motor_timer,
     );
 
     #[cfg(feature = "quad")]
-    dshot::setup_motor_dir(motors_reversed, &mut motor_timer);
+    dshot::setup_3d_mode(user_cfg.reversible, &mut motor_timer);
 
-    crsf::setup(&mut uart_crsf);
+    match user_cfg.rx_protocol {
+        RxProtocol::Crsf => crsf::setup(&mut uart_crsf),
+        RxProtocol::Sbus => sbus::setup(&mut uart_crsf, &clock_cfg),
+    }
+    esc_telemetry::setup(&mut uart_esc_telem);
 
     // Start our main loop
     // update_timer.enable();
-    adc_timer.enable();
     tick_timer.enable();
+    watchdog_timer.enable();
 
-    iwdg::setup(0.1);
+    watchdog::setup();
 
     println!("Init complete; starting main loops");
 
@@ -385,8 +530,9 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
             usb_dev,
             usb_serial,
             flash_onboard,
+            flash_ext,
             power_used: 0.,
-            imu_filters: Default::default(),
+            imu_filters,
             flight_ctrl_filters: Default::default(),
             ext_sensor_active: ExtSensor::Mag,
             pwr_maps: Default::default(),
@@ -394,9 +540,10 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
             motor_pid_coeffs: Default::default(),
             // rpm_readings: Default::default(),
             // rpms_commanded: Default::default(),
-            tick_timer,
             can,
             fix: Default::default(),
+            gps_fix: Default::default(),
+            mag_reading: None,
             posit_inertial: Default::default(),
             ahrs,
             calibrating_accel: false,
@@ -404,7 +551,8 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
         Local {
             // update_timer,
             uart_crsf,
-            // spi_flash, // todo: Fix flash in HAL, then do this.
+            rx_protocol: user_cfg.rx_protocol,
+            uart_esc_telem,
             arm_signals_received: 0,
             disarm_signals_received: 0,
             // update_isr_loop_i: 0,
@@ -414,10 +562,19 @@ pub fn run(mut cx: app::init::Context) -> (Shared, Local) {
             time_with_high_throttle: 0.,
             time_with_low_throttle: 0.,
             dshot_read_timer,
+            watchdog_timer,
+            tick_timer,
             cs_imu,
             params_prev: params,
             batt_curr_adc,
+            mcu_temp_adc,
             task_durations: Default::default(),
+            ubx_parser: Default::default(),
+            msp_parser: Default::default(),
+            usb_decoder: Default::default(),
+            indicators,
+            led_status,
+            camera_tilt,
         },
     )
 }
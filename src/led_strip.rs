@@ -0,0 +1,180 @@
+//! This module contains status display on an addressable (WS2812) RGB LED strip. LEDs at the start
+//! of the strip are the front of the aircraft, and the rest are the rear; this shows orientation
+//! at a distance, in the same way as navigation lights. Warnings override or flash these colors.
+//!
+//! We update the strip at a fixed rate from the main loop. Each update starts a low-priority DMA
+//! transfer, so it doesn't delay motor updates. See `drivers::led_strip_ws2812` for the protocol.
+
+use crate::{
+    drivers::led_strip_ws2812::{LedStrip, Rgb, MAX_LEDS},
+    protocols::crsf::LinkStats,
+    safety::ArmStatus,
+    sensors_shared::BattCellCount,
+    system_status::{SensorStatus, SystemStatus},
+    util,
+};
+
+// Seconds between strip updates. (20Hz)
+const UPDATE_INTERVAL: f32 = 0.05;
+
+// Seconds. Warnings flash on and off at this half-period.
+const FLASH_TIME: f32 = 0.2;
+
+// Below this uplink link quality, 0 - 100, we flash the front LEDs.
+const LOW_LINK_QUALITY: u8 = 70;
+
+// Battery remaining, from `util::batt_left_from_v`, below which we warn. Matches the buzzer.
+const LOW_BATT_THRESH: f32 = 0.2;
+// Below this voltage, we assume no battery is connected, eg powered from USB, and don't warn.
+const BATT_PRESENT_V: f32 = 3.;
+
+// While disarmed, colors are dimmed by this factor, 0 - 255.
+const DISARMED_DIM: u8 = 64;
+
+// Serialized size: Enabled, LED count, front count, brightness, and 4 colors.
+pub const LED_STRIP_CFG_SIZE: usize = 4 + 4 * 3;
+
+/// LED strip settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct LedStripCfg {
+    /// If false, the strip is kept off; eg if one isn't installed.
+    pub enabled: bool,
+    /// Number of LEDs on the strip. 1 - `MAX_LEDS`.
+    pub num_leds: u8,
+    /// The first this-many LEDs are the front; the rest are the rear.
+    pub front_count: u8,
+    /// Applied to all colors. 0 - 255.
+    pub brightness: u8,
+    pub front_color: Rgb,
+    pub rear_color: Rgb,
+    /// Flashed on the rear LEDs at low battery.
+    pub warning_color: Rgb,
+    /// Flashed on the front LEDs at low link quality, and on all LEDs on link loss.
+    pub link_color: Rgb,
+}
+
+impl Default for LedStripCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            num_leds: 8,
+            front_count: 4,
+            brightness: 128,
+            front_color: Rgb::new(255, 255, 255),
+            rear_color: Rgb::new(255, 0, 0),
+            warning_color: Rgb::new(255, 128, 0),
+            link_color: Rgb::new(0, 0, 255),
+        }
+    }
+}
+
+impl LedStripCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf[0] > 1 || buf[1] == 0 || buf[1] as usize > MAX_LEDS || buf[2] > buf[1] {
+            return None;
+        }
+
+        Some(Self {
+            enabled: buf[0] != 0,
+            num_leds: buf[1],
+            front_count: buf[2],
+            brightness: buf[3],
+            front_color: Rgb::from_bytes(&buf[4..7]),
+            rear_color: Rgb::from_bytes(&buf[7..10]),
+            warning_color: Rgb::from_bytes(&buf[10..13]),
+            link_color: Rgb::from_bytes(&buf[13..16]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; LED_STRIP_CFG_SIZE] {
+        let mut result = [0; LED_STRIP_CFG_SIZE];
+
+        result[0] = self.enabled as u8;
+        result[1] = self.num_leds;
+        result[2] = self.front_count;
+        result[3] = self.brightness;
+        result[4..7].clone_from_slice(&self.front_color.to_bytes());
+        result[7..10].clone_from_slice(&self.rear_color.to_bytes());
+        result[10..13].clone_from_slice(&self.warning_color.to_bytes());
+        result[13..16].clone_from_slice(&self.link_color.to_bytes());
+        result
+    }
+}
+
+/// Owns the LED strip.
+pub struct LedStatus {
+    strip: LedStrip,
+    /// Seconds since start.
+    last_update: f32,
+}
+
+impl LedStatus {
+    pub fn new(strip: LedStrip) -> Self {
+        Self {
+            strip,
+            last_update: 0.,
+        }
+    }
+
+    /// Run each main loop update; this only writes to the strip at `UPDATE_INTERVAL`.
+    pub fn update(
+        &mut self,
+        system_status: &SystemStatus,
+        arm_status: ArmStatus,
+        link_stats: &LinkStats,
+        batt_v: f32,
+        cell_count: BattCellCount,
+        cfg: &LedStripCfg,
+        timestamp: f32,
+    ) {
+        if timestamp - self.last_update < UPDATE_INTERVAL {
+            return;
+        }
+        self.last_update = timestamp;
+
+        let num_leds = (cfg.num_leds as usize).min(MAX_LEDS);
+        let mut colors = [Rgb::OFF; MAX_LEDS];
+
+        if cfg.enabled {
+            let flash_on = (timestamp / FLASH_TIME) as u32 % 2 == 0;
+
+            // A link we've never had isn't a warning; eg on the bench, with the radio off.
+            let link_ok = system_status.rf_control_link == SensorStatus::Pass;
+            let link_lost = system_status.update_timestamps.rf_control_link.is_some() && !link_ok;
+
+            let low_batt = batt_v > BATT_PRESENT_V
+                && util::batt_left_from_v(batt_v, cell_count) < LOW_BATT_THRESH;
+            let low_lq = link_ok && link_stats.uplink_link_quality < LOW_LINK_QUALITY;
+
+            let mut brightness = cfg.brightness;
+            if arm_status == ArmStatus::Disarmed {
+                brightness = (brightness as u16 * DISARMED_DIM as u16 / 255) as u8;
+            }
+
+            for (i, color) in colors.iter_mut().take(num_leds).enumerate() {
+                let front = i < cfg.front_count as usize;
+
+                let c = if link_lost {
+                    if flash_on {
+                        cfg.link_color
+                    } else {
+                        Rgb::OFF
+                    }
+                } else if front && low_lq && flash_on {
+                    cfg.link_color
+                } else if !front && low_batt && flash_on {
+                    cfg.warning_color
+                } else if front {
+                    cfg.front_color
+                } else {
+                    cfg.rear_color
+                };
+
+                *color = c.scaled(brightness);
+            }
+        }
+
+        self.strip.write(&colors[..num_leds]);
+    }
+}
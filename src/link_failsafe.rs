@@ -0,0 +1,182 @@
+//! This module contains the control link failsafe. Links flicker; a brief dropout shouldn't start
+//! a return to home, so this has two stages:
+//!
+//! - Stage 1, `Hold`: Once control channel data has been missing for the hold delay, we freeze the
+//!   attitude and throttle commanded when it stopped, with throttle reduced, and wait. Stale stick
+//!   positions are no longer used.
+//! - Stage 2, `Lost`: Once it's been missing for the lost delay, the control link is marked lost;
+//!   `state::update_flight_modes` starts link-lost recovery.
+//!
+//! When channel data resumes, we return to normal from either stage. In acro modes, the attitude
+//! commanded continues from the one held, so there's no jump.
+//!
+//! Receivers send other packets alongside channel data, eg CRSF link stats, or SBUS failsafe
+//! frames. We time these separately: Channel data stopping while other packets continue is a link
+//! dropout; everything stopping points to the receiver, or its wiring. Stage changes are logged in
+//! the event log, with which this was.
+
+use defmt::println;
+use lin_alg::f32::Quaternion;
+
+use crate::{
+    event_log::{self, EventCode},
+    flight_ctrls::common::AttitudeCommanded,
+    system_status::{SensorStatus, SystemStatus},
+};
+
+// Packets other than channel data older than this, in s, indicate the receiver has stopped.
+const RX_PACKET_TIMEOUT: f32 = 0.3;
+
+// Values outside these ranges are rejected when loading config.
+pub const HOLD_DELAY_MIN: f32 = 0.05;
+pub const HOLD_DELAY_MAX: f32 = 2.;
+pub const LOST_DELAY_MIN: f32 = 0.1;
+pub const LOST_DELAY_MAX: f32 = 10.;
+pub const HOLD_THROTTLE_MIN: f32 = 0.5;
+
+// Serialized size: Hold delay, lost delay, and hold throttle.
+pub const LINK_FAILSAFE_CFG_SIZE: usize = 4 * 3;
+
+/// Repr is how it's passed in the event log.
+#[derive(Clone, Copy, PartialEq, Default)]
+#[repr(u8)]
+pub enum LinkStage {
+    #[default]
+    Ok = 0,
+    Hold = 1,
+    Lost = 2,
+}
+
+/// Link failsafe timing. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct LinkFailsafeCfg {
+    /// Seconds without channel data before holding the last attitude and throttle commanded.
+    pub hold_delay: f32,
+    /// Seconds without channel data before the link is lost, and recovery starts. If this is
+    /// below the hold delay, there's no hold.
+    pub lost_delay: f32,
+    /// While holding, throttle is the last commanded times this, 0. to 1.
+    pub hold_throttle: f32,
+}
+
+impl Default for LinkFailsafeCfg {
+    fn default() -> Self {
+        Self {
+            hold_delay: 0.3,
+            lost_delay: 1.5,
+            hold_throttle: 0.9,
+        }
+    }
+}
+
+impl LinkFailsafeCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let hold_delay = f(0);
+        let lost_delay = f(4);
+        let hold_throttle = f(8);
+
+        // These comparisons also reject NaN.
+        if !(HOLD_DELAY_MIN..=HOLD_DELAY_MAX).contains(&hold_delay)
+            || !(LOST_DELAY_MIN..=LOST_DELAY_MAX).contains(&lost_delay)
+            || !(HOLD_THROTTLE_MIN..=1.).contains(&hold_throttle)
+        {
+            return None;
+        }
+
+        Some(Self {
+            hold_delay,
+            lost_delay,
+            hold_throttle,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; LINK_FAILSAFE_CFG_SIZE] {
+        let mut result = [0; LINK_FAILSAFE_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.hold_delay.to_be_bytes());
+        result[4..8].clone_from_slice(&self.lost_delay.to_be_bytes());
+        result[8..12].clone_from_slice(&self.hold_throttle.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Default)]
+pub struct LinkFailsafe {
+    pub stage: LinkStage,
+    /// Attitude and throttle commanded when the hold started.
+    held: Option<(Quaternion, f32)>,
+}
+
+impl LinkFailsafe {
+    /// Run each flight control update, after loading channel data, and before updating attitude
+    /// commanded from it. Sets the control link's status.
+    pub fn update(
+        &mut self,
+        system_status: &mut SystemStatus,
+        cmd: &AttitudeCommanded,
+        cfg: &LinkFailsafeCfg,
+        timestamp: f32,
+    ) {
+        let Some(t) = system_status.update_timestamps.rf_control_link else {
+            // A link we've never had isn't lost; eg on the bench, with the radio off.
+            system_status.rf_control_link = SensorStatus::NotConnected;
+            return;
+        };
+
+        let dropout = timestamp - t;
+
+        let stage = if dropout > cfg.lost_delay {
+            LinkStage::Lost
+        } else if dropout > cfg.hold_delay {
+            LinkStage::Hold
+        } else {
+            LinkStage::Ok
+        };
+
+        if stage == self.stage {
+            return;
+        }
+
+        let rx_sending = match system_status.update_timestamps.rf_packet {
+            Some(t) => timestamp - t < RX_PACKET_TIMEOUT,
+            None => false,
+        };
+
+        match stage {
+            LinkStage::Ok => {
+                // `LinkUp` is logged on reception.
+                self.held = None;
+            }
+            LinkStage::Hold => {
+                println!("Control link dropout; holding");
+                event_log::log(EventCode::LinkHold, rx_sending as u16, 0);
+            }
+            LinkStage::Lost => {
+                // Recovery is handled in `state::update_flight_modes`.
+                event_log::log(EventCode::LinkLost, rx_sending as u16, 0);
+                system_status.rf_control_link = SensorStatus::NotConnected;
+            }
+        }
+
+        // Keep the attitude from before the dropout, if we skip the hold.
+        if stage != LinkStage::Ok && self.held.is_none() {
+            self.held = Some((cmd.quat, cmd.throttle));
+        }
+
+        self.stage = stage;
+    }
+
+    /// Run each time attitude and throttle commanded are updated from channel data, after they
+    /// are. While channel data is missing, replaces them with the held values. Throttle may be
+    /// signed, in 3D mode.
+    pub fn apply(&self, cmd: &mut AttitudeCommanded, cfg: &LinkFailsafeCfg) {
+        if let Some((quat, throttle)) = self.held {
+            cmd.quat = quat;
+            cmd.quat_dt = (0., 0., 0.);
+            cmd.throttle = throttle * cfg.hold_throttle;
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! This module contains the rates the IMU, flight controls, and lower-priority tasks run at. These
+//! are set from user config at init, and are constant after. The IMU's output data rate drives the
+//! main loop; flight controls run on a portion of IMU updates, and the lower-priority tasks cycle
+//! through IMU updates.
+
+use defmt::println;
+use num_enum::TryFromPrimitive;
+
+use crate::main_loop::NUM_IMU_LOOP_TASKS;
+
+// The IMU's internal clock runs at 32.768kHz, so its output data rates are slightly above nominal.
+// This matches measurements at 8kHz.
+const IMU_CLOCK_RATIO: f32 = 1.024;
+
+/// Flight control decimation ratios above this are rejected.
+const FLIGHT_CTRL_IMU_RATIO_MAX: u8 = 16;
+
+/// If the measured mean IMU interval deviates from the configured one by more than this portion,
+/// we flag it; timing-dependent code would be skewed.
+pub const IMU_INTERVAL_TOLERANCE: f32 = 0.03;
+
+#[cfg(feature = "quad")]
+const FLIGHT_CTRL_IMU_RATIO_DEFAULT: u8 = 4; // Likely values: 1, 2, 4, 8.
+#[cfg(feature = "fixed-wing")]
+const FLIGHT_CTRL_IMU_RATIO_DEFAULT: u8 = 8; // Likely values: 4, 8, 16.
+
+/// IMU output data rate. Stored in user config.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ImuOdr {
+    #[default]
+    Hz8k = 0,
+    Hz4k = 1,
+    Hz2k = 2,
+    Hz1k = 3,
+}
+
+impl ImuOdr {
+    /// The ODR field of the ICM426xx `GYRO_CONFIG0` and `ACCEL_CONFIG0` registers.
+    pub fn reg_val(self) -> u8 {
+        match self {
+            Self::Hz8k => 0b0011,
+            Self::Hz4k => 0b0100,
+            Self::Hz2k => 0b0101,
+            Self::Hz1k => 0b0110,
+        }
+    }
+
+    /// The actual rate, in Hz.
+    pub fn rate(self) -> f32 {
+        let nominal = match self {
+            Self::Hz8k => 8_000.,
+            Self::Hz4k => 4_000.,
+            Self::Hz2k => 2_000.,
+            Self::Hz1k => 1_000.,
+        };
+        nominal * IMU_CLOCK_RATIO
+    }
+}
+
+/// Parse and validate the flight control decimation ratio from user config. Invalid values, eg from
+/// configs saved before this field was added, use the default.
+pub fn flight_ctrl_imu_ratio_from_byte(val: u8) -> u8 {
+    if (1..=FLIGHT_CTRL_IMU_RATIO_MAX).contains(&val) {
+        val
+    } else {
+        FLIGHT_CTRL_IMU_RATIO_DEFAULT
+    }
+}
+
+pub fn flight_ctrl_imu_ratio_default() -> u8 {
+    FLIGHT_CTRL_IMU_RATIO_DEFAULT
+}
+
+/// Loop rates, and the time steps derived from them. Times are in seconds; rates in Hz.
+pub struct LoopRates {
+    pub imu_odr: ImuOdr,
+    pub imu: f32,
+    pub dt_imu: f32,
+    /// Flight controls run once per this many IMU updates.
+    pub flight_ctrl_imu_ratio: u32,
+    pub dt_flight_ctrls: f32,
+    /// The interval each lower-priority main loop task runs at.
+    pub dt_tasks: f32,
+}
+
+impl LoopRates {
+    pub fn new(imu_odr: ImuOdr, flight_ctrl_imu_ratio: u8) -> Self {
+        let imu = imu_odr.rate();
+        let dt_imu = 1. / imu;
+        let flight_ctrl_imu_ratio = flight_ctrl_imu_ratio_from_byte(flight_ctrl_imu_ratio) as u32;
+
+        Self {
+            imu_odr,
+            imu,
+            dt_imu,
+            flight_ctrl_imu_ratio,
+            dt_flight_ctrls: dt_imu * flight_ctrl_imu_ratio as f32,
+            dt_tasks: dt_imu * NUM_IMU_LOOP_TASKS as f32,
+        }
+    }
+
+    /// Number of IMU updates in a given time, rounded down.
+    pub fn imu_updates(&self, time: f32) -> u32 {
+        (time / self.dt_imu) as u32
+    }
+
+    /// True if a measured mean IMU interval is consistent with the configured rate.
+    pub fn imu_interval_ok(&self, measured: f32) -> bool {
+        ((measured - self.dt_imu) / self.dt_imu).abs() <= IMU_INTERVAL_TOLERANCE
+    }
+}
+
+// Set once at init, before interrupts are enabled; read-only after. The default matches the default
+// user config.
+static mut LOOP_RATES: LoopRates = LoopRates {
+    imu_odr: ImuOdr::Hz8k,
+    imu: 8_000. * IMU_CLOCK_RATIO,
+    dt_imu: 1. / (8_000. * IMU_CLOCK_RATIO),
+    flight_ctrl_imu_ratio: FLIGHT_CTRL_IMU_RATIO_DEFAULT as u32,
+    dt_flight_ctrls: FLIGHT_CTRL_IMU_RATIO_DEFAULT as f32 / (8_000. * IMU_CLOCK_RATIO),
+    dt_tasks: NUM_IMU_LOOP_TASKS as f32 / (8_000. * IMU_CLOCK_RATIO),
+};
+
+/// Set loop rates from user config. Run this once, at init, prior to setting up the IMU.
+pub fn init(imu_odr: ImuOdr, flight_ctrl_imu_ratio: u8) {
+    let rates = LoopRates::new(imu_odr, flight_ctrl_imu_ratio);
+
+    println!(
+        "Loop rates: IMU: {}Hz, flight controls: {}Hz",
+        rates.imu,
+        1. / rates.dt_flight_ctrls
+    );
+
+    unsafe { LOOP_RATES = rates };
+}
+
+/// The loop rates in use.
+pub fn rates() -> &'static LoopRates {
+    unsafe { &*core::ptr::addr_of!(LOOP_RATES) }
+}
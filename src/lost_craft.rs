@@ -0,0 +1,174 @@
+//! This module contains the lost-craft locator. While we have a valid GPS fix, we periodically
+//! save the position to a flash slot, so it survives the battery ejecting in a crash. On the next
+//! power-up, it's reported over USB and on the OSD, until a new fix supersedes it.
+//!
+//! To limit flash wear, and CPU stalls from writing onboard flash, we only save once we've moved
+//! a set distance since the last save. Writes are performed in the idle task.
+//!
+//! While the link is lost, we send the position in CRSF GPS telemetry frames, so the
+//! transmitter's telemetry log captures it if the receiver can still reach it.
+
+use hal::flash::{Bank, Flash};
+
+use crate::{drivers::gps_ublox::GpsFix, geo::LatLon, storage};
+
+// Marks a valid slot. Erased flash reads 0xff.
+const SLOT_MARKER: u8 = 0xa5;
+
+// Marker, lat, lon, and altitude.
+pub const LAST_POSIT_SIZE: usize = 1 + 4 + 4 + 4;
+
+// We check if we should save at this interval, in seconds, while we have a valid fix.
+const SAVE_INTERVAL: f32 = 5.;
+// We only save once we've moved this far from the last saved position, in meters.
+const SAVE_DIST: f32 = 20.;
+
+/// A position from a GPS fix.
+#[derive(Clone, Copy, Default)]
+pub struct LastPosit {
+    /// Degrees x 1e7.
+    pub lat: i32,
+    /// Degrees x 1e7.
+    pub lon: i32,
+    /// Meters.
+    pub alt_msl: f32,
+}
+
+impl LastPosit {
+    pub fn from_fix(fix: &GpsFix) -> Self {
+        Self {
+            lat: fix.lat,
+            lon: fix.lon,
+            alt_msl: fix.alt_msl,
+        }
+    }
+
+    /// A fix with only the position populated, for telemetry.
+    pub fn to_fix(&self) -> GpsFix {
+        GpsFix {
+            lat: self.lat,
+            lon: self.lon,
+            alt_msl: self.alt_msl,
+            ..Default::default()
+        }
+    }
+
+    /// Returns `None` if the slot is empty, or the values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf[0] != SLOT_MARKER {
+            return None;
+        }
+
+        let lat = i32::from_be_bytes(buf[1..5].try_into().unwrap());
+        let lon = i32::from_be_bytes(buf[5..9].try_into().unwrap());
+        let alt_msl = f32::from_be_bytes(buf[9..13].try_into().unwrap());
+
+        if lat.unsigned_abs() > 900_000_000
+            || lon.unsigned_abs() > 1_800_000_000
+            || !alt_msl.is_finite()
+        {
+            return None;
+        }
+
+        Some(Self { lat, lon, alt_msl })
+    }
+
+    pub fn to_bytes(&self) -> [u8; LAST_POSIT_SIZE] {
+        let mut result = [0; LAST_POSIT_SIZE];
+
+        result[0] = SLOT_MARKER;
+        result[1..5].clone_from_slice(&self.lat.to_be_bytes());
+        result[5..9].clone_from_slice(&self.lon.to_be_bytes());
+        result[9..13].clone_from_slice(&self.alt_msl.to_be_bytes());
+        result
+    }
+
+    /// Horizontal distance, in meters.
+    fn dist(&self, other: &Self) -> f32 {
+        LatLon::from_e7(self.lat, self.lon).dist(&LatLon::from_e7(other.lat, other.lon))
+    }
+}
+
+/// Where the known position came from. Repr is how it's passed over USB.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum PositSource {
+    None = 0,
+    /// A fix received since power-up.
+    Live = 1,
+    /// Saved to flash prior to this power-up.
+    Saved = 2,
+}
+
+#[derive(Default)]
+pub struct LostCraft {
+    /// Loaded from flash at power-up. Cleared once we get a valid fix.
+    pub saved: Option<LastPosit>,
+    /// The most recent position from a valid fix, since power-up.
+    pub live: Option<LastPosit>,
+    /// The last position we wrote to flash this power-up.
+    last_written: Option<LastPosit>,
+    /// Taken by the idle task, which writes it to flash.
+    write_pending: Option<LastPosit>,
+    /// Seconds since start.
+    last_check: f32,
+}
+
+impl LostCraft {
+    /// Run at init, before the main loop starts.
+    pub fn load(flash: &mut Flash) -> Self {
+        let mut buf = [0; LAST_POSIT_SIZE];
+        flash.read(Bank::B1, crate::FLASH_LAST_POSIT_PAGE, 0, &mut buf);
+
+        Self {
+            saved: LastPosit::from_bytes(&buf),
+            ..Default::default()
+        }
+    }
+
+    /// Run periodically, with the most recent fix. `fix_valid` is true if it's recent, and
+    /// accurate enough to use.
+    pub fn update(&mut self, fix: &GpsFix, fix_valid: bool, timestamp: f32) {
+        if !fix_valid {
+            return;
+        }
+
+        let posit = LastPosit::from_fix(fix);
+        self.live = Some(posit);
+        self.saved = None;
+
+        if timestamp - self.last_check < SAVE_INTERVAL {
+            return;
+        }
+        self.last_check = timestamp;
+
+        let moved = match &self.last_written {
+            Some(p) => p.dist(&posit) > SAVE_DIST,
+            None => true,
+        };
+
+        if moved {
+            self.write_pending = Some(posit);
+            self.last_written = Some(posit);
+        }
+    }
+
+    /// The best position we have, and where it's from.
+    pub fn known_posit(&self) -> (Option<LastPosit>, PositSource) {
+        match (self.live, self.saved) {
+            (Some(p), _) => (Some(p), PositSource::Live),
+            (None, Some(p)) => (Some(p), PositSource::Saved),
+            _ => (None, PositSource::None),
+        }
+    }
+
+    /// Run from the idle task. Returns a position to write, if one is pending.
+    pub fn take_write_pending(&mut self) -> Option<LastPosit> {
+        self.write_pending.take()
+    }
+}
+
+/// Write a position to the flash slot. Blocking, and slow; run from the idle task.
+pub fn write(flash: &mut Flash, posit: &LastPosit) {
+    storage::write_onboard_page(flash, crate::FLASH_LAST_POSIT_PAGE, &posit.to_bytes()).ok();
+}
@@ -1,5 +1,5 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 // Used on USB protocol. Allows adding to the const param buff size
 // to make packet size.
 
@@ -26,71 +26,113 @@ use defmt_rtt as _;
 use hal::{
     self,
     adc::Adc,
-    dma::{self, ChannelCfg, DmaInterrupt},
+    dma::{self, DmaInterrupt},
     flash::Flash,
     gpio::{self, Pin},
     i2c::I2c,
-    iwdg,
-    pac::{self, I2C1, I2C2, SPI1, TIM1, TIM2, TIM5},
+    pac::{self, I2C1, I2C2, SPI1, TIM1, TIM17, TIM2, TIM5},
     spi::Spi,
-    timer::{Timer, TimerInterrupt, TICK_OVERFLOW_COUNT},
+    timer::{Timer, TimerInterrupt},
     usart::UsartInterrupt,
 };
+use lin_alg::f32::Vec3;
 use panic_probe as _;
 use rtic::app;
 use usb_device::prelude::*;
 use usbd_serial::{self, SerialPort};
 
+mod adc_cal;
+mod adc_sampling;
+mod alt_estimator;
 mod atmos_model;
+mod batt_failsafe;
+mod blackbox;
 mod board_config;
+mod brownout;
+mod camera_tilt;
 mod can_reception;
 mod controller_interface;
+mod debug_log;
+mod dfu;
+mod dma_stats;
 mod drivers;
+mod event_log;
+mod ext_ctrl;
 mod flight_ctrls;
+mod flight_stats;
+mod geo;
+mod gps_metrics;
+mod ground_ref;
+mod health_trend;
+mod hil;
 mod imu_processing;
+mod indicators;
 mod init;
+mod led_strip;
+mod link_failsafe;
+mod loop_rates;
+mod lost_craft;
 mod main_loop;
+mod motor_kill;
+mod motor_wizard;
+mod output_pattern;
+mod params;
+mod perf_stats;
 mod protocols;
+mod rth;
 mod safety;
+mod self_test;
 mod sensors_shared;
 mod setup;
 mod state;
+mod storage;
 mod system_status;
+mod usb_isr;
 mod util;
+mod vib_test;
+mod watchdog;
+
+// Each of these selects the USB peripheral, and the interrupt its ISR is bound to.
+#[cfg(not(any(feature = "h723", feature = "h743", feature = "g4")))]
+compile_error!("Select an MCU feature: `h723`, `h743`, or `g4`. Otherwise, no USB ISR is bound.");
 
 use crate::{
-    controller_interface::ChannelData,
-    drivers::{baro_dps310 as baro, imu_icm426xx as imu, osd, tof_vl53l1 as tof},
+    blackbox::{self, LogStorage},
+    camera_tilt::CameraTilt,
+    controller_interface::{self, ChannelData, RxProtocol},
+    drivers::{
+        baro_dps310 as baro,
+        flash_spi::ExtFlash,
+        gps_ublox::{self as gps, GpsFix, UbxParser},
+        imu_icm426xx as imu, mag_lis3mdl as mag, osd, tof_vl53l1 as tof,
+    },
     flight_ctrls::{
         autopilot::AutopilotStatus, ctrl_effect_est::AccelMaps, filters::FlightCtrlFilters,
         pid::MotorCoeffs,
     },
     imu_processing::{filter_imu::ImuFilters, imu_shared},
+    indicators::Indicators,
+    led_strip::LedStatus,
     protocols::{
         crsf::{self, LinkStats},
-        dshot, msp, usb_preflight,
+        dshot, esc_telemetry, msp, sbus, usb_frame,
     },
-    sensors_shared::ExtSensor,
+    sensors_shared::{ExtSensor, MagSource},
+    setup::{DmaTransfer, UsbBusType},
     state::{StateVolatile, UserConfig},
-    system_status::SystemStatus,
+    storage::NonVolatileStorage,
+    system_status::{SensorStatus, SystemStatus},
+    util::monotonic,
+    watchdog,
 };
 
 cfg_if! {
     if #[cfg(feature = "h7")] {
-        use hal::{
-            // todo: USB1 on H723; USB2 on H743.
-            usb::{Usb2, UsbBus, Usb2BusType as UsbBusType},
-            // pac::QUADSPI,
-            // qspi::{Qspi},
-        };
+        // use hal::{pac::QUADSPI, qspi::Qspi};
         // This USART alias is made pub here, so we don't repeat this line in other modules.
-        pub use hal::pac::{ADC1 as ADC};
+        pub use hal::pac::{ADC1 as ADC, ADC3 as MCU_TEMP_ADC};
     } else if #[cfg(feature = "g4")] {
-        use hal::{
-            usb::{ UsbBusType},
-        };
-
-        pub use hal::pac::{UART4, ADC2 as ADC};
+        pub use hal::pac::{UART4, ADC2 as ADC, ADC1 as MCU_TEMP_ADC};
     }
 }
 
@@ -115,13 +157,21 @@ cfg_if! {
         // 8 sectors of 128kb each.
         // (H743 is similar, but may have 2 banks, each with those properties)
         const FLASH_CFG_PAGE: usize = 6; // called sector on H7.
-        const FLASH_WAYPOINT_PAGE: usize = 7;
+        // The lost-craft locator's last known position. (Waypoints are stored in user config.)
+        const FLASH_LAST_POSIT_PAGE: usize = 7;
+        // Cumulative flight statistics, and the brownout marker. Sectors 3 - 7 are all in use, so
+        // this caps the firmware at sectors 0 - 2: 384kb. If it grows past that, flashing it
+        // overwrites the brownout marker and stats.
+        const FLASH_STATS_PAGE: usize = 4;
+        const FLASH_BROWNOUT_PAGE: usize = 3;
     } else {
         // G47x/G48x: 512k flash.
         // Assumes configured as a single bank: 128 pages of 4kb each.
         // (If using G4 dual bank mode: 128 pages of pages of 2kb each, per bank)
         const FLASH_CFG_PAGE: usize = 126;
-        const FLASH_WAYPOINT_PAGE: usize = 127;
+        const FLASH_LAST_POSIT_PAGE: usize = 127;
+        const FLASH_STATS_PAGE: usize = 125;
+        const FLASH_BROWNOUT_PAGE: usize = 124;
     }
 }
 
@@ -132,7 +182,11 @@ const CTRL_COEFF_ADJ_AMT: f32 = 0.01; // seconds
 
 // We use a hardware counter to measure relative system time. This is the number of times
 // it has overflowed. (timer expired)
-const TICK_TIMER_PERIOD: f32 = 0.5; // in seconds. Decrease for higher measurement precision.
+const TICK_TIMER_PERIOD: f32 = 0.5; // in seconds. See `util::monotonic`.
+
+// Hz. We check sensor data freshness at this rate; fast enough to catch a stale IMU within
+// `system_status::MAX_UPDATE_PERIOD_IMU`.
+const SENSOR_WATCHDOG_FREQ: f32 = 400.;
 
 static mut CAN_BUF_RX: [u8; 64] = [0; 64];
 
@@ -169,6 +223,8 @@ mod app {
         pub uart_osd: setup::UartOsd, // for our DJI OSD, via MSP protocol
         pub altimeter: baro::Altimeter,
         pub flash_onboard: Flash,
+        /// External SPI flash. Present even if not detected.
+        pub flash_ext: ExtFlash,
         pub motor_timer: setup::MotorTimer,
         pub servo_timer: setup::ServoTimer,
         pub usb_dev: UsbDevice<'static, UsbBusType>,
@@ -188,9 +244,13 @@ mod app {
         // pub motor_pid_state: MotorPidGroup,
         /// PID motor coefficients
         pub motor_pid_coeffs: MotorCoeffs,
-        pub tick_timer: Timer<TIM5>,
         pub can: setup::Can_,
         pub fix: Fix,
+        /// The most recent fix from the GPS on our I2C bus. `fix` is also updated from this.
+        pub gps_fix: GpsFix,
+        /// The most recent magnetometer reading, in µT, prior to calibration. Taken (set to `None`)
+        /// when consumed by the main loop.
+        pub mag_reading: Option<(Vec3, MagSource)>,
         pub ahrs: Ahrs,
         pub posit_inertial: PositInertial,
         pub calibrating_accel: bool,
@@ -199,8 +259,10 @@ mod app {
     #[local]
     pub struct Local {
         // update_timer: Timer<TIM15>,
-        pub uart_crsf: setup::UartCrsf, // for ELRS over CRSF.
-        // spi_flash: SpiFlash,  // todo: Fix flash in HAL, then do this.
+        pub uart_crsf: setup::UartCrsf, // for ELRS over CRSF, or SBUS.
+        /// The receiver protocol on `uart_crsf`, from user config at init.
+        pub rx_protocol: RxProtocol,
+        pub uart_esc_telem: setup::UartEscTelem,
         pub arm_signals_received: u8, // todo: Put sharedin state volatile.
         pub disarm_signals_received: u8,
         /// We use this counter to subdivide the main loop into longer intervals,
@@ -212,14 +274,31 @@ mod app {
         pub time_with_high_throttle: f32,
         pub time_with_low_throttle: f32,
         pub dshot_read_timer: Timer<TIM2>,
+        /// Triggers the sensor watchdog.
+        pub watchdog_timer: Timer<TIM17>,
+        /// Maintains the time since start. See `util::monotonic`.
+        pub tick_timer: Timer<TIM5>,
         pub cs_imu: Pin,
         // todo: `params_prev` is an experimental var used in our alternative/experimental
         // todo flight controls code as a derivative.
         pub params_prev: Params,
         pub batt_curr_adc: Adc<ADC>,
+        pub mcu_temp_adc: Adc<MCU_TEMP_ADC>,
         /// In seconds. Used to track main loop task durations. The 0 index is for the
         /// part of the main loop that runs every time.
         pub task_durations: main_loop::TaskDurations,
+        /// Holds partial UBX frames between GPS reads.
+        pub ubx_parser: UbxParser,
+        /// Holds partial MSP requests between USB reads.
+        pub msp_parser: msp::Parser,
+        /// Holds partial configuration protocol frames between USB reads.
+        pub usb_decoder: usb_frame::Decoder,
+        /// The status LED, and buzzer.
+        pub indicators: Indicators,
+        /// The WS2812 LED strip.
+        pub led_status: LedStatus,
+        /// The camera tilt servo.
+        pub camera_tilt: CameraTilt,
     }
 
     #[init]
@@ -227,10 +306,63 @@ mod app {
         crate::init::run(cx)
     }
 
-    #[idle(shared = [], local = [])]
-    /// In this function, we perform setup code that must occur with interrupts enabled.
-    fn idle(_cx: idle::Context) -> ! {
+    #[idle(shared = [state_volatile, user_cfg, flash_onboard, flash_ext], local = [])]
+    /// In this function, we perform setup code that must occur with interrupts enabled. We also
+    /// write blackbox pages, the lost-craft position, and flight stats, to flash here, since flash
+    /// writes are slow. Control effect maps are saved along with flight stats. User config is
+    /// saved here after parameter writes, once disarmed.
+    fn idle(mut cx: idle::Context) -> ! {
         loop {
+            // Ahead of the others; once a brownout is detected, they won't start.
+            cx.shared
+                .flash_onboard
+                .lock(|flash| brownout::write_mark_pending(flash));
+
+            let (page_ready, backend, posit_pending, stats_pending, cfg_save_pending) =
+                cx.shared.state_volatile.lock(|state| {
+                    (
+                        state
+                            .blackbox
+                            .take_page_ready(state.arm_status != safety::ArmStatus::Disarmed),
+                        state.blackbox.backend,
+                        state.lost_craft.take_write_pending(),
+                        state.flight_stats.take_write_pending().map(|stats| {
+                            (
+                                stats,
+                                state.health_trend.history,
+                                state.accel_maps.to_bytes(),
+                            )
+                        }),
+                        state.arm_status == safety::ArmStatus::Disarmed && params::take_cfg_dirty(),
+                    )
+                });
+
+            if cfg_save_pending {
+                (cx.shared.user_cfg, cx.shared.flash_onboard).lock(|cfg, flash| {
+                    cfg.save(flash);
+                });
+            }
+
+            if let Some(posit) = posit_pending {
+                cx.shared.flash_onboard.lock(|flash| {
+                    lost_craft::write(flash, &posit);
+                });
+            }
+
+            if let Some((stats, health, accel_maps)) = stats_pending {
+                cx.shared.flash_onboard.lock(|flash| {
+                    flight_stats::write(flash, &stats, &health, &accel_maps);
+                });
+            }
+
+            if let Some((page, buf)) = page_ready {
+                (cx.shared.flash_onboard, cx.shared.flash_ext).lock(|flash, flash_ext| {
+                    LogStorage::new(backend, flash, flash_ext)
+                        .write_region(page * blackbox::LOG_PAGE_SIZE, &buf)
+                        .ok();
+                });
+            }
+
             asm::nop();
         }
     }
@@ -245,9 +377,24 @@ mod app {
         #[cfg(feature = "g4")]
         gpio::clear_exti_interrupt(13); // PC13
 
+        perf_stats::mark_imu_ready();
+
         cx.shared.spi1.lock(|spi| {
             imu_shared::read_imu(imu::READINGS_START_ADDR, spi, setup::IMU_DMA_PERIPH);
         });
+
+        // The secondary IMU, if set up. See `dual_imu`.
+        #[cfg(feature = "h7")]
+        imu_shared::read_imu2();
+    }
+
+    #[task(binds = DMA2_STR4,
+    // #[task(binds = DMA2_CH4,
+    priority = 6)]
+    /// Secondary IMU read complete. Store its readings for the main loop. H7 only.
+    fn imu2_tc_isr(_cx: imu2_tc_isr::Context) {
+        #[cfg(feature = "h7")]
+        imu_shared::imu2_read_complete();
     }
 
     /// This ISR Handles received data from the IMU, after DMA transfer is complete. This occurs whenever
@@ -261,10 +408,10 @@ mod app {
     // #[task(binds = DMA1_CH2,
     shared = [altimeter, ahrs, spi1, i2c1, i2c2, params, control_channel_data, link_stats,
     autopilot_status, imu_filters, flight_ctrl_filters, user_cfg, motor_pid_coeffs,
-    motor_timer, servo_timer, state_volatile, system_status, tick_timer, uart_osd, calibrating_accel,
-    flash_onboard, usb_serial],
+    motor_timer, servo_timer, state_volatile, system_status, uart_osd, calibrating_accel,
+    flash_onboard, usb_serial, mag_reading, ext_sensor_active, gps_fix],
     local = [imu_isr_loop_i, cs_imu, params_prev, time_with_high_throttle, time_with_low_throttle,
-    arm_signals_received, disarm_signals_received, batt_curr_adc, task_durations], priority = 4)]
+    arm_signals_received, disarm_signals_received, batt_curr_adc, mcu_temp_adc, task_durations, indicators, led_status, camera_tilt], priority = 4)]
     fn imu_tc_isr(mut cx: imu_tc_isr::Context) {
         cx.local.cs_imu.set_high();
 
@@ -277,136 +424,59 @@ mod app {
             );
         });
 
-        iwdg::pet();
-
         main_loop::run(cx);
     }
 
-    // todo H735 issue on GH: https://github.com/stm32-rs/stm32-rs/issues/743 (works on H743)
-    // todo: NVIC interrupts missing here for H723 etc!
+    // Exactly one of these USB ISRs is compiled in, depending on the MCU; each runs `usb_isr::run`.
+    // On H723, PA11 and PA12 are OTG1's (OTG_HS) embedded full-speed PHY. On H743, they're OTG2's,
+    // whose interrupt is OTG_FS.
+    #[cfg(feature = "h723")]
+    #[task(binds = OTG_HS,
+    shared = [usb_dev, usb_serial, params, control_channel_data, flash_onboard, flash_ext,
+    link_stats, user_cfg, state_volatile, system_status, autopilot_status, motor_timer, servo_timer, calibrating_accel,
+    imu_filters],
+    local = [msp_parser, usb_decoder], priority = 10)]
+    /// See `usb_isr::run`.
+    fn usb_otg_hs_isr(cx: usb_otg_hs_isr::Context) {
+        usb_isr::run(cx);
+    }
+
+    #[cfg(feature = "h743")]
     #[task(binds = OTG_FS,
-    // #[task(binds = USB_LP,
-    shared = [usb_dev, usb_serial, params, control_channel_data, flash_onboard,
-    link_stats, user_cfg, state_volatile, system_status, autopilot_status, motor_timer, servo_timer, calibrating_accel],
-    local = [], priority = 10)]
-    /// This ISR handles interaction over the USB serial port, eg for configuring using a desktop
-    /// application. It should be a high priority, or the host may disconnect the device for not responding
-    /// quickly enough. If the priority is too low, the PC interface software will behave strangely,
-    /// so this is somewhat self-critiquing.
-    /// *It appears we need to set this to be a lower priority than IMU data, but higher than IMU TC.
-    fn usb_isr(cx: usb_isr::Context) {
-        // todo: Do we want to use an approach where we push stats, or this approach where
-        // todo respond only?
-        (
-            cx.shared.usb_dev,
-            cx.shared.usb_serial,
-            cx.shared.params,
-            cx.shared.control_channel_data,
-            cx.shared.link_stats,
-            cx.shared.user_cfg,
-            cx.shared.state_volatile,
-            cx.shared.system_status,
-            cx.shared.autopilot_status,
-            cx.shared.motor_timer,
-            cx.shared.servo_timer,
-            cx.shared.flash_onboard,
-            cx.shared.calibrating_accel,
-            // cx.shared.rpm_readings,
-        )
-            .lock(
-                |usb_dev,
-                 usb_serial,
-                 params,
-                 ch_data,
-                 link_stats,
-                 user_cfg,
-                 state,
-                 system_status,
-                 autopilot_status,
-                 motor_timer,
-                 servo_timer,
-                 flash,
-                 calibrating_accel,
-                 // rpm_readings
-                | {
-                    if !usb_dev.poll(&mut [usb_serial]) {
-                        return;
-                    }
+    shared = [usb_dev, usb_serial, params, control_channel_data, flash_onboard, flash_ext,
+    link_stats, user_cfg, state_volatile, system_status, autopilot_status, motor_timer, servo_timer, calibrating_accel,
+    imu_filters],
+    local = [msp_parser, usb_decoder], priority = 10)]
+    /// See `usb_isr::run`.
+    fn usb_otg_fs_isr(cx: usb_otg_fs_isr::Context) {
+        usb_isr::run(cx);
+    }
 
-                    let mut buf = [0u8; 60]; // todo: Adjust this A/R!!!
-                    match usb_serial.read(&mut buf) {
-                        Ok(_count) => {
-                            usb_preflight::handle_rx(
-                                usb_serial,
-                                &buf,
-                                params.attitude,
-                                &state.attitude_commanded,
-                                params.alt_msl_baro,
-                                state.pressure_static,
-                                state.temp_baro,
-                                params.alt_tof,
-                                state.batt_v,
-                                state.esc_current,
-                                ch_data,
-                                &link_stats,
-                                user_cfg,
-                                system_status,
-                                autopilot_status,
-                                &mut state.arm_status,
-                                // &mut user_cfg.control_mapping,
-                                &mut state.op_mode,
-                                motor_timer,
-                                servo_timer,
-                                &mut state.motor_servo_state,
-                                &mut state.preflight_motors_running,
-                                flash,
-                                calibrating_accel,
-                            );
-                        }
-                        Err(_) => {
-                            // println!("Error reading USB signal from PC");
-                        }
-                    }
-                },
-            )
+    #[cfg(feature = "g4")]
+    #[task(binds = USB_LP,
+    shared = [usb_dev, usb_serial, params, control_channel_data, flash_onboard, flash_ext,
+    link_stats, user_cfg, state_volatile, system_status, autopilot_status, motor_timer, servo_timer, calibrating_accel,
+    imu_filters],
+    local = [msp_parser, usb_decoder], priority = 10)]
+    /// See `usb_isr::run`.
+    fn usb_lp_isr(cx: usb_lp_isr::Context) {
+        usb_isr::run(cx);
     }
 
     #[task(binds = DMA1_STR3,
     // #[task(binds = DMA1_CH3,
-    shared = [motor_timer], priority = 6)]
+    shared = [], priority = 6)]
     /// We use this ISR to initialize the RPM reception procedures upon completion of the dshot
-    /// power setting transmission to the ESC.
-    fn dshot_isr(mut _cx: dshot_isr::Context) {
+    /// power setting transmission to the ESC. It shares no resources, so it never waits on, or
+    /// blocks, the control path.
+    fn dshot_isr(_cx: dshot_isr::Context) {
         dma::clear_interrupt(
             setup::MOTORS_DMA_PERIPH,
             setup::MOTOR_CH,
             DmaInterrupt::TransferComplete,
         );
 
-        // (From testing) We must stop this transaction manually before future transactions will work.
-        dma::stop(setup::MOTORS_DMA_PERIPH, setup::MOTOR_CH);
-
-        _cx.shared.motor_timer.lock(|motor_timer| {
-            motor_timer.disable();
-
-            if dshot::BIDIR_EN {
-                dshot::M1_RPM_I.store(0, Ordering::Release);
-                dshot::M2_RPM_I.store(0, Ordering::Release);
-                dshot::M3_RPM_I.store(0, Ordering::Release);
-                dshot::M4_RPM_I.store(0, Ordering::Release);
-
-                // Make sure to clear these buffers at reception start, not after completion; if we do it after,
-                // they will be blanked before we can process them.
-                unsafe {
-                    dshot::PAYLOAD_REC_1 = [0; dshot::REC_BUF_LEN];
-                    dshot::PAYLOAD_REC_2 = [0; dshot::REC_BUF_LEN];
-                    dshot::PAYLOAD_REC_3 = [0; dshot::REC_BUF_LEN];
-                    dshot::PAYLOAD_REC_4 = [0; dshot::REC_BUF_LEN];
-                }
-
-                dshot::receive_payload();
-            }
-        });
+        dshot::TELEM_SM.on_dma_complete();
     }
 
     #[task(binds = EXTI9_5, priority = 8)]
@@ -477,73 +547,21 @@ mod app {
     /// This interrupt fires slightly after the last bit of RPM data is received.
     /// Its timer is started once power setting is transmitted.
     /// In this ISR, we disable reception, and return the DSHOT lines to an output
-    /// state.
+    /// state. This also ends the window if no ESC replies.
     fn dshot_read_isr(mut cx: dshot_read_isr::Context) {
-        let timer = &mut cx.local.dshot_read_timer; // code shortener.
-        timer.clear_interrupt(TimerInterrupt::Update);
-        timer.disable();
-
-        // Disable interrupts on the motor pins.
-        let exti = unsafe { &(*pac::EXTI::ptr()) };
-        cfg_if! {
-            if #[cfg(feature = "h7")] {
-                exti.cpuimr1.modify(|_, w| {
-                    w.mr6().clear_bit();
-                    w.mr7().clear_bit();
-                    w.mr8().clear_bit();
-                    w.mr9().clear_bit()
-                });
-            } else {
-                exti.imr1.modify(|_, w| {
-                    w.im6().clear_bit();
-                    w.im4().clear_bit();
-                    w.im0().clear_bit();
-                    w.im1().clear_bit()
-                });
-            }
-        }
-
-        // Set motor pins back to their timer alt fn.
-        let alt_mode = 0b10;
-        unsafe {
-            cfg_if! {
-                if #[cfg(feature = "h7")] {
-                    (*pac::GPIOC::ptr())
-                        .moder
-                        .modify(|_, w| {
-                            w.moder6().bits(alt_mode);
-                            w.moder7().bits(alt_mode);
-                            w.moder8().bits(alt_mode);
-                            w.moder9().bits(alt_mode)
-                        });
-
-                } else {
-                    (*pac::GPIOC::ptr())
-                        .moder
-                        .modify(|_, w| w.moder6().bits(alt_mode));
-                    (*pac::GPIOA::ptr())
-                        .moder
-                        .modify(|_, w| w.moder4().bits(alt_mode));
-                    (*pac::GPIOB::ptr())
-                        .moder
-                        .modify(|_, w| {
-                        w.moder0().bits(alt_mode);
-                        w.moder1().bits(alt_mode)
-                    });
-                }
-            }
-        }
-        // We interpret data in the main loop; hot here.
+        dshot::TELEM_SM.on_receive_window_end(cx.local.dshot_read_timer);
+        // We interpret data in the main loop; not here.
     }
 
     // todo: Evaluate priority.
     #[task(binds = UART7,
     // #[task(binds = USART2,
-    shared = [], local = [uart_crsf], priority = 8)]
-    /// This ISR handles CRSF reception. It handles, in an alternating fashion, message starts,
-    /// and message ends. For message starts, it begins a DMA transfer. For message ends, it
-    /// processes the radio data, passing it into shared resources for control channel data,
-    /// and link stats.
+    shared = [], local = [uart_crsf, rx_protocol], priority = 8)]
+    /// This ISR handles CRSF or SBUS reception, depending on user config. For CRSF, DMA receives
+    /// continuously, and the main loop parses frames; here, we only send telemetry when the line
+    /// goes idle between received frames. For SBUS, it handles, in an alternating fashion, message
+    /// starts, and message ends. For message starts, it begins a DMA transfer. For message ends, it
+    /// flags the frame for processing in the main loop.
     ///
     /// Ideally, the only locks we have here are things used in lower-priority ISRs,
     /// like link timer etc.
@@ -552,6 +570,20 @@ mod app {
     fn crsf_isr(mut cx: crsf_isr::Context) {
         let uart = &mut cx.local.uart_crsf; // Code shortener
 
+        watchdog::mark_rx();
+
+        if *cx.local.rx_protocol == RxProtocol::Crsf {
+            uart.clear_interrupt(UsartInterrupt::Idle);
+
+            // The receiver listens for replies between the frames it sends.
+            crsf::send_pending_telemetry(uart);
+            return;
+        }
+
+        let transfer_flag = &sbus::TRANSFER_IN_PROG;
+        let new_packet_flag = &sbus::NEW_PACKET_RECEIVED;
+        let rx_buf = unsafe { &mut sbus::RX_BUFFER[..] };
+
         let start_of_message = uart.regs.isr.read().cmf().bit_is_set();
 
         uart.clear_interrupt(UsartInterrupt::CharDetect(None));
@@ -568,7 +600,7 @@ mod app {
         // todo ts
         uart.clear_interrupt(UsartInterrupt::ReadNotEmpty);
 
-        let transfer_in_prog = crsf::TRANSFER_IN_PROG.load(Ordering::Acquire);
+        let transfer_in_prog = transfer_flag.load(Ordering::Acquire);
 
         // println!("Uart status: {:?}", uart.read_status());
 
@@ -577,7 +609,7 @@ mod app {
 
         // Not sure why we need the additional message start check here.
         if transfer_in_prog == false && start_of_message {
-            crsf::TRANSFER_IN_PROG.store(true, Ordering::Release);
+            transfer_flag.store(true, Ordering::Release);
 
             // Don't allow the starting char, as used in the middle of a message,
             // to trigger an interrupt.
@@ -585,25 +617,20 @@ mod app {
 
             unsafe {
                 uart.read_dma(
-                    &mut crsf::RX_BUFFER,
+                    rx_buf,
                     setup::CRSF_RX_CH,
-                    ChannelCfg {
-                        // Take precedence over the ADC, but not motors.
-                        priority: dma::Priority::Medium,
-                        ..Default::default()
-                    },
+                    setup::dma_cfg(DmaTransfer::CrsfRx),
                     setup::CRSF_DMA_PERIPH,
                 );
             }
-            // uart.read(unsafe { &mut crsf::RX_BUFFER });
         } else if transfer_in_prog == true {
-            crsf::TRANSFER_IN_PROG.store(false, Ordering::Release);
+            transfer_flag.store(false, Ordering::Release);
             // Line is idle.
 
             // A `None` value here re-enables the interrupt without changing the char to match.
             uart.enable_interrupt(UsartInterrupt::CharDetect(None));
 
-            crsf::NEW_PACKET_RECEIVED.store(true, Ordering::Release);
+            new_packet_flag.store(true, Ordering::Release);
 
             // todo ts
             // for _ in 0..8 {
@@ -613,18 +640,35 @@ mod app {
             // }
             // uart.regs.cr3.modify(|_, w| w.dmar().clear_bit());
         } else {
-            println!("Spurious IDLE on CRSF reception");
+            println!("Spurious IDLE on RX reception");
         }
     }
 
+    #[task(binds = USART1,
+    // #[task(binds = USART3,
+    shared = [], local = [uart_esc_telem], priority = 6)]
+    /// The ESC telemetry line went idle, indicating the end of a frame. Copy the frame out for
+    /// processing in the main loop, and start the next read.
+    fn esc_telem_isr(cx: esc_telem_isr::Context) {
+        let uart = cx.local.uart_esc_telem;
+
+        uart.clear_interrupt(UsartInterrupt::Idle);
+        dma::stop(setup::ESC_TELEM_DMA_PERIPH, setup::ESC_TELEM_RX_CH);
+
+        unsafe { esc_telemetry::FRAME = esc_telemetry::RX_BUF };
+        esc_telemetry::NEW_FRAME_RECEIVED.store(true, Ordering::Release);
+
+        esc_telemetry::start_read(uart);
+    }
+
     #[task(binds = USART2,
     // #[task(binds = UART4,
-    shared = [uart_osd, state_volatile, system_status, tick_timer], local = [], priority = 2)]
+    shared = [uart_osd, state_volatile, system_status], local = [], priority = 2)]
     fn osd_rec_isr(mut cx: osd_rec_isr::Context) {
         cx.shared.uart_osd.lock(|uart| {
             uart.clear_interrupt(UsartInterrupt::CharDetect(None));
 
-            let timestamp = cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+            let timestamp = monotonic::now();
 
             cx.shared.system_status.lock(|status| {
                 status.update_timestamps.osd = Some(timestamp);
@@ -661,7 +705,7 @@ mod app {
                 uart.write_dma(
                     &osd::OSD_ARM_BUF,
                     setup::OSD_TX_CH,
-                    Default::default(),
+                    setup::dma_cfg(DmaTransfer::OsdTx),
                     setup::OSD_DMA_PERIPH,
                 )
             };
@@ -684,15 +728,103 @@ mod app {
         osd::OSD_WRITE_IN_PROGRESS.store(false, Ordering::Release);
     }
 
-    #[task(binds = TIM5, shared = [tick_timer], local = [], priority = 1)]
+    #[task(binds = DMA1_STR6,
+    // #[task(binds = DMA1_CH6,
+    shared = [], priority = 2)]
+    /// CRSF telemetry frame sent; the buffer is free for the next one.
+    fn crsf_tx_isr(_cx: crsf_tx_isr::Context) {
+        dma::clear_interrupt(
+            setup::CRSF_DMA_PERIPH,
+            setup::CRSF_TX_CH,
+            DmaInterrupt::TransferComplete,
+        );
+
+        crsf::telemetry_sent();
+    }
+
+    #[task(binds = DMA1_STR7,
+    // #[task(binds = DMA1_CH7,
+    shared = [], priority = 5)]
+    /// Battery voltage and current conversion sequence complete; publish the sample.
+    fn adc_tc_isr(_cx: adc_tc_isr::Context) {
+        adc_sampling::sequence_complete();
+    }
+
+    #[task(binds = DMA2_STR7,
+    // #[task(binds = DMA2_CH7,
+    shared = [], priority = 1)]
+    /// LED strip write complete.
+    fn led_strip_isr(_cx: led_strip_isr::Context) {
+        dma::clear_interrupt(
+            setup::LED_STRIP_DMA_PERIPH,
+            setup::LED_STRIP_CH,
+            DmaInterrupt::TransferComplete,
+        );
+
+        dma::stop(setup::LED_STRIP_DMA_PERIPH, setup::LED_STRIP_CH);
+    }
+
+    #[task(binds = TIM5, shared = [], local = [tick_timer], priority = 1)]
     /// Increments the tick overflow.
-    fn tick_isr(mut cx: tick_isr::Context) {
-        cx.shared.tick_timer.lock(|timer| {
-            // todo: Do this without locking.
-            timer.clear_interrupt(TimerInterrupt::Update);
-        });
+    fn tick_isr(cx: tick_isr::Context) {
+        monotonic::overflow(cx.local.tick_timer);
+    }
+
+    #[task(binds = TIM17,
+    // #[task(binds = TIM1_TRG_COM_TIM17,
+    shared = [system_status, state_volatile, user_cfg, motor_timer, control_channel_data,
+    link_stats],
+    local = [watchdog_timer], priority = 3)]
+    /// Checks how recently we've received data from each sensor. This runs independently of
+    /// the main loop, since that's driven by IMU data, and stops if the IMU does.
+    fn sensor_watchdog_isr(mut cx: sensor_watchdog_isr::Context) {
+        cx.local
+            .watchdog_timer
+            .clear_interrupt(TimerInterrupt::Update);
 
-        TICK_OVERFLOW_COUNT.fetch_add(1, Ordering::Relaxed);
+        let timestamp = monotonic::now();
+
+        (
+            cx.shared.system_status,
+            cx.shared.state_volatile,
+            cx.shared.user_cfg,
+            cx.shared.motor_timer,
+            cx.shared.control_channel_data,
+            cx.shared.link_stats,
+        )
+            .lock(
+                |system_status, state, cfg, motor_timer, control_channel_data, link_stats| {
+                    system_status.update_from_timestamp(timestamp);
+
+                    // Autopilot modes that depend on stale sensors are cancelled by the main loop,
+                    // in `state::update_flight_modes`.
+
+                    if system_status.imu == SensorStatus::Pass {
+                        state.imu_fail_start = None;
+                    } else if state.arm_status != safety::ArmStatus::Disarmed {
+                        // The main loop, which normally reads the receiver, isn't running.
+                        controller_interface::handle_rx_data(
+                            cfg.rx_protocol,
+                            control_channel_data,
+                            &cfg.channel_map,
+                            link_stats,
+                            system_status,
+                            timestamp,
+                        );
+
+                        safety::execute_imu_failure(
+                            cfg.imu_fail_policy,
+                            cfg.imu_fail_descend_pwr,
+                            control_channel_data.as_ref(),
+                            &mut state.imu_fail_start,
+                            timestamp,
+                            &mut state.arm_status,
+                            motor_timer,
+                        );
+                        watchdog::kick_imu_failure();
+                    }
+                },
+            );
     }
 
     #[task(binds = DMA2_STR1,
@@ -713,7 +845,7 @@ mod app {
                 baro::ADDR,
                 &mut sensors_shared::READ_BUF_BARO,
                 setup::BARO_RX_CH,
-                Default::default(),
+                setup::dma_cfg(DmaTransfer::BaroRx),
                 setup::BARO_DMA_PERIPH,
             );
         });
@@ -723,7 +855,7 @@ mod app {
 
     #[task(binds = DMA2_STR2,
     // #[task(binds = DMA2_CH2,
-    shared = [altimeter, params, state_volatile, system_status, tick_timer, imu_filters], priority = 2)]
+    shared = [altimeter, params, state_volatile, system_status, imu_filters], priority = 2)]
     /// Baro read complete; handle data, and start next write.
     fn baro_read_tc_isr(mut cx: baro_read_tc_isr::Context) {
         dma::clear_interrupt(
@@ -734,6 +866,8 @@ mod app {
 
         dma::stop(setup::BARO_DMA_PERIPH, setup::BARO_RX_CH);
 
+        sensors_shared::SEQ_BARO.store(sensors_shared::SEQ_COMPLETE, Ordering::Release);
+
         let buf = unsafe { &sensors_shared::READ_BUF_BARO };
 
         (
@@ -754,10 +888,16 @@ mod app {
 
                 let altitude = util::iir_apply(&mut filters.vv_baro, altitude_raw);
 
+                // In HIL mode, the main loop sets these from synthetic samples.
+                if hil::active() {
+                    return;
+                }
+
                 // todo: We apply a low-pass filter here, since the readings are low-resolution; otherwise
                 // VV would appear as mostly 0, with bursts of activity.
                 // todo: Linear kalman instead?
-                params.v_z_baro = (altitude - params.alt_msl_baro) / main_loop::DT_BARO;
+                params.v_z_baro = (altitude - params.alt_msl_baro)
+                    / (loop_rates::rates().dt_tasks * main_loop::BARO_RATIO as f32);
                 // println!(
                 //     "Alt: {:?}, Raw: {:?}, VZ baro: {:?}, VV IMU: {}",
                 //     altitude,
@@ -768,16 +908,168 @@ mod app {
                 params.alt_msl_baro = altitude;
             });
 
-        let timestamp = cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+        if hil::active() {
+            return;
+        }
+
+        let timestamp = monotonic::now();
 
         cx.shared.system_status.lock(|status| {
             status.update_timestamps.baro = Some(timestamp);
         });
     }
 
+    #[task(binds = DMA2_STR5,
+    // #[task(binds = DMA2_CH5,
+    shared = [i2c1, ext_sensor_active], priority = 5)]
+    /// Ext sensors write complete; start the read for the active sensor.
+    fn ext_sensors_write_tc_isr(cx: ext_sensors_write_tc_isr::Context) {
+        dma::clear_interrupt(
+            setup::EXT_SENSORS_DMA_PERIPH,
+            setup::EXT_SENSORS_TX_CH,
+            DmaInterrupt::TransferComplete,
+        );
+
+        dma::stop(setup::EXT_SENSORS_DMA_PERIPH, setup::EXT_SENSORS_TX_CH);
+
+        (cx.shared.i2c1, cx.shared.ext_sensor_active).lock(|i2c, sensor| unsafe {
+            match sensor {
+                ExtSensor::Gps => {
+                    i2c.read_dma(
+                        gps::ADDR,
+                        &mut sensors_shared::READ_BUF_GPS,
+                        setup::EXT_SENSORS_RX_CH,
+                        setup::dma_cfg(DmaTransfer::ExtSensorsRx),
+                        setup::EXT_SENSORS_DMA_PERIPH,
+                    );
+                }
+                ExtSensor::Tof => {
+                    i2c.read_dma(
+                        tof::ADDR,
+                        &mut sensors_shared::READ_BUF_TOF,
+                        setup::EXT_SENSORS_RX_CH,
+                        setup::dma_cfg(DmaTransfer::ExtSensorsRx),
+                        setup::EXT_SENSORS_DMA_PERIPH,
+                    );
+                }
+                ExtSensor::Mag => {
+                    i2c.read_dma(
+                        mag::ADDR,
+                        &mut sensors_shared::READ_BUF_MAG,
+                        setup::EXT_SENSORS_RX_CH,
+                        setup::dma_cfg(DmaTransfer::ExtSensorsRx),
+                        setup::EXT_SENSORS_DMA_PERIPH,
+                    );
+                }
+                // The TOF interrupt clear is write-only; the sequence is complete.
+                _ => {
+                    sensors_shared::SEQ_EXT.store(sensors_shared::SEQ_COMPLETE, Ordering::Release);
+                }
+            }
+        });
+    }
+
+    #[task(binds = DMA2_STR6,
+    // #[task(binds = DMA2_CH6,
+    shared = [i2c1, ext_sensor_active, fix, gps_fix, params, system_status, mag_reading],
+    local = [ubx_parser], priority = 2)]
+    /// Ext sensors read complete; handle data for the active sensor.
+    fn ext_sensors_read_tc_isr(mut cx: ext_sensors_read_tc_isr::Context) {
+        dma::clear_interrupt(
+            setup::EXT_SENSORS_DMA_PERIPH,
+            setup::EXT_SENSORS_RX_CH,
+            DmaInterrupt::TransferComplete,
+        );
+
+        dma::stop(setup::EXT_SENSORS_DMA_PERIPH, setup::EXT_SENSORS_RX_CH);
+
+        let sensor = cx.shared.ext_sensor_active.lock(|s| *s);
+        let timestamp = monotonic::now();
+
+        match sensor {
+            ExtSensor::Gps => {
+                sensors_shared::SEQ_EXT.store(sensors_shared::SEQ_COMPLETE, Ordering::Release);
+
+                // Pass the bytes read to the UBX parser, and store any fix it completes.
+                let buf = unsafe { &sensors_shared::READ_BUF_GPS };
+
+                for byte in buf {
+                    let Some(frame) = cx.local.ubx_parser.feed(*byte) else {
+                        continue;
+                    };
+                    let Some((gps_fix_, fix_)) = gps::parse_nav_pvt(&frame, timestamp) else {
+                        continue;
+                    };
+                    // In HIL mode, the main loop sets the fix from synthetic samples.
+                    if hil::active() {
+                        continue;
+                    }
+
+                    (
+                        &mut cx.shared.fix,
+                        &mut cx.shared.gps_fix,
+                        &mut cx.shared.params,
+                        &mut cx.shared.system_status,
+                    )
+                        .lock(|fix, gps_fix, params, status| {
+                            if gps_fix_.is_usable() {
+                                params.posit_fused.lat_e8 = gps_fix_.lat as i64 * 10;
+                                params.posit_fused.lon_e8 = gps_fix_.lon as i64 * 10;
+                                status.gps = SensorStatus::Pass;
+                            } else {
+                                status.gps = SensorStatus::Fault;
+                            }
+                            status.update_timestamps.gps = Some(timestamp);
+
+                            *fix = fix_;
+                            *gps_fix = gps_fix_;
+                        });
+                }
+            }
+            ExtSensor::Tof => {
+                let reading = tof::Reading::from_buf(unsafe { &sensors_shared::READ_BUF_TOF });
+
+                (&mut cx.shared.params, &mut cx.shared.system_status).lock(|params, status| {
+                    match tof::agl_from_reading(&reading, params.attitude) {
+                        Ok(agl) => {
+                            params.alt_tof = Some(agl);
+                            status.tof = SensorStatus::Pass;
+                        }
+                        Err(_) => {
+                            params.alt_tof = None;
+                            status.tof = SensorStatus::Fault;
+                        }
+                    }
+                    status.update_timestamps.tof = Some(timestamp);
+                });
+
+                // Let the sensor start its next measurement.
+                (&mut cx.shared.ext_sensor_active, &mut cx.shared.i2c1).lock(|sensor, i2c| {
+                    *sensor = ExtSensor::TofIntClear;
+                    sensors_shared::start_transfer_ext(i2c, ExtSensor::TofIntClear);
+                });
+            }
+            ExtSensor::Mag => {
+                sensors_shared::SEQ_EXT.store(sensors_shared::SEQ_COMPLETE, Ordering::Release);
+
+                let reading = mag::reading_from_buf(unsafe { &sensors_shared::READ_BUF_MAG });
+
+                // Calibration, and disturbance rejection, are applied when the main loop takes it.
+                (&mut cx.shared.mag_reading, &mut cx.shared.system_status).lock(
+                    |mag_reading, status| {
+                        *mag_reading = Some((reading, MagSource::I2c));
+                        status.magnetometer = SensorStatus::Pass;
+                        status.update_timestamps.mag = Some(timestamp);
+                    },
+                );
+            }
+            _ => (),
+        }
+    }
+
     #[task(binds = FDCAN1_IT0,
     // #[task(binds = FDCAN1_INTR0_IT,
-    shared = [can, fix], priority = 14)] // todo temp high pr
+    shared = [can, fix, mag_reading], priority = 14)] // todo temp high pr
     /// Ext sensors write complete; start read of the next sensor in sequence.
     fn can_isr(cx: can_isr::Context) {
         can_reception::run(cx);
@@ -10,45 +10,75 @@ use num_traits::Float;
 use rtic::mutex_prelude::*;
 
 use crate::{
-    app, controller_interface,
+    adc_cal::AdcReadings,
+    adc_sampling, app,
+    blackbox::LogRecord,
+    board_config, brownout,
+    controller_interface::{self, RxProtocol},
     drivers::osd::{AutopilotData, OsdData},
-    flight_ctrls::{self, cmd_updates, ctrl_logic, motor_servo::MotorServoState, InputMode},
-    imu_shared, osd,
-    protocols::{crsf, rpm_reception},
-    safety::{self, ArmStatus},
-    sensors_shared::{self, V_A_ADC_READ_BUF},
-    state::OperationMode,
+    event_log::{self, EventCode},
+    flight_ctrls::{
+        self, cmd_updates,
+        common::{RpmLpf, RpmLpfCfg},
+        control_mapping::ControlMapping,
+        ctrl_logic,
+        input_cal::InputCalResult,
+        motor_servo::MotorServoState,
+        motor_test::MotorTestOutput,
+        stick_cmds::StickAction,
+        InputMode,
+    },
+    hil::{self, HilOutput},
+    imu_processing::{
+        dual_imu::ImuId, fusion::AttConvergence, gyro_temp_comp::TempCalResult, imu_integrity,
+    },
+    imu_shared,
+    link_failsafe::LinkStage,
+    loop_rates, motor_kill, osd,
+    output_pattern::PatternOutput,
+    perf_stats,
+    protocols::{
+        crsf, dshot,
+        esc_telemetry::{self, BattMeasSource},
+        rpm_reception,
+        usb_telem::TelemSnapshot,
+    },
+    safety::{self, ArmStatus, PrearmStatus},
+    sensors_shared::{self, ExtSensor, MagSource},
+    state::{self, OperationMode},
     system_status::{self, SensorStatus, SystemStatus},
-    util,
+    util::{self, monotonic},
 };
 
-const UPDATE_RATE_IMU: f32 = 8_192.; // From measuring.
-pub const DT_IMU: f32 = 1. / UPDATE_RATE_IMU;
-pub const BARO_RATIO: u32 = 42;
-pub const DT_BARO: f32 = DT_IMU * NUM_IMU_LOOP_TASKS as f32 * BARO_RATIO as f32;
+#[cfg(feature = "print-status")]
+use crate::debug_log;
+#[cfg(feature = "quad")]
+use crate::flight_ctrls::reversible;
+#[cfg(feature = "fixed-wing")]
+use crate::output_pattern;
 
-pub const DT_FLIGHT_CTRLS: f32 = 1. / UPDATE_RATE_FLIGHT_CTRLS;
+// IMU and flight control rates are set from user config; see `loop_rates`. The ratios below are
+// in IMU updates, so the rates they produce scale with the IMU rate.
+pub const BARO_RATIO: u32 = 42;
+// ~65Hz. We alternate between the GPS and TOF sensor (if connected), so each gets ~32Hz.
+// Each GPS read is `gps_ublox::READ_BUF_SIZE` bytes, so this comfortably keeps up with
+// NAV-PVT at 10Hz.
+const EXT_SENSORS_RATIO: u32 = 21;
 
 // Every x main update loops, log parameters etc to flash.
 const LOGGING_UPDATE_RATIO: u32 = 100;
 
-// Every x flight ctrl loops, print system status and sensor readings to console,
-// if enabled with the `print-status` feature.
-const PRINT_STATUS_RATIO: u32 = 16_000;
-
 // Every x main loops, log RPM (or servo posit) to angular accel (thrust) data.
 const THRUST_LOG_RATIO: u32 = 20;
 
-#[cfg(feature = "quad")]
-pub const FLIGHT_CTRL_IMU_RATIO: u32 = 4; // Likely values: 1, 2, 4, 8.
+// ~10Hz. We cycle through CRSF telemetry frame types, so each is sent at ~2.5Hz.
+const CRSF_TELEM_RATIO: u32 = 137;
 
-#[cfg(feature = "fixed-wing")]
-pub const FLIGHT_CTRL_IMU_RATIO: u32 = 8; // Likely values: 4, 8, 16.
+// ~1Hz. The MCU temperature changes slowly.
+const MCU_TEMP_RATIO: u32 = 1_300;
 
 use defmt::println;
 
-const UPDATE_RATE_FLIGHT_CTRLS: f32 = UPDATE_RATE_IMU / FLIGHT_CTRL_IMU_RATIO as f32;
-
 pub const NUM_IMU_LOOP_TASKS: u32 = 6; // We cycle through lower-priority tasks in the main loop.
 
 // We run into numerical precision issues if diffing attitude commanded
@@ -75,22 +105,103 @@ pub struct TaskDurations {
     pub flight_ctrl_interval: f32, // seconds
 }
 
+/// Detect a failed motor, and enter degraded control. This lasts until disarm. See
+/// `motor_failure`.
+#[cfg(feature = "quad")]
+fn handle_motor_failure(state: &mut state::StateVolatile, enabled: bool, dt: f32) {
+    let armed = state.arm_status == ArmStatus::Armed;
+    if !armed {
+        state.motor_failed = None;
+    }
+
+    if !enabled || !dshot::BIDIR_EN || state.motor_failed.is_some() {
+        return;
+    }
+
+    if let Some(rotor) = state.motor_failure.update(
+        &state.motor_servo_state.rotor_powers(),
+        &state.motor_servo_state.rotor_rpms(),
+        armed,
+        state.has_taken_off,
+        dt,
+    ) {
+        println!("Motor failure detected. Rotor index: {}", rotor as u8);
+        event_log::log(EventCode::MotorFailure, rotor as u16, 0);
+
+        state.motor_failed = Some(rotor);
+    }
+}
+
+/// Check motors just after arming, and disarm if one fails. See `arm_check`.
+#[cfg(feature = "quad")]
+fn handle_arm_check(
+    state: &mut state::StateVolatile,
+    v_yaw: f32,
+    cfg: &state::UserConfig,
+    dt: f32,
+) {
+    // Without bidirectional DSHOT, there's no RPM telemetry to check.
+    if !dshot::BIDIR_EN {
+        return;
+    }
+
+    if let Some((cause, rotor)) = state.arm_check.update(
+        state.arm_status == ArmStatus::Armed,
+        dshot::reversible(),
+        v_yaw,
+        &state.motor_servo_state.rotor_rpms(),
+        &cfg.mixer,
+        state.motor_servo_state.frontleft_aftright_dir,
+        &cfg.arm_check,
+        dt,
+    ) {
+        println!(
+            "Motor failed the arm check; disarming. Rotor index: {}, cause: {}",
+            rotor, cause as u8
+        );
+        event_log::log(EventCode::ArmCheck, cause as u16, rotor);
+
+        state.arm_status = ArmStatus::Disarmed;
+        event_log::log(EventCode::ArmStatus, state.arm_status as u16, 0);
+    }
+}
+
+/// Decode RPM readings from the bidirectional DSHOT receive buffers. Run this once per flight
+/// control update. The buffers are cleared at the start of each receive window, so a motor without
+/// edges captured this update has no reading.
 fn handle_rpm_readings(
     motor_servo_state: &mut MotorServoState,
+    rpm_lpf: &mut RpmLpf,
     system_status: &mut SystemStatus,
     motor_pole_count: u8,
+    control_mapping: &ControlMapping,
+    rpm_lpf_cfg: &RpmLpfCfg,
+    timestamp: f32,
+    dt: f32,
 ) {
-    let mut rpm_fault = false;
+    // Only decode complete receive windows; otherwise, we may read buffers mid-reception.
+    if !dshot::TELEM_SM.take_window() {
+        return;
+    }
 
-    // todo: Clean up the Optionalble Status vs the non-optioned Rpms.
-    // todo: Consider using only the former.
+    let mut rpm_fault = false;
 
     // Update RPMs here, so we don't have to lock the read ISR.
-    // cx.shared.rotor_rpms.lock(|rotor_rpms| {
-    // let (rpm1_status, rpm2_status, rpm3_status, rpm4_status) = rpm_reception::update_rpms(rpms, &mut rpm_fault, cfg.pole_count);
-    let rpm_readings = rpm_reception::rpm_readings_from_bufs(&mut rpm_fault, motor_pole_count);
+    let rpm_readings =
+        rpm_reception::rpm_readings_from_bufs(&mut rpm_fault, motor_pole_count, control_mapping);
+
+    let desync = motor_servo_state.update_rpm_readings(&rpm_readings, timestamp, dt);
 
-    motor_servo_state.update_rpm_readings(&rpm_readings);
+    // Motors without a reading this window are skipped, and flagged stale if this persists.
+    rpm_lpf.update(&motor_servo_state.rotor_rpms(), rpm_lpf_cfg, timestamp);
+
+    for (i, desynced) in desync.iter().enumerate() {
+        if *desynced && !system_status.esc_desync[i] {
+            println!("ESC desync detected. Rotor index: {}", i);
+            event_log::log(EventCode::EscDesync, i as u16, 0);
+        }
+    }
+    system_status.esc_desync = desync;
 
     system_status.esc_rpm = SensorStatus::Pass;
 
@@ -108,22 +219,32 @@ fn handle_rpm_readings(
 
     #[cfg(feature = "fixed-wing")]
     {
-        if rpm_readings.motor_thrust1.is_none() {
+        if rpm_readings.thrust1.is_none() {
             // todo: Motor 2?
             system_status.esc_rpm = SensorStatus::NotConnected;
         }
     }
 
-    if rpm_fault {
-        system_status::RPM_FAULT.store(true, Ordering::Release);
+    // Log only the first fault; these may occur every update.
+    if rpm_fault && !system_status::RPM_FAULT.swap(true, Ordering::AcqRel) {
+        event_log::log(EventCode::RpmFault, 0, 0);
     }
 }
 
 pub fn run(mut cx: app::imu_tc_isr::Context) {
+    let isr_latency = perf_stats::imu_ready_latency();
+
     *cx.local.imu_isr_loop_i += 1;
     let i = *cx.local.imu_isr_loop_i; // code shortener.
 
-    let timestamp = cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+    let rates = loop_rates::rates();
+
+    let uptime_us = monotonic::now_us();
+    let timestamp = monotonic::to_secs(uptime_us);
+
+    // Debug output due this loop, if any. See `debug_log`.
+    #[cfg(feature = "print-status")]
+    let mut log_snapshot = None;
 
     (
         cx.shared.params,
@@ -133,8 +254,8 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
         // cx.shared.motor_pid_state,
         // cx.shared.motor_pid_coeffs,
         cx.shared.user_cfg,
-        cx.shared.state_volatile,
-        cx.shared.system_status,
+        &mut cx.shared.state_volatile,
+        &mut cx.shared.system_status,
     )
         .lock(
             |params,
@@ -149,14 +270,245 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                     timestamp - system_status.update_timestamps.imu.unwrap_or(0.);
                 system_status.update_timestamps.imu = Some(timestamp);
 
+                state.watchdog.kick(
+                    state.arm_status != ArmStatus::Disarmed,
+                    system_status.rf_control_link == SensorStatus::Pass,
+                    timestamp,
+                );
+
+                let fullscale = imu_shared::fullscale();
                 let mut imu_data = ImuReadings::from_buffer(
+                    imu_shared::accel_gyro_buf(unsafe { &imu_shared::IMU_READINGS }),
+                    fullscale.accel,
+                    fullscale.gyro,
+                );
+
+                state.imu_temp = imu_shared::temp_from_buffer(unsafe { &imu_shared::IMU_READINGS });
+
+                state.imu_integrity.check_readings(
                     unsafe { &imu_shared::IMU_READINGS },
-                    imu_shared::ACCEL_FULLSCALE,
-                    imu_shared::GYRO_FULLSCALE,
+                    &imu_data,
+                    state.arm_status == ArmStatus::Disarmed,
+                    system_status,
+                );
+
+                // With a secondary IMU, its readings may replace the primary's. This runs before
+                // re-initializing the primary, so a failed integrity check is seen here.
+                let imu_failed_over = state.dual_imu.update(
+                    &imu_data,
+                    !state.imu_integrity.reinit_pending(),
+                    &cfg.dual_imu,
+                    state.arm_status == ArmStatus::Disarmed,
+                    system_status,
+                    rates.dt_imu,
+                );
+                state.dual_imu.apply(&mut imu_data, &mut state.imu_temp);
+
+                if imu_failed_over {
+                    state.pid_state_rate.reset_i();
+                }
+
+                if i % rates.imu_updates(imu_integrity::VERIFY_INTERVAL) == 0
+                    || state.imu_integrity.reinit_pending()
+                {
+                    // Full-scale and filter changes from a config save apply on the ground only.
+                    let cfg_changeable = state.op_mode == OperationMode::Preflight
+                        && state.arm_status == ArmStatus::Disarmed;
+
+                    cx.shared.spi1.lock(|spi1| {
+                        if cfg_changeable {
+                            state.imu_integrity.apply_cfg(
+                                &cfg.imu_cfg,
+                                spi1,
+                                cx.local.cs_imu,
+                                system_status,
+                            );
+                        }
+                        state
+                            .imu_integrity
+                            .verify(spi1, cx.local.cs_imu, system_status);
+                    });
+                }
+
+                // The calibration uses readings prior to temperature compensation.
+                if let TempCalResult::Success(comp) =
+                    state.gyro_temp_cal.update(&imu_data, state.imu_temp)
+                {
+                    cfg.gyro_temp_comp = comp;
+
+                    println!(
+                        "Gyro temp cal complete. Ref temp: {}. Slopes: p{} r{} y{}",
+                        comp.ref_temp, comp.slope.0, comp.slope.1, comp.slope.2
+                    );
+
+                    cx.shared.flash_onboard.lock(|flash| {
+                        cfg.save(flash);
+                    });
+                }
+
+                if let InputCalResult::Success(cal) = state
+                    .input_cal_collector
+                    .update(control_channel_data.as_ref(), timestamp)
+                {
+                    cfg.input_map.cal = cal;
+
+                    println!(
+                        "Input cal complete. Throttle: {} - {}",
+                        cal.throttle.min, cal.throttle.max
+                    );
+
+                    cx.shared.flash_onboard.lock(|flash| {
+                        cfg.save(flash);
+                    });
+                }
+
+                let link_ok = system_status.rf_control_link == SensorStatus::Pass;
+
+                match state.stick_cmds.update(
+                    control_channel_data.as_ref().filter(|_| link_ok),
+                    state.arm_status,
+                    params.attitude.rotate_vec(ahrs::UP).dot(ahrs::UP).acos(),
+                    timestamp,
+                ) {
+                    Some(StickAction::AccelCal) => {
+                        cx.shared.calibrating_accel.lock(|c| *c = true);
+                    }
+                    Some(StickAction::SaveConfig) => {
+                        cx.shared.flash_onboard.lock(|flash| {
+                            cfg.save(flash);
+                        });
+                    }
+                    // Handled in `TurtleMode::update`.
+                    Some(StickAction::Turtle) | None => (),
+                }
+
+                // Save the hover throttle estimate after each flight, so the next starts with it.
+                if state.arm_status == ArmStatus::Disarmed {
+                    if let Some(hover_throttle) = state.hover_throttle_est.take_learned() {
+                        cfg.hover_throttle = hover_throttle;
+
+                        println!("Saving hover throttle estimate: {}", hover_throttle);
+
+                        cx.shared.flash_onboard.lock(|flash| {
+                            cfg.save(flash);
+                        });
+                    }
+                }
+
+                // The compensation is calibrated for the ICM.
+                if state.dual_imu.active == ImuId::Icm426xx {
+                    cfg.gyro_temp_comp.apply(&mut imu_data, state.imu_temp);
+                }
+
+                // In HIL mode, synthetic readings from the PC replace the sensors'. The IMU
+                // readings hold between samples; baro and GPS update on each new one.
+                if let Some((sample, new)) = hil::take_sample() {
+                    (imu_data.v_pitch, imu_data.v_roll, imu_data.v_yaw) = sample.gyro;
+                    (imu_data.a_x, imu_data.a_y, imu_data.a_z) = sample.accel;
+
+                    if new {
+                        params.alt_msl_baro = sample.alt_baro;
+                        params.v_z_baro = sample.v_z_baro;
+                        system_status.update_timestamps.baro = Some(timestamp);
+
+                        match sample.gps {
+                            Some(mut gps_fix) => {
+                                gps_fix.timestamp = timestamp;
+                                params.posit_fused.lat_e8 = gps_fix.lat as i64 * 10;
+                                params.posit_fused.lon_e8 = gps_fix.lon as i64 * 10;
+                                system_status.gps = SensorStatus::Pass;
+
+                                cx.shared.gps_fix.lock(|fix| *fix = gps_fix);
+                            }
+                            None => system_status.gps = SensorStatus::Fault,
+                        }
+                        system_status.update_timestamps.gps = Some(timestamp);
+                    }
+                }
+
+                state
+                    .self_test
+                    .update((imu_data.v_pitch, imu_data.v_roll, imu_data.v_yaw));
+
+                state.vib_test.update(
+                    &mut state.motor_test,
+                    (imu_data.v_pitch, imu_data.v_roll, imu_data.v_yaw),
+                    timestamp,
                 );
 
+                if state.self_test.ready_to_run() {
+                    // Use the ADC readings directly, since `batt_v` and `esc_current` may be from
+                    // ESC telemetry.
+                    let batt_v = state.adc_readings.batt_v;
+                    let current = state.adc_readings.current;
+
+                    // From whichever magnetometer reported most recently.
+                    let ts = &system_status.update_timestamps;
+                    let mag_age = [ts.mag, ts.mag_can]
+                        .into_iter()
+                        .flatten()
+                        .map(|t| timestamp - t)
+                        .reduce(f32::min);
+
+                    (
+                        &mut cx.shared.spi1,
+                        &mut cx.shared.i2c1,
+                        &mut cx.shared.i2c2,
+                        &mut cx.shared.altimeter,
+                    )
+                        .lock(|spi1, i2c1, i2c2, altimeter| {
+                            state.self_test.run(
+                                spi1,
+                                cx.local.cs_imu,
+                                i2c1,
+                                i2c2,
+                                altimeter,
+                                &state.motor_servo_state.rotor_rpms(),
+                                batt_v,
+                                current,
+                                cfg.batt_cell_count.num_cells(),
+                                mag_age,
+                                system_status,
+                            );
+                        });
+
+                    println!(
+                        "Self-test complete. Fault: {}",
+                        system_status.self_test_fault
+                    );
+                }
+
+                // Only pass mag data to the AHRS when there's a new reading, and it's not
+                // contaminated by a local magnetic disturbance.
+                let mut mag_data = None;
+                if let Some((mag_raw, source)) = cx.shared.mag_reading.lock(|m| m.take()) {
+                    // The I2C magnetometer's is set when its read completes.
+                    if source == MagSource::Can {
+                        system_status.update_timestamps.mag_can = Some(timestamp);
+                    }
+
+                    if state.mag_cal_collector.active {
+                        state.mag_cal_collector.log(mag_raw);
+                    } else {
+                        let mag = cfg.mag_cal.apply(mag_raw);
+
+                        if !cfg.mag_cal.is_disturbed(mag, cfg.fusion.mag_reject) {
+                            mag_data = Some(mag);
+                        }
+                    }
+
+                    system_status.mag_applied = mag_data.is_some();
+                }
+
                 cx.shared.imu_filters.lock(|imu_filters| {
-                    imu_filters.apply(&mut imu_data);
+                    // Don't pass the step between IMUs through the filters.
+                    if imu_failed_over {
+                        imu_filters.settle(&imu_data);
+                    }
+                    imu_filters.update_gyro_lpfs(&cfg.gyro_lpf);
+                    imu_filters
+                        .update_rpm_notches(&state.motor_servo_state.rotor_rpms(), &cfg.rpm_filter);
+                    imu_filters.apply(&mut imu_data, &cfg.rpm_filter);
                 });
 
                 // Update `params_prev` with past-update data prior to updating params
@@ -164,9 +516,52 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                 *cx.local.params_prev = params.clone();
 
                 cx.shared.ahrs.lock(|ahrs| {
+                    if state.arm_status == ArmStatus::Disarmed {
+                        state.fusion_monitor.handle_reset(ahrs, rates.dt_imu);
+                    }
+
+                    state.accel_health.update(
+                        &imu_data,
+                        ahrs.cal.acc_len_at_rest,
+                        &cfg.accel_health,
+                        system_status,
+                        rates.dt_imu,
+                    );
+
+                    // If the accelerometer is unhealthy, the AHRS gets a substitute reading, so
+                    // it propagates from the gyro only. This also applies to the linear
+                    // acceleration in `params`. Restore the measured reading after, for
+                    // calibration and logging.
+                    let accel_measured = (imu_data.a_x, imu_data.a_y, imu_data.a_z);
+                    (imu_data.a_x, imu_data.a_y, imu_data.a_z) =
+                        state.accel_health.accel_for_fusion(
+                            accel_measured,
+                            params.attitude,
+                            ahrs.cal.acc_len_at_rest,
+                        );
+                    (imu_data.a_x, imu_data.a_y, imu_data.a_z) =
+                        state.fusion_monitor.accel_for_fusion(
+                            (imu_data.a_x, imu_data.a_y, imu_data.a_z),
+                            params.attitude,
+                            ahrs.cal.acc_len_at_rest,
+                            &cfg.fusion,
+                            rates.dt_imu,
+                        );
+
                     // todo: We probably don't need to update AHRS each IMU update, but that's what
                     // todo we're currently doing, since that's updated in `update_from_imu_readings`.
-                    params.update_from_imu_readings(&imu_data, None, ahrs);
+                    params.update_from_imu_readings(&imu_data, mag_data, ahrs);
+
+                    (imu_data.a_x, imu_data.a_y, imu_data.a_z) = accel_measured;
+
+                    state.fusion_monitor.update(
+                        accel_measured,
+                        params.attitude,
+                        ahrs.cal.acc_len_at_rest,
+                        state.accel_health.mode,
+                        rates.dt_imu,
+                    );
+                    system_status.att_convergence = state.fusion_monitor.convergence;
 
                     // todo: Find a home for this.
                     // todo: Linear acc from AHRS would be ideal, but it seems to be coming out wrong here.
@@ -192,7 +587,18 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                         }
                     }
                     // return;
-                    unsafe { crate::VV_IMU += acc_up * DT_IMU };
+                    unsafe { crate::VV_IMU += acc_up * rates.dt_imu };
+
+                    #[cfg(feature = "quad")]
+                    state.hover_throttle_est.update(
+                        state.attitude_commanded.throttle,
+                        acc_up,
+                        params.v_z_baro,
+                        state.arm_status == ArmStatus::Armed
+                            && state.has_taken_off
+                            && state.motor_failed.is_none(),
+                        rates.dt_imu,
+                    );
                 });
 
                 // todo: Delegate to a fn!
@@ -244,12 +650,6 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                     }
                 });
 
-                // handle_rpm_readings(
-                //     &mut state.motor_servo_state,
-                //     system_status,
-                //     cfg.motor_pole_count,
-                // );
-
                 // todo: Impl once you've sorted out your control logic.
                 // todo: Delegate this to another module, eg `attitude_ctrls`.
                 // Update the target attitude based on control inputs
@@ -265,23 +665,89 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
 
                 // Loads channel data and link stats into our shared structures,
                 // from the DMA buffer.
-                if !crsf::TRANSFER_IN_PROG.load(Ordering::Acquire)
-                    && crsf::NEW_PACKET_RECEIVED.load(Ordering::Acquire)
-                {
-                    controller_interface::handle_crsf_data(
-                        control_channel_data,
-                        link_stats,
-                        system_status,
-                        timestamp,
-                    );
+                controller_interface::handle_rx_data(
+                    cfg.rx_protocol,
+                    control_channel_data,
+                    &cfg.channel_map,
+                    link_stats,
+                    system_status,
+                    timestamp,
+                );
+
+                // Each IMU update, so the kill applies to the next motor output. Without channel
+                // data, the latch holds.
+                let (kill_switch, kill_throttle) = match control_channel_data.as_ref() {
+                    Some(ch_data) => (ch_data.kill, ch_data.throttle),
+                    None => (false, 1.),
+                };
+                #[cfg(feature = "quad")]
+                let kill_throttle = reversible::arm_throttle(kill_throttle, dshot::reversible());
+
+                if motor_kill::update(
+                    kill_switch,
+                    kill_throttle < safety::THROTTLE_MAX_TO_ARM,
+                    state.arm_status as u8,
+                ) {
+                    state.arm_status = ArmStatus::Disarmed;
+                    state.preflight_motors_running = false;
+                    state.motor_test.cancel();
+
+                    cx.shared
+                        .motor_timer
+                        .lock(|motor_timer| dshot::stop_all(motor_timer));
                 }
 
-                let timestamp_imu_complete =
-                    cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                let timestamp_imu_complete = monotonic::now();
 
                 cx.local.task_durations.imu = timestamp_imu_complete - timestamp;
 
-                if i % FLIGHT_CTRL_IMU_RATIO == 0 {
+                if i % rates.flight_ctrl_imu_ratio == 0 {
+                    if dshot::BIDIR_EN {
+                        handle_rpm_readings(
+                            &mut state.motor_servo_state,
+                            &mut state.rpm_lpf,
+                            system_status,
+                            cfg.motor_pole_count,
+                            &cfg.control_mapping,
+                            &cfg.rpm_lpf,
+                            timestamp,
+                            rates.dt_flight_ctrls,
+                        );
+                    }
+
+                    #[cfg(feature = "quad")]
+                    handle_motor_failure(state, cfg.motor_fail.enabled, rates.dt_flight_ctrls);
+
+                    #[cfg(feature = "quad")]
+                    handle_arm_check(state, params.v_yaw, cfg, rates.dt_flight_ctrls);
+
+                    #[cfg(feature = "quad")]
+                    state.acro_trainer.update(
+                        state.input_mode == InputMode::Acro
+                            && control_channel_data
+                                .as_ref()
+                                .map_or(false, |ch| ch.acro_trainer),
+                        params,
+                        state.has_taken_off,
+                        &cfg.acro_trainer,
+                    );
+
+                    // Sets the control link's status.
+                    state.link_failsafe.update(
+                        system_status,
+                        &state.attitude_commanded,
+                        &cfg.link_failsafe,
+                        timestamp,
+                    );
+
+                    state.ext_ctrl.update(
+                        control_channel_data
+                            .as_ref()
+                            .map_or(false, |ch| ch.ext_ctrl),
+                        state.link_failsafe.stage == LinkStage::Ok,
+                        timestamp,
+                    );
+
                     // Update our commanded attitude
                     match control_channel_data {
                         Some(ch_data) => {
@@ -298,7 +764,20 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                                         params.attitude,
                                         state.has_taken_off,
                                         cfg.takeoff_attitude,
+                                        state.acro_trainer.correction,
                                     ),
+                                    InputMode::AcroHybrid => {
+                                        cmd_updates::update_att_commanded_acro_hybrid(
+                                            ch_data,
+                                            &cfg.input_map,
+                                            state.attitude_commanded.quat,
+                                            params.attitude,
+                                            state.has_taken_off,
+                                            cfg.takeoff_attitude,
+                                            &cfg.angle_on_center,
+                                            cfg.max_angle,
+                                        )
+                                    }
                                     InputMode::Attitude => {
                                         cmd_updates::update_att_commanded_att_mode(
                                             ch_data,
@@ -313,13 +792,22 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                                     InputMode::Route => (Quaternion::new_identity(), (0., 0., 0.)),
                                 };
 
+                                // Limit tilt in self-leveled modes, when the battery is low.
+                                let attitude_commanded =
+                                    match state.batt_failsafe.max_tilt(&cfg.batt_failsafe) {
+                                        Some(max_tilt) if state.input_mode != InputMode::Acro => {
+                                            ctrl_logic::limit_tilt(attitude_commanded, max_tilt)
+                                        }
+                                        _ => attitude_commanded,
+                                    };
+
                                 state.attitude_commanded.quat = attitude_commanded;
                                 state.attitude_commanded.quat_dt = attitude_commanded_dt;
                             }
 
                             // Set altitude commanded if applicable based on flight mode, and set the throttle.
                             let throttle = match state.input_mode {
-                                InputMode::Acro => ch_data.throttle,
+                                InputMode::Acro | InputMode::AcroHybrid => ch_data.throttle,
                                 InputMode::Attitude => {
                                     // todo: Delegate to a diff fn A/R.
                                     let (alt, vv) = cmd_updates::update_alt_baro_commanded(
@@ -358,7 +846,59 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                                 }
                                 InputMode::Route => 0.,
                             };
-                            state.attitude_commanded.throttle = throttle;
+
+                            // Replaces the above while external control is active. Limits
+                            // below still apply.
+                            let throttle = state.ext_ctrl.apply(
+                                &mut state.attitude_commanded,
+                                throttle,
+                                state.batt_failsafe.max_tilt(&cfg.batt_failsafe),
+                            );
+
+                            // External throttle is limited as the stick's is.
+                            let autopilot_throttle = !state.ext_ctrl.active
+                                && !matches!(
+                                    state.input_mode,
+                                    InputMode::Acro | InputMode::AcroHybrid
+                                );
+                            let throttle = cfg.throttle_limit.apply(
+                                throttle,
+                                ch_data.beginner,
+                                autopilot_throttle,
+                            );
+                            state.attitude_commanded.throttle = state
+                                .batt_failsafe
+                                .limit_throttle(throttle, &cfg.batt_failsafe);
+
+                            // Replaces the above while channel data is missing.
+                            state
+                                .link_failsafe
+                                .apply(&mut state.attitude_commanded, &cfg.link_failsafe);
+
+                            #[cfg(feature = "fixed-wing")]
+                            state.auto_launch.apply(
+                                &mut state.attitude_commanded,
+                                ch_data,
+                                &cfg.auto_launch,
+                            );
+
+                            state.ground_ref.limit_ceiling(
+                                &mut state.attitude_commanded,
+                                state.alt_est.estimate.above_takeoff,
+                                cfg.ceiling,
+                                state.hover_throttle_est.throttle,
+                            );
+
+                            #[cfg(feature = "fixed-wing")]
+                            state.stall_protect.apply(
+                                &mut state.attitude_commanded,
+                                params,
+                                state.airspeed_est.airspeed,
+                                state.arm_status,
+                                state.has_taken_off,
+                                &cfg.airspeed_cfg,
+                                &cfg.stall_protect,
+                            );
                         }
                         None => {}
                     }
@@ -367,21 +907,147 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                         // todo: Figure out where this preflight motor-spin up code should be in this ISR.
                         // todo: Here should be fine, but maybe somewhere else is better.
                         cx.shared.motor_timer.lock(|motor_timer| {
+                            #[cfg(feature = "quad")]
+                            if state.turtle.active() {
+                                state
+                                    .turtle
+                                    .exit(&cfg.control_mapping, motor_timer, timestamp);
+                            }
+
                             if state.preflight_motors_running {
                                 // todo: Use actual arm status!!
 
-                                state
-                                    .motor_servo_state
-                                    .send_to_rotors(ArmStatus::Armed, motor_timer);
+                                state.motor_servo_state.send_to_rotors(
+                                    ArmStatus::Armed,
+                                    &cfg.control_mapping,
+                                    &cfg.output_smoothing,
+                                    motor_timer,
+                                );
+                            } else if state.arm_status != ArmStatus::Disarmed {
+                                // Motor tests are only allowed while disarmed.
+                                state.motor_wizard.cancel();
+                                if state.motor_test.motor_active().is_some()
+                                    || state.output_pattern.in_progress()
+                                {
+                                    state.motor_test.cancel();
+                                    state.output_pattern.cancel();
+                                    dshot::stop_all(motor_timer);
+                                }
                             } else {
+                                if let Some(mapping) = state.motor_wizard.update(
+                                    &mut state.motor_test,
+                                    &cfg.control_mapping,
+                                    cfg.motor_pole_count,
+                                    timestamp,
+                                ) {
+                                    let reversed_changed = mapping.motors_reversed()
+                                        != cfg.control_mapping.motors_reversed();
+
+                                    mapping.apply(&mut state.motor_servo_state);
+
+                                    if reversed_changed {
+                                        dshot::setup_motor_dir(
+                                            mapping.motors_reversed(),
+                                            true,
+                                            motor_timer,
+                                        );
+                                    }
+
+                                    cfg.control_mapping = mapping;
+                                    cx.shared.flash_onboard.lock(|flash| cfg.save(flash));
+                                }
+
+                                match state.motor_test.update(timestamp) {
+                                    MotorTestOutput::Start(motor, power) => {
+                                        dshot::stop_all(motor_timer);
+                                        dshot::set_power_single(motor, power, motor_timer);
+                                    }
+                                    MotorTestOutput::Spin(motor, power) => {
+                                        state.motor_test.rpm = rpm_reception::rpm_from_motor(
+                                            motor,
+                                            cfg.motor_pole_count,
+                                        );
+                                        dshot::set_power_single(motor, power, motor_timer);
+                                    }
+                                    MotorTestOutput::Stop => dshot::stop_all(motor_timer),
+                                    MotorTestOutput::Idle => (),
+                                }
+
+                                match state
+                                    .output_pattern
+                                    .update(control_channel_data.as_ref(), timestamp)
+                                {
+                                    PatternOutput::Motors(p) => {
+                                        dshot::set_power(p[0], p[1], p[2], p[3], motor_timer)
+                                    }
+                                    #[cfg(feature = "fixed-wing")]
+                                    PatternOutput::Servos(posits) => {
+                                        cx.shared.servo_timer.lock(|servo_timer| {
+                                            output_pattern::set_servos(
+                                                posits,
+                                                &cfg.control_mapping,
+                                                &cfg.servo_cfg,
+                                                servo_timer,
+                                            )
+                                        });
+                                    }
+                                    // Servo patterns are refused on quads.
+                                    #[cfg(feature = "quad")]
+                                    PatternOutput::Servos(_) => (),
+                                    PatternOutput::Stop => {
+                                        dshot::stop_all(motor_timer);
+
+                                        #[cfg(feature = "fixed-wing")]
+                                        cx.shared.servo_timer.lock(|servo_timer| {
+                                            output_pattern::set_servos(
+                                                [0.; output_pattern::NUM_SERVOS],
+                                                &cfg.control_mapping,
+                                                &cfg.servo_cfg,
+                                                servo_timer,
+                                            )
+                                        });
+                                    }
+                                    PatternOutput::Idle => (),
+                                }
                                 // todo: Does this interfere with USB reads?
                                 // todo: Experiment and reason this out, if you should do this.
                                 // dshot::stop_all(motor_timer);
                             }
                         });
                     } else {
-                        (cx.shared.flight_ctrl_filters, cx.shared.motor_timer).lock(
-                            |flight_ctrl_filters, motor_timer| {
+                        // Don't resume a motor test if we return to Preflight.
+                        state.motor_test.cancel();
+                        state.motor_wizard.cancel();
+                        state.output_pattern.cancel();
+
+                        // Turtle mode sends its own motor commands while active.
+                        #[cfg(feature = "quad")]
+                        let turtle_active = {
+                            let link_ok = system_status.rf_control_link == SensorStatus::Pass;
+
+                            cx.shared.motor_timer.lock(|motor_timer| {
+                                state.turtle.update(
+                                    control_channel_data.as_ref().filter(|_| link_ok),
+                                    state.stick_cmds.turtle,
+                                    state.arm_status,
+                                    params,
+                                    &state.motor_servo_state.rotor_rpms(),
+                                    &cfg.control_mapping,
+                                    motor_timer,
+                                    timestamp,
+                                )
+                            })
+                        };
+                        #[cfg(feature = "fixed-wing")]
+                        let turtle_active = false;
+
+                        if !turtle_active {
+                            cx.shared.flight_ctrl_filters.lock(|flight_ctrl_filters| {
+                                state
+                                    .profile_switch
+                                    .reset_filters_if_pending(flight_ctrl_filters);
+                                state.dual_imu.reset_filters_if_pending(flight_ctrl_filters);
+
                                 flight_ctrls::run(
                                     params,
                                     cx.local.params_prev,
@@ -389,29 +1055,162 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                                     control_channel_data,
                                     &cfg.ctrl_coeffs,
                                     flight_ctrl_filters,
-                                    motor_timer,
                                     &cfg.input_map,
                                     &cfg.pid_coeffs,
                                     &autopilot_status,
                                     state.has_taken_off,
+                                    &cfg.thrust_comp,
+                                    &cfg.mixer,
+                                    &cfg.desat,
+                                    cfg.idle_pwr,
+                                    &cfg.dyn_idle,
+                                    cfg.ctrl_scheme,
+                                    &cfg.motor_fail,
+                                    &cfg.authority,
                                     // throttle,
                                 );
-                            },
-                        );
+                            });
+
+                            // Only hold the motor timer for the transmission; the RPM edge ISRs
+                            // are below its ceiling.
+                            cx.shared.motor_timer.lock(|motor_timer| {
+                                flight_ctrls::send_motor_cmds(
+                                    state,
+                                    &cfg.control_mapping,
+                                    &cfg.output_smoothing,
+                                    motor_timer,
+                                );
+                            });
+                        }
                     }
 
                     cx.local.task_durations.flight_ctrl_interval = timestamp_imu_complete
                         - system_status.update_timestamps.flight_ctrls.unwrap_or(0.);
                     system_status.update_timestamps.flight_ctrls = Some(timestamp_imu_complete);
+
+                    if hil::active() {
+                        let output = HilOutput {
+                            timestamp: timestamp_imu_complete,
+                            motor_powers: state.motor_servo_state.rotor_powers(),
+                            arm_status: state.arm_status,
+                        };
+
+                        cx.shared
+                            .usb_serial
+                            .lock(|usb_serial| hil::send_output(&output, usb_serial));
+                    }
+                }
+
+                // Blackbox logging, while armed, and enabled by switch or from the PC.
+                let blackbox_switch = match control_channel_data {
+                    Some(ch_data) => ch_data.blackbox,
+                    None => false,
+                };
+                let log_blackbox = state.arm_status != ArmStatus::Disarmed
+                    && (state.blackbox.enabled_usb || blackbox_switch);
+
+                if log_blackbox && !state.blackbox.logging && !state.blackbox.full {
+                    state
+                        .blackbox
+                        .start(cfg.blackbox_rate_divisor, &cfg.gyro_lpf, &cfg.rpm_filter);
+                } else if !log_blackbox && state.blackbox.logging {
+                    state.blackbox.stop();
+                }
+
+                if state.blackbox.logging && i % cfg.blackbox_rate_divisor.max(1) as u32 == 0 {
+                    let att_cmd = state.attitude_commanded.quat;
+                    let rpms = state.motor_servo_state.rotor_rpms();
+
+                    state.blackbox.log(
+                        &LogRecord {
+                            gyro: (imu_data.v_pitch, imu_data.v_roll, imu_data.v_yaw),
+                            rates_commanded: state.attitude_commanded.quat_dt,
+                            attitude_commanded: (att_cmd.w, att_cmd.x, att_cmd.y, att_cmd.z),
+                            rpms: rpms.map(|r| r.unwrap_or(0.)),
+                            throttle: state.attitude_commanded.throttle,
+                            throttle_out: state.thrust_comp.throttle_out,
+                            volt_comp: state.thrust_comp.volt_comp_factor,
+                            idle_correction: state.dyn_idle.correction,
+                        },
+                        i,
+                    );
                 }
 
-                let timestamp_fc_complete =
-                    cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                let timestamp_fc_complete = monotonic::now();
 
                 // todo: Handle this being ~0 for non-FC loops?
                 cx.local.task_durations.flight_ctrls =
                     timestamp_fc_complete - timestamp_imu_complete;
 
+                cx.local.indicators.update(
+                    i,
+                    system_status,
+                    state,
+                    control_channel_data,
+                    cfg.batt_cell_count,
+                    &cfg.indicators,
+                    timestamp,
+                );
+
+                cx.local.led_status.update(
+                    system_status,
+                    state.arm_status,
+                    link_stats,
+                    state.batt_v,
+                    cfg.batt_cell_count,
+                    &cfg.led_strip,
+                    timestamp,
+                );
+
+                cx.local.camera_tilt.update(
+                    params,
+                    control_channel_data,
+                    state.arm_status,
+                    &cfg.camera_tilt,
+                    timestamp,
+                );
+
+                if state.telem_stream.due(timestamp) {
+                    let snapshot = TelemSnapshot {
+                        timestamp,
+                        uptime_us,
+                        attitude: params.attitude,
+                        gyro: (imu_data.v_pitch, imu_data.v_roll, imu_data.v_yaw),
+                        attitude_commanded: state.attitude_commanded.quat,
+                        rates_commanded: state.attitude_commanded.quat_dt,
+                        motor_powers: state.motor_servo_state.rotor_powers(),
+                        rpms: state.motor_servo_state.rotor_rpms(),
+                        batt_v: state.batt_v,
+                        current: state.esc_current,
+                        alt_baro: params.alt_msl_baro,
+                        autopilot_modes: autopilot_status.mode_flags(),
+                        arm_status: state.arm_status,
+                        has_taken_off: state.has_taken_off,
+                        profile: cfg.active_profile,
+                        flight_mode: state::flight_mode_label(state, autopilot_status).0,
+                        fusion_mode: state.accel_health.mode,
+                        throttle_limit: cfg
+                            .throttle_limit
+                            .active_limit(
+                                control_channel_data
+                                    .as_ref()
+                                    .map_or(false, |ch| ch.beginner),
+                            )
+                            .unwrap_or(1.),
+                        rpms_filtered: state.rpm_lpf.current(timestamp),
+                        batt_stage: state.batt_failsafe.stage,
+                        ground_speed: state.gps_metrics.ground_speed,
+                        distance_flown: state.gps_metrics.distance,
+                        home_dist_bearing: state.gps_metrics.home_dist_bearing,
+                        yaw_removed: state.yaw_removed,
+                        warnings: state.warnings.active,
+                    };
+
+                    cx.shared
+                        .usb_serial
+                        .lock(|usb_serial| state.telem_stream.send(&snapshot, usb_serial));
+                }
+
                 // Perform various lower priority tasks like updating altimeter data etc. Space
                 // these out between updates to keep loop time relatively consistent, and
                 // avoid desynchronizing these tasks. This creates slots; one slot runs
@@ -429,59 +1228,273 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                 // We're tracking tasks as ones that make it past the initial flight
                 // control ratio filter, so factor that out.
                 // todo: QC this.
-                // let i_compensated = i / FLIGHT_CTRL_IMU_RATIO;
+                // let i_compensated = i / rates.flight_ctrl_imu_ratio;
                 let i_compensated = i;
 
                 if (i_compensated - 0) % NUM_IMU_LOOP_TASKS == 0 {
-                    let mut batt_v = 0.;
-                    let mut curr_v = 0.;
-                    batt_v = cx
-                        .local
-                        .batt_curr_adc
-                        .reading_to_voltage(unsafe { V_A_ADC_READ_BUF }[0])
-                        * sensors_shared::ADC_BATT_V_DIV;
-                    curr_v = cx
-                        .local
-                        .batt_curr_adc
-                        .reading_to_voltage(unsafe { V_A_ADC_READ_BUF }[1])
-                        * sensors_shared::ADC_CURR_DIV;
-
-                    // todo: Find the current conversion factor. Probably slope + y int
-                    let esc_current = curr_v;
+                    // A complete sample from the previous sequence; never a half-updated one.
+                    // If there isn't a recent one, we keep the last readings, and skip the
+                    // brownout check.
+                    if let Some((sample, _age)) = adc_sampling::latest(timestamp) {
+                        let raw = sample.raw;
+                        let adc = &cx.local.batt_curr_adc;
+                        let pin_v = (adc.reading_to_voltage(raw.0), adc.reading_to_voltage(raw.1));
+
+                        state.adc_readings = AdcReadings::new(raw, pin_v, &cfg.adc_cal);
+
+                        if cfg.adc_cal.apply_pending(&state.adc_readings) {
+                            state.adc_readings = AdcReadings::new(raw, pin_v, &cfg.adc_cal);
+                            cx.shared.flash_onboard.lock(|flash| cfg.save(flash));
+                        }
+
+                        let batt_v = state.adc_readings.batt_v;
+
+                        // From the ADC directly: ESC telemetry is too slow to catch a collapse.
+                        if state.brownout.update(
+                            batt_v,
+                            cfg.batt_cell_count,
+                            state.arm_status as u8,
+                            &cfg.brownout,
+                            rates.dt_tasks,
+                        ) {
+                            println!("Brownout detected; stopping motors");
+                            event_log::log(
+                                EventCode::Brownout,
+                                state.arm_status as u16,
+                                (batt_v * 100.) as u16,
+                            );
+
+                            state.arm_status = ArmStatus::Disarmed;
+                            state.preflight_motors_running = false;
+                            state.motor_test.cancel();
+
+                            cx.shared
+                                .motor_timer
+                                .lock(|motor_timer| dshot::stop_all(motor_timer));
+                        }
+                    }
+
+                    adc_sampling::start(cx.local.batt_curr_adc, timestamp);
+
+                    if (i_compensated - 0) % (NUM_IMU_LOOP_TASKS * MCU_TEMP_RATIO) == 0 {
+                        if let Some(temp) = perf_stats::sample_mcu_temp(cx.local.mcu_temp_adc) {
+                            state.perf_stats.mcu_temp = Some(temp);
+                        }
+                    }
+
+                    let batt_v = state.adc_readings.batt_v;
+                    let esc_current = state.adc_readings.current;
+
+                    let esc_frame =
+                        if esc_telemetry::NEW_FRAME_RECEIVED.swap(false, Ordering::AcqRel) {
+                            Some(unsafe { esc_telemetry::FRAME })
+                        } else {
+                            None
+                        };
+                    state.esc_telemetry.update(esc_frame.as_ref(), timestamp);
+
+                    let telem = &state.esc_telemetry; // code shortener
+                    let max_age = system_status::MAX_UPDATE_PERIOD_ESC_TELEM;
 
                     state.batt_v = batt_v;
                     state.esc_current = esc_current;
 
-                    let timestamp_task_complete =
-                        cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                    match telem.voltage(timestamp, max_age) {
+                        Some(v_esc) => {
+                            system_status.esc_telemetry = if telem.all_recent(timestamp, max_age) {
+                                SensorStatus::Pass
+                            } else {
+                                SensorStatus::Fault
+                            };
+
+                            let i_esc = telem.current(timestamp, max_age);
+
+                            system_status.esc_over_temp = telem.max_temp(timestamp, max_age)
+                                > esc_telemetry::OVER_TEMP_THRESH;
+                            system_status.batt_meas_mismatch = esc_telemetry::batt_meas_mismatch(
+                                batt_v,
+                                esc_current,
+                                v_esc,
+                                i_esc,
+                            );
+
+                            if cfg.batt_meas_source == BattMeasSource::EscTelemetry {
+                                state.batt_v = v_esc;
+                                state.esc_current = i_esc;
+                            }
+                        }
+                        // If ESC telemetry is the configured source, but isn't available, we
+                        // fall back to the ADC.
+                        None => {
+                            system_status.esc_telemetry = SensorStatus::NotConnected;
+                            system_status.esc_over_temp = false;
+                            system_status.batt_meas_mismatch = false;
+                        }
+                    }
+
+                    state.thrust_comp.update_voltage(
+                        state.batt_v,
+                        cfg.batt_cell_count,
+                        rates.dt_tasks,
+                        &cfg.thrust_comp,
+                    );
+
+                    state.batt_failsafe.update(
+                        state.batt_v,
+                        cfg.batt_cell_count,
+                        state.flight_stats.flight.mah_used,
+                        state.arm_status,
+                        &cfg.batt_failsafe,
+                        rates.dt_tasks,
+                    );
+                    system_status.batt_stage = state.batt_failsafe.stage;
+
+                    let timestamp_task_complete = monotonic::now();
 
                     cx.local.task_durations.tasks[0] =
                         timestamp_task_complete - timestamp_fc_complete;
                 } else if (i_compensated - 1) % NUM_IMU_LOOP_TASKS == 0 {
+                    // Before the Preflight check, so profiles can be selected over USB while
+                    // tuning.
+                    state.profile_switch.update(
+                        control_channel_data,
+                        cfg,
+                        &mut state.pid_state_rate,
+                    );
+
                     if state.op_mode == OperationMode::Preflight {
                         return;
                     }
 
+                    // Channel data is held after the link is lost. On the ground, a lost link
+                    // disarms; in the air, we keep the last arm status, and run the lost-link
+                    // procedure.
+                    let link_ok = system_status.rf_control_link == SensorStatus::Pass;
+
                     let controller_arm_status = match control_channel_data {
-                        Some(ch_data) => ch_data.arm_status,
-                        None => {
+                        Some(ch_data) if link_ok || state.has_taken_off => ch_data.arm_status,
+                        _ => {
                             // state.attitude_commanded.throttle = 0.;
                             ArmStatus::Disarmed
                         }
                     };
 
+                    // Motors may be reversed, or unverified, after turtle mode.
+                    #[cfg(feature = "quad")]
+                    let controller_arm_status = if state.turtle.blocks_arming() {
+                        ArmStatus::Disarmed
+                    } else {
+                        controller_arm_status
+                    };
+
+                    // After a tip-over disarm, a motor failing the arm check, or an autopilot
+                    // landing, the arm switch must be cycled. Check all, so cycling it clears all.
+                    #[cfg(feature = "quad")]
+                    let controller_arm_status = {
+                        let switch_armed = controller_arm_status == ArmStatus::Armed;
+
+                        if state.tipover.latch.blocks_arming(switch_armed)
+                            | state.arm_check.latch.blocks_arming(switch_armed)
+                            | state.touchdown_latch.blocks_arming(switch_armed)
+                        {
+                            ArmStatus::Disarmed
+                        } else {
+                            controller_arm_status
+                        }
+                    };
+
+                    // Don't re-arm on the remaining charge after a brownout, or while killed.
+                    let controller_arm_status = if brownout::active() || motor_kill::active() {
+                        ArmStatus::Disarmed
+                    } else {
+                        controller_arm_status
+                    };
+
+                    // The sticks are moved through their full range during input calibration.
+                    let controller_arm_status = if state.input_cal_collector.active() {
+                        ArmStatus::Disarmed
+                    } else {
+                        controller_arm_status
+                    };
+
+                    // During, and after, an auto-launch, the motor stays armed with the switch at
+                    // controls-armed.
+                    #[cfg(feature = "fixed-wing")]
+                    let switch_arm_status = controller_arm_status;
+                    #[cfg(feature = "fixed-wing")]
+                    let controller_arm_status = state
+                        .auto_launch
+                        .controller_arm_status(controller_arm_status);
+
+                    system_status.prearm = match control_channel_data {
+                        Some(ch_data) if link_ok => ch_data.prearm,
+                        Some(ch_data) if ch_data.prearm != PrearmStatus::NotConfigured => {
+                            PrearmStatus::Inactive
+                        }
+                        _ => PrearmStatus::NotConfigured,
+                    };
+
+                    let arm_status_prev = state.arm_status;
+
+                    // In 3D mode, throttle idle for arming is stick center.
+                    #[cfg(feature = "quad")]
+                    let arm_throttle = reversible::arm_throttle(
+                        state.attitude_commanded.throttle,
+                        dshot::reversible(),
+                    );
+                    #[cfg(feature = "fixed-wing")]
+                    let arm_throttle = state.attitude_commanded.throttle;
+
                     safety::handle_arm_status(
                         cx.local.arm_signals_received,
                         cx.local.disarm_signals_received,
                         controller_arm_status,
+                        system_status.prearm,
+                        system_status.att_convergence == AttConvergence::Aligning,
                         &mut state.arm_status,
                         &mut state.has_taken_off,
-                        state.attitude_commanded.throttle,
+                        arm_throttle,
+                        &cfg.arm_cfg,
+                    );
+
+                    if state.arm_status != arm_status_prev {
+                        event_log::log(EventCode::ArmStatus, state.arm_status as u16, 0);
+                    }
+
+                    // Eg armed by auto-launch; don't keep calibrating.
+                    if state.arm_status != ArmStatus::Disarmed {
+                        state.input_cal_collector.cancel();
+                    }
+
+                    // The self-test assumes the conditions it was started in: Preflight,
+                    // disarmed, and motors stopped.
+                    if state.op_mode != OperationMode::Preflight
+                        || state.arm_status != ArmStatus::Disarmed
+                        || state.preflight_motors_running
+                        || state.motor_test.motor_active().is_some()
+                    {
+                        state.self_test.cancel();
+                    }
+
+                    // This arms the motor when it detects a throw.
+                    #[cfg(feature = "fixed-wing")]
+                    state.auto_launch.update(
+                        &mut state.arm_status,
+                        &mut state.has_taken_off,
+                        switch_arm_status,
+                        system_status.prearm,
+                        params,
+                        state.airspeed_est.airspeed,
+                        state.alt_est.estimate.msl,
+                        &cfg.auto_launch,
+                        rates.dt_tasks,
                     );
 
+                    #[cfg(feature = "quad")]
                     let angle_from_upright =
                         params.attitude.rotate_vec(ahrs::UP).dot(ahrs::UP).acos();
 
+                    #[cfg(feature = "quad")]
                     safety::handle_takeoff_attitude_lock(
                         state.arm_status,
                         state.attitude_commanded.throttle,
@@ -489,38 +1502,116 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                         &mut cx.local.time_with_low_throttle,
                         angle_from_upright,
                         &mut state.has_taken_off,
-                        DT_FLIGHT_CTRLS * NUM_IMU_LOOP_TASKS as f32,
+                        rates.dt_tasks,
                     );
 
                     #[cfg(feature = "quad")]
-                    if let Some(ch_data) = control_channel_data {
-                        flight_ctrls::set_input_mode(ch_data.input_mode, state, system_status);
+                    if let Some((cause, detail)) = state.tipover.update(
+                        state.arm_status == ArmStatus::Armed,
+                        state.has_taken_off,
+                        angle_from_upright,
+                        &state.motor_servo_state.rotor_powers(),
+                        if dshot::BIDIR_EN {
+                            Some(&state.motor_servo_state.rotor_rpms())
+                        } else {
+                            None
+                        },
+                        &cfg.tipover,
+                        rates.dt_tasks,
+                    ) {
+                        println!("Tip-over detected on the ground; disarming");
+                        event_log::log(EventCode::Tipover, cause as u16, detail);
+
+                        state.arm_status = ArmStatus::Disarmed;
+                        event_log::log(EventCode::ArmStatus, state.arm_status as u16, 0);
                     }
 
-                    let timestamp_task_complete =
-                        cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                    // A hand launch is a throw with the motor at full power; detect it from
+                    // forward acceleration, then speed.
+                    #[cfg(feature = "fixed-wing")]
+                    safety::handle_takeoff_attitude_lock(
+                        state.arm_status,
+                        state.attitude_commanded.throttle,
+                        Vec3::new(params.a_x, params.a_y, params.a_z).dot(ahrs::FORWARD),
+                        state.airspeed_est.airspeed,
+                        &mut state.launch_detect,
+                        &mut state.has_taken_off,
+                        rates.dt_tasks,
+                    );
+
+                    let gps_fix = cx.shared.gps_fix.lock(|fix| *fix);
+                    state.gps_metrics.update(
+                        &gps_fix,
+                        system_status.gps == SensorStatus::Pass,
+                        state.arm_status,
+                    );
+
+                    state.flight_stats.update(
+                        state.arm_status,
+                        state.has_taken_off,
+                        state.alt_est.estimate.msl,
+                        state.gps_metrics.ground_speed,
+                        state.esc_current,
+                        state.batt_v,
+                        cfg.batt_cell_count,
+                        timestamp,
+                        rates.dt_tasks,
+                    );
+
+                    state.health_trend.update(
+                        state.arm_status,
+                        state.has_taken_off,
+                        &state.motor_servo_state,
+                        &params,
+                        state.flight_stats.totals.flights,
+                        rates.dt_tasks,
+                    );
+
+                    let timestamp_task_complete = monotonic::now();
 
                     cx.local.task_durations.tasks[1] =
                         timestamp_task_complete - timestamp_fc_complete;
                     // For OSD, we have a larger pause between writes so as not to saturate
                     // the UART line.
                 } else if (i_compensated - 2) % (NUM_IMU_LOOP_TASKS * 5) == 0 {
+                    state::update_warnings(state, system_status, link_stats.uplink_link_quality);
+
                     let osd_data = OsdData {
                         arm_status: state.arm_status,
                         battery_voltage: state.batt_v,
                         current_draw: state.esc_current,
-                        alt_msl_baro: params.alt_msl_baro,
+                        alt_msl: state.alt_est.estimate.msl,
+                        alt_above_takeoff: state.alt_est.estimate.above_takeoff,
                         posit_vel: PositVelEarthUnits::default(),
+                        airspeed: state.airspeed_est.airspeed,
                         autopilot: AutopilotData::from_status(&autopilot_status),
-                        base_dist_bearing: (
-                            0., 0., // todo: Fill these out
-                        ),
+                        base_dist_bearing: state.gps_metrics.home_dist_bearing,
+                        ground_speed: state.gps_metrics.ground_speed,
+                        distance_flown: state.gps_metrics.distance,
+                        speed_units: cfg.speed_units,
                         link_quality: link_stats.uplink_link_quality,
                         num_satellites: 0, // todo temp
                         batt_cell_count: cfg.batt_cell_count,
                         throttle: state.attitude_commanded.throttle,
                         total_acc: (params.a_x.powi(2) + params.a_y.powi(2) + params.a_z.powi(2))
                             .sqrt(),
+                        hover_throttle: state.hover_throttle_est.throttle,
+                        wind: if state.wind_est.valid() {
+                            Some((state.wind_est.speed(), state.wind_est.direction()))
+                        } else {
+                            None
+                        },
+                        saved_posit: state.lost_craft.saved.map(|p| (p.lat, p.lon)),
+                        flight_time: state.flight_stats.flight.armed_time,
+                        profile: cfg.active_profile,
+                        flight_mode: state::flight_mode_label(state, autopilot_status).0,
+                        throttle_limit: cfg.throttle_limit.active_limit(
+                            control_channel_data
+                                .as_ref()
+                                .map_or(false, |ch| ch.beginner),
+                        ),
+                        warning: state.warnings.ticker(timestamp),
+                        killed: motor_kill::active(),
                     };
 
                     // todo: Your blocking read here is breaking everything; use DMA.
@@ -528,42 +1619,104 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                         osd::send_osd_data(uart_osd, &osd_data);
                     });
 
-                    let timestamp_task_complete =
-                        cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                    let timestamp_task_complete = monotonic::now();
 
                     cx.local.task_durations.tasks[2] =
                         timestamp_task_complete - timestamp_fc_complete;
                 } else if (i_compensated - 3) % NUM_IMU_LOOP_TASKS == 0 {
-                    // todo: Update this using our new throttle/flt-ctrl scheme.
-                    let mut throttle_prev = 0.;
-                    if let Some(ch_data) = control_channel_data {
-                        autopilot_status.set_modes_from_ctrls(ch_data, &params);
-                        throttle_prev = ch_data.throttle;
-                    }
-
-                    #[cfg(feature = "quad")]
-                    autopilot_status.apply(
-                        &mut state.autopilot_commands,
+                    let gps_fix = cx.shared.gps_fix.lock(|fix| *fix);
+                    state.alt_est.update(
                         params,
-                        // filters,
-                        // coeffs,
+                        &gps_fix,
+                        system_status,
+                        &mut state.ground_ref,
+                        state.arm_status == safety::MOTORS_ARMED,
+                        !state.has_taken_off,
+                        timestamp,
+                        rates.dt_tasks,
+                    );
+
+                    // todo: Update this using our new throttle/flt-ctrl scheme.
+                    state::update_flight_modes(
+                        state,
+                        autopilot_status,
+                        control_channel_data,
                         system_status,
-                        throttle_prev,
-                        DT_FLIGHT_CTRLS * NUM_IMU_LOOP_TASKS as f32,
+                        params,
+                        cfg,
+                        timestamp,
                     );
 
-                    // #[cfg(feature = "fixed-wing")]
-                    //      autopilot_status.apply(
-                    //    &mut state.autopilot_commands,
-                    //     params,
-                    //     // pid_attitude,
-                    //     // filters,
-                    //     // coeffs,
-                    //     system_status,
-                    // );
+                    #[cfg(feature = "quad")]
+                    {
+                        // We learn wind while holding position, close to the held point.
+                        let holding = state.arm_status == ArmStatus::Armed
+                            && state.has_taken_off
+                            && autopilot_status
+                                .loiter_error(params, system_status)
+                                .map(|err| err < flight_ctrls::wind_est::POSIT_ERR_THRESH)
+                                .unwrap_or(false);
+
+                        let att_cmd = state.attitude_commanded.quat.to_euler();
+                        state.wind_est.update(
+                            att_cmd.pitch,
+                            att_cmd.roll,
+                            params.s_yaw_heading,
+                            state.attitude_commanded.throttle,
+                            state.hover_throttle_est.throttle,
+                            state.alt_est.estimate.msl,
+                            holding,
+                            rates.dt_tasks,
+                        );
+
+                        let touched_down = autopilot_status.apply(
+                            &mut state.autopilot_commands,
+                            params,
+                            &state.alt_est.estimate,
+                            // filters,
+                            // coeffs,
+                            system_status,
+                            state.hover_throttle_est.throttle,
+                            &state.wind_est,
+                            rates.dt_tasks,
+                        );
+
+                        // Arming stays blocked until the arm switch is cycled, since it's likely
+                        // still set.
+                        if touched_down && state.arm_status == ArmStatus::Armed {
+                            println!("Landed; disarming");
+                            state.arm_status = ArmStatus::Disarmed;
+                            state.has_taken_off = false;
+                            state.touchdown_latch.trip();
+                            event_log::log(EventCode::ArmStatus, state.arm_status as u16, 0);
+                        }
+                    }
 
-                    let timestamp_task_complete =
-                        cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                    #[cfg(feature = "fixed-wing")]
+                    {
+                        state.airspeed_est.update(
+                            &gps_fix,
+                            params.s_yaw_heading,
+                            system_status.gnss_usable(),
+                            autopilot_status.orbit.is_some(),
+                            &cfg.airspeed_cfg,
+                        );
+
+                        autopilot_status.apply(
+                            &mut state.autopilot_commands,
+                            params,
+                            &state.alt_est.estimate,
+                            // pid_attitude,
+                            // filters,
+                            // coeffs,
+                            system_status,
+                            state.airspeed_est.airspeed,
+                            &cfg.airspeed_cfg,
+                            rates.dt_tasks,
+                        );
+                    }
+
+                    let timestamp_task_complete = monotonic::now();
 
                     cx.local.task_durations.tasks[3] =
                         timestamp_task_complete - timestamp_fc_complete;
@@ -578,71 +1731,180 @@ pub fn run(mut cx: app::imu_tc_isr::Context) {
                     // todo: This should probably be delegatd to a fn; get it
                     // todo out here
                     if i % THRUST_LOG_RATIO == 0 {
-                        flight_ctrls::log_accel_pts(state, params, timestamp);
+                        flight_ctrls::log_accel_pts(state, params, &cfg.authority, timestamp);
+                    }
+
+                    if (i_compensated - 4) % (NUM_IMU_LOOP_TASKS * CRSF_TELEM_RATIO) == 0 {
+                        let gps_fix = cx.shared.gps_fix.lock(|fix| *fix);
+                        let fix_valid = system_status.gps == SensorStatus::Pass;
+
+                        state.lost_craft.update(&gps_fix, fix_valid, timestamp);
+
+                        // While the link is lost, we only send the position, using the last known
+                        // one if we don't have a fix, so the transmitter's log captures it.
+                        let link_lost = system_status.rf_control_link != SensorStatus::Pass;
+                        let last_fix = state.lost_craft.known_posit().0.map(|p| p.to_fix());
+
+                        let frame = if link_lost {
+                            crsf::TelemFrame::Gps
+                        } else {
+                            crsf::TelemFrame::from_index(
+                                i_compensated / (NUM_IMU_LOOP_TASKS * CRSF_TELEM_RATIO),
+                            )
+                        };
+
+                        let data = crsf::TelemData {
+                            batt_v: state.batt_v,
+                            current: state.esc_current,
+                            batt_remaining: util::batt_left_from_v(
+                                state.batt_v,
+                                cfg.batt_cell_count,
+                            ),
+                            pitch: params.s_pitch,
+                            roll: params.s_roll,
+                            yaw: params.s_yaw_heading,
+                            gps_fix: if fix_valid {
+                                Some(&gps_fix)
+                            } else if link_lost {
+                                last_fix.as_ref()
+                            } else {
+                                None
+                            },
+                            flight_mode: state::flight_mode_label(state, autopilot_status).1,
+                        };
+
+                        if cfg.rx_protocol == RxProtocol::Crsf {
+                            crsf::queue_telemetry(frame, &data);
+                        }
                     }
 
-                    let timestamp_task_complete =
-                        cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                    if (i_compensated - 4) % (NUM_IMU_LOOP_TASKS * EXT_SENSORS_RATIO) == 0
+                        && !state.self_test.transfers_paused()
+                    {
+                        (cx.shared.ext_sensor_active, cx.shared.i2c1).lock(|sensor, i2c1| {
+                            let prev_device = match sensor {
+                                ExtSensor::Tof | ExtSensor::TofIntClear => {
+                                    &mut system_status.i2c_tof
+                                }
+                                ExtSensor::Mag => &mut system_status.i2c_mag,
+                                ExtSensor::Gps => &mut system_status.i2c_gps,
+                            };
+                            sensors_shared::check_i2c_bus(
+                                &i2c1.regs,
+                                &sensors_shared::SEQ_EXT,
+                                prev_device,
+                                timestamp,
+                                board_config::PIN_I2C1_SCL,
+                                board_config::PIN_I2C1_SDA,
+                            );
+
+                            // Rotate between the sensors, skipping any backed off after errors.
+                            let gps_avail = system_status.i2c_gps.available(timestamp);
+                            let tof_avail = system_status.tof != SensorStatus::NotConnected
+                                && system_status.i2c_tof.available(timestamp);
+                            let mag_avail = system_status.magnetometer
+                                != SensorStatus::NotConnected
+                                && system_status.i2c_mag.available(timestamp);
+
+                            let order = [
+                                (ExtSensor::Gps, gps_avail),
+                                (ExtSensor::Tof, tof_avail),
+                                (ExtSensor::Mag, mag_avail),
+                            ];
+                            let prev = match sensor {
+                                ExtSensor::TofIntClear => ExtSensor::Tof,
+                                s => *s,
+                            };
+                            let start = order.iter().position(|(s, _)| *s == prev).unwrap_or(0);
+
+                            let Some(next) = (1..=order.len())
+                                .map(|j| order[(start + j) % order.len()])
+                                .find(|(_, avail)| *avail)
+                                .map(|(s, _)| s)
+                            else {
+                                return;
+                            };
+
+                            *sensor = next;
+                            sensors_shared::start_transfer_ext(i2c1, next);
+                        })
+                    }
+
+                    let timestamp_task_complete = monotonic::now();
 
                     cx.local.task_durations.tasks[4] =
                         timestamp_task_complete - timestamp_fc_complete;
                 } else if (i_compensated - 5) % NUM_IMU_LOOP_TASKS == 0 {
                     // Don't poll the baro too fast; we get DMA anomolies and no data.
-                    if (i_compensated - 5) % (NUM_IMU_LOOP_TASKS * BARO_RATIO) == 0 {
+                    if (i_compensated - 5) % (NUM_IMU_LOOP_TASKS * BARO_RATIO) == 0
+                        && !state.self_test.transfers_paused()
+                    {
                         // This is a sloppy way of lowering the refresh rate. Bottom line, for quads:
                         // 8khz loop / (11 * 6(num_tasks)) = 32Hz.
                         // This is fragile, ie if we change any of the above params.
                         // The baro refreshes at 32Hz.
                         cx.shared.i2c2.lock(|i2c2| {
-                            sensors_shared::start_transfer_baro(i2c2);
-                        })
-                    }
+                            sensors_shared::check_i2c_bus(
+                                &i2c2.regs,
+                                &sensors_shared::SEQ_BARO,
+                                &mut system_status.i2c_baro,
+                                timestamp,
+                                board_config::PIN_I2C2_SCL,
+                                board_config::PIN_I2C2_SDA,
+                            );
 
-                    // This isn't part of `update_from_timestamps` due to the params
-                    // in `execute_lost_link`.
-                    match system_status.update_timestamps.rf_control_link {
-                        Some(t) => {
-                            if timestamp - t > system_status::MAX_UPDATE_PERIOD_RC_LINK {
-                                system_status.rf_control_link = SensorStatus::NotConnected;
-
-                                if state.has_taken_off {
-                                    safety::excecute_link_lost(
-                                        system_status,
-                                        autopilot_status,
-                                        params,
-                                        &cfg.base_pt,
-                                    );
-                                }
+                            if system_status.i2c_baro.available(timestamp) {
+                                sensors_shared::start_transfer_baro(i2c2);
                             }
-                        }
-                        None => {
-                            system_status.rf_control_link = SensorStatus::NotConnected;
-                        }
+                        })
                     }
 
-                    let timestamp_task_complete =
-                        cx.shared.tick_timer.lock(|timer| timer.get_timestamp());
+                    state.dma_stats.update();
+
+                    let timestamp_task_complete = monotonic::now();
 
                     cx.local.task_durations.tasks[5] =
                         timestamp_task_complete - timestamp_fc_complete;
                 }
 
-                system_status.update_from_timestamp(timestamp);
+                // Don't let altitude hold use a stale or invalid TOF reading.
+                if system_status.tof != SensorStatus::Pass {
+                    params.alt_tof = None;
+                }
 
-                cx.shared.tick_timer.lock(|tick_timer| {
-                    #[cfg(feature = "print-status")]
-                    if i % PRINT_STATUS_RATIO == 0 {
-                        util::print_status(
-                            params,
-                            system_status,
-                            control_channel_data,
-                            state,
-                            autopilot_status,
-                            tick_timer,
-                            &cx.local.task_durations,
-                        );
-                    }
-                });
+                #[cfg(feature = "print-status")]
+                if let Some(channel) = state.log_channels.due(i) {
+                    log_snapshot = Some(debug_log::Snapshot::capture(
+                        channel,
+                        timestamp,
+                        params,
+                        system_status,
+                        control_channel_data,
+                        link_stats,
+                        state,
+                        autopilot_status,
+                        &cx.local.task_durations,
+                    ));
+                }
             },
-        )
+        );
+
+    // Formatted after releasing the shared resources above, so it doesn't delay lower-priority
+    // ISRs waiting on them.
+    #[cfg(feature = "print-status")]
+    if let Some(snapshot) = log_snapshot {
+        snapshot.print();
+    }
+
+    // We measure ISR timing here, instead of in the lock above, since tasks in it may return
+    // early.
+    let timestamp_isr_complete = monotonic::now();
+
+    (cx.shared.state_volatile, cx.shared.system_status).lock(|state, system_status| {
+        state
+            .perf_stats
+            .update_isr_timing(timestamp, timestamp_isr_complete, isr_latency);
+        system_status.imu_isr_overrun = state.perf_stats.overrunning();
+        system_status.imu_rate_mismatch = state.perf_stats.imu_rate_mismatch();
+    });
 }
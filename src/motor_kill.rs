@@ -0,0 +1,42 @@
+//! This module contains the motor kill switch: A channel that stops the motors at once, regardless
+//! of arm status or autopilot mode. Unlike disarming, the kill latches: Motors stay stopped until
+//! the switch is released, and throttle is at idle.
+//!
+//! It's enforced in `dshot::setup_payload`, after the mixer, which every motor command passes
+//! through. So no other code path, eg the autopilot, a preflight motor test, or a misbehaving
+//! flight control ISR, can spin the motors while it's latched. We also disarm while killed, so the
+//! flight controls don't wind up against stopped motors; once released, arming works as usual.
+//!
+//! The switch is read each IMU update, from the latest channel data. Without channel data, the
+//! latch holds.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::println;
+
+use crate::event_log::{self, EventCode};
+
+/// Set while killed. Checked when setting up DSHOT payloads.
+static KILLED: AtomicBool = AtomicBool::new(false);
+
+/// Run each IMU update. `switch` is the kill switch's position, and `throttle_idle` is true if
+/// throttle is at idle. Returns true on the update the kill trips; stop the motors, and disarm.
+pub fn update(switch: bool, throttle_idle: bool, arm_status: u8) -> bool {
+    if switch {
+        if !KILLED.swap(true, Ordering::AcqRel) {
+            println!("Motor kill");
+            event_log::log(EventCode::MotorKill, 1, arm_status as u16);
+            return true;
+        }
+    } else if throttle_idle && KILLED.swap(false, Ordering::AcqRel) {
+        println!("Motor kill released");
+        event_log::log(EventCode::MotorKill, 0, arm_status as u16);
+    }
+
+    false
+}
+
+/// True while the kill is latched.
+pub fn active() -> bool {
+    KILLED.load(Ordering::Acquire)
+}
@@ -0,0 +1,415 @@
+//! This module contains a preflight motor setup wizard, driven over USB. It identifies motor order
+//! and direction, and sets the control mapping from them, vice setting it by trial and error.
+//! We spin each output in turn, at low power, and note which RPM telemetry slot responds. After
+//! each, the user identifies from the PC application which corner of the frame spun, and which
+//! way. Once all four are identified, we compute the control mapping: The output for each corner,
+//! and reversed flags so each motor spins the way the prop layout requires. The main loop applies
+//! and saves it. We then verify by spinning the front-left motor, and confirming the telemetry
+//! slot identified for it responds.
+//!
+//! Motors are spun with the motor test, so its interlocks apply: Preflight mode, disarmed,
+//! props-off acknowledged, the power cap, and the PC's keep-alive messages while a motor spins. If
+//! the motor test stops early, the wizard is aborted. It also stops if no telemetry slot responds,
+//! eg without bidirectional DSHOT, or if the user doesn't answer in time. Quad only.
+
+use cfg_if::cfg_if;
+use defmt::println;
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    flight_ctrls::{
+        control_mapping::ControlMapping,
+        motor_servo::{RotationDir, MIN_ROTOR_RPM},
+        motor_test::{MotorTest, MotorTestCmd, MAX_DURATION},
+    },
+    protocols::{dshot::Motor, rpm_reception},
+};
+
+#[cfg(feature = "quad")]
+use crate::flight_ctrls::motor_servo::MotorServoHardware;
+
+// Seconds. Before each spin, we wait this long with motors stopped, so the previous motor has
+// spun down, and its telemetry reads low.
+const SETTLE_TIME: f32 = 1.;
+// Seconds each motor spins; long enough for the user to see it. Must be less than the motor
+// test's `MAX_DURATION`.
+const SPIN_TIME: f32 = 2.;
+// Seconds we wait for the user to identify a motor, before giving up.
+const ANSWER_TIMEOUT: f32 = 60.;
+
+// Power, then the rotation direction of the front-left and aft-right motors (u8).
+pub const MOTOR_WIZARD_START_SIZE: usize = 4 + 1;
+// Corner, then the direction it spun.
+pub const MOTOR_WIZARD_ANSWER_SIZE: usize = 2;
+// Status and step, then for each output: Telemetry slot, corner, and reversed flag.
+pub const MOTOR_WIZARD_STATUS_SIZE: usize = 2 + 3 * 4;
+
+// Used in serialized status for slots and corners not identified yet.
+const NONE_VAL: u8 = 0xff;
+
+// By output: Motor 1 - 4. Telemetry slots are indexed the same way.
+const MOTORS: [Motor; 4] = [Motor::M1, Motor::M2, Motor::M3, Motor::M4];
+
+#[cfg(feature = "quad")]
+const OUTPUTS: [MotorServoHardware; 4] = [
+    MotorServoHardware::Pin1,
+    MotorServoHardware::Pin2,
+    MotorServoHardware::Pin3,
+    MotorServoHardware::Pin4,
+];
+
+/// A position on the frame. Repr is how it's passed over USB.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Corner {
+    FrontLeft = 0,
+    FrontRight = 1,
+    AftLeft = 2,
+    AftRight = 3,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Idle,
+    /// Motors stopped. Value is the output index.
+    Settle(usize),
+    Spin(usize),
+    /// Waiting for the user to identify the output that just spun.
+    Answer(usize),
+    VerifySettle,
+    Verify,
+}
+
+/// Reported over USB. Repr is how it's passed.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MotorWizardStatus {
+    Idle = 0,
+    /// Spinning an output, or waiting for it to spin down.
+    Running = 1,
+    /// Waiting for the user to identify the output that just spun.
+    AwaitingAnswer = 2,
+    /// The mapping is saved, and we're spinning the front-left motor to check it.
+    Verifying = 3,
+    Complete = 4,
+    /// The motor test stopped early, eg from a keep-alive timeout, or arming; or the PC stopped
+    /// the wizard.
+    Aborted = 5,
+    /// No RPM telemetry slot responded while an output spun.
+    NoTelemetry = 6,
+    /// The user didn't answer within `ANSWER_TIMEOUT`.
+    TimedOut = 7,
+    /// A corner was identified for more than one output.
+    InvalidAnswer = 8,
+    /// A different telemetry slot responded than identified for the front-left motor.
+    VerifyFailed = 9,
+}
+
+/// Wizard state. Started and answered from the USB ISR, and run from the main loop.
+pub struct MotorWizard {
+    stage: Stage,
+    status: MotorWizardStatus,
+    /// Seconds since start.
+    stage_start: f32,
+    /// Power to run each motor at, 0. to 1.
+    power: f32,
+    /// For the prop layout. Front-right and aft-left spin the other way.
+    frontleft_aftright_dir: RotationDir,
+    start_pending: Option<(f32, RotationDir)>,
+    answer_pending: Option<(Corner, RotationDir)>,
+    /// Reversed flags by output, when the wizard started. Directions seen are with these.
+    reversed_at_start: [bool; 4],
+    /// The highest RPM read on each telemetry slot during the current spin.
+    peak_rpm: [f32; 4],
+    /// By output: The telemetry slot that responded, the corner identified, and whether it must
+    /// be reversed to spin the right way for its corner.
+    slots: [Option<usize>; 4],
+    corners: [Option<Corner>; 4],
+    reversed: [bool; 4],
+}
+
+impl Default for MotorWizard {
+    fn default() -> Self {
+        Self {
+            stage: Stage::Idle,
+            status: MotorWizardStatus::Idle,
+            stage_start: 0.,
+            power: 0.,
+            frontleft_aftright_dir: RotationDir::Clockwise,
+            start_pending: None,
+            answer_pending: None,
+            reversed_at_start: [false; 4],
+            peak_rpm: [0.; 4],
+            slots: [None; 4],
+            corners: [None; 4],
+            reversed: [false; 4],
+        }
+    }
+}
+
+impl MotorWizard {
+    /// Request a start, from the USB ISR. Check the motor test interlocks before calling. Power is
+    /// clamped by the motor test.
+    pub fn request_start(&mut self, power: f32, frontleft_aftright_dir: RotationDir) {
+        self.start_pending = Some((power, frontleft_aftright_dir));
+    }
+
+    /// Identify the output that just spun, from the USB ISR. `dir` is as seen from above. Ignored
+    /// unless we're waiting for an answer.
+    pub fn answer(&mut self, corner: Corner, dir: RotationDir) {
+        if let Stage::Answer(_) = self.stage {
+            self.answer_pending = Some((corner, dir));
+        }
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.stage != Stage::Idle || self.start_pending.is_some()
+    }
+
+    /// Stop, eg on leaving Preflight, or arming. Any motor spinning is stopped by the caller.
+    pub fn cancel(&mut self) {
+        self.start_pending = None;
+        if self.stage != Stage::Idle {
+            println!("Motor wizard aborted");
+            self.finish(MotorWizardStatus::Aborted);
+        }
+    }
+
+    /// Run regularly from the main loop, while in Preflight and disarmed, prior to updating the
+    /// motor test. `mapping` is the one in use. Returns the mapping identified, once the user has
+    /// identified all outputs; the caller applies and saves it. Timestamp is in seconds.
+    pub fn update(
+        &mut self,
+        motor_test: &mut MotorTest,
+        mapping: &ControlMapping,
+        pole_count: u8,
+        timestamp: f32,
+    ) -> Option<ControlMapping> {
+        if let Some((power, dir)) = self.start_pending.take() {
+            self.start(power, dir, mapping, timestamp);
+        }
+
+        let elapsed = timestamp - self.stage_start;
+
+        match self.stage {
+            Stage::Idle => (),
+            Stage::Settle(i) => {
+                if elapsed >= SETTLE_TIME {
+                    self.spin(motor_test, i);
+                    self.set_stage(Stage::Spin(i), timestamp);
+                }
+            }
+            Stage::Spin(i) => {
+                if !self.spin_update(motor_test, i, pole_count, elapsed) {
+                    return None;
+                }
+
+                match self.responding_slot() {
+                    Some(slot) => {
+                        self.slots[i] = Some(slot);
+                        self.status = MotorWizardStatus::AwaitingAnswer;
+                        self.set_stage(Stage::Answer(i), timestamp);
+                    }
+                    None => {
+                        println!("Motor wizard: No RPM telemetry from motor {}", i + 1);
+                        self.finish(MotorWizardStatus::NoTelemetry);
+                    }
+                }
+            }
+            Stage::Answer(i) => {
+                let Some((corner, dir)) = self.answer_pending.take() else {
+                    if elapsed > ANSWER_TIMEOUT {
+                        println!("Motor wizard timed out waiting for an answer");
+                        self.finish(MotorWizardStatus::TimedOut);
+                    }
+                    return None;
+                };
+
+                if self.corners.contains(&Some(corner)) {
+                    println!("Motor wizard: Corner identified twice");
+                    self.finish(MotorWizardStatus::InvalidAnswer);
+                    return None;
+                }
+
+                self.corners[i] = Some(corner);
+                // Flip the output's reversal if it spun against its corner's direction.
+                self.reversed[i] = self.reversed_at_start[i] != (dir != self.dir_for(corner));
+
+                if i + 1 < MOTORS.len() {
+                    self.status = MotorWizardStatus::Running;
+                    self.set_stage(Stage::Settle(i + 1), timestamp);
+                    return None;
+                }
+
+                self.status = MotorWizardStatus::Verifying;
+                self.set_stage(Stage::VerifySettle, timestamp);
+
+                cfg_if! {
+                    if #[cfg(feature = "quad")] {
+                        println!("Motor wizard: Mapping identified");
+                        return Some(self.mapping());
+                    } else {
+                        self.finish(MotorWizardStatus::Aborted);
+                    }
+                }
+            }
+            Stage::VerifySettle => {
+                if elapsed >= SETTLE_TIME {
+                    let i = self.front_left();
+                    self.spin(motor_test, i);
+                    self.set_stage(Stage::Verify, timestamp);
+                }
+            }
+            Stage::Verify => {
+                let i = self.front_left();
+                if !self.spin_update(motor_test, i, pole_count, elapsed) {
+                    return None;
+                }
+
+                // Output `i` has a slot, since it was identified.
+                if self.responding_slot() == self.slots[i] {
+                    println!("Motor wizard complete");
+                    self.finish(MotorWizardStatus::Complete);
+                } else {
+                    println!("Motor wizard: Front-left motor verification failed");
+                    self.finish(MotorWizardStatus::VerifyFailed);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn to_bytes(&self) -> [u8; MOTOR_WIZARD_STATUS_SIZE] {
+        let mut result = [0; MOTOR_WIZARD_STATUS_SIZE];
+
+        result[0] = self.status as u8;
+        result[1] = match self.stage {
+            Stage::Settle(i) | Stage::Spin(i) | Stage::Answer(i) => i as u8,
+            _ => NONE_VAL,
+        };
+
+        for i in 0..MOTORS.len() {
+            let j = 2 + i * 3;
+            result[j] = self.slots[i].map(|s| s as u8).unwrap_or(NONE_VAL);
+            result[j + 1] = self.corners[i].map(|c| c as u8).unwrap_or(NONE_VAL);
+            result[j + 2] = self.reversed[i] as u8;
+        }
+        result
+    }
+
+    fn start(&mut self, power: f32, dir: RotationDir, mapping: &ControlMapping, timestamp: f32) {
+        let reversed = mapping.motors_reversed();
+
+        *self = Self {
+            status: MotorWizardStatus::Running,
+            power,
+            frontleft_aftright_dir: dir,
+            reversed_at_start: [reversed.0, reversed.1, reversed.2, reversed.3],
+            ..Default::default()
+        };
+        self.set_stage(Stage::Settle(0), timestamp);
+        println!("Motor wizard started");
+    }
+
+    /// Start spinning an output, and reset peak readings.
+    fn spin(&mut self, motor_test: &mut MotorTest, i: usize) {
+        // We stop the motor once `SPIN_TIME` elapses.
+        motor_test.cmd_pending = Some(MotorTestCmd::new(MOTORS[i], self.power, MAX_DURATION));
+        self.peak_rpm = [0.; 4];
+    }
+
+    /// Record RPM from all telemetry slots while output `i` spins. Returns true once the spin is
+    /// complete, and the motor stopped. Aborts if the motor test stopped early.
+    fn spin_update(
+        &mut self,
+        motor_test: &mut MotorTest,
+        i: usize,
+        pole_count: u8,
+        elapsed: f32,
+    ) -> bool {
+        if motor_test.motor_active() != Some(MOTORS[i]) {
+            println!("Motor wizard aborted; motor test stopped");
+            self.finish(MotorWizardStatus::Aborted);
+            return false;
+        }
+
+        for (slot, motor) in MOTORS.iter().enumerate() {
+            if let Some(rpm) = rpm_reception::rpm_from_motor(*motor, pole_count) {
+                self.peak_rpm[slot] = self.peak_rpm[slot].max(rpm);
+            }
+        }
+
+        if elapsed < SPIN_TIME {
+            return false;
+        }
+
+        motor_test.stop_pending = true;
+        true
+    }
+
+    /// The telemetry slot with the highest RPM during the last spin, if any turned.
+    fn responding_slot(&self) -> Option<usize> {
+        let mut result = None;
+        let mut max = MIN_ROTOR_RPM;
+
+        for (slot, rpm) in self.peak_rpm.iter().enumerate() {
+            if *rpm >= max {
+                max = *rpm;
+                result = Some(slot);
+            }
+        }
+        result
+    }
+
+    /// The direction the prop layout requires at a corner.
+    fn dir_for(&self, corner: Corner) -> RotationDir {
+        match (corner, self.frontleft_aftright_dir) {
+            (Corner::FrontLeft | Corner::AftRight, dir) => dir,
+            (_, RotationDir::Clockwise) => RotationDir::CounterClockwise,
+            (_, RotationDir::CounterClockwise) => RotationDir::Clockwise,
+        }
+    }
+
+    /// The output identified as front-left. Only valid once all are identified.
+    fn front_left(&self) -> usize {
+        self.corners
+            .iter()
+            .position(|c| *c == Some(Corner::FrontLeft))
+            .unwrap_or(0)
+    }
+
+    #[cfg(feature = "quad")]
+    fn mapping(&self) -> ControlMapping {
+        // By corner, in `Corner` order.
+        let mut outputs = OUTPUTS;
+        let mut reversed = [false; 4];
+
+        for (i, corner) in self.corners.iter().enumerate() {
+            if let Some(c) = corner {
+                outputs[*c as usize] = OUTPUTS[i];
+                reversed[*c as usize] = self.reversed[i];
+            }
+        }
+
+        ControlMapping {
+            front_left: outputs[0],
+            front_right: outputs[1],
+            aft_left: outputs[2],
+            aft_right: outputs[3],
+            reversed: (reversed[0], reversed[1], reversed[2], reversed[3]),
+            frontleft_aftright_dir: self.frontleft_aftright_dir,
+        }
+    }
+
+    fn finish(&mut self, status: MotorWizardStatus) {
+        self.stage = Stage::Idle;
+        self.status = status;
+        self.answer_pending = None;
+    }
+
+    fn set_stage(&mut self, stage: Stage, timestamp: f32) {
+        self.stage = stage;
+        self.stage_start = timestamp;
+    }
+}
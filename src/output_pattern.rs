@@ -0,0 +1,314 @@
+//! This module contains output test patterns, for checking motors and servos on the bench,
+//! without props, from the PC application. Eg ramping all motors up and back down, stepping
+//! through each motor in order, or sweeping each servo through its range. A script on the PC can
+//! run these in turn, polling status, as a full bench checkout.
+//!
+//! Patterns use the motor test interlocks: Preflight mode, disarmed, props-off acknowledged, the
+//! power cap, and the PC's keep-alive messages; see `motor_test`. `MotorTestStop` stops a pattern
+//! immediately, as does moving any stick, in case the PC has locked up. Patterns are started from
+//! the USB ISR, and run from the main loop.
+
+use cfg_if::cfg_if;
+use defmt::println;
+use num_enum::TryFromPrimitive;
+use num_traits::Float;
+
+use crate::{
+    controller_interface::ChannelData,
+    flight_ctrls::motor_test::{KEEPALIVE_TIMEOUT, MAX_POWER},
+};
+
+cfg_if! {
+    if #[cfg(feature = "fixed-wing")] {
+        use crate::{
+            flight_ctrls::control_mapping::ControlMapping,
+            protocols::servo::{self, ServoCfg},
+            setup::{self, ServoTimer},
+        };
+    }
+}
+
+// Seconds to ramp from 0 to the set power, and back.
+const RAMP_TIME: f32 = 5.;
+// Seconds each motor runs, when stepping through them.
+const STEP_TIME: f32 = 0.5;
+// Seconds to sweep each servo: From center to max, to min, and back to center.
+const SWEEP_TIME: f32 = 4.;
+
+// A stick moving more than this from its position when the pattern started stops it. Stick
+// units; -1. to 1., or 0. to 1. for throttle.
+const STICK_THRESH: f32 = 0.2;
+
+// Motor 1 - 4.
+const NUM_MOTORS: usize = 4;
+// By control surface. Surfaces not present idle through their step.
+pub const NUM_SERVOS: usize = crate::protocols::servo::NUM_SURFACES;
+
+// Pattern, and power (f32).
+pub const OUTPUT_PATTERN_START_SIZE: usize = 1 + 4;
+// Status, pattern, the motor or servo active, and progress in percent.
+pub const OUTPUT_PATTERN_STATUS_SIZE: usize = 4;
+
+// Used in serialized status for no pattern, or no motor or servo active.
+const NONE_VAL: u8 = 0xff;
+
+/// Repr is how it's passed over USB.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Pattern {
+    /// Ramp all motors from 0 to the power set, and back, over `RAMP_TIME`.
+    RampAll = 0,
+    /// Run each motor at the power set for `STEP_TIME`, in order.
+    StepEach = 1,
+    /// Sweep each servo slowly through its range, in order. Fixed-wing only.
+    SweepServos = 2,
+}
+
+impl Pattern {
+    /// Seconds.
+    fn duration(self) -> f32 {
+        match self {
+            Self::RampAll => RAMP_TIME,
+            Self::StepEach => STEP_TIME * NUM_MOTORS as f32,
+            Self::SweepServos => SWEEP_TIME * NUM_SERVOS as f32,
+        }
+    }
+}
+
+/// Reported over USB. Repr is how it's passed.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum PatternStatus {
+    Idle = 0,
+    Running = 1,
+    Complete = 2,
+    /// Stopped from the PC, by arming, or by leaving Preflight.
+    Aborted = 3,
+    /// The PC stopped sending keep-alive messages.
+    KeepaliveTimeout = 4,
+    /// A stick moved.
+    StickMoved = 5,
+}
+
+impl Default for PatternStatus {
+    fn default() -> Self {
+        Self::Idle
+    }
+}
+
+/// What the main loop should send to the outputs.
+pub enum PatternOutput {
+    /// Motor powers, 0. to 1., by output: Motor 1 - 4.
+    Motors([f32; NUM_MOTORS]),
+    /// Servo positions, -1. to 1., by control surface.
+    Servos([f32; NUM_SERVOS]),
+    /// Stop all motors, and center servos.
+    Stop,
+    /// No pattern is running, and there's nothing to stop.
+    Idle,
+}
+
+#[derive(Clone, Copy)]
+struct ActivePattern {
+    pattern: Pattern,
+    /// 0. to 1.
+    power: f32,
+    started_at: f32,
+    last_keepalive: f32,
+    /// Roll, pitch, yaw, and throttle, when we first had channel data during the pattern.
+    sticks: Option<[f32; 4]>,
+}
+
+/// Pattern state. Commands are set from the USB ISR, and handled in the main loop, which tracks
+/// timing.
+#[derive(Default)]
+pub struct OutputPattern {
+    start_pending: Option<(Pattern, f32)>,
+    pub stop_pending: bool,
+    pub keepalive_pending: bool,
+    active: Option<ActivePattern>,
+    /// The pattern running, or last run.
+    pattern: Option<Pattern>,
+    status: PatternStatus,
+    /// The motor or servo active, if the pattern runs them in turn.
+    step: Option<usize>,
+    /// 0. to 1.
+    progress: f32,
+}
+
+impl OutputPattern {
+    /// Request a start, from the USB ISR. Check the motor test interlocks before calling. Power
+    /// is clamped to the motor test's cap.
+    pub fn request_start(&mut self, pattern: Pattern, power: f32) {
+        // Treat invalid values as 0.
+        let power = if power.is_nan() {
+            0.
+        } else {
+            power.clamp(0., MAX_POWER)
+        };
+
+        self.start_pending = Some((pattern, power));
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.active.is_some() || self.start_pending.is_some()
+    }
+
+    /// Stop, eg on leaving Preflight, or arming. Outputs are stopped by the caller.
+    pub fn cancel(&mut self) {
+        self.start_pending = None;
+        if self.active.is_some() {
+            println!("Output pattern aborted");
+            self.finish(PatternStatus::Aborted);
+        }
+    }
+
+    /// Start, continue, or stop a pattern. Run this regularly from the main loop, while in
+    /// Preflight mode, and disarmed. `ch_data` is `None` if there's no receiver. Timestamp is in
+    /// seconds.
+    pub fn update(&mut self, ch_data: Option<&ChannelData>, timestamp: f32) -> PatternOutput {
+        if self.stop_pending {
+            self.stop_pending = false;
+            self.start_pending = None;
+
+            if self.active.is_some() {
+                println!("Output pattern stopped");
+                self.finish(PatternStatus::Aborted);
+                return PatternOutput::Stop;
+            }
+        }
+
+        if let Some((pattern, power)) = self.start_pending.take() {
+            self.active = Some(ActivePattern {
+                pattern,
+                power,
+                started_at: timestamp,
+                last_keepalive: timestamp,
+                sticks: None,
+            });
+            self.pattern = Some(pattern);
+            self.status = PatternStatus::Running;
+            self.keepalive_pending = false;
+            println!("Output pattern {} started. Power: {}", pattern as u8, power);
+        }
+
+        let Some(mut active) = self.active else {
+            return PatternOutput::Idle;
+        };
+
+        if self.keepalive_pending {
+            self.keepalive_pending = false;
+            active.last_keepalive = timestamp;
+        }
+
+        if timestamp - active.last_keepalive > KEEPALIVE_TIMEOUT {
+            println!("Output pattern stopped; keep-alive timed out");
+            self.finish(PatternStatus::KeepaliveTimeout);
+            return PatternOutput::Stop;
+        }
+
+        if let Some(ch) = ch_data {
+            let sticks = [ch.roll, ch.pitch, ch.yaw, ch.throttle];
+
+            match active.sticks {
+                Some(start) => {
+                    if sticks
+                        .iter()
+                        .zip(start)
+                        .any(|(v, v_start)| (v - v_start).abs() > STICK_THRESH)
+                    {
+                        println!("Output pattern stopped; stick moved");
+                        self.finish(PatternStatus::StickMoved);
+                        return PatternOutput::Stop;
+                    }
+                }
+                None => active.sticks = Some(sticks),
+            }
+        }
+
+        self.active = Some(active);
+
+        let elapsed = timestamp - active.started_at;
+        let duration = active.pattern.duration();
+
+        if elapsed >= duration {
+            println!("Output pattern complete");
+            self.progress = 1.;
+            self.finish(PatternStatus::Complete);
+            return PatternOutput::Stop;
+        }
+
+        self.progress = elapsed / duration;
+
+        match active.pattern {
+            Pattern::RampAll => {
+                let half = RAMP_TIME / 2.;
+                let power = active.power * (1. - (elapsed - half).abs() / half);
+
+                self.step = None;
+                PatternOutput::Motors([power; NUM_MOTORS])
+            }
+            Pattern::StepEach => {
+                let i = ((elapsed / STEP_TIME) as usize).min(NUM_MOTORS - 1);
+
+                let mut powers = [0.; NUM_MOTORS];
+                powers[i] = active.power;
+
+                self.step = Some(i);
+                PatternOutput::Motors(powers)
+            }
+            Pattern::SweepServos => {
+                let i = ((elapsed / SWEEP_TIME) as usize).min(NUM_SERVOS - 1);
+                let phase = (elapsed - i as f32 * SWEEP_TIME) / SWEEP_TIME;
+
+                // A triangle wave: 0 to 1, to -1, and back to 0.
+                let posit = if phase < 0.25 {
+                    4. * phase
+                } else if phase < 0.75 {
+                    2. - 4. * phase
+                } else {
+                    4. * phase - 4.
+                };
+
+                let mut posits = [0.; NUM_SERVOS];
+                posits[i] = posit;
+
+                self.step = Some(i);
+                PatternOutput::Servos(posits)
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; OUTPUT_PATTERN_STATUS_SIZE] {
+        [
+            self.status as u8,
+            self.pattern.map(|p| p as u8).unwrap_or(NONE_VAL),
+            self.step.map(|s| s as u8).unwrap_or(NONE_VAL),
+            (self.progress * 100.) as u8,
+        ]
+    }
+
+    fn finish(&mut self, status: PatternStatus) {
+        self.active = None;
+        self.status = status;
+        self.step = None;
+        self.keepalive_pending = false;
+    }
+}
+
+/// Set servo positions from a pattern, -1. to 1. Center them with `[0.; NUM_SERVOS]`.
+#[cfg(feature = "fixed-wing")]
+pub fn set_servos(
+    posits: [f32; NUM_SERVOS],
+    mapping: &ControlMapping,
+    servo_cfg: &ServoCfg,
+    timer: &mut ServoTimer,
+) {
+    for (i, posit) in posits.iter().enumerate() {
+        let Some(channel) = mapping.surfaces[i].and_then(setup::servo_channel) else {
+            continue;
+        };
+
+        servo::set_posit(*posit, &servo_cfg.pulses[i], timer, channel);
+    }
+}
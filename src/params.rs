@@ -0,0 +1,393 @@
+//! This module contains the parameter dictionary: A table of tunable user config values, each with
+//! a stable ID, name, type, range, and whether it's flight-critical. The PC application uses it to
+//! list, read, and write parameters generically over USB, and to back up and restore them, vice
+//! each field needing its own messages.
+//!
+//! The table is defined in `state`, next to `UserConfig`, with `param_table!`. Add an entry there
+//! when adding a tunable field. IDs are stable across firmware versions; never reuse or renumber
+//! one. Values are passed as f32, including integer and boolean parameters.
+//!
+//! Writes are range-checked, and flight-critical parameters can't be written while armed. A
+//! successful write marks the config dirty; it's saved to flash from the idle task, once disarmed.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use num_traits::Float;
+
+use crate::{
+    gps_metrics::SpeedUnits,
+    imu_processing::dual_imu::ImuId,
+    rth::{RthAltMode, RthApproach, RthClimb},
+    state::UserConfig,
+};
+
+pub use crate::state::{NUM_PARAMS, PARAMS};
+
+/// Names longer than this fail to compile.
+pub const PARAM_NAME_LEN: usize = 16;
+
+// Parameter count, index, ID, type, and flags; then min, max, default, and value; then the name,
+// padded with 0s.
+pub const PARAM_INFO_SIZE: usize = 2 + 2 + 2 + 1 + 1 + 4 * 4 + PARAM_NAME_LEN;
+// ID, and value.
+pub const PARAM_SET_SIZE: usize = 2 + 4;
+// ID, status, and value.
+pub const PARAM_VALUE_SIZE: usize = 2 + 1 + 4;
+// Count, then ID and value for each parameter.
+pub const PARAMS_EXPORT_SIZE: usize = 2 + NUM_PARAMS * PARAM_SET_SIZE;
+// Parameters applied, and rejected.
+pub const PARAMS_IMPORT_RESULT_SIZE: usize = 2 + 2;
+
+// Used in serialized info for an index past the end of the table.
+const NONE_ID: u16 = 0xffff;
+
+// Flag bits in serialized info.
+const FLAG_CRITICAL: u8 = 1;
+
+// Set when a write succeeds; cleared when the idle task saves.
+static CFG_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// How a parameter's value is stored. Repr is how it's passed over USB.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ParamType {
+    F32 = 0,
+    U8 = 1,
+    Bool = 2,
+}
+
+/// The result of a read or write. Repr is how it's passed over USB.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ParamStatus {
+    Ok = 0,
+    UnknownId = 1,
+    /// Outside the parameter's range, or not a whole number for integer and boolean types.
+    OutOfRange = 2,
+    /// The parameter is flight-critical, and we're armed.
+    Armed = 3,
+}
+
+/// Conversion between a config field's type, and the f32 values passed over USB. Values are
+/// range-checked before `from_f32` is called.
+pub trait ParamValue: Copy {
+    const KIND: ParamType;
+
+    fn to_f32(self) -> f32;
+
+    fn from_f32(val: f32) -> Self;
+}
+
+impl ParamValue for f32 {
+    const KIND: ParamType = ParamType::F32;
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(val: f32) -> Self {
+        val
+    }
+}
+
+impl ParamValue for u8 {
+    const KIND: ParamType = ParamType::U8;
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        val as u8
+    }
+}
+
+impl ParamValue for bool {
+    const KIND: ParamType = ParamType::Bool;
+
+    fn to_f32(self) -> f32 {
+        self as u8 as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        val != 0.
+    }
+}
+
+impl ParamValue for SpeedUnits {
+    const KIND: ParamType = ParamType::U8;
+
+    fn to_f32(self) -> f32 {
+        self as u8 as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        Self::try_from(val as u8).unwrap_or_default()
+    }
+}
+
+impl ParamValue for RthAltMode {
+    const KIND: ParamType = ParamType::U8;
+
+    fn to_f32(self) -> f32 {
+        self as u8 as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        Self::try_from(val as u8).unwrap_or_default()
+    }
+}
+
+impl ParamValue for RthClimb {
+    const KIND: ParamType = ParamType::U8;
+
+    fn to_f32(self) -> f32 {
+        self as u8 as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        Self::try_from(val as u8).unwrap_or_default()
+    }
+}
+
+impl ParamValue for RthApproach {
+    const KIND: ParamType = ParamType::U8;
+
+    fn to_f32(self) -> f32 {
+        self as u8 as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        Self::try_from(val as u8).unwrap_or_default()
+    }
+}
+
+impl ParamValue for ImuId {
+    const KIND: ParamType = ParamType::U8;
+
+    fn to_f32(self) -> f32 {
+        self as u8 as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        Self::try_from(val as u8).unwrap_or_default()
+    }
+}
+
+/// A table entry. Generated by `param_table!`.
+pub struct ParamDef {
+    pub id: u16,
+    pub name: &'static str,
+    pub kind: ParamType,
+    pub min: f32,
+    pub max: f32,
+    /// Can't be written while armed.
+    pub critical: bool,
+    pub get: fn(&UserConfig) -> f32,
+    pub set: fn(&mut UserConfig, f32),
+}
+
+impl ParamDef {
+    /// Validate a value, and write it to the config.
+    fn write(&self, cfg: &mut UserConfig, val: f32, armed: bool) -> ParamStatus {
+        if self.critical && armed {
+            return ParamStatus::Armed;
+        }
+
+        // This comparison also rejects NaN.
+        if !(self.min..=self.max).contains(&val)
+            || (self.kind != ParamType::F32 && val.fract() != 0.)
+        {
+            return ParamStatus::OutOfRange;
+        }
+
+        (self.set)(cfg, val);
+        ParamStatus::Ok
+    }
+}
+
+/// Used by `param_table!` to infer a parameter's type from its field.
+pub const fn kind_of<T: ParamValue>(_get: fn(&UserConfig) -> T) -> ParamType {
+    T::KIND
+}
+
+/// Used by `param_table!` to check IDs at compile time.
+pub const fn ids_unique(ids: &[u16]) -> bool {
+    // Can't use for loops in const fns
+    let mut i = 0;
+    while i < ids.len() {
+        let mut j = i + 1;
+        while j < ids.len() {
+            if ids[i] == ids[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Define the parameter table, as `PARAMS`, and its length, as `NUM_PARAMS`. Each entry is
+/// `id: "name", field.path, min, max, critical;`. The type is inferred from the field. IDs must
+/// be unique, and names at most `PARAM_NAME_LEN` bytes; both are checked at compile time.
+macro_rules! param_table {
+    ($($id:literal: $name:literal, $($field:ident).+, $min:expr, $max:expr, $critical:expr;)*) => {
+        pub const NUM_PARAMS: usize = [$($id),*].len();
+
+        const _: () = {
+            assert!(
+                crate::params::ids_unique(&[$($id),*]),
+                "Parameter IDs must be unique"
+            );
+            $(assert!(
+                $name.len() <= crate::params::PARAM_NAME_LEN,
+                "Parameter name too long"
+            );)*
+        };
+
+        pub static PARAMS: [crate::params::ParamDef; NUM_PARAMS] = [$(
+            crate::params::ParamDef {
+                id: $id,
+                name: $name,
+                kind: crate::params::kind_of(|cfg| cfg.$($field).+),
+                min: $min,
+                max: $max,
+                critical: $critical,
+                get: |cfg| crate::params::ParamValue::to_f32(cfg.$($field).+),
+                set: |cfg, val| cfg.$($field).+ = crate::params::ParamValue::from_f32(val),
+            },
+        )*];
+    };
+}
+
+pub(crate) use param_table;
+
+fn find(id: u16) -> Option<&'static ParamDef> {
+    PARAMS.iter().find(|p| p.id == id)
+}
+
+/// Run from the idle task. Returns `true` if the config should be saved.
+pub fn take_cfg_dirty() -> bool {
+    CFG_DIRTY.swap(false, Ordering::AcqRel)
+}
+
+/// A parameter's description, and current value, by table index; for listing them. Past the end
+/// of the table, only the count is set, and the ID is 0xffff.
+pub fn info_to_bytes(i: u16, cfg: &UserConfig) -> [u8; PARAM_INFO_SIZE] {
+    let mut result = [0; PARAM_INFO_SIZE];
+    result[0..2].clone_from_slice(&(NUM_PARAMS as u16).to_be_bytes());
+    result[2..4].clone_from_slice(&i.to_be_bytes());
+
+    let Some(param) = PARAMS.get(i as usize) else {
+        result[4..6].clone_from_slice(&NONE_ID.to_be_bytes());
+        return result;
+    };
+
+    result[4..6].clone_from_slice(&param.id.to_be_bytes());
+    result[6] = param.kind as u8;
+    result[7] = if param.critical { FLAG_CRITICAL } else { 0 };
+
+    let default = (param.get)(&UserConfig::default());
+
+    for (j, v) in [param.min, param.max, default, (param.get)(cfg)]
+        .iter()
+        .enumerate()
+    {
+        result[8 + j * 4..12 + j * 4].clone_from_slice(&v.to_be_bytes());
+    }
+
+    let name = param.name.as_bytes();
+    result[24..24 + name.len()].clone_from_slice(name);
+
+    result
+}
+
+/// Read a parameter, by ID. The payload is its ID (u16).
+pub fn read(payload: &[u8], cfg: &UserConfig) -> [u8; PARAM_VALUE_SIZE] {
+    let id = u16::from_be_bytes(payload[0..2].try_into().unwrap());
+
+    match find(id) {
+        Some(param) => value_to_bytes(id, ParamStatus::Ok, (param.get)(cfg)),
+        None => value_to_bytes(id, ParamStatus::UnknownId, 0.),
+    }
+}
+
+/// Write a parameter, by ID. The payload is its ID (u16), and value. Returns the status, and the
+/// value after the write; unchanged if it failed.
+pub fn write(payload: &[u8], cfg: &mut UserConfig, armed: bool) -> [u8; PARAM_VALUE_SIZE] {
+    let id = u16::from_be_bytes(payload[0..2].try_into().unwrap());
+    let val = f32::from_be_bytes(payload[2..6].try_into().unwrap());
+
+    let Some(param) = find(id) else {
+        return value_to_bytes(id, ParamStatus::UnknownId, 0.);
+    };
+
+    let status = param.write(cfg, val, armed);
+    if status == ParamStatus::Ok {
+        CFG_DIRTY.store(true, Ordering::Release);
+    }
+
+    value_to_bytes(id, status, (param.get)(cfg))
+}
+
+/// All parameter values, for backup.
+pub fn export(cfg: &UserConfig) -> [u8; PARAMS_EXPORT_SIZE] {
+    let mut result = [0; PARAMS_EXPORT_SIZE];
+    result[0..2].clone_from_slice(&(NUM_PARAMS as u16).to_be_bytes());
+
+    for (i, param) in PARAMS.iter().enumerate() {
+        let start = 2 + i * PARAM_SET_SIZE;
+        result[start..start + 2].clone_from_slice(&param.id.to_be_bytes());
+        result[start + 2..start + 6].clone_from_slice(&(param.get)(cfg).to_be_bytes());
+    }
+
+    result
+}
+
+/// Restore values from a backup, in the format of `export`. Each is validated as in `write`;
+/// invalid ones, and unknown IDs, eg from a different firmware version, are skipped. Returns the
+/// count applied, and rejected.
+pub fn import(
+    payload: &[u8],
+    cfg: &mut UserConfig,
+    armed: bool,
+) -> [u8; PARAMS_IMPORT_RESULT_SIZE] {
+    let count = (u16::from_be_bytes(payload[0..2].try_into().unwrap()) as usize).min(NUM_PARAMS);
+
+    let mut applied: u16 = 0;
+    let mut rejected: u16 = 0;
+
+    for entry in payload[2..2 + count * PARAM_SET_SIZE].chunks_exact(PARAM_SET_SIZE) {
+        let id = u16::from_be_bytes(entry[0..2].try_into().unwrap());
+        let val = f32::from_be_bytes(entry[2..6].try_into().unwrap());
+
+        let status = match find(id) {
+            Some(param) => param.write(cfg, val, armed),
+            None => ParamStatus::UnknownId,
+        };
+
+        if status == ParamStatus::Ok {
+            applied += 1;
+        } else {
+            rejected += 1;
+        }
+    }
+
+    if applied > 0 {
+        CFG_DIRTY.store(true, Ordering::Release);
+    }
+
+    let mut result = [0; PARAMS_IMPORT_RESULT_SIZE];
+    result[0..2].clone_from_slice(&applied.to_be_bytes());
+    result[2..4].clone_from_slice(&rejected.to_be_bytes());
+    result
+}
+
+fn value_to_bytes(id: u16, status: ParamStatus, val: f32) -> [u8; PARAM_VALUE_SIZE] {
+    let mut result = [0; PARAM_VALUE_SIZE];
+    result[0..2].clone_from_slice(&id.to_be_bytes());
+    result[2] = status as u8;
+    result[3..7].clone_from_slice(&val.to_be_bytes());
+    result
+}
@@ -0,0 +1,222 @@
+//! This module contains performance monitoring of the MCU: Core temperature, and the execution
+//! time, inter-arrival jitter, and latency of the IMU ISR. Timing uses `util::monotonic`.
+//!
+//! Latency is from the IMU's data-ready interrupt to the start of the IMU TC ISR. It includes the
+//! SPI read, which is constant; variation, and the worst case, come from the IMU TC ISR being
+//! blocked, eg by a lock ceiling held by lower-priority code.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use cfg_if::cfg_if;
+use hal::{adc::Adc, pac};
+
+use crate::{board_config::MCU_TEMP_ADC_CH, loop_rates, util::monotonic, MCU_TEMP_ADC};
+
+// We compute timing stats over windows of this duration, in seconds.
+const WINDOW_TIME: f32 = 1.;
+
+/// If the IMU ISR's execution time exceeds its period more than this many times in a window
+/// (~1s), we set the overrun flag in `SystemStatus`.
+pub const MAX_OVERRUNS_PER_WINDOW: u32 = 8;
+
+// Microseconds since start, truncated to 32 bits, when the IMU last signaled data ready.
+static IMU_READY_US: AtomicU32 = AtomicU32::new(0);
+
+// Set once a temperature sensor conversion has been started, so there's a result to read.
+static TEMP_CONVERSION_STARTED: AtomicBool = AtomicBool::new(false);
+
+cfg_if! {
+    if #[cfg(feature = "h7")] {
+        // Factory calibration values for the internal temperature sensor, measured at these
+        // temperatures (°C), with VDDA = 3.3V, at 16-bit resolution. RM0468, section 26.4.32.
+        const TS_CAL1_ADDR: u32 = 0x1FF1_E820;
+        const TS_CAL2_ADDR: u32 = 0x1FF1_E840;
+        const TS_CAL1_TEMP: f32 = 30.;
+        const TS_CAL2_TEMP: f32 = 110.;
+        const TS_CAL_VDDA: f32 = 3.3;
+        const TS_CAL_FULLSCALE: f32 = 65_535.;
+    } else {
+        // Factory calibration values for the internal temperature sensor, measured at these
+        // temperatures (°C), with VDDA = 3.0V, at 12-bit resolution. DS12288, Table 6.
+        const TS_CAL1_ADDR: u32 = 0x1FFF_75A8;
+        const TS_CAL2_ADDR: u32 = 0x1FFF_75CA;
+        const TS_CAL1_TEMP: f32 = 30.;
+        const TS_CAL2_TEMP: f32 = 130.;
+        const TS_CAL_VDDA: f32 = 3.0;
+        const TS_CAL_FULLSCALE: f32 = 4_095.;
+    }
+}
+
+/// Connect the internal temperature sensor to its ADC channel. On G4, it's on ADC1; on H7, ADC3.
+pub fn enable_temp_sensor() {
+    unsafe {
+        cfg_if! {
+            if #[cfg(feature = "h7")] {
+                (*pac::ADC3_COMMON::ptr()).ccr.modify(|_, w| w.vsenseen().set_bit());
+            } else {
+                (*pac::ADC12_COMMON::ptr()).ccr.modify(|_, w| w.vsensesel().set_bit());
+            }
+        }
+    }
+}
+
+/// Convert the voltage read on the internal temperature sensor channel to °C, using the factory
+/// calibration points.
+pub fn mcu_temp_from_voltage(v: f32) -> f32 {
+    let (cal1, cal2) = unsafe {
+        (
+            core::ptr::read_volatile(TS_CAL1_ADDR as *const u16),
+            core::ptr::read_volatile(TS_CAL2_ADDR as *const u16),
+        )
+    };
+
+    let v_cal1 = cal1 as f32 / TS_CAL_FULLSCALE * TS_CAL_VDDA;
+    let v_cal2 = cal2 as f32 / TS_CAL_FULLSCALE * TS_CAL_VDDA;
+
+    TS_CAL1_TEMP + (v - v_cal1) * (TS_CAL2_TEMP - TS_CAL1_TEMP) / (v_cal2 - v_cal1)
+}
+
+/// Read the temperature sensor conversion started by the previous call, if any, in °C; then start
+/// the next one. Run from the main loop at a low rate: The conversion completes long before the
+/// next call, so this doesn't block.
+pub fn sample_mcu_temp(adc: &mut Adc<MCU_TEMP_ADC>) -> Option<f32> {
+    let result = if TEMP_CONVERSION_STARTED.load(Ordering::Relaxed) {
+        let raw = adc.read_result();
+        Some(mcu_temp_from_voltage(adc.reading_to_voltage(raw)))
+    } else {
+        None
+    };
+
+    adc.start_conversion(&[MCU_TEMP_ADC_CH]);
+    TEMP_CONVERSION_STARTED.store(true, Ordering::Relaxed);
+
+    result
+}
+
+/// Run in the IMU data-ready ISR.
+pub fn mark_imu_ready() {
+    IMU_READY_US.store(monotonic::now_us() as u32, Ordering::Relaxed);
+}
+
+/// Seconds since the latest `mark_imu_ready`. Run at the start of the IMU TC ISR.
+pub fn imu_ready_latency() -> f32 {
+    let ready = IMU_READY_US.load(Ordering::Relaxed);
+
+    // Truncation to 32 bits may have wrapped since data ready.
+    let us = (monotonic::now_us() as u32).wrapping_sub(ready);
+
+    monotonic::to_secs(us as u64)
+}
+
+/// Min, max, and mean of a duration over a window. Seconds.
+#[derive(Clone, Copy, Default)]
+pub struct TimingStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+/// Accumulates a duration over the current window.
+#[derive(Clone, Copy)]
+struct TimingAccum {
+    min: f32,
+    max: f32,
+    sum: f32,
+}
+
+impl Default for TimingAccum {
+    fn default() -> Self {
+        Self {
+            min: f32::MAX,
+            max: 0.,
+            sum: 0.,
+        }
+    }
+}
+
+impl TimingAccum {
+    fn add(&mut self, val: f32) {
+        self.min = self.min.min(val);
+        self.max = self.max.max(val);
+        self.sum += val;
+    }
+
+    fn stats(&self, count: u32) -> TimingStats {
+        TimingStats {
+            min: self.min,
+            max: self.max,
+            mean: self.sum / count as f32,
+        }
+    }
+}
+
+/// MCU performance stats. Timing stats are from the most recent complete window.
+#[derive(Default)]
+pub struct PerfStats {
+    /// MCU core temperature, in °C. `None` until the first reading; see `sample_mcu_temp`.
+    pub mcu_temp: Option<f32>,
+    /// IMU ISR execution time.
+    pub isr_exec: TimingStats,
+    /// Time between the starts of consecutive IMU ISRs; deviation from the IMU period is jitter.
+    pub isr_interval: TimingStats,
+    /// Time from IMU data ready to the start of the IMU ISR. See the module doc.
+    pub isr_latency: TimingStats,
+    /// Number of IMU ISRs in the last window whose execution time exceeded the IMU period.
+    pub overruns_last_window: u32,
+    /// Total overruns since power-on.
+    pub num_overruns: u32,
+    exec_accum: TimingAccum,
+    interval_accum: TimingAccum,
+    latency_accum: TimingAccum,
+    overruns_this_window: u32,
+    window_i: u32,
+    prev_isr_start: Option<f32>,
+}
+
+impl PerfStats {
+    /// Log timing of an IMU ISR. Timestamps and latency are in seconds. Run this at the end of
+    /// each IMU update.
+    pub fn update_isr_timing(&mut self, isr_start: f32, isr_end: f32, latency: f32) {
+        let exec_time = isr_end - isr_start;
+
+        self.exec_accum.add(exec_time);
+        self.latency_accum.add(latency);
+        if let Some(prev) = self.prev_isr_start {
+            self.interval_accum.add(isr_start - prev);
+        }
+        self.prev_isr_start = Some(isr_start);
+
+        let rates = loop_rates::rates();
+
+        if exec_time > rates.dt_imu {
+            self.overruns_this_window += 1;
+            self.num_overruns += 1;
+        }
+
+        self.window_i += 1;
+        if self.window_i >= rates.imu_updates(WINDOW_TIME) {
+            self.isr_exec = self.exec_accum.stats(self.window_i);
+            self.isr_interval = self.interval_accum.stats(self.window_i);
+            self.isr_latency = self.latency_accum.stats(self.window_i);
+            self.overruns_last_window = self.overruns_this_window;
+
+            self.exec_accum = Default::default();
+            self.interval_accum = Default::default();
+            self.latency_accum = Default::default();
+            self.overruns_this_window = 0;
+            self.window_i = 0;
+        }
+    }
+
+    /// True if the IMU ISR is overrunning its budget too often.
+    pub fn overrunning(&self) -> bool {
+        self.overruns_last_window > MAX_OVERRUNS_PER_WINDOW
+    }
+
+    /// True if the mean IMU interval over the last window doesn't match the configured IMU rate; eg
+    /// the IMU's ODR register doesn't match config, or its clock is off. Filter and control
+    /// time steps would be wrong. False until the first window completes.
+    pub fn imu_rate_mismatch(&self) -> bool {
+        self.isr_interval.mean > 0. && !loop_rates::rates().imu_interval_ok(self.isr_interval.mean)
+    }
+}
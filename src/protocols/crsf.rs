@@ -22,21 +22,43 @@
 //!
 //! Note that there doesn't appear to be a published spec, so we piece together what we can from
 //! code and wisdom from those who've done this before.
+//!
+//! We receive with continuous circular DMA into a ring buffer, and parse it from the main loop.
+//! The receiver may send frames back-to-back, eg link stats, then channel data, without a line-idle
+//! gap, and frames may be split across our reads; so, we don't rely on where DMA or the line pauses
+//! to find frame boundaries. Instead, `FrameParser` scans the byte stream for a valid address, length,
+//! and CRC, extracts every complete frame, and resynchronizes after garbage.
+//!
+//! We send telemetry (battery, attitude, GPS, and flight mode) back to the transmitter. The receiver
+//! expects replies between the frames it sends us, so we only start a transmission from the line-idle
+//! interrupt that ends a received frame.
+//!
+//! We accept follow-me targets from the ground station as MSP write frames. See `follow_me`.
 
-use core::sync::atomic::AtomicBool;
+use core::{
+    f32::consts::{PI, TAU},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
+use cfg_if::cfg_if;
 use defmt::println;
-use hal::{dma::DmaChannel, usart::UsartInterrupt};
+use hal::{
+    dma::{self, ChannelCfg},
+    pac::DMA1,
+    usart::UsartInterrupt,
+};
 use num_enum::TryFromPrimitive; // Enum from integer
 
-use crate::util;
+use crate::{
+    drivers::gps_ublox::GpsFix,
+    flight_ctrls::follow_me::{FollowTarget, FOLLOW_TARGET_SIZE},
+    protocols::msp,
+    setup::{self, DmaTransfer},
+    util,
+};
 
 // For the receiver, 420k baud is hard set.
 pub const BAUD: u32 = 420_000;
-//
-// This buf shift allows us to read messages that we didn't start reading immediately.
-// Note that the most we generally see is 3, but we use a higher value conservatively.
-const MAX_BUF_SHIFT: usize = 1;
 
 const CRC_POLY: u8 = 0xd5;
 const CRC_LUT: [u8; 256] = util::crc_init(CRC_POLY);
@@ -46,28 +68,51 @@ pub const CHANNEL_VAL_MAX: u16 = 1_811;
 pub const CHANNEL_VAL_MIN_F32: f32 = 172.;
 pub const CHANNEL_VAL_MAX_F32: f32 = 1_811.;
 
+pub const NUM_CHANNELS: usize = 16;
+
 // Used both both TX and RX buffers. Includes payload, and other data words.
 // Note that for receiving channel data, we use 26 bytes total (22 of which are channel data).
 
 const PAYLOAD_SIZE_LINK_STATS: usize = 10;
-const PAYLOAD_SIZE_RC_CHANNELS: usize = 22;
+pub const PAYLOAD_SIZE_RC_CHANNELS: usize = 22;
 
 // Note: 64 bytes is allowed per the protocol. Lower this to reduce latency and mem use. (Minor concern)
 // - We only expect 26-byte packets for channel data, and 14-byte packets for link stats.
 const MAX_PAYLOAD_SIZE: usize = PAYLOAD_SIZE_RC_CHANNELS;
 const MAX_PACKET_SIZE: usize = MAX_PAYLOAD_SIZE + 4; // Extra 4: dest, size, frametype, CRC.
 
-// A pad allows lags in reading to not overwrite the packet start with a new message.
-const RX_BUF_SIZE: usize = MAX_PACKET_SIZE + MAX_BUF_SHIFT;
+// The largest frame the protocol allows, and the smallest: Dest, size, frame type, and CRC.
+const FRAME_SIZE_MAX: usize = 64;
+const FRAME_SIZE_MIN: usize = 4;
+
+// At 420k baud, this holds about 3ms of data. We parse it at the IMU rate, so it won't overrun.
+const RX_BUF_SIZE: usize = 128;
 
-pub static mut RX_BUFFER: [u8; RX_BUF_SIZE] = [0; RX_BUF_SIZE];
+// Written continuously by circular DMA, and read by the main loop.
+static mut RX_BUFFER: [u8; RX_BUF_SIZE] = [0; RX_BUF_SIZE];
 
-static mut _TX_BUFFER: [u8; MAX_PACKET_SIZE] = [0; MAX_PACKET_SIZE];
+// Accessed only from the main loop.
+static mut RX_PARSER: FrameParser = FrameParser::new();
 
-pub static TRANSFER_IN_PROG: AtomicBool = AtomicBool::new(false);
+// Telemetry payload sizes.
+const PAYLOAD_SIZE_GPS: usize = 15;
+const PAYLOAD_SIZE_BATTERY: usize = 8;
+const PAYLOAD_SIZE_ATTITUDE: usize = 6;
+// Flight mode strings are null-terminated; this includes the null.
+const PAYLOAD_SIZE_FLIGHT_MODE_MAX: usize = 16;
 
-// Used to determine if we have a new packet we haven't yet parsed.
-pub static NEW_PACKET_RECEIVED: AtomicBool = AtomicBool::new(false);
+// In the MSP status byte, this bit marks the first chunk of a message.
+const MSP_STATUS_START: u8 = 0x10;
+
+// Written by the main loop, and read by DMA, started in the CRSF ISR.
+static mut TX_BUFFER: [u8; MAX_PACKET_SIZE] = [0; MAX_PACKET_SIZE];
+
+// Set when `TX_BUFFER` contains a telemetry frame that hasn't been sent, with its length. The main
+// loop only writes the buffer when this is clear. Cleared when the transfer completes.
+pub static TELEM_PENDING: AtomicBool = AtomicBool::new(false);
+static TELEM_LEN: AtomicUsize = AtomicUsize::new(0);
+// Set while DMA is transmitting `TX_BUFFER`, so we don't restart it.
+static TELEM_SENDING: AtomicBool = AtomicBool::new(false);
 
 // "All packets are in the CRSF format [dest] [len] [type] [payload] [crc8]"
 
@@ -96,6 +141,30 @@ pub struct ChannelDataCrsf {
     pub aux_12: u16,
 }
 
+impl ChannelDataCrsf {
+    /// All channels, in order; the 4 stick channels, then the aux channels.
+    pub fn to_array(&self) -> [u16; NUM_CHANNELS] {
+        [
+            self.channel_1,
+            self.channel_2,
+            self.channel_3,
+            self.channel_4,
+            self.aux_1,
+            self.aux_2,
+            self.aux_3,
+            self.aux_4,
+            self.aux_5,
+            self.aux_6,
+            self.aux_7,
+            self.aux_8,
+            self.aux_9,
+            self.aux_10,
+            self.aux_11,
+            self.aux_12,
+        ]
+    }
+}
+
 #[derive(Default)]
 /// [ELRS document describing the CRSF protocol](https://www.expresslrs.org/3.0/info/signal-health/)
 pub struct LinkStats {
@@ -210,20 +279,139 @@ enum FrameType {
 pub enum PacketData {
     ChannelData(ChannelDataCrsf),
     LinkStats(LinkStats),
+    FollowTarget(FollowTarget),
 }
 
-/// Configure the Char match and idle interrupts, which will allow the initial UART ISR to run
-/// upon receiving data. Run this once, on initial firmware setup.
-/// We alternate between char matching the flight controller destination address, and
-/// line idle, to indicate we're received, or stopped receiving a message respectively.
+/// Start continuous reception into the ring buffer, and enable the idle interrupt, which marks the
+/// gaps between received frames, where we send telemetry. Run this once, on initial firmware setup.
 pub fn setup(uart: &mut crate::setup::UartCrsf) {
-    uart.enable_interrupt(UsartInterrupt::CharDetect(Some(
-        DestAddr::FlightController as u8,
-    )));
+    unsafe {
+        uart.read_dma(
+            &mut RX_BUFFER,
+            setup::CRSF_RX_CH,
+            ChannelCfg {
+                circular: dma::Circular::Enabled,
+                ..setup::dma_cfg(DmaTransfer::CrsfRx)
+            },
+            setup::CRSF_DMA_PERIPH,
+        );
+    }
 
     uart.enable_interrupt(UsartInterrupt::Idle);
 }
 
+/// The index in `RX_BUFFER` that DMA writes next, from its remaining transfer count. Note that
+/// this assumes `setup::CRSF_DMA_PERIPH` is DMA1, and on G4, that `setup::CRSF_RX_CH` is channel 5.
+fn rx_write_i() -> usize {
+    let regs = unsafe { &(*DMA1::ptr()) };
+
+    cfg_if! {
+        if #[cfg(feature = "h7")] {
+            let remaining = regs.st[setup::CRSF_RX_CH as usize].ndtr.read().ndt().bits() as usize;
+        } else {
+            let remaining = regs.cndtr5.read().ndt().bits() as usize;
+        }
+    }
+
+    // The count reloads to the buffer size at wrap, so 0 isn't normally read; this handles it if so.
+    (RX_BUF_SIZE - remaining) % RX_BUF_SIZE
+}
+
+/// Extracts frames from the received byte stream, wherever DMA reads or line-idle gaps split
+/// it. Bytes are copied from the ring buffer to `pending`, and complete frames are removed from its
+/// start. On an invalid length or CRC, we drop a byte, and search for the next address byte.
+struct FrameParser {
+    /// The next index to read from `RX_BUFFER`.
+    read_i: usize,
+    pending: [u8; FRAME_SIZE_MAX],
+    pending_len: usize,
+}
+
+impl FrameParser {
+    const fn new() -> Self {
+        Self {
+            read_i: 0,
+            pending: [0; FRAME_SIZE_MAX],
+            pending_len: 0,
+        }
+    }
+
+    /// Copy received bytes from the ring buffer, up to `write_i`, until `pending` is full.
+    fn fill(&mut self, ring: &[u8], write_i: usize) {
+        while self.pending_len < FRAME_SIZE_MAX && self.read_i != write_i {
+            self.pending[self.pending_len] = ring[self.read_i];
+            self.pending_len += 1;
+            self.read_i = (self.read_i + 1) % ring.len();
+        }
+    }
+
+    /// Remove bytes from the start of `pending`.
+    fn consume(&mut self, n: usize) {
+        self.pending.copy_within(n..self.pending_len, 0);
+        self.pending_len -= n;
+    }
+
+    /// Remove and return the next complete frame with a valid CRC, if there is one, reading from
+    /// the ring buffer as required. Sets `dropped` if we discard bytes that can't start a valid
+    /// frame. Since `pending` holds the largest valid frame, if this returns `None`, we've used all
+    /// data received, up to `write_i`.
+    fn next_frame(
+        &mut self,
+        ring: &[u8],
+        write_i: usize,
+        dropped: &mut bool,
+    ) -> Option<[u8; FRAME_SIZE_MAX]> {
+        loop {
+            self.fill(ring, write_i);
+
+            let start = self.pending[..self.pending_len]
+                .iter()
+                .position(|b| *b == DestAddr::FlightController as u8)
+                .unwrap_or(self.pending_len);
+
+            if start > 0 {
+                *dropped = true;
+                self.consume(start);
+            }
+
+            if self.pending_len < 2 {
+                return None;
+            }
+
+            // The size byte counts bytes after it: frame type, payload, and CRC.
+            let frame_len = self.pending[1] as usize + 2;
+            if !(FRAME_SIZE_MIN..=FRAME_SIZE_MAX).contains(&frame_len) {
+                *dropped = true;
+                self.consume(1);
+                continue;
+            }
+
+            if self.pending_len < frame_len {
+                return None;
+            }
+
+            // The CRC covers frame type, and payload.
+            let crc = util::calc_crc(
+                &CRC_LUT,
+                &self.pending[2..frame_len - 1],
+                frame_len as u8 - 3,
+            );
+
+            if crc != self.pending[frame_len - 1] {
+                *dropped = true;
+                self.consume(1);
+                continue;
+            }
+
+            let mut result = [0; FRAME_SIZE_MAX];
+            result[..frame_len].copy_from_slice(&self.pending[..frame_len]);
+            self.consume(frame_len);
+
+            return Some(result);
+        }
+    }
+}
+
 struct Packet {
     pub dest_addr: DestAddr,
     // Len, starting with `type`, to the end. Payload len + 2 normally, or + 4 with extended packet.
@@ -236,21 +424,11 @@ struct Packet {
 }
 
 impl Packet {
-    /// Decode a packet, from the rx buffer.
-    pub fn from_buf(mut buf: &[u8]) -> Result<Self, DecodeError> {
+    /// Decode a packet, from a frame extracted by `FrameParser`, which has checked its CRC.
+    pub fn from_buf(buf: &[u8]) -> Result<Self, DecodeError> {
         let dest_addr: DestAddr = match buf[0].try_into() {
             Ok(d) => d,
-            Err(_) => {
-                if buf[1] == DestAddr::FlightController as u8 {
-                    // todo: Getting a right shift by one on H7.
-                    // todo: Come back to this and see if it's required after we fix
-                    buf = &buf[1..];
-                    DestAddr::FlightController
-                } else {
-                    // println!("Dest Addr error");
-                    return Err(DecodeError {});
-                }
-            }
+            Err(_) => return Err(DecodeError {}),
         };
 
         // println!("BUF: {:?}", buf);
@@ -286,22 +464,6 @@ impl Packet {
 
         let received_crc = buf[payload_len + 3];
 
-        // Calculate the CRC starting at the frame type buf, and ending at the end of the payload.
-        let expected_crc = util::calc_crc(
-            &CRC_LUT,
-            // len + 2 gets us to the end. -1 to ommit CRC itself, which isn't part of the calculation.
-            &buf[2..len + 1],
-            len as u8 - 1,
-        );
-
-        if expected_crc != received_crc {
-            println!(
-                "CRSF CRC failed on recieved packet. Expected: {}. Received: {}",
-                expected_crc, received_crc
-            );
-            return Err(DecodeError {});
-        };
-
         Ok(Packet {
             dest_addr,
             len,
@@ -336,33 +498,8 @@ impl Packet {
     }
 
     /// Unpack the payload as channel data.
-    /// https://github.com/chris1seto/OzarkRiver/blob/4channel/FlightComputerFirmware/Src/Crsf.c#L148
     pub fn to_channel_data(&self) -> ChannelDataCrsf {
-        let mut data = [0; MAX_PAYLOAD_SIZE];
-        for i in 0..MAX_PAYLOAD_SIZE {
-            data[i] = self.payload[i] as u16;
-        }
-
-        const MASK: u16 = 0x07FF; // 11 bits per channel; this is 1<<11.
-
-        ChannelDataCrsf {
-            channel_1: (data[0] | data[1] << 8) & MASK,
-            channel_2: (data[1] >> 3 | data[2] << 5) & MASK,
-            channel_3: (data[2] >> 6 | data[3] << 2 | data[4] << 10) & MASK,
-            channel_4: (data[4] >> 1 | data[5] << 7) & MASK,
-            aux_1: (data[5] >> 4 | data[6] << 4) & MASK,
-            aux_2: (data[6] >> 7 | data[7] << 1 | data[8] << 9) & MASK,
-            aux_3: (data[8] >> 2 | data[9] << 6) & MASK,
-            aux_4: (data[9] >> 5 | data[10] << 3) & MASK,
-            aux_5: (data[11] | data[12] << 8) & MASK,
-            aux_6: (data[12] >> 3 | data[13] << 5) & MASK,
-            aux_7: (data[13] >> 6 | data[14] << 2 | data[15] << 10) & MASK,
-            aux_8: (data[15] >> 1 | data[16] << 7) & MASK,
-            aux_9: (data[16] >> 4 | data[17] << 4) & MASK,
-            aux_10: (data[17] >> 7 | data[18] << 1 | data[19] << 9) & MASK,
-            aux_11: (data[19] >> 2 | data[20] << 6) & MASK,
-            aux_12: (data[20] >> 5 | data[21] << 3) & MASK,
-        }
+        unpack_channels(&self.payload[..PAYLOAD_SIZE_RC_CHANNELS])
     }
 
     /// Interpret a CRSF packet as link statistics
@@ -384,18 +521,86 @@ impl Packet {
             downlink_snr: data[9] as i8,
         }
     }
+
+    /// Interpret an MSP write frame as a follow-me target, if it is one. The payload is the
+    /// extended destination and origin, then an MSP V1 frame without its preamble or checksum:
+    /// status, size, function, and the payload. The CRSF CRC covers it. We only accept targets in
+    /// a single chunk.
+    pub fn to_follow_target(&self) -> Option<FollowTarget> {
+        let data = &self.payload;
+
+        if data[2] & MSP_STATUS_START == 0
+            || data[3] as usize != FOLLOW_TARGET_SIZE
+            || data[4] != msp::MSG_ID_FOLLOW_TARGET
+        {
+            return None;
+        }
+
+        FollowTarget::from_bytes(&data[5..5 + FOLLOW_TARGET_SIZE])
+    }
+}
+
+/// Unpack 16 11-bit channels, packed little-endian into 22 bytes. SBUS uses the same packing.
+/// https://github.com/chris1seto/OzarkRiver/blob/4channel/FlightComputerFirmware/Src/Crsf.c#L148
+pub fn unpack_channels(buf: &[u8]) -> ChannelDataCrsf {
+    let mut data = [0; PAYLOAD_SIZE_RC_CHANNELS];
+    for i in 0..PAYLOAD_SIZE_RC_CHANNELS {
+        data[i] = buf[i] as u16;
+    }
+
+    const MASK: u16 = 0x07FF; // 11 bits per channel; this is 1<<11.
+
+    ChannelDataCrsf {
+        channel_1: (data[0] | data[1] << 8) & MASK,
+        channel_2: (data[1] >> 3 | data[2] << 5) & MASK,
+        channel_3: (data[2] >> 6 | data[3] << 2 | data[4] << 10) & MASK,
+        channel_4: (data[4] >> 1 | data[5] << 7) & MASK,
+        aux_1: (data[5] >> 4 | data[6] << 4) & MASK,
+        aux_2: (data[6] >> 7 | data[7] << 1 | data[8] << 9) & MASK,
+        aux_3: (data[8] >> 2 | data[9] << 6) & MASK,
+        aux_4: (data[9] >> 5 | data[10] << 3) & MASK,
+        aux_5: (data[11] | data[12] << 8) & MASK,
+        aux_6: (data[12] >> 3 | data[13] << 5) & MASK,
+        aux_7: (data[13] >> 6 | data[14] << 2 | data[15] << 10) & MASK,
+        aux_8: (data[15] >> 1 | data[16] << 7) & MASK,
+        aux_9: (data[16] >> 4 | data[17] << 4) & MASK,
+        aux_10: (data[17] >> 7 | data[18] << 1 | data[19] << 9) & MASK,
+        aux_11: (data[19] >> 2 | data[20] << 6) & MASK,
+        aux_12: (data[20] >> 5 | data[21] << 3) & MASK,
+    }
 }
 
-/// Handle an incomming packet. Triggered whenever the line goes idle.
-pub fn handle_packet(rx_chan: DmaChannel, rx_fault: &mut bool) -> Option<PacketData> {
-    let buf = unsafe { &RX_BUFFER };
+/// Parse received data, and return the next packet we use, if there is one. Run this from the main
+/// loop, repeatedly until it returns `None`; there may be several frames since the last call.
+pub fn next_packet(rx_fault: &mut bool) -> Option<PacketData> {
+    let parser = unsafe { &mut RX_PARSER };
+
+    let write_i = rx_write_i();
+
+    loop {
+        let mut dropped = false;
+        let frame = parser.next_frame(unsafe { &RX_BUFFER }, write_i, &mut dropped);
+
+        if dropped {
+            *rx_fault = true;
+            println!("Discarded invalid CRSF data");
+        }
+
+        let frame = frame?;
+
+        if let Some(result) = handle_packet(&frame, rx_fault) {
+            return Some(result);
+        }
+    }
+}
 
+/// Handle a received frame. Returns `None` for frames we don't use.
+fn handle_packet(buf: &[u8], rx_fault: &mut bool) -> Option<PacketData> {
     let packet = match Packet::from_buf(buf) {
         Ok(p) => p,
         Err(_) => {
             *rx_fault = true;
             println!("Error Parsing CRSF packet");
-            println!("BUF: {:?}", buf);
             return None;
         }
     };
@@ -459,6 +664,10 @@ pub fn handle_packet(rx_chan: DmaChannel, rx_fault: &mut bool) -> Option<PacketD
             let link_stats = packet.to_link_stats();
             result = Some(PacketData::LinkStats(link_stats));
         }
+        FrameType::MspWrite => {
+            // Other MSP messages aren't an error; we just don't use them.
+            result = packet.to_follow_target().map(PacketData::FollowTarget);
+        }
         _ => {
             *rx_fault = true;
             println!("Unexpected Rx frame type: {}", packet.frame_type as u8);
@@ -467,3 +676,285 @@ pub fn handle_packet(rx_chan: DmaChannel, rx_fault: &mut bool) -> Option<PacketD
 
     result
 }
+
+/// Which telemetry frame to send. We cycle through these.
+#[derive(Clone, Copy)]
+pub enum TelemFrame {
+    Battery,
+    Attitude,
+    Gps,
+    FlightMode,
+}
+
+impl TelemFrame {
+    pub const COUNT: u32 = 4;
+
+    pub fn from_index(i: u32) -> Self {
+        match i % Self::COUNT {
+            0 => Self::Battery,
+            1 => Self::Attitude,
+            2 => Self::Gps,
+            _ => Self::FlightMode,
+        }
+    }
+}
+
+/// Data for telemetry frames, assembled by the main loop.
+pub struct TelemData<'a> {
+    /// Volts.
+    pub batt_v: f32,
+    /// Amps.
+    pub current: f32,
+    /// 0. to 1.
+    pub batt_remaining: f32,
+    /// Radians.
+    pub pitch: f32,
+    pub roll: f32,
+    pub yaw: f32,
+    /// `None` if we don't have a usable fix.
+    pub gps_fix: Option<&'a GpsFix>,
+    pub flight_mode: &'a str,
+}
+
+/// Write a telemetry frame to `buf`, addressed to the receiver, with a CRC. Returns frame length.
+fn build_frame(frame_type: FrameType, payload: &[u8], buf: &mut [u8; MAX_PACKET_SIZE]) -> usize {
+    let payload_len = payload.len();
+
+    buf[0] = DestAddr::FlightController as u8; // Sync byte.
+    buf[1] = payload_len as u8 + 2; // Type and CRC.
+    buf[2] = frame_type as u8;
+    buf[3..3 + payload_len].clone_from_slice(payload);
+    // The CRC covers frame type, and payload.
+    buf[3 + payload_len] =
+        util::calc_crc(&CRC_LUT, &buf[2..3 + payload_len], payload_len as u8 + 1);
+
+    payload_len + 4
+}
+
+/// Build a telemetry frame, and queue it for transmission in the next gap between received frames.
+/// Does nothing if the previous frame hasn't been sent. Run this from the main loop, at a low rate.
+pub fn queue_telemetry(frame: TelemFrame, data: &TelemData) {
+    if TELEM_PENDING.load(Ordering::Acquire) {
+        return;
+    }
+
+    let mut payload = [0; MAX_PAYLOAD_SIZE];
+
+    let (frame_type, payload_len) = match frame {
+        TelemFrame::Battery => {
+            // Volts x 10, amps x 10, mAh used (24 bits; we don't track this), and % remaining.
+            payload[0..2].clone_from_slice(&((data.batt_v * 10.) as u16).to_be_bytes());
+            payload[2..4].clone_from_slice(&((data.current * 10.) as u16).to_be_bytes());
+            payload[7] = (data.batt_remaining.clamp(0., 1.) * 100.) as u8;
+
+            (FrameType::BatterySensor, PAYLOAD_SIZE_BATTERY)
+        }
+        TelemFrame::Attitude => {
+            // Radians x 10,000. This only fits ±3.2767 rad, so we send heading as -π to π.
+            let yaw = if data.yaw > PI {
+                data.yaw - TAU
+            } else if data.yaw < -PI {
+                data.yaw + TAU
+            } else {
+                data.yaw
+            };
+
+            payload[0..2].clone_from_slice(&((data.pitch * 10_000.) as i16).to_be_bytes());
+            payload[2..4].clone_from_slice(&((data.roll * 10_000.) as i16).to_be_bytes());
+            payload[4..6].clone_from_slice(&((yaw * 10_000.) as i16).to_be_bytes());
+
+            (FrameType::Attitude, PAYLOAD_SIZE_ATTITUDE)
+        }
+        TelemFrame::Gps => {
+            // Without a fix, we send zeros, including satellite count.
+            if let Some(fix) = data.gps_fix {
+                // Degrees x 1e7, km/h x 10, degrees x 100, meters + 1,000, and satellite count.
+                payload[0..4].clone_from_slice(&fix.lat.to_be_bytes());
+                payload[4..8].clone_from_slice(&fix.lon.to_be_bytes());
+                payload[8..10].clone_from_slice(&((fix.ground_speed * 36.) as u16).to_be_bytes());
+                payload[10..12]
+                    .clone_from_slice(&((fix.course.to_degrees() * 100.) as u16).to_be_bytes());
+                payload[12..14]
+                    .clone_from_slice(&((fix.alt_msl + 1_000.).max(0.) as u16).to_be_bytes());
+                payload[14] = fix.num_sv;
+            }
+
+            (FrameType::Gps, PAYLOAD_SIZE_GPS)
+        }
+        TelemFrame::FlightMode => {
+            // Truncate if required, leaving room for the null terminator, which is already in place.
+            let mode = data.flight_mode.as_bytes();
+            let len = mode.len().min(PAYLOAD_SIZE_FLIGHT_MODE_MAX - 1);
+            payload[..len].clone_from_slice(&mode[..len]);
+
+            (FrameType::FlightMode, len + 1)
+        }
+    };
+
+    let len = build_frame(frame_type, &payload[..payload_len], unsafe {
+        &mut TX_BUFFER
+    });
+
+    TELEM_LEN.store(len, Ordering::Release);
+    TELEM_PENDING.store(true, Ordering::Release);
+}
+
+/// Start sending a queued telemetry frame, if there is one. Run this from the CRSF ISR, when a received
+/// frame ends, so we never transmit while one is inbound.
+pub fn send_pending_telemetry(uart: &mut setup::UartCrsf) {
+    if !TELEM_PENDING.load(Ordering::Acquire) || TELEM_SENDING.load(Ordering::Acquire) {
+        return;
+    }
+
+    let len = TELEM_LEN.load(Ordering::Acquire);
+
+    dma::stop(setup::CRSF_DMA_PERIPH, setup::CRSF_TX_CH);
+
+    unsafe {
+        uart.write_dma(
+            &TX_BUFFER[..len],
+            setup::CRSF_TX_CH,
+            setup::dma_cfg(DmaTransfer::CrsfTx),
+            setup::CRSF_DMA_PERIPH,
+        );
+    }
+
+    TELEM_SENDING.store(true, Ordering::Release);
+}
+
+/// Run from the CRSF TX DMA transfer complete ISR. The main loop may then write the next frame.
+pub fn telemetry_sent() {
+    // This appears to be a required step between UART DMA transmission.
+    dma::stop(setup::CRSF_DMA_PERIPH, setup::CRSF_TX_CH);
+
+    TELEM_SENDING.store(false, Ordering::Release);
+    TELEM_PENDING.store(false, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A link stats frame, then a channel data frame, back-to-back.
+    fn frames() -> ([u8; MAX_PACKET_SIZE], usize, [u8; MAX_PACKET_SIZE], usize) {
+        let mut link_stats = [0; MAX_PACKET_SIZE];
+        let link_stats_len = build_frame(
+            FrameType::LinkStatistics,
+            &[50, 60, 100, 5, 0, 2, 3, 40, 90, 7],
+            &mut link_stats,
+        );
+
+        let mut channels = [0; MAX_PACKET_SIZE];
+        let mut payload = [0; PAYLOAD_SIZE_RC_CHANNELS];
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b = i as u8 * 11;
+        }
+        let channels_len = build_frame(FrameType::RcChannelsPacked, &payload, &mut channels);
+
+        (link_stats, link_stats_len, channels, channels_len)
+    }
+
+    /// Write bytes to the ring buffer at `write_i`, as DMA would, wrapping at its end. Returns the
+    /// new write index.
+    fn write(ring: &mut [u8; RX_BUF_SIZE], write_i: usize, bytes: &[u8]) -> usize {
+        for (i, b) in bytes.iter().enumerate() {
+            ring[(write_i + i) % RX_BUF_SIZE] = *b;
+        }
+        (write_i + bytes.len()) % RX_BUF_SIZE
+    }
+
+    fn assert_frame(frame: Option<[u8; FRAME_SIZE_MAX]>, expected: &[u8]) {
+        let frame = frame.expect("Expected a frame");
+        assert_eq!(&frame[..expected.len()], expected);
+    }
+
+    #[test]
+    fn concatenated_frames() {
+        let (link_stats, link_stats_len, channels, channels_len) = frames();
+        let mut ring = [0; RX_BUF_SIZE];
+        let mut parser = FrameParser::new();
+        let mut dropped = false;
+
+        let write_i = write(&mut ring, 0, &link_stats[..link_stats_len]);
+        let write_i = write(&mut ring, write_i, &channels[..channels_len]);
+
+        let frame = parser.next_frame(&ring, write_i, &mut dropped);
+        assert_frame(frame, &link_stats[..link_stats_len]);
+
+        let frame = parser.next_frame(&ring, write_i, &mut dropped);
+        assert_frame(frame, &channels[..channels_len]);
+
+        assert!(parser.next_frame(&ring, write_i, &mut dropped).is_none());
+        assert!(!dropped);
+    }
+
+    #[test]
+    fn split_across_reads() {
+        let (link_stats, link_stats_len, channels, channels_len) = frames();
+        let mut ring = [0; RX_BUF_SIZE];
+        let mut parser = FrameParser::new();
+        let mut dropped = false;
+
+        let mut stream = [0; MAX_PACKET_SIZE * 2];
+        stream[..link_stats_len].copy_from_slice(&link_stats[..link_stats_len]);
+        stream[link_stats_len..link_stats_len + channels_len]
+            .copy_from_slice(&channels[..channels_len]);
+        let stream = &stream[..link_stats_len + channels_len];
+
+        // Split within the first frame's header, then within the second frame.
+        let write_i = write(&mut ring, 0, &stream[..1]);
+        assert!(parser.next_frame(&ring, write_i, &mut dropped).is_none());
+
+        let write_i = write(&mut ring, write_i, &stream[1..link_stats_len + 5]);
+        let frame = parser.next_frame(&ring, write_i, &mut dropped);
+        assert_frame(frame, &link_stats[..link_stats_len]);
+        assert!(parser.next_frame(&ring, write_i, &mut dropped).is_none());
+
+        let write_i = write(&mut ring, write_i, &stream[link_stats_len + 5..]);
+        let frame = parser.next_frame(&ring, write_i, &mut dropped);
+        assert_frame(frame, &channels[..channels_len]);
+
+        assert!(!dropped);
+    }
+
+    #[test]
+    fn split_across_ring_buffer_wrap() {
+        let (_, _, channels, channels_len) = frames();
+        let mut ring = [0; RX_BUF_SIZE];
+        let mut parser = FrameParser::new();
+        let mut dropped = false;
+
+        // Start the frame 10 bytes before the end of the ring buffer.
+        let start = RX_BUF_SIZE - 10;
+        parser.read_i = start;
+
+        let write_i = write(&mut ring, start, &channels[..channels_len]);
+        assert!(write_i < start);
+
+        let frame = parser.next_frame(&ring, write_i, &mut dropped);
+        assert_frame(frame, &channels[..channels_len]);
+        assert!(!dropped);
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let (link_stats, link_stats_len, channels, channels_len) = frames();
+        let mut ring = [0; RX_BUF_SIZE];
+        let mut parser = FrameParser::new();
+        let mut dropped = false;
+
+        // Garbage, including address bytes with an invalid length, and a frame with a bad CRC.
+        let mut bad_crc = link_stats;
+        bad_crc[link_stats_len - 1] ^= 0xff;
+
+        let write_i = write(&mut ring, 0, &[0x12, 0xc8, 0xff, 0x00, 0xc8, 0x01]);
+        let write_i = write(&mut ring, write_i, &bad_crc[..link_stats_len]);
+        let write_i = write(&mut ring, write_i, &channels[..channels_len]);
+
+        let frame = parser.next_frame(&ring, write_i, &mut dropped);
+        assert_frame(frame, &channels[..channels_len]);
+        assert!(dropped);
+
+        assert!(parser.next_frame(&ring, write_i, &mut dropped).is_none());
+    }
+}
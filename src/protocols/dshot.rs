@@ -13,8 +13,15 @@
 //!
 //! The DSHOT protocol (DSHOT-300, DSHOT-600 etc) is determined by the `DSHOT_ARR_600` and
 //! `DSHOT_PSC_600` settings; ie set a 600kHz countdown for DSHOT-600.
+//!
+//! In 3D (reversible) mode, the throttle range is split: 48 - 1_047 is reverse, and 1_048 - 2_047
+//! forward, each from slowest to fastest. 0 stops the motor. Power is then -1. to 1.
+//!
+//! In bidirectional mode, each transmission is followed by a receive window, sequenced by
+//! `TelemetryStateMachine`: The motor DMA TC ISR, the motor line EXTI ISRs, and the receive timer
+//! ISR each call into it, and it owns switching the motor lines between output and input.
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
 
 // todo: Bidirectional: Set timers to active low, set GPIO idle to high, and perhaps set down counting
 // todo if required. Then figure out input capture, and fix in HAL.
@@ -25,15 +32,16 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use cfg_if::cfg_if;
 use defmt::println;
 use hal::{
-    delay_ms,
-    dma::{self, ChannelCfg, Priority},
-    pac,
-    timer::{CountDir, OutputCompare, Polarity},
+    delay_ms, dma,
+    pac::{self, TIM2},
+    timer::{CountDir, OutputCompare, Polarity, Timer, TimerInterrupt},
 };
 
 use crate::{
     board_config::{AHB_FREQ, DSHOT_SPEED, TIM_CLK_SPEED},
-    setup::{self, MotorTimer},
+    hil, motor_kill,
+    setup::{self, DmaTransfer, MotorTimer},
+    watchdog,
 };
 
 // Enable bidirectional DSHOT, which returns RPM data
@@ -50,6 +58,13 @@ pub const PSC_DSHOT: u16 = 0;
 // ESC telemetry is false except when setting motor direction.
 static mut ESC_TELEM: bool = false;
 
+// Set once the ESCs are in 3D mode. See `setup_3d_mode`.
+static REVERSIBLE: AtomicBool = AtomicBool::new(false);
+
+// Set the telemetry bit in the next packet sent to this motor only. Used to request UART
+// telemetry from one ESC at a time, since they share a wire.
+static mut TELEM_REQUEST: Option<Motor> = None;
+
 // We use these flags to determine how to handle the TC ISRs, ie when
 // a send command is received, set the mode to input and vice versa.
 
@@ -87,12 +102,207 @@ pub static mut PAYLOAD_REC_2: [u16; REC_BUF_LEN] = [0; REC_BUF_LEN];
 pub static mut PAYLOAD_REC_3: [u16; REC_BUF_LEN] = [0; REC_BUF_LEN];
 pub static mut PAYLOAD_REC_4: [u16; REC_BUF_LEN] = [0; REC_BUF_LEN];
 
+// GPIO MODER values for the motor lines.
+const MODER_INPUT: u8 = 0b00;
+const MODER_ALT: u8 = 0b10;
+
+pub static TELEM_SM: TelemetryStateMachine = TelemetryStateMachine::new();
+
+/// The bidirectional DSHOT transmit and receive sequence. Without bidirectional DSHOT, this
+/// alternates between `Idle` and `Transmitting`.
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+#[repr(u8)]
+pub enum TelemetryState {
+    /// No transmission in progress, and no unread receive window.
+    Idle = 0,
+    /// Power settings are being sent by DMA.
+    Transmitting = 1,
+    /// Transmission is complete, and the motor lines are inputs, but no ESC has replied yet.
+    WaitingTurnaround = 2,
+    /// Edges are being recorded from at least one ESC.
+    Receiving = 3,
+    /// The receive window has ended, and the motor lines are outputs again. The receive buffers
+    /// are complete, and won't be modified until the next transmission completes.
+    Decoding = 4,
+}
+
+impl TelemetryState {
+    fn from_u8(val: u8) -> Self {
+        match val {
+            1 => Self::Transmitting,
+            2 => Self::WaitingTurnaround,
+            3 => Self::Receiving,
+            4 => Self::Decoding,
+            _ => Self::Idle,
+        }
+    }
+}
+
+/// Sequences bidirectional DSHOT transmission and RPM reception. Shared between ISRs of different
+/// priorities, so state is held in atomics.
+pub struct TelemetryStateMachine {
+    state: AtomicU8,
+    /// The number of transmissions started while a receive window was still open. This
+    /// indicates the receive timer didn't fire.
+    timeouts: AtomicU32,
+    /// The number of receive windows that ended with no reply from any ESC.
+    no_replies: AtomicU32,
+}
+
+impl TelemetryStateMachine {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(TelemetryState::Idle as u8),
+            timeouts: AtomicU32::new(0),
+            no_replies: AtomicU32::new(0),
+        }
+    }
+
+    pub fn state(&self) -> TelemetryState {
+        TelemetryState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: TelemetryState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    pub fn timeouts(&self) -> u32 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    pub fn no_replies(&self) -> u32 {
+        self.no_replies.load(Ordering::Relaxed)
+    }
+
+    /// Run prior to starting a DMA transmission. If the previous receive window never ended,
+    /// end it here, so the motor lines are outputs again; otherwise, the transmission would be
+    /// lost, and we'd stay in input mode.
+    fn on_transmit(&self) {
+        if self.start_transmit() {
+            unsafe { (*TIM2::ptr()).cr1.modify(|_, w| w.cen().clear_bit()) };
+            end_reception();
+        }
+    }
+
+    /// The state transition for `on_transmit`. Returns `true` if the previous receive window
+    /// timed out, and must be ended.
+    fn start_transmit(&self) -> bool {
+        let timed_out = matches!(
+            self.state(),
+            TelemetryState::WaitingTurnaround | TelemetryState::Receiving
+        );
+
+        if timed_out {
+            self.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.set_state(TelemetryState::Transmitting);
+        timed_out
+    }
+
+    /// Run in the motor DMA transfer-complete ISR. Stops the transmission, and in bidirectional
+    /// mode, opens the receive window.
+    ///
+    /// We stop the motor timer through its register block, instead of locking the `MotorTimer`
+    /// resource; that lock is shared with the control path, and USB. This only clears the counter
+    /// enable. A transmission can't be mid-setup when this runs: Starting one stops the previous
+    /// transfer first, and this ISR preempts the send path as soon as that completes.
+    pub fn on_dma_complete(&self) {
+        // (From testing) We must stop this transaction manually before future transactions will work.
+        dma::stop(setup::MOTORS_DMA_PERIPH, setup::MOTOR_CH);
+        unsafe { (*pac::TIM3::ptr()).cr1.modify(|_, w| w.cen().clear_bit()) };
+
+        if !self.transmit_complete(BIDIR_EN) {
+            return;
+        }
+
+        M1_RPM_I.store(0, Ordering::Release);
+        M2_RPM_I.store(0, Ordering::Release);
+        M3_RPM_I.store(0, Ordering::Release);
+        M4_RPM_I.store(0, Ordering::Release);
+
+        // Make sure to clear these buffers at reception start, not after completion; if we do it after,
+        // they will be blanked before we can process them.
+        unsafe {
+            PAYLOAD_REC_1 = [0; REC_BUF_LEN];
+            PAYLOAD_REC_2 = [0; REC_BUF_LEN];
+            PAYLOAD_REC_3 = [0; REC_BUF_LEN];
+            PAYLOAD_REC_4 = [0; REC_BUF_LEN];
+        }
+
+        start_reception();
+    }
+
+    /// The state transition for `on_dma_complete`. Returns `true` if a receive window opens. The
+    /// motor lines are still outputs, so no edges are recorded until it's started.
+    fn transmit_complete(&self, bidir: bool) -> bool {
+        if bidir {
+            self.set_state(TelemetryState::WaitingTurnaround);
+        } else {
+            self.set_state(TelemetryState::Idle);
+        }
+        bidir
+    }
+
+    /// Run in the motor line EXTI ISRs, prior to recording an edge. Returns `false` if the edge
+    /// is outside a receive window, and should be ignored.
+    fn on_edge(&self) -> bool {
+        match self.state() {
+            TelemetryState::Receiving => true,
+            TelemetryState::WaitingTurnaround => {
+                self.set_state(TelemetryState::Receiving);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Run in the receive timer ISR, slightly after the last bit of RPM data is received. Returns
+    /// the motor lines to outputs, and marks the receive buffers as ready to decode.
+    pub fn on_receive_window_end(&self, timer: &mut Timer<TIM2>) {
+        timer.clear_interrupt(TimerInterrupt::Update);
+        timer.disable();
+
+        end_reception();
+        self.receive_window_end();
+    }
+
+    /// The state transition for `on_receive_window_end`.
+    fn receive_window_end(&self) {
+        match self.state() {
+            TelemetryState::Receiving => self.set_state(TelemetryState::Decoding),
+            TelemetryState::WaitingTurnaround => {
+                // No ESC replied. Leave the (cleared) buffers to decode, so this shows up as
+                // missing RPM readings.
+                self.no_replies.fetch_add(1, Ordering::Relaxed);
+                self.set_state(TelemetryState::Decoding);
+            }
+            // A transmission already timed out this window.
+            _ => (),
+        }
+    }
+
+    /// Run in the main loop, prior to decoding the receive buffers. Returns `true` if a complete
+    /// receive window is ready; the buffers may then be decoded until the next transmission
+    /// completes. We decode in the main loop, since it requires user config.
+    pub fn take_window(&self) -> bool {
+        self.state
+            .compare_exchange(
+                TelemetryState::Decoding as u8,
+                TelemetryState::Idle as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+}
+
 /// Specify the motor by its connection to the ESC.
 // /// Includes methods that get information regarding timer
 // /// and DMA, per specific board setups, in `setup`.
 // /// Note that this is more appplicable to quads, but isn't in the `quad` module due to how
 // /// we've structured DSHOT code.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum Motor {
     M1,
     M2,
@@ -121,9 +331,9 @@ pub enum Command {
     /// SpinDir1 and 2 are forced normal and reversed. If you have the ESC set to reversed in the config,
     /// these will not reverse the motor direction, since it is already operating in reverse.
     SpinDir1 = 7, // 6x
-    SpinDir2 = 8,   // 6x
-    _3dModeOff = 9, // 6x
-    _3dModeOn = 10, // 6x
+    SpinDir2 = 8,  // 6x
+    Mode3dOff = 9, // 6x
+    Mode3dOn = 10, // 6x
     _SettingsRequest = 11,
     SaveSettings = 12, // 6x, wait at least 35ms before next command.
     /// Normal and reversed with respect to configuration.
@@ -170,7 +380,7 @@ pub enum CmdType {
 /// Stop all motors, by setting their power to 0. Note that the Motor Stop command may not
 /// be implemented, and this approach gets the job done. Run this at program init, so the ESC
 /// get its required zero-throttle setting, generally required by ESC firmware to complete
-/// initialization.
+/// initialization. In 3D mode, this sends 0, vice minimum forward.
 pub fn stop_all(timer: &mut MotorTimer) {
     // Note that the stop command (Command 0) is currently not implemented, so set throttles to 0.
     set_power(0., 0., 0., 0., timer);
@@ -178,8 +388,13 @@ pub fn stop_all(timer: &mut MotorTimer) {
 
 /// Set up the direction for each motor, in accordance with user config. Note: This blocks!
 /// (at least for now). The intended use case is to run this only at init, and during Preflight,
-/// if adjusting motor mapping.
-pub fn setup_motor_dir(motors_reversed: (bool, bool, bool, bool), timer: &mut MotorTimer) {
+/// if adjusting motor mapping. If `save` is false, the ESCs revert to their saved direction on
+/// power-up; eg for temporary reversal in turtle mode, without wearing ESC flash.
+pub fn setup_motor_dir(
+    motors_reversed: (bool, bool, bool, bool),
+    save: bool,
+    timer: &mut MotorTimer,
+) {
     // Throttle must have been commanded to 0 a certain number of timers,
     // and the telemetry bit must be bit set to use commands.
     // Setting the throttle twice (with 1ms delay) doesn't work; 10x works. The required value is evidently between
@@ -188,6 +403,9 @@ pub fn setup_motor_dir(motors_reversed: (bool, bool, bool, bool), timer: &mut Mo
         stop_all(timer);
         delay_ms(PAUSE_BETWEEN_COMMANDS, AHB_FREQ);
     }
+    // This blocks the main loop, which normally kicks the watchdog.
+    watchdog::kick_blocking();
+
     // I've confirmed that setting direction without the telemetry bit set will fail.
     unsafe { ESC_TELEM = true };
 
@@ -227,19 +445,69 @@ pub fn setup_motor_dir(motors_reversed: (bool, bool, bool, bool), timer: &mut Mo
         delay_ms(PAUSE_BETWEEN_COMMANDS, AHB_FREQ);
     }
 
-    for _ in 0..REPEAT_COMMAND_COUNT {
-        setup_payload(Motor::M1, CmdType::Command(Command::SaveSettings));
-        setup_payload(Motor::M2, CmdType::Command(Command::SaveSettings));
-        setup_payload(Motor::M3, CmdType::Command(Command::SaveSettings));
-        setup_payload(Motor::M4, CmdType::Command(Command::SaveSettings));
+    watchdog::kick_blocking();
 
-        send_payload(timer);
+    if save {
+        send_cmd_all(Command::SaveSettings, timer);
+        delay_ms(PAUSE_AFTER_SAVE, AHB_FREQ);
+    }
 
+    unsafe { ESC_TELEM = false };
+}
+
+/// Put the ESCs in, or take them out of, 3D (reversible) mode, and save this to their config.
+/// Note: This blocks. Run this at init, after the ESC warmup. Power commands are signed once
+/// enabled; see `setup_payload`.
+pub fn setup_3d_mode(enabled: bool, timer: &mut MotorTimer) {
+    // As with setting direction, throttle must have been commanded to 0, and the telemetry bit
+    // set, for the ESCs to accept commands.
+    for _ in 0..30 {
+        stop_all(timer);
         delay_ms(PAUSE_BETWEEN_COMMANDS, AHB_FREQ);
     }
+    unsafe { ESC_TELEM = true };
+
+    delay_ms(PAUSE_BETWEEN_COMMANDS, AHB_FREQ);
+
+    let cmd = if enabled {
+        Command::Mode3dOn
+    } else {
+        Command::Mode3dOff
+    };
+    send_cmd_all(cmd, timer);
+
+    send_cmd_all(Command::SaveSettings, timer);
     delay_ms(PAUSE_AFTER_SAVE, AHB_FREQ);
 
     unsafe { ESC_TELEM = false };
+
+    REVERSIBLE.store(enabled, Ordering::Release);
+    stop_all(timer);
+}
+
+/// Returns `true` if the ESCs are in 3D mode, ie power commands are signed.
+pub fn reversible() -> bool {
+    REVERSIBLE.load(Ordering::Acquire)
+}
+
+/// Send a command to all motors, repeated as required by ESC firmware.
+fn send_cmd_all(cmd: Command, timer: &mut MotorTimer) {
+    for _ in 0..REPEAT_COMMAND_COUNT {
+        setup_payload(Motor::M1, CmdType::Command(cmd));
+        setup_payload(Motor::M2, CmdType::Command(cmd));
+        setup_payload(Motor::M3, CmdType::Command(cmd));
+        setup_payload(Motor::M4, CmdType::Command(cmd));
+
+        send_payload(timer);
+
+        delay_ms(PAUSE_BETWEEN_COMMANDS, AHB_FREQ);
+    }
+}
+
+/// Request UART telemetry from a motor's ESC. The telemetry bit is set in the next packet sent
+/// to it.
+pub fn request_telemetry(motor: Motor) {
+    unsafe { TELEM_REQUEST = Some(motor) };
 }
 
 /// Calculate CRC. Used for both sending and receiving. `data` here does not include the
@@ -264,10 +532,29 @@ pub fn setup_payload(rotor: Motor, cmd: CmdType) {
 
     let data_word = match cmd {
         CmdType::Command(c) => c as u16,
+        // Motors stay stopped in HIL mode, or while killed, regardless of what the flight
+        // controls command.
+        CmdType::Power(_) if hil::active() || motor_kill::active() => {
+            if reversible() {
+                0
+            } else {
+                48
+            }
+        }
+        CmdType::Power(pwr) if reversible() => power_3d(pwr),
         CmdType::Power(pwr) => (pwr * 1_999.) as u16 + 48,
     };
 
-    let packet = (data_word << 1) | (unsafe { ESC_TELEM } as u16);
+    let telem_requested = unsafe {
+        if TELEM_REQUEST == Some(rotor) {
+            TELEM_REQUEST = None;
+            true
+        } else {
+            false
+        }
+    };
+
+    let packet = (data_word << 1) | (unsafe { ESC_TELEM } || telem_requested) as u16;
 
     // Compute the checksum
     let packet = (packet << 4) | calc_crc(packet);
@@ -292,8 +579,19 @@ pub fn setup_payload(rotor: Motor, cmd: CmdType) {
     // Note that the end stays 0-padded, since we init with 0s, and never change those values.
 }
 
+/// The DSHOT value for a signed power, -1. to 1., in 3D mode. Exactly 0. stops the motor.
+fn power_3d(power: f32) -> u16 {
+    if power > 0. {
+        (power.min(1.) * 999.) as u16 + 1_048
+    } else if power < 0. {
+        ((-power).min(1.) * 999.) as u16 + 48
+    } else {
+        0
+    }
+}
+
 /// Set a rotor pair's power, using a 16-bit DHOT word, transmitted over DMA via timer CCR (duty)
-/// settings. `power` ranges from 0. to 1.
+/// settings. `power` ranges from 0. to 1., or -1. to 1. in 3D mode.
 pub fn set_power(power1: f32, power2: f32, power3: f32, power4: f32, timer: &mut MotorTimer) {
     setup_payload(Motor::M1, CmdType::Power(power1));
     setup_payload(Motor::M2, CmdType::Power(power2));
@@ -314,83 +612,92 @@ fn send_payload(timer: &mut MotorTimer) {
     // Stop any transations in progress.
     dma::stop(setup::MOTORS_DMA_PERIPH, setup::MOTOR_CH);
 
+    TELEM_SM.on_transmit();
+
     unsafe {
         timer.write_dma_burst(
             &PAYLOAD,
             setup::DSHOT_BASE_DIR_OFFSET,
             NUM_MOTORS as u8, // Update a channel per number of motors, up to 4.
             setup::MOTOR_CH,
-            ChannelCfg {
-                // Take precedence over CRSF and ADCs.
-                priority: Priority::High,
-                ..ChannelCfg::default()
-            },
+            setup::dma_cfg(DmaTransfer::Motors),
             true,
             setup::MOTORS_DMA_PERIPH,
         );
     }
 }
 
-/// Receive an RPM payload for all channels in bidirectional mode.
-/// Note that we configure what won't affect the FC-ESC transmission in the reception timer's
-/// ISR on payload-reception-complete. Here, we configure things that would affect transmission.
-pub fn receive_payload() {
-    // Set all motor pins to input mode.
-    let input_mode = 0b00;
+/// Set the GPIO mode of all motor lines.
+fn set_motor_pin_mode(mode: u8) {
     unsafe {
         cfg_if! {
             if #[cfg(feature = "h7")] {
                 (*pac::GPIOC::ptr())
                     .moder
                     .modify(|_, w| {
-                        w.moder6().bits(input_mode);
-                        w.moder7().bits(input_mode);
-                        w.moder8().bits(input_mode);
-                        w.moder9().bits(input_mode)
+                        w.moder6().bits(mode);
+                        w.moder7().bits(mode);
+                        w.moder8().bits(mode);
+                        w.moder9().bits(mode)
                     });
 
             } else {
                 (*pac::GPIOC::ptr())
                     .moder
-                    .modify(|_, w| w.moder6().bits(input_mode));
+                    .modify(|_, w| w.moder6().bits(mode));
                 (*pac::GPIOA::ptr())
                     .moder
-                    .modify(|_, w| w.moder4().bits(input_mode));
+                    .modify(|_, w| w.moder4().bits(mode));
                 (*pac::GPIOB::ptr())
                     .moder
                     .modify(|_, w| {
-                    w.moder0().bits(input_mode);
-                    w.moder1().bits(input_mode)
+                    w.moder0().bits(mode);
+                    w.moder1().bits(mode)
                 });
             }
         }
     }
+}
 
-    // Enable interrupts on the motor pins.
+/// Unmask or mask the EXTI interrupts on the motor lines. These are configured at init.
+fn set_motor_exti(enabled: bool) {
     let exti = unsafe { &(*pac::EXTI::ptr()) };
     cfg_if! {
         if #[cfg(feature = "h7")] {
             exti.cpuimr1.modify(|_, w| {
-                w.mr6().set_bit();
-                w.mr7().set_bit();
-                w.mr8().set_bit();
-                w.mr9().set_bit()
+                w.mr6().bit(enabled);
+                w.mr7().bit(enabled);
+                w.mr8().bit(enabled);
+                w.mr9().bit(enabled)
             });
         } else {
             exti.imr1.modify(|_, w| {
-                w.im6().set_bit();
-                w.im4().set_bit();
-                w.im0().set_bit();
-                w.im1().set_bit()
+                w.im6().bit(enabled);
+                w.im4().bit(enabled);
+                w.im0().bit(enabled);
+                w.im1().bit(enabled)
             });
         }
     }
+}
+
+/// Receive an RPM payload for all channels in bidirectional mode: Set the motor lines to inputs,
+/// enable their interrupts, and start the receive timer.
+fn start_reception() {
+    set_motor_pin_mode(MODER_INPUT);
+    set_motor_exti(true);
 
     unsafe {
-        (*pac::TIM2::ptr()).cr1.modify(|_, w| w.cen().set_bit());
+        (*TIM2::ptr()).cr1.modify(|_, w| w.cen().set_bit());
     }
 }
 
+/// Disable reception, and return the motor lines to their timer alt fn.
+fn end_reception() {
+    set_motor_exti(false);
+    set_motor_pin_mode(MODER_ALT);
+}
+
 /// Change timer polarity and count direction, to enable or disable bidirectional DSHOT.
 /// This results in the signal being active low for enabled, and active high for disabled.
 /// Timer settings default (in HAL and hardware) to disabled.
@@ -437,31 +744,16 @@ pub fn set_to_output(timer: &mut MotorTimer) {
     timer.enable_pwm_output(Motor::M4.tim_channel(), oc, 0.);
 }
 
-/// This function, called in motor line EXTI ISRs, updates a motor's receive
-/// RPM buffer with the current count, from the RPM-receive timer.
-pub fn _update_rec_buf(rpm_i: &AtomicUsize, payload_rec: &mut [u16]) {
-    let count = unsafe { (*pac::TIM2::ptr()).cnt.read().bits() as u16 };
-
-    let i = rpm_i.fetch_add(1, Ordering::Relaxed);
-
-    // This shouldn't come up, but this ensures it won't overflow if it does for whatever
-    // reason.
-    if i >= REC_BUF_LEN {
-        println!("Error: RPM I overflow");
-        rpm_i.store(0, Ordering::Release);
-    }
+// These are hard-coded per motor, since updating the receive buffer through an argument failed
+// for unknown reasons.
 
-    unsafe {
-        // We know the first edge is low, then alternates low, high.
-        // payload_rec[i] = count; // todo: What the hell; why doesn't this work?
-        PAYLOAD_REC_3[i] = count;
+/// Called in motor line EXTI ISRs; updates motor 1's receive RPM buffer with the current count,
+/// from the RPM-receive timer.
+pub fn update_rec_buf_1(rpm_i: &AtomicUsize) {
+    if !TELEM_SM.on_edge() {
+        return;
     }
-}
 
-// todo: We are having a mysterious problem with updating the receive buf using an argument.
-// todo: Hard coded fns for now.
-
-pub fn update_rec_buf_1(rpm_i: &AtomicUsize) {
     let count = unsafe { (*pac::TIM2::ptr()).cnt.read().bits() as u16 };
 
     let mut i = rpm_i.fetch_add(1, Ordering::Relaxed);
@@ -481,6 +773,10 @@ pub fn update_rec_buf_1(rpm_i: &AtomicUsize) {
 }
 
 pub fn update_rec_buf_2(rpm_i: &AtomicUsize) {
+    if !TELEM_SM.on_edge() {
+        return;
+    }
+
     let count = unsafe { (*pac::TIM2::ptr()).cnt.read().bits() as u16 };
 
     let mut i = rpm_i.fetch_add(1, Ordering::Relaxed);
@@ -497,6 +793,10 @@ pub fn update_rec_buf_2(rpm_i: &AtomicUsize) {
 }
 
 pub fn update_rec_buf_3(rpm_i: &AtomicUsize) {
+    if !TELEM_SM.on_edge() {
+        return;
+    }
+
     let count = unsafe { (*pac::TIM2::ptr()).cnt.read().bits() as u16 };
 
     let mut i = rpm_i.fetch_add(1, Ordering::Relaxed);
@@ -513,6 +813,10 @@ pub fn update_rec_buf_3(rpm_i: &AtomicUsize) {
 }
 
 pub fn update_rec_buf_4(rpm_i: &AtomicUsize) {
+    if !TELEM_SM.on_edge() {
+        return;
+    }
+
     let count = unsafe { (*pac::TIM2::ptr()).cnt.read().bits() as u16 };
 
     let mut i = rpm_i.fetch_add(1, Ordering::Relaxed);
@@ -527,3 +831,99 @@ pub fn update_rec_buf_4(rpm_i: &AtomicUsize) {
         PAYLOAD_REC_4[i] = count;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bidirectional_cycle() {
+        let sm = TelemetryStateMachine::new();
+        assert!(sm.state() == TelemetryState::Idle);
+
+        assert!(!sm.start_transmit());
+        assert!(sm.state() == TelemetryState::Transmitting);
+        // No receive window is open, so edges are ignored.
+        assert!(!sm.on_edge());
+
+        assert!(sm.transmit_complete(true));
+        assert!(sm.state() == TelemetryState::WaitingTurnaround);
+        assert!(!sm.take_window());
+
+        assert!(sm.on_edge());
+        assert!(sm.state() == TelemetryState::Receiving);
+        assert!(sm.on_edge());
+        assert!(sm.state() == TelemetryState::Receiving);
+
+        sm.receive_window_end();
+        assert!(sm.state() == TelemetryState::Decoding);
+        assert!(!sm.on_edge());
+
+        assert!(sm.take_window());
+        assert!(sm.state() == TelemetryState::Idle);
+        assert!(!sm.take_window());
+
+        assert_eq!(sm.timeouts(), 0);
+        assert_eq!(sm.no_replies(), 0);
+    }
+
+    #[test]
+    fn no_reply() {
+        let sm = TelemetryStateMachine::new();
+
+        sm.start_transmit();
+        sm.transmit_complete(true);
+        sm.receive_window_end();
+
+        // The cleared buffers are still decoded, so missing readings show up.
+        assert!(sm.state() == TelemetryState::Decoding);
+        assert_eq!(sm.no_replies(), 1);
+        assert!(sm.take_window());
+    }
+
+    #[test]
+    fn receive_window_timeout() {
+        let sm = TelemetryStateMachine::new();
+
+        sm.start_transmit();
+        sm.transmit_complete(true);
+        sm.on_edge();
+
+        // The receive timer didn't fire before the next transmission.
+        assert!(sm.start_transmit());
+        assert!(sm.state() == TelemetryState::Transmitting);
+        assert_eq!(sm.timeouts(), 1);
+
+        // If it fires late, it doesn't disturb the transmission.
+        sm.receive_window_end();
+        assert!(sm.state() == TelemetryState::Transmitting);
+        assert!(!sm.take_window());
+        assert_eq!(sm.no_replies(), 0);
+
+        // The next cycle is unaffected.
+        assert!(sm.transmit_complete(true));
+        assert!(sm.on_edge());
+        sm.receive_window_end();
+        assert!(sm.take_window());
+        assert_eq!(sm.timeouts(), 1);
+    }
+
+    #[test]
+    fn not_bidirectional() {
+        let sm = TelemetryStateMachine::new();
+
+        for _ in 0..3 {
+            assert!(!sm.start_transmit());
+            assert!(sm.state() == TelemetryState::Transmitting);
+
+            assert!(!sm.transmit_complete(false));
+            assert!(sm.state() == TelemetryState::Idle);
+
+            assert!(!sm.on_edge());
+            assert!(!sm.take_window());
+        }
+
+        assert_eq!(sm.timeouts(), 0);
+        assert_eq!(sm.no_replies(), 0);
+    }
+}
@@ -0,0 +1,218 @@
+//! ESC telemetry over UART, in the KISS / BLHeli_32 format. All ESCs share a single telemetry
+//! wire; an ESC sends one frame after receiving a DSHOT packet with the telemetry bit set, so we
+//! request telemetry from one motor at a time.
+//!
+//! Frame format (10 bytes, big endian):
+//! - 0: Temperature, °C
+//! - 1-2: Voltage, 0.01V
+//! - 3-4: Current, 0.01A
+//! - 5-6: Consumption, mAh
+//! - 7-8: eRPM / 100
+//! - 9: CRC8
+//!
+//! [KISS telemetry protocol](https://www.rcgroups.com/forums/showatt.php?attachmentid=8524039&d=1450424877)
+
+use core::sync::atomic::AtomicBool;
+
+use hal::usart::UsartInterrupt;
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    protocols::dshot::{self, Motor},
+    setup::{self, DmaTransfer, UartEscTelem},
+    util,
+};
+
+pub const BAUD: u32 = 115_200;
+
+pub const FRAME_SIZE: usize = 10;
+
+const CRC_POLY: u8 = 0x07;
+const CRC_LUT: [u8; 256] = util::crc_init(CRC_POLY);
+
+pub const NUM_ESCS: usize = 4;
+
+// If we don't receive a frame within this time (s) of requesting it, move on to the next motor.
+// A frame takes just under 1ms to transmit at 115,200 baud.
+const REQUEST_TIMEOUT: f32 = 0.01;
+
+// We set the over-temperature flag above this ESC temperature, in °C.
+pub const OVER_TEMP_THRESH: f32 = 100.;
+
+// If ESC and ADC battery readings differ by more than this portion, one of them is likely
+// miscalibrated or faulty.
+const MISMATCH_THRESH: f32 = 0.15;
+// Below this current (A), ADC and ESC current readings are mostly noise and offsets; skip the
+// current cross-check.
+const MISMATCH_MIN_CURRENT: f32 = 5.;
+
+/// DMA writes here. We copy completed frames to `FRAME` in the ISR, before restarting the read.
+pub static mut RX_BUF: [u8; FRAME_SIZE] = [0; FRAME_SIZE];
+pub static mut FRAME: [u8; FRAME_SIZE] = [0; FRAME_SIZE];
+
+/// Set in the UART idle ISR; cleared when the frame is processed in the main loop.
+pub static NEW_FRAME_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// Enable the idle-line interrupt, which we use to detect the end of a frame, and start the
+/// first read.
+pub fn setup(uart: &mut UartEscTelem) {
+    uart.enable_interrupt(UsartInterrupt::Idle);
+    start_read(uart);
+}
+
+/// Start a DMA read of one frame. Run this after handling each idle-line interrupt.
+pub fn start_read(uart: &mut UartEscTelem) {
+    unsafe {
+        // Clear the old frame, so a spurious idle interrupt doesn't re-process it.
+        RX_BUF = [0xff; FRAME_SIZE];
+
+        uart.read_dma(
+            &mut RX_BUF,
+            setup::ESC_TELEM_RX_CH,
+            setup::dma_cfg(DmaTransfer::EscTelemRx),
+            setup::ESC_TELEM_DMA_PERIPH,
+        );
+    }
+}
+
+/// Which measurement source we use for battery voltage and current. Stored in user config.
+#[derive(Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum BattMeasSource {
+    /// The flight controller's ADC.
+    Adc = 0,
+    /// ESC telemetry. Voltage from the ESCs; current summed across them.
+    EscTelemetry = 1,
+}
+
+impl Default for BattMeasSource {
+    fn default() -> Self {
+        Self::Adc
+    }
+}
+
+/// Telemetry from a single ESC.
+#[derive(Clone, Copy, Default)]
+pub struct EscTelemetry {
+    /// °C
+    pub temp: f32,
+    /// V
+    pub voltage: f32,
+    /// A
+    pub current: f32,
+    /// mAh, since the ESC powered on.
+    pub consumption: f32,
+    /// Electrical RPM.
+    pub erpm: f32,
+    /// Time of the last valid frame, in seconds since start. `None` if we've never received one.
+    pub timestamp: Option<f32>,
+}
+
+impl EscTelemetry {
+    /// Parse a frame, validating its CRC.
+    pub fn from_frame(buf: &[u8; FRAME_SIZE], timestamp: f32) -> Option<Self> {
+        let crc = util::calc_crc(&CRC_LUT, buf, (FRAME_SIZE - 1) as u8);
+        if crc != buf[FRAME_SIZE - 1] {
+            return None;
+        }
+
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]) as f32;
+
+        Some(Self {
+            temp: buf[0] as f32,
+            voltage: u16_at(1) / 100.,
+            current: u16_at(3) / 100.,
+            consumption: u16_at(5),
+            erpm: u16_at(7) * 100.,
+            timestamp: Some(timestamp),
+        })
+    }
+}
+
+/// Telemetry for all ESCs, and the state of our round-robin requests.
+#[derive(Default)]
+pub struct EscTelemetryState {
+    pub escs: [EscTelemetry; NUM_ESCS],
+    /// Index of the motor we last requested telemetry from.
+    motor_requested: usize,
+    /// Time we last requested telemetry, in seconds since start.
+    requested_at: f32,
+    pub num_crc_errors: u32,
+}
+
+impl EscTelemetryState {
+    /// Process a new frame, if there is one, and request telemetry from the next motor once
+    /// the current request is answered, or times out. Run this regularly from the main loop.
+    pub fn update(&mut self, new_frame: Option<&[u8; FRAME_SIZE]>, timestamp: f32) {
+        let received = new_frame.is_some();
+
+        if let Some(frame) = new_frame {
+            match EscTelemetry::from_frame(frame, timestamp) {
+                Some(t) => self.escs[self.motor_requested] = t,
+                None => self.num_crc_errors += 1,
+            }
+        }
+
+        if received || timestamp - self.requested_at > REQUEST_TIMEOUT {
+            self.motor_requested = (self.motor_requested + 1) % NUM_ESCS;
+            self.requested_at = timestamp;
+
+            let motor = match self.motor_requested {
+                0 => Motor::M1,
+                1 => Motor::M2,
+                2 => Motor::M3,
+                _ => Motor::M4,
+            };
+            dshot::request_telemetry(motor);
+        }
+    }
+
+    /// ESCs we've received a frame from recently.
+    fn recent(&self, timestamp: f32, max_age: f32) -> impl Iterator<Item = &EscTelemetry> {
+        self.escs.iter().filter(move |e| match e.timestamp {
+            Some(t) => timestamp - t <= max_age,
+            None => false,
+        })
+    }
+
+    /// True if we've received telemetry from all ESCs within `max_age` seconds.
+    pub fn all_recent(&self, timestamp: f32, max_age: f32) -> bool {
+        self.recent(timestamp, max_age).count() == NUM_ESCS
+    }
+
+    /// The highest temperature reported by any ESC, in °C.
+    pub fn max_temp(&self, timestamp: f32, max_age: f32) -> f32 {
+        self.recent(timestamp, max_age)
+            .map(|e| e.temp)
+            .fold(0., f32::max)
+    }
+
+    /// Battery voltage; the mean of that reported by each ESC.
+    pub fn voltage(&self, timestamp: f32, max_age: f32) -> Option<f32> {
+        let (sum, count) = self
+            .recent(timestamp, max_age)
+            .fold((0., 0), |(s, c), e| (s + e.voltage, c + 1));
+
+        if count == 0 {
+            None
+        } else {
+            Some(sum / count as f32)
+        }
+    }
+
+    /// Total current drawn by the ESCs, in A.
+    pub fn current(&self, timestamp: f32, max_age: f32) -> f32 {
+        self.recent(timestamp, max_age).map(|e| e.current).sum()
+    }
+}
+
+/// Compare battery voltage and current from the ADC against ESC telemetry. Returns true if they
+/// disagree substantially.
+pub fn batt_meas_mismatch(v_adc: f32, i_adc: f32, v_esc: f32, i_esc: f32) -> bool {
+    let v_mismatch = (v_adc - v_esc).abs() > v_esc * MISMATCH_THRESH;
+
+    let i_mismatch =
+        i_esc > MISMATCH_MIN_CURRENT && (i_adc - i_esc).abs() > i_esc * MISMATCH_THRESH;
+
+    v_mismatch || i_mismatch
+}
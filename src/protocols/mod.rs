@@ -3,7 +3,12 @@
 pub mod crsf;
 pub mod dshot;
 pub mod esc_can;
+pub mod esc_telemetry;
 pub mod msp;
+pub mod msp_usb;
 pub mod rpm_reception;
+pub mod sbus;
 pub mod servo;
+pub mod usb_frame;
 pub mod usb_preflight;
+pub mod usb_telem;
@@ -13,7 +13,7 @@
 use defmt::println;
 
 use crate::{
-    setup::{UartOsd, OSD_DMA_PERIPH, OSD_TX_CH},
+    setup::{self, DmaTransfer, UartOsd, OSD_DMA_PERIPH, OSD_TX_CH},
     util,
 };
 
@@ -40,6 +40,9 @@ pub const METADATA_SIZE_V2: usize = FRAME_START_I_V2 + CRC_SIZE_V2;
 pub const MSG_ID_DP: u8 = 182;
 pub const MSG_ID_STATUS: u8 = 101;
 pub const MSG_ID_FC_TYPE: u8 = 3;
+/// Our own function, for follow-me targets; not in the MultiWii or Betaflight sets. The payload
+/// is as in the USB `FollowTarget` message.
+pub const MSG_ID_FOLLOW_TARGET: u8 = 240;
 
 #[derive(Copy, Clone)]
 #[repr(u8)]
@@ -127,12 +130,147 @@ impl<'a> Packet<'a> {
 
     pub fn _send_v1(&self, buf: &mut [u8], uart: &mut UartOsd) {
         self.to_buf_v1(buf);
-        unsafe { uart.write_dma(&buf, OSD_TX_CH, Default::default(), OSD_DMA_PERIPH) };
+        unsafe {
+            uart.write_dma(
+                &buf,
+                OSD_TX_CH,
+                setup::dma_cfg(DmaTransfer::OsdTx),
+                OSD_DMA_PERIPH,
+            )
+        };
     }
 
     // todo: DRY
     pub fn _send_v2(&self, buf: &mut [u8], uart: &mut UartOsd) {
         self._to_buf_v2(buf);
-        unsafe { uart.write_dma(&buf, OSD_TX_CH, Default::default(), OSD_DMA_PERIPH) };
+        unsafe {
+            uart.write_dma(
+                &buf,
+                OSD_TX_CH,
+                setup::dma_cfg(DmaTransfer::OsdTx),
+                OSD_DMA_PERIPH,
+            )
+        };
+    }
+}
+
+// We only parse requests, which generally have small or empty payloads.
+const MAX_RX_PAYLOAD_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ParseState {
+    Preamble0,
+    Preamble1,
+    Direction,
+    Size,
+    Function,
+    Payload,
+    Crc,
+}
+
+/// A complete V1 request, with a valid checksum.
+pub struct Request<'a> {
+    pub function: u8,
+    pub payload: &'a [u8],
+}
+
+/// Streaming MSP V1 request parser. Feed it bytes in order, eg as they arrive over USB in chunks;
+/// it returns a request once one is complete and passes its checksum.
+pub struct Parser {
+    state: ParseState,
+    function: u8,
+    size: usize,
+    i: usize,
+    payload: [u8; MAX_RX_PAYLOAD_SIZE],
+    crc: u8,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self {
+            state: ParseState::Preamble0,
+            function: 0,
+            size: 0,
+            i: 0,
+            payload: [0; MAX_RX_PAYLOAD_SIZE],
+            crc: 0,
+        }
+    }
+}
+
+impl Parser {
+    /// True if we've received part of a frame.
+    pub fn in_frame(&self) -> bool {
+        self.state != ParseState::Preamble0
+    }
+
+    /// Process a single byte. Returns a request when one completes.
+    pub fn feed(&mut self, byte: u8) -> Option<Request> {
+        match self.state {
+            ParseState::Preamble0 => {
+                if byte == PREAMBLE_0 {
+                    self.state = ParseState::Preamble1;
+                }
+            }
+            ParseState::Preamble1 => {
+                self.state = match byte {
+                    PREAMBLE_1_V1 => ParseState::Direction,
+                    // Eg a repeated first preamble byte.
+                    PREAMBLE_0 => ParseState::Preamble1,
+                    _ => ParseState::Preamble0,
+                };
+            }
+            ParseState::Direction => {
+                self.state = if byte == Direction::VtxToFc as u8 {
+                    ParseState::Size
+                } else {
+                    ParseState::Preamble0
+                };
+            }
+            ParseState::Size => {
+                self.size = byte as usize;
+                self.crc = byte;
+                self.i = 0;
+
+                self.state = if self.size > MAX_RX_PAYLOAD_SIZE {
+                    // Too long to buffer; resync on the next frame.
+                    ParseState::Preamble0
+                } else {
+                    ParseState::Function
+                };
+            }
+            ParseState::Function => {
+                self.function = byte;
+                self.crc ^= byte;
+
+                self.state = if self.size == 0 {
+                    ParseState::Crc
+                } else {
+                    ParseState::Payload
+                };
+            }
+            ParseState::Payload => {
+                self.payload[self.i] = byte;
+                self.crc ^= byte;
+                self.i += 1;
+
+                if self.i == self.size {
+                    self.state = ParseState::Crc;
+                }
+            }
+            ParseState::Crc => {
+                self.state = ParseState::Preamble0;
+
+                if byte == self.crc {
+                    return Some(Request {
+                        function: self.function,
+                        payload: &self.payload[..self.size],
+                    });
+                }
+                println!("MSP checksum failure");
+            }
+        }
+
+        None
     }
 }
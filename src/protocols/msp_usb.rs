@@ -0,0 +1,209 @@
+//! Responds to MSP V1 requests over USB, so Betaflight Configurator (and similar tools) can display
+//! attitude, sensor readings, receiver channels, and battery state. This runs alongside our own USB
+//! protocol; we route reads that start with the MSP preamble here.
+//!
+//! [Message formats](https://github.com/betaflight/betaflight/blob/master/src/main/msp/msp.c)
+
+use ahrs::Params;
+use defmt::println;
+use usbd_serial::SerialPort;
+
+use crate::{
+    controller_interface::{ChannelData, InputModeSwitch},
+    loop_rates,
+    protocols::{
+        crsf::LinkStats,
+        msp::{self, Direction, Packet, Request, METADATA_SIZE_V1},
+    },
+    safety::ArmStatus,
+    setup,
+    state::StateVolatile,
+    system_status::{SensorStatus, SystemStatus},
+};
+
+const MSG_ID_API_VERSION: u8 = 1;
+const MSG_ID_FC_VARIANT: u8 = 2;
+const MSG_ID_RAW_IMU: u8 = 102;
+const MSG_ID_RC: u8 = 105;
+const MSG_ID_ATTITUDE: u8 = 108;
+const MSG_ID_ANALOG: u8 = 110;
+
+const MSP_PROTOCOL_VERSION: u8 = 0;
+const API_VERSION_MAJOR: u8 = 1;
+const API_VERSION_MINOR: u8 = 44;
+
+const FC_VARIANT: &[u8; 4] = b"CRVS";
+
+// `MSP_STATUS` sensor flags.
+const SENSOR_ACC: u16 = 1 << 0;
+const SENSOR_BARO: u16 = 1 << 1;
+const SENSOR_MAG: u16 = 1 << 2;
+const SENSOR_GPS: u16 = 1 << 3;
+const SENSOR_RANGEFINDER: u16 = 1 << 4;
+const SENSOR_GYRO: u16 = 1 << 5;
+
+// `MSP_RAW_IMU` reports acceleration in these units per G.
+const ACC_1G: f32 = 512.;
+const G: f32 = 9.80665;
+
+const NUM_RC_CHANNELS: usize = 8;
+
+// Large enough for our largest response, `MSP_RAW_IMU`.
+const MAX_TX_PAYLOAD_SIZE: usize = 18;
+
+/// Map a control value, -1. to 1., to a pulse width in µs, as MSP reports.
+fn ctrl_to_us(val: f32) -> u16 {
+    (1_500. + val.clamp(-1., 1.) * 500.) as u16
+}
+
+/// Map a throttle value, 0. to 1., to a pulse width in µs.
+fn throttle_to_us(val: f32) -> u16 {
+    (1_000. + val.clamp(0., 1.) * 1_000.) as u16
+}
+
+/// Respond to a complete MSP request. Unsupported functions receive an error response.
+pub fn handle_request(
+    usb_serial: &mut SerialPort<'static, setup::UsbBusType>,
+    request: &Request,
+    params: &Params,
+    ch_data: &Option<ChannelData>,
+    link_stats: &LinkStats,
+    state: &StateVolatile,
+    system_status: &SystemStatus,
+) {
+    let mut payload = [0; MAX_TX_PAYLOAD_SIZE];
+
+    let payload_len = match request.function {
+        MSG_ID_API_VERSION => {
+            payload[0] = MSP_PROTOCOL_VERSION;
+            payload[1] = API_VERSION_MAJOR;
+            payload[2] = API_VERSION_MINOR;
+            3
+        }
+        MSG_ID_FC_VARIANT => {
+            payload[0..4].copy_from_slice(FC_VARIANT);
+            4
+        }
+        msp::MSG_ID_STATUS => {
+            let mut sensors = 0;
+            if system_status.imu == SensorStatus::Pass {
+                sensors |= SENSOR_ACC | SENSOR_GYRO;
+            }
+            if system_status.baro == SensorStatus::Pass {
+                sensors |= SENSOR_BARO;
+            }
+            if system_status.mag_applied {
+                sensors |= SENSOR_MAG;
+            }
+            if system_status.gnss_usable() {
+                sensors |= SENSOR_GPS;
+            }
+            if system_status.tof == SensorStatus::Pass {
+                sensors |= SENSOR_RANGEFINDER;
+            }
+
+            // Flight mode flags. We only report armed.
+            let flags: u32 = if state.arm_status == ArmStatus::Disarmed {
+                0
+            } else {
+                1
+            };
+
+            // Cycle time in µs, I2C error count, sensors, flight mode flags, and profile.
+            payload[0..2]
+                .copy_from_slice(&((loop_rates::rates().dt_imu * 1_000_000.) as u16).to_le_bytes());
+            payload[4..6].copy_from_slice(&sensors.to_le_bytes());
+            payload[6..10].copy_from_slice(&flags.to_le_bytes());
+            11
+        }
+        MSG_ID_RAW_IMU => {
+            // Acceleration, gyro, and magnetometer; 3 axes each. Gyro is in °/s. We don't have
+            // raw magnetometer readings here, so report 0.
+            let vals = [
+                params.a_x / G * ACC_1G,
+                params.a_y / G * ACC_1G,
+                params.a_z / G * ACC_1G,
+                params.v_roll.to_degrees(),
+                params.v_pitch.to_degrees(),
+                params.v_yaw.to_degrees(),
+            ];
+            for (i, val) in vals.iter().enumerate() {
+                payload[i * 2..i * 2 + 2].copy_from_slice(&(*val as i16).to_le_bytes());
+            }
+            18
+        }
+        MSG_ID_RC => {
+            // Roll, pitch, yaw, throttle (AETR), then the arm and input mode switches. We report
+            // centered sticks and low throttle if we don't have channel data.
+            let mut channels = [1_500; NUM_RC_CHANNELS];
+            channels[3] = 1_000;
+            channels[4] = 1_000;
+            channels[5] = 1_000;
+
+            if let Some(ch) = ch_data {
+                channels[0] = ctrl_to_us(ch.roll);
+                channels[1] = ctrl_to_us(ch.pitch);
+                channels[2] = ctrl_to_us(ch.yaw);
+                channels[3] = throttle_to_us(ch.throttle);
+                channels[4] = if ch.arm_status == ArmStatus::Disarmed {
+                    1_000
+                } else {
+                    2_000
+                };
+                channels[5] = match ch.input_mode {
+                    InputModeSwitch::Acro => 1_000,
+                    InputModeSwitch::AttitudeLoiter => 1_500,
+                    InputModeSwitch::Route => 2_000,
+                };
+            }
+
+            for (i, ch) in channels.iter().enumerate() {
+                payload[i * 2..i * 2 + 2].copy_from_slice(&ch.to_le_bytes());
+            }
+            NUM_RC_CHANNELS * 2
+        }
+        MSG_ID_ATTITUDE => {
+            // Roll and pitch in tenths of a degree; heading in degrees.
+            let roll = (params.s_roll.to_degrees() * 10.) as i16;
+            let pitch = (params.s_pitch.to_degrees() * 10.) as i16;
+            let heading = params.s_yaw_heading.to_degrees() as i16;
+
+            payload[0..2].copy_from_slice(&roll.to_le_bytes());
+            payload[2..4].copy_from_slice(&pitch.to_le_bytes());
+            payload[4..6].copy_from_slice(&heading.to_le_bytes());
+            6
+        }
+        MSG_ID_ANALOG => {
+            // Volts x 10 (legacy), mAh drawn (we don't track this), RSSI (0 - 1023),
+            // amps x 100, and volts x 100.
+            let rssi = link_stats.uplink_link_quality.min(100) as u16 * 1_023 / 100;
+
+            payload[0] = (state.batt_v * 10.) as u8;
+            payload[3..5].copy_from_slice(&rssi.to_le_bytes());
+            payload[5..7].copy_from_slice(&((state.esc_current * 100.) as i16).to_le_bytes());
+            payload[7..9].copy_from_slice(&((state.batt_v * 100.) as u16).to_le_bytes());
+            9
+        }
+        _ => {
+            println!("Unsupported MSP function: {}", request.function);
+
+            let mut buf = [0; METADATA_SIZE_V1];
+            Packet::new(Direction::Error, request.function as u16, 0, &[]).to_buf_v1(&mut buf);
+            usb_serial.write(&buf).ok();
+            return;
+        }
+    };
+
+    let mut buf = [0; MAX_TX_PAYLOAD_SIZE + METADATA_SIZE_V1];
+    Packet::new(
+        Direction::FcToVtx,
+        request.function as u16,
+        payload_len,
+        &payload[..payload_len],
+    )
+    .to_buf_v1(&mut buf);
+
+    usb_serial
+        .write(&buf[..payload_len + METADATA_SIZE_V1])
+        .ok();
+}
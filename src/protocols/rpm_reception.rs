@@ -60,8 +60,11 @@ use num_traits::float::FloatCore; // round
 
 use crate::{
     board_config::{DSHOT_SPEED, TIM_CLK_SPEED},
-    dshot::{self, calc_crc, REC_BUF_LEN},
-    flight_ctrls::motor_servo::RpmReadings,
+    dshot::{self, calc_crc, Motor, REC_BUF_LEN},
+    flight_ctrls::{
+        control_mapping::ControlMapping,
+        motor_servo::{MotorServoHardware, RpmReadings},
+    },
 };
 
 // Number of counter ticks per bit.
@@ -336,7 +339,6 @@ fn process_rpm(payload: &[u16; REC_BUF_LEN], pole_count: u8) -> Result<f32, RpmE
     // Convert our 20-bit raw GCR data to the 16-bit data packet, using a specific mapping.
     let packet = reduce_bit_count(gcr)?;
 
-    // todo: Don't hard code the motor mapping!!
     match rpm_from_data(packet, pole_count)? {
         EscData::Rpm(rpm) => Ok(rpm),
         EscData::Telem(_, _) => {
@@ -362,25 +364,63 @@ fn error_helper(payload: &[u16; REC_BUF_LEN], fault: &mut bool, pole_count: u8)
     }
 }
 
-/// Update the motor RPM struct with our buffer data.
-/// We delegate to a sub-function for each motor, so we can propogate motor-specific
-/// statuses.
+/// Read RPM from a single motor's DSHOT line, independent of rotor mapping. Used by preflight
+/// motor tests.
+pub fn rpm_from_motor(motor: Motor, pole_count: u8) -> Option<f32> {
+    let payload = unsafe {
+        match motor {
+            Motor::M1 => &dshot::PAYLOAD_REC_1,
+            Motor::M2 => &dshot::PAYLOAD_REC_2,
+            Motor::M3 => &dshot::PAYLOAD_REC_3,
+            Motor::M4 => &dshot::PAYLOAD_REC_4,
+        }
+    };
+
+    process_rpm(payload, pole_count).ok()
+}
+
+/// Read RPM from the DSHOT line of an output. `None` for outputs without DSHOT.
+fn rpm_from_output(output: MotorServoHardware, fault: &mut bool, pole_count: u8) -> Option<f32> {
+    let payload = unsafe {
+        match output {
+            MotorServoHardware::Pin1 => &dshot::PAYLOAD_REC_1,
+            MotorServoHardware::Pin2 => &dshot::PAYLOAD_REC_2,
+            MotorServoHardware::Pin3 => &dshot::PAYLOAD_REC_3,
+            MotorServoHardware::Pin4 => &dshot::PAYLOAD_REC_4,
+            _ => return None,
+        }
+    };
+
+    error_helper(payload, fault, pole_count)
+}
+
+/// Update the motor RPM struct with our buffer data, using the control mapping to find each
+/// motor's output. We delegate to a sub-function for each motor, so we can propogate
+/// motor-specific statuses.
 #[cfg(feature = "quad")]
-pub fn rpm_readings_from_bufs(fault: &mut bool, pole_count: u8) -> RpmReadings {
-    // todo: Don't hard-code the mapping!
+pub fn rpm_readings_from_bufs(
+    fault: &mut bool,
+    pole_count: u8,
+    mapping: &ControlMapping,
+) -> RpmReadings {
     RpmReadings {
-        aft_right: error_helper(&unsafe { dshot::PAYLOAD_REC_1 }, fault, pole_count),
-        front_right: error_helper(&unsafe { dshot::PAYLOAD_REC_2 }, fault, pole_count),
-        aft_left: error_helper(&unsafe { dshot::PAYLOAD_REC_3 }, fault, pole_count),
-        front_left: error_helper(&unsafe { dshot::PAYLOAD_REC_4 }, fault, pole_count),
+        front_left: rpm_from_output(mapping.front_left, fault, pole_count),
+        front_right: rpm_from_output(mapping.front_right, fault, pole_count),
+        aft_left: rpm_from_output(mapping.aft_left, fault, pole_count),
+        aft_right: rpm_from_output(mapping.aft_right, fault, pole_count),
     }
 }
 
 #[cfg(feature = "fixed-wing")]
-pub fn rpm_readings_from_bufs(fault: &mut bool, pole_count: u8) -> RpmReadings {
-    // todo: Don't hard-code the mapping!
+pub fn rpm_readings_from_bufs(
+    fault: &mut bool,
+    pole_count: u8,
+    mapping: &ControlMapping,
+) -> RpmReadings {
     RpmReadings {
-        thrust1: error_helper(&unsafe { dshot::PAYLOAD_REC_1 }, fault, pole_count),
-        thrust2: error_helper(&unsafe { dshot::PAYLOAD_REC_2 }, fault, pole_count),
+        thrust1: rpm_from_output(mapping.motor_thrust1, fault, pole_count),
+        thrust2: mapping
+            .motor_thrust2
+            .and_then(|output| rpm_from_output(output, fault, pole_count)),
     }
 }
@@ -0,0 +1,135 @@
+//! SBUS support, for receiving radio control signals from SBUS receivers, as an alternative to
+//! CRSF. Uses the same UART, DMA channel and ISR as CRSF; which protocol is active is set in user
+//! config, and applied at init.
+//!
+//! https://github.com/bolderflight/sbus/blob/main/README.md
+//!
+//!  * 100000 baud
+//!  * Inverted
+//!  * 8 bits, even parity, 2 stop bits
+//!  * 25-byte frames: Header (0x0f), 22 bytes of 16 11-bit channels, flags, and footer.
+//!  * Sent every 7 or 14ms, with a gap between frames, so UART idle marks the end of each.
+//!
+//! Channel values use the same scale as CRSF, so we convert to CRSF channel data, and all downstream
+//! processing (arm switch, input map etc) is shared.
+
+use core::sync::atomic::AtomicBool;
+
+use defmt::println;
+use hal::{
+    clocks::Clocks,
+    gpio::{Pin, PinMode, Pull},
+    usart::UsartInterrupt,
+};
+
+use crate::{
+    board_config::PIN_CRSF_RX,
+    protocols::crsf::{self, ChannelDataCrsf},
+};
+
+pub const BAUD: u32 = 100_000;
+
+const HEADER: u8 = 0x0f;
+const FOOTER: u8 = 0x00;
+// SBUS2 receivers cycle the footer's upper nibble between telemetry slots.
+const FOOTER_SBUS2_MASK: u8 = 0x0f;
+const FOOTER_SBUS2: u8 = 0x04;
+
+const FRAME_SIZE: usize = 25;
+
+// Flags byte.
+const FLAG_FRAME_LOST: u8 = 1 << 2;
+const FLAG_FAILSAFE: u8 = 1 << 3;
+
+// This buf shift allows us to read frames that we didn't start reading immediately.
+const MAX_BUF_SHIFT: usize = 1;
+const RX_BUF_SIZE: usize = FRAME_SIZE + MAX_BUF_SHIFT;
+
+pub static mut RX_BUFFER: [u8; RX_BUF_SIZE] = [0; RX_BUF_SIZE];
+
+pub static TRANSFER_IN_PROG: AtomicBool = AtomicBool::new(false);
+
+// Used to determine if we have a new frame we haven't yet parsed.
+pub static NEW_PACKET_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// A decoded SBUS frame.
+pub struct Frame {
+    pub channel_data: ChannelDataCrsf,
+    /// The receiver missed a frame from the transmitter; channel values are held from the last one.
+    pub frame_lost: bool,
+    /// The receiver has lost the link, and is sending its failsafe values. We ignore these, so our
+    /// own link-lost handling applies.
+    pub failsafe: bool,
+}
+
+/// Reconfigure the UART for SBUS, and set up the char match and idle interrupts, as with CRSF. Run
+/// this once, on initial firmware setup, in place of `crsf::setup`.
+pub fn setup(uart: &mut crate::setup::UartCrsf, clock_cfg: &Clocks) {
+    // The inverted line idles low; the pull-up we use for CRSF would hold it in a break condition
+    // with no receiver connected.
+    let mut rx_pin = Pin::new(PIN_CRSF_RX.0, PIN_CRSF_RX.1, PinMode::Alt(PIN_CRSF_RX.2));
+    rx_pin.pull(Pull::Down);
+
+    uart.set_baud(BAUD, clock_cfg).ok();
+
+    // Frame format and inversion can only be changed while the UART is disabled.
+    uart.regs.cr1.modify(|_, w| w.ue().clear_bit());
+
+    // Even parity. The word length includes the parity bit, so we use 9-bit words.
+    uart.regs
+        .cr1
+        .modify(|_, w| w.m0().set_bit().pce().set_bit().ps().clear_bit());
+    // 2 stop bits, and invert the RX line.
+    uart.regs
+        .cr2
+        .modify(|_, w| unsafe { w.stop().bits(0b10) }.rxinv().set_bit());
+
+    uart.regs.cr1.modify(|_, w| w.ue().set_bit());
+
+    uart.enable_interrupt(UsartInterrupt::CharDetect(Some(HEADER)));
+    uart.enable_interrupt(UsartInterrupt::Idle);
+}
+
+/// Handle an incoming frame. Triggered whenever the line goes idle.
+pub fn handle_frame(rx_fault: &mut bool) -> Option<Frame> {
+    let mut buf = unsafe { &RX_BUFFER[..] };
+
+    if buf[0] != HEADER {
+        // See the note on this in the CRSF module.
+        if buf[1] == HEADER {
+            buf = &buf[1..];
+        } else {
+            *rx_fault = true;
+            println!("SBUS header error");
+            return None;
+        }
+    }
+
+    let footer = buf[FRAME_SIZE - 1];
+    if footer != FOOTER && footer & FOOTER_SBUS2_MASK != FOOTER_SBUS2 {
+        *rx_fault = true;
+        println!("SBUS footer error: {}", footer);
+        return None;
+    }
+
+    let flags = buf[FRAME_SIZE - 2];
+
+    Some(Frame {
+        channel_data: crsf::unpack_channels(&buf[1..1 + crsf::PAYLOAD_SIZE_RC_CHANNELS]),
+        frame_lost: flags & FLAG_FRAME_LOST != 0,
+        failsafe: flags & FLAG_FAILSAFE != 0,
+    })
+}
+
+/// SBUS doesn't report link quality, so we estimate it from the frame-lost flag, in the same
+/// 0 - 100 format CRSF uses. Moves 1/20th of the way toward 0 or 100 each frame, rounding up, so
+/// it reaches both ends.
+pub fn update_link_quality(lq: &mut u8, frame_lost: bool) {
+    let prev = (*lq).min(100);
+
+    *lq = if frame_lost {
+        prev - (prev + 19) / 20
+    } else {
+        prev + (100 - prev + 19) / 20
+    };
+}
@@ -1,41 +1,207 @@
 //! This module provides a hardware interface for servos.
-//! This are used by fixed-wing, eg for use with elevons.
+//! This are used by fixed-wing control surfaces; see `flight_ctrls::surface_mix`.
+//!
+//! Pulse high time sets servo position. Common hobby servos center at 1.5ms, with endpoints
+//! near 1 and 2ms, but this varies by servo, and by linkage geometry; we set min, center, and
+//! max pulse widths per servo in user config. Update frequency is configurable too: Analog
+//! servos generally require 50Hz; digital servos handle 200Hz or more.
 
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::println;
 use hal::timer::TimChannel;
+use num_enum::TryFromPrimitive;
+
+use crate::{setup::ServoTimer, util};
+
+// We reject pulse widths outside this range, in µs.
+const PULSE_LIMIT_MIN: f32 = 800.;
+const PULSE_LIMIT_MAX: f32 = 2_200.;
 
-use crate::{board_config::ARR_SERVOS, setup::ServoTimer, util};
+// We reject update frequencies outside this range, in Hz.
+const FREQ_MIN: f32 = 50.;
+const FREQ_MAX: f32 = 400.;
 
-// These values are to set middle, min and max values of 1.5ms, 1ms, and 2ms used
-// by common hobby servos. They adjust duty cycle, which sets servo position.
-// Calculations, assuming frequency of 500Hz; 500Hz = 2ms.
-// ARR indicates
+// The servo timer's update frequency, in Hz, as f32 bits. We compute pulse widths from this, vice
+// from user config, so they stay correct if applying a new frequency failed. 0 until set.
+static FREQ: AtomicU32 = AtomicU32::new(0);
 
-const FREQ: f32 = 500.; // This must match the above PSC and ARR.
-const PERIOD: f32 = 1. / FREQ;
+// Control surface servo outputs. Fixed-wing only.
+pub const NUM_SURFACES: usize = 4;
+
+// Serialized sizes. Each servo is min, center, and max pulse width, in µs.
+pub const SERVO_PULSE_CFG_SIZE: usize = 4 * 3;
+pub const SERVO_CFG_SIZE: usize = SERVO_PULSE_CFG_SIZE * NUM_SURFACES + 4;
+
+/// Which end of its travel to move a servo to, for mechanical setup.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ServoJog {
+    Min = 0,
+    Center = 1,
+    Max = 2,
+}
 
-// At 300Hz, it appears the limits ("20g" servo)  are 0.4 to 2.6ms. This corresponds to a range of 3/4 Tau.
-// The min values are full Clockwise; max are full CCW.
-// Other servos use a nominal range between 1 and 2 ms.
-const HIGH_TIME_MIN: f32 = 0.001;
-const HIGH_TIME_MAX: f32 = 0.002;
+impl ServoJog {
+    /// The position this corresponds to, -1. to 1.
+    pub fn posit(&self) -> f32 {
+        match self {
+            Self::Min => -1.,
+            Self::Center => 0.,
+            Self::Max => 1.,
+        }
+    }
+}
 
-const DUTY_MIN: f32 = HIGH_TIME_MIN / PERIOD;
-const DUTY_MAX: f32 = HIGH_TIME_MAX / PERIOD;
+/// Pulse widths for one servo, in µs. Reversal and trim (in position units) are set in the
+/// control mapping; `center` is the mechanical center.
+#[derive(Clone, Copy)]
+pub struct ServoPulseCfg {
+    pub min: f32,
+    pub center: f32,
+    pub max: f32,
+}
 
-const ARR_MIN: f32 = ARR_SERVOS as f32 * DUTY_MIN;
-const ARR_MAX: f32 = ARR_SERVOS as f32 * DUTY_MAX;
+impl Default for ServoPulseCfg {
+    fn default() -> Self {
+        Self {
+            min: 1_000.,
+            center: 1_500.,
+            max: 2_000.,
+        }
+    }
+}
 
-// Let's reason this out for 300Hz Period full = 1/300 = 3.33ms
-// 2ms / 3.33ms = 60% high time for max. 1ms /3.33ms = 30% high time for min.
+impl ServoPulseCfg {
+    /// Convert a position, -1. to 1., to a pulse width in µs. The center is not required to be
+    /// halfway between the endpoints.
+    pub fn pulse_width(&self, posit: f32) -> f32 {
+        let posit = posit.clamp(-1., 1.);
 
-pub fn set_posit(posit: f32, range_in: (f32, f32), timer: &mut ServoTimer, channel: TimChannel) {
-    let mut duty_arr = util::map_linear(posit, range_in, (ARR_MIN, ARR_MAX)) as u32;
+        if posit >= 0. {
+            util::map_linear(posit, (0., 1.), (self.center, self.max))
+        } else {
+            util::map_linear(posit, (-1., 0.), (self.min, self.center))
+        }
+    }
 
-    if duty_arr == ARR_SERVOS {
-        // Allow a bit of low pulse time. This can occur at max duty cycle at 500Hz.
-        duty_arr -= 100;
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Self {
+            min: f32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            center: f32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            max: f32::from_be_bytes(buf[8..12].try_into().unwrap()),
+        }
     }
 
+    pub fn to_bytes(&self) -> [u8; SERVO_PULSE_CFG_SIZE] {
+        let mut result = [0; SERVO_PULSE_CFG_SIZE];
+
+        result[0..4].clone_from_slice(&self.min.to_be_bytes());
+        result[4..8].clone_from_slice(&self.center.to_be_bytes());
+        result[8..12].clone_from_slice(&self.max.to_be_bytes());
+        result
+    }
+
+    pub fn valid(&self) -> bool {
+        // These comparisons also reject NaN.
+        self.min >= PULSE_LIMIT_MIN
+            && self.min < self.center
+            && self.center < self.max
+            && self.max <= PULSE_LIMIT_MAX
+    }
+}
+
+/// Servo output configuration. Stored in user config; fixed-wing only.
+#[derive(Clone, Copy)]
+pub struct ServoCfg {
+    /// By control surface, in the order of `ControlMapping::surfaces`.
+    pub pulses: [ServoPulseCfg; NUM_SURFACES],
+    /// Hz. We default to 50Hz, since all servos support it.
+    pub update_freq: f32,
+}
+
+impl Default for ServoCfg {
+    fn default() -> Self {
+        Self {
+            pulses: Default::default(),
+            update_freq: 50.,
+        }
+    }
+}
+
+impl ServoCfg {
+    /// Parse and validate. Returns `None` if any pulse width range is invalid, or the frequency
+    /// is out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let mut pulses = [ServoPulseCfg::default(); NUM_SURFACES];
+        for (i, pulse) in pulses.iter_mut().enumerate() {
+            let start = i * SERVO_PULSE_CFG_SIZE;
+            *pulse = ServoPulseCfg::from_bytes(&buf[start..start + SERVO_PULSE_CFG_SIZE]);
+        }
+
+        let i = SERVO_PULSE_CFG_SIZE * NUM_SURFACES;
+        let result = Self {
+            pulses,
+            update_freq: f32::from_be_bytes(buf[i..i + 4].try_into().unwrap()),
+        };
+
+        if result.valid() {
+            Some(result)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; SERVO_CFG_SIZE] {
+        let mut result = [0; SERVO_CFG_SIZE];
+
+        for (i, pulse) in self.pulses.iter().enumerate() {
+            let start = i * SERVO_PULSE_CFG_SIZE;
+            result[start..start + SERVO_PULSE_CFG_SIZE].clone_from_slice(&pulse.to_bytes());
+        }
+
+        let i = SERVO_PULSE_CFG_SIZE * NUM_SURFACES;
+        result[i..i + 4].clone_from_slice(&self.update_freq.to_be_bytes());
+        result
+    }
+
+    pub fn valid(&self) -> bool {
+        // Each pulse must end before the next period starts.
+        let period = 1_000_000. / self.update_freq;
+
+        // These comparisons also reject NaN.
+        (FREQ_MIN..=FREQ_MAX).contains(&self.update_freq)
+            && self.pulses.iter().all(|p| p.valid() && p.max < period)
+    }
+}
+
+/// Set the servo timer's update frequency, in Hz, if it's changed. The HAL picks PSC and ARR. Run
+/// at init, and when user config changes. If the HAL can't set it, we keep the previous one.
+pub fn set_freq(freq: f32, timer: &mut ServoTimer) {
+    if freq.to_bits() == FREQ.load(Ordering::Acquire) {
+        return;
+    }
+
+    if timer.set_freq(freq).is_err() {
+        println!("Unable to set the servo update frequency to {}Hz", freq);
+        return;
+    }
+
+    FREQ.store(freq.to_bits(), Ordering::Release);
+}
+
+/// Set a servo's position, -1. to 1. Does nothing until the timer's frequency is set.
+pub fn set_posit(posit: f32, cfg: &ServoPulseCfg, timer: &mut ServoTimer, channel: TimChannel) {
+    let freq = f32::from_bits(FREQ.load(Ordering::Acquire));
+    if freq == 0. {
+        return;
+    }
+
+    // µs to portion of the period.
+    let duty = cfg.pulse_width(posit) * freq / 1_000_000.;
+
+    let duty_arr = (duty * timer.get_max_duty() as f32) as u32;
+
     #[cfg(feature = "h7")]
     let duty_arr = duty_arr as u16;
 
@@ -0,0 +1,465 @@
+//! This module contains the framing for the USB configuration protocol: Each message is a frame,
+//! with a start byte, the protocol version, the message type, and the payload length, followed by
+//! the payload, and a CRC16. Frames are decoded a byte at a time, so they may span USB reads, and
+//! a read may hold several. Message types and payloads are described in `usb_preflight`.
+//!
+//! Format - Byte 0: `FRAME_START`. 1: Protocol version. 2: Message type. 3-4: Payload length (u16,
+//! big endian). Then the payload, then CRC16-CCITT (big endian) over bytes 1 through the end of
+//! the payload.
+//!
+//! On connection, the PC sends `Hello`, with its protocol version; we reply with ours, and whether
+//! we accept it. Other frames are ignored until a version is accepted. Responses use the framing of
+//! the request.
+//!
+//! PC software from before framing sends unframed messages, starting with `anyleaf_usb::MSG_START`.
+//! We still handle these, if they fit in one read, and reply to the first on each connection with
+//! `UpgradeNotice`, so the PC software can prompt for an update.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use anyleaf_usb::{CRC_LEN, PAYLOAD_START_I};
+use defmt::println;
+use usbd_serial::SerialPort;
+
+use crate::{
+    protocols::usb_preflight::{self, MsgType},
+    setup,
+};
+
+pub const FRAME_START: u8 = 0xa7;
+
+/// Incremented when message layouts change incompatibly.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Start, version, message type, and payload length.
+const HEADER_SIZE: usize = 1 + 1 + 1 + 2;
+
+/// Fits the largest payload the PC sends: User config.
+pub const MAX_PAYLOAD_SIZE: usize = 1_024;
+
+const CRC_SIZE: usize = 2;
+
+const MAX_FRAME_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD_SIZE + CRC_SIZE;
+
+/// Microseconds. If the PC goes this long between reads mid-frame, we discard the partial frame.
+const FRAME_TIMEOUT: u64 = 100_000;
+
+const CRC_POLY: u16 = 0x1021;
+const CRC_INIT: u16 = 0xffff;
+const CRC_LUT: [u16; 256] = crc16_init(CRC_POLY);
+
+// The version accepted in the handshake; 0 if none yet.
+static VERSION: AtomicU8 = AtomicU8::new(0);
+
+// Set when the PC last sent a framed message; responses are framed to match.
+static FRAMED: AtomicBool = AtomicBool::new(false);
+
+static UPGRADE_NOTICE_SENT: AtomicBool = AtomicBool::new(false);
+
+const fn crc16_init(poly: u16) -> [u16; 256] {
+    let mut lut = [0; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        // Can't use for loops in const fns
+        let mut crc = (i as u16) << 8;
+
+        let mut j = 0;
+        while j < 8 {
+            crc = (crc << 1) ^ (if (crc & 0x8000) > 0 { poly } else { 0 });
+            j += 1;
+        }
+        lut[i] = crc;
+
+        i += 1;
+    }
+
+    lut
+}
+
+fn crc16_update(crc: u16, byte: u8) -> u16 {
+    (crc << 8) ^ CRC_LUT[((crc >> 8) as u8 ^ byte) as usize]
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(CRC_INIT, |crc, b| crc16_update(crc, *b))
+}
+
+/// Reset the handshake, eg when the USB device leaves the Configured state.
+pub fn reset() {
+    VERSION.store(0, Ordering::Release);
+    FRAMED.store(false, Ordering::Release);
+    UPGRADE_NOTICE_SENT.store(false, Ordering::Release);
+}
+
+/// True if responses should be framed.
+pub fn framed() -> bool {
+    FRAMED.load(Ordering::Acquire)
+}
+
+/// Handle `Hello`, with the PC's protocol version. Replies with ours, and whether we accept it.
+pub fn handshake(pc_version: u8, usb_serial: &mut SerialPort<'static, setup::UsbBusType>) {
+    let accepted = pc_version == PROTOCOL_VERSION;
+
+    if accepted {
+        VERSION.store(pc_version, Ordering::Release);
+        println!("USB protocol version {} accepted", pc_version);
+    } else {
+        VERSION.store(0, Ordering::Release);
+        println!(
+            "USB protocol version {} not supported; ours is {}",
+            pc_version, PROTOCOL_VERSION
+        );
+    }
+
+    // Always framed, so the PC can read it even if we didn't accept its version.
+    send(
+        MsgType::HelloResp,
+        &[PROTOCOL_VERSION, accepted as u8],
+        usb_serial,
+    );
+}
+
+/// Check a received frame against the handshake, and its payload length against its message type.
+/// Returns the message type if it should be handled.
+pub fn check_frame(frame: &Frame) -> Option<MsgType> {
+    let msg_type = match MsgType::try_from(frame.msg_type) {
+        Ok(t) => t,
+        Err(_) => {
+            println!("Invalid message type received over USB");
+            return None;
+        }
+    };
+
+    if msg_type != MsgType::Hello && frame.version != VERSION.load(Ordering::Acquire) {
+        println!("USB frame received without a matching protocol version; ignoring");
+        return None;
+    }
+
+    if frame.payload.len() != msg_type.payload_size() {
+        println!("USB frame payload length doesn't match its message type");
+        return None;
+    }
+
+    FRAMED.store(true, Ordering::Release);
+    Some(msg_type)
+}
+
+/// Run on receiving an unframed message, from PC software that predates framing. Responses are
+/// unframed, and the first on each connection is preceded by `UpgradeNotice`.
+pub fn handle_unframed(usb_serial: &mut SerialPort<'static, setup::UsbBusType>) {
+    FRAMED.store(false, Ordering::Release);
+
+    if !UPGRADE_NOTICE_SENT.swap(true, Ordering::AcqRel) {
+        println!("Unframed USB message received; the PC software should be updated");
+
+        usb_preflight::send_payload::<{ 1 + PAYLOAD_START_I + CRC_LEN }>(
+            MsgType::UpgradeNotice,
+            &[PROTOCOL_VERSION],
+            usb_serial,
+        );
+    }
+}
+
+/// Write a framed message. `payload` must be the message type's payload size.
+pub fn send(
+    msg_type: MsgType,
+    payload: &[u8],
+    usb_serial: &mut SerialPort<'static, setup::UsbBusType>,
+) {
+    let len = payload.len() as u16;
+
+    let mut header = [0; HEADER_SIZE];
+    header[0] = FRAME_START;
+    header[1] = PROTOCOL_VERSION;
+    header[2] = msg_type as u8;
+    header[3..5].clone_from_slice(&len.to_be_bytes());
+
+    let crc = payload
+        .iter()
+        .fold(crc16(&header[1..]), |crc, b| crc16_update(crc, *b));
+
+    usb_serial.write(&header).ok();
+    usb_serial.write(payload).ok();
+    usb_serial.write(&crc.to_be_bytes()).ok();
+}
+
+/// A complete frame, with a valid CRC. The message type isn't checked.
+pub struct Frame<'a> {
+    pub version: u8,
+    pub msg_type: u8,
+    pub payload: &'a [u8],
+}
+
+/// Streaming frame decoder. Feed it bytes in order, eg as they arrive over USB in chunks; it
+/// returns a frame once one is complete and passes its CRC. Bytes are buffered from a start byte
+/// until they complete a frame, or fail its length or CRC check; on failure, we resync on the next
+/// start byte inside the bytes already received, so a stray start byte doesn't cost us the frame
+/// after it. If that uncovers more than one complete frame, the rest are returned on the next
+/// calls.
+pub struct Decoder {
+    /// Starts with `FRAME_START`, if not empty.
+    buf: [u8; MAX_FRAME_SIZE],
+    len: usize,
+    /// The size of the frame returned last; it's discarded on the next call.
+    consumed: usize,
+    /// Microseconds. The time of the last USB read.
+    last_rx: u64,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_SIZE],
+            len: 0,
+            consumed: 0,
+            last_rx: 0,
+        }
+    }
+}
+
+impl Decoder {
+    /// True if we've received part of a frame.
+    pub fn in_frame(&self) -> bool {
+        self.len > self.consumed
+    }
+
+    /// Discard any partial frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.consumed = 0;
+    }
+
+    /// Run on each USB read, with the time from `monotonic::now_us`, before feeding its bytes.
+    /// Discards a partial frame if the PC stopped sending mid-frame, eg if it was interrupted, so it
+    /// doesn't swallow the start of the next one.
+    pub fn rx(&mut self, timestamp: u64) {
+        if self.in_frame() && timestamp.saturating_sub(self.last_rx) > FRAME_TIMEOUT {
+            println!("USB frame timed out; discarding");
+            self.reset();
+        }
+        self.last_rx = timestamp;
+    }
+
+    /// Process a single byte. Returns a frame when one completes.
+    pub fn feed(&mut self, byte: u8) -> Option<Frame> {
+        self.discard(self.consumed);
+        self.consumed = 0;
+
+        if self.len == 0 && byte != FRAME_START {
+            return None;
+        }
+
+        // `decode` never leaves the buffer full: A full buffer holds a frame of any valid length.
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        self.decode()
+    }
+
+    /// Remove bytes from the start of the buffer.
+    fn discard(&mut self, count: usize) {
+        self.buf.copy_within(count..self.len, 0);
+        self.len -= count;
+    }
+
+    /// Discard a frame that failed a check, up to the next start byte after its own.
+    fn resync(&mut self) {
+        let next = self.buf[1..self.len]
+            .iter()
+            .position(|b| *b == FRAME_START)
+            .map(|i| i + 1)
+            .unwrap_or(self.len);
+
+        self.discard(next);
+    }
+
+    fn decode(&mut self) -> Option<Frame> {
+        // After a resync, the remaining bytes may hold a frame, or fail a check themselves.
+        while self.len >= HEADER_SIZE {
+            let payload_len = u16::from_be_bytes([self.buf[3], self.buf[4]]) as usize;
+
+            if payload_len > MAX_PAYLOAD_SIZE {
+                println!("USB frame too long; discarding");
+                self.resync();
+                continue;
+            }
+
+            let crc_i = HEADER_SIZE + payload_len;
+            if self.len < crc_i + CRC_SIZE {
+                return None;
+            }
+
+            let crc = u16::from_be_bytes([self.buf[crc_i], self.buf[crc_i + 1]]);
+            if crc != crc16(&self.buf[1..crc_i]) {
+                println!("Incorrect CRC on USB frame");
+                self.resync();
+                continue;
+            }
+
+            self.consumed = crc_i + CRC_SIZE;
+
+            return Some(Frame {
+                version: self.buf[1],
+                msg_type: self.buf[2],
+                payload: &self.buf[HEADER_SIZE..crc_i],
+            });
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_frame(msg_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![FRAME_START, PROTOCOL_VERSION, msg_type];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        let crc = crc16(&frame[1..]);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame
+    }
+
+    /// Three frames of different lengths, including an empty one.
+    fn frames() -> [Vec<u8>; 3] {
+        let payload: Vec<u8> = (0..60).map(|i| (i * 7) as u8).collect();
+
+        [
+            build_frame(3, &payload),
+            build_frame(5, &[]),
+            build_frame(9, &[1, 2, 3, FRAME_START, 4]),
+        ]
+    }
+
+    /// Feed bytes, and return the message type and payload of each frame decoded.
+    fn feed_all(decoder: &mut Decoder, bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut result = Vec::new();
+
+        for b in bytes {
+            if let Some(frame) = decoder.feed(*b) {
+                assert!(frame.version == PROTOCOL_VERSION);
+                result.push((frame.msg_type, frame.payload.to_vec()));
+            }
+        }
+        result
+    }
+
+    fn expected(frames: &[Vec<u8>]) -> Vec<(u8, Vec<u8>)> {
+        frames
+            .iter()
+            .map(|f| (f[2], f[HEADER_SIZE..f.len() - CRC_SIZE].to_vec()))
+            .collect()
+    }
+
+    /// A simple LCG, so the tests are repeatable without a dependency.
+    fn random_bytes(seed: u32, count: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn concatenated_frames() {
+        let frames = frames();
+        let mut decoder = Decoder::default();
+
+        assert_eq!(feed_all(&mut decoder, &frames.concat()), expected(&frames));
+        assert!(!decoder.in_frame());
+    }
+
+    #[test]
+    fn split_across_reads() {
+        let frames = frames();
+        let stream = frames.concat();
+
+        for chunk_size in [1, 2, 3, 7, 8, 64] {
+            let mut decoder = Decoder::default();
+            let mut decoded = Vec::new();
+
+            for (i, chunk) in stream.chunks(chunk_size).enumerate() {
+                decoder.rx(i as u64 * 1_000);
+                decoded.extend(feed_all(&mut decoder, chunk));
+            }
+            assert_eq!(decoded, expected(&frames));
+        }
+    }
+
+    #[test]
+    fn resync_inside_failed_frame() {
+        let frames = frames();
+
+        // The start of a frame, cut off; its length takes in the frames after it, and the
+        // padding, then fails its CRC. The frames inside it should still decode.
+        let mut stream = vec![FRAME_START, PROTOCOL_VERSION, 3, 0];
+        stream.extend(frames.concat());
+        stream.extend([0; 100]);
+
+        let mut decoder = Decoder::default();
+        assert_eq!(feed_all(&mut decoder, &stream), expected(&frames));
+
+        // A header too long to buffer.
+        let mut stream = vec![FRAME_START, PROTOCOL_VERSION, 3];
+        stream.extend(frames.concat());
+
+        let mut decoder = Decoder::default();
+        assert_eq!(feed_all(&mut decoder, &stream), expected(&frames));
+
+        // A corrupted CRC.
+        let mut stream = frames.concat();
+        let corrupt_i = frames[0].len() - 1;
+        stream[corrupt_i] ^= 1;
+
+        let mut decoder = Decoder::default();
+        assert_eq!(feed_all(&mut decoder, &stream), expected(&frames[1..]));
+    }
+
+    #[test]
+    fn timeout_discards_partial_frame() {
+        let frames = frames();
+        let mut decoder = Decoder::default();
+
+        decoder.rx(0);
+        assert!(feed_all(&mut decoder, &frames[0][..20]).is_empty());
+        assert!(decoder.in_frame());
+
+        // Within the timeout, the partial frame is kept.
+        decoder.rx(FRAME_TIMEOUT);
+        assert!(decoder.in_frame());
+
+        decoder.rx(FRAME_TIMEOUT * 3);
+        assert!(!decoder.in_frame());
+
+        assert_eq!(feed_all(&mut decoder, &frames[2]), expected(&frames[2..]));
+    }
+
+    #[test]
+    fn random_bytes_then_frames() {
+        let frames = frames();
+
+        for seed in 0..50 {
+            let mut decoder = Decoder::default();
+            assert!(feed_all(&mut decoder, &random_bytes(seed, 5_000)).is_empty());
+
+            // Garbage may leave a partial frame that takes in the real ones; padding completes it,
+            // so it fails its CRC, and we resync onto them.
+            let mut stream = frames.concat();
+            stream.extend([0; MAX_FRAME_SIZE]);
+            assert_eq!(feed_all(&mut decoder, &stream), expected(&frames));
+
+            // Or, the PC pauses, and it times out.
+            let mut decoder = Decoder::default();
+            decoder.rx(0);
+            feed_all(&mut decoder, &random_bytes(seed, 5_000));
+            decoder.rx(FRAME_TIMEOUT * 2);
+            assert_eq!(feed_all(&mut decoder, &frames.concat()), expected(&frames));
+        }
+    }
+}
@@ -2,7 +2,8 @@
 //! controller, and a PC running configuration software, over USB. Usd with the `Preflight` PC
 //! software.
 //!
-//! Format - Byte 0: message type. Byte -1: CRC. Rest: payload
+//! Messages are framed as described in `usb_frame`. PC software that predates framing sends them
+//! unframed: Byte 0: start byte. Byte 2: message type. Byte -1: CRC. Rest: payload
 //! We use Little-endian float representations.
 
 // todo: Should we use this module and/or a similar structure for data exchange over RF,
@@ -20,22 +21,100 @@ use hal::flash::Flash;
 use lin_alg::f32::Quaternion;
 
 use crate::{
-    controller_interface::ChannelData,
+    adc_cal::{self, AdcCalCfg, AdcReadings, ADC_CAL_CFG_SIZE, ADC_READINGS_SIZE},
+    alt_estimator::{AltEstimator, ALT_EST_SIZE},
+    batt_failsafe::BATT_FAILSAFE_CFG_SIZE,
+    blackbox::{Blackbox, LogStorage},
+    brownout::{self, BROWNOUT_CFG_SIZE},
+    camera_tilt::{self, CAMERA_TILT_CFG_SIZE},
+    controller_interface::{ChannelData, CHANNEL_MAP_SIZE},
+    debug_log::{LogChannels, LOG_CHANNELS_SIZE},
+    dfu::{self, VERSION_SIZE},
+    dma_stats::{DmaStats, DMA_REPORT_SIZE},
+    drivers::{flash_spi::ExtFlash, imu_icm426xx::IMU_CFG_SIZE},
+    event_log::{self, EVENT_SIZE},
+    ext_ctrl::{self, ExtAttCmd, ExtCtrl, EXT_ATT_CMD_SIZE, EXT_FEEDBACK_SIZE},
     flight_ctrls::{
-        common::AttitudeCommanded,
-        motor_servo::{MotorPower, MotorRpm, MotorServoState},
+        acro_trainer::ACRO_TRAINER_CFG_SIZE,
+        airspeed::AIRSPEED_CFG_SIZE,
+        arm_check::ARM_CHECK_CFG_SIZE,
+        authority::{Authority, AUTHORITY_CFG_SIZE, AUTHORITY_STATUS_SIZE},
+        auto_launch::AUTO_LAUNCH_CFG_SIZE,
+        cmd_updates::ANGLE_ON_CENTER_CFG_SIZE,
+        common::{AttitudeCommanded, RPM_LPF_CFG_SIZE},
+        control_mapping::{ControlMapping, MappingStatus, CONTROL_MAPPING_SIZE},
+        ctrl_effect_est::{AccelMaps, ACCEL_MAPS_FIT_SIZE},
+        dyn_idle::DYN_IDLE_CFG_SIZE,
+        follow_me::{self, FollowTarget, FOLLOW_CFG_SIZE, FOLLOW_TARGET_SIZE},
+        hover_est::HoverThrottleEst,
+        input_cal::{InputCalCollector, INPUT_CAL_SIZE},
+        mixer::{Mixer, MixerPreset, DESAT_CFG_SIZE, MIXER_SIZE},
+        motor_failure::MOTOR_FAIL_CFG_SIZE,
+        motor_servo::{
+            MotorPower, MotorRpm, MotorServoState, RotationDir, OUTPUT_SMOOTHING_CFG_SIZE,
+        },
+        motor_test::{MotorTest, MotorTestCmd},
+        pid::RPM_CTRL_CFG_SIZE,
+        profiles::{self, CtrlProfile, NUM_PROFILES, PROFILE_SIZE},
+        rates::{self, CURVE_SAMPLES_SIZE, RATES_SIZE},
+        stall_protect::STALL_PROTECT_CFG_SIZE,
+        throttle_limit::THROTTLE_LIMIT_CFG_SIZE,
+        thrust_comp::{ThrustComp, THRUST_COMP_CFG_SIZE, THRUST_COMP_STATE_SIZE},
+        tipover::TIPOVER_CFG_SIZE,
+        wind_est::WindEst,
     },
-    safety::ArmStatus,
+    flight_stats::{FlightStatsState, FLIGHT_STATS_SIZE},
+    ground_ref::{GroundRef, GROUND_REF_SIZE, SET_GROUND_REF_SIZE},
+    health_trend::{HealthTrend, HEALTH_RECORD_SIZE},
+    hil::{self, HilSample, HIL_OUTPUT_SIZE, HIL_SAMPLE_SIZE},
+    imu_processing::{
+        accel_health::ACCEL_HEALTH_CFG_SIZE,
+        dual_imu::DUAL_IMU_CFG_SIZE,
+        filter_imu::GyroLpfCfg,
+        fusion::{self, FusionMonitor, FUSION_CFG_SIZE, FUSION_STATUS_SIZE},
+        gyro_temp_comp::GyroTempCal,
+        mag_cal::MagCalCollector,
+    },
+    indicators::{self, INDICATOR_CFG_SIZE},
+    led_strip::LED_STRIP_CFG_SIZE,
+    link_failsafe::LINK_FAILSAFE_CFG_SIZE,
+    lost_craft::LostCraft,
+    motor_wizard::{
+        Corner, MotorWizard, MOTOR_WIZARD_ANSWER_SIZE, MOTOR_WIZARD_START_SIZE,
+        MOTOR_WIZARD_STATUS_SIZE,
+    },
+    output_pattern::{
+        OutputPattern, Pattern, OUTPUT_PATTERN_START_SIZE, OUTPUT_PATTERN_STATUS_SIZE,
+    },
+    params::{
+        self, PARAMS_EXPORT_SIZE, PARAMS_IMPORT_RESULT_SIZE, PARAM_INFO_SIZE, PARAM_SET_SIZE,
+        PARAM_VALUE_SIZE,
+    },
+    protocols::{
+        dshot::{self, Motor},
+        esc_telemetry::{EscTelemetryState, NUM_ESCS},
+        servo::{ServoJog, SERVO_CFG_SIZE},
+        usb_frame,
+        usb_telem::{self, TELEM_SNAPSHOT_SIZE},
+    },
+    rth::RTH_CFG_SIZE,
+    safety::{ArmStatus, ARM_CFG_SIZE},
+    self_test::{SelfTest, SELF_TEST_REPORT_SIZE},
     setup,
-    state::{OperationMode, UserConfig, MAX_WAYPOINTS},
+    state::{
+        self, OperationMode, UserConfig, Warnings, MAX_WAYPOINTS, WARNINGS_SIZE, WARNING_INFO_SIZE,
+    },
+    storage::NonVolatileStorage,
     system_status::{self, SystemStatus},
     util,
+    vib_test::{VibTest, VIB_RESULT_SIZE},
 };
 
 cfg_if! {
     if #[cfg(feature = "fixed-wing")] {
         // use crate::flight_ctrls::ServoWingPosition;
         use crate::flight_ctrls;
+        use crate::protocols::servo;
     } else {
         // use crate::flight_ctrls::{RotorPosition};
     }
@@ -44,7 +123,10 @@ cfg_if! {
 use num_enum::TryFromPrimitive;
 use usbd_serial::SerialPort;
 
-use crate::{flight_ctrls::autopilot::AutopilotStatus, protocols::crsf::LinkStats}; // Enum from integer
+use crate::{
+    flight_ctrls::autopilot::AutopilotStatus,
+    protocols::crsf::{LinkStats, NUM_CHANNELS},
+}; // Enum from integer
 
 const CRC_POLY: u8 = 0xab;
 const CRC_LUT: [u8; 256] = util::crc_init(CRC_POLY);
@@ -57,7 +139,8 @@ const QUATERNION_SIZE: usize = F32_SIZE * 4;
 // Quaternion attitude + quaternion target + altimeter_baro + altimeter_agl +
 // + option byte for altimeter + voltage reading + current reading.
 
-const PARAMS_SIZE: usize = 2 * QUATERNION_SIZE + 6 * F32_SIZE + 1 + 4 * 3 + 1 + F32_SIZE * 4; //
+const PARAMS_SIZE: usize =
+    2 * QUATERNION_SIZE + 6 * F32_SIZE + 1 + 4 * 3 + 1 + F32_SIZE * 4 + F32_SIZE; // Last is IMU temp.
 const CONTROLS_SIZE: usize = 19; // Includes first byte as an Option byte.
                                  // const LINK_STATS_SIZE: usize = F32_BYTES * 4; // Only the first 4 fields.
 const LINK_STATS_SIZE: usize = 5; // Only 5 fields.
@@ -67,17 +150,84 @@ const WAYPOINT_MAX_NAME_LEN: usize = 12; // todo
 pub const WAYPOINT_SIZE: usize = F32_SIZE * 3 + WAYPOINT_MAX_NAME_LEN + 1;
 pub const WAYPOINTS_SIZE: usize = crate::state::MAX_WAYPOINTS * WAYPOINT_SIZE;
 pub const SET_SERVO_POSIT_SIZE: usize = 1 + F32_SIZE; // Servo num, value
-pub const SYS_STATUS_SIZE: usize = 12; // Sensor status (u8) * 12
+
+// Sensor status (u8) * 12, 4 flags, and stale counts (u16) for IMU, baro, GPS, mag, and TOF.
+// Then the low-battery failsafe stage, and the IMU config mismatch flag.
+pub const SYS_STATUS_SIZE: usize = 22 + 2 * 7 + 1 + 1 + 1 + 1 + 1;
 pub const AP_STATUS_SIZE: usize = 12; //
 pub const SYS_AP_STATUS_SIZE: usize = SYS_STATUS_SIZE + AP_STATUS_SIZE;
-#[cfg(feature = "quad")]
-pub const CONTROL_MAPPING_SIZE: usize = 2; // Packed tightly!
-                                           // todo: May need to change to add `servo_high` etc.
-#[cfg(feature = "fixed-wing")]
-pub const CONTROL_MAPPING_SIZE: usize = 2; // Packed tightly! todo?
 pub const SET_MOTOR_POWER_SIZE: usize = F32_SIZE * 4;
 
-pub const CONFIG_SIZE: usize = F32_SIZE * 8;
+pub const CONFIG_SIZE: usize = F32_SIZE * 22
+    + 7
+    + CONTROL_MAPPING_SIZE
+    + INPUT_CAL_SIZE
+    + RATES_SIZE
+    + THRUST_COMP_CFG_SIZE
+    + MIXER_SIZE
+    + F32_SIZE
+    + RPM_CTRL_CFG_SIZE
+    + 1
+    + ANGLE_ON_CENTER_CFG_SIZE
+    + F32_SIZE
+    + SERVO_CFG_SIZE
+    + AIRSPEED_CFG_SIZE
+    + 2
+    + F32_SIZE
+    + OUTPUT_SMOOTHING_CFG_SIZE
+    + 3
+    + DYN_IDLE_CFG_SIZE
+    + F32_SIZE
+    + STALL_PROTECT_CFG_SIZE
+    + CHANNEL_MAP_SIZE
+    + ARM_CFG_SIZE
+    + INDICATOR_CFG_SIZE
+    + LED_STRIP_CFG_SIZE
+    + CAMERA_TILT_CFG_SIZE
+    + BROWNOUT_CFG_SIZE
+    + PROFILE_SIZE * NUM_PROFILES
+    + 1
+    + ADC_CAL_CFG_SIZE
+    + ACCEL_HEALTH_CFG_SIZE
+    + MOTOR_FAIL_CFG_SIZE
+    + 1
+    + ACRO_TRAINER_CFG_SIZE
+    + FOLLOW_CFG_SIZE
+    + TIPOVER_CFG_SIZE
+    + THROTTLE_LIMIT_CFG_SIZE
+    + RPM_LPF_CFG_SIZE
+    + BATT_FAILSAFE_CFG_SIZE
+    + IMU_CFG_SIZE
+    + 1
+    + DESAT_CFG_SIZE
+    + AUTO_LAUNCH_CFG_SIZE
+    + LINK_FAILSAFE_CFG_SIZE
+    + FUSION_CFG_SIZE
+    + AUTHORITY_CFG_SIZE
+    + RTH_CFG_SIZE
+    + DUAL_IMU_CFG_SIZE
+    + ARM_CHECK_CFG_SIZE;
+pub const RAW_CHANNELS_SIZE: usize = 1 + NUM_CHANNELS * 2;
+pub const LAST_POSIT_MSG_SIZE: usize = 1 + 4 * 3;
+// Events are sent in groups of this many, to keep messages short.
+pub const EVENTS_PER_MSG: usize = 6;
+pub const EVENT_LOG_MSG_SIZE: usize = 2 + EVENTS_PER_MSG * EVENT_SIZE; // Count and start index.
+pub const GYRO_LPF_SIZE: usize = 2 * (1 + F32_SIZE); // Type and cutoff, for each stage.
+pub const LOG_CHUNK_DATA_SIZE: usize = 64;
+pub const LOG_CHUNK_SIZE: usize = 4 + LOG_CHUNK_DATA_SIZE; // Offset (u32), and data.
+
+// Per ESC: Reading present, temperature, voltage, current, consumption, and eRPM.
+const ESC_TELEM_SIZE_PER: usize = 1 + F32_SIZE * 5;
+pub const ESC_TELEM_SIZE: usize = NUM_ESCS * ESC_TELEM_SIZE_PER;
+pub const MOTOR_TEST_START_SIZE: usize = 1 + F32_SIZE + 2; // Motor, power, duration (ms, u16)
+pub const MOTOR_TEST_STATUS_SIZE: usize = 3 + F32_SIZE; // Active, motor, RPM present, RPM.
+pub const VIB_TEST_RESULT_SIZE: usize = 3 + VIB_RESULT_SIZE; // Status, index, result present.
+
+// Totals, then armed and airborne time this flight.
+pub const FLIGHT_STATS_MSG_SIZE: usize = FLIGHT_STATS_SIZE + F32_SIZE * 2;
+pub const PROFILE_MSG_SIZE: usize = 2 + PROFILE_SIZE; // Index, active index, and the profile.
+pub const ADC_CAL_MSG_SIZE: usize = ADC_CAL_CFG_SIZE + ADC_READINGS_SIZE;
+pub const HEALTH_TREND_MSG_SIZE: usize = 3 + HEALTH_RECORD_SIZE; // Count, index, record present.
 
 // const START_BYTE: u8 =
 
@@ -130,6 +280,319 @@ pub enum MsgType {
     ReqConfig = 23,
     SaveConfig = 24,
     CalibrateAccel = 25,
+    /// Capture a gyro bias point for temperature compensation. Send once cold, and again once warm.
+    CalibrateGyroTemp = 26,
+    /// Start logging mag readings for calibration. Rotate the aircraft in a figure-eight after sending.
+    StartMagCal = 27,
+    /// Stop logging mag readings, compute the calibration, and save it.
+    EndMagCal = 28,
+    /// Request the gyro lowpass settings actually running. (From PC)
+    ReqGyroLpf = 29,
+    /// Gyro lowpass settings actually running, with cutoffs after clamping. (From FC)
+    GyroLpf = 30,
+    /// Request the size of the blackbox log, in bytes. (From PC)
+    ReqLogSize = 31,
+    /// Blackbox log size, in bytes, as a u32. (From FC)
+    LogSize = 32,
+    /// Request a chunk of the blackbox log, at a byte offset (u32). (From PC)
+    ReqLogChunk = 33,
+    /// A chunk of the blackbox log: the offset it starts at, then its data. The frame CRC
+    /// covers the chunk. (From FC)
+    LogChunk = 34,
+    /// Enable or disable blackbox logging while armed. (From PC)
+    SetBlackboxEnabled = 35,
+    /// Erase the blackbox log. This takes a while. (From PC)
+    EraseLog = 36,
+    ReqEscTelemetry = 37,
+    /// Latest UART telemetry from each ESC. (From FC)
+    EscTelemetry = 38,
+    /// The user confirms props are removed. Required before motor tests. (From PC)
+    AckPropsOff = 39,
+    /// Spin a single motor: motor index (0-3), power (0. to 1.), and duration in ms. Power
+    /// and duration are capped. Preflight mode only. (From PC)
+    MotorTestStart = 40,
+    /// Must be sent at least every 200ms while a motor test runs, or it stops. (From PC)
+    MotorTestKeepAlive = 41,
+    /// Stop all motors. (From PC)
+    MotorTestStop = 42,
+    ReqMotorTestStatus = 43,
+    /// Whether a test is running, which motor, and its RPM reading. (From FC)
+    MotorTestStatus = 44,
+    /// Set and save the full control mapping. Preflight mode only. (From PC)
+    SetControlMapping = 45,
+    /// The result of `SetControlMapping`; a `MappingStatus` code. (From FC)
+    ControlMappingStatus = 46,
+    /// Start control input calibration. Disarmed only. Request status to prompt the user
+    /// through its stages. (From PC)
+    StartInputCal = 47,
+    ReqInputCalStatus = 48,
+    /// The current calibration stage; an `InputCalStage` code. (From FC)
+    InputCalStatus = 49,
+    ReqInputCal = 50,
+    /// Calibrated min, center, and max for pitch, roll, yaw, and throttle. (From FC)
+    InputCal = 51,
+    /// Reset the input calibration to defaults, and save. (From PC)
+    ResetInputCal = 52,
+    ReqRates = 53,
+    /// Center sensitivity (deg/s), max rate (deg/s), and expo, for pitch, roll, and yaw.
+    /// (From FC)
+    Rates = 54,
+    /// Set and save rates, in the same format as `Rates`. (From PC)
+    SetRates = 55,
+    ReqRateCurves = 56,
+    /// Each axis's rate curve, in deg/s, at evenly-spaced stick deflections from 0 to full.
+    /// (From FC)
+    RateCurves = 57,
+    ReqThrustComp = 58,
+    /// Filtered battery voltage per cell, voltage compensation multiplier, and throttle after
+    /// compensation. (From FC)
+    ThrustComp = 59,
+    ReqMixer = 60,
+    /// The active mixer preset and weights. (From FC)
+    Mixer = 61,
+    /// Set and save the mixer: a preset, and weights. Weights are ignored unless the preset is
+    /// Custom. Replies with `Mixer`. Disarmed only. (From PC)
+    SetMixer = 62,
+    #[cfg(feature = "fixed-wing")]
+    /// Move a servo to its min, center, or max pulse width, for mechanical setup. Payload is the
+    /// control surface (0 - 3, as in `ControlMapping::surfaces`), then the position (0: min, 1:
+    /// center, 2: max).
+    /// Disarmed only. (From PC)
+    ServoJog = 63,
+    /// Run the sensor self-test. The aircraft must be stationary. Preflight mode, disarmed, with
+    /// motors stopped only. (From PC)
+    StartSelfTest = 64,
+    ReqSelfTest = 65,
+    /// Test state (0: never run, 1: in progress, 2: complete), then the status and a numeric
+    /// detail for each check. See `SelfTestReport`. (From FC)
+    SelfTest = 66,
+    /// Read the external flash's JEDEC ID, for hardware verification. (From PC)
+    ReqFlashId = 67,
+    /// Whether the external flash was detected at init, then the JEDEC ID just read: manufacturer,
+    /// memory type, and capacity. The ID is 0s if the read failed. (From FC)
+    FlashId = 68,
+    ReqHoverThrottle = 69,
+    /// The current hover throttle estimate, then the one saved in config. (From FC)
+    HoverThrottle = 70,
+    ReqWindEst = 71,
+    /// Whether the wind estimate is valid, then wind speed in m/s, and the direction it's from,
+    /// in radians. (From FC)
+    WindEst = 72,
+    ReqRawChannels = 73,
+    /// Whether channel data is present, then all 16 receiver channels, as received; u16 each.
+    /// Used to identify switches when setting up the channel map. (From FC)
+    RawChannels = 74,
+    /// Sound the lost-model beacon, or stop it: 1 or 0. Works whether or not the link is up.
+    /// (From PC)
+    SetBeacon = 75,
+    ReqLastPosit = 76,
+    /// The last known position: Its source (0: none, 1: a fix since power-up, 2: saved prior to
+    /// power-up), then lat and lon in degrees x 1e7 (i32), and altitude MSL in m. (From FC)
+    LastPosit = 77,
+    /// Request events from the event log, starting at an index; 0 is the oldest. (From PC)
+    ReqEventLog = 78,
+    /// The number of events stored, the start index, then up to `EVENTS_PER_MSG` events, oldest
+    /// first. Each is timestamp (f32, s since power-up), code, and two u16 payload words. Slots
+    /// past the end of the log are 0. (From FC)
+    EventLog = 79,
+    /// Start the vibration analysis, with motor power (f32, 0. to 1.). The motor test interlocks
+    /// apply, and the PC must send `MotorTestKeepAlive` while it runs; `MotorTestStop` aborts it.
+    /// (From PC)
+    StartVibTest = 80,
+    /// Request a vibration result by index: 0 for motors stopped, then 1 - 4 for each motor.
+    /// (From PC)
+    ReqVibTestResult = 81,
+    /// Test status (0: idle, 1: running, 2: complete, 3: aborted), the index, and whether that
+    /// result is present. Then, for pitch, roll, and yaw: RMS in rad/s, the dominant frequency in
+    /// Hz, and its amplitude in rad/s. (From FC)
+    VibTestResult = 82,
+    /// Hold the camera tilt servo at its min, center, or max pulse width, for mechanical setup:
+    /// 0, 1, or 2. 0xff releases it. Only while disarmed. (From PC)
+    CameraTiltJog = 83,
+    /// Start streaming `TelemSnapshot`s at a rate in Hz (u8, 10 - 200), or stop: 0. Streaming
+    /// stops on its own if the PC stops reading. (From PC)
+    SetTelemStream = 84,
+    /// Sequence number (u32), timestamp (s), attitude, pitch/roll/yaw rates, attitude commanded,
+    /// rates commanded, 4 motor powers, 4 RPMs, RPM present flags (bit per motor), battery voltage
+    /// and current, baro altitude MSL, autopilot mode flags (u16), arm status, whether takeoff
+    /// has been detected, the active control profile's index, and the flight mode (u8; see
+    /// `state::FlightMode`). (From FC)
+    TelemSnapshot = 85,
+    /// Reboot into the MCU's USB DFU bootloader, for a firmware update. Only in Preflight, while
+    /// disarmed, with motors stopped. The device disconnects. (From PC)
+    EnterBootloader = 86,
+    ReqVersion = 87,
+    /// Major, minor, and patch version, then the first 8 characters of the git commit hash, as
+    /// ASCII; blank if unavailable at build time. (From FC)
+    Version = 88,
+    ReqTakeoffState = 89,
+    /// Whether takeoff has been detected: 1 or 0. Until it has, attitude corrections are
+    /// attenuated or ignored, eg on the bench. Resets on disarm, and on landing. (From FC)
+    TakeoffState = 90,
+    ReqFlightStats = 91,
+    /// Cumulative airframe statistics: Flights (u32), airborne time in s, mAh used, max altitude
+    /// above the arming point in m, and max ground speed in m/s. Then, for the current or most
+    /// recent flight, armed time and airborne time, in s. (From FC)
+    FlightStats = 92,
+    /// Request a control profile, by index (u8, 0 - 2). (From PC)
+    ReqProfile = 93,
+    /// The profile's index, the active profile's index, then P, I, D, and attitude TTC, control
+    /// TTCs, rate curves as in `Rates`, and each gyro lowpass stage's type and cutoff. (From FC)
+    Profile = 94,
+    /// A profile's index, then its values, as in `Profile`. If it's the active profile, it takes
+    /// effect immediately. Saved to flash. (From PC)
+    SetProfile = 95,
+    /// Make a profile active, by index. Resets the PID integrators. (From PC)
+    SelectProfile = 96,
+    ReqAdcCal = 97,
+    /// Battery divider ratio, current sensor slope in mV/A, and current zero offset in V, then
+    /// raw ADC counts for voltage and current (u16), and the calibrated voltage and current.
+    /// (From FC)
+    AdcCal = 98,
+    /// Divider ratio, current slope, and current offset, as in `AdcCal`. Saved to flash. (From PC)
+    SetAdcCal = 99,
+    /// The true battery voltage, measured with a multimeter. We compute the divider ratio from
+    /// it, and save. Only while disarmed. (From PC)
+    CalBattV = 100,
+    /// Record the current sensor's output as its zero. Only while disarmed, with motors
+    /// stopped. (From PC)
+    CalCurrentZero = 101,
+    /// 1 to enable HIL mode, 0 to disable. Enabling requires Preflight, disarmed, with motors
+    /// stopped; disabling requires disarmed. See `hil`. (From PC)
+    SetHil = 102,
+    /// Synthetic gyro (rad/s) and accel (m/s^2) readings, baro altitude MSL and vertical
+    /// velocity, then a GPS valid flag (u8), lat and lon (i32, e7), altitude MSL, ground speed,
+    /// and course. Ignored unless HIL is active. (From PC)
+    HilSample = 103,
+    /// Sent after each flight control update while HIL is active: Timestamp in s, motor powers
+    /// 0. to 1., and arm status (u8). (From FC)
+    HilOutput = 104,
+    ReqDmaReport = 105,
+    /// Per DMA transfer, in `setup::DmaTransfer` order: Transfer ID, controller (1 or 2),
+    /// channel, priority (0 - 3, low to very high), transfer error count (u16), and FIFO error
+    /// count (u16; H7 only). Counts are since power-up. (From FC)
+    DmaReport = 106,
+    /// A follow-me target: Lat and lon in degrees x 1e7 (i32), and altitude MSL in m. Send
+    /// periodically, eg at 1 Hz, as the target moves. See `follow_me`. (From PC)
+    FollowTarget = 107,
+    /// Start the motor setup wizard, with motor power (f32, 0. to 1.), and the rotation
+    /// direction of the front-left and aft-right motors for the prop layout (0: CW, 1: CCW). The
+    /// motor test interlocks apply, and the PC must send `MotorTestKeepAlive` while motors spin;
+    /// `MotorTestStop` aborts it. See `motor_wizard`. Quad only. (From PC)
+    StartMotorWizard = 108,
+    /// Identify the motor that just spun: Its corner (0: front left, 1: front right, 2: aft
+    /// left, 3: aft right), then the direction it spun, seen from above (0: CW, 1: CCW).
+    /// (From PC)
+    MotorWizardAnswer = 109,
+    ReqMotorWizardStatus = 110,
+    /// Wizard status (See `MotorWizardStatus`), and the output being identified (0xff if none).
+    /// Then for each output, 1 - 4: The RPM telemetry slot that responded, the corner
+    /// identified, and whether it's reversed in the resulting mapping. Slots and corners not
+    /// identified yet are 0xff. (From FC)
+    MotorWizardStatus = 111,
+    ReqAltEst = 112,
+    /// The altitude estimate: MSL, AGL, and the offset added to baro altitude to match GPS, in
+    /// m. Then the MSL source, the AGL source (0: none, 1: baro, 2: GPS, 3: TOF), and whether
+    /// AGL is valid. See `alt_estimator`. (From FC)
+    AltEst = 113,
+    /// Request a flight's health trend record, by index (u8); 0 is the oldest stored. (From PC)
+    ReqHealthTrend = 114,
+    /// Flights stored, the index, and whether that record is present. Then the flight number
+    /// (u32), airborne time in s (u16), average power for each motor (u16, 1e-4 units), average
+    /// RPM for each motor (u16), and average command for control surfaces 1 - 3 while flying
+    /// straight (i16, 1e-4 units; fixed-wing only). See `health_trend`. (From FC)
+    HealthTrend = 115,
+    /// Start an output test pattern (0: ramp all motors, 1: step through each motor, 2: sweep
+    /// each servo; fixed-wing only), with motor power (f32, 0. to 1.). The motor test interlocks
+    /// apply, and the PC must send `MotorTestKeepAlive` while it runs; `MotorTestStop` stops it.
+    /// See `output_pattern`. (From PC)
+    StartOutputPattern = 116,
+    ReqOutputPatternStatus = 117,
+    /// Status (See `PatternStatus`), the pattern running or last run, the motor or servo active
+    /// (0xff if none), and progress in percent. (From FC)
+    OutputPatternStatus = 118,
+    /// Sent on connection, to start the handshake: The PC's protocol version. Framed. See
+    /// `usb_frame`. (From PC)
+    Hello = 119,
+    /// Our protocol version, and whether we accepted the PC's. Framed. (From FC)
+    HelloResp = 120,
+    /// Sent unframed, in reply to the first unframed message on a connection: Our protocol
+    /// version. The PC software predates framing, and should be updated. (From FC)
+    UpgradeNotice = 121,
+    ReqAccelMapFit = 122,
+    /// Control effect maps: Whether they were loaded from flash at power-up (1 or 0), then for each
+    /// of pitch, roll, and yaw: Coefficients in use (squared, linear, and constant; f32), fitted
+    /// coefficients, samples fit and rejected (u32), RMS residual, R², and whether the fit is
+    /// trustworthy; only then are its coefficients used. See `ctrl_effect_est`. (From FC)
+    AccelMapFit = 123,
+    /// Request a parameter's description, by index in the parameter table (u16), to list them.
+    /// See `params`. (From PC)
+    ReqParamInfo = 124,
+    /// Parameter count (u16), index (u16), ID (u16), type, and flags (bit 0: flight-critical);
+    /// then min, max, default, and current value; then the name, padded with 0s. ID is 0xffff
+    /// past the end of the table. (From FC)
+    ParamInfo = 125,
+    /// Request a parameter's value, by ID (u16). Replies with `ParamValue`. (From PC)
+    ReqParamValue = 126,
+    /// ID (u16), status (See `ParamStatus`), and value. (From FC)
+    ParamValue = 127,
+    /// Write a parameter: ID (u16), and value. Validated, and saved once disarmed. Flight-critical
+    /// parameters can't be written while armed. Replies with `ParamValue`. (From PC)
+    SetParam = 128,
+    ReqParamExport = 129,
+    /// All parameter values, for backup: Count (u16), then ID (u16) and value for each. (From FC)
+    ParamExport = 130,
+    /// Restore parameter values, in the format of `ParamExport`. Each is validated as in
+    /// `SetParam`. Replies with `ParamImportResult`. (From PC)
+    ParamImport = 131,
+    /// Parameters applied, and rejected (u16 each). (From FC)
+    ParamImportResult = 132,
+    ReqWarnings = 133,
+    /// Active, and latched warning flags (u32 each); bits are warning IDs. See `state::Warning`.
+    /// (From FC)
+    Warnings = 134,
+    /// Request a warning's description, by index in the warning table, to list them. (From PC)
+    ReqWarningInfo = 135,
+    /// Warning count, index, ID, and severity (See `state::Severity`); then the label, padded with
+    /// 0s. ID is 0xff past the end of the table. (From FC)
+    WarningInfo = 136,
+    /// Clear latched warnings whose conditions have cleared. Replies with `Warnings`. (From PC)
+    ClearWarnings = 137,
+    ReqLogChannels = 138,
+    /// Debug output channels enabled, as a bitmask (See `debug_log::LogChannel`), and whether
+    /// output is compiled in, with the `print-status` feature. (From FC)
+    LogChannels = 139,
+    /// Set the debug output channels enabled, as a bitmask. Replies with `LogChannels`. Not saved.
+    /// (From PC)
+    SetLogChannels = 140,
+    ReqGroundRef = 141,
+    /// The ground reference: Source (0: none, 1: captured on arming, 2: set manually), field
+    /// elevation in m MSL, altitude above takeoff in m, and whether it's valid. See `ground_ref`.
+    /// (From FC)
+    GroundRef = 142,
+    /// Set the field elevation manually: Set (1), or clear (0), then elevation in m MSL. Eg when
+    /// arming on a moving platform. Rejected once airborne. Replies with `GroundRef`. Not saved.
+    /// (From PC)
+    SetGroundRef = 143,
+    ReqFusionStatus = 144,
+    /// Attitude convergence (0: aligning, 1: converged, 2: diverged), the mean gravity residual
+    /// over the last second in radians, and the fusion gain in effect. See `fusion`. (From FC)
+    FusionStatus = 145,
+    /// Reset the attitude estimate, eg after moving the craft on the bench while powered. It
+    /// re-aligns; arming is blocked until it converges. Ignored unless disarmed. Replies with
+    /// `FusionStatus`. (From PC)
+    ResetAttitude = 146,
+    ReqExtFeedback = 147,
+    /// Whether external control is active, the attitude estimate (w, x, y, z), and pitch, roll,
+    /// and yaw rates in rad/s. See `ext_ctrl`. (From FC)
+    ExtFeedback = 148,
+    /// An external attitude command: Attitude quaternion (w, x, y, z), and throttle, 0. to 1.
+    /// Used while the `ext_ctrl` switch is on. Replies with `ExtFeedback`. (From PC)
+    SetExtAttitude = 149,
+    ReqAuthority = 150,
+    /// Control authority scaling: Enabled, and frozen; then for pitch, roll, and yaw: If the
+    /// reference is captured, scale, and drag coefficient in 1/s. See `authority`. (From FC)
+    Authority = 151,
 }
 
 impl MessageType for MsgType {
@@ -137,7 +600,7 @@ impl MessageType for MsgType {
         *self as u8
     }
 
-    fn payload_size(&self) -> usize {
+    pub fn payload_size(&self) -> usize {
         match self {
             Self::Params => PARAMS_SIZE,
             Self::SetMotorDirs => 1, // Packed bits: motors 1-4, R-L. True = CW.
@@ -166,6 +629,133 @@ impl MessageType for MsgType {
             Self::ReqConfig => 0,
             Self::SaveConfig => CONFIG_SIZE,
             Self::CalibrateAccel => 0,
+            Self::CalibrateGyroTemp => 0,
+            Self::StartMagCal => 0,
+            Self::EndMagCal => 0,
+            Self::ReqGyroLpf => 0,
+            Self::GyroLpf => GYRO_LPF_SIZE,
+            Self::ReqLogSize => 0,
+            Self::LogSize => 4,
+            Self::ReqLogChunk => 4,
+            Self::LogChunk => LOG_CHUNK_SIZE,
+            Self::SetBlackboxEnabled => 1,
+            Self::EraseLog => 0,
+            Self::ReqEscTelemetry => 0,
+            Self::EscTelemetry => ESC_TELEM_SIZE,
+            Self::AckPropsOff => 0,
+            Self::MotorTestStart => MOTOR_TEST_START_SIZE,
+            Self::MotorTestKeepAlive => 0,
+            Self::MotorTestStop => 0,
+            Self::ReqMotorTestStatus => 0,
+            Self::MotorTestStatus => MOTOR_TEST_STATUS_SIZE,
+            Self::SetControlMapping => CONTROL_MAPPING_SIZE,
+            Self::ControlMappingStatus => 1,
+            Self::StartInputCal => 0,
+            Self::ReqInputCalStatus => 0,
+            Self::InputCalStatus => 1,
+            Self::ReqInputCal => 0,
+            Self::InputCal => INPUT_CAL_SIZE,
+            Self::ResetInputCal => 0,
+            Self::ReqRates => 0,
+            Self::Rates => RATES_SIZE,
+            Self::SetRates => RATES_SIZE,
+            Self::ReqRateCurves => 0,
+            Self::RateCurves => CURVE_SAMPLES_SIZE,
+            Self::ReqThrustComp => 0,
+            Self::ThrustComp => THRUST_COMP_STATE_SIZE,
+            Self::ReqMixer => 0,
+            Self::Mixer => MIXER_SIZE,
+            Self::SetMixer => MIXER_SIZE,
+            #[cfg(feature = "fixed-wing")]
+            Self::ServoJog => 2,
+            Self::StartSelfTest => 0,
+            Self::ReqSelfTest => 0,
+            Self::SelfTest => SELF_TEST_REPORT_SIZE,
+            Self::ReqFlashId => 0,
+            Self::FlashId => 4,
+            Self::ReqHoverThrottle => 0,
+            Self::HoverThrottle => F32_SIZE * 2,
+            Self::ReqWindEst => 0,
+            Self::WindEst => 1 + F32_SIZE * 2,
+            Self::ReqRawChannels => 0,
+            Self::RawChannels => RAW_CHANNELS_SIZE,
+            Self::SetBeacon => 1,
+            Self::ReqLastPosit => 0,
+            Self::LastPosit => LAST_POSIT_MSG_SIZE,
+            Self::ReqEventLog => 1,
+            Self::EventLog => EVENT_LOG_MSG_SIZE,
+            Self::StartVibTest => F32_SIZE,
+            Self::ReqVibTestResult => 1,
+            Self::VibTestResult => VIB_TEST_RESULT_SIZE,
+            Self::CameraTiltJog => 1,
+            Self::SetTelemStream => 1,
+            Self::TelemSnapshot => TELEM_SNAPSHOT_SIZE,
+            Self::EnterBootloader => 0,
+            Self::ReqVersion => 0,
+            Self::Version => VERSION_SIZE,
+            Self::ReqTakeoffState => 0,
+            Self::TakeoffState => 1,
+            Self::ReqFlightStats => 0,
+            Self::FlightStats => FLIGHT_STATS_MSG_SIZE,
+            Self::ReqProfile => 1,
+            Self::Profile => PROFILE_MSG_SIZE,
+            Self::SetProfile => 1 + PROFILE_SIZE,
+            Self::SelectProfile => 1,
+            Self::ReqAdcCal => 0,
+            Self::AdcCal => ADC_CAL_MSG_SIZE,
+            Self::SetAdcCal => ADC_CAL_CFG_SIZE,
+            Self::CalBattV => F32_SIZE,
+            Self::CalCurrentZero => 0,
+            Self::SetHil => 1,
+            Self::HilSample => HIL_SAMPLE_SIZE,
+            Self::HilOutput => HIL_OUTPUT_SIZE,
+            Self::ReqDmaReport => 0,
+            Self::DmaReport => DMA_REPORT_SIZE,
+            Self::FollowTarget => FOLLOW_TARGET_SIZE,
+            Self::StartMotorWizard => MOTOR_WIZARD_START_SIZE,
+            Self::MotorWizardAnswer => MOTOR_WIZARD_ANSWER_SIZE,
+            Self::ReqMotorWizardStatus => 0,
+            Self::MotorWizardStatus => MOTOR_WIZARD_STATUS_SIZE,
+            Self::ReqAltEst => 0,
+            Self::AltEst => ALT_EST_SIZE,
+            Self::ReqHealthTrend => 1,
+            Self::HealthTrend => HEALTH_TREND_MSG_SIZE,
+            Self::StartOutputPattern => OUTPUT_PATTERN_START_SIZE,
+            Self::ReqOutputPatternStatus => 0,
+            Self::OutputPatternStatus => OUTPUT_PATTERN_STATUS_SIZE,
+            Self::Hello => 1,
+            Self::HelloResp => 2,
+            Self::UpgradeNotice => 1,
+            Self::ReqAccelMapFit => 0,
+            Self::AccelMapFit => ACCEL_MAPS_FIT_SIZE,
+            Self::ReqParamInfo => 2,
+            Self::ParamInfo => PARAM_INFO_SIZE,
+            Self::ReqParamValue => 2,
+            Self::ParamValue => PARAM_VALUE_SIZE,
+            Self::SetParam => PARAM_SET_SIZE,
+            Self::ReqParamExport => 0,
+            Self::ParamExport => PARAMS_EXPORT_SIZE,
+            Self::ParamImport => PARAMS_EXPORT_SIZE,
+            Self::ParamImportResult => PARAMS_IMPORT_RESULT_SIZE,
+            Self::ReqWarnings => 0,
+            Self::Warnings => WARNINGS_SIZE,
+            Self::ReqWarningInfo => 1,
+            Self::WarningInfo => WARNING_INFO_SIZE,
+            Self::ClearWarnings => 0,
+            Self::ReqLogChannels => 0,
+            Self::LogChannels => LOG_CHANNELS_SIZE,
+            Self::SetLogChannels => 1,
+            Self::ReqGroundRef => 0,
+            Self::GroundRef => GROUND_REF_SIZE,
+            Self::SetGroundRef => SET_GROUND_REF_SIZE,
+            Self::ReqFusionStatus => 0,
+            Self::FusionStatus => FUSION_STATUS_SIZE,
+            Self::ResetAttitude => 0,
+            Self::ReqExtFeedback => 0,
+            Self::ExtFeedback => EXT_FEEDBACK_SIZE,
+            Self::SetExtAttitude => EXT_ATT_CMD_SIZE,
+            Self::ReqAuthority => 0,
+            Self::Authority => AUTHORITY_STATUS_SIZE,
         }
     }
 }
@@ -180,6 +770,49 @@ fn quat_to_bytes(p: Quaternion) -> [u8; QUATERNION_SIZE] {
     result
 }
 
+fn esc_telemetry_to_bytes(telem: &EscTelemetryState) -> [u8; ESC_TELEM_SIZE] {
+    let mut result = [0; ESC_TELEM_SIZE];
+
+    for (i, esc) in telem.escs.iter().enumerate() {
+        let r = &mut result[i * ESC_TELEM_SIZE_PER..(i + 1) * ESC_TELEM_SIZE_PER];
+
+        r[0] = esc.timestamp.is_some() as u8;
+        r[1..5].clone_from_slice(&esc.temp.to_be_bytes());
+        r[5..9].clone_from_slice(&esc.voltage.to_be_bytes());
+        r[9..13].clone_from_slice(&esc.current.to_be_bytes());
+        r[13..17].clone_from_slice(&esc.consumption.to_be_bytes());
+        r[17..21].clone_from_slice(&esc.erpm.to_be_bytes());
+    }
+    result
+}
+
+fn motor_test_status_to_bytes(motor_test: &MotorTest) -> [u8; MOTOR_TEST_STATUS_SIZE] {
+    let mut result = [0; MOTOR_TEST_STATUS_SIZE];
+
+    if let Some(motor) = motor_test.motor_active() {
+        result[0] = 1;
+        result[1] = motor as u8;
+    }
+    if let Some(rpm) = motor_test.rpm {
+        result[2] = 1;
+        result[3..7].clone_from_slice(&rpm.to_be_bytes());
+    }
+    result
+}
+
+/// Both stages are reported as off if the coefficients haven't been computed yet.
+fn gyro_lpf_to_bytes(cfg: Option<GyroLpfCfg>) -> [u8; GYRO_LPF_SIZE] {
+    let mut result = [0; GYRO_LPF_SIZE];
+
+    if let Some(c) = cfg {
+        result[0] = c.lpf1.type_ as u8;
+        result[1..5].clone_from_slice(&c.lpf1.cutoff.to_be_bytes());
+        result[5] = c.lpf2.type_ as u8;
+        result[6..10].clone_from_slice(&c.lpf2.cutoff.to_be_bytes());
+    }
+    result
+}
+
 fn params_to_bytes(
     attitude: Quaternion,
     attitude_commanded: Quaternion,
@@ -192,6 +825,7 @@ fn params_to_bytes(
     // rpm_status: &RpmReadings,
     motor_servo_state: &MotorServoState,
     aircraft_type: u8,
+    imu_temp: f32,
 ) -> [u8; PARAMS_SIZE] {
     let mut result = [0; PARAMS_SIZE];
 
@@ -250,6 +884,9 @@ fn params_to_bytes(
     result[i] = aircraft_type;
     i += 1;
 
+    result[i..i + 4].clone_from_slice(&imu_temp.to_be_bytes());
+    i += 4;
+
     // todo: Update for new system
     // result[i..i + 4].clone_from_slice(&current_pwr.front_left.to_be_bytes());
     // i += 4;
@@ -298,7 +935,9 @@ impl From<&LinkStats> for [u8; LINK_STATS_SIZE] {
 
 impl SystemStatus {
     pub fn to_bytes(&self) -> [u8; SYS_STATUS_SIZE] {
-        [
+        let mut result = [0; SYS_STATUS_SIZE];
+
+        result[..22].clone_from_slice(&[
             self.imu as u8,
             self.baro as u8,
             self.tof as u8,
@@ -311,7 +950,41 @@ impl SystemStatus {
             self.osd as u8,
             system_status::RX_FAULT.load(Ordering::Acquire) as u8,
             system_status::RPM_FAULT.load(Ordering::Acquire) as u8,
+            self.mag_applied as u8,
+            self.esc_over_temp as u8,
+            self.batt_meas_mismatch as u8,
+            self.imu_isr_overrun as u8,
+            self.ext_flash_fallback as u8,
+            self.imu_rate_mismatch as u8,
+            self.prearm as u8,
+            self.prev_brownout as u8,
+            brownout::active() as u8,
+            self.accel_fault as u8,
+        ]);
+
+        let counts = &self.stale_counts;
+        for (i, count) in [
+            counts.imu,
+            counts.baro,
+            counts.gps,
+            counts.mag,
+            counts.tof,
+            self.imu_implausible_count,
+            self.imu_recoveries,
         ]
+        .iter()
+        .enumerate()
+        {
+            result[22 + i * 2..24 + i * 2].clone_from_slice(&count.to_be_bytes());
+        }
+
+        result[36] = self.batt_stage as u8;
+        result[37] = self.imu_cfg_mismatch as u8;
+        result[38] = self.att_convergence as u8;
+        result[39] = self.imu_secondary as u8;
+        result[40] = self.imu_failed_over as u8;
+
+        result
     }
 }
 
@@ -411,15 +1084,50 @@ fn waypoints_to_buf(w: &[Option<PositVelEarthUnits>; MAX_WAYPOINTS]) -> [u8; WAY
 // Readings for preflight
 struct PreflightData {}
 
-/// Handle incoming data from the PC
+/// Parse a message in the unframed format, from PC software that predates `usb_frame`: A start
+/// byte, device code, and message type, then the payload, and a CRC8. It must fit in `rx_buf`,
+/// a single read. Returns the message type, and payload.
+pub fn parse_unframed(rx_buf: &[u8]) -> Option<(MsgType, &[u8])> {
+    if rx_buf[0] != MSG_START {
+        println!("Invalid start byte rec");
+        return None;
+    }
+
+    let rx_msg_type: MsgType = match rx_buf.get(2).map(|t| MsgType::try_from(*t)) {
+        Some(Ok(d)) => d,
+        _ => {
+            println!("Invalid message type received over USB");
+            return None; // todo: Send message back over USB?
+        }
+    };
+
+    let payload_end = rx_msg_type.payload_size() + PAYLOAD_START_I;
+    if payload_end + CRC_LEN > rx_buf.len() {
+        println!("Unframed USB message too long for a single read; ignoring");
+        return None;
+    }
+
+    if !anyleaf_usb::check_crc(&rx_buf, payload_end) {
+        println!("Incorrect inbound CRC on {} message", rx_buf[0]);
+        // todo: return here.
+    }
+
+    Some((rx_msg_type, &rx_buf[PAYLOAD_START_I..payload_end]))
+}
+
+/// Handle incoming data from the PC. `rx_payload` must be `rx_msg_type`'s payload size; see
+/// `usb_frame::check_frame`, and `parse_unframed`.
 pub fn handle_rx(
     usb_serial: &mut SerialPort<'static, setup::UsbBusType>,
-    rx_buf: &[u8],
+    rx_msg_type: MsgType,
+    rx_payload: &[u8],
     attitude: Quaternion,
+    rates: (f32, f32, f32),
     attitude_commanded: &AttitudeCommanded,
     altitude_baro: f32,
     pressure_static: f32,
     temp_baro: f32,
+    imu_temp: f32,
     altitude_agl: Option<f32>,
     batt_v: f32,
     esc_current: f32,
@@ -429,6 +1137,7 @@ pub fn handle_rx(
     sys_status: &SystemStatus,
     autopilot_status: &AutopilotStatus,
     arm_status: &mut ArmStatus,
+    has_taken_off: bool,
     op_mode: &mut OperationMode,
     motor_timer: &mut setup::MotorTimer,
     servo_timer: &mut setup::ServoTimer,
@@ -436,26 +1145,36 @@ pub fn handle_rx(
     motor_servo_state: &mut MotorServoState,
     preflight_motors_running: &mut bool,
     flash: &mut Flash,
+    flash_ext: &mut ExtFlash,
     calibrating_accel: &mut bool,
+    gyro_temp_cal: &mut GyroTempCal,
+    mag_cal_collector: &mut MagCalCollector,
+    gyro_lpf_implemented: Option<GyroLpfCfg>,
+    blackbox: &mut Blackbox,
+    esc_telemetry: &EscTelemetryState,
+    motor_test: &mut MotorTest,
+    input_cal_collector: &mut InputCalCollector,
+    thrust_comp: &ThrustComp,
+    self_test: &mut SelfTest,
+    hover_throttle_est: &HoverThrottleEst,
+    wind_est: &WindEst,
+    lost_craft: &LostCraft,
+    vib_test: &mut VibTest,
+    motor_wizard: &mut MotorWizard,
+    output_pattern: &mut OutputPattern,
+    alt_est: &AltEstimator,
+    flight_stats: &FlightStatsState,
+    health_trend: &HealthTrend,
+    adc_readings: &AdcReadings,
+    dma_stats: &DmaStats,
+    accel_maps: &AccelMaps,
+    warnings: &mut Warnings,
+    log_channels: &mut LogChannels,
+    ground_ref: &mut GroundRef,
+    fusion_monitor: &FusionMonitor,
+    ext_ctrl: &ExtCtrl,
+    authority: &Authority,
 ) {
-    if rx_buf[0] != MSG_START {
-        println!("Invalid start byte rec");
-        return;
-    }
-
-    let rx_msg_type: MsgType = match rx_buf[2].try_into() {
-        Ok(d) => d,
-        Err(_) => {
-            println!("Invalid message type received over USB");
-            return; // todo: Send message back over USB?
-        }
-    };
-
-    if !anyleaf_usb::check_crc(&rx_buf, rx_msg_type.payload_size() + PAYLOAD_START_I) {
-        println!("Incorrect inbound CRC on {} message", rx_buf[0]);
-        // todo: return here.
-    }
-
     cfg_if! {
         if #[cfg(feature = "quad")] {
             let motors_armed = ArmStatus::Armed;
@@ -474,7 +1193,10 @@ pub fn handle_rx(
         MsgType::ReqParams => {
             // todo: current behavior is to set preflight at first params request, and never set
             // todo it back. This could potentially be dangerous.
-            *op_mode = OperationMode::Preflight;
+            // HIL runs the control loops in Normal mode, while the PC polls params.
+            if !hil::active() {
+                *op_mode = OperationMode::Preflight;
+            }
             let payload = params_to_bytes(
                 attitude,
                 attitude_commanded.quat,
@@ -487,6 +1209,7 @@ pub fn handle_rx(
                 // rpm_status,
                 motor_servo_state,
                 aircraft_type,
+                imu_temp,
             );
 
             send_payload::<{ PARAMS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
@@ -515,6 +1238,14 @@ pub fn handle_rx(
         }
         MsgType::LinkStats => {}
         MsgType::ArmMotors => {
+            if brownout::active() {
+                println!("Can't arm after a brownout; power cycle first");
+                return;
+            }
+            if input_cal_collector.active() {
+                println!("Can't arm while calibrating inputs");
+                return;
+            }
             // We use the same `ArmStatus` flag for testing motors in preflight as we do
             // for flight.
             *arm_status = motors_armed;
@@ -526,6 +1257,10 @@ pub fn handle_rx(
         }
         // todo: Message type for set arm to arm controls.
         MsgType::StartMotors => {
+            if brownout::active() {
+                println!("Can't start motors after a brownout; power cycle first");
+                return;
+            }
             *preflight_motors_running = true;
             println!("Preflight motors started");
             // cfg_if! {
@@ -592,8 +1327,8 @@ pub fn handle_rx(
         MsgType::Updatewaypoints => {}
         #[cfg(feature = "fixed-wing")]
         MsgType::SetServoPosit => {
-            let servo = rx_buf[1];
-            let value = f32::from_be_bytes(rx_buf[2..6].try_into().unwrap());
+            let servo = rx_payload[0];
+            let value = f32::from_be_bytes(rx_payload[1..5].try_into().unwrap());
 
             // todo: COme back to this.
             // let l = ServoWingPosition::Left as u8;
@@ -629,29 +1364,7 @@ pub fn handle_rx(
         }
         MsgType::SysApStatus => {}
         MsgType::ReqControlMapping => {
-            // todo: This message type needs to be replaced by
-            // todo MotorServoState. perhaps
-            // let payload = [0_u8; 2]; // todo placeholder while we re-set up motorss etc
-
-            // todo: Temp ahrdcoded mappings:
-
-            //             rotor_front_left_hardware: MotorServoHardware::Pin4,
-            //             rotor_front_right_hardware: MotorServoHardware::Pin2,
-            //             rotor_aft_left_hardware: MotorServoHardware::Pin3,
-            //             rotor_aft_right_hardware: MotorServoHardware::Pin1,
-
-            let m = &motor_servo_state; // code shortener
-            #[cfg(feature = "quad")]
-            let payload = [
-                // 2 bits each
-                (3) | ((1) << 2) | ((2) << 4) | ((0) << 6),
-                // 1 bit each
-                false as u8
-                    | ((false as u8) << 1)
-                    | ((false as u8) << 2)
-                    | ((false as u8) << 3)
-                    | ((m.frontleft_aftright_dir as u8) << 4),
-            ];
+            let payload = config.control_mapping.to_bytes();
 
             send_payload::<{ CONTROL_MAPPING_SIZE + PAYLOAD_START_I + CRC_LEN }>(
                 MsgType::ControlMapping,
@@ -660,12 +1373,52 @@ pub fn handle_rx(
             );
         }
         MsgType::ControlMapping => {}
+        MsgType::SetControlMapping => {
+            let status = match ControlMapping::from_bytes(&rx_payload[..CONTROL_MAPPING_SIZE]) {
+                // Remapping outputs while flying would be dangerous, and setting motor
+                // directions blocks.
+                Ok(_) if *op_mode != OperationMode::Preflight => MappingStatus::NotPreflight,
+                Ok(mapping) => {
+                    #[cfg(feature = "quad")]
+                    let reversed_changed =
+                        mapping.motors_reversed() != config.control_mapping.motors_reversed();
+
+                    mapping.apply(motor_servo_state);
+
+                    #[cfg(feature = "fixed-wing")]
+                    setup::set_output_pins(&mapping);
+
+                    #[cfg(feature = "quad")]
+                    if reversed_changed {
+                        crate::protocols::dshot::setup_motor_dir(
+                            mapping.motors_reversed(),
+                            true,
+                            motor_timer,
+                        );
+                    }
+
+                    config.control_mapping = mapping;
+                    config.save(flash);
+
+                    println!("Control mapping updated");
+                    MappingStatus::Success
+                }
+                Err(e) => e,
+            };
+
+            send_payload::<{ 1 + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ControlMappingStatus,
+                &[status as u8],
+                usb_serial,
+            );
+        }
+        MsgType::ControlMappingStatus => {}
         MsgType::SetMotorPowers => {
             let power = MotorPower {
-                front_left: f32::from_be_bytes(rx_buf[0..4].try_into().unwrap()),
-                front_right: f32::from_be_bytes(rx_buf[4..8].try_into().unwrap()),
-                aft_left: f32::from_be_bytes(rx_buf[8..12].try_into().unwrap()),
-                aft_right: f32::from_be_bytes(rx_buf[12..16].try_into().unwrap()),
+                front_left: f32::from_be_bytes(rx_payload[0..4].try_into().unwrap()),
+                front_right: f32::from_be_bytes(rx_payload[4..8].try_into().unwrap()),
+                aft_left: f32::from_be_bytes(rx_payload[8..12].try_into().unwrap()),
+                aft_right: f32::from_be_bytes(rx_payload[12..16].try_into().unwrap()),
             };
 
             println!("Preflight motor power FL: {}", power.front_left);
@@ -674,18 +1427,19 @@ pub fn handle_rx(
         MsgType::SetMotorRpms => {
             // todo: YOu need a safety rail on this.
             let rpms = MotorRpm {
-                front_left: f32::from_be_bytes(rx_buf[0..4].try_into().unwrap()),
-                front_right: f32::from_be_bytes(rx_buf[4..8].try_into().unwrap()),
-                aft_left: f32::from_be_bytes(rx_buf[8..12].try_into().unwrap()),
-                aft_right: f32::from_be_bytes(rx_buf[12..16].try_into().unwrap()),
+                front_left: f32::from_be_bytes(rx_payload[0..4].try_into().unwrap()),
+                front_right: f32::from_be_bytes(rx_payload[4..8].try_into().unwrap()),
+                aft_left: f32::from_be_bytes(rx_payload[8..12].try_into().unwrap()),
+                aft_right: f32::from_be_bytes(rx_payload[12..16].try_into().unwrap()),
             };
 
             // todo.
             // motor_servo_state.set_cmds_from_rpms(
             //     &rpms,
-            //     rpm_readings,
             //     motor_pid_group,
             //     motor_pid_coeffs,
+            //     &config.rpm_ctrl,
+            //     batt_v,
             // );
         }
         MsgType::Config => (),
@@ -700,18 +1454,966 @@ pub fn handle_rx(
         }
         MsgType::SaveConfig => {
             println!("Save config received");
-            *config =
-                UserConfig::from_bytes(&rx_buf[PAYLOAD_START_I..PAYLOAD_START_I + CONFIG_SIZE]);
+            *config = UserConfig::from_bytes(&rx_payload[..CONFIG_SIZE]);
             config.save(flash);
+
+            #[cfg(feature = "fixed-wing")]
+            servo::set_freq(config.servo_cfg.update_freq, servo_timer);
         }
         MsgType::CalibrateAccel => {
             println!("Calibrate accel request received");
             *calibrating_accel = true;
         }
+        MsgType::CalibrateGyroTemp => {
+            println!("Gyro temperature cal point requested");
+            gyro_temp_cal.start();
+        }
+        MsgType::StartMagCal => {
+            println!("Mag cal started");
+            mag_cal_collector.start();
+        }
+        MsgType::EndMagCal => {
+            if let Some(cal) = mag_cal_collector.finish() {
+                println!(
+                    "Mag cal complete. Hard iron: x{} y{} z{}. Field strength: {}µT",
+                    cal.hard_iron.x, cal.hard_iron.y, cal.hard_iron.z, cal.field_strength
+                );
+                config.mag_cal = cal;
+                config.save(flash);
+            }
+        }
+        MsgType::ReqGyroLpf => {
+            let payload = gyro_lpf_to_bytes(gyro_lpf_implemented);
+
+            send_payload::<{ GYRO_LPF_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::GyroLpf,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::GyroLpf => {}
+        MsgType::ReqLogSize => {
+            let payload = blackbox.log_size().to_be_bytes();
+
+            send_payload::<{ 4 + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::LogSize,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::LogSize => {}
+        MsgType::ReqLogChunk => {
+            let offset = u32::from_be_bytes(rx_payload[..4].try_into().unwrap());
+
+            let mut payload = [0xff; LOG_CHUNK_SIZE];
+            payload[0..4].clone_from_slice(&offset.to_be_bytes());
+
+            // Past the end of the log, we send erased (0xff) data.
+            let log_size = blackbox.log_size();
+            if offset < log_size {
+                let len = (log_size - offset).min(LOG_CHUNK_DATA_SIZE as u32) as usize;
+                LogStorage::new(blackbox.backend, flash, flash_ext)
+                    .read_region(offset as usize, &mut payload[4..4 + len])
+                    .ok();
+            }
+
+            send_payload::<{ LOG_CHUNK_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::LogChunk,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::LogChunk => {}
+        MsgType::SetBlackboxEnabled => {
+            blackbox.enabled_usb = rx_payload[0] != 0;
+        }
+        MsgType::ReqEscTelemetry => {
+            let payload = esc_telemetry_to_bytes(esc_telemetry);
+
+            send_payload::<{ ESC_TELEM_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::EscTelemetry,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::EscTelemetry => {}
+        MsgType::AckPropsOff => {
+            motor_test.props_off_ack = true;
+            println!("Props-off acknowledged");
+        }
+        MsgType::MotorTestStart => {
+            if *op_mode != OperationMode::Preflight
+                || *arm_status != ArmStatus::Disarmed
+                || *preflight_motors_running
+                || !motor_test.props_off_ack
+                || vib_test.in_progress()
+                || motor_wizard.in_progress()
+                || output_pattern.in_progress()
+            {
+                println!("Motor test refused; must be in Preflight, disarmed, with props-off ack");
+                return;
+            }
+
+            let payload = &rx_payload[..MOTOR_TEST_START_SIZE];
+
+            let motor = match payload[0] {
+                0 => Motor::M1,
+                1 => Motor::M2,
+                2 => Motor::M3,
+                3 => Motor::M4,
+                _ => {
+                    println!("Invalid motor for motor test");
+                    return;
+                }
+            };
+            let power = f32::from_be_bytes(payload[1..5].try_into().unwrap());
+            let duration = u16::from_be_bytes(payload[5..7].try_into().unwrap()) as f32 / 1_000.;
+
+            motor_test.cmd_pending = Some(MotorTestCmd::new(motor, power, duration));
+        }
+        MsgType::MotorTestKeepAlive => {
+            motor_test.keepalive_pending = true;
+            output_pattern.keepalive_pending = true;
+        }
+        MsgType::MotorTestStop => {
+            motor_test.stop_pending = true;
+            motor_wizard.cancel();
+            output_pattern.stop_pending = true;
+        }
+        MsgType::ReqMotorTestStatus => {
+            let payload = motor_test_status_to_bytes(motor_test);
+
+            send_payload::<{ MOTOR_TEST_STATUS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::MotorTestStatus,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::MotorTestStatus => {}
+        MsgType::StartInputCal => {
+            // The sticks are moved through their full range, so don't allow this while armed.
+            if *arm_status != ArmStatus::Disarmed {
+                println!("Can't calibrate inputs while armed");
+                return;
+            }
+            input_cal_collector.start_pending = true;
+        }
+        MsgType::ReqInputCalStatus => {
+            send_payload::<{ 1 + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::InputCalStatus,
+                &[input_cal_collector.stage as u8],
+                usb_serial,
+            );
+        }
+        MsgType::InputCalStatus => {}
+        MsgType::ReqInputCal => {
+            let payload = config.input_map.cal.to_bytes();
+
+            send_payload::<{ INPUT_CAL_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::InputCal,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::InputCal => {}
+        MsgType::ResetInputCal => {
+            if *arm_status != ArmStatus::Disarmed {
+                println!("Can't reset input cal while armed");
+                return;
+            }
+            // Cancel a calibration in progress, so it doesn't overwrite the defaults.
+            *input_cal_collector = Default::default();
+            config.input_map.cal = Default::default();
+            config.save(flash);
+            println!("Input cal reset to defaults");
+        }
+        MsgType::ReqRates => {
+            let payload = rates::rates_to_bytes(&config.input_map.rates());
+
+            send_payload::<{ RATES_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Rates,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::Rates => {}
+        MsgType::SetRates => match rates::rates_from_bytes(&rx_payload[..RATES_SIZE]) {
+            Some([pitch, roll, yaw]) => {
+                config.input_map.pitch_rate = pitch;
+                config.input_map.roll_rate = roll;
+                config.input_map.yaw_rate = yaw;
+                config.save(flash);
+                println!("Rates updated");
+            }
+            None => println!("Invalid rates received; not applied"),
+        },
+        MsgType::ReqRateCurves => {
+            let payload = rates::curve_samples_to_bytes(&config.input_map.rates());
+
+            send_payload::<{ CURVE_SAMPLES_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::RateCurves,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::RateCurves => {}
+        MsgType::ReqThrustComp => {
+            let payload = thrust_comp.to_bytes();
+
+            send_payload::<{ THRUST_COMP_STATE_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ThrustComp,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::ThrustComp => {}
+        MsgType::ReqMixer => {
+            let payload = config.mixer.to_bytes();
+
+            send_payload::<{ MIXER_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Mixer,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::Mixer => {}
+        MsgType::SetMixer => {
+            if *arm_status != ArmStatus::Disarmed {
+                println!("Can't change the mixer while armed");
+                return;
+            }
+
+            match Mixer::from_bytes(&rx_payload[..MIXER_SIZE]) {
+                Some(mixer) => {
+                    config.mixer = match mixer.preset {
+                        MixerPreset::Custom => mixer,
+                        p => Mixer::from_preset(p),
+                    };
+                    config.save(flash);
+                    println!("Mixer updated");
+                }
+                None => println!("Invalid mixer received; not applied"),
+            }
+
+            send_payload::<{ MIXER_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Mixer,
+                &config.mixer.to_bytes(),
+                usb_serial,
+            );
+        }
+        #[cfg(feature = "fixed-wing")]
+        MsgType::ServoJog => {
+            if *op_mode != OperationMode::Preflight || *arm_status != ArmStatus::Disarmed {
+                println!("Servos can only be jogged in preflight, while disarmed");
+                return;
+            }
+
+            let jog = match ServoJog::try_from(rx_payload[1]) {
+                Ok(j) => j,
+                Err(_) => {
+                    println!("Invalid servo jog position requested");
+                    return;
+                }
+            };
+
+            let i = rx_payload[0] as usize;
+            let channel = config
+                .control_mapping
+                .surfaces
+                .get(i)
+                .copied()
+                .flatten()
+                .and_then(setup::servo_channel);
+
+            let Some(channel) = channel else {
+                println!("Invalid servo requested, or its surface isn't present");
+                return;
+            };
+
+            servo::set_posit(
+                jog.posit(),
+                &config.servo_cfg.pulses[i],
+                servo_timer,
+                channel,
+            );
+        }
+        MsgType::StartSelfTest => {
+            if *op_mode != OperationMode::Preflight
+                || *arm_status != ArmStatus::Disarmed
+                || *preflight_motors_running
+                || motor_test.motor_active().is_some()
+                || vib_test.in_progress()
+                || motor_wizard.in_progress()
+                || output_pattern.in_progress()
+            {
+                println!("Self-test refused; must be in Preflight, disarmed, with motors stopped");
+                return;
+            }
+            self_test.start();
+        }
+        MsgType::ReqSelfTest => {
+            send_payload::<{ SELF_TEST_REPORT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::SelfTest,
+                &self_test.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::SelfTest => {}
+        MsgType::EraseLog => {
+            // This blocks for a while, so don't allow it in flight.
+            if *arm_status != ArmStatus::Disarmed {
+                println!("Can't erase the blackbox log while armed");
+                return;
+            }
+            println!("Erasing blackbox log");
+            blackbox.erase(&mut LogStorage::new(blackbox.backend, flash, flash_ext));
+        }
+        MsgType::ReqFlashId => {
+            let mut payload = [0; 4];
+            payload[0] = flash_ext.detected() as u8;
+            if let Ok(id) = flash_ext.read_jedec_id() {
+                payload[1..4].copy_from_slice(&id);
+            }
+
+            send_payload::<{ 4 + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::FlashId,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::FlashId => {}
+        MsgType::ReqHoverThrottle => {
+            let mut payload = [0; F32_SIZE * 2];
+            payload[0..4].copy_from_slice(&hover_throttle_est.throttle.to_be_bytes());
+            payload[4..8].copy_from_slice(&config.hover_throttle.to_be_bytes());
+
+            send_payload::<{ F32_SIZE * 2 + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::HoverThrottle,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::HoverThrottle => {}
+        MsgType::ReqWindEst => {
+            let mut payload = [0; 1 + F32_SIZE * 2];
+            payload[0] = wind_est.valid() as u8;
+            payload[1..5].copy_from_slice(&wind_est.speed().to_be_bytes());
+            payload[5..9].copy_from_slice(&wind_est.direction().to_be_bytes());
+
+            send_payload::<{ 1 + F32_SIZE * 2 + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::WindEst,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::WindEst => {}
+        MsgType::ReqRawChannels => {
+            let mut payload = [0; RAW_CHANNELS_SIZE];
+            if let Some(ch_data) = controls {
+                payload[0] = 1;
+                for (i, val) in ch_data.raw.iter().enumerate() {
+                    payload[1 + i * 2..3 + i * 2].copy_from_slice(&val.to_be_bytes());
+                }
+            }
+
+            send_payload::<{ RAW_CHANNELS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::RawChannels,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::RawChannels => {}
+        MsgType::SetBeacon => {
+            indicators::BEACON_USB.store(rx_payload[0] != 0, Ordering::Release);
+        }
+        MsgType::ReqLastPosit => {
+            let mut payload = [0; LAST_POSIT_MSG_SIZE];
+            let (posit, source) = lost_craft.known_posit();
+            payload[0] = source as u8;
+            if let Some(p) = posit {
+                payload[1..5].copy_from_slice(&p.lat.to_be_bytes());
+                payload[5..9].copy_from_slice(&p.lon.to_be_bytes());
+                payload[9..13].copy_from_slice(&p.alt_msl.to_be_bytes());
+            }
+
+            send_payload::<{ LAST_POSIT_MSG_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::LastPosit,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::LastPosit => {}
+        MsgType::ReqEventLog => {
+            let start = rx_payload[0] as usize;
+
+            let mut payload = [0; EVENT_LOG_MSG_SIZE];
+            payload[0] = event_log::count() as u8;
+            payload[1] = start as u8;
+
+            for i in 0..EVENTS_PER_MSG {
+                if let Some(event) = event_log::get(start + i) {
+                    payload[2 + i * EVENT_SIZE..2 + (i + 1) * EVENT_SIZE]
+                        .copy_from_slice(&event.to_bytes());
+                }
+            }
+
+            send_payload::<{ EVENT_LOG_MSG_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::EventLog,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::EventLog => {}
+        MsgType::StartVibTest => {
+            if *op_mode != OperationMode::Preflight
+                || *arm_status != ArmStatus::Disarmed
+                || *preflight_motors_running
+                || !motor_test.props_off_ack
+                || motor_test.motor_active().is_some()
+                || self_test.in_progress()
+                || motor_wizard.in_progress()
+                || output_pattern.in_progress()
+            {
+                println!(
+                    "Vibration test refused; must be in Preflight, disarmed, with props-off ack"
+                );
+                return;
+            }
+
+            let power = f32::from_be_bytes(rx_payload[..F32_SIZE].try_into().unwrap());
+            vib_test.request_start(power);
+        }
+        MsgType::ReqVibTestResult => {
+            let i = rx_payload[0] as usize;
+
+            let mut payload = [0; VIB_TEST_RESULT_SIZE];
+            payload[0] = vib_test.status() as u8;
+            payload[1] = i as u8;
+            if let Some(Some(result)) = vib_test.results.get(i) {
+                payload[2] = 1;
+                payload[3..].copy_from_slice(&result.to_bytes());
+            }
+
+            send_payload::<{ VIB_TEST_RESULT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::VibTestResult,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::VibTestResult => {}
+        MsgType::CameraTiltJog => {
+            if *arm_status != ArmStatus::Disarmed {
+                println!("Can't jog the camera tilt servo while armed");
+                return;
+            }
+
+            let val = rx_payload[0];
+            if val != camera_tilt::JOG_NONE && ServoJog::try_from(val).is_err() {
+                println!("Invalid camera tilt jog position requested");
+                return;
+            }
+
+            camera_tilt::JOG_USB.store(val, Ordering::Release);
+        }
+        MsgType::SetTelemStream => {
+            let rate = rx_payload[0];
+            if rate != 0 && !(usb_telem::RATE_MIN..=usb_telem::RATE_MAX).contains(&rate) {
+                println!("Invalid telemetry stream rate requested");
+                return;
+            }
+
+            usb_telem::TELEM_STREAM_RATE.store(rate, Ordering::Release);
+        }
+        MsgType::TelemSnapshot => {}
+        MsgType::EnterBootloader => {
+            if *op_mode != OperationMode::Preflight
+                || *arm_status != ArmStatus::Disarmed
+                || *preflight_motors_running
+                || motor_test.motor_active().is_some()
+                || vib_test.in_progress()
+                || motor_wizard.in_progress()
+                || output_pattern.in_progress()
+            {
+                println!("Bootloader refused; must be in Preflight, disarmed, with motors stopped");
+                return;
+            }
+
+            println!("Rebooting into the bootloader");
+            dfu::reboot_to_bootloader(motor_timer);
+        }
+        MsgType::ReqVersion => {
+            send_payload::<{ VERSION_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Version,
+                &dfu::version_to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::Version => {}
+        MsgType::ReqTakeoffState => {
+            send_payload::<{ 1 + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::TakeoffState,
+                &[has_taken_off as u8],
+                usb_serial,
+            );
+        }
+        MsgType::TakeoffState => {}
+        MsgType::ReqFlightStats => {
+            let mut payload = [0; FLIGHT_STATS_MSG_SIZE];
+            payload[..FLIGHT_STATS_SIZE].copy_from_slice(&flight_stats.totals.to_bytes());
+
+            let i = FLIGHT_STATS_SIZE;
+            payload[i..i + 4].copy_from_slice(&flight_stats.flight.armed_time.to_be_bytes());
+            payload[i + 4..i + 8].copy_from_slice(&flight_stats.flight.airborne_time.to_be_bytes());
+
+            send_payload::<{ FLIGHT_STATS_MSG_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::FlightStats,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::FlightStats => {}
+        MsgType::ReqProfile => {
+            let i = rx_payload[0] as usize;
+            if i >= NUM_PROFILES {
+                println!("Invalid control profile requested");
+                return;
+            }
+
+            let mut payload = [0; PROFILE_MSG_SIZE];
+            payload[0] = i as u8;
+            payload[1] = config.active_profile;
+            payload[2..].copy_from_slice(&profiles::get(config, i).to_bytes());
+
+            send_payload::<{ PROFILE_MSG_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Profile,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::Profile => {}
+        MsgType::SetProfile => {
+            let i = rx_payload[0] as usize;
+            if i >= NUM_PROFILES {
+                println!("Invalid control profile index; not applied");
+                return;
+            }
+
+            match CtrlProfile::from_bytes(&rx_payload[1..1 + PROFILE_SIZE]) {
+                Some(profile) => {
+                    profiles::set(config, i, &profile);
+                    config.save(flash);
+                    println!("Control profile {} updated", i + 1);
+                }
+                None => println!("Invalid control profile received; not applied"),
+            }
+        }
+        MsgType::SelectProfile => {
+            let i = rx_payload[0];
+            if i as usize >= NUM_PROFILES {
+                println!("Invalid control profile selected");
+                return;
+            }
+
+            profiles::PROFILE_USB.store(i, Ordering::Release);
+        }
+        MsgType::ReqAdcCal => {
+            let mut payload = [0; ADC_CAL_MSG_SIZE];
+            payload[..ADC_CAL_CFG_SIZE].copy_from_slice(&config.adc_cal.to_bytes());
+            payload[ADC_CAL_CFG_SIZE..].copy_from_slice(&adc_readings.to_bytes());
+
+            send_payload::<{ ADC_CAL_MSG_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::AdcCal,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::AdcCal => {}
+        MsgType::SetAdcCal => match AdcCalCfg::from_bytes(&rx_payload[..ADC_CAL_CFG_SIZE]) {
+            Some(cal) => {
+                config.adc_cal = cal;
+                config.save(flash);
+                println!("ADC calibration updated");
+            }
+            None => println!("Invalid ADC calibration received; not applied"),
+        },
+        MsgType::CalBattV => {
+            if *arm_status != ArmStatus::Disarmed {
+                println!("Can't calibrate battery voltage while armed");
+                return;
+            }
+
+            let v = f32::from_be_bytes(rx_payload[..F32_SIZE].try_into().unwrap());
+            // This comparison also rejects NaN.
+            if !(v > 0. && v.is_finite()) {
+                println!("Invalid battery voltage received");
+                return;
+            }
+
+            adc_cal::BATT_V_CAL.store(v.to_bits(), Ordering::Release);
+        }
+        MsgType::CalCurrentZero => {
+            if *arm_status != ArmStatus::Disarmed
+                || *preflight_motors_running
+                || motor_test.motor_active().is_some()
+            {
+                println!("Can't calibrate current zero with motors running");
+                return;
+            }
+
+            adc_cal::CURR_ZERO_CAL.store(true, Ordering::Release);
+        }
+        MsgType::SetHil => {
+            if rx_payload[0] != 0 {
+                if hil::active() {
+                    return;
+                }
+                if *op_mode != OperationMode::Preflight
+                    || *arm_status != ArmStatus::Disarmed
+                    || *preflight_motors_running
+                    || motor_test.motor_active().is_some()
+                    || vib_test.in_progress()
+                    || motor_wizard.in_progress()
+                    || output_pattern.in_progress()
+                {
+                    println!("HIL refused; must be in Preflight, disarmed, with motors stopped");
+                    return;
+                }
+
+                hil::enable();
+                dshot::stop_all(motor_timer);
+                // Run the control loops on the synthetic input.
+                *op_mode = OperationMode::Normal;
+                println!("HIL mode enabled");
+            } else {
+                if !hil::active() {
+                    return;
+                }
+                if *arm_status != ArmStatus::Disarmed {
+                    println!("Can't disable HIL while armed");
+                    return;
+                }
+
+                hil::disable();
+                *op_mode = OperationMode::Preflight;
+                println!("HIL mode disabled");
+            }
+        }
+        MsgType::HilSample => match HilSample::from_bytes(&rx_payload[..HIL_SAMPLE_SIZE]) {
+            Some(sample) => hil::store_sample(sample),
+            None => println!("Invalid HIL sample received"),
+        },
+        MsgType::HilOutput => {}
+        MsgType::ReqDmaReport => {
+            send_payload::<{ DMA_REPORT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::DmaReport,
+                &dma_stats.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::DmaReport => {}
+        MsgType::FollowTarget => {
+            match FollowTarget::from_bytes(&rx_payload[..FOLLOW_TARGET_SIZE]) {
+                Some(target) => follow_me::store_target(target),
+                None => println!("Invalid follow-me target received"),
+            }
+        }
+        MsgType::StartMotorWizard => {
+            if cfg!(feature = "fixed-wing") {
+                println!("The motor wizard is quad only");
+                return;
+            }
+
+            if *op_mode != OperationMode::Preflight
+                || *arm_status != ArmStatus::Disarmed
+                || *preflight_motors_running
+                || !motor_test.props_off_ack
+                || motor_test.motor_active().is_some()
+                || vib_test.in_progress()
+                || self_test.in_progress()
+                || motor_wizard.in_progress()
+                || output_pattern.in_progress()
+            {
+                println!(
+                    "Motor wizard refused; must be in Preflight, disarmed, with props-off ack"
+                );
+                return;
+            }
+
+            let payload = &rx_payload[..MOTOR_WIZARD_START_SIZE];
+
+            let power = f32::from_be_bytes(payload[0..4].try_into().unwrap());
+            let Ok(dir) = RotationDir::try_from(payload[4]) else {
+                println!("Invalid direction for the motor wizard");
+                return;
+            };
+
+            motor_wizard.request_start(power, dir);
+        }
+        MsgType::MotorWizardAnswer => {
+            let payload = &rx_payload[..MOTOR_WIZARD_ANSWER_SIZE];
+
+            match (
+                Corner::try_from(payload[0]),
+                RotationDir::try_from(payload[1]),
+            ) {
+                (Ok(corner), Ok(dir)) => motor_wizard.answer(corner, dir),
+                _ => println!("Invalid motor wizard answer"),
+            }
+        }
+        MsgType::ReqMotorWizardStatus => {
+            send_payload::<{ MOTOR_WIZARD_STATUS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::MotorWizardStatus,
+                &motor_wizard.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::MotorWizardStatus => {}
+        MsgType::ReqAltEst => {
+            send_payload::<{ ALT_EST_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::AltEst,
+                &alt_est.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::AltEst => {}
+        MsgType::ReqHealthTrend => {
+            let i = rx_payload[0] as usize;
+            let history = &health_trend.history;
+
+            let mut payload = [0; HEALTH_TREND_MSG_SIZE];
+            payload[0] = history.len() as u8;
+            payload[1] = i as u8;
+            if let Some(record) = history.get(i) {
+                payload[2] = 1;
+                payload[3..].copy_from_slice(&record.to_bytes());
+            }
+
+            send_payload::<{ HEALTH_TREND_MSG_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::HealthTrend,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::HealthTrend => {}
+        MsgType::StartOutputPattern => {
+            if *op_mode != OperationMode::Preflight
+                || *arm_status != ArmStatus::Disarmed
+                || *preflight_motors_running
+                || !motor_test.props_off_ack
+                || motor_test.motor_active().is_some()
+                || vib_test.in_progress()
+                || self_test.in_progress()
+                || motor_wizard.in_progress()
+                || output_pattern.in_progress()
+            {
+                println!(
+                    "Output pattern refused; must be in Preflight, disarmed, with props-off ack"
+                );
+                return;
+            }
+
+            let payload = &rx_payload[..OUTPUT_PATTERN_START_SIZE];
+
+            let pattern = match Pattern::try_from(payload[0]) {
+                Ok(Pattern::SweepServos) if cfg!(feature = "quad") => {
+                    println!("Servo patterns are fixed-wing only");
+                    return;
+                }
+                Ok(p) => p,
+                Err(_) => {
+                    println!("Invalid output pattern requested");
+                    return;
+                }
+            };
+            let power = f32::from_be_bytes(payload[1..5].try_into().unwrap());
+
+            output_pattern.request_start(pattern, power);
+        }
+        MsgType::ReqOutputPatternStatus => {
+            send_payload::<{ OUTPUT_PATTERN_STATUS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::OutputPatternStatus,
+                &output_pattern.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::OutputPatternStatus => {}
+        MsgType::Hello => usb_frame::handshake(rx_payload[0], usb_serial),
+        MsgType::HelloResp => {}
+        MsgType::UpgradeNotice => {}
+        MsgType::ReqAccelMapFit => {
+            send_payload::<{ ACCEL_MAPS_FIT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::AccelMapFit,
+                &accel_maps.fit_to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::AccelMapFit => {}
+        MsgType::ReqParamInfo => {
+            let i = u16::from_be_bytes(rx_payload[0..2].try_into().unwrap());
+
+            send_payload::<{ PARAM_INFO_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ParamInfo,
+                &params::info_to_bytes(i, config),
+                usb_serial,
+            );
+        }
+        MsgType::ParamInfo => {}
+        MsgType::ReqParamValue => {
+            send_payload::<{ PARAM_VALUE_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ParamValue,
+                &params::read(rx_payload, config),
+                usb_serial,
+            );
+        }
+        MsgType::ParamValue => {}
+        MsgType::SetParam => {
+            let payload = params::write(rx_payload, config, *arm_status != ArmStatus::Disarmed);
+
+            send_payload::<{ PARAM_VALUE_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ParamValue,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::ReqParamExport => {
+            send_payload::<{ PARAMS_EXPORT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ParamExport,
+                &params::export(config),
+                usb_serial,
+            );
+        }
+        MsgType::ParamExport => {}
+        MsgType::ParamImport => {
+            let payload = params::import(rx_payload, config, *arm_status != ArmStatus::Disarmed);
+
+            send_payload::<{ PARAMS_IMPORT_RESULT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ParamImportResult,
+                &payload,
+                usb_serial,
+            );
+        }
+        MsgType::ParamImportResult => {}
+        MsgType::ReqWarnings => {
+            send_payload::<{ WARNINGS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Warnings,
+                &warnings.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::Warnings => {}
+        MsgType::ReqWarningInfo => {
+            send_payload::<{ WARNING_INFO_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::WarningInfo,
+                &state::warning_info_to_bytes(rx_payload[0]),
+                usb_serial,
+            );
+        }
+        MsgType::WarningInfo => {}
+        MsgType::ClearWarnings => {
+            warnings.clear_latched();
+
+            send_payload::<{ WARNINGS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Warnings,
+                &warnings.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::ReqLogChannels => {
+            send_payload::<{ LOG_CHANNELS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::LogChannels,
+                &log_channels.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::LogChannels => {}
+        MsgType::SetLogChannels => {
+            log_channels.mask = rx_payload[0];
+
+            send_payload::<{ LOG_CHANNELS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::LogChannels,
+                &log_channels.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::ReqGroundRef => {
+            send_payload::<{ GROUND_REF_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::GroundRef,
+                &ground_ref.to_bytes(alt_est.estimate.above_takeoff),
+                usb_serial,
+            );
+        }
+        MsgType::GroundRef => {}
+        MsgType::SetGroundRef => {
+            if has_taken_off {
+                println!("Can't set the ground reference while airborne");
+            } else if ground_ref.set_manual(&rx_payload[..SET_GROUND_REF_SIZE]) {
+                println!("Ground reference set");
+            } else {
+                println!("Invalid ground reference received; not applied");
+            }
+
+            // Altitude above takeoff updates on the next estimator run.
+            send_payload::<{ GROUND_REF_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::GroundRef,
+                &ground_ref.to_bytes(alt_est.estimate.above_takeoff),
+                usb_serial,
+            );
+        }
+        MsgType::ReqFusionStatus => {
+            send_payload::<{ FUSION_STATUS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::FusionStatus,
+                &fusion_monitor.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::FusionStatus => {}
+        MsgType::ResetAttitude => {
+            // The reset runs from the main loop; the status reflects it once there.
+            if *arm_status == ArmStatus::Disarmed {
+                fusion::request_reset();
+            } else {
+                println!("Can't reset the attitude estimate unless disarmed");
+            }
+
+            send_payload::<{ FUSION_STATUS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::FusionStatus,
+                &fusion_monitor.to_bytes(),
+                usb_serial,
+            );
+        }
+        MsgType::ReqExtFeedback => {
+            send_payload::<{ EXT_FEEDBACK_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ExtFeedback,
+                &ext_ctrl.feedback_to_bytes(attitude, rates),
+                usb_serial,
+            );
+        }
+        MsgType::ExtFeedback => {}
+        MsgType::SetExtAttitude => {
+            // Taken by the main loop; invalid commands are dropped, and go stale.
+            match ExtAttCmd::from_bytes(&rx_payload[..EXT_ATT_CMD_SIZE]) {
+                Some(cmd) => ext_ctrl::store_cmd(cmd),
+                None => println!("Invalid external attitude command received"),
+            }
+
+            send_payload::<{ EXT_FEEDBACK_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::ExtFeedback,
+                &ext_ctrl.feedback_to_bytes(attitude, rates),
+                usb_serial,
+            );
+        }
+        MsgType::ReqAuthority => {
+            send_payload::<{ AUTHORITY_STATUS_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+                MsgType::Authority,
+                &authority.to_bytes(&config.authority),
+                usb_serial,
+            );
+        }
+        MsgType::Authority => {}
     }
 }
 
-fn send_payload<const N: usize>(
+pub fn send_payload<const N: usize>(
     msg_type: MsgType,
     payload: &[u8],
     usb_serial: &mut SerialPort<'static, setup::UsbBusType>,
@@ -719,6 +2421,11 @@ fn send_payload<const N: usize>(
     // N is the packet size.
     let payload_size = msg_type.payload_size();
 
+    if usb_frame::framed() {
+        usb_frame::send(msg_type, &payload[..payload_size], usb_serial);
+        return;
+    }
+
     let mut tx_buf = [0; N];
 
     tx_buf[0] = MSG_START;
@@ -0,0 +1,267 @@
+//! This module contains telemetry streaming over USB: Once the PC enables it, we push a fixed-layout
+//! snapshot of flight state at a set rate, for live plotting. This avoids a request and response
+//! per value.
+//!
+//! Snapshots are sent from the main loop, not the USB ISR. We only write a snapshot once the
+//! previous one has left the serial port's buffer, so we never block, or send a partial packet.
+//! Streaming stops if the USB device leaves the Configured state, or if the host stops reading.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use anyleaf_usb::{CRC_LEN, PAYLOAD_START_I};
+use defmt::println;
+use lin_alg::f32::Quaternion;
+use usbd_serial::SerialPort;
+
+use crate::{
+    batt_failsafe::BattStage,
+    flight_ctrls::common::RpmFiltered,
+    imu_processing::accel_health::FusionMode,
+    protocols::usb_preflight::{self, MsgType},
+    safety::ArmStatus,
+    setup,
+    state::FlightMode,
+};
+
+/// Snapshots per second, set over USB. 0 is off.
+pub static TELEM_STREAM_RATE: AtomicU8 = AtomicU8::new(0);
+
+// Hz. Requested rates outside this range are rejected.
+pub const RATE_MIN: u8 = 10;
+pub const RATE_MAX: u8 = 200;
+
+// Seconds. If no snapshot has left the buffer in this time, the host has stopped reading, and we
+// stop streaming.
+const STALL_TIMEOUT: f32 = 1.;
+
+// Sequence number, timestamp, attitude, gyro, attitude commanded, rates commanded, motor powers,
+// RPMs, RPM present flags, battery voltage and current, baro altitude, autopilot modes, arm
+// status, has taken off, the active control profile, the flight mode, the attitude fusion
+// mode, the throttle limit, filtered RPMs, their rates of change, and fresh flags, the
+// low-battery failsafe stage, ground speed, distance flown, distance and bearing to home, GNSS
+// valid flags, the yaw removed by mixer desaturation, active warning flags, and microseconds since
+// start.
+pub const TELEM_SNAPSHOT_SIZE: usize = 4
+    + 4
+    + 16
+    + 12
+    + 16
+    + 12
+    + 16
+    + 16
+    + 1
+    + 4
+    + 4
+    + 4
+    + 2
+    + 1
+    + 1
+    + 1
+    + 1
+    + 1
+    + 4
+    + 16
+    + 16
+    + 1
+    + 1
+    + 4
+    + 4
+    + 8
+    + 1
+    + 4
+    + 4
+    + 8;
+
+/// Flight state sent in each snapshot.
+pub struct TelemSnapshot {
+    /// Seconds since start.
+    pub timestamp: f32,
+    pub attitude: Quaternion,
+    /// Pitch, roll, and yaw rates, in rad/s.
+    pub gyro: (f32, f32, f32),
+    pub attitude_commanded: Quaternion,
+    /// Pitch, roll, and yaw rates commanded, in rad/s.
+    pub rates_commanded: (f32, f32, f32),
+    /// 0. to 1. In the order of `MotorServoState::rotor_rpms`.
+    pub motor_powers: [f32; 4],
+    pub rpms: [Option<f32>; 4],
+    pub batt_v: f32,
+    pub current: f32,
+    /// Meters MSL.
+    pub alt_baro: f32,
+    /// See `AutopilotStatus::mode_flags`.
+    pub autopilot_modes: u16,
+    pub arm_status: ArmStatus,
+    /// See `safety::handle_takeoff_attitude_lock`.
+    pub has_taken_off: bool,
+    /// The active control profile's index.
+    pub profile: u8,
+    /// See `state::flight_mode_label`.
+    pub flight_mode: FlightMode,
+    /// See `accel_health`.
+    pub fusion_mode: FusionMode,
+    /// The throttle limit in effect, 0. to 1.; 1. if unlimited. See `flight_ctrls::throttle_limit`.
+    pub throttle_limit: f32,
+    /// Filtered RPM and RPM/s, in the order of `rpms`; `None` if stale. See `common::RpmLpf`.
+    pub rpms_filtered: [Option<RpmFiltered>; 4],
+    /// See `batt_failsafe`.
+    pub batt_stage: BattStage,
+    /// m/s. `None` without a usable fix. See `gps_metrics`.
+    pub ground_speed: Option<f32>,
+    /// Meters, this flight.
+    pub distance_flown: f32,
+    /// Distance to home in m, and bearing to it in radians. `None` if unknown.
+    pub home_dist_bearing: Option<(f32, f32)>,
+    /// The portion of the yaw command removed by mixer desaturation, 0. to 1. See `mixer`.
+    pub yaw_removed: f32,
+    /// Bits are `state::Warning` IDs.
+    pub warnings: u32,
+    /// Microseconds since start; the same time as `timestamp`, without its loss of resolution as
+    /// time passes. See `util::monotonic`.
+    pub uptime_us: u64,
+}
+
+impl TelemSnapshot {
+    fn to_bytes(&self, seq: u32) -> [u8; TELEM_SNAPSHOT_SIZE] {
+        let mut result = [0; TELEM_SNAPSHOT_SIZE];
+
+        let mut i = 0;
+        let mut put = |bytes: &[u8]| {
+            result[i..i + bytes.len()].clone_from_slice(bytes);
+            i += bytes.len();
+        };
+
+        put(&seq.to_be_bytes());
+        put(&self.timestamp.to_be_bytes());
+
+        for q in [self.attitude, self.attitude_commanded] {
+            put(&q.w.to_be_bytes());
+            put(&q.x.to_be_bytes());
+            put(&q.y.to_be_bytes());
+            put(&q.z.to_be_bytes());
+        }
+
+        for v in [
+            self.gyro.0,
+            self.gyro.1,
+            self.gyro.2,
+            self.rates_commanded.0,
+            self.rates_commanded.1,
+            self.rates_commanded.2,
+        ] {
+            put(&v.to_be_bytes());
+        }
+
+        for p in self.motor_powers {
+            put(&p.to_be_bytes());
+        }
+
+        let mut rpm_present = 0;
+        for (j, rpm) in self.rpms.iter().enumerate() {
+            put(&rpm.unwrap_or(0.).to_be_bytes());
+            if rpm.is_some() {
+                rpm_present |= 1 << j;
+            }
+        }
+        put(&[rpm_present]);
+
+        put(&self.batt_v.to_be_bytes());
+        put(&self.current.to_be_bytes());
+        put(&self.alt_baro.to_be_bytes());
+        put(&self.autopilot_modes.to_be_bytes());
+        put(&[self.arm_status as u8]);
+        put(&[self.has_taken_off as u8]);
+        put(&[self.profile]);
+        put(&[self.flight_mode as u8]);
+        put(&[self.fusion_mode as u8]);
+        put(&self.throttle_limit.to_be_bytes());
+
+        let mut rpm_fresh = 0;
+        for (j, filtered) in self.rpms_filtered.iter().enumerate() {
+            put(&filtered.map_or(0., |f| f.rpm).to_be_bytes());
+            if filtered.is_some() {
+                rpm_fresh |= 1 << j;
+            }
+        }
+        for filtered in self.rpms_filtered {
+            put(&filtered.map_or(0., |f| f.rate).to_be_bytes());
+        }
+        put(&[rpm_fresh]);
+        put(&[self.batt_stage as u8]);
+
+        put(&self.ground_speed.unwrap_or(0.).to_be_bytes());
+        put(&self.distance_flown.to_be_bytes());
+        let (home_dist, home_bearing) = self.home_dist_bearing.unwrap_or((0., 0.));
+        put(&home_dist.to_be_bytes());
+        put(&home_bearing.to_be_bytes());
+        put(&[self.ground_speed.is_some() as u8 | (self.home_dist_bearing.is_some() as u8) << 1]);
+        put(&self.yaw_removed.to_be_bytes());
+        put(&self.warnings.to_be_bytes());
+        put(&self.uptime_us.to_be_bytes());
+
+        result
+    }
+}
+
+/// Streaming state. Run from the main loop.
+#[derive(Default)]
+pub struct TelemStream {
+    /// Incremented each snapshot sent, so the PC can detect drops.
+    seq: u32,
+    active: bool,
+    /// Seconds since start.
+    last_sent: f32,
+    /// Seconds since start. The last time the previous snapshot had left the buffer.
+    last_drained: f32,
+}
+
+impl TelemStream {
+    /// Run each main loop update. If this returns true, build a snapshot, and pass it to `send`.
+    pub fn due(&mut self, timestamp: f32) -> bool {
+        let rate = TELEM_STREAM_RATE.load(Ordering::Acquire);
+
+        if rate == 0 {
+            self.active = false;
+            return false;
+        }
+
+        if !self.active {
+            self.active = true;
+            self.last_drained = timestamp;
+            self.last_sent = 0.;
+        }
+
+        timestamp - self.last_sent >= 1. / rate as f32
+    }
+
+    pub fn send(
+        &mut self,
+        snapshot: &TelemSnapshot,
+        usb_serial: &mut SerialPort<'static, setup::UsbBusType>,
+    ) {
+        self.last_sent = snapshot.timestamp;
+
+        // `flush` returns an error while the previous snapshot is still in the buffer.
+        if usb_serial.flush().is_err() {
+            if snapshot.timestamp - self.last_drained > STALL_TIMEOUT {
+                println!("USB host stopped reading; telemetry stream stopped");
+                stop();
+            }
+            return;
+        }
+        self.last_drained = snapshot.timestamp;
+
+        usb_preflight::send_payload::<{ TELEM_SNAPSHOT_SIZE + PAYLOAD_START_I + CRC_LEN }>(
+            MsgType::TelemSnapshot,
+            &snapshot.to_bytes(self.seq),
+            usb_serial,
+        );
+
+        self.seq = self.seq.wrapping_add(1);
+    }
+}
+
+/// Stop streaming. Run from the USB ISR when the device isn't Configured.
+pub fn stop() {
+    TELEM_STREAM_RATE.store(0, Ordering::Release);
+}
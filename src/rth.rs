@@ -0,0 +1,326 @@
+//! This module contains return to home: Flying back to the base point, used by link-lost recovery,
+//! the low-battery failsafe, and follow-me once its target is stale. Fields differ, so the path is
+//! configurable:
+//!
+//! - Altitude: Hold the altitude at the start of the return, or a fixed altitude, above takeoff
+//!   or MSL. We never descend to return; if we're above a fixed altitude, we hold our own.
+//! - Climb first, then fly home, eg to clear trees; or climb while flying home.
+//! - On arriving within the approach radius: Loiter overhead, or land. Fixed-wing orbits the base
+//!   point at the loiter radius; it doesn't auto-land yet.
+//!
+//! If we start within the approach radius, we go straight to the approach, vice flying an outbound
+//! leg. Without GNSS, we only hold altitude.
+
+use ahrs::{ppks::PositVelEarthUnits, Params};
+use defmt::println;
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    alt_estimator::AltEstimate,
+    flight_ctrls::{autopilot::AutopilotStatus, common::AltType},
+    geo::LatLon,
+    system_status::SystemStatus,
+};
+
+#[cfg(feature = "quad")]
+use crate::flight_ctrls::autopilot::LandingCfg;
+#[cfg(feature = "fixed-wing")]
+use crate::flight_ctrls::autopilot::{Orbit, ORBIT_DEFAULT_GROUNDSPEED};
+
+// With climb-first, we start towards home once within this distance of the return altitude, in
+// m.
+const CLIMB_TOLERANCE: f32 = 2.;
+
+// m/s. Descent speed when landing on arrival.
+#[cfg(feature = "quad")]
+const LAND_DESCENT_SPEED: f32 = 1.;
+
+// Values outside these ranges, in m, are rejected when loading config.
+pub const ALT_MIN: f32 = 5.;
+pub const ALT_MAX: f32 = 9_000.;
+pub const APPROACH_RADIUS_MIN: f32 = 2.;
+pub const APPROACH_RADIUS_MAX: f32 = 200.;
+pub const LOITER_RADIUS_MIN: f32 = 10.;
+pub const LOITER_RADIUS_MAX: f32 = 500.;
+
+// Serialized size: Altitude mode, altitude, climb, approach, approach radius, and loiter radius.
+pub const RTH_CFG_SIZE: usize = 1 + 4 + 1 + 1 + 4 + 4;
+
+/// Repr is how it's stored in config.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RthAltMode {
+    /// Hold the altitude at the start of the return.
+    Current = 0,
+    /// Above takeoff, if we have a ground reference; see `ground_ref`. MSL otherwise.
+    #[default]
+    FixedAgl = 1,
+    FixedMsl = 2,
+}
+
+/// Repr is how it's stored in config.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RthClimb {
+    /// Reach the return altitude before flying home.
+    #[default]
+    First = 0,
+    /// Fly home while climbing.
+    EnRoute = 1,
+}
+
+/// Repr is how it's stored in config.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RthApproach {
+    /// Hold position over the base point; orbit it on fixed-wing.
+    #[default]
+    Loiter = 0,
+    /// Descend and land at the base point. Quad only; fixed-wing loiters.
+    Land = 1,
+}
+
+/// Return to home settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct RthCfg {
+    pub alt_mode: RthAltMode,
+    /// The return altitude, in m, for fixed altitude modes.
+    pub alt: f32,
+    pub climb: RthClimb,
+    pub approach: RthApproach,
+    /// Within this distance of the base point, in m, we start the approach.
+    pub approach_radius: f32,
+    /// Fixed-wing orbit radius over the base point, in m.
+    pub loiter_radius: f32,
+}
+
+impl Default for RthCfg {
+    fn default() -> Self {
+        Self {
+            alt_mode: Default::default(),
+            alt: 100.,
+            climb: Default::default(),
+            approach: Default::default(),
+            approach_radius: 10.,
+            loiter_radius: 30.,
+        }
+    }
+}
+
+impl RthCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let f = |i: usize| f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+
+        let alt = f(1);
+        let approach_radius = f(7);
+        let loiter_radius = f(11);
+
+        // These comparisons also reject NaN.
+        if !(ALT_MIN..=ALT_MAX).contains(&alt)
+            || !(APPROACH_RADIUS_MIN..=APPROACH_RADIUS_MAX).contains(&approach_radius)
+            || !(LOITER_RADIUS_MIN..=LOITER_RADIUS_MAX).contains(&loiter_radius)
+        {
+            return None;
+        }
+
+        Some(Self {
+            alt_mode: RthAltMode::try_from(buf[0]).ok()?,
+            alt,
+            climb: RthClimb::try_from(buf[5]).ok()?,
+            approach: RthApproach::try_from(buf[6]).ok()?,
+            approach_radius,
+            loiter_radius,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; RTH_CFG_SIZE] {
+        let mut result = [0; RTH_CFG_SIZE];
+
+        result[0] = self.alt_mode as u8;
+        result[1..5].clone_from_slice(&self.alt.to_be_bytes());
+        result[5] = self.climb as u8;
+        result[6] = self.approach as u8;
+        result[7..11].clone_from_slice(&self.approach_radius.to_be_bytes());
+        result[11..15].clone_from_slice(&self.loiter_radius.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RthPhase {
+    Climb,
+    Return,
+    Approach,
+}
+
+#[derive(Default)]
+pub struct Rth {
+    /// `None` when not returning.
+    phase: Option<RthPhase>,
+    /// Altitude held, set at the start of the return.
+    alt: Option<(AltType, f32)>,
+    /// `execute` ran since the last `end_update`.
+    executed: bool,
+}
+
+impl Rth {
+    /// Run from `update_flight_modes` each update while returning. Sets the autopilot modes for
+    /// the return's phase.
+    pub fn execute(
+        &mut self,
+        system_status: &SystemStatus,
+        autopilot_status: &mut AutopilotStatus,
+        params: &Params,
+        alt: &AltEstimate,
+        base_pt: &PositVelEarthUnits,
+        cfg: &RthCfg,
+    ) {
+        self.executed = true;
+
+        let (alt_type, alt_current) = match cfg.alt_mode {
+            RthAltMode::FixedMsl => (AltType::Msl, alt.msl),
+            _ => alt.default_alt(),
+        };
+
+        let dist = if system_status.gnss_usable() {
+            let posit = LatLon::new(params.posit_fused.lat_e8, params.posit_fused.lon_e8);
+            Some(posit.dist(&LatLon::from_posit(base_pt)))
+        } else {
+            None
+        };
+        let near = dist.map_or(false, |d| d < cfg.approach_radius);
+
+        if self.phase.is_none() {
+            let alt_target = match cfg.alt_mode {
+                RthAltMode::Current => alt_current,
+                RthAltMode::FixedAgl | RthAltMode::FixedMsl => cfg.alt.max(alt_current),
+            };
+            self.alt = Some((alt_type, alt_target));
+
+            let phase = if near {
+                RthPhase::Approach
+            } else if cfg.climb == RthClimb::First {
+                RthPhase::Climb
+            } else {
+                RthPhase::Return
+            };
+
+            println!("Returning home. Alt: {}, dist: {:?}", alt_target, dist);
+            self.phase = Some(phase);
+        }
+
+        let Some((_, alt_target)) = self.alt else {
+            return;
+        };
+
+        let phase = match self.phase {
+            Some(RthPhase::Climb) if alt_current > alt_target - CLIMB_TOLERANCE => RthPhase::Return,
+            Some(RthPhase::Return) if near => RthPhase::Approach,
+            Some(p) => p,
+            None => RthPhase::Climb,
+        };
+
+        if Some(phase) != self.phase && phase == RthPhase::Approach {
+            println!("Arrived home; approaching");
+        }
+        self.phase = Some(phase);
+
+        autopilot_status.alt_hold = self.alt;
+
+        // Without GNSS, we can only hold altitude. `cancel_modes_for_stale_sensors` clears
+        // point modes in that case.
+        if dist.is_none() {
+            return;
+        }
+
+        match phase {
+            RthPhase::Climb => {
+                autopilot_status.direct_to_point = None;
+            }
+            RthPhase::Return => {
+                autopilot_status.direct_to_point = Some(base_pt.clone());
+            }
+            RthPhase::Approach => {
+                autopilot_status.direct_to_point = None;
+                self.approach(autopilot_status, alt, base_pt, cfg);
+            }
+        }
+    }
+
+    #[cfg(feature = "quad")]
+    fn approach(
+        &self,
+        autopilot_status: &mut AutopilotStatus,
+        alt: &AltEstimate,
+        base_pt: &PositVelEarthUnits,
+        cfg: &RthCfg,
+    ) {
+        match cfg.approach {
+            RthApproach::Loiter => {
+                autopilot_status.loiter = Some(PositVelEarthUnits {
+                    elevation_msl: alt.msl,
+                    ..base_pt.clone()
+                });
+            }
+            RthApproach::Land => {
+                autopilot_status.alt_hold = None;
+                autopilot_status.loiter = None;
+
+                if autopilot_status.land.is_none() {
+                    autopilot_status.land = Some(LandingCfg {
+                        descent_starting_alt_msl: alt.msl,
+                        descent_speed: LAND_DESCENT_SPEED,
+                        touchdown_point: base_pt.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "fixed-wing")]
+    fn approach(
+        &self,
+        autopilot_status: &mut AutopilotStatus,
+        _alt: &AltEstimate,
+        base_pt: &PositVelEarthUnits,
+        cfg: &RthCfg,
+    ) {
+        if autopilot_status.orbit.is_none() {
+            autopilot_status.orbit = Some(Orbit {
+                shape: Default::default(),
+                center: LatLon::from_posit(base_pt),
+                radius: cfg.loiter_radius,
+                ground_speed: ORBIT_DEFAULT_GROUNDSPEED,
+                direction: Default::default(),
+            });
+        }
+    }
+
+    /// Run at the end of `update_flight_modes`. If nothing returned this update, the next return
+    /// starts fresh.
+    pub fn end_update(&mut self) {
+        if !self.executed {
+            self.phase = None;
+            self.alt = None;
+        }
+        self.executed = false;
+    }
+}
+
+/// Clear the autopilot modes a return may set. Used when whatever started it ends; the switches
+/// set modes again after.
+pub fn clear_modes(autopilot_status: &mut AutopilotStatus) {
+    autopilot_status.alt_hold = None;
+    autopilot_status.direct_to_point = None;
+    autopilot_status.land = None;
+
+    #[cfg(feature = "quad")]
+    {
+        autopilot_status.loiter = None;
+    }
+    #[cfg(feature = "fixed-wing")]
+    {
+        autopilot_status.orbit = None;
+    }
+}
@@ -20,15 +20,27 @@ use hal::{
     gpio::{self, Port},
     pac,
 };
-use num_traits::Float;
+use num_enum::TryFromPrimitive;
 
+#[cfg(feature = "quad")]
+use crate::flight_ctrls::autopilot::LandingCfg;
 use crate::{
+    alt_estimator::AltEstimate,
+    batt_failsafe::BattAction,
+    controller_interface::ChannelData,
     flight_ctrls::{autopilot::AutopilotStatus, common::AltType},
+    motor_kill,
+    protocols::dshot,
+    rth::{Rth, RthCfg},
+    setup::MotorTimer,
     system_status::{SensorStatus, SystemStatus},
-}; // abs on float.
+};
 
-// We must receive arm or disarm signals for this many update cycles in a row to perform those actions.
-pub const NUM_ARM_DISARM_SIGNALS_REQUIRED: u8 = 5;
+// Required signal counts above this are rejected when loading config.
+const ARM_SIGNALS_MAX: u8 = 100;
+
+// Serialized size: Arm, and disarm signal counts.
+pub const ARM_CFG_SIZE: usize = 2;
 
 // This flag starts false, then is set as soon as we receive a disarm signal with throttle idle.
 // Stays set throughout the remaindeer of run. Ensures the device doesn't start in an armed state.
@@ -37,27 +49,61 @@ static RECEIVED_INITIAL_DISARM: AtomicBool = AtomicBool::new(false);
 // This flag gets set if you command arm from the controller without the throttle in the idle position.
 // When this flag is set, the aircraft won't arm until the arm switch is cycled back to safe.
 static ARM_COMMANDED_WITHOUT_IDLE: AtomicBool = AtomicBool::new(false);
+
+// This flag gets set if you command arm while a prearm switch is configured, and not active. As
+// above, the arm switch must then be cycled to safe.
+static ARM_COMMANDED_WITHOUT_PREARM: AtomicBool = AtomicBool::new(false);
+
+// As above, if you command arm while the attitude estimate is aligning. Arming as soon as it
+// converges, with the switch already set, would be a surprise.
+static ARM_COMMANDED_WHILE_ALIGNING: AtomicBool = AtomicBool::new(false);
 // static CONTROLLER_PREV_ARMED: AtomicBool = AtomicBool::new(false);
 
-const THROTTLE_MAX_TO_ARM: f32 = 0.005;
+pub const THROTTLE_MAX_TO_ARM: f32 = 0.005;
 
-// Altitude to climb to while executing lost link procedure, in meters AGL. This altitude should keep
-// it clear of trees, while remaining below most legal drone limits. A higher alt may increase chances
-// of req-acquiring the link.
-const LOST_LINK_RTB_ALT: f32 = 100.;
+// Seconds. `ImuFailPolicy::Descend` holds power for at most this long, then disarms.
+const IMU_FAIL_DESCEND_TIME: f32 = 20.;
 
-// A/C mus be within this altitude of the commanded alt (ie `LOST_LINK_RTB_ALT`) before proceeding
-// towards base etc.
-const ALT_EPSILON_BEFORE_LATERAL: f32 = 20.;
+// m/s. Descent speed when landing from the low-battery failsafe.
+#[cfg(feature = "quad")]
+const BATT_LAND_DESCENT_SPEED: f32 = 1.;
 
 // If power has been higher than this power level for this time, consider teh craft airborne
 // for the purposes of the attitude lock.
 const TAKEOFF_POWER_THRESH: f32 = 0.2;
 const IDLE_POWER_THRESH: f32 = 0.07; // todo: TIe to cfg (etc) idle.
-const TAKEOFF_POWER_TIME: f32 = 1.;
+#[cfg(feature = "quad")]
+pub const TAKEOFF_POWER_TIME: f32 = 1.;
 const IDLE_POWER_TIME: f32 = 5.;
+#[cfg(feature = "quad")]
 const UPRIGHT_THRESH: f32 = 0.17; // radians
 
+// Fixed-wing takeoff detection. A hand or bungee launch shows as a spike in forward acceleration,
+// in m/s^2; we then consider the craft airborne if it holds flying speed, in m/s, for this time
+// within the window after the spike. Throttle alone can't be used for this: It's often at full
+// before the throw.
+#[cfg(feature = "fixed-wing")]
+const LAUNCH_ACCEL_THRESH: f32 = 15.;
+#[cfg(feature = "fixed-wing")]
+const LAUNCH_WINDOW: f32 = 2.;
+#[cfg(feature = "fixed-wing")]
+const FLYING_SPEED_THRESH: f32 = 8.;
+#[cfg(feature = "fixed-wing")]
+const FLYING_SPEED_TIME: f32 = 0.5;
+// Fallback, eg for a runway takeoff, or without a speed estimate: Throttle held above this for
+// this time.
+#[cfg(feature = "fixed-wing")]
+const FW_TAKEOFF_POWER_THRESH: f32 = 0.5;
+#[cfg(feature = "fixed-wing")]
+const FW_TAKEOFF_POWER_TIME: f32 = 3.;
+// Landed: Throttle idle, and speed below this for `IDLE_POWER_TIME`. A glide at idle is faster.
+#[cfg(feature = "fixed-wing")]
+const LANDED_SPEED_THRESH: f32 = 2.;
+
+// Before takeoff, we scale attitude corrections by this at zero throttle, rising to full authority
+// at `TAKEOFF_POWER_THRESH`. This prevents flipping on the bench, where corrections can't converge.
+const GROUND_AUTHORITY_MIN: f32 = 0.2;
+
 // Block RX reception of packets coming in at a faster rate then this. This prevents external
 // sources from interfering with other parts of the application by taking too much time.
 // Note that we expect a 500hz packet rate for control channel data.
@@ -100,6 +146,24 @@ impl Default for ArmStatus {
     }
 }
 
+/// What to do if IMU data goes stale in flight. Without it, we have no attitude estimate, so can't
+/// fly normally.
+#[derive(Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ImuFailPolicy {
+    /// Set all motors to a fixed power, for an uncontrolled descent. Normal flight resumes if IMU
+    /// data does. We disarm after `IMU_FAIL_DESCEND_TIME`, or on the arm or kill switch.
+    Descend = 0,
+    /// Disarm; motors stop.
+    Disarm = 1,
+}
+
+impl Default for ImuFailPolicy {
+    fn default() -> Self {
+        Self::Disarm
+    }
+}
+
 #[cfg(feature = "fixed-wing")]
 /// Enable servos, by resetting its pins.
 fn enable_servos() {
@@ -161,25 +225,76 @@ fn disable_servos() {
     }
 }
 
+/// The state of the optional prearm switch. Repr u8 is for passing over USB serial.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum PrearmStatus {
+    /// No prearm channel is assigned; arming doesn't require it.
+    #[default]
+    NotConfigured = 0,
+    Inactive = 1,
+    Active = 2,
+}
+
+/// Arming settings. Stored in user config.
+#[derive(Clone, Copy)]
+pub struct ArmCfg {
+    /// We must receive arm signals for this many update cycles in a row to arm.
+    pub arm_signals: u8,
+    /// We must receive disarm signals for this many update cycles in a row to disarm.
+    pub disarm_signals: u8,
+}
+
+impl Default for ArmCfg {
+    fn default() -> Self {
+        Self {
+            arm_signals: 10,
+            disarm_signals: 5,
+        }
+    }
+}
+
+impl ArmCfg {
+    /// Parse and validate. Returns `None` if values are out of range.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if !(1..=ARM_SIGNALS_MAX).contains(&buf[0]) || !(1..=ARM_SIGNALS_MAX).contains(&buf[1]) {
+            return None;
+        }
+
+        Some(Self {
+            arm_signals: buf[0],
+            disarm_signals: buf[1],
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARM_CFG_SIZE] {
+        [self.arm_signals, self.disarm_signals]
+    }
+}
+
 /// Arm or disarm the arm state (and therefor the motors), based on arm switch status and throttle.
-/// Arm switch must be set while throttle is idle.
+/// Arm switch must be set while throttle is idle, while the prearm switch, if configured, is
+/// active, and once the attitude estimate has aligned. Disarming doesn't depend on these.
 pub fn handle_arm_status(
     arm_signals_received: &mut u8,
     disarm_signals_received: &mut u8,
     controller_arm_status: ArmStatus,
+    prearm: PrearmStatus,
+    aligning: bool,
     arm_status: &mut ArmStatus,
     has_taken_off: &mut bool,
     throttle: f32,
+    cfg: &ArmCfg,
 ) {
     match arm_status.clone() {
         MOTORS_ARMED => {
             if controller_arm_status != MOTORS_ARMED {
-                *disarm_signals_received += 1;
+                *disarm_signals_received = disarm_signals_received.saturating_add(1);
             } else {
                 *disarm_signals_received = 0;
             }
 
-            if *disarm_signals_received >= NUM_ARM_DISARM_SIGNALS_REQUIRED {
+            if *disarm_signals_received >= cfg.disarm_signals {
                 *disarm_signals_received = 0;
 
                 // On fixed, this could be either disarmed, or controls armed.
@@ -199,19 +314,33 @@ pub fn handle_arm_status(
             #[cfg(feature = "fixed-wing")]
             enable_servos();
         }
-        ArmStatus::Disarmed => {
+        // Disarmed, or on fixed-wing, controls armed.
+        _ => {
             if controller_arm_status == MOTORS_ARMED {
-                *arm_signals_received += 1;
+                *arm_signals_received = arm_signals_received.saturating_add(1);
             } else {
                 RECEIVED_INITIAL_DISARM.store(true, Ordering::Release);
                 ARM_COMMANDED_WITHOUT_IDLE.store(false, Ordering::Release);
+                ARM_COMMANDED_WITHOUT_PREARM.store(false, Ordering::Release);
+                ARM_COMMANDED_WHILE_ALIGNING.store(false, Ordering::Release);
                 *arm_signals_received = 0;
             }
 
-            if *arm_signals_received >= NUM_ARM_DISARM_SIGNALS_REQUIRED {
+            if *arm_signals_received >= cfg.arm_signals {
                 *arm_signals_received = 0;
 
-                if !ARM_COMMANDED_WITHOUT_IDLE.load(Ordering::Acquire) {
+                if prearm == PrearmStatus::Inactive {
+                    // Require the arm switch be cycled, so setting prearm with the arm switch
+                    // already set doesn't arm.
+                    ARM_COMMANDED_WITHOUT_PREARM.store(true, Ordering::Release);
+                } else if ARM_COMMANDED_WITHOUT_PREARM.load(Ordering::Acquire) {
+                    // println!("Arm commanded without prearm; cycle arm switch to arm.");
+                } else if ARM_COMMANDED_WHILE_ALIGNING.load(Ordering::Acquire) {
+                    // Cycle the arm switch to arm.
+                } else if aligning {
+                    ARM_COMMANDED_WHILE_ALIGNING.store(true, Ordering::Release);
+                    println!("Arm commanded while the attitude estimate is aligning");
+                } else if !ARM_COMMANDED_WITHOUT_IDLE.load(Ordering::Acquire) {
                     if throttle < THROTTLE_MAX_TO_ARM {
                         if !RECEIVED_INITIAL_DISARM.load(Ordering::Acquire) {
                             // println!(
@@ -233,60 +362,167 @@ pub fn handle_arm_status(
                 }
             }
 
+            // Controls follow the switch, without the motor's arming checks; eg for gliding, or
+            // an auto-launch. The motor position implies controls armed.
             #[cfg(feature = "fixed-wing")]
-            disable_servos();
-        }
-        #[cfg(feature = "fixed-wing")]
-        ArmStatus::ControlsArmed => {
-            enable_servos();
+            if *arm_status != MOTORS_ARMED {
+                if controller_arm_status == ArmStatus::Disarmed {
+                    *arm_status = ArmStatus::Disarmed;
+                    disable_servos();
+                } else {
+                    *arm_status = ArmStatus::ControlsArmed;
+                    enable_servos();
+                }
+            }
         }
     }
 }
 
-/// If we are airborne and haven't received a radio signal in a certain amount of time,
-/// execute a lost-link
-/// procedure.
-pub fn excecute_link_lost(
-    system_status: &mut SystemStatus,
+/// Run from `update_flight_modes` while the low-battery failsafe's stage 3 action is in effect.
+/// Returns to the base point if configured to, and GNSS is usable; see `rth`. Otherwise,
+/// descends and lands where we are. Fixed-wing always returns.
+#[cfg_attr(feature = "fixed-wing", allow(unused_variables))]
+pub fn execute_batt_failsafe(
+    action: BattAction,
+    system_status: &SystemStatus,
     autopilot_status: &mut AutopilotStatus,
     params: &Params,
+    alt: &AltEstimate,
     base_pt: &PositVelEarthUnits,
+    rth: &mut Rth,
+    rth_cfg: &RthCfg,
 ) {
-    // todo: Put back. Getting this spammed in console. Is the link actually lost?
-    // println!("Link lost. Executing recovery.");
-    // todo: Consider how you want to handle this, with and without GPS.
+    let rth =
+        cfg!(feature = "fixed-wing") || (action == BattAction::Rth && system_status.gnss_usable());
+
+    if rth {
+        rth.execute(
+            system_status,
+            autopilot_status,
+            params,
+            alt,
+            base_pt,
+            rth_cfg,
+        );
+        return;
+    }
 
-    // todo: To start, command an attitude-mode hover, with baro alt hold.
+    #[cfg(feature = "quad")]
+    if autopilot_status.land.is_none() {
+        println!("Low battery; landing");
+
+        autopilot_status.alt_hold = None;
+        autopilot_status.direct_to_point = None;
+        autopilot_status.loiter = None;
+
+        autopilot_status.land = Some(LandingCfg {
+            descent_starting_alt_msl: alt.msl,
+            descent_speed: BATT_LAND_DESCENT_SPEED,
+            touchdown_point: PositVelEarthUnits {
+                elevation_msl: alt.msl,
+                ..params.posit_fused.clone()
+            },
+        });
+    }
+}
+
+/// Run from the sensor watchdog when IMU data is stale while armed. The main loop doesn't run
+/// without IMU data, so we command the motors directly, and handle the arm and kill switches here,
+/// from `ch_data`. `descend_pwr` is 0. to 1. `fail_start` is when the failure started, in s; the
+/// caller clears it once IMU data resumes.
+pub fn execute_imu_failure(
+    policy: ImuFailPolicy,
+    descend_pwr: f32,
+    ch_data: Option<&ChannelData>,
+    fail_start: &mut Option<f32>,
+    timestamp: f32,
+    arm_status: &mut ArmStatus,
+    motor_timer: &mut MotorTimer,
+) {
+    let start = *fail_start.get_or_insert(timestamp);
 
-    // todo: Make sure you resume flight once link is re-acquired.
-    // }
+    let (disarm_switch, kill_switch) = match ch_data {
+        Some(ch) => (ch.arm_status == ArmStatus::Disarmed, ch.kill),
+        None => (false, false),
+    };
 
-    autopilot_status.alt_hold = Some((AltType::Msl, LOST_LINK_RTB_ALT));
+    // The kill releases once the main loop resumes, with throttle at idle.
+    motor_kill::update(kill_switch, false, *arm_status as u8);
 
-    #[cfg(feature = "quad")]
-    if system_status.gnss_can == SensorStatus::Pass {
-        if (params.alt_msl_baro - LOST_LINK_RTB_ALT).abs() < ALT_EPSILON_BEFORE_LATERAL {
-            autopilot_status.direct_to_point = Some(base_pt.clone());
+    let disarm = match policy {
+        ImuFailPolicy::Descend => {
+            disarm_switch || motor_kill::active() || timestamp - start >= IMU_FAIL_DESCEND_TIME
         }
+        ImuFailPolicy::Disarm => true,
+    };
+
+    if disarm {
+        println!("IMU data stale; disarming");
+        *arm_status = ArmStatus::Disarmed;
+        dshot::stop_all(motor_timer);
+        return;
     }
 
-    #[cfg(feature = "fixed-wing")]
-    if system_status.gnss_can == SensorStatus::Pass {
-    } else if system_status.magnetometer == SensorStatus::Pass {
-        if (params.alt_msl_baro - LOST_LINK_RTB_ALT).abs() < ALT_EPSILON_BEFORE_LATERAL {
-            autopilot_status.direct_to_point = Some(base_pt.clone());
+    dshot::set_power(
+        descend_pwr,
+        descend_pwr,
+        descend_pwr,
+        descend_pwr,
+        motor_timer,
+    );
+}
+
+/// Cancel autopilot modes that depend on sensors without recent, valid data.
+pub fn cancel_modes_for_stale_sensors(
+    system_status: &SystemStatus,
+    autopilot_status: &mut AutopilotStatus,
+) {
+    let baro_usable =
+        system_status.baro == SensorStatus::Pass || system_status.baro_can == SensorStatus::Pass;
+
+    match autopilot_status.alt_hold {
+        Some((AltType::Msl | AltType::Takeoff, _)) if !baro_usable => {
+            autopilot_status.alt_hold = None
+        }
+        Some((AltType::Agl, _)) if system_status.tof != SensorStatus::Pass => {
+            autopilot_status.alt_hold = None
+        }
+        _ => (),
+    }
+
+    if !system_status.gnss_usable() {
+        autopilot_status.direct_to_point = None;
+        autopilot_status.sequence = false;
+        #[cfg(feature = "quad")]
+        {
+            autopilot_status.loiter = None;
+            autopilot_status.follow = None;
+        }
+        #[cfg(feature = "fixed-wing")]
+        {
+            autopilot_status.orbit = None;
         }
+    }
+}
 
-        // todo: Store lost-link heading somewhere (probably a LostLinkStatus struct etc)
-        // Climb with reverse heading if no GPS available.
-        // Note that quadcopter movements may be too unstable to attempt this.
+/// The portion of attitude correction authority to apply. Before takeoff, corrections are
+/// attenuated below the takeoff throttle threshold; this coexists with air mode, which otherwise
+/// raises motors to maintain authority at low throttle.
+pub fn ground_authority(throttle: f32, has_taken_off: bool) -> f32 {
+    if has_taken_off {
+        return 1.;
     }
+
+    let portion = (throttle / TAKEOFF_POWER_THRESH).clamp(0., 1.);
+    GROUND_AUTHORITY_MIN + (1. - GROUND_AUTHORITY_MIN) * portion
 }
 
 /// Unlock the takeoff attitude lock if motor power has exceed a certain power level for a
-/// certain amount of time. This is done by changing the `has_taken_off` variable.
+/// certain amount of time. This is done by changing the `has_taken_off` variable. Re-lock it
+/// after landing (idle, and upright, for a time), or on disarm.
 ///
 /// todo: Perhaps take more factors into account. This is probably ok for now.
+#[cfg(feature = "quad")]
 pub fn handle_takeoff_attitude_lock(
     arm_status: ArmStatus,
     throttle: f32,
@@ -296,7 +532,15 @@ pub fn handle_takeoff_attitude_lock(
     has_taken_off: &mut bool,
     dt: f32,
 ) {
-    if arm_status == MOTORS_ARMED && throttle >= TAKEOFF_POWER_THRESH {
+    // `handle_arm_status` resets this on a switch disarm; this covers other paths, eg over USB.
+    if arm_status != MOTORS_ARMED {
+        *has_taken_off = false;
+        *time_with_high_throttle = 0.;
+        *time_with_low_throttle = 0.;
+        return;
+    }
+
+    if throttle >= TAKEOFF_POWER_THRESH {
         // todo: Scope `time_with_high_throttle` locally.
         if *time_with_high_throttle >= TAKEOFF_POWER_TIME {
             *has_taken_off = true;
@@ -304,10 +548,7 @@ pub fn handle_takeoff_attitude_lock(
             return;
         }
         *time_with_high_throttle += dt;
-    } else if arm_status == MOTORS_ARMED
-        && throttle <= IDLE_POWER_THRESH
-        && angle_from_upright < UPRIGHT_THRESH
-    {
+    } else if throttle <= IDLE_POWER_THRESH && angle_from_upright < UPRIGHT_THRESH {
         if *time_with_low_throttle >= IDLE_POWER_TIME {
             *has_taken_off = false;
             *time_with_low_throttle = 0.;
@@ -319,3 +560,88 @@ pub fn handle_takeoff_attitude_lock(
         *time_with_low_throttle = 0.;
     }
 }
+
+/// Fixed-wing launch detection state, for `handle_takeoff_attitude_lock`.
+#[cfg(feature = "fixed-wing")]
+#[derive(Default)]
+pub struct LaunchDetect {
+    /// Seconds since the last forward acceleration spike, while within `LAUNCH_WINDOW`.
+    since_launch: Option<f32>,
+    /// Seconds at flying speed since the spike.
+    time_at_speed: f32,
+    time_with_high_throttle: f32,
+    time_landed: f32,
+}
+
+/// Unlock the takeoff attitude lock on a launch: A forward acceleration spike, followed by
+/// flying speed. Or, as a fallback, sustained high throttle. Re-lock it after landing (idle, and
+/// stopped, for a time), or on disarm. `fwd_accel` is in m/s^2, along the body's forward axis;
+/// `speed` is airspeed, or ground speed, in m/s.
+///
+/// Without a speed estimate, we can't tell a glide from landing, so only disarming re-locks.
+#[cfg(feature = "fixed-wing")]
+pub fn handle_takeoff_attitude_lock(
+    arm_status: ArmStatus,
+    throttle: f32,
+    fwd_accel: f32,
+    speed: Option<f32>,
+    launch: &mut LaunchDetect,
+    has_taken_off: &mut bool,
+    dt: f32,
+) {
+    // `handle_arm_status` resets this on a switch disarm; this covers other paths, eg over USB.
+    if arm_status != MOTORS_ARMED {
+        *has_taken_off = false;
+        *launch = Default::default();
+        return;
+    }
+
+    if *has_taken_off {
+        match speed {
+            Some(s) if throttle <= IDLE_POWER_THRESH && s < LANDED_SPEED_THRESH => {
+                launch.time_landed += dt;
+                if launch.time_landed >= IDLE_POWER_TIME {
+                    println!("Landing detected");
+                    *has_taken_off = false;
+                    *launch = Default::default();
+                }
+            }
+            _ => launch.time_landed = 0.,
+        }
+        return;
+    }
+
+    if fwd_accel >= LAUNCH_ACCEL_THRESH {
+        launch.since_launch = Some(0.);
+    }
+
+    if let Some(t) = launch.since_launch {
+        match speed {
+            Some(s) if s >= FLYING_SPEED_THRESH => launch.time_at_speed += dt,
+            _ => launch.time_at_speed = 0.,
+        }
+
+        if launch.time_at_speed >= FLYING_SPEED_TIME {
+            println!("Launch detected");
+            *has_taken_off = true;
+        } else if t + dt > LAUNCH_WINDOW {
+            launch.since_launch = None;
+            launch.time_at_speed = 0.;
+        } else {
+            launch.since_launch = Some(t + dt);
+        }
+    }
+
+    if throttle >= FW_TAKEOFF_POWER_THRESH {
+        launch.time_with_high_throttle += dt;
+        if launch.time_with_high_throttle >= FW_TAKEOFF_POWER_TIME {
+            *has_taken_off = true;
+        }
+    } else {
+        launch.time_with_high_throttle = 0.;
+    }
+
+    if *has_taken_off {
+        *launch = Default::default();
+    }
+}
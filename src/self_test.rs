@@ -0,0 +1,370 @@
+//! This module contains an on-demand preflight self-test, started over USB. It re-checks that each
+//! sensor responds, and that readings are plausible with the aircraft stationary, and motors off.
+//! `setup::init_sensors` runs similar checks once, at boot.
+//!
+//! Sensors are normally read by DMA sequences started from the main loop, so the test runs in
+//! stages to avoid colliding with them:
+//! - Measure the gyro noise floor from the normal IMU stream.
+//! - Stop starting baro and external sensor transfers, and wait for any in progress to complete.
+//! - Run the blocking checks in a single main loop pass, with the IMU's SPI bus locked.
+//! - Baro and external sensor transfers resume at their next task slot.
+
+use defmt::println;
+use hal::{delay_us, gpio::Pin};
+use num_traits::Float;
+
+use crate::{
+    board_config::AHB_FREQ,
+    drivers::{baro_dps310 as baro, gps_ublox as gps, imu_icm426xx as imu, tof_vl53l1 as tof},
+    imu_processing::imu_shared,
+    loop_rates,
+    setup::{I2cBaro, I2cMag, SpiImu},
+    system_status::{self, SensorStatus, SystemStatus},
+};
+
+// Time to collect IMU readings over to measure the gyro noise floor, in seconds.
+const GYRO_SAMPLE_TIME: f32 = 0.5;
+// We wait this long, in seconds, after pausing baro and external sensor transfers, so any in
+// progress can complete before we use the buses.
+const QUIESCE_TIME: f32 = 0.025;
+
+// Standard deviation of any gyro axis above this, in rad/s, with the aircraft stationary, is a fault.
+const MAX_GYRO_NOISE: f32 = 0.03;
+
+// Pa. Covers sea level weather extremes, up to ~9,000m.
+const BARO_PRESSURE_MIN: f32 = 30_000.;
+const BARO_PRESSURE_MAX: f32 = 110_000.;
+
+// With motors off, an RPM reading above this is a fault.
+const MAX_RPM_STOPPED: f32 = 100.;
+
+// Below this battery voltage, we assume we're powered by USB alone.
+const NO_BATT_V: f32 = 1.;
+const CELL_V_MIN: f32 = 3.;
+const CELL_V_MAX: f32 = 4.4;
+// Amps. With motors off, current should be near 0.
+const MAX_CURRENT_STOPPED: f32 = 3.;
+
+const CHECK_SIZE: usize = 5;
+pub const NUM_CHECKS: usize = 10;
+// Test state, then each check.
+pub const SELF_TEST_REPORT_SIZE: usize = 1 + NUM_CHECKS * CHECK_SIZE;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Idle,
+    CollectGyro,
+    /// Waiting for baro and external sensor transfers to complete. Value is IMU updates remaining.
+    Quiesce(u32),
+    Run,
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Self::Idle
+    }
+}
+
+/// The result of one check.
+#[derive(Clone, Copy, Default)]
+pub struct Check {
+    pub status: SensorStatus,
+    /// Meaning depends on the check; see `SelfTestReport`.
+    pub detail: f32,
+}
+
+impl Check {
+    fn new(status: SensorStatus, detail: f32) -> Self {
+        Self { status, detail }
+    }
+
+    /// From a device ID read. `NotConnected` on no response; `Fault` if it responds with the
+    /// wrong ID. Detail is the ID read, or -1 if no response.
+    fn from_id<E>(read: Result<u16, E>, expected: u16) -> Self {
+        match read {
+            Ok(id) if id == expected => Self::new(SensorStatus::Pass, id as f32),
+            Ok(id) => Self::new(SensorStatus::Fault, id as f32),
+            Err(_) => Self::new(SensorStatus::NotConnected, -1.),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; CHECK_SIZE] {
+        let mut result = [0; CHECK_SIZE];
+
+        result[0] = self.status as u8;
+        result[1..5].clone_from_slice(&self.detail.to_be_bytes());
+        result
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SelfTestReport {
+    /// Detail: WHO_AM_I.
+    pub imu: Check,
+    /// Detail: Product ID.
+    pub baro: Check,
+    /// Our magnetometer readings come in over CAN, so we can't read an ID; this checks for a recent
+    /// reading. Detail: Seconds since the last reading, or -1 if never received.
+    pub mag: Check,
+    /// Detail: Model ID.
+    pub tof: Check,
+    /// The GPS module has no ID register; this checks that it responds. Detail: Bytes available.
+    pub gps: Check,
+    /// Detail: Pa.
+    pub baro_pressure: Check,
+    /// Detail: The highest standard deviation of any axis, in rad/s.
+    pub gyro_noise: Check,
+    /// `Pass` if all readings are 0 or absent. Detail: The highest reading.
+    pub rpm: Check,
+    /// `Pass` if 0 (USB power only), or in range for the configured cell count. Detail: V.
+    pub batt_adc: Check,
+    /// Detail: A.
+    pub current_adc: Check,
+}
+
+impl SelfTestReport {
+    fn checks(&self) -> [&Check; NUM_CHECKS] {
+        [
+            &self.imu,
+            &self.baro,
+            &self.mag,
+            &self.tof,
+            &self.gps,
+            &self.baro_pressure,
+            &self.gyro_noise,
+            &self.rpm,
+            &self.batt_adc,
+            &self.current_adc,
+        ]
+    }
+
+    /// A check failed. The external sensors (mag, TOF, GPS) being absent isn't a fault.
+    pub fn fault(&self) -> bool {
+        self.checks()
+            .iter()
+            .any(|c| c.status == SensorStatus::Fault)
+            || self.imu.status == SensorStatus::NotConnected
+            || self.baro.status == SensorStatus::NotConnected
+    }
+}
+
+/// Self-test state. Started from the USB ISR, and run from the main loop.
+#[derive(Default)]
+pub struct SelfTest {
+    stage: Stage,
+    gyro_count: u32,
+    gyro_mean: [f32; 3],
+    /// Sum of squared differences from the mean, for each axis (Welford's algorithm).
+    gyro_m2: [f32; 3],
+    /// The latest completed report, if any.
+    pub report: Option<SelfTestReport>,
+}
+
+impl SelfTest {
+    /// Start a test. Clears the previous report. The aircraft should be stationary.
+    pub fn start(&mut self) {
+        *self = Self {
+            stage: Stage::CollectGyro,
+            ..Default::default()
+        };
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    /// Abandon a test in progress, without a report; eg if we leave Preflight, or arm.
+    pub fn cancel(&mut self) {
+        if self.in_progress() {
+            println!("Self-test cancelled");
+            *self = Default::default();
+        }
+    }
+
+    /// Don't start baro or external sensor transfers while this is true.
+    pub fn transfers_paused(&self) -> bool {
+        matches!(self.stage, Stage::Quiesce(_) | Stage::Run)
+    }
+
+    /// The blocking checks are ready to run. If so, run `run` this update.
+    pub fn ready_to_run(&self) -> bool {
+        self.stage == Stage::Run
+    }
+
+    /// Run each IMU update, with gyro readings prior to filtering; rad/s.
+    pub fn update(&mut self, gyro: (f32, f32, f32)) {
+        match self.stage {
+            Stage::CollectGyro => {
+                self.gyro_count += 1;
+                let n = self.gyro_count as f32;
+
+                for (i, v) in [gyro.0, gyro.1, gyro.2].into_iter().enumerate() {
+                    let delta = v - self.gyro_mean[i];
+                    self.gyro_mean[i] += delta / n;
+                    self.gyro_m2[i] += delta * (v - self.gyro_mean[i]);
+                }
+
+                let rates = loop_rates::rates();
+                if self.gyro_count >= rates.imu_updates(GYRO_SAMPLE_TIME) {
+                    self.stage = Stage::Quiesce(rates.imu_updates(QUIESCE_TIME));
+                }
+            }
+            Stage::Quiesce(0) => self.stage = Stage::Run,
+            Stage::Quiesce(n) => self.stage = Stage::Quiesce(n - 1),
+            _ => (),
+        }
+    }
+
+    /// Run the blocking checks, store the report, and update system status with the results.
+    /// `mag_age` is seconds since the last magnetometer reading. `batt_v` and `current` are from
+    /// the ADC.
+    pub fn run(
+        &mut self,
+        spi: &mut SpiImu,
+        cs_imu: &mut Pin,
+        i2c_ext: &mut I2cMag,
+        i2c_baro: &mut I2cBaro,
+        altimeter: &baro::Altimeter,
+        rpms: &[Option<f32>],
+        batt_v: f32,
+        current: f32,
+        num_cells: f32,
+        mag_age: Option<f32>,
+        system_status: &mut SystemStatus,
+    ) {
+        delay_us(imu_shared::IMU_DMA_SETTLE_TIME, AHB_FREQ);
+
+        let imu = Check::from_id(
+            imu::read_device_id(spi, cs_imu).map(|id| id as u16),
+            imu::DEVICE_ID as u16,
+        );
+        let baro = Check::from_id(
+            baro::read_product_id(i2c_baro).map(|id| id as u16),
+            baro::PRODUCT_ID as u16,
+        );
+        let tof = Check::from_id(tof::read_model_id(i2c_ext), tof::MODEL_ID);
+
+        let gps = match gps::bytes_available(i2c_ext) {
+            Ok(bytes) => Check::new(SensorStatus::Pass, bytes as f32),
+            Err(_) => Check::new(SensorStatus::NotConnected, -1.),
+        };
+
+        let mag = match mag_age {
+            Some(age) if age <= system_status::MAX_UPDATE_PERIOD_MAG => {
+                Check::new(SensorStatus::Pass, age)
+            }
+            Some(age) => Check::new(SensorStatus::NotConnected, age),
+            None => Check::new(SensorStatus::NotConnected, -1.),
+        };
+
+        let baro_pressure = match altimeter.read_pressure_temp(i2c_baro) {
+            // This comparison also rejects NaN.
+            Ok((pressure, _)) if (BARO_PRESSURE_MIN..=BARO_PRESSURE_MAX).contains(&pressure) => {
+                Check::new(SensorStatus::Pass, pressure)
+            }
+            Ok((pressure, _)) => Check::new(SensorStatus::Fault, pressure),
+            Err(_) => Check::new(SensorStatus::NotConnected, 0.),
+        };
+
+        let mut noise = 0.;
+        if self.gyro_count > 1 {
+            for m2 in self.gyro_m2 {
+                noise = (m2 / (self.gyro_count - 1) as f32).sqrt().max(noise);
+            }
+        }
+        let gyro_noise = if noise <= MAX_GYRO_NOISE {
+            Check::new(SensorStatus::Pass, noise)
+        } else {
+            Check::new(SensorStatus::Fault, noise)
+        };
+
+        let rpm_max = rpms.iter().map(|r| r.unwrap_or(0.)).fold(0., f32::max);
+        let rpm = if rpm_max <= MAX_RPM_STOPPED {
+            Check::new(SensorStatus::Pass, rpm_max)
+        } else {
+            Check::new(SensorStatus::Fault, rpm_max)
+        };
+
+        let batt_v_ok = batt_v < NO_BATT_V
+            || (num_cells * CELL_V_MIN..=num_cells * CELL_V_MAX).contains(&batt_v);
+        let batt_adc = Check::new(
+            if batt_v_ok {
+                SensorStatus::Pass
+            } else {
+                SensorStatus::Fault
+            },
+            batt_v,
+        );
+
+        let current_adc = Check::new(
+            if current.abs() <= MAX_CURRENT_STOPPED {
+                SensorStatus::Pass
+            } else {
+                SensorStatus::Fault
+            },
+            current,
+        );
+
+        let report = SelfTestReport {
+            imu,
+            baro,
+            mag,
+            tof,
+            gps,
+            baro_pressure,
+            gyro_noise,
+            rpm,
+            batt_adc,
+            current_adc,
+        };
+
+        // Statuses of sensors that are streaming are refreshed as readings arrive; this reflects
+        // the test until then. We only downgrade the external sensors, since their `Pass` status
+        // depends on the readings themselves.
+        system_status.imu = if imu.status == SensorStatus::Pass {
+            gyro_noise.status
+        } else {
+            imu.status
+        };
+        system_status.baro = if baro.status == SensorStatus::Pass {
+            baro_pressure.status
+        } else {
+            baro.status
+        };
+        if tof.status != SensorStatus::Pass {
+            system_status.tof = tof.status;
+        }
+        if gps.status != SensorStatus::Pass {
+            system_status.gps = gps.status;
+        }
+        if rpm.status == SensorStatus::Fault {
+            system_status.esc_rpm = SensorStatus::Fault;
+        }
+        system_status.self_test_fault = report.fault();
+
+        self.report = Some(report);
+        self.stage = Stage::Idle;
+    }
+
+    pub fn to_bytes(&self) -> [u8; SELF_TEST_REPORT_SIZE] {
+        let mut result = [0; SELF_TEST_REPORT_SIZE];
+
+        // 0: Never run. 1: In progress. 2: Complete.
+        result[0] = if self.in_progress() {
+            1
+        } else if self.report.is_some() {
+            2
+        } else {
+            0
+        };
+
+        if let Some(report) = &self.report {
+            for (i, check) in report.checks().iter().enumerate() {
+                result[1 + i * CHECK_SIZE..1 + (i + 1) * CHECK_SIZE]
+                    .clone_from_slice(&check.to_bytes());
+            }
+        }
+
+        result
+    }
+}
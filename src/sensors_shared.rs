@@ -1,41 +1,68 @@
 //! This module contains code shared between sensors. Currently this is
 //! regarding DMA operations on the barometer and external sensors I2C lines.
 
-use hal::dma;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use defmt::println;
+use hal::{
+    delay_us, dma,
+    gpio::{Pin, PinMode},
+    pac::i2c1::RegisterBlock,
+};
 
 use crate::{
     baro,
-    setup::{self, I2cBaro, BARO_DMA_PERIPH, BARO_RX_CH, BARO_TX_CH},
+    board_config::{PortPinAlt, AHB_FREQ},
+    drivers::{gps_ublox as gps, mag_lis3mdl as mag, tof_vl53l1 as tof},
+    setup::{
+        self, DmaTransfer, I2cBaro, I2cMag, BARO_DMA_PERIPH, BARO_RX_CH, BARO_TX_CH,
+        EXT_SENSORS_DMA_PERIPH, EXT_SENSORS_RX_CH, EXT_SENSORS_TX_CH,
+    },
+    system_status::I2cDeviceHealth,
 };
 
 // Each of these values is register, value to write to register.
 // We sequence these using TC ISRs.
 pub static mut WRITE_BUF_BARO: [u8; 1] = [baro::Reg::PsrB2 as u8];
-// pub static mut WRITE_BUF_MAG: [u8; 1] = [mag::Reg::OutXL as u8];
-pub static mut WRITE_BUF_TOF: [u8; 2] = [0; 2];
+pub static mut WRITE_BUF_MAG: [u8; 1] = [mag::READ_START];
+pub static mut WRITE_BUF_TOF: [u8; 2] = tof::RESULT_REG;
+pub static mut WRITE_BUF_TOF_CLEAR: [u8; 3] = tof::CLEAR_INT_BUF;
+pub static mut WRITE_BUF_GPS: [u8; 1] = [gps::Reg::DataStream as u8];
 
 pub static mut READ_BUF_BARO: [u8; 6] = [0; 6]; // 3x pressure, 3x temperature.
-pub static mut READ_BUF_MAG: [u8; 6] = [0; 6]; // 2 mag for each dimension.
-pub static mut READ_BUF_TOF: [u8; 2] = [0; 2];
+pub static mut READ_BUF_MAG: [u8; mag::READ_BUF_SIZE] = [0; mag::READ_BUF_SIZE];
+pub static mut READ_BUF_TOF: [u8; tof::READ_BUF_SIZE] = [0; tof::READ_BUF_SIZE];
+pub static mut READ_BUF_GPS: [u8; gps::READ_BUF_SIZE] = [0; gps::READ_BUF_SIZE];
 
-pub static mut V_A_ADC_READ_BUF: [u16; 2] = [0; 2];
+// The state of the transfer sequence on each bus; one of the `SEQ_` values below. We set
+// `SEQ_IN_PROG` when starting a sequence, and `SEQ_COMPLETE` in the TC ISR that ends it. A sequence
+// still in progress when we go to start the next one has stalled, eg from a NACK, or a device
+// holding SDA low.
+pub static SEQ_BARO: AtomicU8 = AtomicU8::new(SEQ_IDLE);
+pub static SEQ_EXT: AtomicU8 = AtomicU8::new(SEQ_IDLE);
 
-// These values correspond to how much the voltage divider on these ADC pins reduces the input
-// voltage. Multiply by these values to get the true readings.
-// V batt / V read
-pub const ADC_BATT_V_DIV: f32 = 11.;
+const SEQ_IDLE: u8 = 0;
+const SEQ_IN_PROG: u8 = 1;
+pub const SEQ_COMPLETE: u8 = 2;
 
-// mA/V
-pub const ADC_CURR_DIV: f32 = 400.; // todo
+// Half of an SCL period when manually clocking the bus, in µs. ie 100kHz.
+const BUS_RESET_HALF_PERIOD: u32 = 5;
 
-pub const ADC_SAMPLE_FREQ: f32 = 50.; // todo: what should this be?
+/// Where a magnetometer reading came from: The LIS3MDL on our I2C bus, or a DroneCAN node.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MagSource {
+    I2c,
+    Can,
+}
 
 /// We use this to sequence DMA writes and reads among the extenral sensors.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum ExtSensor {
     Mag,
     Gps,
     Tof,
+    /// Clearing the TOF sensor's interrupt after a read, so it starts the next measurement.
+    TofIntClear,
 }
 
 /// Start continous transfers for all sensors controlled by this module.
@@ -48,17 +75,132 @@ pub fn start_transfer_baro(i2c_baro: &mut I2cBaro) {
         dma::stop(BARO_DMA_PERIPH, BARO_TX_CH);
         dma::stop(BARO_DMA_PERIPH, BARO_RX_CH);
 
+        SEQ_BARO.store(SEQ_IN_PROG, Ordering::Release);
+
         i2c_baro.write_dma(
             baro::ADDR,
             &WRITE_BUF_BARO,
             false,
             BARO_TX_CH,
-            Default::default(),
+            setup::dma_cfg(DmaTransfer::BaroTx),
             BARO_DMA_PERIPH,
         );
     }
 }
 
+/// Start a transfer with an external sensor on I2C1. For reads, this writes the register
+/// address; the read itself is started in the write TC ISR.
+pub fn start_transfer_ext(i2c_ext: &mut I2cMag, sensor: ExtSensor) {
+    unsafe {
+        dma::stop(EXT_SENSORS_DMA_PERIPH, EXT_SENSORS_TX_CH);
+        dma::stop(EXT_SENSORS_DMA_PERIPH, EXT_SENSORS_RX_CH);
+
+        let (addr, buf): (u8, &[u8]) = match sensor {
+            ExtSensor::Gps => (gps::ADDR, &WRITE_BUF_GPS),
+            ExtSensor::Tof => (tof::ADDR, &WRITE_BUF_TOF),
+            ExtSensor::TofIntClear => (tof::ADDR, &WRITE_BUF_TOF_CLEAR),
+            ExtSensor::Mag => (mag::ADDR, &WRITE_BUF_MAG),
+        };
+
+        // Only the interrupt clear isn't followed by a read. It continues the TOF sequence, vice
+        // starting a new one.
+        let autoend = sensor == ExtSensor::TofIntClear;
+        if !autoend {
+            SEQ_EXT.store(SEQ_IN_PROG, Ordering::Release);
+        }
+
+        i2c_ext.write_dma(
+            addr,
+            buf,
+            autoend,
+            EXT_SENSORS_TX_CH,
+            setup::dma_cfg(DmaTransfer::ExtSensorsTx),
+            EXT_SENSORS_DMA_PERIPH,
+        );
+    }
+}
+
+/// Run this prior to starting a transfer sequence on a bus. Checks the peripheral's error flags,
+/// and whether the previous sequence completed. Attributes the result to the device that sequence
+/// was with, and, on error, resets the bus. `seq` is `SEQ_BARO` or `SEQ_EXT`.
+pub fn check_i2c_bus(
+    regs: &RegisterBlock,
+    seq: &AtomicU8,
+    device: &mut I2cDeviceHealth,
+    timestamp: f32,
+    scl: PortPinAlt,
+    sda: PortPinAlt,
+) {
+    let isr = regs.isr.read();
+    let flags_set = isr.nackf().bit_is_set() || isr.berr().bit_is_set() || isr.arlo().bit_is_set();
+
+    if flags_set {
+        regs.icr.write(|w| {
+            w.nackcf()
+                .set_bit()
+                .berrcf()
+                .set_bit()
+                .arlocf()
+                .set_bit()
+                .stopcf()
+                .set_bit()
+        });
+    }
+
+    match seq.swap(SEQ_IDLE, Ordering::AcqRel) {
+        SEQ_IN_PROG => (),
+        SEQ_COMPLETE if !flags_set => {
+            device.record_success();
+            return;
+        }
+        // No sequence since the last check; eg the device is backed off.
+        SEQ_IDLE if !flags_set => return,
+        _ => (),
+    }
+
+    device.record_error(timestamp);
+    reset_i2c_bus(regs, scl, sda);
+}
+
+/// Recover a bus where a device may be holding SDA low, eg from a transfer interrupted mid-byte:
+/// Clock SCL manually until the device releases it, generate a stop condition, then re-enable the
+/// peripheral. This runs with the bus' DMA stopped; the peripheral's configuration is retained.
+pub fn reset_i2c_bus(regs: &RegisterBlock, scl: PortPinAlt, sda: PortPinAlt) {
+    regs.cr1.modify(|_, w| w.pe().clear_bit());
+
+    // The pins are already open-drain.
+    let mut scl_pin = Pin::new(scl.0, scl.1, PinMode::Output);
+    let mut sda_pin = Pin::new(sda.0, sda.1, PinMode::Output);
+
+    sda_pin.set_high();
+
+    // 9 clocks is enough for a device to finish any byte, and its ack bit.
+    for _ in 0..9 {
+        scl_pin.set_low();
+        delay_us(BUS_RESET_HALF_PERIOD, AHB_FREQ);
+        scl_pin.set_high();
+        delay_us(BUS_RESET_HALF_PERIOD, AHB_FREQ);
+    }
+
+    // Stop condition: SDA rising while SCL is high.
+    sda_pin.set_low();
+    delay_us(BUS_RESET_HALF_PERIOD, AHB_FREQ);
+    scl_pin.set_high();
+    delay_us(BUS_RESET_HALF_PERIOD, AHB_FREQ);
+    sda_pin.set_high();
+    delay_us(BUS_RESET_HALF_PERIOD, AHB_FREQ);
+
+    scl_pin.mode(PinMode::Alt(scl.2));
+    sda_pin.mode(PinMode::Alt(sda.2));
+
+    // Clearing PE resets the peripheral's state machine and flags; it must stay clear for at least
+    // 3 APB clock cycles.
+    delay_us(1, AHB_FREQ);
+    regs.cr1.modify(|_, w| w.pe().set_bit());
+
+    println!("I2C bus reset");
+}
+
 #[derive(Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum BattCellCount {
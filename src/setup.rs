@@ -13,22 +13,19 @@ use hal::timer::OutputCompare;
 use hal::{
     can::Can,
     clocks::Clocks,
-    dma::{self, DmaChannel, DmaInput, DmaInterrupt, DmaPeriph},
+    dma::{self, ChannelCfg, DmaChannel, DmaInput, DmaInterrupt, DmaPeriph, Priority},
     gpio::{Edge, OutputSpeed, OutputType, Pin, PinMode, Port, Pull},
     i2c::{I2c, I2cConfig, I2cSpeed},
     pac::{self, I2C1, I2C2, SPI1},
     spi::{BaudRate, Spi, SpiConfig, SpiMode},
-    timer::{BasicTimer, MasterModeSelection, TimChannel, Timer, TimerConfig, TimerInterrupt},
+    timer::{TimChannel, Timer, TimerConfig, TimerInterrupt},
     usart::{Usart, UsartConfig, UsartInterrupt},
 };
 
 use crate::board_config::*;
 
-cfg_if! {
-    if #[cfg(feature = "fixed-wing")] {
-        use crate::protocols::servo::ServoWing;
-    }
-}
+#[cfg(feature = "fixed-wing")]
+use crate::flight_ctrls::{control_mapping::ControlMapping, motor_servo::MotorServoHardware};
 use defmt::println;
 
 #[cfg(feature = "g4")]
@@ -38,16 +35,24 @@ use crate::{
     crsf,
     drivers::{
         baro_dps310 as baro,
-        flash_spi,
-        // tof_vl53l1 as tof,
-        imu_icm426xx as imu,
+        flash_spi::ExtFlash,
+        gps_ublox::{self as gps, GpsError, GpsNavRate},
+        imu_icm426xx::{self as imu, ImuCfg, ImuError},
+        mag_lis3mdl as mag, tof_vl53l1 as tof,
     },
+    loop_rates,
     protocols::{
         dshot::{self, Motor},
-        msp, servo,
+        esc_telemetry, msp, servo,
     },
-    safety, sensors_shared,
+    safety,
     system_status::{SensorStatus, SystemStatus},
+    util::monotonic,
+};
+#[cfg(feature = "h7")]
+use crate::{
+    drivers::imu_ism330dhcx as imu2,
+    imu_processing::{dual_imu::DualImuCfg, imu_shared},
 };
 
 // Keep all DMA channel number bindings in this code block, to make sure we don't use duplicates.
@@ -60,16 +65,20 @@ pub const BATT_CURR_DMA_PERIPH: DmaPeriph = DmaPeriph::Dma1;
 pub const BARO_DMA_PERIPH: DmaPeriph = DmaPeriph::Dma2;
 pub const OSD_DMA_PERIPH: DmaPeriph = DmaPeriph::Dma2;
 pub const EXT_SENSORS_DMA_PERIPH: DmaPeriph = DmaPeriph::Dma2;
+pub const LED_STRIP_DMA_PERIPH: DmaPeriph = DmaPeriph::Dma2;
+pub const IMU2_DMA_PERIPH: DmaPeriph = DmaPeriph::Dma2;
 
 // DMA 1
 pub const IMU_TX_CH: DmaChannel = DmaChannel::C1;
 pub const IMU_RX_CH: DmaChannel = DmaChannel::C2;
 
-// Note: DMA1, C4 is unused.
 pub const MOTOR_CH: DmaChannel = DmaChannel::C3;
 
+pub const ESC_TELEM_DMA_PERIPH: DmaPeriph = DmaPeriph::Dma1;
+pub const ESC_TELEM_RX_CH: DmaChannel = DmaChannel::C4;
+
 pub const CRSF_RX_CH: DmaChannel = DmaChannel::C5;
-// pub const CRSF_TX_CH: DmaChannel = DmaChannel::C6; // Note: Unused
+pub const CRSF_TX_CH: DmaChannel = DmaChannel::C6;
 
 pub const BATT_CURR_DMA_CH: DmaChannel = DmaChannel::C7;
 
@@ -80,18 +89,236 @@ pub const BARO_RX_CH: DmaChannel = DmaChannel::C2;
 pub const OSD_TX_CH: DmaChannel = DmaChannel::C3;
 // pub const OSD_RX_CH: DmaChannel = DmaChannel::C4;
 
+// Currently, just the GPS.
+pub const EXT_SENSORS_TX_CH: DmaChannel = DmaChannel::C5;
+pub const EXT_SENSORS_RX_CH: DmaChannel = DmaChannel::C6;
+
+// WS2812 LED strip, via TIM4 burst DMA.
+pub const LED_STRIP_CH: DmaChannel = DmaChannel::C7;
+
+// The secondary IMU. It's H7 only, but we assign channels on both, so DMA reports match.
+cfg_if! {
+    if #[cfg(feature = "h7")] {
+        pub const IMU2_TX_CH: DmaChannel = DmaChannel::C0;
+    } else {
+        pub const IMU2_TX_CH: DmaChannel = DmaChannel::C8;
+    }
+}
+pub const IMU2_RX_CH: DmaChannel = DmaChannel::C4;
+
 pub const MOTORS_DMA_INPUT: DmaInput = DmaInput::Tim3Up;
 
+/// Each DMA transfer we use. Indexes `dma_priorities`.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum DmaTransfer {
+    ImuTx = 0,
+    ImuRx = 1,
+    Motors = 2,
+    EscTelemRx = 3,
+    CrsfRx = 4,
+    CrsfTx = 5,
+    BattCurr = 6,
+    BaroTx = 7,
+    BaroRx = 8,
+    OsdTx = 9,
+    ExtSensorsTx = 10,
+    ExtSensorsRx = 11,
+    LedStrip = 12,
+    Imu2Tx = 13,
+    Imu2Rx = 14,
+}
+
+pub const NUM_DMA_TRANSFERS: usize = 15;
+
+/// A transfer's DMA controller, channel, request input, and priority.
+#[derive(Clone, Copy)]
+pub struct DmaAssignment {
+    pub transfer: DmaTransfer,
+    pub periph: DmaPeriph,
+    pub channel: DmaChannel,
+    pub input: DmaInput,
+    pub priority: Priority,
+}
+
+/// Channel assignment and priority for every DMA transfer, in `DmaTransfer` order. Channels are
+/// muxed from this in `setup_dma`, and each transfer starts with its priority from `dma_cfg`.
+///
+/// Priority only arbitrates between channels on the same controller, and only matters when
+/// requests are pending at once. We rank by how late a transfer can be serviced before something
+/// breaks:
+/// - Motors: A late burst stretches a DSHOT bit, and the ESC rejects the frame.
+/// - IMU: The readout must finish before the next sample; it drives the main loop.
+/// - CRSF and ESC telemetry RX: UART bytes arrive every few µs; a late one overruns.
+/// - I2C sensors: The bus stretches the clock while waiting, so we only lose time.
+/// - ADC: A short sequence each task cycle; a late one only ages the sample.
+/// - CRSF TX, OSD, and the LED strip: Nothing depends on when these finish.
+pub fn dma_priorities() -> [DmaAssignment; NUM_DMA_TRANSFERS] {
+    cfg_if! {
+        if #[cfg(feature = "h7")] {
+            let adc_dma_ip = DmaInput::Adc1;
+            let crsf_dma_ip = DmaInput::Uart7Rx;
+            let crsf_dma_tx_ip = DmaInput::Uart7Tx;
+            let osd_dma_ip = DmaInput::Usart2Tx;
+            // let osd_dma_rx_ip = DmaInput::Usart2Rx;
+            let esc_telem_dma_ip = DmaInput::Usart1Rx;
+            let imu2_dma_tx_ip = DmaInput::Spi4Tx;
+            let imu2_dma_rx_ip = DmaInput::Spi4Rx;
+        } else {
+            let crsf_dma_ip = DmaInput::Usart2Rx;
+            let crsf_dma_tx_ip = DmaInput::Usart2Tx;
+            let adc_dma_ip = DmaInput::Adc2;
+            let osd_dma_ip = DmaInput::Uart4Tx;
+            // let osd_dma_rx_ip = DmaInput::Uart4Rx;
+            let esc_telem_dma_ip = DmaInput::Usart3Rx;
+            // No secondary IMU; these are muxed, but never requested.
+            let imu2_dma_tx_ip = DmaInput::Spi3Tx;
+            let imu2_dma_rx_ip = DmaInput::Spi3Rx;
+        }
+    }
+
+    let a = |transfer, periph, channel, input, priority| DmaAssignment {
+        transfer,
+        periph,
+        channel,
+        input,
+        priority,
+    };
+
+    use DmaTransfer::*;
+
+    [
+        a(
+            ImuTx,
+            IMU_DMA_PERIPH,
+            IMU_TX_CH,
+            DmaInput::Spi1Tx,
+            Priority::High,
+        ),
+        a(
+            ImuRx,
+            IMU_DMA_PERIPH,
+            IMU_RX_CH,
+            DmaInput::Spi1Rx,
+            Priority::High,
+        ),
+        a(
+            Motors,
+            MOTORS_DMA_PERIPH,
+            MOTOR_CH,
+            MOTORS_DMA_INPUT,
+            Priority::VeryHigh,
+        ),
+        a(
+            EscTelemRx,
+            ESC_TELEM_DMA_PERIPH,
+            ESC_TELEM_RX_CH,
+            esc_telem_dma_ip,
+            Priority::Medium,
+        ),
+        a(
+            CrsfRx,
+            CRSF_DMA_PERIPH,
+            CRSF_RX_CH,
+            crsf_dma_ip,
+            Priority::Medium,
+        ),
+        a(
+            CrsfTx,
+            CRSF_DMA_PERIPH,
+            CRSF_TX_CH,
+            crsf_dma_tx_ip,
+            Priority::Low,
+        ),
+        a(
+            BattCurr,
+            BATT_CURR_DMA_PERIPH,
+            BATT_CURR_DMA_CH,
+            adc_dma_ip,
+            Priority::Low,
+        ),
+        a(
+            BaroTx,
+            BARO_DMA_PERIPH,
+            BARO_TX_CH,
+            DmaInput::I2c2Tx,
+            Priority::Medium,
+        ),
+        a(
+            BaroRx,
+            BARO_DMA_PERIPH,
+            BARO_RX_CH,
+            DmaInput::I2c2Rx,
+            Priority::Medium,
+        ),
+        a(OsdTx, OSD_DMA_PERIPH, OSD_TX_CH, osd_dma_ip, Priority::Low),
+        a(
+            ExtSensorsTx,
+            EXT_SENSORS_DMA_PERIPH,
+            EXT_SENSORS_TX_CH,
+            DmaInput::I2c1Tx,
+            Priority::Medium,
+        ),
+        a(
+            ExtSensorsRx,
+            EXT_SENSORS_DMA_PERIPH,
+            EXT_SENSORS_RX_CH,
+            DmaInput::I2c1Rx,
+            Priority::Medium,
+        ),
+        a(
+            LedStrip,
+            LED_STRIP_DMA_PERIPH,
+            LED_STRIP_CH,
+            DmaInput::Tim4Up,
+            Priority::Low,
+        ),
+        a(
+            Imu2Tx,
+            IMU2_DMA_PERIPH,
+            IMU2_TX_CH,
+            imu2_dma_tx_ip,
+            Priority::High,
+        ),
+        a(
+            Imu2Rx,
+            IMU2_DMA_PERIPH,
+            IMU2_RX_CH,
+            imu2_dma_rx_ip,
+            Priority::High,
+        ),
+    ]
+}
+
+/// The channel config to start a transfer with, at its priority from `dma_priorities`.
+pub fn dma_cfg(transfer: DmaTransfer) -> ChannelCfg {
+    ChannelCfg {
+        priority: dma_priorities()[transfer as usize].priority,
+        ..Default::default()
+    }
+}
+
 // Used for commanding timer DMA, for DSHOT protocol. Maps to CCR1, and is incremented
 // automatically when we set burst len = 4 in the DMA write and read.
 // Calculate by taking the Adddress Offset for the associated CCR channel in the
 // RM register table, and dividing by 4.
 pub const DSHOT_BASE_DIR_OFFSET: u8 = 0x34 / 4;
 
+// The LED strip uses a single TIM4 channel; we burst-write its CCR only.
 cfg_if! {
     if #[cfg(feature = "h7")] {
-        // todo: USB2 on H743; USB1 on H723.
-        // use hal::usb_otg::Usb1BusType as UsbBusType;
+        pub const LED_STRIP_TIM_CH: TimChannel = TimChannel::C1;
+        pub const LED_STRIP_BASE_DIR_OFFSET: u8 = 0x34 / 4; // CCR1
+    } else {
+        pub const LED_STRIP_TIM_CH: TimChannel = TimChannel::C2;
+        pub const LED_STRIP_BASE_DIR_OFFSET: u8 = 0x38 / 4; // CCR2
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "h723")] {
+        pub use hal::usb_otg::Usb1BusType as UsbBusType;
+    } else if #[cfg(feature = "h743")] {
         pub use hal::usb_otg::Usb2BusType as UsbBusType;
     } else {
         pub use hal::usb::UsbBusType;
@@ -104,9 +331,10 @@ pub type Can_ = FdCan<Can, NormalOperationMode>;
 // Define types for peripheral buses here; call these types from driver modules.
 pub type MotorTimer = Timer<pac::TIM3>;
 pub type ServoTimer = Timer<pac::TIM8>; // Valid for H7 on all channels. Valid for G4 on Ch 1, 3, 4.
+pub type CameraTimer = Timer<pac::TIM15>; // Camera tilt servo, on CH1.
 pub type SpiImu = Spi<SPI1>;
 pub type I2cBaro = I2c<I2C2>;
-pub type I2cMag = I2c<I2C1>; // Currently unused.
+pub type I2cMag = I2c<I2C1>; // Shared by the GPS, mag, and TOF.
 pub type SpiPacFlash = pac::SPI2;
 
 cfg_if! {
@@ -116,12 +344,18 @@ cfg_if! {
         pub type SpiFlash = Spi<SpiPacFlash>;
         pub type UartCrsf = Usart<pac::UART7>;
         pub type UartOsd = Usart<pac::USART2>;
+        // ESC telemetry can use any spare UART; change it here, and its pin in `board_config`.
+        pub type UartEscTelemRegs = pac::USART1;
+        pub type UartEscTelem = Usart<pac::USART1>;
+        pub type SpiImu2 = Spi<pac::SPI4>;
     } else {
         pub type UartCrsfRegs = pac::USART2;
         type UartOsdRegs = pac::UART4;
         pub type SpiFlash = Spi2<SpiPacFlash>;
         pub type UartCrsf = Usart<pac::USART2>;
         pub type UartOsd = Usart4<pac::UART4>;
+        pub type UartEscTelemRegs = pac::USART3;
+        pub type UartEscTelem = Usart<pac::USART3>;
     }
 }
 
@@ -133,26 +367,32 @@ pub fn init_sensors(
     params: &mut Params,
     base_pt: &mut PositVelEarthUnits,
     spi1: &mut Spi<SPI1>,
-    spi_flash: &mut SpiFlash,
+    flash_ext: &mut ExtFlash,
     i2c_mag: &mut I2cMag,
     i2c_baro: &mut I2cBaro,
     cs_imu: &mut Pin,
-    cs_flash: &mut Pin,
+    imu_cfg: &ImuCfg,
+    gps_nav_rate: GpsNavRate,
     clock_cfg: &Clocks,
 ) -> (SystemStatus, baro::Altimeter) {
     let mut system_status = SystemStatus::default();
 
-    match imu::setup(spi1, cs_imu) {
+    match imu::setup(spi1, cs_imu, loop_rates::rates().imu_odr, imu_cfg) {
         Ok(_) => system_status.imu = SensorStatus::Pass,
+        // The IMU responds, but may not be filtering or scaling readings as configured.
+        Err(ImuError::ConfigMismatch) => {
+            system_status.imu = SensorStatus::Pass;
+            system_status.imu_cfg_mismatch = true;
+        }
         Err(_) => system_status.imu = SensorStatus::NotConnected,
     };
 
-    // match mag::setup(i2c1) {
-    //     Ok(_) => system_status.magnetometer = SensorStatus::Pass,
-    //     Err(_) => system_status.magnetometer = SensorStatus::NotConnected,
-    // }
+    match mag::setup(i2c_mag) {
+        Ok(_) => system_status.magnetometer = SensorStatus::Pass,
+        Err(_) => system_status.magnetometer = SensorStatus::NotConnected,
+    }
 
-    match flash_spi::setup(spi_flash, cs_flash) {
+    match flash_ext.setup() {
         Ok(_) => system_status.flash_spi = SensorStatus::Pass,
         Err(_) => system_status.flash_spi = SensorStatus::NotConnected,
     }
@@ -168,6 +408,21 @@ pub fn init_sensors(
         }
     };
 
+    // `gps` is set to `Pass` once we receive a usable fix.
+    match gps::setup(i2c_mag, gps_nav_rate) {
+        Ok(_) => (),
+        Err(GpsError::NotConnected) => system_status.gps = SensorStatus::NotConnected,
+        Err(GpsError::ConfigFailed) => {
+            system_status.gps = SensorStatus::Fault;
+            system_status.gps_config_failed = true;
+        }
+    }
+
+    match tof::setup(i2c_mag) {
+        Ok(_) => system_status.tof = SensorStatus::Pass,
+        Err(_) => system_status.tof = SensorStatus::NotConnected,
+    }
+
     // let fix = gps::get_fix(uart1);
     // match fix {
     //     Ok(f) => {
@@ -205,12 +460,49 @@ impl Motor {
     }
 }
 
+/// The servo timer channel on an output pin, if it has one.
 #[cfg(feature = "fixed-wing")]
-impl ServoWing {
-    pub fn tim_channel(&self) -> TimChannel {
-        match self {
-            Self::S1 => TimChannel::C3,
-            Self::S2 => TimChannel::C4,
+pub fn servo_channel(output: MotorServoHardware) -> Option<TimChannel> {
+    match output {
+        MotorServoHardware::Pin1 => Some(TimChannel::C1),
+        #[cfg(feature = "h7")]
+        MotorServoHardware::Pin2 => Some(TimChannel::C2),
+        MotorServoHardware::Pin3 => Some(TimChannel::C3),
+        MotorServoHardware::Pin4 => Some(TimChannel::C4),
+        _ => None,
+    }
+}
+
+/// Route each motor output pin to the motor or servo timer, per the control mapping. Run at init,
+/// and when the mapping changes.
+#[cfg(feature = "fixed-wing")]
+pub fn set_output_pins(mapping: &ControlMapping) {
+    let alt_motors = 2; // TIM3
+
+    cfg_if! {
+        if #[cfg(feature = "h7")] {
+            let alt_servos = 3; // TIM8
+            let pins = [(Port::C, 6), (Port::C, 7), (Port::C, 8), (Port::C, 9)];
+        } else {
+            let alt_servos = 4; // TIM8
+            let pins = [(Port::C, 6), (Port::A, 4), (Port::B, 0), (Port::B, 1)];
+        }
+    }
+
+    for (i, (port, pin)) in pins.into_iter().enumerate() {
+        let output = i as u8 + 1;
+
+        if mapping
+            .surfaces
+            .iter()
+            .flatten()
+            .any(|s| *s as u8 == output)
+        {
+            let mut pin = Pin::new(port, pin, PinMode::Alt(alt_servos));
+            // Pull up, so a pulse isn't shortened on an MCU reset or similar condition.
+            pin.pull(Pull::Up);
+        } else {
+            Pin::new(port, pin, PinMode::Alt(alt_motors));
         }
     }
 }
@@ -219,7 +511,7 @@ impl ServoWing {
 pub fn setup_pins() {
     // Rotors connected to Tim3 CH1-4, or Tim8 (ch 1-4 on H7)
 
-    // todo: For configuring H7 fixed wing with a third servo and 1 motor, you need
+    // On fixed-wing, `set_output_pins` re-routes these per the control mapping, once it's loaded.
 
     // FOr the breakdown of these timers by MCU and aircraft type, see the `MotorTimers` struct.
 
@@ -232,7 +524,6 @@ pub fn setup_pins() {
             // On H7, we TIM3 and TIM8 have full overlap as ch 1-4 for our timer pins.
             let alt_servos = 3; // TIM8 (Avail on all channels)
 
-            // todo: Let us customize; set motor2 as `alt_servos` if equipped with a rudder etc.
             let mut motor2 = Pin::new(Port::C, 7, PinMode::Alt(alt_motors)); // Ch2
             let mut motor3 = Pin::new(Port::C, 8, PinMode::Alt(alt_servos)); // Ch3
             let mut motor4 = Pin::new(Port::C, 9, PinMode::Alt(alt_servos)); // Ch4
@@ -340,6 +631,27 @@ pub fn setup_pins() {
     let mut uart_osd_rx = Pin::new(PIN_OSD_RX.0, PIN_OSD_RX.1, PinMode::Alt(PIN_OSD_RX.2));
     uart_osd_rx.pull(Pull::Up);
 
+    // WS2812 data; TIM4 PWM. See the `led_strip` module.
+    let _led_strip = Pin::new(
+        PIN_LED_STRIP.0,
+        PIN_LED_STRIP.1,
+        PinMode::Alt(PIN_LED_STRIP.2),
+    );
+
+    // Camera tilt servo; TIM15 PWM. See the `camera_tilt` module.
+    let _camera_tilt = Pin::new(
+        PIN_CAMERA_TILT.0,
+        PIN_CAMERA_TILT.1,
+        PinMode::Alt(PIN_CAMERA_TILT.2),
+    );
+
+    let mut uart_esc_telem_rx = Pin::new(
+        PIN_ESC_TELEM_RX.0,
+        PIN_ESC_TELEM_RX.1,
+        PinMode::Alt(PIN_ESC_TELEM_RX.2),
+    );
+    uart_esc_telem_rx.pull(Pull::Up);
+
     // We use UARTs for misc external devices, including ESC telemetry,
     // and VTX OSD.
 
@@ -356,25 +668,13 @@ pub fn setup_pins() {
     let imu_exti_edge = Edge::Falling;
     imu_exti_pin.enable_interrupt(imu_exti_edge);
 
-    let i2c_alt = PinMode::Alt(4);
+    // I2C1 for external sensors, via pads.
+    let mut scl1 = Pin::new(PIN_I2C1_SCL.0, PIN_I2C1_SCL.1, PinMode::Alt(PIN_I2C1_SCL.2));
+    let mut sda1 = Pin::new(PIN_I2C1_SDA.0, PIN_I2C1_SDA.1, PinMode::Alt(PIN_I2C1_SDA.2));
 
-    cfg_if! {
-        if #[cfg(feature = "h7")] {
-            // I2C1 for external sensors, via pads
-            let mut scl1 = Pin::new(Port::B, 8, i2c_alt);
-            let mut sda1 = Pin::new(Port::B, 9, i2c_alt);
-
-            // I2C2 for the DPS310 barometer, and pads.
-            let mut scl2 = Pin::new(Port::B, 10, i2c_alt);
-            let mut sda2 = Pin::new(Port::B, 11, i2c_alt);
-        } else {
-            let mut scl1 = Pin::new(Port::A, 15, i2c_alt);
-            let mut sda1 = Pin::new(Port::B, 9, i2c_alt);
-
-            let mut scl2 = Pin::new(Port::A, 9, i2c_alt);
-            let mut sda2 = Pin::new(Port::A, 8, i2c_alt);
-        }
-    }
+    // I2C2 for the DPS310 barometer, and pads.
+    let mut scl2 = Pin::new(PIN_I2C2_SCL.0, PIN_I2C2_SCL.1, PinMode::Alt(PIN_I2C2_SCL.2));
+    let mut sda2 = Pin::new(PIN_I2C2_SDA.0, PIN_I2C2_SDA.1, PinMode::Alt(PIN_I2C2_SDA.2));
 
     scl2.pull(Pull::Up);
     sda2.pull(Pull::Up);
@@ -402,38 +702,10 @@ pub fn setup_dma() {
     #[cfg(feature = "g4")]
     dma::enable_mux1();
 
-    // IMU
-    dma::mux(IMU_DMA_PERIPH, IMU_TX_CH, DmaInput::Spi1Tx);
-    dma::mux(IMU_DMA_PERIPH, IMU_RX_CH, DmaInput::Spi1Rx);
-
-    // DSHOT, all 4 motors.
-    dma::mux(MOTORS_DMA_PERIPH, MOTOR_CH, MOTORS_DMA_INPUT);
-
-    // todo: This matrix is perhaps better suited to go with the consts at the top of this module.
-    cfg_if! {
-        if #[cfg(feature = "h7")] {
-            let adc_dma_ip = DmaInput::Adc1;
-            let crsf_dma_ip = DmaInput::Uart7Rx;
-            let osd_dma_ip = DmaInput::Usart2Tx;
-            let osd_dma_rx_ip = DmaInput::Usart2Rx;
-        } else {
-            let crsf_dma_ip = DmaInput::Usart2Rx;
-            let adc_dma_ip = DmaInput::Adc2;
-            let osd_dma_ip = DmaInput::Uart4Tx;
-            let osd_dma_rx_ip = DmaInput::Uart4Rx;
-        }
+    for assignment in dma_priorities() {
+        dma::mux(assignment.periph, assignment.channel, assignment.input);
     }
 
-    dma::mux(CRSF_DMA_PERIPH, CRSF_RX_CH, crsf_dma_ip);
-    // CRSF TX is currently unused.
-    // dma::mux(CRSF_DMA_PERIPH, CRSF_TX_CH, DmaInput::Usart2Tx);
-    dma::mux(BATT_CURR_DMA_PERIPH, BATT_CURR_DMA_CH, adc_dma_ip);
-    dma::mux(OSD_DMA_PERIPH, OSD_TX_CH, osd_dma_ip);
-    // dma::mux(OSD_DMA_PERIPH, OSD_RX_CH, osd_dma_rx_ip);
-
-    dma::mux(BARO_DMA_PERIPH, BARO_TX_CH, DmaInput::I2c2Tx);
-    dma::mux(BARO_DMA_PERIPH, BARO_RX_CH, DmaInput::I2c2Rx);
-
     // We use Spi transfer complete to know when our readings are ready - in its ISR,
     // we trigger the attitude-rates PID loop.
     dma::enable_interrupt(IMU_DMA_PERIPH, IMU_RX_CH, DmaInterrupt::TransferComplete);
@@ -447,8 +719,61 @@ pub fn setup_dma() {
     dma::enable_interrupt(BARO_DMA_PERIPH, BARO_TX_CH, DmaInterrupt::TransferComplete);
     dma::enable_interrupt(BARO_DMA_PERIPH, BARO_RX_CH, DmaInterrupt::TransferComplete);
 
+    dma::enable_interrupt(
+        EXT_SENSORS_DMA_PERIPH,
+        EXT_SENSORS_TX_CH,
+        DmaInterrupt::TransferComplete,
+    );
+    dma::enable_interrupt(
+        EXT_SENSORS_DMA_PERIPH,
+        EXT_SENSORS_RX_CH,
+        DmaInterrupt::TransferComplete,
+    );
+
     dma::enable_interrupt(OSD_DMA_PERIPH, OSD_TX_CH, DmaInterrupt::TransferComplete);
     // dma::enable_interrupt(OSD_DMA_PERIPH, OSD_RX_CH, DmaInterrupt::TransferComplete);
+
+    // Telemetry frames are held until their transmission completes.
+    dma::enable_interrupt(CRSF_DMA_PERIPH, CRSF_TX_CH, DmaInterrupt::TransferComplete);
+
+    // The secondary IMU's readings are stored in its TC ISR.
+    #[cfg(feature = "h7")]
+    dma::enable_interrupt(IMU2_DMA_PERIPH, IMU2_RX_CH, DmaInterrupt::TransferComplete);
+}
+
+/// Set up the secondary IMU's bus, and the IMU itself, if enabled in config. If it responds, we
+/// start reading it on each primary IMU update. See `dual_imu`.
+#[cfg(feature = "h7")]
+pub fn setup_imu2(spi4_pac: pac::SPI4, cfg: &DualImuCfg, system_status: &mut SystemStatus) {
+    if !cfg.enabled {
+        return;
+    }
+
+    let spi_gpiospeed = OutputSpeed::Medium;
+    for (port, pin, alt) in [PIN_SCK_IMU2, PIN_MISO_IMU2, PIN_MOSI_IMU2] {
+        Pin::new(port, pin, PinMode::Alt(alt)).output_speed(spi_gpiospeed);
+    }
+
+    let mut cs = Pin::new(PIN_CS_IMU2.0, PIN_CS_IMU2.1, PinMode::Output);
+    cs.set_high();
+
+    let spi_cfg = SpiConfig {
+        mode: SpiMode::mode3(),
+        ..Default::default()
+    };
+    let mut spi = Spi::new(spi4_pac, spi_cfg, IMU2_BAUD_DIV);
+
+    system_status.imu_secondary = match imu2::setup(&mut spi, &mut cs) {
+        Ok(_) => SensorStatus::Pass,
+        Err(ImuError::ConfigMismatch) => SensorStatus::Fault,
+        Err(_) => SensorStatus::NotConnected,
+    };
+
+    if system_status.imu_secondary == SensorStatus::Pass {
+        imu_shared::init_imu2(spi);
+    } else {
+        println!("Secondary IMU setup failed");
+    }
 }
 
 /// Configure the SPI and I2C busses.
@@ -459,6 +784,7 @@ pub fn setup_busses(
     i2c2_pac: I2C2,
     uart_osd_pac: UartOsdRegs,
     uart_crsf_pac: UartCrsfRegs,
+    uart_esc_telem_pac: UartEscTelemRegs,
     clock_cfg: &Clocks,
 ) -> (
     Spi<SPI1>,
@@ -469,6 +795,7 @@ pub fn setup_busses(
     I2c<I2C2>,
     UartOsd,
     UartCrsf,
+    UartEscTelem,
 ) {
     // We use SPI1 for the IMU
     // SPI input clock is 400MHz for H7, and 170Mhz for G4. 400MHz / 32 = 12.5 MHz. 170Mhz / 8 = 21.25Mhz.
@@ -477,7 +804,7 @@ pub fn setup_busses(
 
     cfg_if! {
         if #[cfg(feature = "h7")] {
-            // Config of USB pins on H743. We don't need this on G4 or H723
+            // USB pins use AF10 on both H723 (OTG1) and H743 (OTG2). We don't need this on G4.
             let _usb_dm = Pin::new(Port::A, 11, PinMode::Alt(10));
             let _usb_dp = Pin::new(Port::A, 12, PinMode::Alt(10));
         }
@@ -600,8 +927,27 @@ pub fn setup_busses(
         clock_cfg,
     );
 
+    // We use this UART to receive telemetry from the ESCs, in the KISS / BLHeli_32 format.
+    let uart_esc_telem = Usart::new(
+        uart_esc_telem_pac,
+        esc_telemetry::BAUD,
+        UsartConfig {
+            overrun_disabled: true,
+            ..Default::default()
+        },
+        clock_cfg,
+    );
+
     (
-        spi_imu, spi_flash, cs_imu, cs_flash, i2c1, i2c2, uart_osd, uart_crsf,
+        spi_imu,
+        spi_flash,
+        cs_imu,
+        cs_flash,
+        i2c1,
+        i2c2,
+        uart_osd,
+        uart_crsf,
+        uart_esc_telem,
     )
 }
 
@@ -609,9 +955,9 @@ pub fn setup_busses(
 pub fn setup_timers(
     tim1_pac: pac::TIM1,
     tim5_pac: pac::TIM5,
-    tim6_pac: pac::TIM6,
+    tim17_pac: pac::TIM17,
     clock_cfg: &Clocks,
-) -> (Timer<pac::TIM1>, Timer<pac::TIM5>, BasicTimer<pac::TIM6>) {
+) -> (Timer<pac::TIM1>, Timer<pac::TIM5>, Timer<pac::TIM17>) {
     let ctrl_coeff_adj_timer = Timer::new_tim1(
         tim1_pac,
         1. / crate::CTRL_COEFF_ADJ_TIMEOUT,
@@ -633,27 +979,29 @@ pub fn setup_timers(
     // );
     // lost_link_timer.enable_interrupt(TimerInterrupt::Update);
 
-    // We use this timer to maintain a time since bootup.
-    // A shorter timeout period will allow higher resolution measurements, while a longer one
-    // will command an interrupt less often. (The interrupt only increments an atomic overflow counter).
+    // We use this timer to maintain a time since bootup; see `util::monotonic`.
+    // A longer timeout period will command an interrupt less often. (The interrupt only
+    // increments an atomic overflow counter.)
     let mut tick_timer = Timer::new_tim5(
         tim5_pac,
         1. / crate::TICK_TIMER_PERIOD,
         Default::default(),
         &clock_cfg,
     );
-    // todo: Maybe manually set ARR and PSC, since ARR is what controls precision?
+    monotonic::setup(&mut tick_timer);
     tick_timer.enable_interrupt(TimerInterrupt::Update);
 
-    // Set up a basic timer that will trigger our ADC reads, at a fixed rate.
-    // If you wish to sample at a fixed rate, consider using a basic timer (TIM6 or TIM7)
-    let mut adc_timer = BasicTimer::new(tim6_pac, sensors_shared::ADC_SAMPLE_FREQ, &clock_cfg);
-
-    // The update event is selected as a trigger output (TRGO). For instance a
-    // master timer can then be used as a prescaler for a slave timer.
-    adc_timer.set_mastermode(MasterModeSelection::Update);
+    // The sensor watchdog checks sensor data freshness on this timer, since the main loop
+    // is driven by IMU data, and stops if it does.
+    let mut watchdog_timer = Timer::new_tim17(
+        tim17_pac,
+        crate::SENSOR_WATCHDOG_FREQ,
+        Default::default(),
+        &clock_cfg,
+    );
+    watchdog_timer.enable_interrupt(TimerInterrupt::Update);
 
-    (ctrl_coeff_adj_timer, tick_timer, adc_timer)
+    (ctrl_coeff_adj_timer, tick_timer, watchdog_timer)
 }
 
 /// Configures all 4 motor timers for quadcopters, or combinations of motors and servos
@@ -669,36 +1017,28 @@ pub fn setup_motor_timers(motor_timer: &mut MotorTimer, servo_timer: &mut ServoT
             dshot::set_to_output(motor_timer);
             dshot::set_bidirectional(dshot::BIDIR_EN, motor_timer);
         } else {
-            servo_timer.set_prescaler(servo::PSC_SERVOS);
-            servo_timer.set_auto_reload(servo::ARR_SERVOS);
+            // Servo update frequency is set from user config, once it's loaded.
 
             // Arbitrary duty cycle set, since we'll override it with DMA bursts for the motor, and
             // position settings for the servos.
             motor_timer.enable_pwm_output(Motor::M1.tim_channel(), OutputCompare::Pwm1, 0.);
-            servo_timer.enable_pwm_output(ServoWing::S1.tim_channel(), OutputCompare::Pwm1, 0.);
-            servo_timer.enable_pwm_output(ServoWing::S2.tim_channel(), OutputCompare::Pwm1, 0.);
+
+            // Every servo channel; which pins use them depends on the control mapping. See
+            // `set_output_pins`.
+            for output in [
+                MotorServoHardware::Pin1,
+                MotorServoHardware::Pin2,
+                MotorServoHardware::Pin3,
+                MotorServoHardware::Pin4,
+            ] {
+                if let Some(channel) = servo_channel(output) {
+                    servo_timer.enable_pwm_output(channel, OutputCompare::Pwm1, 0.);
+                }
+            }
 
             // PAC, since our HAL currently only sets this on `new`.
             servo_timer.regs.cr1.modify(|_, w| w.opm().set_bit()); // todo: Does this work?
 
-            // Set servo pins to pull-up, to make sure they don't shorten a pulse on a MCU reset
-            // or similar condition.
-            // todo: #1: Don't hard-code these pins. #2: Consider if this is helping and/or sufficient.
-            #[cfg(feature = "h7")]
-            unsafe {
-                (*pac::GPIOC::ptr()).pupdr.modify(|_, w| {
-                    w.pupdr8().bits(0b01);
-                    w.pupdr9().bits(0b01)
-                });
-            }
-            #[cfg(feature = "g4")]
-            unsafe {
-                (*pac::GPIOB::ptr()).pupdr.modify(|_, w| {
-                    w.pupdr0().bits(0b01);
-                    w.pupdr1().bits(0b01)
-                });
-            }
-
             // Motor timer is enabled in Timer burst DMA. We enable the servo timer here.
             servo_timer.enable();
         }
@@ -1,41 +1,126 @@
 //! This module contains code related to state, both config stored to flash, and volatile data
 //! specific to the current flight, and cleared when power is removed.
-use ahrs::ppks::PositVelEarthUnits;
-use cfg_if::cfg_if;
+use core::f32::consts::TAU;
+
+use ahrs::{ppks::PositVelEarthUnits, Params};
 use hal::flash::{Bank, Flash};
-use lin_alg::f32::Quaternion;
+use lin_alg::f32::{Quaternion, Vec3};
 
-cfg_if! {
-    if #[cfg(feature = "fixed-wing")] {
-        use lin_alg::f32::Vec3;
-    } else {
-        use crate::flight_ctrls::InputMode;
-    }
-}
+#[cfg(feature = "quad")]
+use crate::flight_ctrls::{
+    arm_check::ArmCheck,
+    follow_me::{FollowMe, FollowStatus},
+    motor_failure::MotorFailureDetect,
+    set_input_mode,
+    tipover::TipoverDetect,
+    turtle::TurtleMode,
+    InputMode, RotorPosition,
+};
 
 use defmt::println;
 
 use crate::flight_ctrls::pid::PidStateRate;
-#[cfg(feature = "fixed-wing")]
-use crate::flight_ctrls::{ControlSurfaceConfig, YawControl};
 use crate::{
-    controller_interface::InputModeSwitch,
+    adc_cal::{AdcCalCfg, AdcReadings, ADC_CAL_CFG_SIZE},
+    alt_estimator::AltEstimator,
+    batt_failsafe::{BattFailsafe, BattFailsafeCfg, BattStage, BATT_FAILSAFE_CFG_SIZE},
+    blackbox::{self, Blackbox},
+    brownout::{BrownoutCfg, BrownoutDetect, BROWNOUT_CFG_SIZE},
+    camera_tilt::{CameraTiltCfg, CAMERA_TILT_CFG_SIZE},
+    controller_interface::{
+        ChannelData, ChannelMap, InputModeSwitch, RxProtocol, CHANNEL_MAP_SIZE,
+    },
+    debug_log::LogChannels,
+    dma_stats::DmaStats,
+    drivers::{
+        gps_ublox::GpsNavRate,
+        imu_icm426xx::{ImuCfg, IMU_CFG_SIZE},
+    },
+    esc_telemetry::{BattMeasSource, EscTelemetryState},
+    event_log::{self, EventCode},
+    ext_ctrl::ExtCtrl,
     flight_ctrls::{
-        autopilot::LandingCfg,
-        common::{AttitudeCommanded, CtrlInputs, CtrlMix, InputMap},
+        acro_trainer::{AcroTrainer, AcroTrainerCfg, ACRO_TRAINER_CFG_SIZE},
+        airspeed::{AirspeedCfg, AirspeedEst, AIRSPEED_CFG_SIZE},
+        arm_check::{self, ArmCheckCfg, ARM_CHECK_CFG_SIZE},
+        authority::{self, Authority, AuthorityCfg, AUTHORITY_CFG_SIZE},
+        auto_launch::{self, AutoLaunchCfg, AUTO_LAUNCH_CFG_SIZE},
+        autopilot::{AutopilotStatus, LandingCfg},
+        cmd_updates::{self, AngleOnCenterCfg, ANGLE_ON_CENTER_CFG_SIZE},
+        common::{
+            ArmLatch, AttitudeCommanded, CtrlInputs, CtrlMix, InputMap, RpmLpf, RpmLpfCfg,
+            RPM_LPF_CFG_SIZE,
+        },
+        control_mapping::{ControlMapping, CONTROL_MAPPING_SIZE},
         ctrl_effect_est::AccelMaps,
         ctrl_logic::{CtrlCoeffs, DragCoeffs},
-        motor_servo::MotorServoState,
-        pid::PidCoeffs,
+        dyn_idle::{self, DynIdle, DynIdleCfg, DYN_IDLE_CFG_SIZE},
+        follow_me::{FollowCfg, FOLLOW_CFG_SIZE},
+        hover_est::{self, HoverThrottleEst},
+        input_cal::{InputCal, InputCalCollector, INPUT_CAL_SIZE},
+        mixer::{self, DesatCfg, Mixer, DESAT_CFG_SIZE, MIXER_SIZE},
+        motor_failure::{MotorFailCfg, MOTOR_FAIL_CFG_SIZE},
+        motor_servo::{MotorServoState, OutputSmoothingCfg, OUTPUT_SMOOTHING_CFG_SIZE},
+        motor_test::MotorTest,
+        pid::{MotorPidGroup, PidCoeffs, RpmCtrlCfg, RPM_CTRL_CFG_SIZE},
+        profiles::{self, CtrlProfile, ProfileSwitch, NUM_PROFILES, PROFILE_SIZE},
+        rates::{self, RATES_SIZE},
+        stall_protect::{StallProtect, StallProtectCfg, STALL_PROTECT_CFG_SIZE},
+        stick_cmds::StickCmds,
+        throttle_limit::{self, ThrottleLimitCfg, THROTTLE_LIMIT_CFG_SIZE},
+        thrust_comp::{ThrustComp, ThrustCompCfg, THRUST_COMP_CFG_SIZE},
+        tipover::{TipoverCfg, TIPOVER_CFG_SIZE},
+        wind_est::WindEst,
+        CtrlScheme,
+    },
+    flight_stats::FlightStatsState,
+    gps_metrics::{GpsMetrics, SpeedUnits},
+    ground_ref::GroundRef,
+    health_trend::HealthTrend,
+    imu_processing::{
+        accel_health::{AccelHealth, AccelHealthCfg, FusionMode, ACCEL_HEALTH_CFG_SIZE},
+        dual_imu::{self, DualImu, DualImuCfg, DUAL_IMU_CFG_SIZE},
+        filter_imu::{GyroLpfCfg, LpfStageCfg, LpfType, RpmFilterCfg},
+        fusion::{self, AttConvergence, FusionCfg, FusionMonitor, FUSION_CFG_SIZE},
+        gyro_temp_comp::{GyroTempCal, GyroTempComp},
+        imu_integrity::ImuIntegrity,
+        mag_cal::{MagCal, MagCalCollector},
     },
-    safety::ArmStatus,
+    indicators::{IndicatorCfg, INDICATOR_CFG_SIZE},
+    led_strip::{LedStripCfg, LED_STRIP_CFG_SIZE},
+    link_failsafe::{self, LinkFailsafe, LinkFailsafeCfg, LinkStage, LINK_FAILSAFE_CFG_SIZE},
+    loop_rates::{self, ImuOdr},
+    lost_craft::LostCraft,
+    motor_kill,
+    motor_wizard::MotorWizard,
+    output_pattern::OutputPattern,
+    params::param_table,
+    perf_stats::PerfStats,
+    protocols::{
+        servo::{ServoCfg, SERVO_CFG_SIZE},
+        usb_telem::TelemStream,
+    },
+    rth::{self, Rth, RthCfg, RTH_CFG_SIZE},
+    safety::{self, ArmCfg, ArmStatus, ImuFailPolicy, PrearmStatus, ARM_CFG_SIZE},
+    self_test::SelfTest,
     sensors_shared::BattCellCount,
+    storage::{self, StorageBackend},
+    system_status::{SensorStatus, SystemStatus},
     usb_preflight::CONFIG_SIZE,
+    vib_test::VibTest,
+    watchdog::Watchdog,
+};
+#[cfg(feature = "fixed-wing")]
+use crate::{
+    flight_ctrls::{auto_launch::AutoLaunch, ControlSurfaceConfig, YawControl},
+    safety::LaunchDetect,
 };
 
 // The maximum number of waypoints available.
 pub const MAX_WAYPOINTS: usize = 30; // todo: Consider raising this.
 
+const IMU_FAIL_DESCEND_PWR_DEFAULT: f32 = 0.25;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum OperationMode {
     /// Eg flying
@@ -50,6 +135,543 @@ impl Default for OperationMode {
     }
 }
 
+/// The flight mode, as reported to the pilot over CRSF, the OSD, and USB. Composed from the
+/// operation mode, link-lost recovery, autopilot modes, and input mode by `flight_mode_label`.
+/// Values are stable; they're sent in USB telemetry.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum FlightMode {
+    Preflight = 0,
+    /// Link-lost recovery, without a position to return to.
+    Failsafe = 1,
+    /// Link-lost recovery, returning to the base point.
+    Rth = 2,
+    Land = 3,
+    Takeoff = 4,
+    DirectToPoint = 5,
+    /// Loiter on quads; orbit on fixed-wing.
+    Loiter = 6,
+    AltHold = 7,
+    Route = 8,
+    Attitude = 9,
+    AcroHybrid = 10,
+    Acro = 11,
+    /// Fixed-wing, with no autopilot mode active.
+    Manual = 12,
+    /// A motor failed; degraded control, and descending. Quad only.
+    MotorFail = 13,
+    /// Following a target from the ground station. Quad only.
+    Follow = 14,
+}
+
+// The longest label, for fixed-width display.
+pub const FLIGHT_MODE_LABEL_LEN: usize = 9;
+
+impl FlightMode {
+    /// A short, upper-case description. At most `FLIGHT_MODE_LABEL_LEN` characters.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Preflight => "PREFLIGHT",
+            Self::Failsafe => "FAILSAFE",
+            Self::Rth => "RTH",
+            Self::Land => "LAND",
+            Self::Takeoff => "TAKEOFF",
+            Self::DirectToPoint => "DIRECT",
+            #[cfg(feature = "quad")]
+            Self::Loiter => "LOITER",
+            #[cfg(feature = "fixed-wing")]
+            Self::Loiter => "ORBIT",
+            Self::AltHold => "ALT HOLD",
+            Self::Route => "ROUTE",
+            Self::Attitude => "ANGLE",
+            Self::AcroHybrid => "ACRO HYB",
+            Self::Acro => "ACRO",
+            Self::Manual => "MANUAL",
+            Self::MotorFail => "MTR FAIL",
+            Self::Follow => "FOLLOW",
+        }
+    }
+}
+
+/// The flight mode in effect, and its label. In order of precedence: Preflight, motor failure,
+/// link-lost recovery, landing and takeoff, follow-me, point and altitude holds, then the pilot's
+/// input mode. This
+/// reads only state set by `update_flight_modes`, and the operation mode, so it agrees with what
+/// the flight controls are doing.
+pub fn flight_mode_label(
+    state: &StateVolatile,
+    autopilot_status: &AutopilotStatus,
+) -> (FlightMode, &'static str) {
+    let mode = if state.op_mode == OperationMode::Preflight {
+        FlightMode::Preflight
+    } else if motor_failed(state) {
+        FlightMode::MotorFail
+    } else if state.link_lost_recovery {
+        if autopilot_status.direct_to_point.is_some() {
+            FlightMode::Rth
+        } else {
+            FlightMode::Failsafe
+        }
+    } else if autopilot_status.land.is_some() {
+        FlightMode::Land
+    } else if autopilot_status.takeoff {
+        FlightMode::Takeoff
+    } else if let Some(mode) = follow_flight_mode(state) {
+        mode
+    } else if autopilot_status.direct_to_point.is_some() {
+        FlightMode::DirectToPoint
+    } else if hold_pt_active(autopilot_status) {
+        FlightMode::Loiter
+    } else if autopilot_status.alt_hold.is_some() {
+        FlightMode::AltHold
+    } else {
+        input_flight_mode(state)
+    };
+
+    (mode, mode.label())
+}
+
+#[cfg(feature = "quad")]
+fn motor_failed(state: &StateVolatile) -> bool {
+    state.motor_failed.is_some()
+}
+
+#[cfg(feature = "fixed-wing")]
+fn motor_failed(_state: &StateVolatile) -> bool {
+    false
+}
+
+/// While follow-me is enabled, what it's doing: Following, holding for a stale target, or
+/// returning.
+#[cfg(feature = "quad")]
+fn follow_flight_mode(state: &StateVolatile) -> Option<FlightMode> {
+    match state.follow_me.status {
+        FollowStatus::Inactive => None,
+        FollowStatus::Following => Some(FlightMode::Follow),
+        FollowStatus::Holding => Some(FlightMode::Loiter),
+        FollowStatus::Returning => Some(FlightMode::Rth),
+    }
+}
+
+#[cfg(feature = "fixed-wing")]
+fn follow_flight_mode(_state: &StateVolatile) -> Option<FlightMode> {
+    None
+}
+
+#[cfg(feature = "quad")]
+fn hold_pt_active(autopilot_status: &AutopilotStatus) -> bool {
+    autopilot_status.loiter.is_some()
+}
+
+#[cfg(feature = "fixed-wing")]
+fn hold_pt_active(autopilot_status: &AutopilotStatus) -> bool {
+    autopilot_status.orbit.is_some()
+}
+
+#[cfg(feature = "quad")]
+fn input_flight_mode(state: &StateVolatile) -> FlightMode {
+    match state.input_mode {
+        InputMode::Acro => FlightMode::Acro,
+        InputMode::AcroHybrid => FlightMode::AcroHybrid,
+        InputMode::Attitude => FlightMode::Attitude,
+        InputMode::Loiter => FlightMode::Loiter,
+        InputMode::Route => FlightMode::Route,
+    }
+}
+
+#[cfg(feature = "fixed-wing")]
+fn input_flight_mode(_state: &StateVolatile) -> FlightMode {
+    FlightMode::Manual
+}
+
+/// How urgent a warning is. The ticker shows only the most severe active warnings. Repr is how
+/// it's passed over USB.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Severity {
+    Info = 0,
+    Caution = 1,
+    /// Latched on the ticker after its condition clears, until the next arm, or cleared over USB.
+    Critical = 2,
+}
+
+// The longest warning label; the OSD pads to this.
+pub const WARNING_LABEL_LEN: usize = 10;
+
+// Seconds each warning is shown, while the ticker rotates through several.
+const TICKER_PERIOD: f32 = 2.;
+
+// Below this link quality, in percent as CRSF reports it, we warn.
+const LINK_QUALITY_WARN: u8 = 50;
+
+/// Define `Warning`, with its labels and severities. Each entry is `Variant = id, "LABEL",
+/// Severity;`. IDs are bits in `Warnings` flags, and are passed over USB; they're stable, and
+/// must be below 32. Labels are at most `WARNING_LABEL_LEN` bytes; checked at compile time.
+macro_rules! warning_table {
+    ($($variant:ident = $id:literal, $label:literal, $severity:ident;)*) => {
+        /// A condition shown on the OSD warnings ticker, and reported over USB. To add one, add a
+        /// line to the table, and set it in `update_warnings`.
+        #[derive(Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        pub enum Warning {
+            $($variant = $id,)*
+        }
+
+        impl Warning {
+            /// In ticker order.
+            pub const ALL: [Self; [$($id),*].len()] = [$(Self::$variant),*];
+
+            /// Displayed on the OSD, and in the PC application.
+            pub fn label(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $label,)*
+                }
+            }
+
+            pub fn severity(self) -> Severity {
+                match self {
+                    $(Self::$variant => Severity::$severity,)*
+                }
+            }
+
+            fn bit(self) -> u32 {
+                1 << self as u8
+            }
+        }
+
+        const _: () = {
+            $(
+                assert!($id < 32, "Warning IDs must be below 32");
+                assert!($label.len() <= WARNING_LABEL_LEN, "Warning label too long");
+            )*
+        };
+    };
+}
+
+warning_table! {
+    BattLow = 0, "BATT LOW", Caution;
+    BattLimit = 1, "BATT LIMIT", Caution;
+    BattCrit = 2, "BATT CRIT", Critical;
+    MotorFail = 3, "MOTOR FAIL", Critical;
+    EscDesync = 4, "ESC DESYNC", Critical;
+    EscHot = 5, "ESC HOT", Caution;
+    ImuFail = 6, "IMU FAIL", Critical;
+    GyroOnly = 7, "GYRO ONLY", Caution;
+    BaroFail = 8, "BARO FAIL", Caution;
+    GpsFail = 9, "GPS FAIL", Caution;
+    LinkLost = 10, "LINK LOST", Critical;
+    LinkLow = 11, "LQ LOW", Caution;
+    Tipover = 12, "TIPOVER", Critical;
+    Stall = 13, "STALL", Caution;
+    Trainer = 14, "TRAINER", Info;
+    Prearm = 15, "PREARM", Info;
+    LaunchReady = 16, "LAUNCH RDY", Info;
+    LaunchAbort = 17, "LAUNCH ABT", Caution;
+    Ceiling = 18, "CEILING", Caution;
+    LinkHold = 19, "LINK HOLD", Caution;
+    AttAlign = 20, "ATT ALIGN", Info;
+    AttDiverged = 21, "ATT DIVERG", Critical;
+    ExtCtrl = 22, "EXT CTRL", Info;
+    ImuSwitch = 23, "IMU SWITCH", Caution;
+    Killed = 24, "KILLED", Critical;
+    MotorCheck = 25, "MOTOR CHK", Critical;
+}
+
+// Serialized size: Active, and latched flags.
+pub const WARNINGS_SIZE: usize = 4 + 4;
+
+// Serialized size: Warning count, index, ID, and severity; then the label, padded with 0s.
+pub const WARNING_INFO_SIZE: usize = 1 + 1 + 1 + 1 + WARNING_LABEL_LEN;
+
+/// Active warnings, and the OSD ticker. Subsystems' conditions are collected each OSD update, in
+/// `update_warnings`.
+#[derive(Default)]
+pub struct Warnings {
+    /// Bits are `Warning` IDs.
+    pub active: u32,
+    /// Critical warnings that have been active since the last arm, or clear.
+    pub latched: u32,
+    /// The warning on the ticker, and when it was first shown, in seconds since start.
+    shown: Option<(Warning, f32)>,
+    armed: bool,
+}
+
+impl Warnings {
+    pub fn set(&mut self, warning: Warning, active: bool) {
+        if active {
+            self.active |= warning.bit();
+            if warning.severity() == Severity::Critical {
+                self.latched |= warning.bit();
+            }
+        } else {
+            self.active &= !warning.bit();
+        }
+    }
+
+    /// Clear latched warnings whose conditions have cleared.
+    pub fn clear_latched(&mut self) {
+        self.latched = self.active & self.latched;
+    }
+
+    /// The warning to show on the ticker, if any. Rotates through the most severe of those active
+    /// or latched, in `Warning::ALL` order, each shown for `TICKER_PERIOD`.
+    pub fn ticker(&mut self, timestamp: f32) -> Option<Warning> {
+        let flags = self.active | self.latched;
+        let top = Warning::ALL
+            .iter()
+            .filter(|w| flags & w.bit() != 0)
+            .map(|w| w.severity())
+            .max();
+
+        let Some(top) = top else {
+            self.shown = None;
+            return None;
+        };
+
+        let eligible = |w: &Warning| flags & w.bit() != 0 && w.severity() == top;
+
+        if let Some((w, t)) = self.shown {
+            if eligible(&w) && timestamp - t < TICKER_PERIOD {
+                return Some(w);
+            }
+        }
+
+        // The next eligible warning after the one shown, wrapping around.
+        let start = match self.shown {
+            Some((w, _)) => Warning::ALL.iter().position(|a| *a == w).unwrap_or(0) + 1,
+            None => 0,
+        };
+        let next = (0..Warning::ALL.len())
+            .map(|i| Warning::ALL[(start + i) % Warning::ALL.len()])
+            .find(eligible);
+
+        self.shown = next.map(|w| (w, timestamp));
+        next
+    }
+
+    pub fn to_bytes(&self) -> [u8; WARNINGS_SIZE] {
+        let mut result = [0; WARNINGS_SIZE];
+        result[0..4].clone_from_slice(&self.active.to_be_bytes());
+        result[4..8].clone_from_slice(&self.latched.to_be_bytes());
+        result
+    }
+}
+
+/// A warning's ID, severity, and label, by index in `Warning::ALL`; for the PC application to
+/// display the same text. Past the end of the table, only the count is set, and the ID is 0xff.
+pub fn warning_info_to_bytes(i: u8) -> [u8; WARNING_INFO_SIZE] {
+    let mut result = [0; WARNING_INFO_SIZE];
+    result[0] = Warning::ALL.len() as u8;
+    result[1] = i;
+
+    let Some(warning) = Warning::ALL.get(i as usize) else {
+        result[2] = 0xff;
+        return result;
+    };
+
+    result[2] = *warning as u8;
+    result[3] = warning.severity() as u8;
+
+    let label = warning.label().as_bytes();
+    result[4..4 + label.len()].clone_from_slice(label);
+
+    result
+}
+
+/// Run from the main loop, before updating the OSD. Sets each warning from its subsystem's state.
+/// Latched warnings clear on arming.
+pub fn update_warnings(state: &mut StateVolatile, system_status: &SystemStatus, link_quality: u8) {
+    let armed = state.arm_status == safety::MOTORS_ARMED;
+    if armed && !state.warnings.armed {
+        state.warnings.latched = 0;
+    }
+    state.warnings.armed = armed;
+
+    // A link we've never had isn't a warning; eg on the bench, with the radio off.
+    let link_lost = system_status.update_timestamps.rf_control_link.is_some()
+        && system_status.rf_control_link != SensorStatus::Pass;
+
+    let stage = state.batt_failsafe.stage;
+    let motor_fail = motor_failed(state);
+    let tipover = tipover_tripped(state);
+    let motor_check = arm_check_tripped(state);
+    let (launch_ready, launch_abort) = launch_status(state);
+    let gyro_only = state.accel_health.mode == FusionMode::GyroOnly;
+    let stall = state.stall_protect.active;
+    let ceiling = state.ground_ref.at_ceiling;
+    let link_hold = state.link_failsafe.stage == LinkStage::Hold;
+    let att_align = system_status.att_convergence == AttConvergence::Aligning;
+    let att_diverged = system_status.att_convergence == AttConvergence::Diverged;
+    let trainer = state.acro_trainer.active;
+    let ext_ctrl = state.ext_ctrl.active;
+    let prearm = system_status.prearm == PrearmStatus::Active && !armed;
+    let desync = system_status.esc_desync.iter().any(|d| *d);
+    let link_low = armed && !link_lost && link_quality < LINK_QUALITY_WARN;
+
+    let w = &mut state.warnings;
+
+    w.set(Warning::BattLow, stage == BattStage::Warn);
+    w.set(Warning::BattLimit, stage == BattStage::Limit);
+    w.set(Warning::BattCrit, stage == BattStage::Action);
+    w.set(Warning::MotorFail, motor_fail);
+    w.set(Warning::EscDesync, desync);
+    w.set(Warning::EscHot, system_status.esc_over_temp);
+    w.set(Warning::ImuFail, system_status.imu != SensorStatus::Pass);
+    w.set(Warning::GyroOnly, gyro_only);
+    w.set(Warning::BaroFail, system_status.baro == SensorStatus::Fault);
+    w.set(Warning::GpsFail, system_status.gps == SensorStatus::Fault);
+    w.set(Warning::LinkLost, link_lost);
+    w.set(Warning::LinkLow, link_low);
+    w.set(Warning::Tipover, tipover);
+    w.set(Warning::Stall, stall);
+    w.set(Warning::Trainer, trainer);
+    w.set(Warning::Prearm, prearm);
+    w.set(Warning::LaunchReady, launch_ready);
+    w.set(Warning::LaunchAbort, launch_abort);
+    w.set(Warning::Ceiling, ceiling);
+    w.set(Warning::LinkHold, link_hold);
+    w.set(Warning::AttAlign, att_align);
+    w.set(Warning::AttDiverged, att_diverged);
+    w.set(Warning::ExtCtrl, ext_ctrl);
+    w.set(Warning::ImuSwitch, system_status.imu_failed_over);
+    w.set(Warning::Killed, motor_kill::active());
+    w.set(Warning::MotorCheck, motor_check);
+}
+
+#[cfg(feature = "quad")]
+fn tipover_tripped(state: &StateVolatile) -> bool {
+    state.tipover.latch.tripped()
+}
+
+#[cfg(feature = "fixed-wing")]
+fn tipover_tripped(_state: &StateVolatile) -> bool {
+    false
+}
+
+#[cfg(feature = "quad")]
+fn arm_check_tripped(state: &StateVolatile) -> bool {
+    state.arm_check.latch.tripped()
+}
+
+#[cfg(feature = "fixed-wing")]
+fn arm_check_tripped(_state: &StateVolatile) -> bool {
+    false
+}
+
+/// Auto-launch waiting for the throw, and aborted.
+#[cfg(feature = "quad")]
+fn launch_status(_state: &StateVolatile) -> (bool, bool) {
+    (false, false)
+}
+
+#[cfg(feature = "fixed-wing")]
+fn launch_status(state: &StateVolatile) -> (bool, bool) {
+    (state.auto_launch.ready(), state.auto_launch.aborted())
+}
+
+/// Run periodically from the main loop. This is the only place input and autopilot modes change
+/// in flight, so they're applied in a fixed order: The low-battery action, then link-lost recovery,
+/// override the pilot's switches; otherwise, modes follow the switches, then follow-me. Modes that
+/// depend on stale sensors are then cancelled.
+pub fn update_flight_modes(
+    state: &mut StateVolatile,
+    autopilot_status: &mut AutopilotStatus,
+    ch_data: &Option<ChannelData>,
+    system_status: &mut SystemStatus,
+    params: &Params,
+    cfg: &UserConfig,
+    timestamp: f32,
+) {
+    let modes_prev = autopilot_status.mode_flags();
+    let alt = state.alt_est.estimate;
+
+    // A depleted battery may not complete a link-lost return, so this takes precedence. The pilot
+    // can hold the override switch to keep control.
+    let batt_action = state.batt_failsafe.action_required(
+        state.has_taken_off,
+        ch_data.as_ref().map_or(false, |ch| ch.batt_override),
+    );
+
+    if state.batt_failsafe.acting && !batt_action {
+        // Clear what the action commanded; the switches set modes again below.
+        rth::clear_modes(autopilot_status);
+        println!("Ending low-battery action");
+    }
+    state.batt_failsafe.acting = batt_action;
+
+    // We only recover if we had a link, and lost it, once airborne.
+    let link_lost = system_status.update_timestamps.rf_control_link.is_some()
+        && system_status.rf_control_link != SensorStatus::Pass;
+    let recovery = link_lost && state.has_taken_off && !batt_action;
+
+    if state.link_lost_recovery && !recovery {
+        // Clear what the recovery commanded; the switches set modes again below.
+        rth::clear_modes(autopilot_status);
+        println!("Link regained; ending link-lost recovery");
+    }
+    state.link_lost_recovery = recovery;
+
+    if batt_action {
+        #[cfg(feature = "quad")]
+        state.follow_me.stop(autopilot_status);
+
+        safety::execute_batt_failsafe(
+            cfg.batt_failsafe.action,
+            system_status,
+            autopilot_status,
+            params,
+            &alt,
+            &cfg.base_pt,
+            &mut state.rth,
+            &cfg.rth,
+        );
+    } else if recovery {
+        #[cfg(feature = "quad")]
+        state.follow_me.stop(autopilot_status);
+
+        state.rth.execute(
+            system_status,
+            autopilot_status,
+            params,
+            &alt,
+            &cfg.base_pt,
+            &cfg.rth,
+        );
+    } else if let Some(ch_data) = ch_data {
+        #[cfg(feature = "quad")]
+        set_input_mode(
+            ch_data.input_mode,
+            state,
+            system_status,
+            &cfg.angle_on_center,
+        );
+
+        autopilot_status.set_modes_from_ctrls(ch_data, params, &alt);
+
+        #[cfg(feature = "quad")]
+        state.follow_me.update(
+            ch_data.follow_me,
+            autopilot_status,
+            system_status,
+            params,
+            &alt,
+            &cfg.base_pt,
+            &cfg.follow,
+            &mut state.rth,
+            &cfg.rth,
+            timestamp,
+        );
+    }
+    state.rth.end_update();
+
+    safety::cancel_modes_for_stale_sensors(system_status, autopilot_status);
+
+    let modes = autopilot_status.mode_flags();
+    if modes != modes_prev {
+        event_log::log(EventCode::AutopilotModes, modes, 0);
+    }
+}
+
 // #[derive(Clone, Copy, PartialEq)]
 // pub enum AircraftType {
 //     /// Angry bumblebee
@@ -90,12 +712,17 @@ pub struct UserConfig {
     #[cfg(feature = "fixed-wing")]
     pub control_surface_config: ControlSurfaceConfig,
     /// Set a ceiling the aircraft won't exceed. Defaults to 400' (Legal limit in US for drones).
-    /// In meters.
+    /// In meters above takeoff; not enforced without a ground reference.
     pub ceiling: Option<f32>,
-    /// In Attitude and related control modes, max pitch angle (from straight up), ie
-    /// full speed, without going horizontal or further.
-    // max_angle: f32, // radians
+    /// In Acro hybrid mode, max tilt angle (from straight up) of the attitude commanded. Full
+    /// Acro is unconstrained. Radians.
+    pub max_angle: f32,
+    /// Servo pulse widths, and update frequency. Fixed-wing only.
+    pub servo_cfg: ServoCfg,
+    /// Minimum airspeed, and wind estimate settings. Fixed-wing only.
+    pub airspeed_cfg: AirspeedCfg,
     pub max_velocity: f32, // m/s
+    /// Air mode idle: The minimum power of any motor while armed. 0. to 1.
     pub idle_pwr: f32,
     // /// These input ranges map raw output from a manual controller to full scale range of our control scheme.
     // /// (min, max). Set using an initial calibration / setup procedure.
@@ -107,8 +734,6 @@ pub struct UserConfig {
     pub mapping_obstacles: bool,
     pub max_speed_hor: f32,
     pub max_speed_ver: f32,
-    // /// Map motor connection number to position, or servos for fixed wing
-    // pub control_mapping: ControlMapping,
     // Note that this inst includes idle power.
     // todo: We want to store this inst, but RTIC doesn't like it not being sync. Maybe static mut.
     // todo. For now, lives in the acro PID fn lol.
@@ -133,6 +758,176 @@ pub struct UserConfig {
     pub pid_coeffs: PidCoeffs,
     /// This is a dupe from AHRS, but here for storing/loading in config.
     pub acc_cal_bias: (f32, f32, f32),
+    /// Gyro bias slope vs IMU temperature.
+    pub gyro_temp_comp: GyroTempComp,
+    /// Magnetometer hard and soft-iron calibration.
+    pub mag_cal: MagCal,
+    /// GPS navigation solution rate. Set on the module at init.
+    pub gps_nav_rate: GpsNavRate,
+    /// Gyro notch filtering at motor rotation frequencies.
+    pub rpm_filter: RpmFilterCfg,
+    /// Gyro lowpass filter chain.
+    pub gyro_lpf: GyroLpfCfg,
+    /// Log a blackbox record every this many IMU updates.
+    pub blackbox_rate_divisor: u8,
+    /// Use the ADC, or ESC telemetry, for battery voltage and current.
+    pub batt_meas_source: BattMeasSource,
+    /// Map motor and servo functions to output pins, and set motor directions and servo trim.
+    pub control_mapping: ControlMapping,
+    /// Thrust linearization and battery voltage compensation.
+    pub thrust_comp: ThrustCompCfg,
+    /// Per-motor weights for converting the control mix to motor power. Quad only.
+    pub mixer: Mixer,
+    /// Battery-voltage-aware RPM setpoint limiting and feedforward, for RPM control.
+    pub rpm_ctrl: RpmCtrlCfg,
+    /// Rate PID, or model-based flight controls.
+    pub ctrl_scheme: CtrlScheme,
+    /// Acro with auto-level on center.
+    pub angle_on_center: AngleOnCenterCfg,
+    /// Radio receiver protocol. This is applied at init, so changes take effect after a restart.
+    pub rx_protocol: RxProtocol,
+    /// Descend, or disarm, if IMU data goes stale in flight.
+    pub imu_fail_policy: ImuFailPolicy,
+    /// Motor power for `ImuFailPolicy::Descend`. 0. to 1.
+    pub imu_fail_descend_pwr: f32,
+    /// Motor output slew-rate limiting and smoothing.
+    pub output_smoothing: OutputSmoothingCfg,
+    /// Where to store the blackbox log. Applied at init, so changes take effect after a restart.
+    pub log_storage: StorageBackend,
+    /// IMU output data rate; this sets the main loop rate. Applied at init, so changes take
+    /// effect after a restart.
+    pub imu_odr: ImuOdr,
+    /// Flight controls run once per this many IMU updates. Applied at init, so changes take
+    /// effect after a restart.
+    pub flight_ctrl_imu_ratio: u8,
+    /// Hold motors above a minimum RPM while airborne. Requires bidirectional DSHOT.
+    pub dyn_idle: DynIdleCfg,
+    /// The last hover throttle estimate; saved after each flight. See `HoverThrottleEst`.
+    pub hover_throttle: f32,
+    /// Limits pitch-up, and adds throttle, near the minimum airspeed. Fixed-wing only.
+    pub stall_protect: StallProtectCfg,
+    /// Which receiver channel each switch function is on.
+    pub channel_map: ChannelMap,
+    /// Signal counts required to arm and disarm.
+    pub arm_cfg: ArmCfg,
+    /// Buzzer, and lost-model beacon settings.
+    pub indicators: IndicatorCfg,
+    /// WS2812 LED strip colors, and layout.
+    pub led_strip: LedStripCfg,
+    /// Camera tilt servo limits, and pitch compensation.
+    pub camera_tilt: CameraTiltCfg,
+    /// Battery voltage collapse thresholds.
+    pub brownout: BrownoutCfg,
+    /// Tuning sets. The active one's slot is stale; its values are in the fields above. See the
+    /// `profiles` module.
+    pub profiles: [CtrlProfile; NUM_PROFILES],
+    pub active_profile: u8,
+    /// Battery voltage divider, and current sensor slope and offset.
+    pub adc_cal: AdcCalCfg,
+    /// Accelerometer plausibility thresholds, for falling back to gyro-only attitude.
+    pub accel_health: AccelHealthCfg,
+    /// Degraded control after a motor failure. Quad only.
+    pub motor_fail: MotorFailCfg,
+    /// Tilt limit, and correction gain, for Acro Trainer. Quad only.
+    pub acro_trainer: AcroTrainerCfg,
+    /// ESCs are in 3D mode, so thrust may be negative. See `reversible`. Applied at init, so
+    /// changes take effect after a restart. Quad only.
+    pub reversible: bool,
+    /// Standoff distance, and height above the target, for follow-me. Quad only.
+    pub follow: FollowCfg,
+    /// Ground tip-over protection thresholds. Quad only.
+    pub tipover: TipoverCfg,
+    /// Throttle limit, eg for a racing class, or a beginner.
+    pub throttle_limit: ThrottleLimitCfg,
+    /// Lowpass cutoffs for RPM readings, and their rate of change, for control use.
+    pub rpm_lpf: RpmLpfCfg,
+    /// Low-battery failsafe thresholds, and the stage 3 action.
+    pub batt_failsafe: BattFailsafeCfg,
+    /// IMU full-scale ranges, and anti-alias and UI filters.
+    pub imu_cfg: ImuCfg,
+    /// Units for ground speed on the OSD.
+    pub speed_units: SpeedUnits,
+    /// How the mixer desaturates, and how much yaw it keeps. Quad only.
+    pub desat: DesatCfg,
+    /// Hand launches with the motor stopped until the throw. Fixed-wing only.
+    pub auto_launch: AutoLaunchCfg,
+    /// Timing of the hold, and lost-link stages, on losing control channel data.
+    pub link_failsafe: LinkFailsafeCfg,
+    /// Accelerometer correction gain, and mag rejection, for attitude fusion.
+    pub fusion: FusionCfg,
+    /// Control authority scaling from measured effectiveness.
+    pub authority: AuthorityCfg,
+    /// Return-to-home altitude, path, and approach. Used by link-lost recovery, the low-battery
+    /// failsafe, and follow-me.
+    pub rth: RthCfg,
+    /// A secondary IMU, cross-checked against the primary, for failover.
+    pub dual_imu: DualImuCfg,
+    /// Motor RPM, and yaw response, checks just after arming. Quad only.
+    pub arm_check: ArmCheckCfg,
+}
+
+// Tunable fields, for the parameter dictionary; see `params`. Ranges match those enforced when
+// loading config, in stored units. IDs are stable; never reuse or renumber one.
+param_table! {
+    1: "max_angle", max_angle, 0.01, TAU / 4., true;
+    2: "max_velocity", max_velocity, 0., 100., false;
+    3: "max_speed_hor", max_speed_hor, 0., 100., false;
+    4: "max_speed_ver", max_speed_ver, 0., 50., false;
+    5: "idle_pwr", idle_pwr, 0., mixer::IDLE_PWR_MAX, true;
+    6: "pid_p", pid_coeffs.p, 0., 10., true;
+    7: "pid_i", pid_coeffs.i, 0., 10., true;
+    8: "pid_d", pid_coeffs.d, 0., 10., true;
+    9: "pid_i_windup", pid_coeffs.max_i_windup, 0., 10., true;
+    10: "att_ttc", pid_coeffs.att_ttc, 0.05, 5., true;
+    11: "ttc_per_dtheta", ctrl_coeffs.ttc_per_dθ, 0.01, 5., true;
+    12: "max_ttc_per_dth", ctrl_coeffs.max_ttc_per_dθ, 0.01, 10., true;
+    13: "motor_poles", motor_pole_count, 2., 64., true;
+    14: "bb_rate_div", blackbox_rate_divisor, 1., 254., false;
+    15: "imu_fail_pwr", imu_fail_descend_pwr, 0., 1., true;
+    16: "hover_throttle", hover_throttle,
+        hover_est::HOVER_THROTTLE_MIN, hover_est::HOVER_THROTTLE_MAX, false;
+    17: "thr_limit", throttle_limit.limit, throttle_limit::LIMIT_MIN, 1., false;
+    18: "thr_limit_beg", throttle_limit.beginner_limit, throttle_limit::LIMIT_MIN, 1., false;
+    19: "thr_limit_ap_ex", throttle_limit.exempt_autopilot, 0., 1., false;
+    20: "aoc_enabled", angle_on_center.enabled, 0., 1., true;
+    21: "aoc_deadband", angle_on_center.deadband, 0., cmd_updates::DEADBAND_MAX, false;
+    22: "dyn_idle_en", dyn_idle.enabled, 0., 1., true;
+    23: "dyn_idle_rpm", dyn_idle.min_rpm, dyn_idle::MIN_RPM_MIN, dyn_idle::MIN_RPM_MAX, true;
+    24: "dyn_idle_gain", dyn_idle.gain, 0., dyn_idle::GAIN_MAX, true;
+    25: "speed_units", speed_units, 0., 1., false;
+    26: "desat_yaw_min", desat.yaw_min, 0., 1., true;
+    27: "launch_enabled", auto_launch.enabled, 0., 1., true;
+    28: "launch_throttle", auto_launch.throttle, auto_launch::THROTTLE_MIN, 1., true;
+    29: "launch_pitch", auto_launch.pitch, 0., auto_launch::PITCH_MAX, true;
+    30: "launch_duration", auto_launch.duration,
+        auto_launch::DURATION_MIN, auto_launch::DURATION_MAX, true;
+    31: "launch_alt_gain", auto_launch.alt_gain, 0., auto_launch::ALT_GAIN_MAX, true;
+    32: "link_hold_delay", link_failsafe.hold_delay,
+        link_failsafe::HOLD_DELAY_MIN, link_failsafe::HOLD_DELAY_MAX, false;
+    33: "link_lost_delay", link_failsafe.lost_delay,
+        link_failsafe::LOST_DELAY_MIN, link_failsafe::LOST_DELAY_MAX, false;
+    34: "link_hold_thr", link_failsafe.hold_throttle, link_failsafe::HOLD_THROTTLE_MIN, 1., false;
+    35: "fusion_gain", fusion.gain, fusion::GAIN_MIN, fusion::GAIN_MAX, false;
+    36: "mag_reject", fusion.mag_reject, fusion::MAG_REJECT_MIN, fusion::MAG_REJECT_MAX, false;
+    37: "auth_adapt_en", authority.enabled, 0., 1., true;
+    38: "auth_adapt_max", authority.max_adapt,
+        authority::MAX_ADAPT_MIN, authority::MAX_ADAPT_MAX, true;
+    39: "rth_alt_mode", rth.alt_mode, 0., 2., false;
+    40: "rth_alt", rth.alt, rth::ALT_MIN, rth::ALT_MAX, false;
+    41: "rth_climb", rth.climb, 0., 1., false;
+    42: "rth_approach", rth.approach, 0., 1., false;
+    43: "rth_appr_radius", rth.approach_radius,
+        rth::APPROACH_RADIUS_MIN, rth::APPROACH_RADIUS_MAX, false;
+    44: "rth_loiter_rad", rth.loiter_radius, rth::LOITER_RADIUS_MIN, rth::LOITER_RADIUS_MAX, false;
+    45: "imu2_enabled", dual_imu.enabled, 0., 1., true;
+    46: "imu_primary", dual_imu.primary, 0., 1., true;
+    47: "imu_xcheck", dual_imu.cross_check, 0., 1., true;
+    48: "imu_xcheck_diff", dual_imu.max_diff, dual_imu::MAX_DIFF_MIN, dual_imu::MAX_DIFF_MAX, true;
+    49: "imu_xcheck_time", dual_imu.max_diff_time,
+        dual_imu::MAX_DIFF_TIME_MIN, dual_imu::MAX_DIFF_TIME_MAX, true;
+    50: "arm_check_en", arm_check.enabled, 0., 1., true;
+    51: "arm_check_step", arm_check.step_power,
+        arm_check::STEP_POWER_MIN, arm_check::STEP_POWER_MAX, true;
 }
 
 impl Default for UserConfig {
@@ -144,15 +939,15 @@ impl Default for UserConfig {
             control_surface_config: ControlSurfaceConfig::default(),
             // aircraft_type: AircraftType::Quadcopter,
             ceiling: Some(122.),
-            // todo: Do we want max angle and vel here? Do we use them, vice settings in InpuMap?
-            // max_angle: TAU * 0.22,
+            // todo: Do we want max vel here? Do we use it, vice settings in InpuMap?
+            max_angle: TAU * 0.22,
+            servo_cfg: Default::default(),
+            airspeed_cfg: Default::default(),
             max_velocity: 30., // todo: raise?
-            // Note: Idle power now handled in `power_interp_inst`
-            idle_pwr: 0.02, // scale of 0 to 1.
+            idle_pwr: mixer::IDLE_PWR_DEFAULT,
             mapping_obstacles: false,
             max_speed_hor: 20.,
             max_speed_ver: 20.,
-            // control_mapping: Default::default(),
             // altitude_cal: Default::default(),
             // Make sure to update this interp table if you change idle power.
             // todo: This LUT setup is backwards! You need to put thrust on a fixed spacing,
@@ -195,12 +990,64 @@ impl Default for UserConfig {
             base_pt: Default::default(),
             pid_coeffs: Default::default(),
             acc_cal_bias: (0., 0., 0.),
+            gyro_temp_comp: Default::default(),
+            mag_cal: Default::default(),
+            gps_nav_rate: Default::default(),
+            rpm_filter: Default::default(),
+            gyro_lpf: Default::default(),
+            blackbox_rate_divisor: blackbox::DEFAULT_RATE_DIVISOR,
+            batt_meas_source: Default::default(),
+            control_mapping: Default::default(),
+            thrust_comp: Default::default(),
+            mixer: Default::default(),
+            rpm_ctrl: Default::default(),
+            ctrl_scheme: Default::default(),
+            angle_on_center: Default::default(),
+            rx_protocol: Default::default(),
+            imu_fail_policy: Default::default(),
+            imu_fail_descend_pwr: IMU_FAIL_DESCEND_PWR_DEFAULT,
+            output_smoothing: Default::default(),
+            log_storage: Default::default(),
+            imu_odr: Default::default(),
+            flight_ctrl_imu_ratio: loop_rates::flight_ctrl_imu_ratio_default(),
+            dyn_idle: Default::default(),
+            hover_throttle: hover_est::HOVER_THROTTLE_DEFAULT,
+            stall_protect: Default::default(),
+            channel_map: Default::default(),
+            arm_cfg: Default::default(),
+            indicators: Default::default(),
+            led_strip: Default::default(),
+            camera_tilt: Default::default(),
+            brownout: Default::default(),
+            profiles: Default::default(),
+            active_profile: 0,
+            adc_cal: Default::default(),
+            accel_health: Default::default(),
+            motor_fail: Default::default(),
+            acro_trainer: Default::default(),
+            reversible: false,
+            follow: Default::default(),
+            tipover: Default::default(),
+            throttle_limit: Default::default(),
+            rpm_lpf: Default::default(),
+            batt_failsafe: Default::default(),
+            imu_cfg: Default::default(),
+            speed_units: Default::default(),
+            desat: Default::default(),
+            auto_launch: Default::default(),
+            link_failsafe: Default::default(),
+            fusion: Default::default(),
+            authority: Default::default(),
+            rth: Default::default(),
+            dual_imu: Default::default(),
+            arm_check: Default::default(),
         }
     }
 }
 
 impl UserConfig {
-    /// For use with Preflight, via USB
+    /// For use with Preflight, via USB. Invalid or unrecognized values, eg from configs saved
+    /// before a field was added, use that field's default.
     pub fn from_bytes(buf: &[u8]) -> Self {
         let pid_coeffs = PidCoeffs {
             p: f32::from_be_bytes(buf[0..4].try_into().unwrap()),
@@ -216,11 +1063,334 @@ impl UserConfig {
             f32::from_be_bytes(buf[28..32].try_into().unwrap()),
         );
 
-        Self {
+        let gyro_temp_comp = GyroTempComp {
+            ref_temp: f32::from_be_bytes(buf[32..36].try_into().unwrap()),
+            slope: (
+                f32::from_be_bytes(buf[36..40].try_into().unwrap()),
+                f32::from_be_bytes(buf[40..44].try_into().unwrap()),
+                f32::from_be_bytes(buf[44..48].try_into().unwrap()),
+            ),
+        };
+
+        let mag_cal = MagCal {
+            hard_iron: Vec3::new(
+                f32::from_be_bytes(buf[48..52].try_into().unwrap()),
+                f32::from_be_bytes(buf[52..56].try_into().unwrap()),
+                f32::from_be_bytes(buf[56..60].try_into().unwrap()),
+            ),
+            soft_iron: Vec3::new(
+                f32::from_be_bytes(buf[60..64].try_into().unwrap()),
+                f32::from_be_bytes(buf[64..68].try_into().unwrap()),
+                f32::from_be_bytes(buf[68..72].try_into().unwrap()),
+            ),
+            field_strength: f32::from_be_bytes(buf[72..76].try_into().unwrap()),
+        };
+
+        let gps_nav_rate = GpsNavRate::try_from(buf[76]).unwrap_or_default();
+
+        let rpm_filter = RpmFilterCfg {
+            q: f32::from_be_bytes(buf[77..81].try_into().unwrap()),
+            enabled: buf[81] != 0,
+            second_harmonic: buf[82] != 0,
+        };
+
+        let gyro_lpf = GyroLpfCfg {
+            lpf1: LpfStageCfg {
+                type_: LpfType::try_from(buf[83]).unwrap_or(LpfType::Off),
+                cutoff: f32::from_be_bytes(buf[84..88].try_into().unwrap()),
+            },
+            lpf2: LpfStageCfg {
+                type_: LpfType::try_from(buf[88]).unwrap_or(LpfType::Off),
+                cutoff: f32::from_be_bytes(buf[89..93].try_into().unwrap()),
+            },
+        };
+
+        // Configs saved before this field was added will have it as 0xff.
+        let blackbox_rate_divisor = match buf[93] {
+            0 | 0xff => blackbox::DEFAULT_RATE_DIVISOR,
+            d => d,
+        };
+
+        let batt_meas_source = BattMeasSource::try_from(buf[94]).unwrap_or_default();
+
+        let control_mapping =
+            ControlMapping::from_bytes(&buf[95..95 + CONTROL_MAPPING_SIZE]).unwrap_or_default();
+
+        let i = 95 + CONTROL_MAPPING_SIZE;
+        let mut input_map = InputMap {
+            cal: InputCal::from_bytes(&buf[i..i + INPUT_CAL_SIZE]).unwrap_or_default(),
+            ..Default::default()
+        };
+
+        let i = i + INPUT_CAL_SIZE;
+        if let Some([pitch, roll, yaw]) = rates::rates_from_bytes(&buf[i..i + RATES_SIZE]) {
+            input_map.pitch_rate = pitch;
+            input_map.roll_rate = roll;
+            input_map.yaw_rate = yaw;
+        }
+
+        let i = i + RATES_SIZE;
+        let mut thrust_comp = ThrustCompCfg::from_bytes(&buf[i..i + THRUST_COMP_CFG_SIZE]);
+        if !thrust_comp.valid() {
+            thrust_comp = Default::default();
+        }
+
+        let i = i + THRUST_COMP_CFG_SIZE;
+        let mixer = Mixer::from_bytes(&buf[i..i + MIXER_SIZE]).unwrap_or_default();
+
+        let i = i + MIXER_SIZE;
+        let mut idle_pwr = f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        // This comparison also rejects NaN.
+        if !(0. ..=mixer::IDLE_PWR_MAX).contains(&idle_pwr) {
+            idle_pwr = mixer::IDLE_PWR_DEFAULT;
+        }
+
+        let i = i + 4;
+        let mut rpm_ctrl = RpmCtrlCfg::from_bytes(&buf[i..i + RPM_CTRL_CFG_SIZE]);
+        if !rpm_ctrl.valid() {
+            rpm_ctrl = Default::default();
+        }
+
+        let i = i + RPM_CTRL_CFG_SIZE;
+        let ctrl_scheme = CtrlScheme::try_from(buf[i]).unwrap_or_default();
+
+        let i = i + 1;
+        let angle_on_center =
+            AngleOnCenterCfg::from_bytes(&buf[i..i + ANGLE_ON_CENTER_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + ANGLE_ON_CENTER_CFG_SIZE;
+        let mut max_angle = f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        // This comparison also rejects NaN.
+        if !(max_angle > 0. && max_angle <= TAU / 4.) {
+            max_angle = TAU * 0.22;
+        }
+
+        let i = i + 4;
+        let servo_cfg = ServoCfg::from_bytes(&buf[i..i + SERVO_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + SERVO_CFG_SIZE;
+        let airspeed_cfg =
+            AirspeedCfg::from_bytes(&buf[i..i + AIRSPEED_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + AIRSPEED_CFG_SIZE;
+        let rx_protocol = RxProtocol::try_from(buf[i]).unwrap_or_default();
+
+        let i = i + 1;
+        let imu_fail_policy = ImuFailPolicy::try_from(buf[i]).unwrap_or_default();
+
+        let i = i + 1;
+        let mut imu_fail_descend_pwr = f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        // This comparison also rejects NaN.
+        if !(0. ..=1.).contains(&imu_fail_descend_pwr) {
+            imu_fail_descend_pwr = IMU_FAIL_DESCEND_PWR_DEFAULT;
+        }
+
+        let i = i + 4;
+        let output_smoothing =
+            OutputSmoothingCfg::from_bytes(&buf[i..i + OUTPUT_SMOOTHING_CFG_SIZE])
+                .unwrap_or_default();
+
+        let i = i + OUTPUT_SMOOTHING_CFG_SIZE;
+        let log_storage = StorageBackend::try_from(buf[i]).unwrap_or_default();
+
+        let i = i + 1;
+        let imu_odr = ImuOdr::try_from(buf[i]).unwrap_or_default();
+
+        let i = i + 1;
+        let flight_ctrl_imu_ratio = loop_rates::flight_ctrl_imu_ratio_from_byte(buf[i]);
+
+        let i = i + 1;
+        let dyn_idle = DynIdleCfg::from_bytes(&buf[i..i + DYN_IDLE_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + DYN_IDLE_CFG_SIZE;
+        let mut hover_throttle = f32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        // This comparison also rejects NaN.
+        if !(hover_est::HOVER_THROTTLE_MIN..=hover_est::HOVER_THROTTLE_MAX)
+            .contains(&hover_throttle)
+        {
+            hover_throttle = hover_est::HOVER_THROTTLE_DEFAULT;
+        }
+
+        let i = i + 4;
+        let stall_protect =
+            StallProtectCfg::from_bytes(&buf[i..i + STALL_PROTECT_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + STALL_PROTECT_CFG_SIZE;
+        let channel_map = ChannelMap::from_bytes(&buf[i..i + CHANNEL_MAP_SIZE]).unwrap_or_default();
+
+        let i = i + CHANNEL_MAP_SIZE;
+        let arm_cfg = ArmCfg::from_bytes(&buf[i..i + ARM_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + ARM_CFG_SIZE;
+        let indicators =
+            IndicatorCfg::from_bytes(&buf[i..i + INDICATOR_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + INDICATOR_CFG_SIZE;
+        let led_strip =
+            LedStripCfg::from_bytes(&buf[i..i + LED_STRIP_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + LED_STRIP_CFG_SIZE;
+        let camera_tilt =
+            CameraTiltCfg::from_bytes(&buf[i..i + CAMERA_TILT_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + CAMERA_TILT_CFG_SIZE;
+        let brownout = BrownoutCfg::from_bytes(&buf[i..i + BROWNOUT_CFG_SIZE]).unwrap_or_default();
+
+        let mut i = i + BROWNOUT_CFG_SIZE;
+        let mut profiles_saved = [None; NUM_PROFILES];
+        for profile in &mut profiles_saved {
+            *profile = CtrlProfile::from_bytes(&buf[i..i + PROFILE_SIZE]);
+            i += PROFILE_SIZE;
+        }
+
+        let active_profile = if (buf[i] as usize) < NUM_PROFILES {
+            buf[i]
+        } else {
+            0
+        };
+
+        let profiles = profiles_saved.map(|p| p.unwrap_or_default());
+
+        let i = i + 1;
+        let adc_cal = AdcCalCfg::from_bytes(&buf[i..i + ADC_CAL_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + ADC_CAL_CFG_SIZE;
+        let accel_health =
+            AccelHealthCfg::from_bytes(&buf[i..i + ACCEL_HEALTH_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + ACCEL_HEALTH_CFG_SIZE;
+        let motor_fail =
+            MotorFailCfg::from_bytes(&buf[i..i + MOTOR_FAIL_CFG_SIZE]).unwrap_or_default();
+
+        // Configs saved before this field was added will have it as 0xff.
+        let i = i + MOTOR_FAIL_CFG_SIZE;
+        let reversible = buf[i] == 1;
+
+        let i = i + 1;
+        let acro_trainer =
+            AcroTrainerCfg::from_bytes(&buf[i..i + ACRO_TRAINER_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + ACRO_TRAINER_CFG_SIZE;
+        let follow = FollowCfg::from_bytes(&buf[i..i + FOLLOW_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + FOLLOW_CFG_SIZE;
+        let tipover = TipoverCfg::from_bytes(&buf[i..i + TIPOVER_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + TIPOVER_CFG_SIZE;
+        let throttle_limit =
+            ThrottleLimitCfg::from_bytes(&buf[i..i + THROTTLE_LIMIT_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + THROTTLE_LIMIT_CFG_SIZE;
+        let rpm_lpf = RpmLpfCfg::from_bytes(&buf[i..i + RPM_LPF_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + RPM_LPF_CFG_SIZE;
+        let batt_failsafe =
+            BattFailsafeCfg::from_bytes(&buf[i..i + BATT_FAILSAFE_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + BATT_FAILSAFE_CFG_SIZE;
+        let imu_cfg = ImuCfg::from_bytes(&buf[i..i + IMU_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + IMU_CFG_SIZE;
+        let speed_units = SpeedUnits::try_from(buf[i]).unwrap_or_default();
+
+        let i = i + 1;
+        let desat = DesatCfg::from_bytes(&buf[i..i + DESAT_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + DESAT_CFG_SIZE;
+        let auto_launch =
+            AutoLaunchCfg::from_bytes(&buf[i..i + AUTO_LAUNCH_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + AUTO_LAUNCH_CFG_SIZE;
+        let link_failsafe =
+            LinkFailsafeCfg::from_bytes(&buf[i..i + LINK_FAILSAFE_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + LINK_FAILSAFE_CFG_SIZE;
+        let fusion = FusionCfg::from_bytes(&buf[i..i + FUSION_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + FUSION_CFG_SIZE;
+        let authority =
+            AuthorityCfg::from_bytes(&buf[i..i + AUTHORITY_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + AUTHORITY_CFG_SIZE;
+        let rth = RthCfg::from_bytes(&buf[i..i + RTH_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + RTH_CFG_SIZE;
+        let dual_imu = DualImuCfg::from_bytes(&buf[i..i + DUAL_IMU_CFG_SIZE]).unwrap_or_default();
+
+        let i = i + DUAL_IMU_CFG_SIZE;
+        let arm_check =
+            ArmCheckCfg::from_bytes(&buf[i..i + ARM_CHECK_CFG_SIZE]).unwrap_or_default();
+
+        let mut result = Self {
             pid_coeffs,
             acc_cal_bias,
+            gyro_temp_comp,
+            mag_cal,
+            gps_nav_rate,
+            rpm_filter,
+            gyro_lpf,
+            blackbox_rate_divisor,
+            batt_meas_source,
+            control_mapping,
+            input_map,
+            thrust_comp,
+            mixer,
+            idle_pwr,
+            rpm_ctrl,
+            ctrl_scheme,
+            angle_on_center,
+            max_angle,
+            servo_cfg,
+            airspeed_cfg,
+            rx_protocol,
+            imu_fail_policy,
+            imu_fail_descend_pwr,
+            output_smoothing,
+            log_storage,
+            imu_odr,
+            flight_ctrl_imu_ratio,
+            dyn_idle,
+            hover_throttle,
+            stall_protect,
+            channel_map,
+            arm_cfg,
+            indicators,
+            led_strip,
+            camera_tilt,
+            brownout,
+            profiles,
+            active_profile,
+            adc_cal,
+            accel_health,
+            motor_fail,
+            reversible,
+            acro_trainer,
+            follow,
+            tipover,
+            throttle_limit,
+            rpm_lpf,
+            batt_failsafe,
+            imu_cfg,
+            speed_units,
+            desat,
+            auto_launch,
+            link_failsafe,
+            fusion,
+            authority,
+            rth,
+            dual_imu,
+            arm_check,
             ..Default::default()
+        };
+
+        // The active profile's slot is written from the live values on save, and includes
+        // control coefficients, which aren't stored elsewhere. Configs saved before profiles were
+        // added keep the values loaded above.
+        if let Some(profile) = profiles_saved[active_profile as usize] {
+            profile.apply(&mut result);
         }
+
+        result
     }
 
     /// For use with Preflight, via USB
@@ -235,16 +1405,187 @@ impl UserConfig {
         result[20..24].clone_from_slice(&self.acc_cal_bias.0.to_be_bytes());
         result[24..28].clone_from_slice(&self.acc_cal_bias.1.to_be_bytes());
         result[28..32].clone_from_slice(&self.acc_cal_bias.2.to_be_bytes());
+        result[32..36].clone_from_slice(&self.gyro_temp_comp.ref_temp.to_be_bytes());
+        result[36..40].clone_from_slice(&self.gyro_temp_comp.slope.0.to_be_bytes());
+        result[40..44].clone_from_slice(&self.gyro_temp_comp.slope.1.to_be_bytes());
+        result[44..48].clone_from_slice(&self.gyro_temp_comp.slope.2.to_be_bytes());
+        result[48..52].clone_from_slice(&self.mag_cal.hard_iron.x.to_be_bytes());
+        result[52..56].clone_from_slice(&self.mag_cal.hard_iron.y.to_be_bytes());
+        result[56..60].clone_from_slice(&self.mag_cal.hard_iron.z.to_be_bytes());
+        result[60..64].clone_from_slice(&self.mag_cal.soft_iron.x.to_be_bytes());
+        result[64..68].clone_from_slice(&self.mag_cal.soft_iron.y.to_be_bytes());
+        result[68..72].clone_from_slice(&self.mag_cal.soft_iron.z.to_be_bytes());
+        result[72..76].clone_from_slice(&self.mag_cal.field_strength.to_be_bytes());
+        result[76] = self.gps_nav_rate as u8;
+        result[77..81].clone_from_slice(&self.rpm_filter.q.to_be_bytes());
+        result[81] = self.rpm_filter.enabled as u8;
+        result[82] = self.rpm_filter.second_harmonic as u8;
+        result[83] = self.gyro_lpf.lpf1.type_ as u8;
+        result[84..88].clone_from_slice(&self.gyro_lpf.lpf1.cutoff.to_be_bytes());
+        result[88] = self.gyro_lpf.lpf2.type_ as u8;
+        result[89..93].clone_from_slice(&self.gyro_lpf.lpf2.cutoff.to_be_bytes());
+        result[93] = self.blackbox_rate_divisor;
+        result[94] = self.batt_meas_source as u8;
+        result[95..95 + CONTROL_MAPPING_SIZE].clone_from_slice(&self.control_mapping.to_bytes());
+
+        let i = 95 + CONTROL_MAPPING_SIZE;
+        result[i..i + INPUT_CAL_SIZE].clone_from_slice(&self.input_map.cal.to_bytes());
+
+        let i = i + INPUT_CAL_SIZE;
+        result[i..i + RATES_SIZE].clone_from_slice(&rates::rates_to_bytes(&self.input_map.rates()));
+
+        let i = i + RATES_SIZE;
+        result[i..i + THRUST_COMP_CFG_SIZE].clone_from_slice(&self.thrust_comp.to_bytes());
+
+        let i = i + THRUST_COMP_CFG_SIZE;
+        result[i..i + MIXER_SIZE].clone_from_slice(&self.mixer.to_bytes());
+
+        let i = i + MIXER_SIZE;
+        result[i..i + 4].clone_from_slice(&self.idle_pwr.to_be_bytes());
+
+        let i = i + 4;
+        result[i..i + RPM_CTRL_CFG_SIZE].clone_from_slice(&self.rpm_ctrl.to_bytes());
+
+        let i = i + RPM_CTRL_CFG_SIZE;
+        result[i] = self.ctrl_scheme as u8;
+
+        let i = i + 1;
+        result[i..i + ANGLE_ON_CENTER_CFG_SIZE].clone_from_slice(&self.angle_on_center.to_bytes());
+
+        let i = i + ANGLE_ON_CENTER_CFG_SIZE;
+        result[i..i + 4].clone_from_slice(&self.max_angle.to_be_bytes());
+
+        let i = i + 4;
+        result[i..i + SERVO_CFG_SIZE].clone_from_slice(&self.servo_cfg.to_bytes());
+
+        let i = i + SERVO_CFG_SIZE;
+        result[i..i + AIRSPEED_CFG_SIZE].clone_from_slice(&self.airspeed_cfg.to_bytes());
+
+        let i = i + AIRSPEED_CFG_SIZE;
+        result[i] = self.rx_protocol as u8;
+
+        let i = i + 1;
+        result[i] = self.imu_fail_policy as u8;
+
+        let i = i + 1;
+        result[i..i + 4].clone_from_slice(&self.imu_fail_descend_pwr.to_be_bytes());
+
+        let i = i + 4;
+        result[i..i + OUTPUT_SMOOTHING_CFG_SIZE]
+            .clone_from_slice(&self.output_smoothing.to_bytes());
+
+        let i = i + OUTPUT_SMOOTHING_CFG_SIZE;
+        result[i] = self.log_storage as u8;
+
+        let i = i + 1;
+        result[i] = self.imu_odr as u8;
+
+        let i = i + 1;
+        result[i] = self.flight_ctrl_imu_ratio;
+
+        let i = i + 1;
+        result[i..i + DYN_IDLE_CFG_SIZE].clone_from_slice(&self.dyn_idle.to_bytes());
+
+        let i = i + DYN_IDLE_CFG_SIZE;
+        result[i..i + 4].clone_from_slice(&self.hover_throttle.to_be_bytes());
+
+        let i = i + 4;
+        result[i..i + STALL_PROTECT_CFG_SIZE].clone_from_slice(&self.stall_protect.to_bytes());
+
+        let i = i + STALL_PROTECT_CFG_SIZE;
+        result[i..i + CHANNEL_MAP_SIZE].clone_from_slice(&self.channel_map.to_bytes());
+
+        let i = i + CHANNEL_MAP_SIZE;
+        result[i..i + ARM_CFG_SIZE].clone_from_slice(&self.arm_cfg.to_bytes());
+
+        let i = i + ARM_CFG_SIZE;
+        result[i..i + INDICATOR_CFG_SIZE].clone_from_slice(&self.indicators.to_bytes());
+
+        let i = i + INDICATOR_CFG_SIZE;
+        result[i..i + LED_STRIP_CFG_SIZE].clone_from_slice(&self.led_strip.to_bytes());
+
+        let i = i + LED_STRIP_CFG_SIZE;
+        result[i..i + CAMERA_TILT_CFG_SIZE].clone_from_slice(&self.camera_tilt.to_bytes());
+
+        let i = i + CAMERA_TILT_CFG_SIZE;
+        result[i..i + BROWNOUT_CFG_SIZE].clone_from_slice(&self.brownout.to_bytes());
+
+        let mut i = i + BROWNOUT_CFG_SIZE;
+        for j in 0..NUM_PROFILES {
+            result[i..i + PROFILE_SIZE].clone_from_slice(&profiles::get(self, j).to_bytes());
+            i += PROFILE_SIZE;
+        }
+
+        result[i] = self.active_profile;
+
+        let i = i + 1;
+        result[i..i + ADC_CAL_CFG_SIZE].clone_from_slice(&self.adc_cal.to_bytes());
+
+        let i = i + ADC_CAL_CFG_SIZE;
+        result[i..i + ACCEL_HEALTH_CFG_SIZE].clone_from_slice(&self.accel_health.to_bytes());
+
+        let i = i + ACCEL_HEALTH_CFG_SIZE;
+        result[i..i + MOTOR_FAIL_CFG_SIZE].clone_from_slice(&self.motor_fail.to_bytes());
+
+        let i = i + MOTOR_FAIL_CFG_SIZE;
+        result[i] = self.reversible as u8;
+
+        let i = i + 1;
+        result[i..i + ACRO_TRAINER_CFG_SIZE].clone_from_slice(&self.acro_trainer.to_bytes());
+
+        let i = i + ACRO_TRAINER_CFG_SIZE;
+        result[i..i + FOLLOW_CFG_SIZE].clone_from_slice(&self.follow.to_bytes());
+
+        let i = i + FOLLOW_CFG_SIZE;
+        result[i..i + TIPOVER_CFG_SIZE].clone_from_slice(&self.tipover.to_bytes());
+
+        let i = i + TIPOVER_CFG_SIZE;
+        result[i..i + THROTTLE_LIMIT_CFG_SIZE].clone_from_slice(&self.throttle_limit.to_bytes());
+
+        let i = i + THROTTLE_LIMIT_CFG_SIZE;
+        result[i..i + RPM_LPF_CFG_SIZE].clone_from_slice(&self.rpm_lpf.to_bytes());
+
+        let i = i + RPM_LPF_CFG_SIZE;
+        result[i..i + BATT_FAILSAFE_CFG_SIZE].clone_from_slice(&self.batt_failsafe.to_bytes());
+
+        let i = i + BATT_FAILSAFE_CFG_SIZE;
+        result[i..i + IMU_CFG_SIZE].clone_from_slice(&self.imu_cfg.to_bytes());
+
+        let i = i + IMU_CFG_SIZE;
+        result[i] = self.speed_units as u8;
+
+        let i = i + 1;
+        result[i..i + DESAT_CFG_SIZE].clone_from_slice(&self.desat.to_bytes());
+
+        let i = i + DESAT_CFG_SIZE;
+        result[i..i + AUTO_LAUNCH_CFG_SIZE].clone_from_slice(&self.auto_launch.to_bytes());
+
+        let i = i + AUTO_LAUNCH_CFG_SIZE;
+        result[i..i + LINK_FAILSAFE_CFG_SIZE].clone_from_slice(&self.link_failsafe.to_bytes());
+
+        let i = i + LINK_FAILSAFE_CFG_SIZE;
+        result[i..i + FUSION_CFG_SIZE].clone_from_slice(&self.fusion.to_bytes());
+
+        let i = i + FUSION_CFG_SIZE;
+        result[i..i + AUTHORITY_CFG_SIZE].clone_from_slice(&self.authority.to_bytes());
+
+        let i = i + AUTHORITY_CFG_SIZE;
+        result[i..i + RTH_CFG_SIZE].clone_from_slice(&self.rth.to_bytes());
+
+        let i = i + RTH_CFG_SIZE;
+        result[i..i + DUAL_IMU_CFG_SIZE].clone_from_slice(&self.dual_imu.to_bytes());
+
+        let i = i + DUAL_IMU_CFG_SIZE;
+        result[i..i + ARM_CHECK_CFG_SIZE].clone_from_slice(&self.arm_check.to_bytes());
 
         result
     }
 
     pub fn save(&self, flash: &mut Flash) {
-        flash.erase_page(Bank::B1, crate::FLASH_CFG_PAGE).ok();
+        let success =
+            storage::write_onboard_page(flash, crate::FLASH_CFG_PAGE, &self.to_bytes()).is_ok();
 
-        flash
-            .write_page(Bank::B1, crate::FLASH_CFG_PAGE, &self.to_bytes())
-            .ok();
+        event_log::log(EventCode::ConfigSave, success as u16, 0);
     }
 
     pub fn load(flash: &mut Flash) -> Self {
@@ -259,7 +1600,21 @@ impl UserConfig {
 #[derive(Default)]
 pub struct StateVolatile {
     pub arm_status: ArmStatus,
+    /// When IMU data went stale while armed, in s. See `safety::execute_imu_failure`.
+    pub imu_fail_start: Option<f32>,
     pub op_mode: OperationMode,
+    /// Lost-link recovery is in control of autopilot modes. See `update_flight_modes`.
+    pub link_lost_recovery: bool,
+    /// Return-to-home progress, while link-lost recovery, the low-battery failsafe, or follow-me
+    /// is returning.
+    pub rth: Rth,
+    /// Holds the last attitude and throttle commanded on losing channel data, then marks the link
+    /// lost.
+    pub link_failsafe: LinkFailsafe,
+    /// Applies the fusion gain, and monitors attitude convergence.
+    pub fusion_monitor: FusionMonitor,
+    /// Attitude and throttle commands from a companion computer.
+    pub ext_ctrl: ExtCtrl,
     #[cfg(feature = "quad")] // todo: Why is this quad only?
     pub input_mode: InputMode,
     pub input_mode_switch: InputModeSwitch,
@@ -280,12 +1635,18 @@ pub struct StateVolatile {
     pub ctrl_mix: CtrlMix,
     /// We use this to determine if we can unlock the attitude controls from the takeoff attitude.
     pub has_taken_off: bool,
+    #[cfg(feature = "fixed-wing")]
+    pub launch_detect: LaunchDetect,
+    #[cfg(feature = "fixed-wing")]
+    pub auto_launch: AutoLaunch,
     /// Angular drag coefficient, continuously updated.
     pub angular_drag_coeff: f32,
     pub batt_v: f32,      // volts
     pub esc_current: f32, // amps
-    /// Drag calculated drag coefficients from flight params.
+    /// Drag calculated drag coefficients from flight params. Set by `authority`.
     pub drag_coeffs: DragCoeffs,
+    /// Per-axis control authority scaling, from measured effectiveness.
+    pub authority: Authority,
     /// We log angular acceleration vice control data (RPM deltas, or servo commands/positions) as part
     /// of the control-effect model in our flight-controls system
     /// Relates motor pair delta RPM difference to angular acceleration for quads, or servo settings
@@ -295,13 +1656,50 @@ pub struct StateVolatile {
     pub pressure_static: f32,
     /// Temperature, in K, measured by the barometer
     pub temp_baro: f32,
+    /// IMU die temperature, in °C. Used for gyro temperature compensation.
+    pub imu_temp: f32,
+    /// Tracks the two-temperature gyro bias calibration, when active.
+    pub gyro_temp_cal: GyroTempCal,
+    /// Logs magnetometer readings during a figure-eight calibration.
+    pub mag_cal_collector: MagCalCollector,
     /// Holds all motor and servo mappings and state.
     /// todo: Mappings are more of a User Cfg functionality
     pub motor_servo_state: MotorServoState,
+    /// Filtered RPM readings, and their rate of change, for control use.
+    pub rpm_lpf: RpmLpf,
     /// Use this, in combination with arm status, and `MotorServoState`.
     pub preflight_motors_running: bool,
+    /// Estimated throttle required to hover. Quad only; on fixed-wing, this stays at the saved
+    /// value.
+    pub hover_throttle_est: HoverThrottleEst,
+    /// Estimated wind, learned during position hold. Quad only.
+    pub wind_est: WindEst,
+    /// The last known position, saved to flash.
+    pub lost_craft: LostCraft,
+    /// The flight timer, and cumulative airframe statistics.
+    pub flight_stats: FlightStatsState,
+    /// Ground speed, distance flown, and distance and bearing to home, from GNSS.
+    pub gps_metrics: GpsMetrics,
+    /// Active warnings, and the OSD ticker.
+    pub warnings: Warnings,
+    /// Debug output channels enabled, with the `print-status` feature. Set over USB.
+    pub log_channels: LogChannels,
+    /// Per-flight motor and servo averages, saved with flight stats.
+    pub health_trend: HealthTrend,
+    pub brownout: BrownoutDetect,
+    pub batt_failsafe: BattFailsafe,
+    pub profile_switch: ProfileSwitch,
+    pub adc_readings: AdcReadings,
+    /// Whether the accelerometer is used in attitude fusion.
+    pub accel_health: AccelHealth,
+    /// Set when a motor fails in flight; cleared on disarm. See `motor_failure`.
     #[cfg(feature = "quad")]
-    pub estimated_hover_power: f32,
+    pub motor_failed: Option<RotorPosition>,
+    #[cfg(feature = "quad")]
+    pub motor_failure: MotorFailureDetect,
+    /// The portion of the yaw command removed by mixer desaturation, 0. to 1. See `mixer`. Quad
+    /// only; stays 0. on fixed-wing.
+    pub yaw_removed: f32,
     #[cfg(feature = "quad")]
     /// Per motor.
     pub estimated_hover_rpm: f32,
@@ -310,4 +1708,52 @@ pub struct StateVolatile {
     // /// Todo: Along these lines, you probably don't want to update target attitude each
     // pub att_cmd_history: [Quaternion; crate::TORQUE_CMD_UPDATE_RATIO as usize],
     pub pid_state_rate: PidStateRate,
+    pub blackbox: Blackbox,
+    pub esc_telemetry: EscTelemetryState,
+    pub perf_stats: PerfStats,
+    /// DMA transfer and FIFO error counts.
+    pub dma_stats: DmaStats,
+    pub motor_test: MotorTest,
+    /// Stick range and center calibration, started over USB.
+    pub input_cal_collector: InputCalCollector,
+    /// Stick gestures, while disarmed, for actions on the field.
+    pub stick_cmds: StickCmds,
+    pub thrust_comp: ThrustComp,
+    pub dyn_idle: DynIdle,
+    /// For motor RPM control.
+    pub motor_pid_state: MotorPidGroup,
+    /// Synthetic airspeed, from GPS and heading. Fixed-wing only.
+    pub airspeed_est: AirspeedEst,
+    /// Fixed-wing only.
+    pub stall_protect: StallProtect,
+    /// On-demand preflight sensor self-test, started over USB.
+    pub self_test: SelfTest,
+    /// On-demand preflight vibration analysis, started over USB.
+    pub vib_test: VibTest,
+    /// Preflight motor order and direction setup, driven over USB.
+    pub motor_wizard: MotorWizard,
+    /// Preflight motor and servo test patterns, started over USB.
+    pub output_pattern: OutputPattern,
+    /// Altitude MSL and AGL, fused from the baro, GPS, and TOF sensor.
+    pub alt_est: AltEstimator,
+    /// Field elevation, captured on arming, or set over USB; for altitude above takeoff.
+    pub ground_ref: GroundRef,
+    /// Streams telemetry snapshots over USB, once the PC enables it.
+    pub telem_stream: TelemStream,
+    pub watchdog: Watchdog,
+    #[cfg(feature = "quad")]
+    pub turtle: TurtleMode,
+    /// Quad only.
+    pub acro_trainer: AcroTrainer,
+    #[cfg(feature = "quad")]
+    pub follow_me: FollowMe,
+    #[cfg(feature = "quad")]
+    pub tipover: TipoverDetect,
+    #[cfg(feature = "quad")]
+    pub arm_check: ArmCheck,
+    /// Set when the autopilot disarms on landing.
+    #[cfg(feature = "quad")]
+    pub touchdown_latch: ArmLatch,
+    pub imu_integrity: ImuIntegrity,
+    pub dual_imu: DualImu,
 }
@@ -0,0 +1,220 @@
+//! This module contains an abstraction over non-volatile storage, so the blackbox can use either
+//! the MCU's onboard flash, or the external SPI flash.
+//!
+//! User config stays on onboard flash: It's loaded before the external flash is brought up, and
+//! contains the storage selection itself.
+//!
+//! Erases and writes are performed a page or sector at a time. We check for a brownout before
+//! each, and stop if one has been detected; see `brownout`. Onboard erases stall the CPU, so we
+//! extend the watchdog timeout around them.
+//!
+//! That's as fine-grained as interrupting gets: An erase, once started, runs to completion, since
+//! neither the onboard flash nor our external flash driver supports suspending one. On H7, an
+//! onboard page is a 128kb sector. Erasing the whole external chip uses the chip erase command,
+//! which is several times faster than erasing each sector, but can't be stopped; we only do this
+//! on request from the PC, while disarmed.
+
+use defmt::println;
+use hal::flash::{Bank, Flash};
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    brownout,
+    drivers::flash_spi::{self, ExtFlash, FlashSpiError},
+    watchdog,
+};
+
+/// We write onboard flash a page at a time, and treat each as this size.
+pub const ONBOARD_PAGE_SIZE: usize = 4_096;
+
+#[derive(Clone, Copy)]
+pub enum StorageError {
+    /// The address range is outside of the region.
+    OutOfRange,
+    Hardware,
+    /// A brownout was detected, so we didn't start, or stopped partway.
+    Brownout,
+}
+
+impl From<FlashSpiError> for StorageError {
+    fn from(e: FlashSpiError) -> Self {
+        match e {
+            FlashSpiError::OutOfRange => Self::OutOfRange,
+            _ => Self::Hardware,
+        }
+    }
+}
+
+/// Stop before the next erase or write, if the battery is collapsing.
+fn check_brownout() -> Result<(), StorageError> {
+    if brownout::active() {
+        Err(StorageError::Brownout)
+    } else {
+        Ok(())
+    }
+}
+
+/// Erase a page of onboard flash, and write a record to its start. For single-page records, like
+/// user config. Blocking, and slow.
+pub fn write_onboard_page(flash: &mut Flash, page: usize, data: &[u8]) -> Result<(), StorageError> {
+    // Check once, before the erase: Once erased, we finish the write, which is quick, vice
+    // leaving the page without a record.
+    check_brownout()?;
+    watchdog::flash_op(|| flash.erase_page(Bank::B1, page)).map_err(|_| StorageError::Hardware)?;
+
+    flash
+        .write_page(Bank::B1, page, data)
+        .map_err(|_| StorageError::Hardware)
+}
+
+/// A region of flash. Addresses are in bytes, relative to the region's start.
+pub trait NonVolatileStorage {
+    /// Size of the region, in bytes.
+    fn size(&self) -> usize;
+    /// Erase a region; it then reads as 0xff. `addr` and `len` must be multiples of the
+    /// backend's erase size. Blocking, and slow.
+    fn erase_region(&mut self, addr: usize, len: usize) -> Result<(), StorageError>;
+    /// Write to an erased region. `addr` must be a multiple of `ONBOARD_PAGE_SIZE` for onboard
+    /// flash.
+    fn write_region(&mut self, addr: usize, data: &[u8]) -> Result<(), StorageError>;
+    fn read_region(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), StorageError>;
+}
+
+/// Where to store the blackbox log. Applied at init, so changes take effect after a restart.
+#[derive(Clone, Copy, PartialEq, Default, TryFromPrimitive)]
+#[repr(u8)]
+pub enum StorageBackend {
+    /// External flash if it's detected; onboard otherwise.
+    #[default]
+    Auto = 0,
+    Onboard = 1,
+    /// If the external flash isn't detected, we fall back to onboard.
+    External = 2,
+}
+
+impl StorageBackend {
+    /// Select a backend based on this setting, and whether the external flash was detected. Returns
+    /// the backend, and whether we fell back from an explicit external selection.
+    pub fn resolve(self, ext_detected: bool) -> (Self, bool) {
+        match self {
+            Self::Onboard => (Self::Onboard, false),
+            Self::Auto if ext_detected => (Self::External, false),
+            Self::Auto => (Self::Onboard, false),
+            Self::External if ext_detected => (Self::External, false),
+            Self::External => {
+                println!("External flash selected, but not detected; using onboard flash");
+                (Self::Onboard, true)
+            }
+        }
+    }
+}
+
+/// A region of the MCU's onboard flash, consisting of whole pages. Note that the CPU stalls while
+/// this is being written, if it's executing from the same bank.
+pub struct OnboardStorage<'a> {
+    pub flash: &'a mut Flash,
+    pub first_page: usize,
+    pub num_pages: usize,
+}
+
+impl<'a> NonVolatileStorage for OnboardStorage<'a> {
+    fn size(&self) -> usize {
+        self.num_pages * ONBOARD_PAGE_SIZE
+    }
+
+    fn erase_region(&mut self, addr: usize, len: usize) -> Result<(), StorageError> {
+        if addr + len > self.size() {
+            return Err(StorageError::OutOfRange);
+        }
+
+        for page in addr / ONBOARD_PAGE_SIZE..(addr + len).div_ceil(ONBOARD_PAGE_SIZE) {
+            check_brownout()?;
+            watchdog::flash_op(|| self.flash.erase_page(Bank::B1, self.first_page + page))
+                .map_err(|_| StorageError::Hardware)?;
+        }
+        Ok(())
+    }
+
+    fn write_region(&mut self, addr: usize, data: &[u8]) -> Result<(), StorageError> {
+        if addr + data.len() > self.size() || addr % ONBOARD_PAGE_SIZE != 0 {
+            return Err(StorageError::OutOfRange);
+        }
+
+        for (i, chunk) in data.chunks(ONBOARD_PAGE_SIZE).enumerate() {
+            check_brownout()?;
+            self.flash
+                .write_page(
+                    Bank::B1,
+                    self.first_page + addr / ONBOARD_PAGE_SIZE + i,
+                    chunk,
+                )
+                .map_err(|_| StorageError::Hardware)?;
+        }
+        Ok(())
+    }
+
+    fn read_region(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        if addr + buf.len() > self.size() {
+            return Err(StorageError::OutOfRange);
+        }
+
+        // Split reads that cross a page boundary, since pages may not be contiguous.
+        let mut i = 0;
+        while i < buf.len() {
+            let page = (addr + i) / ONBOARD_PAGE_SIZE;
+            let offset = (addr + i) % ONBOARD_PAGE_SIZE;
+            let len = (ONBOARD_PAGE_SIZE - offset).min(buf.len() - i);
+
+            self.flash.read(
+                Bank::B1,
+                self.first_page + page,
+                offset,
+                &mut buf[i..i + len],
+            );
+            i += len;
+        }
+        Ok(())
+    }
+}
+
+/// The whole external flash chip.
+impl NonVolatileStorage for ExtFlash {
+    fn size(&self) -> usize {
+        self.capacity()
+    }
+
+    fn erase_region(&mut self, addr: usize, len: usize) -> Result<(), StorageError> {
+        check_brownout()?;
+
+        if addr == 0 && len == self.capacity() {
+            self.erase_chip()?;
+            return Ok(());
+        }
+
+        let mut sector = addr - addr % flash_spi::SECTOR_SIZE;
+        while sector < addr + len {
+            check_brownout()?;
+            self.erase_sector(sector)?;
+            sector += flash_spi::SECTOR_SIZE;
+        }
+        Ok(())
+    }
+
+    fn write_region(&mut self, addr: usize, data: &[u8]) -> Result<(), StorageError> {
+        // Split into chunks that don't cross page boundaries.
+        let mut i = 0;
+        while i < data.len() {
+            let len =
+                (flash_spi::PAGE_SIZE - (addr + i) % flash_spi::PAGE_SIZE).min(data.len() - i);
+            check_brownout()?;
+            self.program_page(addr + i, &data[i..i + len])?;
+            i += len;
+        }
+        Ok(())
+    }
+
+    fn read_region(&mut self, addr: usize, buf: &mut [u8]) -> Result<(), StorageError> {
+        self.fast_read(addr, buf)?;
+        Ok(())
+    }
+}
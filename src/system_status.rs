@@ -2,6 +2,13 @@
 
 use core::sync::atomic::AtomicBool;
 
+use crate::{
+    batt_failsafe::BattStage,
+    event_log::{self, EventCode},
+    imu_processing::{filter_imu::NUM_RPM_NOTCH_MOTORS, fusion::AttConvergence},
+    safety::PrearmStatus,
+};
+
 // A problem with the CRSF control data packet.
 pub static RX_FAULT: AtomicBool = AtomicBool::new(false);
 
@@ -9,34 +16,91 @@ pub static RX_FAULT: AtomicBool = AtomicBool::new(false);
 pub static RPM_FAULT: AtomicBool = AtomicBool::new(false);
 
 // These times are used to trigger faults if it's been too long since a given
-// update. They are in seconds.
-pub const MAX_UPDATE_PERIOD_IMU: f32 = 1. / crate::main_loop::DT_IMU + 0.0001;
-pub const MAX_UPDATE_PERIOD_GNSS: f32 = 0.4;
-pub const MAX_UPDATE_PERIOD_BARO: f32 = 0.5;
+// update. They are in seconds. They're checked by the sensor watchdog, independent of the IMU.
+pub const MAX_UPDATE_PERIOD_IMU: f32 = 0.005;
+pub const MAX_UPDATE_PERIOD_GNSS: f32 = 1.;
+pub const MAX_UPDATE_PERIOD_BARO: f32 = 0.1;
 pub const MAX_UPDATE_PERIOD_MAG: f32 = 0.4;
-pub const MAX_UPDATE_PERIOD_RC_LINK: f32 = 0.3;
 pub const MAX_UPDATE_PERIOD_OSD: f32 = 1.;
+pub const MAX_UPDATE_PERIOD_TOF: f32 = 0.2;
+pub const MAX_UPDATE_PERIOD_ESC_TELEM: f32 = 0.2;
+
+// After this many I2C errors in a row with a device, we stop polling it.
+pub const I2C_MAX_CONSECUTIVE_ERRORS: u8 = 10;
+// After an I2C error, we skip the device for this long, in seconds, so the others on its bus
+// continue.
+pub const I2C_ERROR_BACKOFF: f32 = 0.5;
 
 // We have these faults as atomics so as to not require locking a more-generally-used struct.
 
 use defmt::println;
 
+/// Returns `true` if the sensor went stale this update; ie we've received data from it previously,
+/// but not recently.
 fn set_status(
     status: &mut SensorStatus,
     timestamp_current: f32,
     timestamp_update: Option<f32>,
     max_update_period: f32,
-) {
+) -> bool {
     match timestamp_update {
         Some(t) => {
             if timestamp_current - t > max_update_period {
+                let went_stale = *status != SensorStatus::NotConnected;
                 *status = SensorStatus::NotConnected;
+                went_stale
             } else {
                 *status = SensorStatus::Pass;
+                false
             }
         }
         None => {
             *status = SensorStatus::NotConnected;
+            false
+        }
+    }
+}
+
+/// The number of times each sensor's data went stale since power-on. Reported over USB.
+#[derive(Default)]
+pub struct StaleCounts {
+    pub imu: u16,
+    pub baro: u16,
+    pub gps: u16,
+    pub mag: u16,
+    pub tof: u16,
+}
+
+/// Error tracking for a device we poll over I2C with DMA.
+#[derive(Default)]
+pub struct I2cDeviceHealth {
+    /// Errors since power-on, including stalled transfers.
+    pub error_count: u16,
+    pub consecutive_errors: u8,
+    /// Set after `I2C_MAX_CONSECUTIVE_ERRORS`; we no longer poll the device.
+    pub failed: bool,
+    /// Seconds since start. We skip the device until this time.
+    backoff_until: f32,
+}
+
+impl I2cDeviceHealth {
+    /// If we should start a transfer with this device.
+    pub fn available(&self, timestamp: f32) -> bool {
+        !self.failed && timestamp >= self.backoff_until
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_errors = 0;
+    }
+
+    pub fn record_error(&mut self, timestamp: f32) {
+        self.error_count = self.error_count.saturating_add(1);
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        self.backoff_until = timestamp + I2C_ERROR_BACKOFF;
+
+        if self.consecutive_errors >= I2C_MAX_CONSECUTIVE_ERRORS && !self.failed {
+            self.failed = true;
+            println!("I2C device failed; no longer polling it");
         }
     }
 }
@@ -50,13 +114,50 @@ pub struct SystemStatus {
     pub baro_can: SensorStatus,
     /// The GPS module is connected. Detected on init.
     pub gnss_can: SensorStatus,
-    /// The time-of-flight sensor module is connected. Detected on init.
+    /// GPS module on our I2C bus. `Pass` if we have a recent 3D fix with acceptable accuracy;
+    /// `Fault` if we're receiving fixes that don't meet that.
+    pub gps: SensorStatus,
+    /// The GPS module responded on init, but didn't accept our configuration. If set, `gps` is
+    /// `Fault` until we receive usable fixes anyway.
+    pub gps_config_failed: bool,
+    /// The time-of-flight sensor module is connected. Detected on init. `Fault` if the latest reading
+    /// was invalid, or is stale.
     pub tof: SensorStatus,
-    ///  magnetometer is connected. Likely on the same module as GPS. Detected on init.
-    // pub magnetometer: SensorStatus,
+    /// The magnetometer on our I2C bus is connected. Likely on the same module as GPS. Detected
+    /// on init.
+    pub magnetometer: SensorStatus,
     pub magnetometer_can: SensorStatus,
+    /// UART telemetry from the ESCs. `Fault` if only some ESCs are reporting.
     pub esc_telemetry: SensorStatus,
+    /// An ESC reports a temperature above `esc_telemetry::OVER_TEMP_THRESH`.
+    pub esc_over_temp: bool,
+    /// Battery voltage or current from the ADC disagrees with ESC telemetry.
+    pub batt_meas_mismatch: bool,
+    /// The IMU ISR's execution time exceeded its period more than
+    /// `perf_stats::MAX_OVERRUNS_PER_WINDOW` times in the past second.
+    pub imu_isr_overrun: bool,
+    /// The measured IMU update interval deviates from the configured rate by more than
+    /// `loop_rates::IMU_INTERVAL_TOLERANCE`.
+    pub imu_rate_mismatch: bool,
+    /// The previous session ended in a brownout. See `brownout`.
+    pub prev_brownout: bool,
+    /// The prearm switch state, from the latest channel data. Displayed so setup problems are
+    /// visible.
+    pub prearm: PrearmStatus,
+    /// The last on-demand preflight self-test found a fault. See `self_test::SelfTestReport`.
+    pub self_test_fault: bool,
+    /// The accelerometer is unhealthy; attitude is propagated from the gyro only. See
+    /// `accel_health`.
+    pub accel_fault: bool,
+    /// Whether the attitude estimate agrees with gravity; arming is blocked while aligning. See
+    /// `fusion`.
+    pub att_convergence: AttConvergence,
+    /// The low-battery failsafe's stage. See `batt_failsafe`.
+    pub batt_stage: BattStage,
     pub esc_rpm: SensorStatus,
+    /// By motor, in the order of `MotorServoState::rotor_rpms`: The motor is commanded above a
+    /// threshold power, but reports near-zero RPM. See `motor_servo::DESYNC_TIME`.
+    pub esc_desync: [bool; NUM_RPM_NOTCH_MOTORS],
     pub esc_can: SensorStatus,
     pub servos_can: SensorStatus,
     pub rf_control_link: SensorStatus, // todo: For now, we use `link_lost` instead.
@@ -65,50 +166,121 @@ pub struct SystemStatus {
     // todo: You should have more specific faults than this. Eg what went wrong.
     // pub rf_control_fault: bool,
     // pub esc_rpm_fault: bool,
-    /// SPI flash, which we may use in the future for data logging.
+    /// External SPI flash, used for the blackbox log.
     pub flash_spi: SensorStatus,
+    /// External flash was selected for the blackbox log, but not detected; we're logging to
+    /// onboard flash.
+    pub ext_flash_fallback: bool,
     pub osd: SensorStatus,
+    /// True if magnetometer readings are currently being passed to the AHRS. False if
+    /// there's no recent reading, or if the latest was rejected as disturbed.
+    pub mag_applied: bool,
     pub update_timestamps: UpdateTimestamps,
+    pub stale_counts: StaleCounts,
+    pub i2c_baro: I2cDeviceHealth,
+    pub i2c_gps: I2cDeviceHealth,
+    pub i2c_tof: I2cDeviceHealth,
+    pub i2c_mag: I2cDeviceHealth,
+    /// Onsets of implausible IMU data, eg all-zero readings, or a stuck gyro.
+    pub imu_implausible_count: u16,
+    /// Times we've re-initialized the IMU after implausible data, or a failed register readback.
+    pub imu_recoveries: u16,
+    /// IMU registers read back after setup don't match the configured full-scale ranges and
+    /// filters. Readings may be scaled or filtered incorrectly.
+    pub imu_cfg_mismatch: bool,
+    /// The secondary IMU, if enabled; see `dual_imu`. `Fault` if its readings are stale or
+    /// implausible.
+    pub imu_secondary: SensorStatus,
+    /// We've switched IMUs since power-up.
+    pub imu_failed_over: bool,
 }
 
 impl SystemStatus {
+    /// We have position data good enough to navigate with, from either GNSS source.
+    pub fn gnss_usable(&self) -> bool {
+        self.gps == SensorStatus::Pass || self.gnss_can == SensorStatus::Pass
+    }
+
+    /// Set sensor status based on how recently we've received data from each. This runs from the
+    /// sensor watchdog, so it continues if the IMU stops, along with the main loop.
     pub fn update_from_timestamp(&mut self, timestamp: f32) {
-        set_status(
+        if set_status(
             &mut self.imu,
             timestamp,
             self.update_timestamps.imu,
             MAX_UPDATE_PERIOD_IMU,
-        );
-        set_status(
+        ) {
+            self.stale_counts.imu = self.stale_counts.imu.saturating_add(1);
+            event_log::log(EventCode::SensorStale, 0, 0);
+        }
+        if set_status(
             &mut self.baro,
             timestamp,
             self.update_timestamps.baro,
             MAX_UPDATE_PERIOD_BARO,
-        );
+        ) {
+            self.stale_counts.baro = self.stale_counts.baro.saturating_add(1);
+            event_log::log(EventCode::SensorStale, 1, 0);
+        }
         set_status(
             &mut self.baro_can,
             timestamp,
             self.update_timestamps.baro_can,
             MAX_UPDATE_PERIOD_BARO,
         );
-        // set_status(
-        //     &mut self.magnetometer,
-        //     timestamp,
-        //     self.update_timestamps.mag,
-        //     MAX_UPDATE_PERIOD_MAG,
-        // );
-        set_status(
+        // As with the TOF sensor below, an I2C magnetometer detected at init stays `Fault` when
+        // stale, so we keep polling it. `Pass` is set as each reading arrives.
+        if self.magnetometer != SensorStatus::NotConnected {
+            match self.update_timestamps.mag {
+                Some(t) if timestamp - t <= MAX_UPDATE_PERIOD_MAG => (),
+                Some(_) if self.magnetometer == SensorStatus::Pass => {
+                    self.magnetometer = SensorStatus::Fault;
+                    self.stale_counts.mag = self.stale_counts.mag.saturating_add(1);
+                    event_log::log(EventCode::SensorStale, 3, 0);
+                }
+                _ => self.magnetometer = SensorStatus::Fault,
+            }
+        }
+        if set_status(
             &mut self.magnetometer_can,
             timestamp,
             self.update_timestamps.mag_can,
             MAX_UPDATE_PERIOD_MAG,
-        );
+        ) {
+            self.stale_counts.mag = self.stale_counts.mag.saturating_add(1);
+            event_log::log(EventCode::SensorStale, 3, 0);
+        }
+        if self.magnetometer != SensorStatus::Pass && self.magnetometer_can != SensorStatus::Pass {
+            self.mag_applied = false;
+        }
         set_status(
             &mut self.gnss_can,
             timestamp,
             self.update_timestamps.gnss_can,
             MAX_UPDATE_PERIOD_GNSS,
         );
+        // `gps` is set to `Pass` or `Fault` based on fix quality as each fix arrives; here, we only
+        // check for staleness.
+        if let Some(t) = self.update_timestamps.gps {
+            if timestamp - t > MAX_UPDATE_PERIOD_GNSS && self.gps != SensorStatus::NotConnected {
+                self.gps = SensorStatus::NotConnected;
+                self.stale_counts.gps = self.stale_counts.gps.saturating_add(1);
+                event_log::log(EventCode::SensorStale, 2, 0);
+            }
+        }
+        // A TOF sensor detected at init stays `Fault` (vice `NotConnected`) when stale, so we keep
+        // polling it. `Pass` and `Fault` are otherwise set as each reading arrives.
+        if self.tof != SensorStatus::NotConnected {
+            match self.update_timestamps.tof {
+                Some(t) if timestamp - t <= MAX_UPDATE_PERIOD_TOF => (),
+                Some(_) if self.tof == SensorStatus::Pass => {
+                    self.tof = SensorStatus::Fault;
+                    self.stale_counts.tof = self.stale_counts.tof.saturating_add(1);
+                    event_log::log(EventCode::SensorStale, 4, 0);
+                }
+                _ => self.tof = SensorStatus::Fault,
+            }
+        }
         set_status(
             &mut self.osd,
             timestamp,
@@ -139,12 +311,18 @@ impl Default for SensorStatus {
 pub struct UpdateTimestamps {
     pub imu: Option<f32>,
     pub gnss_can: Option<f32>,
+    pub gps: Option<f32>,
+    pub tof: Option<f32>,
     pub baro: Option<f32>,
     pub baro_can: Option<f32>,
+    pub mag: Option<f32>,
     pub mag_can: Option<f32>,
     pub imu_can: Option<f32>,
     pub ahrs_can: Option<f32>,
+    /// Control channel data. Timeouts are set in `LinkFailsafeCfg`.
     pub rf_control_link: Option<f32>,
+    /// Any packet from the receiver, including ones without channel data; eg link stats.
+    pub rf_packet: Option<f32>,
     pub flight_ctrls: Option<f32>, // not really a sensor, but useful to track.
     pub osd: Option<f32>,          // not really a sensor, but useful to track.
 }
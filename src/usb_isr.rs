@@ -0,0 +1,206 @@
+//! This module contains the USB ISR's body: Polling the USB device, and dispatching data from the
+//! PC, eg for configuring using a desktop application, to the MSP, framed, or legacy unframed
+//! handlers. The ISR itself is bound per MCU in `main`, since the interrupt differs: `OTG_HS` on
+//! H723, `OTG_FS` on H743, and `USB_LP` on G4. Each binding shares the same resources, and calls
+//! `run`.
+
+use cfg_if::cfg_if;
+use rtic::mutex_prelude::*;
+use usb_device::prelude::*;
+
+use crate::{
+    app,
+    protocols::{msp, msp_usb, usb_frame, usb_preflight, usb_telem},
+    util::monotonic,
+};
+
+cfg_if! {
+    if #[cfg(feature = "h723")] {
+        type Context<'a> = app::usb_otg_hs_isr::Context<'a>;
+    } else if #[cfg(feature = "h743")] {
+        type Context<'a> = app::usb_otg_fs_isr::Context<'a>;
+    } else {
+        type Context<'a> = app::usb_lp_isr::Context<'a>;
+    }
+}
+
+/// This handles interaction over the USB serial port. Its ISR should be a high priority, or the
+/// host may disconnect the device for not responding quickly enough. If the priority is too low,
+/// the PC interface software will behave strangely, so this is somewhat self-critiquing.
+/// *It appears we need to set this to be a lower priority than IMU data, but higher than IMU TC.
+pub fn run(mut cx: Context) {
+    // todo: Do we want to use an approach where we push stats, or this approach where
+    // todo respond only?
+    (
+        cx.shared.usb_dev,
+        cx.shared.usb_serial,
+        cx.shared.params,
+        cx.shared.control_channel_data,
+        cx.shared.link_stats,
+        cx.shared.user_cfg,
+        cx.shared.state_volatile,
+        cx.shared.system_status,
+        cx.shared.autopilot_status,
+        cx.shared.motor_timer,
+        cx.shared.servo_timer,
+        cx.shared.flash_onboard,
+        cx.shared.flash_ext,
+        cx.shared.calibrating_accel,
+        cx.shared.imu_filters,
+        // cx.shared.rpm_readings,
+    )
+        .lock(
+            |usb_dev,
+             usb_serial,
+             params,
+             ch_data,
+             link_stats,
+             user_cfg,
+             state,
+             system_status,
+             autopilot_status,
+             motor_timer,
+             servo_timer,
+             flash,
+             flash_ext,
+             calibrating_accel,
+             imu_filters,
+             // rpm_readings
+            | {
+                let data_ready = usb_dev.poll(&mut [usb_serial]);
+
+                // Eg the cable was unplugged, or the host suspended the device.
+                if usb_dev.state() != UsbDeviceState::Configured {
+                    usb_telem::stop();
+                    usb_frame::reset();
+                    cx.local.usb_decoder.reset();
+                }
+
+                if !data_ready {
+                    return;
+                }
+
+                let mut buf = [0u8; 128]; // todo: Adjust this A/R!!!
+                let read = usb_serial.read(&mut buf);
+
+                if let Ok(count) = read {
+                    if count > 0 {
+                        cx.local.usb_decoder.rx(monotonic::now_us());
+                    }
+                }
+
+                match read {
+                    // MSP requests start with its preamble; they may span several reads.
+                    Ok(count)
+                        if count > 0
+                            && !cx.local.usb_decoder.in_frame()
+                            && (buf[0] == msp::PREAMBLE_0 || cx.local.msp_parser.in_frame()) =>
+                    {
+                        for byte in &buf[..count] {
+                            if let Some(request) = cx.local.msp_parser.feed(*byte) {
+                                msp_usb::handle_request(
+                                    usb_serial,
+                                    &request,
+                                    params,
+                                    ch_data,
+                                    link_stats,
+                                    state,
+                                    system_status,
+                                );
+                            }
+                        }
+                    }
+                    Ok(count) => {
+                        // Unframed messages, from PC software that predates framing, arrive
+                        // whole, in a single read.
+                        let unframed = count > 0
+                            && buf[0] == anyleaf_usb::MSG_START
+                            && !cx.local.usb_decoder.in_frame();
+                        if unframed {
+                            usb_frame::handle_unframed(usb_serial);
+                        }
+
+                        let mut i = 0;
+                        while i < count {
+                            let msg = if unframed {
+                                i = count;
+                                usb_preflight::parse_unframed(&buf[..count])
+                            } else {
+                                i += 1;
+                                cx.local.usb_decoder.feed(buf[i - 1]).and_then(|frame| {
+                                    usb_frame::check_frame(&frame)
+                                        .map(|msg_type| (msg_type, frame.payload))
+                                })
+                            };
+
+                            let Some((msg_type, payload)) = msg else {
+                                continue;
+                            };
+
+                            usb_preflight::handle_rx(
+                                usb_serial,
+                                msg_type,
+                                payload,
+                                params.attitude,
+                                (params.v_pitch, params.v_roll, params.v_yaw),
+                                &state.attitude_commanded,
+                                params.alt_msl_baro,
+                                state.pressure_static,
+                                state.temp_baro,
+                                state.imu_temp,
+                                params.alt_tof,
+                                state.batt_v,
+                                state.esc_current,
+                                ch_data,
+                                &link_stats,
+                                user_cfg,
+                                system_status,
+                                autopilot_status,
+                                &mut state.arm_status,
+                                state.has_taken_off,
+                                // &mut user_cfg.control_mapping,
+                                &mut state.op_mode,
+                                motor_timer,
+                                servo_timer,
+                                &mut state.motor_servo_state,
+                                &mut state.preflight_motors_running,
+                                flash,
+                                flash_ext,
+                                calibrating_accel,
+                                &mut state.gyro_temp_cal,
+                                &mut state.mag_cal_collector,
+                                imu_filters.gyro_lpfs_implemented(),
+                                &mut state.blackbox,
+                                &state.esc_telemetry,
+                                &mut state.motor_test,
+                                &mut state.input_cal_collector,
+                                &state.thrust_comp,
+                                &mut state.self_test,
+                                &state.hover_throttle_est,
+                                &state.wind_est,
+                                &state.lost_craft,
+                                &mut state.vib_test,
+                                &mut state.motor_wizard,
+                                &mut state.output_pattern,
+                                &state.alt_est,
+                                &state.flight_stats,
+                                &state.health_trend,
+                                &state.adc_readings,
+                                &state.dma_stats,
+                                &state.accel_maps,
+                                &mut state.warnings,
+                                &mut state.log_channels,
+                                &mut state.ground_ref,
+                                &state.fusion_monitor,
+                                &state.ext_ctrl,
+                                &state.authority,
+                            );
+                        }
+                    }
+                    Err(_) => {
+                        // println!("Error reading USB signal from PC");
+                    }
+                }
+            },
+        )
+}
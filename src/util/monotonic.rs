@@ -0,0 +1,84 @@
+//! This module contains a monotonic clock: Microseconds since start, as a 64-bit count that won't
+//! wrap in the life of the aircraft. It's built on the tick timer (TIM5), which counts at 1MHz,
+//! and wraps each `TICK_TIMER_PERIOD`; the tick ISR counts the wraps.
+//!
+//! `now_us` may be called from any priority, without locking the timer: It reads the counter and
+//! its update flag directly. If the counter wrapped and the tick ISR, which runs at the lowest
+//! priority, hasn't counted it yet, the pending flag accounts for it. The tick ISR clears the flag
+//! and counts the wrap together in a critical section, so a reader never sees one without the
+//! other.
+
+use core::sync::atomic::Ordering;
+
+use cortex_m::interrupt;
+use hal::{
+    pac::{self, TIM5},
+    timer::{Timer, TimerInterrupt, TICK_OVERFLOW_COUNT},
+};
+
+use crate::board_config::TIM_CLK_SPEED;
+
+/// Hz. The tick timer's count rate.
+pub const COUNT_FREQ: u32 = 1_000_000;
+
+/// Tick timer counts between wraps.
+pub const PERIOD_COUNTS: u32 = (crate::TICK_TIMER_PERIOD * COUNT_FREQ as f32) as u32;
+
+const PSC: u16 = (TIM_CLK_SPEED / COUNT_FREQ - 1) as u16;
+
+/// Set the tick timer to count at `COUNT_FREQ`, with its period unchanged. Run at init, before
+/// enabling it.
+pub fn setup(timer: &mut Timer<TIM5>) {
+    timer.set_prescaler(PSC);
+    timer.set_auto_reload(PERIOD_COUNTS - 1);
+
+    // Load the prescaler now, instead of at the first wrap. This sets the update flag; it's not a
+    // wrap.
+    timer.reinitialize();
+    timer.clear_interrupt(TimerInterrupt::Update);
+}
+
+/// Run from the tick timer's update ISR.
+pub fn overflow(timer: &mut Timer<TIM5>) {
+    interrupt::free(|_| {
+        timer.clear_interrupt(TimerInterrupt::Update);
+        TICK_OVERFLOW_COUNT.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+/// Microseconds since start. May be called from any priority.
+pub fn now_us() -> u64 {
+    let regs = unsafe { &(*pac::TIM5::ptr()) };
+
+    loop {
+        let overflows = TICK_OVERFLOW_COUNT.load(Ordering::Acquire);
+        let count = regs.cnt.read().bits();
+        let pending = regs.sr.read().uif().bit_is_set();
+        // Read after the flag: If it's set, this is from after the wrap.
+        let count_after = regs.cnt.read().bits();
+
+        // The tick ISR ran between reads; try again.
+        if TICK_OVERFLOW_COUNT.load(Ordering::Acquire) != overflows {
+            continue;
+        }
+
+        let (overflows, count) = if pending {
+            (overflows as u64 + 1, count_after)
+        } else {
+            (overflows as u64, count)
+        };
+
+        return overflows * PERIOD_COUNTS as u64 + count as u64;
+    }
+}
+
+/// Seconds since start. Loses resolution as time passes, like other `f32` timestamps; use
+/// `now_us` for long intervals.
+pub fn now() -> f32 {
+    to_secs(now_us())
+}
+
+/// Convert microseconds to seconds.
+pub fn to_secs(us: u64) -> f32 {
+    us as f32 / COUNT_FREQ as f32
+}
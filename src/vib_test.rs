@@ -0,0 +1,348 @@
+//! This module contains a preflight vibration analysis, started over USB. We record gyro readings
+//! at full IMU rate with all motors stopped, then with each motor spun individually, and report,
+//! for each axis, the RMS noise, and the dominant vibration frequency and its amplitude. This
+//! identifies a problem motor, and the frequency a notch filter should target.
+//!
+//! Motors are spun with the motor test, so its interlocks apply: Preflight mode, disarmed, props-off
+//! acknowledged, the power cap, and the PC's keep-alive messages. If the motor test stops early for
+//! any of these reasons, the analysis is aborted.
+//!
+//! Spectra are computed with a real FFT over blocks of readings, and averaged over the recording
+//! (Welch's method). To keep each IMU update short, we transform one axis per update, and skip
+//! the readings that arrive meanwhile.
+
+use cmsis_dsp_sys as dsp_sys;
+use num_traits::Float;
+
+use crate::{
+    flight_ctrls::motor_test::{MotorTest, MotorTestCmd, MAX_DURATION},
+    loop_rates,
+    protocols::dshot::Motor,
+};
+
+// Seconds. Before each recording, we wait this long with motors stopped, so the gyro settles,
+// and any previous motor has spun down.
+const SETTLE_TIME: f32 = 1.;
+// Seconds. After starting a motor, we wait this long for it to reach a steady speed.
+const SPINUP_TIME: f32 = 0.5;
+// Seconds of readings recorded for each motor. Spin-up and recording must complete within
+// the motor test's `MAX_DURATION`.
+const RECORD_TIME: f32 = 2.;
+
+// FFT block length. With an 8kHz IMU rate, bins are 15.6Hz wide.
+const FFT_LEN: usize = 512;
+const NUM_BINS: usize = FFT_LEN / 2;
+
+// Hz. We ignore peaks below this; they're from motion and drift, vice vibration.
+const MIN_PEAK_FREQ: f32 = 40.;
+
+// Motors stopped, then each motor.
+pub const NUM_VIB_RESULTS: usize = 5;
+
+// For each axis: RMS, peak frequency, and peak amplitude.
+pub const VIB_RESULT_SIZE: usize = 3 * 3 * 4;
+
+const MOTORS: [Motor; 4] = [Motor::M1, Motor::M2, Motor::M3, Motor::M4];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Idle,
+    /// Motors stopped. Value is the result index; 0 is all stopped, then each motor.
+    Settle(usize),
+    Spinup(usize),
+    Record(usize),
+}
+
+/// Reported over USB. Repr is how it's passed.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum VibTestStatus {
+    Idle = 0,
+    Running = 1,
+    Complete = 2,
+    /// The motor test stopped early, eg from a keep-alive timeout, or arming.
+    Aborted = 3,
+}
+
+/// Results from one recording. Axes are pitch, roll, yaw.
+#[derive(Clone, Copy, Default)]
+pub struct VibResult {
+    /// Standard deviation of readings, in rad/s.
+    pub rms: [f32; 3],
+    /// The frequency with the most vibration, in Hz.
+    pub peak_freq: [f32; 3],
+    /// The amplitude at the peak frequency, in rad/s.
+    pub peak_amp: [f32; 3],
+}
+
+impl VibResult {
+    pub fn to_bytes(&self) -> [u8; VIB_RESULT_SIZE] {
+        let mut result = [0; VIB_RESULT_SIZE];
+
+        for axis in 0..3 {
+            let i = axis * 12;
+            result[i..i + 4].clone_from_slice(&self.rms[axis].to_be_bytes());
+            result[i + 4..i + 8].clone_from_slice(&self.peak_freq[axis].to_be_bytes());
+            result[i + 8..i + 12].clone_from_slice(&self.peak_amp[axis].to_be_bytes());
+        }
+        result
+    }
+}
+
+/// Vibration analysis state. Started from the USB ISR, and run from the main loop.
+pub struct VibTest {
+    stage: Stage,
+    status: VibTestStatus,
+    /// Seconds since start.
+    stage_start: f32,
+    /// Power to run each motor at, 0. to 1.
+    power: f32,
+    start_pending: Option<f32>,
+    count: u32,
+    mean: [f32; 3],
+    /// Sum of squared differences from the mean, for each axis (Welford's algorithm).
+    m2: [f32; 3],
+    /// Readings for the current FFT block.
+    block: [[f32; FFT_LEN]; 3],
+    block_i: usize,
+    /// While transforming a full block, the next axis to transform.
+    fft_axis: Option<usize>,
+    /// Summed magnitude spectra, for each axis.
+    spectrum: [[f32; NUM_BINS]; 3],
+    num_blocks: u32,
+    fft_out: [f32; FFT_LEN],
+    fft_mag: [f32; NUM_BINS],
+    window: [f32; FFT_LEN],
+    pub results: [Option<VibResult>; NUM_VIB_RESULTS],
+}
+
+impl Default for VibTest {
+    fn default() -> Self {
+        Self {
+            stage: Stage::Idle,
+            status: VibTestStatus::Idle,
+            stage_start: 0.,
+            power: 0.,
+            start_pending: None,
+            count: 0,
+            mean: [0.; 3],
+            m2: [0.; 3],
+            block: [[0.; FFT_LEN]; 3],
+            block_i: 0,
+            fft_axis: None,
+            spectrum: [[0.; NUM_BINS]; 3],
+            num_blocks: 0,
+            fft_out: [0.; FFT_LEN],
+            fft_mag: [0.; NUM_BINS],
+            window: [0.; FFT_LEN],
+            results: [None; NUM_VIB_RESULTS],
+        }
+    }
+}
+
+impl VibTest {
+    /// Request a test, from the USB ISR. Check the motor test interlocks before calling. Power is
+    /// clamped by the motor test.
+    pub fn request_start(&mut self, power: f32) {
+        self.start_pending = Some(power);
+    }
+
+    pub fn status(&self) -> VibTestStatus {
+        self.status
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.stage != Stage::Idle
+    }
+
+    /// Run each IMU update, with gyro readings prior to filtering; rad/s. Timestamp is in seconds.
+    pub fn update(&mut self, motor_test: &mut MotorTest, gyro: (f32, f32, f32), timestamp: f32) {
+        if let Some(power) = self.start_pending.take() {
+            self.start(power, timestamp);
+        }
+
+        let elapsed = timestamp - self.stage_start;
+
+        match self.stage {
+            Stage::Idle => (),
+            Stage::Settle(i) => {
+                if elapsed < SETTLE_TIME {
+                    return;
+                }
+
+                self.reset_recording();
+
+                if i == 0 {
+                    // No motor to spin up; measure the noise floor.
+                    self.set_stage(Stage::Record(i), timestamp);
+                } else {
+                    let motor = MOTORS[i - 1];
+                    // We stop the motor once recording is complete.
+                    motor_test.cmd_pending =
+                        Some(MotorTestCmd::new(motor, self.power, MAX_DURATION));
+                    self.set_stage(Stage::Spinup(i), timestamp);
+                }
+            }
+            Stage::Spinup(i) => {
+                if !motor_running(motor_test, i) {
+                    self.abort();
+                } else if elapsed >= SPINUP_TIME {
+                    self.set_stage(Stage::Record(i), timestamp);
+                }
+            }
+            Stage::Record(i) => {
+                if i > 0 && !motor_running(motor_test, i) {
+                    self.abort();
+                    return;
+                }
+
+                self.add_reading(gyro);
+
+                if elapsed < RECORD_TIME {
+                    return;
+                }
+
+                if i > 0 {
+                    motor_test.stop_pending = true;
+                }
+                self.results[i] = Some(self.result());
+
+                if i + 1 < NUM_VIB_RESULTS {
+                    self.set_stage(Stage::Settle(i + 1), timestamp);
+                } else {
+                    self.stage = Stage::Idle;
+                    self.status = VibTestStatus::Complete;
+                }
+            }
+        }
+    }
+
+    /// Clears previous results. We reset in place, vice replacing `self`, since this is large.
+    fn start(&mut self, power: f32, timestamp: f32) {
+        // Hann window.
+        for (i, w) in self.window.iter_mut().enumerate() {
+            *w = 0.5 - 0.5 * (2. * core::f32::consts::PI * i as f32 / FFT_LEN as f32).cos();
+        }
+
+        self.power = power;
+        self.results = [None; NUM_VIB_RESULTS];
+        self.status = VibTestStatus::Running;
+        self.set_stage(Stage::Settle(0), timestamp);
+    }
+
+    fn abort(&mut self) {
+        self.stage = Stage::Idle;
+        self.status = VibTestStatus::Aborted;
+    }
+
+    fn set_stage(&mut self, stage: Stage, timestamp: f32) {
+        self.stage = stage;
+        self.stage_start = timestamp;
+    }
+
+    fn reset_recording(&mut self) {
+        self.count = 0;
+        self.mean = [0.; 3];
+        self.m2 = [0.; 3];
+        self.block_i = 0;
+        self.fft_axis = None;
+        self.spectrum = [[0.; NUM_BINS]; 3];
+        self.num_blocks = 0;
+    }
+
+    fn add_reading(&mut self, gyro: (f32, f32, f32)) {
+        let readings = [gyro.0, gyro.1, gyro.2];
+
+        self.count += 1;
+        let n = self.count as f32;
+
+        for (i, v) in readings.into_iter().enumerate() {
+            let delta = v - self.mean[i];
+            self.mean[i] += delta / n;
+            self.m2[i] += delta * (v - self.mean[i]);
+        }
+
+        if let Some(axis) = self.fft_axis {
+            self.transform(axis);
+            self.fft_axis = if axis < 2 { Some(axis + 1) } else { None };
+
+            if self.fft_axis.is_none() {
+                self.num_blocks += 1;
+                self.block_i = 0;
+            }
+            return;
+        }
+
+        for (axis, v) in readings.into_iter().enumerate() {
+            self.block[axis][self.block_i] = v;
+        }
+        self.block_i += 1;
+
+        if self.block_i == FFT_LEN {
+            self.fft_axis = Some(0);
+        }
+    }
+
+    /// Transform a full block for one axis, and add its magnitude spectrum to the sum.
+    fn transform(&mut self, axis: usize) {
+        let block = &mut self.block[axis];
+
+        let mean = block.iter().sum::<f32>() / FFT_LEN as f32;
+        for (v, w) in block.iter_mut().zip(self.window.iter()) {
+            *v = (*v - mean) * w;
+        }
+
+        unsafe {
+            let mut inst: dsp_sys::arm_rfft_fast_instance_f32 = core::mem::zeroed();
+            dsp_sys::arm_rfft_fast_init_f32(&mut inst, FFT_LEN as u16);
+
+            // Note: This modifies the input buffer.
+            dsp_sys::arm_rfft_fast_f32(&mut inst, block.as_mut_ptr(), self.fft_out.as_mut_ptr(), 0);
+            dsp_sys::arm_cmplx_mag_f32(
+                self.fft_out.as_ptr(),
+                self.fft_mag.as_mut_ptr(),
+                NUM_BINS as u32,
+            );
+        }
+
+        for (sum, mag) in self.spectrum[axis].iter_mut().zip(self.fft_mag.iter()) {
+            *sum += mag;
+        }
+    }
+
+    fn result(&self) -> VibResult {
+        let mut result = VibResult::default();
+
+        let bin_width = loop_rates::rates().imu / FFT_LEN as f32;
+        let min_bin = (MIN_PEAK_FREQ / bin_width).ceil().max(1.) as usize;
+
+        for axis in 0..3 {
+            if self.count > 1 {
+                result.rms[axis] = (self.m2[axis] / self.count as f32).sqrt();
+            }
+
+            if self.num_blocks == 0 {
+                continue;
+            }
+
+            let mut peak_bin = min_bin;
+            for bin in min_bin..NUM_BINS {
+                if self.spectrum[axis][bin] > self.spectrum[axis][peak_bin] {
+                    peak_bin = bin;
+                }
+            }
+
+            result.peak_freq[axis] = peak_bin as f32 * bin_width;
+            // A sinusoid of amplitude A produces a Hann-windowed peak of A * N / 4.
+            result.peak_amp[axis] =
+                self.spectrum[axis][peak_bin] / self.num_blocks as f32 * 4. / FFT_LEN as f32;
+        }
+
+        result
+    }
+}
+
+/// The motor for result `i` is commanded, or running.
+fn motor_running(motor_test: &MotorTest, i: usize) -> bool {
+    motor_test.cmd_pending.is_some() || motor_test.motor_active() == Some(MOTORS[i - 1])
+}
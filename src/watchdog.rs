@@ -0,0 +1,122 @@
+//! This module contains the independent watchdog (IWDG). It resets the MCU if not kicked within
+//! `TIMEOUT`; otherwise a lockup, eg a stuck I2C transaction in an ISR, or an infinite loop while
+//! parsing, would leave the motors at their last commanded value.
+//!
+//! We kick from the main loop. It runs on IMU data, so a dead IMU path stops the kicks. While
+//! disarmed, we also require the receiver ISR to have run recently, if the link is up, so a dead
+//! radio path causes a reset vice being masked by a healthy main loop.
+//!
+//! In flight, a reset stops the motors, so the watchdog doesn't handle sensor failures there:
+//! Receiver dropouts are handled by the link failsafe (`link_failsafe`), and a stale IMU by
+//! the IMU failure policy (`safety::execute_imu_failure`). The sensor watchdog ISR runs that
+//! policy, and kicks while it does, since the main loop can't.
+//!
+//! After a watchdog reset, we log it in the event log. We don't re-arm on our own: Arming requires
+//! the arm switch be seen disarmed after power-up; see `safety::handle_arm_status`.
+//!
+//! Onboard flash erases stall the CPU, and may take longer than the timeout; we extend it around
+//! them. Bounded blocking sections, eg sending DSHOT commands, kick directly.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cfg_if::cfg_if;
+use cortex_m::interrupt;
+use hal::{iwdg, pac};
+
+// Seconds.
+const TIMEOUT: f32 = 0.1;
+// Seconds. Covers an H7 sector erase.
+const TIMEOUT_FLASH: f32 = 4.;
+
+// Seconds. While disarmed, we stop kicking if the receiver ISR hasn't run within this window.
+// Receivers send frames at 25Hz or faster.
+const RX_WINDOW: f32 = 0.1;
+
+// Set by the receiver ISR, and cleared by the main loop when it checks it.
+static RX_ALIVE: AtomicBool = AtomicBool::new(false);
+
+// Number of flash operations in progress, which may be nested across priorities. Accessed in
+// critical sections.
+static mut FLASH_OPS: u8 = 0;
+
+/// Start the watchdog. Run at the end of init; it can't be stopped once started.
+pub fn setup() {
+    iwdg::setup(TIMEOUT);
+}
+
+/// Run from the receiver ISR.
+pub fn mark_rx() {
+    RX_ALIVE.store(true, Ordering::Release);
+}
+
+/// Kick the watchdog without checking ISRs. Only for bounded blocking sections, during which the
+/// main loop can't run.
+pub fn kick_blocking() {
+    iwdg::pet();
+}
+
+/// Run from the sensor watchdog ISR while it executes the IMU failure policy.
+pub fn kick_imu_failure() {
+    iwdg::pet();
+}
+
+/// Run a flash operation with an extended timeout. Blocking.
+pub fn flash_op<T>(f: impl FnOnce() -> T) -> T {
+    interrupt::free(|_| unsafe {
+        if FLASH_OPS == 0 {
+            iwdg::setup(TIMEOUT_FLASH);
+        }
+        FLASH_OPS += 1;
+    });
+
+    let result = f();
+
+    interrupt::free(|_| unsafe {
+        FLASH_OPS -= 1;
+        if FLASH_OPS == 0 {
+            iwdg::setup(TIMEOUT);
+        }
+    });
+
+    result
+}
+
+/// Run at init. Returns true if the last reset was from the watchdog, and clears the reset
+/// flags, so the next reset is reported correctly.
+pub fn check_reset_cause() -> bool {
+    let rcc = unsafe { &(*pac::RCC::ptr()) };
+
+    cfg_if! {
+        if #[cfg(feature = "h7")] {
+            let result = rcc.rsr.read().iwdg1rstf().bit_is_set();
+            rcc.rsr.modify(|_, w| w.rmvf().set_bit());
+        } else {
+            let result = rcc.csr.read().iwdgrstf().bit_is_set();
+            rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        }
+    }
+
+    result
+}
+
+#[derive(Default)]
+pub struct Watchdog {
+    /// Seconds since start, when the receiver ISR last ran, as of our last check.
+    rx_seen: f32,
+}
+
+impl Watchdog {
+    /// Run each main loop update. Kicks the watchdog, unless we're disarmed, and the receiver ISR
+    /// has stopped. `link_up` is from the receiver's link status.
+    pub fn kick(&mut self, armed: bool, link_up: bool, timestamp: f32) {
+        if RX_ALIVE.swap(false, Ordering::AcqRel) || armed || !link_up {
+            self.rx_seen = timestamp;
+        }
+
+        if timestamp - self.rx_seen > RX_WINDOW {
+            return;
+        }
+
+        iwdg::pet();
+    }
+}